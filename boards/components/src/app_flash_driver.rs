@@ -11,6 +11,7 @@
 //!     ));
 //! ```
 
+use capsules::ab_copies::AbCopies;
 use capsules::app_flash_driver::AppFlash;
 use capsules::nonvolatile_to_pages::NonvolatileToPages;
 use core::mem::MaybeUninit;
@@ -25,6 +26,7 @@ use kernel::static_init_half;
 macro_rules! app_flash_component_helper {
     ($F:ty, $buffer_size: literal) => {{
         static mut BUFFER: [u8; $buffer_size] = [0; $buffer_size];
+        use capsules::ab_copies::AbCopies;
         use capsules::app_flash_driver::AppFlash;
         use capsules::nonvolatile_to_pages::NonvolatileToPages;
         use core::mem::MaybeUninit;
@@ -32,11 +34,13 @@ macro_rules! app_flash_component_helper {
         static mut page_buffer: MaybeUninit<<$F as hil::flash::Flash>::Page> =
             MaybeUninit::uninit();
         static mut nv_to_page: MaybeUninit<NonvolatileToPages<'static, $F>> = MaybeUninit::uninit();
+        static mut ab_copies: MaybeUninit<AbCopies<'static>> = MaybeUninit::uninit();
         static mut app_flash: MaybeUninit<AppFlash<'static>> = MaybeUninit::uninit();
         (
             &mut BUFFER,
             &mut page_buffer,
             &mut nv_to_page,
+            &mut ab_copies,
             &mut app_flash,
         )
     };};
@@ -73,6 +77,7 @@ impl<
         &'static mut [u8],
         &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
         &'static mut MaybeUninit<NonvolatileToPages<'static, F>>,
+        &'static mut MaybeUninit<AbCopies<'static>>,
         &'static mut MaybeUninit<AppFlash<'static>>,
     );
     type Output = &'static AppFlash<'static>;
@@ -93,17 +98,24 @@ impl<
         );
         self.storage.set_client(nv_to_page);
 
-        let app_flash = static_init_half!(
+        let ab_copies = static_init_half!(
             static_buffer.3,
+            AbCopies<'static>,
+            AbCopies::new(nv_to_page)
+        );
+        nv_to_page.set_client(ab_copies);
+
+        let app_flash = static_init_half!(
+            static_buffer.4,
             capsules::app_flash_driver::AppFlash<'static>,
             capsules::app_flash_driver::AppFlash::new(
-                nv_to_page,
+                ab_copies,
                 self.board_kernel.create_grant(&grant_cap),
                 static_buffer.0
             )
         );
 
-        nv_to_page.set_client(app_flash);
+        ab_copies.set_client(app_flash);
 
         app_flash
     }
@@ -0,0 +1,244 @@
+//! Component for IPv6 over a USB Ethernet adapter.
+//!
+//! This provides one Component, CdcEcmComponent. It makes the device a USB
+//! Ethernet adapter (CDC-ECM) and runs the IPv6 and UDP stack over it, with
+//! addresses configured by SLAAC. It takes the place of the UDPMuxComponent
+//! on boards without a radio, and its outputs are used the same way, e.g. for
+//! the UDPDriverComponent.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpinator", // Product
+//!     "Serial No. 5",   // Serial number
+//! ];
+//! let (ecm, udp_send_mux, udp_recv_mux, udp_port_table, slaac) =
+//!     components::cdc_ecm::CdcEcmComponent::new(
+//!         &nrf52::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x2341,
+//!         0x005d,
+//!         STRINGS,
+//!         [0x02, 0x00, 0x00, 0x12, 0x34, 0x56], // The device's MAC address
+//!         [0x02, 0x00, 0x00, 0x12, 0x34, 0x57], // The host's MAC address
+//!         mux_alarm,
+//!     )
+//!     .finalize(components::usb_cdc_ecm_component_helper!(
+//!         nrf52::usbd::Usbd,
+//!         nrf52::rtc::Rtc
+//!     ));
+//! udp_driver.set_interface_addresses(slaac);
+//! ecm.enable();
+//! ecm.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::net::ipv6::ipv6_link::IP6Link;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
+use capsules::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+use capsules::net::ipv6::slaac::Slaac;
+use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::network_capabilities::{IpVisibilityCapability, UdpVisibilityCapability};
+use capsules::net::udp::udp_port_table::{SocketBindingEntry, UdpPortManager, MAX_NUM_BOUND_PORTS};
+use capsules::net::udp::udp_recv::MuxUdpReceiver;
+use capsules::net::udp::udp_send::MuxUdpSender;
+use capsules::net::udp::UDPHeader;
+use capsules::usb::cdc_ecm::{self, CdcEcm};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+const MAX_PAYLOAD_LEN: usize = super::udp_mux::MAX_PAYLOAD_LEN;
+const UDP_HDR_SIZE: usize = 8;
+const IP6_HDR_SIZE: usize = 40;
+
+// The payload of the IP6Packet, and the buffer it is encoded into for the
+// adapter.
+static mut UDP_DGRAM: [u8; MAX_PAYLOAD_LEN - UDP_HDR_SIZE] = [0; MAX_PAYLOAD_LEN - UDP_HDR_SIZE];
+static mut TX_BUF: [u8; IP6_HDR_SIZE + MAX_PAYLOAD_LEN] = [0; IP6_HDR_SIZE + MAX_PAYLOAD_LEN];
+
+// The host's MAC address, as the string in the descriptors.
+static mut MAC_ADDRESS_STRING: [u8; 12] = [0; 12];
+
+static mut USED_KERNEL_PORTS: [Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS] =
+    [None; MAX_NUM_BOUND_PORTS];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_cdc_ecm_component_helper {
+    ($U:ty, $A:ty $(,)?) => {{
+        use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+        use capsules::net::udp::udp_send::MuxUdpSender;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<capsules::usb::cdc_ecm::CdcEcm<'static, $U>> =
+            MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<
+            capsules::net::ipv6::slaac::Slaac<'static, VirtualMuxAlarm<'static, $A>>,
+        > = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF4: MaybeUninit<
+            MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>>,
+        > = MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4)
+    };};
+}
+
+pub struct CdcEcmComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    A: 'static + Alarm<'static>,
+> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    mac_address: [u8; 6],
+    host_mac_address: [u8; 6],
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
+    CdcEcmComponent<U, A>
+{
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        mac_address: [u8; 6],
+        host_mac_address: [u8; 6],
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            mac_address,
+            host_mac_address,
+            alarm_mux,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>> Component
+    for CdcEcmComponent<U, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<CdcEcm<'static, U>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<Slaac<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<
+            MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        >,
+    );
+    type Output = (
+        &'static CdcEcm<'static, U>,
+        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static MuxUdpReceiver<'static>,
+        &'static UdpPortManager,
+        &'static Slaac<'static, VirtualMuxAlarm<'static, A>>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let mac_address_string =
+            cdc_ecm::format_mac_address(&self.host_mac_address, &mut MAC_ADDRESS_STRING);
+        let strings = static_init!(
+            [&'static str; 4],
+            [
+                self.strings[0],
+                self.strings[1],
+                self.strings[2],
+                mac_address_string,
+            ]
+        );
+
+        let ecm = static_init_half!(
+            static_buffer.0,
+            CdcEcm<'static, U>,
+            CdcEcm::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                strings,
+                self.mac_address,
+                self.host_mac_address,
+                &mut cdc_ecm::RX_BUFFER,
+            )
+        );
+        self.usb.set_client(ecm);
+
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let ip_vis = static_init!(
+            IpVisibilityCapability,
+            IpVisibilityCapability::new(&create_cap)
+        );
+
+        let ip_pyld: IPPayload = IPPayload {
+            header: TransportHeader::UDP(UDPHeader::new()),
+            payload: &mut UDP_DGRAM,
+        };
+        let ip6_dg = static_init!(IP6Packet<'static>, IP6Packet::new(ip_pyld));
+
+        let ip_send = static_init_half!(
+            static_buffer.3,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+            IP6SendStruct::new_with_link(ip6_dg, &mut TX_BUF, ecm, ip_vis)
+        );
+        ecm.set_transmit_client(ip_send);
+
+        let ip_receive = static_init!(IP6RecvStruct<'static>, IP6RecvStruct::new());
+        ecm.set_receive_client(ip_receive);
+
+        let slaac_alarm = static_init_half!(
+            static_buffer.1,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let slaac = static_init_half!(
+            static_buffer.2,
+            Slaac<'static, VirtualMuxAlarm<'static, A>>,
+            Slaac::new(slaac_alarm, cdc_ecm::eui64(&self.mac_address))
+        );
+        slaac_alarm.set_alarm_client(slaac);
+        slaac.set_sender(ip_send);
+        ip_receive.set_client(slaac);
+        ecm.set_interface_addresses(slaac);
+
+        let udp_recv_mux = static_init!(MuxUdpReceiver<'static>, MuxUdpReceiver::new());
+        slaac.set_client(udp_recv_mux);
+
+        let udp_send_mux = static_init_half!(
+            static_buffer.4,
+            MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+            MuxUdpSender::new(ip_send)
+        );
+        ip_send.set_client(udp_send_mux);
+
+        let create_table_cap = create_capability!(capabilities::CreatePortTableCapability);
+        let udp_port_table = static_init!(
+            UdpPortManager,
+            UdpPortManager::new(&create_table_cap, &mut USED_KERNEL_PORTS, udp_vis)
+        );
+
+        (ecm, udp_send_mux, udp_recv_mux, udp_port_table, slaac)
+    }
+}
@@ -0,0 +1,128 @@
+//! Component for USB DFU (Device Firmware Upgrade) support.
+//!
+//! This provides a component for updating the kernel image staged for the
+//! bootloader and the TBF apps over USB, with a tool like `dfu-util`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let dfu = components::dfu::DfuComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915,
+//!     0x521f,
+//!     STRINGS,
+//!     &nrf52::nvmc::NVMC,
+//!     TARGETS,
+//!     false,
+//! )
+//! .finalize(components::dfu_component_helper!(
+//!     nrf52::usbd::Usbd,
+//!     nrf52::nvmc::Nvmc
+//! ));
+//!
+//! dfu.enable();
+//! dfu.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::usb::dfu::{Dfu, Target};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! dfu_component_helper {
+    ($U:ty, $F:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        use kernel::hil;
+        static mut BUF1: MaybeUninit<<$F as hil::flash::Flash>::Page> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<capsules::usb::dfu::Dfu<'static, $U, $F>> =
+            MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct DfuComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, Dfu<'static, U, F>>,
+> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str],
+    flash: &'static F,
+    targets: &'static [Target],
+    runtime: bool,
+}
+
+impl<
+        U: 'static + hil::usb::UsbController<'static>,
+        F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, Dfu<'static, U, F>>,
+    > DfuComponent<U, F>
+{
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        flash: &'static F,
+        targets: &'static [Target],
+        runtime: bool,
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            flash,
+            targets,
+            runtime,
+        }
+    }
+}
+
+impl<
+        U: 'static + hil::usb::UsbController<'static>,
+        F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, Dfu<'static, U, F>>,
+    > Component for DfuComponent<U, F>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<Dfu<'static, U, F>>,
+    );
+    type Output = &'static Dfu<'static, U, F>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let page = static_init_half!(
+            static_buffer.0,
+            <F as hil::flash::Flash>::Page,
+            <F as hil::flash::Flash>::Page::default()
+        );
+
+        let dfu = static_init_half!(
+            static_buffer.1,
+            Dfu<'static, U, F>,
+            Dfu::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                self.flash,
+                page,
+                self.targets,
+                self.runtime,
+            )
+        );
+        self.usb.set_client(dfu);
+        hil::flash::HasClient::set_client(self.flash, dfu);
+
+        dfu
+    }
+}
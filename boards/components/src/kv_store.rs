@@ -0,0 +1,96 @@
+//! Components for the per-application key-value store.
+//!
+//! This provides one component, KVStoreComponent, which provides a system
+//! call interface to a `hil::kv_system` implementation such as TicKV.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let kv_key = static_init!(capsules::tickv::TicKVKeyType, [0; 8]);
+//! let kv_driver = components::kv_store::KVStoreComponent::new(
+//!     board_kernel,
+//!     tickv,
+//!     kv_key,
+//! )
+//! .finalize(components::kv_store_component_helper!(
+//!     capsules::tickv::TicKVStore<'static, FlashUser<'static, lowrisc::flash_ctrl::FlashCtrl>>
+//! ));
+//! ```
+
+use capsules::kv_driver::KVStoreDriver;
+use capsules::kv_store::{KVStore, HEADER_LENGTH};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::hil::kv_system::KVSystem;
+use kernel::{static_init, static_init_half};
+
+/// The largest value applications can store.
+pub const MAX_VALUE_LENGTH: usize = 256;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! kv_store_component_helper {
+    ($S:ty $(,)?) => {{
+        use capsules::kv_store::KVStore;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<KVStore<'static, $S>> = MaybeUninit::uninit();
+        &mut BUF1
+    };};
+}
+
+pub struct KVStoreComponent<S: 'static + KVSystem<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    kv_system: &'static S,
+    key_buffer: &'static mut S::K,
+}
+
+impl<S: 'static + KVSystem<'static>> KVStoreComponent<S> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        kv_system: &'static S,
+        key_buffer: &'static mut S::K,
+    ) -> Self {
+        Self {
+            board_kernel,
+            kv_system,
+            key_buffer,
+        }
+    }
+}
+
+impl<S: 'static + KVSystem<'static>> Component for KVStoreComponent<S> {
+    type StaticInput = &'static mut MaybeUninit<KVStore<'static, S>>;
+    type Output = &'static KVStoreDriver<'static>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let unhashed_key = static_init!([u8; 64], [0; 64]);
+        let data = static_init!(
+            [u8; HEADER_LENGTH + MAX_VALUE_LENGTH],
+            [0; HEADER_LENGTH + MAX_VALUE_LENGTH]
+        );
+        let kv_store = static_init_half!(
+            static_buffer,
+            KVStore<'static, S>,
+            KVStore::new(self.kv_system, unhashed_key, self.key_buffer, data)
+        );
+        self.kv_system.set_client(kv_store);
+
+        let driver_buffer = static_init!([u8; MAX_VALUE_LENGTH], [0; MAX_VALUE_LENGTH]);
+        let driver = static_init!(
+            KVStoreDriver<'static>,
+            KVStoreDriver::new(
+                kv_store,
+                self.board_kernel.create_grant(&grant_cap),
+                driver_buffer
+            )
+        );
+        hil::kv_store::KVStore::set_client(kv_store, driver);
+
+        driver
+    }
+}
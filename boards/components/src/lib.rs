@@ -8,11 +8,13 @@ pub mod app_flash_driver;
 pub mod bus;
 pub mod button;
 pub mod cdc;
+pub mod cdc_ecm;
 pub mod console;
 pub mod crc;
 pub mod ctap;
 pub mod debug_queue;
 pub mod debug_writer;
+pub mod dfu;
 pub mod ft6x06;
 pub mod fxos8700;
 pub mod gpio;
@@ -22,6 +24,7 @@ pub mod humidity;
 pub mod i2c;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod kv_store;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
@@ -29,6 +32,7 @@ pub mod lldb;
 pub mod lsm303agr;
 pub mod lsm303dlhc;
 pub mod mlx90614;
+pub mod msc;
 pub mod mx25r6435f;
 pub mod ninedof;
 pub mod nonvolatile_storage;
@@ -52,3 +56,5 @@ pub mod tickv;
 pub mod touch;
 pub mod udp_driver;
 pub mod udp_mux;
+pub mod usb_composite;
+pub mod usb_hid;
@@ -0,0 +1,111 @@
+//! Component for Mass Storage over USB support.
+//!
+//! This provides a component for using the USB mass storage driver. This
+//! exposes a region of a nonvolatile storage device as a drive to the USB host.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpinator", // Product
+//!     "0123456789AB",   // Serial number, at least 12 hex digits
+//! ];
+//! let msc = components::msc::MassStorageComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x2341,
+//!     0x005b,
+//!     STRINGS,
+//!     nonvolatile_storage,
+//!     0x60000,
+//!     0x20000,
+//!     false,
+//! )
+//! .finalize(components::usb_msc_component_helper!(nrf52::usbd::Usbd));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::usb::msc::MassStorage;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_msc_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::msc::MassStorage<'static, $U>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct MassStorageComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    storage: &'static dyn NonvolatileStorage<'static>,
+    start: usize,
+    length: usize,
+    read_only: bool,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> MassStorageComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'static dyn NonvolatileStorage<'static>,
+        start: usize,
+        length: usize,
+        read_only: bool,
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            storage,
+            start,
+            length,
+            read_only,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for MassStorageComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<MassStorage<'static, U>>;
+    type Output = &'static MassStorage<'static, U>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let msc = static_init_half!(
+            s,
+            MassStorage<'static, U>,
+            MassStorage::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                self.storage,
+                self.start,
+                self.length,
+                self.read_only,
+                &mut capsules::usb::msc::BUFFER,
+            )
+        );
+        self.usb.set_client(msc);
+        self.storage.set_client(msc);
+
+        msc
+    }
+}
@@ -25,6 +25,7 @@
 //!        0x40000,
 //!        flash_ctrl_read_buf,
 //!        page_buffer,
+//!        dynamic_deferred_caller,
 //!    )
 //!    .finalize(components::tickv_component_helper!(
 //!        lowrisc::flash_ctrl::FlashCtrl
@@ -37,6 +38,7 @@ use capsules::virtual_flash::FlashUser;
 use capsules::virtual_flash::MuxFlash;
 use core::mem::MaybeUninit;
 use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil;
@@ -96,6 +98,7 @@ pub struct TicKVComponent<F: 'static + hil::flash::Flash> {
     flash_size: usize,
     tickfs_read_buf: &'static mut [u8; 512],
     flash_read_buffer: &'static mut F::Page,
+    deferred_caller: &'static DynamicDeferredCall,
 }
 
 impl<F: 'static + hil::flash::Flash> TicKVComponent<F> {
@@ -105,6 +108,7 @@ impl<F: 'static + hil::flash::Flash> TicKVComponent<F> {
         flash_size: usize,
         tickfs_read_buf: &'static mut [u8; 512],
         flash_read_buffer: &'static mut F::Page,
+        deferred_caller: &'static DynamicDeferredCall,
     ) -> Self {
         Self {
             mux_flash,
@@ -112,6 +116,7 @@ impl<F: 'static + hil::flash::Flash> TicKVComponent<F> {
             flash_size,
             tickfs_read_buf,
             flash_read_buffer,
+            deferred_caller,
         }
     }
 }
@@ -141,8 +146,14 @@ impl<F: 'static + hil::flash::Flash> Component for TicKVComponent<F> {
                 self.flash_read_buffer,
                 self.region_offset,
                 self.flash_size,
+                self.deferred_caller,
             )
         );
+        driver.initialize_callback_handle(
+            self.deferred_caller
+                .register(driver)
+                .expect("no deferred call slot available for tickv"),
+        );
         virtual_flash.set_client(driver);
         driver.initalise();
         driver
@@ -0,0 +1,130 @@
+//! Components for composite USB devices.
+//!
+//! This provides two components, `UsbCompositeComponent`, which provides a
+//! composite device on a USB controller, and `UsbFunctionComponent`, which
+//! provides a virtual USB controller for one class of the composite device.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let composite = components::usb_composite::UsbCompositeComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915,
+//!     0x503a,
+//!     strings,
+//! )
+//! .finalize(components::usb_composite_component_helper!(nrf52::usbd::Usbd));
+//! let function = components::usb_composite::UsbFunctionComponent::new(composite)
+//!     .finalize(components::usb_function_component_helper!(nrf52::usbd::Usbd));
+//! ```
+//!
+//! Each function is then passed as the USB controller of a class component,
+//! and the composite device is enabled and attached once all classes are
+//! created.
+
+use core::mem::MaybeUninit;
+
+use capsules::usb::composite::{CompositeDevice, UsbFunction};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_composite_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::composite::CompositeDevice<'static, $U>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_function_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::composite::UsbFunction<'static, $U>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct UsbCompositeComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbCompositeComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbCompositeComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<CompositeDevice<'static, U>>;
+    type Output = &'static CompositeDevice<'static, U>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let descriptor_storage = static_init!([u8; 256], [0; 256]);
+
+        let composite = static_init_half!(
+            static_buffer,
+            CompositeDevice<'static, U>,
+            CompositeDevice::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                descriptor_storage,
+            )
+        );
+        self.usb.set_client(composite);
+
+        composite
+    }
+}
+
+pub struct UsbFunctionComponent<U: 'static + hil::usb::UsbController<'static>> {
+    composite: &'static CompositeDevice<'static, U>,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbFunctionComponent<U> {
+    pub fn new(composite: &'static CompositeDevice<'static, U>) -> Self {
+        Self { composite }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbFunctionComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<UsbFunction<'static, U>>;
+    type Output = &'static UsbFunction<'static, U>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let function = static_init_half!(
+            static_buffer,
+            UsbFunction<'static, U>,
+            UsbFunction::new(self.composite)
+        );
+        function.setup();
+
+        function
+    }
+}
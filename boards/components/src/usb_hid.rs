@@ -0,0 +1,171 @@
+//! Component for HID over USB support.
+//!
+//! This provides a component for using a USB HID device with a report
+//! descriptor given by the board, together with the syscall driver that lets a
+//! process send and receive its reports.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpboard",  // Product
+//!     "Serial No. 5",   // Serial number
+//! ];
+//! let (hid, hid_driver) = components::usb_hid::UsbHidComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x2341,
+//!     0x005c,
+//!     STRINGS,
+//!     board_kernel,
+//!     capsules::usb::hid::KEYBOARD_REPORT_DESCRIPTOR,
+//!     capsules::usb::hid::BootProtocol::Keyboard,
+//!     8,
+//!     1,
+//! )
+//! .finalize(components::usb_hid_component_helper!(nrf52::usbd::Usbd));
+//!
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::usb::descriptors::{
+    DescriptorType, HIDCountryCode, HIDDescriptor, HIDSubordinateDescriptor, ReportDescriptor,
+};
+use capsules::usb::hid::{BootProtocol, HidDevice};
+use capsules::usb_hid_driver::UsbHidDriver;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_hid_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<capsules::usb::hid::HidDevice<'static, $U>> =
+            MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<
+            capsules::usb_hid_driver::UsbHidDriver<
+                'static,
+                capsules::usb::hid::HidDevice<'static, $U>,
+            >,
+        > = MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct UsbHidComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    board_kernel: &'static kernel::Kernel,
+    report_descriptor: &'static [u8],
+    boot_protocol: BootProtocol,
+    input_report_len: usize,
+    output_report_len: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbHidComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        board_kernel: &'static kernel::Kernel,
+        report_descriptor: &'static [u8],
+        boot_protocol: BootProtocol,
+        input_report_len: usize,
+        output_report_len: usize,
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            board_kernel,
+            report_descriptor,
+            boot_protocol,
+            input_report_len,
+            output_report_len,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbHidComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<HidDevice<'static, U>>,
+        &'static mut MaybeUninit<UsbHidDriver<'static, HidDevice<'static, U>>>,
+    );
+    type Output = (
+        &'static HidDevice<'static, U>,
+        &'static UsbHidDriver<'static, HidDevice<'static, U>>,
+    );
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let report = static_init!(
+            ReportDescriptor<'static>,
+            ReportDescriptor {
+                desc: self.report_descriptor,
+            }
+        );
+        let sub_descriptors = static_init!(
+            [HIDSubordinateDescriptor; 1],
+            [HIDSubordinateDescriptor {
+                typ: DescriptorType::Report,
+                len: self.report_descriptor.len() as u16,
+            }]
+        );
+        let hid_descriptor = static_init!(
+            HIDDescriptor<'static>,
+            HIDDescriptor {
+                hid_class: 0x0111,
+                country_code: HIDCountryCode::NotSupported,
+                sub_descriptors: sub_descriptors,
+            }
+        );
+
+        let hid = static_init_half!(
+            s.0,
+            HidDevice<'static, U>,
+            HidDevice::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                hid_descriptor,
+                report,
+                self.boot_protocol,
+                self.input_report_len,
+                self.output_report_len,
+            )
+        );
+        self.usb.set_client(hid);
+
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let hid_driver = static_init_half!(
+            s.1,
+            UsbHidDriver<'static, HidDevice<'static, U>>,
+            UsbHidDriver::new(
+                hid,
+                static_init!([u8; 64], [0; 64]),
+                static_init!([u8; 64], [0; 64]),
+                self.board_kernel.create_grant(&grant_cap),
+            )
+        );
+        hid.set_client(hid_driver);
+
+        (hid, hid_driver)
+    }
+}
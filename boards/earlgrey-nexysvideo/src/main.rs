@@ -77,6 +77,7 @@ struct EarlGreyNexysVideo {
         capsules::virtual_uart::UartDevice<'static>,
     >,
     i2c_master: &'static capsules::i2c_master::I2CMasterDriver<lowrisc::i2c::I2c<'static>>,
+    kv_driver: &'static capsules::kv_driver::KVStoreDriver<'static>,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -93,6 +94,7 @@ impl Platform for EarlGreyNexysVideo {
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::low_level_debug::DRIVER_NUM => f(Some(self.lldb)),
             capsules::i2c_master::DRIVER_NUM => f(Some(self.i2c_master)),
+            capsules::kv_driver::DRIVER_NUM => f(Some(self.kv_driver)),
             _ => f(None),
         }
     }
@@ -123,7 +125,7 @@ pub unsafe fn reset_handler() {
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 1], Default::default());
+        static_init!([DynamicDeferredCallClientState; 2], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -288,18 +290,29 @@ pub unsafe fn reset_handler() {
     );
 
     // TicKV
-    let _tickv = components::tickv::TicKVComponent::new(
+    let tickv = components::tickv::TicKVComponent::new(
         &mux_flash,                                  // Flash controller
         0x20040000 / lowrisc::flash_ctrl::PAGE_SIZE, // Region offset (size / page_size)
         0x40000,                                     // Region size
         flash_ctrl_read_buf,                         // Buffer used internally in TicKV
         page_buffer,                                 // Buffer used with the flash controller
+        dynamic_deferred_caller,                     // Used to deliver generate_key callbacks
     )
     .finalize(components::tickv_component_helper!(
         lowrisc::flash_ctrl::FlashCtrl
     ));
     hil::flash::HasClient::set_client(&peripherals.flash_ctrl, mux_flash);
 
+    // Per-app key-value store on top of TicKV
+    let kv_key = static_init!(capsules::tickv::TicKVKeyType, [0; 8]);
+    let kv_driver = components::kv_store::KVStoreComponent::new(board_kernel, tickv, kv_key)
+        .finalize(components::kv_store_component_helper!(
+            capsules::tickv::TicKVStore<
+                'static,
+                capsules::virtual_flash::FlashUser<'static, lowrisc::flash_ctrl::FlashCtrl>,
+            >
+        ));
+
     /// These symbols are defined in the linker script.
     extern "C" {
         /// Beginning of the ROM region containing app images.
@@ -320,6 +333,7 @@ pub unsafe fn reset_handler() {
         hmac,
         lldb: lldb,
         i2c_master,
+        kv_driver,
     };
 
     kernel::procs::load_processes(
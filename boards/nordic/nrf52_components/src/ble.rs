@@ -73,6 +73,10 @@ impl Component for BLEComponent {
         kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
             self.radio, ble_radio,
         );
+        ble_radio.set_exchange_radio(self.radio);
+        kernel::hil::ble_advertising::BleConnectionDriver::set_connection_client(
+            self.radio, ble_radio,
+        );
         ble_radio_virtual_alarm.set_alarm_client(ble_radio);
 
         ble_radio
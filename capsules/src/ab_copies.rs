@@ -0,0 +1,472 @@
+//! Atomic updates of data kept in two copies in nonvolatile storage.
+//!
+//! `AbCopies` keeps data in two slots, A and B, and writes each update to the
+//! slot that doesn't hold the current copy. Each copy starts with a header
+//! holding a sequence number, the length of the data and a CRC-32 of the data
+//! and the header. The header is written after the data, so a copy only
+//! counts once all of it has been written. If the update is interrupted, for
+//! example by a reset, the previous copy in the other slot is still intact.
+//!
+//! Reads return the complete copy with the highest sequence number. Finding
+//! it means reading the headers and checking the CRC of the data, so reads
+//! and updates read the whole current copy first.
+//!
+//! The two slots must not overlap, and when the storage is flash they must
+//! not share a flash page, since the pages of one slot are erased while it
+//! is written.
+//!
+//! `capsules::app_flash_driver` uses this to let applications update the data
+//! in two of their writeable flash regions atomically. `AbCopies` also passes
+//! plain writes through to the storage.
+//!
+//! ```text
+//! slot A                               slot B
+//! +--------+----------------+          +--------+---------------------+
+//! | header | copy 6         |          | header | copy 7 (current)    |
+//! +--------+----------------+          +--------+---------------------+
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ab_copies = static_init!(
+//!     capsules::ab_copies::AbCopies<'static>,
+//!     capsules::ab_copies::AbCopies::new(nv_to_page)
+//! );
+//! nv_to_page.set_client(ab_copies);
+//! ab_copies.set_client(app_flash);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ReturnCode;
+
+/// The size of the header at the start of each slot.
+pub const HEADER_SIZE: usize = 16;
+
+const MAGIC: [u8; 4] = *b"TKAB";
+
+/// The start address and size of the two slots.
+pub type Slots = [(usize, usize); 2];
+
+/// A complete copy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Copy {
+    /// The slot holding the copy, 0 or 1
+    pub slot: usize,
+    pub sequence: u32,
+    /// The length of the data
+    pub length: usize,
+}
+
+/// CRC-32 (IEEE 802.3), computed bit by bit to avoid a table.
+#[derive(Clone, Copy, PartialEq)]
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        Crc32(0xFFFF_FFFF)
+    }
+
+    fn update(self, data: &[u8]) -> Crc32 {
+        let mut crc = self.0;
+        for b in data {
+            crc ^= *b as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+            }
+        }
+        Crc32(crc)
+    }
+
+    /// The CRC of a copy also covers its sequence number and length.
+    fn finish(self, sequence: u32, length: usize) -> u32 {
+        let crc = self
+            .update(&sequence.to_le_bytes())
+            .update(&(length as u32).to_le_bytes());
+        !crc.0
+    }
+}
+
+/// A header that may belong to a complete copy.
+#[derive(Clone, Copy, PartialEq)]
+struct Header {
+    sequence: u32,
+    length: usize,
+    crc: u32,
+}
+
+/// Implement `Client` to receive callbacks from `AbCopies`.
+pub trait Client {
+    /// Fill `chunk` with the data at `offset` of the copy being written.
+    fn fill(&self, offset: usize, chunk: &mut [u8]);
+
+    /// `chunk` is the data at `offset` of a copy being read. If the copy
+    /// turns out to be incomplete, the data of the previous copy follows.
+    fn deliver(&self, offset: usize, chunk: &[u8]);
+
+    /// A read finished. Returns the copy that was delivered last, or `FAIL`
+    /// if there is no complete copy. The buffer is only lost if the storage
+    /// fails.
+    fn read_done(&self, buffer: Option<&'static mut [u8]>, result: Result<Copy, ReturnCode>);
+
+    /// An update finished, and the new copy is now the current one. The
+    /// buffer is only lost if the storage fails.
+    fn update_done(&self, buffer: Option<&'static mut [u8]>, result: Result<Copy, ReturnCode>);
+
+    /// A plain write finished.
+    fn write_done(&self, buffer: &'static mut [u8], length: usize);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Deliver up to this many bytes of the current copy
+    Read(usize),
+    /// Write a new copy of this length
+    Update(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Reading the header of a slot
+    Header(usize),
+    /// Reading the data of a slot to check its CRC
+    Check {
+        slot: usize,
+        offset: usize,
+        crc: Crc32,
+    },
+    /// Writing the data of a new copy
+    Data {
+        slot: usize,
+        sequence: u32,
+        offset: usize,
+        crc: Crc32,
+    },
+    /// Writing the header of a new copy
+    Commit(Copy),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Copies(Mode, Step),
+    Write,
+}
+
+pub struct AbCopies<'a> {
+    storage: &'a dyn NonvolatileStorage<'static>,
+    slots: Cell<Slots>,
+    headers: Cell<[Option<Header>; 2]>,
+    /// Slots whose CRC has been checked and failed
+    checked: Cell<[bool; 2]>,
+    operation: Cell<Operation>,
+    client: OptionalCell<&'a dyn Client>,
+}
+
+impl<'a> AbCopies<'a> {
+    pub fn new(storage: &'a dyn NonvolatileStorage<'static>) -> AbCopies<'a> {
+        AbCopies {
+            storage,
+            slots: Cell::new([(0, 0); 2]),
+            headers: Cell::new([None; 2]),
+            checked: Cell::new([false; 2]),
+            operation: Cell::new(Operation::Idle),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    /// The largest copy that fits in both slots, or `None` if the slots
+    /// can't be used.
+    pub fn capacity(slots: Slots) -> Option<usize> {
+        let [(a, a_size), (b, b_size)] = slots;
+        let overlap = a < b.saturating_add(b_size) && b < a.saturating_add(a_size);
+        if overlap || a_size <= HEADER_SIZE || b_size <= HEADER_SIZE {
+            None
+        } else {
+            Some(cmp::min(a_size, b_size) - HEADER_SIZE)
+        }
+    }
+
+    /// Write `length` bytes of `buffer` to `address`, like
+    /// `NonvolatileStorage::write()`.
+    pub fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.operation.set(Operation::Write);
+        let ret = self.storage.write(buffer, address, length);
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+        }
+        ret
+    }
+
+    /// Read the current copy in `slots`, delivering up to `length` bytes of
+    /// it through `Client::deliver()`. The buffer is only lost if the storage
+    /// fails.
+    pub fn read(
+        &self,
+        slots: Slots,
+        length: usize,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+        self.start(slots, Mode::Read(length), buffer)
+    }
+
+    /// Write a new copy of `length` bytes to `slots`, getting the data
+    /// through `Client::fill()`. The buffer is only lost if the storage
+    /// fails.
+    pub fn update(
+        &self,
+        slots: Slots,
+        length: usize,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+        match AbCopies::capacity(slots) {
+            Some(capacity) if length > capacity => Err((ReturnCode::ESIZE, Some(buffer))),
+            _ => self.start(slots, Mode::Update(length), buffer),
+        }
+    }
+
+    fn start(
+        &self,
+        slots: Slots,
+        mode: Mode,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+        if self.operation.get() != Operation::Idle {
+            return Err((ReturnCode::EBUSY, Some(buffer)));
+        }
+        if AbCopies::capacity(slots).is_none() {
+            return Err((ReturnCode::EINVAL, Some(buffer)));
+        }
+        if buffer.len() < HEADER_SIZE {
+            return Err((ReturnCode::ESIZE, Some(buffer)));
+        }
+        self.slots.set(slots);
+        self.headers.set([None; 2]);
+        self.checked.set([false; 2]);
+        self.operation.set(Operation::Copies(mode, Step::Header(0)));
+        match self.storage.read(buffer, slots[0].0, HEADER_SIZE) {
+            ReturnCode::SUCCESS => Ok(()),
+            e => {
+                self.operation.set(Operation::Idle);
+                Err((e, None))
+            }
+        }
+    }
+
+    fn parse(&self, slot: usize, header: &[u8]) -> Option<Header> {
+        let field = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let length = field(8) as usize;
+        if header[0..4] != MAGIC || length > self.slots.get()[slot].1 - HEADER_SIZE {
+            return None;
+        }
+        Some(Header {
+            sequence: field(4),
+            length,
+            crc: field(12),
+        })
+    }
+
+    /// The slot that may hold the newest complete copy and hasn't been
+    /// checked yet.
+    fn candidate(&self) -> Option<usize> {
+        let headers = self.headers.get();
+        let checked = self.checked.get();
+        (0..2)
+            .filter(|slot| !checked[*slot])
+            .filter_map(|slot| headers[slot].map(|header| (slot, header.sequence)))
+            .max_by_key(|(slot, sequence)| (*sequence, 1 - *slot))
+            .map(|(slot, _)| slot)
+    }
+
+    /// Read the next part of the data of a slot being checked, or decide if
+    /// the copy is complete.
+    fn check(&self, mode: Mode, slot: usize, offset: usize, crc: Crc32, buffer: &'static mut [u8]) {
+        let header = match self.headers.get()[slot] {
+            Some(header) => header,
+            None => return self.located(mode, None, buffer),
+        };
+        if offset == header.length {
+            if crc.finish(header.sequence, header.length) == header.crc {
+                let copy = Copy {
+                    slot,
+                    sequence: header.sequence,
+                    length: header.length,
+                };
+                return self.located(mode, Some(copy), buffer);
+            }
+            let mut checked = self.checked.get();
+            checked[slot] = true;
+            self.checked.set(checked);
+            return match self.candidate() {
+                Some(next) => self.check(mode, next, 0, Crc32::new(), buffer),
+                None => self.located(mode, None, buffer),
+            };
+        }
+        let length = cmp::min(buffer.len(), header.length - offset);
+        self.operation
+            .set(Operation::Copies(mode, Step::Check { slot, offset, crc }));
+        let address = self.slots.get()[slot].0 + HEADER_SIZE + offset;
+        let ret = self.storage.read(buffer, address, length);
+        if ret != ReturnCode::SUCCESS {
+            self.failed(mode, ret);
+        }
+    }
+
+    /// The current copy is known.
+    fn located(&self, mode: Mode, current: Option<Copy>, buffer: &'static mut [u8]) {
+        match mode {
+            Mode::Read(_) => {
+                self.operation.set(Operation::Idle);
+                self.client.map(move |client| {
+                    client.read_done(Some(buffer), current.ok_or(ReturnCode::FAIL))
+                });
+            }
+            Mode::Update(length) => {
+                // Write to the other slot, which may hold an older or an
+                // incomplete copy.
+                let (slot, sequence) = current.map_or((0, 1), |copy| {
+                    (1 - copy.slot, copy.sequence.wrapping_add(1))
+                });
+                self.write_data(length, slot, sequence, 0, Crc32::new(), buffer);
+            }
+        }
+    }
+
+    /// Write the next part of the data of the new copy, or its header once
+    /// all the data is written.
+    fn write_data(
+        &self,
+        total: usize,
+        slot: usize,
+        sequence: u32,
+        offset: usize,
+        crc: Crc32,
+        buffer: &'static mut [u8],
+    ) {
+        let mode = Mode::Update(total);
+        let address = self.slots.get()[slot].0;
+        let ret = if offset == total {
+            let copy = Copy {
+                slot,
+                sequence,
+                length: total,
+            };
+            buffer[0..4].copy_from_slice(&MAGIC);
+            buffer[4..8].copy_from_slice(&sequence.to_le_bytes());
+            buffer[8..12].copy_from_slice(&(total as u32).to_le_bytes());
+            buffer[12..16].copy_from_slice(&crc.finish(sequence, total).to_le_bytes());
+            self.operation
+                .set(Operation::Copies(mode, Step::Commit(copy)));
+            self.storage.write(buffer, address, HEADER_SIZE)
+        } else {
+            let length = cmp::min(buffer.len(), total - offset);
+            self.client
+                .map(|client| client.fill(offset, &mut buffer[..length]));
+            let crc = crc.update(&buffer[..length]);
+            self.operation.set(Operation::Copies(
+                mode,
+                Step::Data {
+                    slot,
+                    sequence,
+                    offset,
+                    crc,
+                },
+            ));
+            self.storage
+                .write(buffer, address + HEADER_SIZE + offset, length)
+        };
+        if ret != ReturnCode::SUCCESS {
+            self.failed(mode, ret);
+        }
+    }
+
+    /// The storage failed, and didn't give the buffer back.
+    fn failed(&self, mode: Mode, error: ReturnCode) {
+        self.operation.set(Operation::Idle);
+        self.client.map(|client| match mode {
+            Mode::Read(_) => client.read_done(None, Err(error)),
+            Mode::Update(_) => client.update_done(None, Err(error)),
+        });
+    }
+}
+
+impl NonvolatileStorageClient<'static> for AbCopies<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        let (mode, step) = match self.operation.get() {
+            Operation::Copies(mode, step) => (mode, step),
+            _ => return,
+        };
+        match step {
+            Step::Header(slot) => {
+                let mut headers = self.headers.get();
+                headers[slot] = self.parse(slot, &buffer[..HEADER_SIZE]);
+                self.headers.set(headers);
+                if slot == 0 {
+                    self.operation.set(Operation::Copies(mode, Step::Header(1)));
+                    let ret = self
+                        .storage
+                        .read(buffer, self.slots.get()[1].0, HEADER_SIZE);
+                    if ret != ReturnCode::SUCCESS {
+                        self.failed(mode, ret);
+                    }
+                } else {
+                    match self.candidate() {
+                        Some(slot) => self.check(mode, slot, 0, Crc32::new(), buffer),
+                        None => self.located(mode, None, buffer),
+                    }
+                }
+            }
+            Step::Check { slot, offset, crc } => {
+                if let Mode::Read(limit) = mode {
+                    if offset < limit {
+                        let deliver = cmp::min(length, limit - offset);
+                        self.client
+                            .map(|client| client.deliver(offset, &buffer[..deliver]));
+                    }
+                }
+                let crc = crc.update(&buffer[..length]);
+                self.check(mode, slot, offset + length, crc, buffer);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.operation.get() {
+            Operation::Write => {
+                self.operation.set(Operation::Idle);
+                self.client
+                    .map(move |client| client.write_done(buffer, length));
+            }
+            Operation::Copies(
+                Mode::Update(total),
+                Step::Data {
+                    slot,
+                    sequence,
+                    offset,
+                    crc,
+                },
+            ) => self.write_data(total, slot, sequence, offset + length, crc, buffer),
+            Operation::Copies(_, Step::Commit(copy)) => {
+                self.operation.set(Operation::Idle);
+                self.client
+                    .map(move |client| client.update_done(Some(buffer), Ok(copy)));
+            }
+            _ => {}
+        }
+    }
+}
@@ -9,6 +9,13 @@
 //! ensure that there is room to write to. This should be accomplished by
 //! declaring `const` buffers.
 //!
+//! Apps with two writeable flash regions in their TBF header can also use
+//! them as the two slots of a `capsules::ab_copies::AbCopies`. Updates are
+//! then written to the slot that doesn't hold the current copy, and reads
+//! return the last complete copy, so a reset in the middle of an update
+//! doesn't corrupt the data. The callback for an update only comes once the
+//! new copy is committed.
+//!
 //! Usage
 //! -----
 //!
@@ -16,39 +23,63 @@
 //! # use kernel::static_init;
 //!
 //! pub static mut APP_FLASH_BUFFER: [u8; 512] = [0; 512];
+//! let ab_copies = static_init!(
+//!     capsules::ab_copies::AbCopies<'static>,
+//!     capsules::ab_copies::AbCopies::new(nv_to_page));
+//! nv_to_page.set_client(ab_copies);
 //! let app_flash = static_init!(
 //!     capsules::app_flash_driver::AppFlash<'static>,
-//!     capsules::app_flash_driver::AppFlash::new(nv_to_page,
+//!     capsules::app_flash_driver::AppFlash::new(ab_copies,
 //!         board_kernel.create_grant(&grant_cap), &mut APP_FLASH_BUFFER));
+//! ab_copies.set_client(app_flash);
 //! ```
 
 use core::cmp;
 use kernel::common::cells::{OptionalCell, TakeCell};
-use kernel::hil;
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
+use crate::ab_copies::{self, AbCopies, Slots};
+
 /// Syscall driver number.
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::AppFlash as usize;
 
+#[derive(Clone, Copy, PartialEq)]
+enum UserOperation {
+    /// Write the `allow` 0 buffer at this address
+    Write(usize),
+    /// Commit a new copy of this length
+    Update(usize),
+    /// Read the current copy
+    Read,
+}
+
 #[derive(Default)]
 pub struct App {
     callback: Option<Callback>,
     buffer: Option<AppSlice<Shared, u8>>,
-    pending_command: bool,
-    flash_address: usize,
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    pending_command: Option<UserOperation>,
 }
 
 pub struct AppFlash<'a> {
-    driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    driver: &'a AbCopies<'a>,
     apps: Grant<App>,
     current_app: OptionalCell<AppId>,
     buffer: TakeCell<'static, [u8]>,
 }
 
+/// The first two writeable flash regions of an app.
+fn slots(appid: AppId) -> Option<Slots> {
+    Some([
+        appid.get_writeable_flash_region(0)?,
+        appid.get_writeable_flash_region(1)?,
+    ])
+}
+
 impl<'a> AppFlash<'a> {
     pub fn new(
-        driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        driver: &'a AbCopies<'a>,
         grant: Grant<App>,
         buffer: &'static mut [u8],
     ) -> AppFlash<'a> {
@@ -63,63 +94,106 @@ impl<'a> AppFlash<'a> {
     // Check to see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending command
     // completes.
-    fn enqueue_write(&self, flash_address: usize, appid: AppId) -> ReturnCode {
+    fn enqueue_command(&self, operation: UserOperation, appid: AppId) -> ReturnCode {
         self.apps
             .enter(appid, |app, _| {
-                // Check that this is a valid range in the app's flash.
-                let flash_length = app.buffer.as_mut().map_or(0, |app_buffer| app_buffer.len());
-                let (app_flash_start, app_flash_end) = appid.get_editable_flash_range();
-                if flash_address < app_flash_start
-                    || flash_address >= app_flash_end
-                    || flash_address + flash_length >= app_flash_end
-                {
-                    return ReturnCode::EINVAL;
+                match operation {
+                    UserOperation::Write(flash_address) => {
+                        // Check that this is a valid range in the app's flash.
+                        let flash_length =
+                            app.buffer.as_mut().map_or(0, |app_buffer| app_buffer.len());
+                        let (app_flash_start, app_flash_end) = appid.get_editable_flash_range();
+                        if flash_address < app_flash_start
+                            || flash_address >= app_flash_end
+                            || flash_address + flash_length >= app_flash_end
+                        {
+                            return ReturnCode::EINVAL;
+                        }
+                    }
+                    UserOperation::Update(length) => {
+                        let capacity = match slots(appid).and_then(AbCopies::capacity) {
+                            Some(capacity) => capacity,
+                            None => return ReturnCode::ENOSUPPORT,
+                        };
+                        let app_length = app.buffer.as_ref().map_or(0, |buffer| buffer.len());
+                        if length > capacity {
+                            return ReturnCode::ESIZE;
+                        } else if length > app_length {
+                            return ReturnCode::EINVAL;
+                        }
+                    }
+                    UserOperation::Read => {
+                        if slots(appid).and_then(AbCopies::capacity).is_none() {
+                            return ReturnCode::ENOSUPPORT;
+                        }
+                    }
                 }
 
                 if self.current_app.is_none() {
-                    self.current_app.set(appid);
-
-                    app.buffer
-                        .as_mut()
-                        .map_or(ReturnCode::ERESERVE, |app_buffer| {
-                            // Copy contents to internal buffer and write it.
-                            self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
-                                let length = cmp::min(buffer.len(), app_buffer.len());
-                                let d = &mut app_buffer.as_mut()[0..length];
-                                for (i, c) in buffer.as_mut()[0..length].iter_mut().enumerate() {
-                                    *c = d[i];
-                                }
-
-                                self.driver.write(buffer, flash_address, length)
-                            })
-                        })
+                    let ret = self.start_command(operation, appid, app);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                    }
+                    ret
                 } else {
                     // Queue this request for later.
-                    if app.pending_command == true {
+                    if app.pending_command.is_some() {
                         ReturnCode::ENOMEM
                     } else {
-                        app.pending_command = true;
-                        app.flash_address = flash_address;
+                        app.pending_command = Some(operation);
                         ReturnCode::SUCCESS
                     }
                 }
             })
             .unwrap_or_else(|err| err.into())
     }
-}
-
-impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for AppFlash<'_> {
-    fn read_done(&self, _buffer: &'static mut [u8], _length: usize) {}
 
-    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
-        // Put our write buffer back.
-        self.buffer.replace(buffer);
+    fn start_command(&self, operation: UserOperation, appid: AppId, app: &mut App) -> ReturnCode {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::ERESERVE,
+        };
+        let result = match operation {
+            UserOperation::Write(flash_address) => {
+                let app_buffer = match app.buffer.as_mut() {
+                    Some(app_buffer) => app_buffer,
+                    None => {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::ERESERVE;
+                    }
+                };
+                // Copy contents to internal buffer and write it.
+                let length = cmp::min(buffer.len(), app_buffer.len());
+                buffer[0..length].copy_from_slice(&app_buffer.as_ref()[0..length]);
+                return self.driver.write(buffer, flash_address, length);
+            }
+            UserOperation::Update(length) => match slots(appid) {
+                Some(slots) => self.driver.update(slots, length, buffer),
+                None => Err((ReturnCode::ENOSUPPORT, Some(buffer))),
+            },
+            UserOperation::Read => {
+                let length = app.read_buffer.as_ref().map_or(0, |buffer| buffer.len());
+                match slots(appid) {
+                    Some(slots) => self.driver.read(slots, length, buffer),
+                    None => Err((ReturnCode::ENOSUPPORT, Some(buffer))),
+                }
+            }
+        };
+        match result {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((e, buffer)) => {
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                e
+            }
+        }
+    }
 
-        // Notify the current application that the command finished.
+    /// Notify the current application and start the next queued command.
+    fn complete_command(&self, r0: usize, r1: usize, r2: usize) {
         self.current_app.take().map(|appid| {
             let _ = self.apps.enter(appid, |app, _| {
                 app.callback.map(|mut cb| {
-                    cb.schedule(0, 0, 0);
+                    cb.schedule(r0, r1, r2);
                 });
             });
         });
@@ -127,68 +201,127 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for AppFlash<'_
         // Check if there are any pending events.
         for cntr in self.apps.iter() {
             let started_command = cntr.enter(|app, _| {
-                if app.pending_command {
-                    app.pending_command = false;
-                    self.current_app.set(app.appid());
-                    let flash_address = app.flash_address;
-
-                    app.buffer.as_mut().map_or(false, |app_buffer| {
-                        self.buffer.take().map_or(false, |buffer| {
-                            if app_buffer.len() != 512 {
-                                false
-                            } else {
-                                // Copy contents to internal buffer and write it.
-                                let length = cmp::min(buffer.len(), app_buffer.len());
-                                let d = &mut app_buffer.as_mut()[0..length];
-                                for (i, c) in buffer.as_mut()[0..length].iter_mut().enumerate() {
-                                    *c = d[i];
-                                }
-
-                                self.driver.write(buffer, flash_address, length)
-                                    == ReturnCode::SUCCESS
-                            }
-                        })
-                    })
-                } else {
-                    false
-                }
+                let appid = app.appid();
+                app.pending_command.take().map_or(false, |operation| {
+                    let ret = self.start_command(operation, appid, app);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                        true
+                    } else {
+                        app.callback.map(|mut cb| {
+                            cb.schedule(usize::from(ret), 0, 0);
+                        });
+                        false
+                    }
+                })
             });
             if started_command {
                 break;
             }
         }
     }
+
+    fn copy_done(
+        &self,
+        buffer: Option<&'static mut [u8]>,
+        result: Result<ab_copies::Copy, ReturnCode>,
+    ) {
+        buffer.map(|buffer| self.buffer.replace(buffer));
+        match result {
+            Ok(copy) => self.complete_command(
+                usize::from(ReturnCode::SUCCESS),
+                copy.length,
+                copy.sequence as usize,
+            ),
+            Err(e) => self.complete_command(usize::from(e), 0, 0),
+        }
+    }
+}
+
+impl ab_copies::Client for AppFlash<'_> {
+    fn fill(&self, offset: usize, chunk: &mut [u8]) {
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.buffer.as_ref().map(|app_buffer| {
+                    let data = app_buffer.as_ref().get(offset..).unwrap_or(&[]);
+                    let length = cmp::min(chunk.len(), data.len());
+                    chunk[..length].copy_from_slice(&data[..length]);
+                    chunk[length..].iter_mut().for_each(|b| *b = 0);
+                });
+            });
+        });
+    }
+
+    fn deliver(&self, offset: usize, chunk: &[u8]) {
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.read_buffer.as_mut().map(|read_buffer| {
+                    let data = read_buffer.as_mut().get_mut(offset..).unwrap_or(&mut []);
+                    let length = cmp::min(chunk.len(), data.len());
+                    data[..length].copy_from_slice(&chunk[..length]);
+                });
+            });
+        });
+    }
+
+    fn read_done(
+        &self,
+        buffer: Option<&'static mut [u8]>,
+        result: Result<ab_copies::Copy, ReturnCode>,
+    ) {
+        self.copy_done(buffer, result);
+    }
+
+    fn update_done(
+        &self,
+        buffer: Option<&'static mut [u8]>,
+        result: Result<ab_copies::Copy, ReturnCode>,
+    ) {
+        self.copy_done(buffer, result);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        // Put our write buffer back.
+        self.buffer.replace(buffer);
+
+        // Notify the current application that the command finished.
+        self.complete_command(0, 0, 0);
+    }
 }
 
 impl Driver for AppFlash<'_> {
-    /// Setup buffer to write from.
+    /// Setup buffers.
     ///
     /// ### `allow_num`
     ///
-    /// - `0`: Set write buffer. This entire buffer will be written to flash.
+    /// - `0`: Set write buffer. This entire buffer will be written to flash,
+    ///        or the start of it is written as a new copy.
+    /// - `1`: Set the buffer the current copy is read into.
     fn allow(
         &self,
         appid: AppId,
         allow_num: usize,
         slice: Option<AppSlice<Shared, u8>>,
     ) -> ReturnCode {
-        match allow_num {
-            0 => self
-                .apps
-                .enter(appid, |app, _| {
-                    app.buffer = slice;
-                    ReturnCode::SUCCESS
-                })
-                .unwrap_or_else(|err| err.into()),
-            _ => ReturnCode::ENOSUPPORT,
-        }
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.buffer = slice,
+                    1 => app.read_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
     }
 
     /// Setup callbacks.
     ///
     /// ### `subscribe_num`
     ///
-    /// - `0`: Set a write_done callback.
+    /// - `0`: Set a write_done callback. For copies, the callback receives
+    ///        the `ReturnCode` of the operation, the length of the copy and
+    ///        its sequence number.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -213,6 +346,11 @@ impl Driver for AppFlash<'_> {
     ///
     /// - `0`: Driver check.
     /// - `1`: Write the memory from the `allow` buffer to the address in flash.
+    /// - `2`: Commit the first `arg1` bytes of the `allow` 0 buffer as a new
+    ///        copy in the app's first two writeable flash regions.
+    /// - `3`: Read the last complete copy into the `allow` 1 buffer.
+    /// - `4`: Get the largest copy that fits in the app's first two writeable
+    ///        flash regions.
     fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         match command_num {
             0 =>
@@ -224,9 +362,17 @@ impl Driver for AppFlash<'_> {
             // Write to flash from the allowed buffer.
             1 => {
                 let flash_address = arg1;
-                self.enqueue_write(flash_address, appid)
+                self.enqueue_command(UserOperation::Write(flash_address), appid)
             }
 
+            2 => self.enqueue_command(UserOperation::Update(arg1), appid),
+            3 => self.enqueue_command(UserOperation::Read, appid),
+            4 => slots(appid)
+                .and_then(AbCopies::capacity)
+                .map_or(ReturnCode::ENOSUPPORT, |capacity| {
+                    ReturnCode::SuccessWithValue { value: capacity }
+                }),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
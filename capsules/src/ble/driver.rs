@@ -0,0 +1,400 @@
+//! BLE GATT server userspace interface.
+//!
+//! Lets processes add services and characteristics to the shared
+//! `GattServer`, advertise so that a central can connect, and serve the
+//! values of their characteristics. Each process can add up to
+//! `MAX_CHARACTERISTICS` characteristics. The value of a characteristic is
+//! the buffer the process shares for it: reads are answered by the kernel
+//! from that buffer, writes are copied into it, and the process is told
+//! about both. Services can only be added while no central is connected.
+//!
+//! ### Allow system call
+//!
+//! * 0: UUID of the next service or characteristic to add, 2 or 16 bytes in
+//!      little-endian order
+//! * 1: Advertising data, at most 31 bytes of AD structures
+//! * 16 + n: Value of characteristic n
+//!
+//! ### Subscribe system call
+//!
+//! * 0: Events, called with the event, a characteristic and an argument:
+//!   * 0 connected
+//!   * 1 disconnected, with the reason
+//!   * 2 characteristic read
+//!   * 3 characteristic written, with the length of the new value
+//!   * 4 notifications of the characteristic enabled (1) or disabled (0)
+//!
+//! ### Command system call
+//!
+//! * 0: Driver check
+//! * 1: Add a primary service with the UUID in buffer 0, whose length is the
+//!      first argument. Returns the service's handle.
+//! * 2: Add a characteristic with the UUID in buffer 0 to the last service.
+//!      The first argument holds the properties and the second the UUID
+//!      length. Returns the characteristic number n.
+//! * 3: Set the length of the value of characteristic n (first argument) to
+//!      the second argument, and notify the central if it subscribed.
+//! * 4: Start advertising with the data in buffer 1, at the interval in ms
+//!      given by the first argument
+//! * 5: Stop advertising
+//! * 6: Disconnect
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gatt_driver = static_init!(
+//!     capsules::ble::driver::GattDriver<'static>,
+//!     capsules::ble::driver::GattDriver::new(
+//!         link_layer,
+//!         gatt,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! gatt.set_client(gatt_driver);
+//! ```
+
+use crate::ble::gatt::{error, properties, GattServer, GattServerClient, Uuid};
+use crate::ble::link_layer::Link;
+use core::cmp;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleGatt as usize;
+
+/// Number of characteristics each process can add.
+pub const MAX_CHARACTERISTICS: usize = 4;
+const VALUE_ALLOW_BASE: usize = 16;
+
+mod event {
+    pub const CONNECTED: usize = 0;
+    pub const DISCONNECTED: usize = 1;
+    pub const READ: usize = 2;
+    pub const WRITTEN: usize = 3;
+    pub const NOTIFICATIONS: usize = 4;
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    uuid: Option<AppSlice<Shared, u8>>,
+    adv_data: Option<AppSlice<Shared, u8>>,
+    values: [Option<AppSlice<Shared, u8>>; MAX_CHARACTERISTICS],
+    value_lens: [usize; MAX_CHARACTERISTICS],
+    /// Handles of the characteristics' values.
+    handles: [u16; MAX_CHARACTERISTICS],
+    characteristics: usize,
+    /// Notifications that could not be queued yet.
+    notify_pending: [bool; MAX_CHARACTERISTICS],
+}
+
+impl App {
+    fn uuid(&self, len: usize) -> Option<Uuid> {
+        self.uuid
+            .as_ref()
+            .filter(|uuid| uuid.len() >= len)
+            .and_then(|uuid| Uuid::from_slice(&uuid.as_ref()[..len]))
+    }
+
+    fn schedule(&mut self, event: usize, characteristic: usize, arg: usize) {
+        self.callback
+            .map(|mut cb| cb.schedule(event, characteristic, arg));
+    }
+}
+
+pub struct GattDriver<'a> {
+    link: &'a dyn Link<'a>,
+    gatt: &'a GattServer<'a>,
+    apps: Grant<App>,
+}
+
+impl<'a> GattDriver<'a> {
+    pub fn new(
+        link: &'a dyn Link<'a>,
+        gatt: &'a GattServer<'a>,
+        grant: Grant<App>,
+    ) -> GattDriver<'a> {
+        GattDriver {
+            link: link,
+            gatt: gatt,
+            apps: grant,
+        }
+    }
+
+    #[inline]
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    // Runs `closure` on the app owning the characteristic with `key` and the
+    // characteristic's number.
+    fn with_characteristic<F, R>(&self, key: usize, closure: F) -> Option<R>
+    where
+        F: FnOnce(&mut App, usize) -> R,
+        R: Copy,
+    {
+        let id = key / MAX_CHARACTERISTICS;
+        let n = key % MAX_CHARACTERISTICS;
+        let mut closure = Some(closure);
+        let mut result = None;
+        for app in self.apps.iter() {
+            result = result.or_else(|| {
+                app.enter(|app, _| {
+                    if app.appid().id() == id && n < app.characteristics {
+                        closure.take().map(|closure| closure(app, n))
+                    } else {
+                        None
+                    }
+                })
+            });
+        }
+        result
+    }
+
+    fn add_characteristic(&self, appid: AppId, properties: u8, uuid_len: usize) -> ReturnCode {
+        let uuid = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.characteristics == MAX_CHARACTERISTICS {
+                    Err(ReturnCode::ENOMEM)
+                } else {
+                    app.uuid(uuid_len)
+                        .map(|uuid| (uuid, app.characteristics))
+                        .ok_or(ReturnCode::EINVAL)
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        let (uuid, n) = match uuid {
+            Ok(uuid) => uuid,
+            Err(rc) => return rc,
+        };
+        let key = appid.id() * MAX_CHARACTERISTICS + n;
+        match self.gatt.add_characteristic(uuid, properties, key) {
+            Ok(handle) => self.do_with_app(appid, |app| {
+                app.handles[n] = handle;
+                app.characteristics += 1;
+                ReturnCode::SuccessWithValue { value: n }
+            }),
+            Err(rc) => rc,
+        }
+    }
+
+    /// Sends the notification of characteristic `n` of the app, or marks it
+    /// pending if there is no space.
+    fn notify(&self, appid: AppId, n: usize) -> ReturnCode {
+        let handle = self.apps.enter(appid, |app, _| app.handles[n]).unwrap_or(0);
+        let rc = self.gatt.notify(handle);
+        if rc == ReturnCode::EBUSY {
+            self.do_with_app(appid, |app| {
+                app.notify_pending[n] = true;
+                ReturnCode::SUCCESS
+            })
+        } else if rc == ReturnCode::EOFF {
+            // No central subscribed.
+            ReturnCode::SUCCESS
+        } else {
+            rc
+        }
+    }
+
+    fn start_advertising(&self, app: &mut App, interval_ms: u32) -> ReturnCode {
+        let rc = app.adv_data.as_ref().map_or(ReturnCode::SUCCESS, |data| {
+            self.link.set_advertising_data(data.as_ref())
+        });
+        if rc != ReturnCode::SUCCESS {
+            return rc;
+        }
+        self.link.start_advertising(interval_ms)
+    }
+}
+
+impl<'a> GattServerClient for GattDriver<'a> {
+    fn read(&self, key: usize, offset: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        self.with_characteristic(key, |app, n| {
+            let result = match app.values[n] {
+                Some(ref value) => {
+                    let len = cmp::min(app.value_lens[n], value.len());
+                    if offset > len {
+                        Err(error::INVALID_OFFSET)
+                    } else {
+                        let copied = cmp::min(len - offset, buf.len());
+                        buf[..copied].copy_from_slice(&value.as_ref()[offset..offset + copied]);
+                        Ok(copied)
+                    }
+                }
+                None => Ok(0),
+            };
+            if result.is_ok() {
+                app.schedule(event::READ, n, 0);
+            }
+            result
+        })
+        .unwrap_or(Err(error::UNLIKELY_ERROR))
+    }
+
+    fn write(&self, key: usize, value: &[u8]) -> Result<(), u8> {
+        self.with_characteristic(key, |app, n| match app.values[n] {
+            Some(ref mut buffer) if buffer.len() >= value.len() => {
+                buffer.as_mut()[..value.len()].copy_from_slice(value);
+                app.value_lens[n] = value.len();
+                app.schedule(event::WRITTEN, n, value.len());
+                Ok(())
+            }
+            _ => Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH),
+        })
+        .unwrap_or(Err(error::UNLIKELY_ERROR))
+    }
+
+    fn notifications_changed(&self, key: usize, enabled: bool) {
+        self.with_characteristic(key, |app, n| {
+            app.schedule(event::NOTIFICATIONS, n, enabled as usize);
+        });
+    }
+
+    fn connected(&self) {
+        self.apps.each(|app| app.schedule(event::CONNECTED, 0, 0));
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.apps.each(|app| {
+            app.notify_pending = [false; MAX_CHARACTERISTICS];
+            app.schedule(event::DISCONNECTED, 0, reason as usize);
+        });
+    }
+
+    fn notify_ready(&self) {
+        // Look for the first pending notification outside of the grant, as
+        // the server reads the value back through `read`.
+        let mut pending = None;
+        for app in self.apps.iter() {
+            pending = pending.or_else(|| {
+                app.enter(|app, _| {
+                    let n = app.notify_pending.iter().position(|&pending| pending)?;
+                    app.notify_pending[n] = false;
+                    Some((app.appid(), n))
+                })
+            });
+        }
+        if let Some((appid, n)) = pending {
+            self.notify(appid, n);
+        }
+    }
+}
+
+impl<'a> Driver for GattDriver<'a> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self.do_with_app(appid, |app| {
+                app.uuid = slice;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(appid, |app| {
+                app.adv_data = slice;
+                ReturnCode::SUCCESS
+            }),
+            n if n >= VALUE_ALLOW_BASE && n < VALUE_ALLOW_BASE + MAX_CHARACTERISTICS => self
+                .do_with_app(appid, |app| {
+                    let n = n - VALUE_ALLOW_BASE;
+                    app.value_lens[n] = slice.as_ref().map_or(0, |slice| slice.len());
+                    app.values[n] = slice;
+                    ReturnCode::SUCCESS
+                }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(appid, |app| {
+                app.callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            // Add a service
+            1 => {
+                let uuid = self
+                    .apps
+                    .enter(appid, |app, _| app.uuid(arg1))
+                    .unwrap_or(None);
+                match uuid {
+                    Some(uuid) => match self.gatt.add_service(uuid) {
+                        Ok(handle) => ReturnCode::SuccessWithValue {
+                            value: handle as usize,
+                        },
+                        Err(rc) => rc,
+                    },
+                    None => ReturnCode::EINVAL,
+                }
+            }
+
+            // Add a characteristic
+            2 => {
+                let properties = arg1 as u8;
+                let supported = properties::READ
+                    | properties::WRITE
+                    | properties::WRITE_WITHOUT_RESPONSE
+                    | properties::NOTIFY;
+                if arg1 > 0xff || properties & !supported != 0 {
+                    return ReturnCode::EINVAL;
+                }
+                self.add_characteristic(appid, properties, arg2)
+            }
+
+            // Update a value
+            3 => {
+                let rc = self.do_with_app(appid, |app| {
+                    if arg1 >= app.characteristics {
+                        return ReturnCode::EINVAL;
+                    }
+                    match app.values[arg1] {
+                        Some(ref value) if arg2 <= value.len() => {
+                            app.value_lens[arg1] = arg2;
+                            ReturnCode::SUCCESS
+                        }
+                        _ => ReturnCode::ESIZE,
+                    }
+                });
+                if rc == ReturnCode::SUCCESS {
+                    self.notify(appid, arg1)
+                } else {
+                    rc
+                }
+            }
+
+            // Start advertising
+            4 => self.do_with_app(appid, |app| self.start_advertising(app, arg1 as u32)),
+
+            // Stop advertising
+            5 => self.link.stop_advertising(),
+
+            // Disconnect
+            6 => self.link.disconnect(),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
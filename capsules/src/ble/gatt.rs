@@ -0,0 +1,671 @@
+//! A minimal GATT server over the Attribute Protocol.
+//!
+//! `GattServer` holds the attribute database and answers the ATT requests a
+//! client needs to discover services and characteristics and to read, write
+//! and subscribe to characteristic values: Exchange MTU, Find Information,
+//! Find By Type Value, Read By Type, Read, Read Blob, Read By Group Type,
+//! Write Request and Write Command. Values are sent with Handle Value
+//! Notifications. Other requests are answered with "Request Not Supported".
+//!
+//! The database starts with the mandatory Generic Access service, holding the
+//! device name, and an empty Generic Attribute service. Services added later
+//! are primary services. Characteristic values are either static, or owned by
+//! the `GattServerClient` and identified by a key the client chooses when it
+//! adds the characteristic. Characteristics that can notify get a Client
+//! Characteristic Configuration descriptor, which is cleared on every new
+//! connection as bonding is not supported.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gatt = static_init!(
+//!     capsules::ble::gatt::GattServer<'static>,
+//!     capsules::ble::gatt::GattServer::new(l2cap, b"Tock")
+//! );
+//! l2cap.set_client(gatt);
+//! gatt.set_client(gatt_driver);
+//! ```
+
+use crate::ble::l2cap::{L2cap, L2capClient, ATT_CID, ATT_MTU};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::ReturnCode;
+
+/// Number of attributes in the database.
+pub const MAX_ATTRIBUTES: usize = 40;
+
+/// Characteristic properties.
+pub mod properties {
+    pub const READ: u8 = 0x02;
+    pub const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+    pub const WRITE: u8 = 0x08;
+    pub const NOTIFY: u8 = 0x10;
+}
+
+/// ATT error codes, as returned by a `GattServerClient`.
+pub mod error {
+    pub const INVALID_HANDLE: u8 = 0x01;
+    pub const READ_NOT_PERMITTED: u8 = 0x02;
+    pub const WRITE_NOT_PERMITTED: u8 = 0x03;
+    pub const INVALID_PDU: u8 = 0x04;
+    pub const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+    pub const INVALID_OFFSET: u8 = 0x07;
+    pub const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+    pub const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+    pub const UNLIKELY_ERROR: u8 = 0x0e;
+    pub const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+}
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4.8
+mod opcode {
+    pub const ERROR_RSP: u8 = 0x01;
+    pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+    pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+    pub const FIND_INFORMATION_REQ: u8 = 0x04;
+    pub const FIND_INFORMATION_RSP: u8 = 0x05;
+    pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+    pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+    pub const READ_BY_TYPE_REQ: u8 = 0x08;
+    pub const READ_BY_TYPE_RSP: u8 = 0x09;
+    pub const READ_REQ: u8 = 0x0a;
+    pub const READ_RSP: u8 = 0x0b;
+    pub const READ_BLOB_REQ: u8 = 0x0c;
+    pub const READ_BLOB_RSP: u8 = 0x0d;
+    pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+    pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+    pub const WRITE_REQ: u8 = 0x12;
+    pub const WRITE_RSP: u8 = 0x13;
+    pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+    pub const WRITE_CMD: u8 = 0x52;
+    /// Set in the opcodes of commands, which never get a response.
+    pub const COMMAND_FLAG: u8 = 0x40;
+}
+
+const PRIMARY_SERVICE: Uuid = Uuid::Uuid16(0x2800);
+const SECONDARY_SERVICE: Uuid = Uuid::Uuid16(0x2801);
+const CHARACTERISTIC: Uuid = Uuid::Uuid16(0x2803);
+const CLIENT_CONFIGURATION: Uuid = Uuid::Uuid16(0x2902);
+const GENERIC_ACCESS: Uuid = Uuid::Uuid16(0x1800);
+const GENERIC_ATTRIBUTE: Uuid = Uuid::Uuid16(0x1801);
+const DEVICE_NAME: Uuid = Uuid::Uuid16(0x2a00);
+const APPEARANCE: Uuid = Uuid::Uuid16(0x2a01);
+/// Unknown appearance.
+static APPEARANCE_VALUE: [u8; 2] = [0, 0];
+
+/// The Bluetooth base UUID, 00000000-0000-1000-8000-00805F9B34FB, in the
+/// little-endian order used on air. 16-bit UUIDs replace bytes 12 and 13.
+const BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[derive(Copy, Clone, Debug)]
+pub enum Uuid {
+    Uuid16(u16),
+    /// A 128-bit UUID in little-endian byte order.
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Decodes a 2 or 16 byte little-endian UUID.
+    pub fn from_slice(bytes: &[u8]) -> Option<Uuid> {
+        match bytes.len() {
+            2 => Some(Uuid::Uuid16(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(bytes);
+                Some(Uuid::Uuid128(uuid))
+            }
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        match self {
+            Uuid::Uuid16(uuid) => buf[..2].copy_from_slice(&uuid.to_le_bytes()),
+            Uuid::Uuid128(uuid) => buf[..16].copy_from_slice(uuid),
+        }
+        self.len()
+    }
+
+    fn to_128(&self) -> [u8; 16] {
+        match self {
+            Uuid::Uuid16(uuid) => {
+                let mut full = BASE_UUID;
+                full[12..14].copy_from_slice(&uuid.to_le_bytes());
+                full
+            }
+            Uuid::Uuid128(uuid) => *uuid,
+        }
+    }
+}
+
+impl PartialEq for Uuid {
+    fn eq(&self, other: &Uuid) -> bool {
+        self.to_128() == other.to_128()
+    }
+}
+
+pub trait GattServerClient {
+    /// Copies the value of characteristic `key`, starting at `offset`, into
+    /// `buf`. Returns the number of bytes copied or an ATT error code.
+    fn read(&self, key: usize, offset: usize, buf: &mut [u8]) -> Result<usize, u8>;
+
+    /// Sets the value of characteristic `key`. Returns an ATT error code if
+    /// the value is not accepted.
+    fn write(&self, key: usize, value: &[u8]) -> Result<(), u8>;
+
+    /// The client enabled or disabled notifications of characteristic `key`.
+    fn notifications_changed(&self, key: usize, enabled: bool);
+
+    fn connected(&self);
+
+    fn disconnected(&self, reason: u8);
+
+    /// `notify` can be called again after it returned EBUSY.
+    fn notify_ready(&self);
+}
+
+#[derive(Copy, Clone)]
+enum Value {
+    Static(&'static [u8]),
+    Client(usize),
+}
+
+#[derive(Copy, Clone)]
+enum Attribute {
+    PrimaryService(Uuid),
+    /// A characteristic declaration. Its value follows it directly.
+    Characteristic(u8, Uuid),
+    Value(u8, Uuid, Value),
+    /// The configuration descriptor of the characteristic value before it.
+    ClientConfiguration(bool),
+}
+
+impl Attribute {
+    fn attribute_type(&self) -> Uuid {
+        match self {
+            Attribute::PrimaryService(_) => PRIMARY_SERVICE,
+            Attribute::Characteristic(_, _) => CHARACTERISTIC,
+            Attribute::Value(_, uuid, _) => *uuid,
+            Attribute::ClientConfiguration(_) => CLIENT_CONFIGURATION,
+        }
+    }
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+pub struct GattServer<'a> {
+    l2cap: &'a L2cap<'a>,
+    client: OptionalCell<&'a dyn GattServerClient>,
+    attributes: MapCell<[Option<Attribute>; MAX_ATTRIBUTES]>,
+    count: Cell<usize>,
+    /// A response that did not fit in the link layer's queue.
+    pending_response: MapCell<[u8; ATT_MTU]>,
+    pending_len: Cell<usize>,
+}
+
+impl<'a> GattServer<'a> {
+    /// Creates a server whose Generic Access service has the given device
+    /// name.
+    pub fn new(l2cap: &'a L2cap<'a>, device_name: &'static [u8]) -> GattServer<'a> {
+        let mut attributes = [None; MAX_ATTRIBUTES];
+        let gap = [
+            Attribute::PrimaryService(GENERIC_ACCESS),
+            Attribute::Characteristic(properties::READ, DEVICE_NAME),
+            Attribute::Value(properties::READ, DEVICE_NAME, Value::Static(device_name)),
+            Attribute::Characteristic(properties::READ, APPEARANCE),
+            Attribute::Value(
+                properties::READ,
+                APPEARANCE,
+                Value::Static(&APPEARANCE_VALUE),
+            ),
+            Attribute::PrimaryService(GENERIC_ATTRIBUTE),
+        ];
+        for (slot, attribute) in attributes.iter_mut().zip(gap.iter()) {
+            *slot = Some(*attribute);
+        }
+        GattServer {
+            l2cap: l2cap,
+            client: OptionalCell::empty(),
+            attributes: MapCell::new(attributes),
+            count: Cell::new(gap.len()),
+            pending_response: MapCell::new([0; ATT_MTU]),
+            pending_len: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn GattServerClient) {
+        self.client.set(client);
+    }
+
+    fn add(&self, new: &[Attribute]) -> Result<u16, ReturnCode> {
+        if self.l2cap.is_connected() {
+            // The connected client may have cached the database.
+            return Err(ReturnCode::EBUSY);
+        }
+        let count = self.count.get();
+        if count + new.len() > MAX_ATTRIBUTES {
+            return Err(ReturnCode::ENOMEM);
+        }
+        self.attributes.map(|attributes| {
+            for (slot, attribute) in attributes[count..].iter_mut().zip(new.iter()) {
+                *slot = Some(*attribute);
+            }
+        });
+        self.count.set(count + new.len());
+        Ok(count as u16 + 1)
+    }
+
+    /// Adds a primary service, returning its handle. The characteristics
+    /// added next belong to it.
+    pub fn add_service(&self, uuid: Uuid) -> Result<u16, ReturnCode> {
+        self.add(&[Attribute::PrimaryService(uuid)])
+    }
+
+    /// Adds a characteristic to the last service, whose value belongs to the
+    /// client and is identified by `key`. Returns the handle of the value.
+    pub fn add_characteristic(
+        &self,
+        uuid: Uuid,
+        properties: u8,
+        key: usize,
+    ) -> Result<u16, ReturnCode> {
+        let declaration = Attribute::Characteristic(properties, uuid);
+        let value = Attribute::Value(properties, uuid, Value::Client(key));
+        let handle = if properties & properties::NOTIFY != 0 {
+            self.add(&[declaration, value, Attribute::ClientConfiguration(false)])?
+        } else {
+            self.add(&[declaration, value])?
+        };
+        Ok(handle + 1)
+    }
+
+    /// Whether the connected client enabled notifications of the value with
+    /// handle `handle`.
+    pub fn notifications_enabled(&self, handle: u16) -> bool {
+        match self.attribute(handle + 1) {
+            Some(Attribute::ClientConfiguration(enabled)) => enabled,
+            _ => false,
+        }
+    }
+
+    /// Sends the value with handle `handle` in a notification. Returns EOFF
+    /// if the client did not enable notifications, and EBUSY if there is no
+    /// space to queue it, in which case `notify_ready` follows.
+    pub fn notify(&self, handle: u16) -> ReturnCode {
+        if !self.l2cap.is_connected() || !self.notifications_enabled(handle) {
+            return ReturnCode::EOFF;
+        }
+        if self.pending_len.get() > 0 {
+            return ReturnCode::EBUSY;
+        }
+        let mut pdu = [0; ATT_MTU];
+        pdu[0] = opcode::HANDLE_VALUE_NTF;
+        pdu[1..3].copy_from_slice(&handle.to_le_bytes());
+        match self.read_value(handle, 0, &mut pdu[3..]) {
+            Ok(len) => self.l2cap.send(ATT_CID, &pdu[..3 + len]),
+            Err(_) => ReturnCode::FAIL,
+        }
+    }
+
+    fn attribute(&self, handle: u16) -> Option<Attribute> {
+        if handle == 0 || handle as usize > self.count.get() {
+            return None;
+        }
+        self.attributes
+            .map_or(None, |attributes| attributes[handle as usize - 1])
+    }
+
+    fn set_attribute(&self, handle: u16, attribute: Attribute) {
+        self.attributes
+            .map(|attributes| attributes[handle as usize - 1] = Some(attribute));
+    }
+
+    /// The last handle of the service with handle `handle`.
+    fn group_end(&self, handle: u16) -> u16 {
+        let count = self.count.get() as u16;
+        (handle + 1..=count)
+            .find(|&next| match self.attribute(next) {
+                Some(Attribute::PrimaryService(_)) => true,
+                _ => false,
+            })
+            .map_or(count, |next| next - 1)
+    }
+
+    /// Copies the value of the attribute with handle `handle`, from
+    /// `offset`, into `buf`.
+    fn read_value(&self, handle: u16, offset: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        let mut value = [0; 19];
+        let len = match self.attribute(handle) {
+            None => return Err(error::INVALID_HANDLE),
+            Some(Attribute::PrimaryService(uuid)) => uuid.write(&mut value),
+            Some(Attribute::Characteristic(properties, uuid)) => {
+                value[0] = properties;
+                value[1..3].copy_from_slice(&(handle + 1).to_le_bytes());
+                3 + uuid.write(&mut value[3..])
+            }
+            Some(Attribute::Value(properties, _, value)) => {
+                if properties & properties::READ == 0 {
+                    return Err(error::READ_NOT_PERMITTED);
+                }
+                return match value {
+                    Value::Static(bytes) => {
+                        if offset > bytes.len() {
+                            return Err(error::INVALID_OFFSET);
+                        }
+                        let len = core::cmp::min(bytes.len() - offset, buf.len());
+                        buf[..len].copy_from_slice(&bytes[offset..offset + len]);
+                        Ok(len)
+                    }
+                    Value::Client(key) => {
+                        self.client.map_or(Err(error::UNLIKELY_ERROR), |client| {
+                            client.read(key, offset, buf)
+                        })
+                    }
+                };
+            }
+            Some(Attribute::ClientConfiguration(enabled)) => {
+                value[0] = enabled as u8;
+                2
+            }
+        };
+        if offset > len {
+            return Err(error::INVALID_OFFSET);
+        }
+        let copied = core::cmp::min(len - offset, buf.len());
+        buf[..copied].copy_from_slice(&value[offset..offset + copied]);
+        Ok(copied)
+    }
+
+    fn write_value(&self, handle: u16, value: &[u8], command: bool) -> Result<(), u8> {
+        let permission = if command {
+            properties::WRITE_WITHOUT_RESPONSE
+        } else {
+            properties::WRITE
+        };
+        match self.attribute(handle) {
+            None => Err(error::INVALID_HANDLE),
+            Some(Attribute::Value(properties, _, Value::Client(key)))
+                if properties & permission != 0 =>
+            {
+                self.client.map_or(Err(error::UNLIKELY_ERROR), |client| {
+                    client.write(key, value)
+                })
+            }
+            Some(Attribute::ClientConfiguration(_)) => {
+                if value.len() != 2 {
+                    return Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH);
+                }
+                let enabled = value[0] & 0x01 != 0;
+                self.set_attribute(handle, Attribute::ClientConfiguration(enabled));
+                if let Some(Attribute::Value(_, _, Value::Client(key))) = self.attribute(handle - 1)
+                {
+                    self.client
+                        .map(|client| client.notifications_changed(key, enabled));
+                }
+                Ok(())
+            }
+            Some(_) => Err(error::WRITE_NOT_PERMITTED),
+        }
+    }
+
+    /// Parses the handle range at the start of `request`.
+    fn handle_range(request: &[u8]) -> Result<(u16, u16), (u16, u8)> {
+        let start = read_u16(&request[1..]);
+        let end = read_u16(&request[3..]);
+        if start == 0 || start > end {
+            Err((start, error::INVALID_HANDLE))
+        } else {
+            Ok((start, end))
+        }
+    }
+
+    /// Handles `request`, writing the response into `rsp`. Returns the length
+    /// of the response, or the handle and error code of an error response.
+    fn handle_request(&self, request: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        let len = request.len();
+        let count = self.count.get() as u16;
+        match request[0] {
+            opcode::EXCHANGE_MTU_REQ if len == 3 => {
+                // Only the minimum MTU is supported.
+                rsp[0] = opcode::EXCHANGE_MTU_RSP;
+                rsp[1..3].copy_from_slice(&(ATT_MTU as u16).to_le_bytes());
+                Ok(3)
+            }
+            opcode::FIND_INFORMATION_REQ if len == 5 => {
+                let (start, end) = Self::handle_range(request)?;
+                rsp[0] = opcode::FIND_INFORMATION_RSP;
+                let mut used = 2;
+                let mut format_len = 0;
+                for handle in start..=core::cmp::min(end, count) {
+                    let uuid = match self.attribute(handle) {
+                        Some(attribute) => attribute.attribute_type(),
+                        None => continue,
+                    };
+                    // All entries must have the same UUID format.
+                    if format_len == 0 {
+                        format_len = uuid.len();
+                    }
+                    if uuid.len() != format_len || used + 2 + format_len > ATT_MTU {
+                        break;
+                    }
+                    rsp[used..used + 2].copy_from_slice(&handle.to_le_bytes());
+                    uuid.write(&mut rsp[used + 2..]);
+                    used += 2 + format_len;
+                }
+                if format_len == 0 {
+                    return Err((start, error::ATTRIBUTE_NOT_FOUND));
+                }
+                rsp[1] = if format_len == 2 { 0x01 } else { 0x02 };
+                Ok(used)
+            }
+            opcode::FIND_BY_TYPE_VALUE_REQ if len >= 7 => {
+                let (start, end) = Self::handle_range(request)?;
+                let attribute_type = Uuid::Uuid16(read_u16(&request[5..]));
+                let value = &request[7..];
+                rsp[0] = opcode::FIND_BY_TYPE_VALUE_RSP;
+                let mut used = 1;
+                for handle in start..=core::cmp::min(end, count) {
+                    match self.attribute(handle) {
+                        Some(attribute) if attribute.attribute_type() == attribute_type => {}
+                        _ => continue,
+                    }
+                    let mut found = [0; ATT_MTU];
+                    match self.read_value(handle, 0, &mut found) {
+                        Ok(found_len) if &found[..found_len] == value => {}
+                        _ => continue,
+                    }
+                    if used + 4 > ATT_MTU {
+                        break;
+                    }
+                    let group_end = if attribute_type == PRIMARY_SERVICE {
+                        self.group_end(handle)
+                    } else {
+                        handle
+                    };
+                    rsp[used..used + 2].copy_from_slice(&handle.to_le_bytes());
+                    rsp[used + 2..used + 4].copy_from_slice(&group_end.to_le_bytes());
+                    used += 4;
+                }
+                if used == 1 {
+                    return Err((start, error::ATTRIBUTE_NOT_FOUND));
+                }
+                Ok(used)
+            }
+            opcode::READ_BY_TYPE_REQ if len == 7 || len == 21 => {
+                let (start, end) = Self::handle_range(request)?;
+                let attribute_type =
+                    Uuid::from_slice(&request[5..]).ok_or((0, error::INVALID_PDU))?;
+                rsp[0] = opcode::READ_BY_TYPE_RSP;
+                let mut used = 2;
+                let mut entry_len = 0;
+                for handle in start..=core::cmp::min(end, count) {
+                    match self.attribute(handle) {
+                        Some(attribute) if attribute.attribute_type() == attribute_type => {}
+                        _ => continue,
+                    }
+                    let mut value = [0; ATT_MTU - 4];
+                    let value_len = match self.read_value(handle, 0, &mut value) {
+                        Ok(value_len) => value_len,
+                        Err(code) if entry_len == 0 => return Err((handle, code)),
+                        Err(_) => break,
+                    };
+                    if entry_len == 0 {
+                        entry_len = 2 + value_len;
+                    }
+                    if 2 + value_len != entry_len || used + entry_len > ATT_MTU {
+                        break;
+                    }
+                    rsp[used..used + 2].copy_from_slice(&handle.to_le_bytes());
+                    rsp[used + 2..used + entry_len].copy_from_slice(&value[..value_len]);
+                    used += entry_len;
+                }
+                if entry_len == 0 {
+                    return Err((start, error::ATTRIBUTE_NOT_FOUND));
+                }
+                rsp[1] = entry_len as u8;
+                Ok(used)
+            }
+            opcode::READ_REQ if len == 3 => {
+                let handle = read_u16(&request[1..]);
+                rsp[0] = opcode::READ_RSP;
+                let value_len = self
+                    .read_value(handle, 0, &mut rsp[1..ATT_MTU])
+                    .map_err(|code| (handle, code))?;
+                Ok(1 + value_len)
+            }
+            opcode::READ_BLOB_REQ if len == 5 => {
+                let handle = read_u16(&request[1..]);
+                let offset = read_u16(&request[3..]) as usize;
+                rsp[0] = opcode::READ_BLOB_RSP;
+                let value_len = self
+                    .read_value(handle, offset, &mut rsp[1..ATT_MTU])
+                    .map_err(|code| (handle, code))?;
+                Ok(1 + value_len)
+            }
+            opcode::READ_BY_GROUP_TYPE_REQ if len == 7 || len == 21 => {
+                let (start, end) = Self::handle_range(request)?;
+                let group_type = Uuid::from_slice(&request[5..]).ok_or((0, error::INVALID_PDU))?;
+                if group_type != PRIMARY_SERVICE && group_type != SECONDARY_SERVICE {
+                    return Err((start, error::UNSUPPORTED_GROUP_TYPE));
+                }
+                rsp[0] = opcode::READ_BY_GROUP_TYPE_RSP;
+                let mut used = 2;
+                let mut entry_len = 0;
+                for handle in start..=core::cmp::min(end, count) {
+                    let uuid = match self.attribute(handle) {
+                        Some(Attribute::PrimaryService(uuid)) if group_type == PRIMARY_SERVICE => {
+                            uuid
+                        }
+                        _ => continue,
+                    };
+                    if entry_len == 0 {
+                        entry_len = 4 + uuid.len();
+                    }
+                    if 4 + uuid.len() != entry_len || used + entry_len > ATT_MTU {
+                        break;
+                    }
+                    rsp[used..used + 2].copy_from_slice(&handle.to_le_bytes());
+                    rsp[used + 2..used + 4].copy_from_slice(&self.group_end(handle).to_le_bytes());
+                    uuid.write(&mut rsp[used + 4..]);
+                    used += entry_len;
+                }
+                if entry_len == 0 {
+                    return Err((start, error::ATTRIBUTE_NOT_FOUND));
+                }
+                rsp[1] = entry_len as u8;
+                Ok(used)
+            }
+            opcode::WRITE_REQ | opcode::WRITE_CMD if len >= 3 => {
+                let handle = read_u16(&request[1..]);
+                let command = request[0] == opcode::WRITE_CMD;
+                self.write_value(handle, &request[3..], command)
+                    .map_err(|code| (handle, code))?;
+                rsp[0] = opcode::WRITE_RSP;
+                Ok(1)
+            }
+            opcode::EXCHANGE_MTU_REQ
+            | opcode::FIND_INFORMATION_REQ
+            | opcode::FIND_BY_TYPE_VALUE_REQ
+            | opcode::READ_BY_TYPE_REQ
+            | opcode::READ_REQ
+            | opcode::READ_BLOB_REQ
+            | opcode::READ_BY_GROUP_TYPE_REQ
+            | opcode::WRITE_REQ => Err((0, error::INVALID_PDU)),
+            _ => Err((0, error::REQUEST_NOT_SUPPORTED)),
+        }
+    }
+
+    fn send_response(&self, rsp: &[u8]) {
+        if self.l2cap.send(ATT_CID, rsp) != ReturnCode::SUCCESS {
+            // Sent once the link layer has space.
+            self.pending_response
+                .map(|pending| pending[..rsp.len()].copy_from_slice(rsp));
+            self.pending_len.set(rsp.len());
+        }
+    }
+}
+
+impl<'a> L2capClient for GattServer<'a> {
+    fn connected(&self) {
+        self.pending_len.set(0);
+        for handle in 1..=self.count.get() as u16 {
+            if let Some(Attribute::ClientConfiguration(true)) = self.attribute(handle) {
+                self.set_attribute(handle, Attribute::ClientConfiguration(false));
+            }
+        }
+        self.client.map(|client| client.connected());
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.pending_len.set(0);
+        self.client.map(|client| client.disconnected(reason));
+    }
+
+    fn received(&self, pdu: &[u8]) {
+        let request_opcode = match pdu.first() {
+            Some(&request_opcode) => request_opcode,
+            None => return,
+        };
+        let mut rsp = [0; ATT_MTU];
+        match self.handle_request(pdu, &mut rsp) {
+            _ if request_opcode & opcode::COMMAND_FLAG != 0 => {
+                // Commands get no response, not even an error.
+            }
+            Ok(len) => self.send_response(&rsp[..len]),
+            Err((handle, code)) => {
+                rsp[0] = opcode::ERROR_RSP;
+                rsp[1] = request_opcode;
+                rsp[2..4].copy_from_slice(&handle.to_le_bytes());
+                rsp[4] = code;
+                self.send_response(&rsp[..5]);
+            }
+        }
+    }
+
+    fn send_ready(&self) {
+        let len = self.pending_len.get();
+        if len > 0 {
+            let mut rsp = [0; ATT_MTU];
+            self.pending_response
+                .map(|pending| rsp[..len].copy_from_slice(&pending[..len]));
+            if self.l2cap.send(ATT_CID, &rsp[..len]) != ReturnCode::SUCCESS {
+                return;
+            }
+            self.pending_len.set(0);
+        }
+        self.client.map(|client| client.notify_ready());
+    }
+}
@@ -0,0 +1,157 @@
+//! L2CAP for Bluetooth Low Energy, with the fixed channels only.
+//!
+//! `L2cap` reassembles the messages the link layer receives and hands those
+//! on the Attribute Protocol channel to its client. There is no support for
+//! connection-oriented channels: requests on the LE signaling channel are
+//! answered with Command Reject, and pairing requests on the Security Manager
+//! channel with Pairing Failed.
+//!
+//! The ATT MTU is fixed at its minimum of 23 bytes, so every message fits in
+//! a single data PDU and is never fragmented on transmission.
+
+use crate::ble::link_layer::{Link, LinkLayerClient, LLID_CONTINUATION, LLID_START};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::ReturnCode;
+
+/// Channel of the Attribute Protocol.
+pub const ATT_CID: u16 = 0x0004;
+const SIGNALING_CID: u16 = 0x0005;
+const SMP_CID: u16 = 0x0006;
+
+/// Largest message on the ATT channel.
+pub const ATT_MTU: usize = 23;
+const HEADER_LEN: usize = 4;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part A], section 4.1
+const COMMAND_REJECT: u8 = 0x01;
+const COMMAND_NOT_UNDERSTOOD: u16 = 0x0000;
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part H], section 3.5
+const PAIRING_REQUEST: u8 = 0x01;
+const PAIRING_FAILED: u8 = 0x05;
+const PAIRING_NOT_SUPPORTED: u8 = 0x05;
+
+pub trait L2capClient {
+    fn connected(&self);
+
+    fn disconnected(&self, reason: u8);
+
+    /// A message was received on the ATT channel.
+    fn received(&self, pdu: &[u8]);
+
+    /// Space is available again after `send` returned EBUSY.
+    fn send_ready(&self);
+}
+
+pub struct L2cap<'a> {
+    link: &'a dyn Link<'a>,
+    client: OptionalCell<&'a dyn L2capClient>,
+    rx_buffer: MapCell<[u8; HEADER_LEN + ATT_MTU]>,
+    /// Bytes of the message being reassembled received so far, or `None` if
+    /// the rest of the message is being discarded.
+    rx_len: Cell<Option<usize>>,
+}
+
+impl<'a> L2cap<'a> {
+    pub fn new(link: &'a dyn Link<'a>) -> L2cap<'a> {
+        L2cap {
+            link: link,
+            client: OptionalCell::empty(),
+            rx_buffer: MapCell::new([0; HEADER_LEN + ATT_MTU]),
+            rx_len: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn L2capClient) {
+        self.client.set(client);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.link.is_connected()
+    }
+
+    pub fn disconnect(&self) -> ReturnCode {
+        self.link.disconnect()
+    }
+
+    /// Sends `pdu`, of at most `ATT_MTU` bytes, on channel `cid`. Returns
+    /// EBUSY if the link layer's queue is full.
+    pub fn send(&self, cid: u16, pdu: &[u8]) -> ReturnCode {
+        if pdu.len() > ATT_MTU {
+            return ReturnCode::ESIZE;
+        }
+        let mut frame = [0; HEADER_LEN + ATT_MTU];
+        frame[0..2].copy_from_slice(&(pdu.len() as u16).to_le_bytes());
+        frame[2..4].copy_from_slice(&cid.to_le_bytes());
+        frame[HEADER_LEN..HEADER_LEN + pdu.len()].copy_from_slice(pdu);
+        self.link
+            .transmit(LLID_START, &frame[..HEADER_LEN + pdu.len()])
+    }
+
+    fn frame_received(&self, cid: u16, pdu: &[u8]) {
+        match cid {
+            ATT_CID => {
+                self.client.map(|client| client.received(pdu));
+            }
+            SIGNALING_CID if pdu.len() >= 4 && pdu[0] % 2 == 0 => {
+                // Requests have even codes.
+                let [reason_lo, reason_hi] = COMMAND_NOT_UNDERSTOOD.to_le_bytes();
+                self.send(
+                    SIGNALING_CID,
+                    &[COMMAND_REJECT, pdu[1], 2, 0, reason_lo, reason_hi],
+                );
+            }
+            SMP_CID if pdu.first() == Some(&PAIRING_REQUEST) => {
+                self.send(SMP_CID, &[PAIRING_FAILED, PAIRING_NOT_SUPPORTED]);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a> LinkLayerClient for L2cap<'a> {
+    fn connected(&self) {
+        self.rx_len.set(None);
+        self.client.map(|client| client.connected());
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.client.map(|client| client.disconnected(reason));
+    }
+
+    fn received(&self, llid: u8, payload: &[u8]) {
+        let received = match (llid, self.rx_len.get()) {
+            (LLID_START, _) => 0,
+            (LLID_CONTINUATION, Some(received)) => received,
+            _ => return,
+        };
+        let total = self.rx_buffer.map_or(None, |buffer| {
+            if received + payload.len() > buffer.len() {
+                return None;
+            }
+            buffer[received..received + payload.len()].copy_from_slice(payload);
+            Some(received + payload.len())
+        });
+        self.rx_len.set(total);
+        let total = match total {
+            Some(total) if total >= HEADER_LEN => total,
+            _ => return,
+        };
+
+        let mut frame = [0; HEADER_LEN + ATT_MTU];
+        self.rx_buffer.map(|buffer| frame = *buffer);
+        let len = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+        let cid = u16::from_le_bytes([frame[2], frame[3]]);
+        if HEADER_LEN + len > frame.len() {
+            // Larger than the MTU.
+            self.rx_len.set(None);
+        } else if total >= HEADER_LEN + len {
+            self.rx_len.set(None);
+            self.frame_received(cid, &frame[HEADER_LEN..HEADER_LEN + len]);
+        }
+    }
+
+    fn transmit_done(&self) {
+        self.client.map(|client| client.send_ready());
+    }
+}
@@ -0,0 +1,1021 @@
+//! Bluetooth Low Energy link layer, slave role.
+//!
+//! `LinkLayer` sends connectable undirected advertisements (ADV_IND), answers
+//! scan requests and accepts a CONNECT_IND from a master. Once connected it
+//! follows the master through connection events, hopping channels with
+//! channel selection algorithm #1, and carries data PDUs in both directions
+//! with the SN/NESN acknowledgement scheme.
+//!
+//! Connection events are timed with an alarm, normally a `VirtualMuxAlarm`:
+//! the receive window opens before the expected anchor point by the window
+//! widening that the master's and our sleep clock accuracies require, and
+//! the anchor is resynchronized on every packet received. The connection is
+//! dropped once no packet was received for the supervision timeout.
+//!
+//! The following LL control procedures are supported: connection update,
+//! channel map update, termination by either side, feature exchange,
+//! version exchange, LE ping and data length (with the default lengths).
+//! Encryption is not supported and is rejected. Unknown control PDUs are
+//! answered with LL_UNKNOWN_RSP.
+//!
+//! Each connection event consists of one exchange: the master's packet and
+//! our reply. Payloads are processed after the reply has been transmitted,
+//! so responses go out in a later event.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let link_layer = static_init!(
+//!     capsules::ble::link_layer::LinkLayer<'static, nrf52::ble_radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble::link_layer::LinkLayer::new(&nrf52::ble_radio::RADIO, ll_alarm)
+//! );
+//! ll_alarm.set_alarm_client(link_layer);
+//! kernel::hil::ble_advertising::BleConnectionDriver::set_connection_client(
+//!     &nrf52::ble_radio::RADIO,
+//!     link_layer,
+//! );
+//! link_layer.set_address([0xf0, 0x11, 0x22, 0x33, 0x44, 0xf5]);
+//! link_layer.set_client(l2cap);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::hil::ble_advertising::{self, BleConnectionDriver, RadioChannel, T_IFS_US};
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::ReturnCode;
+
+/// Maximum payload of a data channel PDU.
+pub const MAX_PAYLOAD_LEN: usize = 27;
+/// Maximum length of advertising data.
+pub const MAX_ADV_DATA_LEN: usize = 31;
+const PDU_LEN: usize = 2 + MAX_PAYLOAD_LEN;
+const ADDRESS_LEN: usize = 6;
+/// Number of data PDUs waiting to be acknowledged, one of which is reserved
+/// for control PDUs.
+const TX_QUEUE_LEN: usize = 4;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3
+const ADV_IND: u8 = 0b0000;
+const SCAN_REQ: u8 = 0b0011;
+const SCAN_RSP: u8 = 0b0100;
+const CONNECT_IND: u8 = 0b0101;
+const ADV_TYPE_MASK: u8 = 0x0f;
+const TXADD: u8 = 1 << 6;
+const RXADD: u8 = 1 << 7;
+const SCAN_REQ_LEN: usize = 2 * ADDRESS_LEN;
+const CONNECT_IND_LEN: usize = 34;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4
+/// LLID of a continuation fragment of an L2CAP message, or an empty PDU.
+pub const LLID_CONTINUATION: u8 = 0b01;
+/// LLID of the start of an L2CAP message.
+pub const LLID_START: u8 = 0b10;
+const LLID_CONTROL: u8 = 0b11;
+const LLID_MASK: u8 = 0b11;
+const NESN: u8 = 1 << 2;
+const SN: u8 = 1 << 3;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4.2
+const LL_CONNECTION_UPDATE_IND: u8 = 0x00;
+const LL_CHANNEL_MAP_IND: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_ENC_REQ: u8 = 0x03;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0c;
+const LL_REJECT_IND: u8 = 0x0d;
+const LL_SLAVE_FEATURE_REQ: u8 = 0x0e;
+const LL_PING_REQ: u8 = 0x12;
+const LL_PING_RSP: u8 = 0x13;
+const LL_LENGTH_REQ: u8 = 0x14;
+const LL_LENGTH_RSP: u8 = 0x15;
+
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part D], error codes used as
+/// disconnection reasons.
+pub mod reason {
+    pub const CONNECTION_TIMEOUT: u8 = 0x08;
+    pub const REMOTE_USER_TERMINATED: u8 = 0x13;
+    pub const LOCAL_HOST_TERMINATED: u8 = 0x16;
+    pub const UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1a;
+    pub const INSTANT_PASSED: u8 = 0x28;
+    pub const CONNECTION_FAILED_TO_BE_ESTABLISHED: u8 = 0x3e;
+}
+
+/// Version 4.2 of the specification.
+const VERSION: u8 = 0x08;
+/// No company identifier has been assigned.
+const COMPANY_ID: u16 = 0xffff;
+const SUBVERSION: u16 = 0;
+
+const NUM_DATA_CHANNELS: u8 = 37;
+const UNIT_US: u32 = 1250;
+/// Accuracy of our own sleep clock, in ppm.
+const SLAVE_SCA_PPM: u32 = 50;
+/// Sleep clock accuracy by the SCA field of CONNECT_IND.
+const MASTER_SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+/// Added to the receive window on both sides for the alarm's granularity and
+/// the radio's ramp-up.
+const WINDOW_MARGIN_US: u32 = 100;
+/// How long to listen for a request after an advertisement was sent, counted
+/// from the end of the advertisement.
+const REQUEST_WINDOW_US: u32 = T_IFS_US + 100;
+/// Range of the random delay added to each advertising interval.
+const ADV_DELAY_MAX_MS: u32 = 10;
+
+/// Time on air, in microseconds, of a PDU of `len` bytes: preamble, access
+/// address, PDU and CRC at 1 Mbit/s.
+fn air_time_us(len: usize) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+pub trait LinkLayerClient {
+    fn connected(&self);
+
+    /// The connection has ended, with one of the `reason` codes.
+    fn disconnected(&self, reason: u8);
+
+    /// A data PDU was received. `llid` is `LLID_START` or
+    /// `LLID_CONTINUATION`.
+    fn received(&self, llid: u8, payload: &[u8]);
+
+    /// A PDU passed to `transmit` was acknowledged, freeing space in the
+    /// transmit queue.
+    fn transmit_done(&self);
+}
+
+/// The link layer as used by the host layers.
+pub trait Link<'a> {
+    fn set_client(&self, client: &'a dyn LinkLayerClient);
+
+    /// See `LinkLayer::set_advertising_data`.
+    fn set_advertising_data(&self, data: &[u8]) -> ReturnCode;
+
+    /// See `LinkLayer::start_advertising`.
+    fn start_advertising(&self, interval_ms: u32) -> ReturnCode;
+
+    fn stop_advertising(&self) -> ReturnCode;
+
+    /// Queues a data PDU, as in `LinkLayer::transmit`.
+    fn transmit(&self, llid: u8, payload: &[u8]) -> ReturnCode;
+
+    /// Ends the connection.
+    fn disconnect(&self) -> ReturnCode;
+
+    fn is_connected(&self) -> bool;
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Waiting for the next advertising event.
+    AdvertisingIdle,
+    /// Advertised on the channel with this index and listening for a
+    /// request.
+    Advertising(u8),
+    /// Waiting for the next connection event.
+    ConnectionIdle,
+    /// Listening for the master's packet in a connection event.
+    ConnectionEvent,
+}
+
+#[derive(Copy, Clone)]
+struct ConnectionUpdate {
+    instant: u16,
+    window_size_us: u32,
+    window_offset_us: u32,
+    interval_us: u32,
+    timeout_us: u32,
+}
+
+#[derive(Copy, Clone)]
+struct Connection {
+    access_address: u32,
+    crc_init: u32,
+    interval_us: u32,
+    timeout_us: u32,
+    master_sca_ppm: u32,
+    channel_map: [u8; 5],
+    hop: u8,
+    /// Unmapped channel of the current event.
+    unmapped_channel: u8,
+    /// Channel of the current event.
+    channel: u8,
+    event_counter: u16,
+    sn: bool,
+    nesn: bool,
+    /// Whether the last PDU sent has not been acknowledged yet.
+    unacknowledged: bool,
+    /// Whether the last PDU sent was the one at the head of the transmit
+    /// queue, rather than an empty PDU.
+    head_sent: bool,
+    /// Length of the window in which the master may start the next event,
+    /// beyond the anchor point: the transmit window after connection set up
+    /// or a connection update.
+    window_us: u32,
+    /// Whether a packet has been received on this connection.
+    established: bool,
+    update: Option<ConnectionUpdate>,
+    channel_map_update: Option<(u16, [u8; 5])>,
+    version_sent: bool,
+    /// The reason to end the connection once the current event is over.
+    terminate: Option<u8>,
+}
+
+impl Connection {
+    fn window_widening_us(&self, since_sync_us: u32) -> u32 {
+        let ppm = (self.master_sca_ppm + SLAVE_SCA_PPM) as u64;
+        (ppm * since_sync_us as u64 / 1_000_000) as u32 + WINDOW_MARGIN_US
+    }
+
+    /// Advances to the next event's data channel, with channel selection
+    /// algorithm #1 (BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B],
+    /// section 4.5.8.2).
+    fn hop(&mut self) {
+        self.unmapped_channel = (self.unmapped_channel + self.hop) % NUM_DATA_CHANNELS;
+        self.channel = remap(&self.channel_map, self.unmapped_channel);
+    }
+}
+
+fn channel_used(map: &[u8; 5], channel: u8) -> bool {
+    map[channel as usize / 8] & (1 << (channel % 8)) != 0
+}
+
+fn used_channels(map: &[u8; 5]) -> u8 {
+    (0..NUM_DATA_CHANNELS)
+        .filter(|&channel| channel_used(map, channel))
+        .count() as u8
+}
+
+fn remap(map: &[u8; 5], unmapped: u8) -> u8 {
+    if channel_used(map, unmapped) {
+        return unmapped;
+    }
+    let index = unmapped % cmp::max(used_channels(map), 1);
+    (0..NUM_DATA_CHANNELS)
+        .filter(|&channel| channel_used(map, channel))
+        .nth(index as usize)
+        .unwrap_or(unmapped)
+}
+
+/// Whether `instant` is in the past relative to `counter`.
+fn instant_passed(instant: u16, counter: u16) -> bool {
+    instant.wrapping_sub(counter) >= 32767
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+pub struct LinkLayer<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn LinkLayerClient>,
+    state: Cell<State>,
+    address: Cell<[u8; ADDRESS_LEN]>,
+    adv_data: MapCell<[u8; MAX_ADV_DATA_LEN]>,
+    adv_data_len: Cell<usize>,
+    advertising: Cell<bool>,
+    advertising_interval_ms: Cell<u32>,
+    /// Start of the current advertising event.
+    adv_event_start: Cell<A::Ticks>,
+    random_nonce: Cell<u32>,
+
+    connection: Cell<Connection>,
+    /// Anchor point of the current connection event. Before the master's
+    /// packet is received it is the expected one.
+    anchor: Cell<A::Ticks>,
+    /// Anchor point of the last event in which a packet was received.
+    last_sync: Cell<A::Ticks>,
+
+    tx_queue: MapCell<[[u8; PDU_LEN]; TX_QUEUE_LEN]>,
+    tx_head: Cell<usize>,
+    tx_count: Cell<usize>,
+    /// PDU received in the current event, processed once it ends.
+    rx_pdu: MapCell<[u8; PDU_LEN]>,
+    rx_pending: Cell<bool>,
+    /// Whether a data PDU was acknowledged in the current event.
+    tx_freed: Cell<bool>,
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> LinkLayer<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A) -> LinkLayer<'a, R, A> {
+        LinkLayer {
+            radio: radio,
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            address: Cell::new([0; ADDRESS_LEN]),
+            adv_data: MapCell::new([0; MAX_ADV_DATA_LEN]),
+            adv_data_len: Cell::new(0),
+            advertising: Cell::new(false),
+            advertising_interval_ms: Cell::new(100),
+            adv_event_start: Cell::new(A::Ticks::from(0)),
+            random_nonce: Cell::new(0xdeadbeef),
+            connection: Cell::new(Connection {
+                access_address: 0,
+                crc_init: 0,
+                interval_us: 0,
+                timeout_us: 0,
+                master_sca_ppm: 0,
+                channel_map: [0; 5],
+                hop: 0,
+                unmapped_channel: 0,
+                channel: 0,
+                event_counter: 0,
+                sn: false,
+                nesn: false,
+                unacknowledged: false,
+                head_sent: false,
+                window_us: 0,
+                established: false,
+                update: None,
+                channel_map_update: None,
+                version_sent: false,
+                terminate: None,
+            }),
+            anchor: Cell::new(A::Ticks::from(0)),
+            last_sync: Cell::new(A::Ticks::from(0)),
+            tx_queue: MapCell::new([[0; PDU_LEN]; TX_QUEUE_LEN]),
+            tx_head: Cell::new(0),
+            tx_count: Cell::new(0),
+            rx_pdu: MapCell::new([0; PDU_LEN]),
+            rx_pending: Cell::new(false),
+            tx_freed: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn LinkLayerClient) {
+        self.client.set(client);
+    }
+
+    /// Sets the device address, which must be a static random address: the
+    /// two most significant bits of the last byte are 1.
+    pub fn set_address(&self, address: [u8; ADDRESS_LEN]) {
+        self.address.set(address);
+        let seed = u32::from_le_bytes([address[0], address[1], address[2], address[3]]);
+        self.random_nonce.set(seed | 1);
+    }
+
+    pub fn address(&self) -> [u8; ADDRESS_LEN] {
+        self.address.get()
+    }
+
+    /// Sets the data included in advertisements, at most
+    /// `MAX_ADV_DATA_LEN` bytes of AD structures.
+    pub fn set_advertising_data(&self, data: &[u8]) -> ReturnCode {
+        if data.len() > MAX_ADV_DATA_LEN {
+            return ReturnCode::ESIZE;
+        }
+        self.adv_data
+            .map(|adv_data| adv_data[..data.len()].copy_from_slice(data));
+        self.adv_data_len.set(data.len());
+        ReturnCode::SUCCESS
+    }
+
+    /// Starts advertising every `interval_ms` milliseconds plus a random
+    /// delay. Advertising pauses while a master is connected and resumes
+    /// when the connection ends, until `stop_advertising` is called.
+    pub fn start_advertising(&self, interval_ms: u32) -> ReturnCode {
+        if interval_ms < 20 {
+            return ReturnCode::EINVAL;
+        }
+        self.advertising_interval_ms.set(interval_ms);
+        self.advertising.set(true);
+        if self.state.get() == State::Idle {
+            self.state.set(State::AdvertisingIdle);
+            self.adv_event_start.set(self.alarm.now());
+            self.alarm.set_alarm(self.alarm.now(), A::Ticks::from(0));
+        }
+        ReturnCode::SUCCESS
+    }
+
+    pub fn stop_advertising(&self) -> ReturnCode {
+        self.advertising.set(false);
+        match self.state.get() {
+            State::AdvertisingIdle => {
+                self.alarm.disarm();
+                self.state.set(State::Idle);
+            }
+            State::Advertising(_) => {
+                if self.radio.stop() == ReturnCode::SUCCESS {
+                    self.alarm.disarm();
+                    self.state.set(State::Idle);
+                }
+                // Otherwise the exchange completes first.
+            }
+            _ => {}
+        }
+        ReturnCode::SUCCESS
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.state.get() {
+            State::ConnectionIdle | State::ConnectionEvent => true,
+            _ => false,
+        }
+    }
+
+    /// Queues a data PDU with LLID `llid` and `payload`, of at most
+    /// `MAX_PAYLOAD_LEN` bytes. Returns EBUSY if the queue is full, in which
+    /// case `transmit_done` is called once there is space.
+    pub fn transmit(&self, llid: u8, payload: &[u8]) -> ReturnCode {
+        if !self.is_connected() || self.connection.get().terminate.is_some() {
+            return ReturnCode::EOFF;
+        }
+        if (llid != LLID_START && llid != LLID_CONTINUATION) || payload.len() > MAX_PAYLOAD_LEN {
+            return ReturnCode::EINVAL;
+        }
+        if self.tx_count.get() >= TX_QUEUE_LEN - 1 {
+            return ReturnCode::EBUSY;
+        }
+        self.enqueue(llid, payload)
+    }
+
+    /// Ends the connection with LL_TERMINATE_IND.
+    pub fn disconnect(&self) -> ReturnCode {
+        if !self.is_connected() {
+            return ReturnCode::EOFF;
+        }
+        self.enqueue(
+            LLID_CONTROL,
+            &[LL_TERMINATE_IND, reason::REMOTE_USER_TERMINATED],
+        )
+    }
+
+    fn enqueue(&self, llid: u8, payload: &[u8]) -> ReturnCode {
+        let count = self.tx_count.get();
+        if count >= TX_QUEUE_LEN {
+            return ReturnCode::EBUSY;
+        }
+        let index = (self.tx_head.get() + count) % TX_QUEUE_LEN;
+        self.tx_queue.map(|queue| {
+            queue[index][0] = llid;
+            queue[index][1] = payload.len() as u8;
+            queue[index][2..2 + payload.len()].copy_from_slice(payload);
+        });
+        self.tx_count.set(count + 1);
+        ReturnCode::SUCCESS
+    }
+
+    /// Removes the acknowledged PDU at the head of the queue. Returns the
+    /// LLID and the control opcode, if any, of the removed PDU.
+    fn dequeue(&self) -> (u8, Option<u8>) {
+        let head = self.tx_head.get();
+        let removed = self.tx_queue.map_or((0, None), |queue| {
+            let pdu = &queue[head];
+            let opcode = if pdu[0] == LLID_CONTROL && pdu[1] > 0 {
+                Some(pdu[2])
+            } else {
+                None
+            };
+            (pdu[0], opcode)
+        });
+        self.tx_head.set((head + 1) % TX_QUEUE_LEN);
+        self.tx_count.set(self.tx_count.get() - 1);
+        removed
+    }
+
+    fn random_delay_ms(&self) -> u32 {
+        let mut nonce = self.random_nonce.get();
+        nonce ^= nonce << 13;
+        nonce ^= nonce >> 17;
+        nonce ^= nonce << 5;
+        self.random_nonce.set(nonce);
+        nonce % (ADV_DELAY_MAX_MS + 1)
+    }
+
+    fn ticks_to_us(ticks: A::Ticks) -> u32 {
+        (ticks.into_u32() as u64 * 1_000_000 / <A::Frequency as Frequency>::frequency() as u64)
+            as u32
+    }
+
+    /// Sets the alarm for `when`, or to fire immediately if `when` is
+    /// already past.
+    fn set_alarm_at(&self, when: A::Ticks) {
+        let now = self.alarm.now();
+        let dt = when.wrapping_sub(now);
+        if dt.into_u32() > A::Ticks::max_value().into_u32() / 2 {
+            self.alarm.set_alarm(now, A::Ticks::from(0));
+        } else {
+            self.alarm.set_alarm(now, dt);
+        }
+    }
+
+    // Advertising
+
+    fn advertise(&self, channel: u8) {
+        let address = self.address.get();
+        let mut pdu = [0; 2 + ADDRESS_LEN + MAX_ADV_DATA_LEN];
+        let data_len = self.adv_data_len.get();
+        pdu[0] = ADV_IND | TXADD;
+        pdu[1] = (ADDRESS_LEN + data_len) as u8;
+        pdu[2..2 + ADDRESS_LEN].copy_from_slice(&address);
+        self.adv_data.map(|data| {
+            pdu[2 + ADDRESS_LEN..2 + ADDRESS_LEN + data_len].copy_from_slice(&data[..data_len])
+        });
+        let len = 2 + ADDRESS_LEN + data_len;
+
+        self.state.set(State::Advertising(channel));
+        let rc = RadioChannel::from_channel_index(channel).map_or(ReturnCode::FAIL, |channel| {
+            self.radio.advertise(channel, &pdu[..len])
+        });
+        if rc == ReturnCode::SUCCESS {
+            let window = air_time_us(len) + REQUEST_WINDOW_US;
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_us(window));
+        } else {
+            self.advertising_event_done();
+        }
+    }
+
+    /// Moves on to the next advertising channel, or ends the event.
+    fn next_advertising_channel(&self, channel: u8) {
+        if !self.advertising.get() {
+            self.state.set(State::Idle);
+        } else if channel < 39 {
+            self.advertise(channel + 1);
+        } else {
+            self.advertising_event_done();
+        }
+    }
+
+    fn advertising_event_done(&self) {
+        self.state.set(State::AdvertisingIdle);
+        let interval = self.advertising_interval_ms.get() + self.random_delay_ms();
+        let start = self
+            .adv_event_start
+            .get()
+            .wrapping_add(A::ticks_from_ms(interval));
+        self.adv_event_start.set(start);
+        self.set_alarm_at(start);
+    }
+
+    /// Handles a request to our advertisement, returning the length of the
+    /// reply.
+    fn advertising_request(&self, rx: &[u8], tx: &mut [u8]) -> usize {
+        if rx.len() < 2 || rx[1] as usize + 2 > rx.len() {
+            return 0;
+        }
+        let header = rx[0];
+        let payload = &rx[2..2 + rx[1] as usize];
+        let address = self.address.get();
+        let to_us =
+            |offset: usize| header & RXADD != 0 && payload[offset..offset + ADDRESS_LEN] == address;
+        match header & ADV_TYPE_MASK {
+            SCAN_REQ if payload.len() == SCAN_REQ_LEN && to_us(ADDRESS_LEN) => {
+                tx[0] = SCAN_RSP | TXADD;
+                tx[1] = ADDRESS_LEN as u8;
+                tx[2..2 + ADDRESS_LEN].copy_from_slice(&address);
+                2 + ADDRESS_LEN
+            }
+            CONNECT_IND if payload.len() == CONNECT_IND_LEN && to_us(ADDRESS_LEN) => {
+                self.connect(&payload[2 * ADDRESS_LEN..]);
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    /// Sets up a connection from the LLData of CONNECT_IND.
+    fn connect(&self, ll_data: &[u8]) {
+        let window_size = ll_data[7] as u32;
+        let window_offset = read_u16(&ll_data[8..]) as u32;
+        let interval = read_u16(&ll_data[10..]) as u32;
+        let timeout = read_u16(&ll_data[14..]) as u32;
+        let mut channel_map = [0; 5];
+        channel_map.copy_from_slice(&ll_data[16..21]);
+        channel_map[4] &= 0x1f;
+        let hop = ll_data[21] & 0x1f;
+        let sca = ll_data[21] >> 5;
+        if hop < 5
+            || hop > 16
+            || interval < 6
+            || interval > 3200
+            || window_size < 1
+            || window_size > 8
+            || used_channels(&channel_map) < 2
+        {
+            return;
+        }
+
+        // The transmit window starts 1.25 ms plus the window offset after
+        // the end of CONNECT_IND.
+        let now = self.alarm.now();
+        self.last_sync.set(now);
+        self.anchor
+            .set(now.wrapping_add(A::ticks_from_us(UNIT_US + window_offset * UNIT_US)));
+        let mut connection = Connection {
+            access_address: u32::from_le_bytes([ll_data[0], ll_data[1], ll_data[2], ll_data[3]]),
+            crc_init: u32::from_le_bytes([ll_data[4], ll_data[5], ll_data[6], 0]),
+            interval_us: interval * UNIT_US,
+            timeout_us: timeout as u32 * 10_000,
+            master_sca_ppm: MASTER_SCA_PPM[sca as usize],
+            channel_map: channel_map,
+            hop: hop,
+            unmapped_channel: 0,
+            channel: 0,
+            event_counter: 0,
+            sn: false,
+            nesn: false,
+            unacknowledged: false,
+            head_sent: false,
+            window_us: window_size * UNIT_US,
+            established: false,
+            update: None,
+            channel_map_update: None,
+            version_sent: false,
+            terminate: None,
+        };
+        connection.hop();
+        self.connection.set(connection);
+        self.tx_head.set(0);
+        self.tx_count.set(0);
+        self.rx_pending.set(false);
+        self.state.set(State::ConnectionIdle);
+    }
+
+    // Connections
+
+    /// Sets the alarm to open the receive window of the next event.
+    fn schedule_event(&self) {
+        let connection = self.connection.get();
+        let since_sync = Self::ticks_to_us(self.anchor.get().wrapping_sub(self.last_sync.get()));
+        let widening = connection.window_widening_us(since_sync);
+        self.state.set(State::ConnectionIdle);
+        self.set_alarm_at(self.anchor.get().wrapping_sub(A::ticks_from_us(widening)));
+    }
+
+    fn start_event(&self) {
+        let connection = self.connection.get();
+        let since_sync = Self::ticks_to_us(self.anchor.get().wrapping_sub(self.last_sync.get()));
+        let widening = connection.window_widening_us(since_sync);
+        let channel = RadioChannel::from_channel_index(connection.channel);
+        let rc = channel.map_or(ReturnCode::FAIL, |channel| {
+            self.radio
+                .listen(channel, connection.access_address, connection.crc_init)
+        });
+        self.state.set(State::ConnectionEvent);
+        if rc == ReturnCode::SUCCESS {
+            let close = self
+                .anchor
+                .get()
+                .wrapping_add(A::ticks_from_us(widening + connection.window_us));
+            self.set_alarm_at(close);
+        } else {
+            self.close_event();
+        }
+    }
+
+    /// Handles the master's packet, returning the length of our reply.
+    fn connection_packet(&self, rx: &[u8], result: ReturnCode, tx: &mut [u8]) -> usize {
+        let mut connection = self.connection.get();
+        let valid = result == ReturnCode::SUCCESS
+            && rx.len() >= 2
+            && rx[1] as usize <= MAX_PAYLOAD_LEN
+            && rx[1] as usize + 2 <= rx.len();
+        if valid {
+            // Resynchronize to the start of the master's packet.
+            let anchor = self
+                .alarm
+                .now()
+                .wrapping_sub(A::ticks_from_us(air_time_us(rx[1] as usize + 2)));
+            self.anchor.set(anchor);
+            self.last_sync.set(anchor);
+            connection.established = true;
+            connection.window_us = 0;
+
+            let header = rx[0];
+            if (header & NESN != 0) != connection.sn {
+                // The master acknowledged our last PDU.
+                connection.sn = !connection.sn;
+                if connection.unacknowledged && connection.head_sent {
+                    match self.dequeue() {
+                        (LLID_CONTROL, Some(LL_TERMINATE_IND)) => {
+                            connection.terminate = Some(reason::LOCAL_HOST_TERMINATED);
+                        }
+                        (LLID_CONTROL, _) => {}
+                        _ => self.tx_freed.set(true),
+                    }
+                }
+                connection.unacknowledged = false;
+            }
+            if (header & SN != 0) == connection.nesn {
+                // A new PDU, rather than a retransmission.
+                connection.nesn = !connection.nesn;
+                if rx[1] > 0 {
+                    let len = rx[1] as usize + 2;
+                    self.rx_pdu
+                        .map(|pdu| pdu[..len].copy_from_slice(&rx[..len]));
+                    self.rx_pending.set(true);
+                }
+            }
+        }
+
+        // Retransmit an unacknowledged PDU, otherwise send the head of the
+        // queue or an empty PDU.
+        let mut header = 0;
+        if connection.nesn {
+            header |= NESN;
+        }
+        if connection.sn {
+            header |= SN;
+        }
+        let mut len = 0;
+        let mut llid = LLID_CONTINUATION;
+        let send_head = if connection.unacknowledged {
+            connection.head_sent
+        } else {
+            self.tx_count.get() > 0
+        };
+        if send_head {
+            let head = self.tx_head.get();
+            self.tx_queue.map(|queue| {
+                llid = queue[head][0];
+                len = queue[head][1] as usize;
+                tx[2..2 + len].copy_from_slice(&queue[head][2..2 + len]);
+            });
+        }
+        tx[0] = llid | header;
+        tx[1] = len as u8;
+        connection.head_sent = send_head;
+        connection.unacknowledged = true;
+        self.connection.set(connection);
+        2 + len
+    }
+
+    /// Ends the current connection event and schedules the next one.
+    fn close_event(&self) {
+        let mut connection = self.connection.get();
+        if let Some(reason) = connection.terminate {
+            self.disconnected(reason);
+            return;
+        }
+        if self.rx_pending.take() {
+            let mut pdu = [0; PDU_LEN];
+            self.rx_pdu.map(|rx| pdu = *rx);
+            self.process_pdu(&pdu[..2 + pdu[1] as usize]);
+            connection = self.connection.get();
+            if let Some(reason) = connection.terminate {
+                self.disconnected(reason);
+                return;
+            }
+        }
+
+        if self.tx_freed.take() {
+            self.client.map(|client| client.transmit_done());
+        }
+
+        // Supervision timeout. Until the first packet arrives, the connection
+        // fails after six intervals.
+        let now = self.alarm.now();
+        let since_sync = Self::ticks_to_us(now.wrapping_sub(self.last_sync.get()));
+        if connection.established && since_sync > connection.timeout_us {
+            self.disconnected(reason::CONNECTION_TIMEOUT);
+            return;
+        }
+        if !connection.established && since_sync > 6 * connection.interval_us + connection.window_us
+        {
+            self.disconnected(reason::CONNECTION_FAILED_TO_BE_ESTABLISHED);
+            return;
+        }
+
+        // The next event.
+        connection.event_counter = connection.event_counter.wrapping_add(1);
+        let mut next_anchor = self
+            .anchor
+            .get()
+            .wrapping_add(A::ticks_from_us(connection.interval_us));
+        match connection.update {
+            Some(update) if update.instant == connection.event_counter => {
+                // The first packet with the new parameters comes in a
+                // transmit window after the old interval and the offset.
+                next_anchor = next_anchor.wrapping_add(A::ticks_from_us(update.window_offset_us));
+                connection.window_us = update.window_size_us;
+                connection.interval_us = update.interval_us;
+                connection.timeout_us = update.timeout_us;
+                connection.update = None;
+            }
+            _ => {}
+        }
+        match connection.channel_map_update {
+            Some((instant, map)) if instant == connection.event_counter => {
+                connection.channel_map = map;
+                connection.channel_map_update = None;
+            }
+            _ => {}
+        }
+        connection.hop();
+        self.anchor.set(next_anchor);
+        self.connection.set(connection);
+        self.schedule_event();
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.state.set(State::Idle);
+        self.alarm.disarm();
+        self.tx_count.set(0);
+        self.rx_pending.set(false);
+        self.client.map(|client| client.disconnected(reason));
+        if self.advertising.get() && self.state.get() == State::Idle {
+            self.start_advertising(self.advertising_interval_ms.get());
+        }
+    }
+
+    fn process_pdu(&self, pdu: &[u8]) {
+        let llid = pdu[0] & LLID_MASK;
+        let payload = &pdu[2..];
+        match llid {
+            LLID_START | LLID_CONTINUATION => {
+                self.client.map(|client| client.received(llid, payload));
+            }
+            LLID_CONTROL => self.control(payload),
+            _ => {}
+        }
+    }
+
+    /// Handles an LL control PDU.
+    fn control(&self, payload: &[u8]) {
+        let mut connection = self.connection.get();
+        let opcode = payload[0];
+        let data = &payload[1..];
+        match opcode {
+            LL_CONNECTION_UPDATE_IND if data.len() == 11 => {
+                let instant = read_u16(&data[9..]);
+                if instant_passed(instant, connection.event_counter) {
+                    connection.terminate = Some(reason::INSTANT_PASSED);
+                } else {
+                    connection.update = Some(ConnectionUpdate {
+                        instant: instant,
+                        window_size_us: data[0] as u32 * UNIT_US,
+                        window_offset_us: read_u16(&data[1..]) as u32 * UNIT_US,
+                        interval_us: read_u16(&data[3..]) as u32 * UNIT_US,
+                        timeout_us: read_u16(&data[7..]) as u32 * 10_000,
+                    });
+                }
+            }
+            LL_CHANNEL_MAP_IND if data.len() == 7 => {
+                let instant = read_u16(&data[5..]);
+                let mut map = [0; 5];
+                map.copy_from_slice(&data[..5]);
+                map[4] &= 0x1f;
+                if instant_passed(instant, connection.event_counter) {
+                    connection.terminate = Some(reason::INSTANT_PASSED);
+                } else if used_channels(&map) >= 2 {
+                    connection.channel_map_update = Some((instant, map));
+                }
+            }
+            LL_TERMINATE_IND if data.len() == 1 => {
+                connection.terminate = Some(data[0]);
+            }
+            LL_FEATURE_REQ | LL_SLAVE_FEATURE_REQ => {
+                // No optional features are supported.
+                self.enqueue(LLID_CONTROL, &[LL_FEATURE_RSP, 0, 0, 0, 0, 0, 0, 0, 0]);
+            }
+            LL_VERSION_IND => {
+                if !connection.version_sent {
+                    connection.version_sent = true;
+                    let [company_lo, company_hi] = COMPANY_ID.to_le_bytes();
+                    let [sub_lo, sub_hi] = SUBVERSION.to_le_bytes();
+                    self.enqueue(
+                        LLID_CONTROL,
+                        &[
+                            LL_VERSION_IND,
+                            VERSION,
+                            company_lo,
+                            company_hi,
+                            sub_lo,
+                            sub_hi,
+                        ],
+                    );
+                }
+            }
+            LL_PING_REQ => {
+                self.enqueue(LLID_CONTROL, &[LL_PING_RSP]);
+            }
+            LL_LENGTH_REQ => {
+                // Only the default data lengths are supported.
+                let [octets, _] = (MAX_PAYLOAD_LEN as u16).to_le_bytes();
+                let [time_lo, time_hi] = 328u16.to_le_bytes();
+                self.enqueue(
+                    LLID_CONTROL,
+                    &[
+                        LL_LENGTH_RSP,
+                        octets,
+                        0,
+                        time_lo,
+                        time_hi,
+                        octets,
+                        0,
+                        time_lo,
+                        time_hi,
+                    ],
+                );
+            }
+            LL_ENC_REQ => {
+                self.enqueue(
+                    LLID_CONTROL,
+                    &[LL_REJECT_IND, reason::UNSUPPORTED_REMOTE_FEATURE],
+                );
+            }
+            // Responses to requests we never send.
+            LL_UNKNOWN_RSP | LL_FEATURE_RSP | LL_REJECT_IND | LL_PING_RSP | LL_LENGTH_RSP => {}
+            _ => {
+                self.enqueue(LLID_CONTROL, &[LL_UNKNOWN_RSP, opcode]);
+            }
+        }
+        self.connection.set(connection);
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> Link<'a> for LinkLayer<'a, R, A> {
+    fn set_client(&self, client: &'a dyn LinkLayerClient) {
+        LinkLayer::set_client(self, client);
+    }
+
+    fn set_advertising_data(&self, data: &[u8]) -> ReturnCode {
+        LinkLayer::set_advertising_data(self, data)
+    }
+
+    fn start_advertising(&self, interval_ms: u32) -> ReturnCode {
+        LinkLayer::start_advertising(self, interval_ms)
+    }
+
+    fn stop_advertising(&self) -> ReturnCode {
+        LinkLayer::stop_advertising(self)
+    }
+
+    fn transmit(&self, llid: u8, payload: &[u8]) -> ReturnCode {
+        LinkLayer::transmit(self, llid, payload)
+    }
+
+    fn disconnect(&self) -> ReturnCode {
+        LinkLayer::disconnect(self)
+    }
+
+    fn is_connected(&self) -> bool {
+        LinkLayer::is_connected(self)
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> time::AlarmClient for LinkLayer<'a, R, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::AdvertisingIdle => {
+                self.adv_event_start.set(self.alarm.now());
+                self.advertise(37);
+            }
+            State::Advertising(channel) => {
+                // Nothing has been received.
+                if self.radio.stop() == ReturnCode::SUCCESS {
+                    self.next_advertising_channel(channel);
+                }
+            }
+            State::ConnectionIdle => self.start_event(),
+            State::ConnectionEvent => {
+                // The master's packet was missed.
+                if self.radio.stop() == ReturnCode::SUCCESS {
+                    self.close_event();
+                }
+            }
+            State::Idle => {}
+        }
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> ble_advertising::ConnectionClient
+    for LinkLayer<'a, R, A>
+{
+    fn packet_received(
+        &self,
+        rx: &[u8],
+        _rssi: Option<i8>,
+        result: ReturnCode,
+        tx: &mut [u8],
+    ) -> usize {
+        match self.state.get() {
+            State::Advertising(_) if result == ReturnCode::SUCCESS => {
+                self.advertising_request(rx, tx)
+            }
+            State::ConnectionEvent => self.connection_packet(rx, result, tx),
+            _ => 0,
+        }
+    }
+
+    fn exchange_done(&self, _result: ReturnCode) {
+        match self.state.get() {
+            State::Advertising(channel) => self.next_advertising_channel(channel),
+            State::ConnectionIdle => {
+                // CONNECT_IND was received.
+                self.client.map(|client| client.connected());
+                self.schedule_event();
+            }
+            State::ConnectionEvent => self.close_event(),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,9 @@
+//! Bluetooth Low Energy peripheral stack: a link layer that accepts
+//! connections, L2CAP, and a GATT server that processes can add services to.
+//! Also holds the filter rules of the advertising driver's scanning.
+
+pub mod driver;
+pub mod gatt;
+pub mod l2cap;
+pub mod link_layer;
+pub mod scan_filter;
@@ -0,0 +1,271 @@
+//! Filter rules for received advertisements.
+//!
+//! A `ScanFilter` decides which advertisements a scanning process is told
+//! about, so that it is not woken up for every device in range. A packet is
+//! reported if all enabled rules accept it:
+//!
+//! * Address allowlist: the advertiser's address is in a list.
+//! * RSSI threshold: the packet was received at least this strongly. Packets
+//!   whose strength the radio could not measure pass.
+//! * AD structure: the advertising data, or the scan response if there is
+//!   one, contains an AD structure of a given type whose data starts with a
+//!   pattern. The special type `SERVICE_UUID` instead matches a 16 or 128-bit
+//!   UUID in any of the service UUID lists.
+//! * Duplicate suppression: the advertiser has not been reported with the
+//!   same PDU type and data since scanning started. Only the last
+//!   `DUPLICATE_ENTRIES` advertisers are remembered.
+//!
+//! The allowlist and the pattern are kept by the caller, usually in process
+//! memory, and passed in when filtering.
+
+use core::cmp;
+
+/// Address of the advertiser.
+pub type Address = [u8; ADDRESS_LEN];
+pub const ADDRESS_LEN: usize = 6;
+
+/// Number of advertisers remembered for duplicate suppression.
+pub const DUPLICATE_ENTRIES: usize = 8;
+
+/// AD type to match service UUIDs in any of the UUID lists.
+pub const SERVICE_UUID: u8 = 0;
+
+// Bluetooth Core Specification Supplement, Part A, section 1.1
+const INCOMPLETE_16BIT_UUIDS: u8 = 0x02;
+const COMPLETE_16BIT_UUIDS: u8 = 0x03;
+const INCOMPLETE_128BIT_UUIDS: u8 = 0x06;
+const COMPLETE_128BIT_UUIDS: u8 = 0x07;
+
+/// FNV-1a over the PDU type and data, to recognize repeated advertisements.
+fn digest(pdu_type: u8, data: &[u8]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for &byte in [pdu_type].iter().chain(data.iter()) {
+        hash = (hash ^ byte as u32).wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+/// Calls `f` with the type and data of each AD structure in `data`, until it
+/// returns true.
+fn any_structure<F: Fn(u8, &[u8]) -> bool>(mut data: &[u8], f: F) -> bool {
+    while data.len() >= 2 {
+        let len = data[0] as usize;
+        if len == 0 || len + 1 > data.len() {
+            return false;
+        }
+        if f(data[1], &data[2..len + 1]) {
+            return true;
+        }
+        data = &data[len + 1..];
+    }
+    false
+}
+
+#[derive(Default)]
+pub struct ScanFilter {
+    rssi_threshold: Option<i8>,
+    ad_type: Option<u8>,
+    suppress_duplicates: bool,
+    seen: [Option<(Address, u32)>; DUPLICATE_ENTRIES],
+    next_seen: usize,
+}
+
+impl ScanFilter {
+    /// Only reports packets received with at least `threshold` dBm, or all
+    /// packets if `None`.
+    pub fn set_rssi_threshold(&mut self, threshold: Option<i8>) {
+        self.rssi_threshold = threshold;
+    }
+
+    /// Only reports advertisements with an AD structure of type `ad_type`
+    /// matching the pattern, or all advertisements if `None`.
+    pub fn set_ad_type(&mut self, ad_type: Option<u8>) {
+        self.ad_type = ad_type;
+    }
+
+    pub fn set_suppress_duplicates(&mut self, suppress: bool) {
+        self.suppress_duplicates = suppress;
+        self.reset();
+    }
+
+    /// Forgets the advertisers reported so far.
+    pub fn reset(&mut self) {
+        self.seen = [None; DUPLICATE_ENTRIES];
+        self.next_seen = 0;
+    }
+
+    /// Whether the advertiser `address` passes the allowlist, which holds
+    /// consecutive addresses, and the packet passes the RSSI threshold.
+    pub fn accepts_advertiser(
+        &self,
+        address: &Address,
+        rssi: Option<i8>,
+        allowlist: Option<&[u8]>,
+    ) -> bool {
+        let allowed = allowlist.map_or(true, |allowlist| {
+            allowlist
+                .chunks_exact(ADDRESS_LEN)
+                .any(|entry| entry == address)
+        });
+        let strong = match (self.rssi_threshold, rssi) {
+            (Some(threshold), Some(rssi)) => rssi >= threshold,
+            _ => true,
+        };
+        allowed && strong
+    }
+
+    /// Whether the advertising data or the scan response data match the AD
+    /// structure rule with `pattern`.
+    pub fn matches_data(&self, adv_data: &[u8], scan_response: &[u8], pattern: &[u8]) -> bool {
+        let ad_type = match self.ad_type {
+            Some(ad_type) => ad_type,
+            None => return true,
+        };
+        let matches = |data_type: u8, data: &[u8]| {
+            if ad_type != SERVICE_UUID {
+                return data_type == ad_type && data.starts_with(pattern);
+            }
+            let uuid_len = match data_type {
+                INCOMPLETE_16BIT_UUIDS | COMPLETE_16BIT_UUIDS => 2,
+                INCOMPLETE_128BIT_UUIDS | COMPLETE_128BIT_UUIDS => 16,
+                _ => return false,
+            };
+            uuid_len == pattern.len() && data.chunks_exact(uuid_len).any(|uuid| uuid == pattern)
+        };
+        any_structure(adv_data, matches) || any_structure(scan_response, matches)
+    }
+
+    /// Whether the advertisement was already reported.
+    pub fn is_duplicate(&self, address: &Address, pdu_type: u8, data: &[u8]) -> bool {
+        let digest = digest(pdu_type, data);
+        self.suppress_duplicates
+            && self
+                .seen
+                .iter()
+                .any(|entry| *entry == Some((*address, digest)))
+    }
+
+    /// Records a reported advertisement for duplicate suppression.
+    pub fn reported(&mut self, address: &Address, pdu_type: u8, data: &[u8]) {
+        if !self.suppress_duplicates {
+            return;
+        }
+        let digest = digest(pdu_type, data);
+        // Replace the advertiser's previous entry, if any.
+        let index = self
+            .seen
+            .iter()
+            .position(|entry| entry.map_or(false, |(seen, _)| seen == *address))
+            .unwrap_or_else(|| {
+                let index = self.next_seen;
+                self.next_seen = (index + 1) % DUPLICATE_ENTRIES;
+                index
+            });
+        self.seen[index] = Some((*address, digest));
+    }
+}
+
+/// Splits an advertising channel PDU into its type, the advertiser's
+/// address and the data following it.
+pub fn parse_pdu(pdu: &[u8]) -> Option<(u8, Address, &[u8])> {
+    if pdu.len() < 2 + ADDRESS_LEN {
+        return None;
+    }
+    let len = cmp::min(pdu[1] as usize & 0x3f, pdu.len() - 2);
+    if len < ADDRESS_LEN {
+        return None;
+    }
+    let mut address = [0; ADDRESS_LEN];
+    address.copy_from_slice(&pdu[2..2 + ADDRESS_LEN]);
+    Some((pdu[0] & 0x0f, address, &pdu[2 + ADDRESS_LEN..2 + len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Address = [1, 2, 3, 4, 5, 0xc6];
+    const OTHER: Address = [9, 9, 9, 9, 9, 0xc9];
+    // Flags, a 16-bit UUID list with 0x180f and 0x180a, and the name "Tock".
+    const DATA: [u8; 15] = [
+        0x02, 0x01, 0x06, 0x05, 0x03, 0x0f, 0x18, 0x0a, 0x18, 0x05, 0x09, b'T', b'o', b'c', b'k',
+    ];
+
+    #[test]
+    fn allowlist_and_rssi() {
+        let mut filter = ScanFilter::default();
+        let mut allowlist = [0; 12];
+        allowlist[6..].copy_from_slice(&ADDRESS);
+        assert!(filter.accepts_advertiser(&OTHER, Some(-90), None));
+        assert!(filter.accepts_advertiser(&ADDRESS, None, Some(&allowlist)));
+        assert!(!filter.accepts_advertiser(&OTHER, None, Some(&allowlist)));
+
+        filter.set_rssi_threshold(Some(-70));
+        assert!(filter.accepts_advertiser(&ADDRESS, Some(-70), None));
+        assert!(!filter.accepts_advertiser(&ADDRESS, Some(-71), None));
+        assert!(filter.accepts_advertiser(&ADDRESS, None, None));
+    }
+
+    #[test]
+    fn ad_structures() {
+        let mut filter = ScanFilter::default();
+        assert!(filter.matches_data(&[], &[], &[]));
+
+        filter.set_ad_type(Some(0x09));
+        assert!(filter.matches_data(&DATA, &[], b"To"));
+        assert!(!filter.matches_data(&DATA, &[], b"ock"));
+        assert!(filter.matches_data(&[], &DATA, b"Tock"));
+
+        filter.set_ad_type(Some(SERVICE_UUID));
+        assert!(filter.matches_data(&DATA, &[], &[0x0a, 0x18]));
+        assert!(!filter.matches_data(&DATA, &[], &[0x18, 0x0a]));
+        assert!(!filter.matches_data(&DATA, &[], &[0x0f, 0x18, 0x0a, 0x18]));
+
+        // Malformed data does not match, nor panic.
+        assert!(!filter.matches_data(&[0x09, 0x03, 0x0f], &[], &[0x0f, 0x18]));
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut filter = ScanFilter::default();
+        filter.reported(&ADDRESS, 0, &DATA);
+        assert!(!filter.is_duplicate(&ADDRESS, 0, &DATA));
+
+        filter.set_suppress_duplicates(true);
+        filter.reported(&ADDRESS, 0, &DATA);
+        assert!(filter.is_duplicate(&ADDRESS, 0, &DATA));
+        assert!(!filter.is_duplicate(&ADDRESS, 0, &DATA[..3]));
+        assert!(!filter.is_duplicate(&ADDRESS, 2, &DATA));
+        assert!(!filter.is_duplicate(&OTHER, 0, &DATA));
+
+        // New data replaces the advertiser's entry.
+        filter.reported(&ADDRESS, 0, &DATA[..3]);
+        assert!(filter.is_duplicate(&ADDRESS, 0, &DATA[..3]));
+        assert!(!filter.is_duplicate(&ADDRESS, 0, &DATA));
+
+        // The oldest advertisers are forgotten.
+        for i in 0..DUPLICATE_ENTRIES as u8 {
+            filter.reported(&[i, 0, 0, 0, 0, 0xc0], 0, &DATA);
+        }
+        assert!(!filter.is_duplicate(&ADDRESS, 0, &DATA[..3]));
+        assert!(filter.is_duplicate(&[1, 0, 0, 0, 0, 0xc0], 0, &DATA));
+
+        filter.reset();
+        assert!(!filter.is_duplicate(&[1, 0, 0, 0, 0, 0xc0], 0, &DATA));
+    }
+
+    #[test]
+    fn pdus() {
+        let mut pdu = [0; 2 + 6 + 3];
+        pdu[0] = 0x42;
+        pdu[1] = 9;
+        pdu[2..8].copy_from_slice(&ADDRESS);
+        pdu[8..].copy_from_slice(&DATA[..3]);
+        assert_eq!(parse_pdu(&pdu), Some((0x02, ADDRESS, &DATA[..3])));
+        pdu[1] = 30;
+        assert_eq!(parse_pdu(&pdu), Some((0x02, ADDRESS, &DATA[..3])));
+        pdu[1] = 5;
+        assert_eq!(parse_pdu(&pdu), None);
+        assert_eq!(parse_pdu(&pdu[..7]), None);
+    }
+}
@@ -10,13 +10,23 @@
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
 //! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
 //!
+//! Received advertisements go through each process's filter rules (see
+//! `ble::scan_filter`) before the process is told about them. If the radio
+//! also implements `BleConnectionDriver` and is passed to `set_exchange_radio`,
+//! processes can additionally scan actively, sending a SCAN_REQ to scannable
+//! advertisers, and answer SCAN_REQs to their own scannable advertisements with
+//! scan response data.
+//!
 //! ### Allow system call
 //!
 //! The allow systems calls are used for buffers from allocated by userland
 //!
-//! There are two different buffers:
+//! There are the following buffers:
 //! * 0: Advertising data
-//! * 1: Passive scanning buffer
+//! * 1: Scanning buffer
+//! * 2: Address allowlist, consecutive 6-byte advertiser addresses
+//! * 3: Pattern of the AD structure filter
+//! * 4: Scan response data
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -48,7 +58,21 @@
 //!
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
-//! * 5: start scanning
+//! * 2: configure the TX power
+//! * 5: start scanning, passively if the first argument is 0 and actively if
+//!      it is 1
+//! * 6: only report packets received with at least the RSSI in dBm given as
+//!      the first argument, or all packets if it is 0
+//! * 7: only report advertisements with an AD structure of the type given by
+//!      the first argument whose data starts with the first bytes of buffer 3,
+//!      as many as the second argument. Type 0 matches a 2 or 16-byte service
+//!      UUID in any of the UUID lists instead.
+//! * 8: remove the AD structure filter
+//! * 9: suppress duplicate advertisements if the first argument is 1
+//!
+//! Each report copies the advertisement PDU into the scanning buffer, followed
+//! by the scan response PDU when scanning actively, and calls the callback with
+//! the result and the lengths of both PDUs.
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -81,6 +105,13 @@
 //! nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_tx_client(&nrf52::radio::RADIO,
 //!                                                                   ble_radio);
 //! ble_radio_virtual_alarm.set_client(ble_radio);
+//!
+//! // Optional, for active scanning and scan responses.
+//! ble_radio.set_exchange_radio(&nrf52::radio::RADIO);
+//! kernel::hil::ble_advertising::BleConnectionDriver::set_connection_client(
+//!     &nrf52::radio::RADIO,
+//!     ble_radio,
+//! );
 //! ```
 //!
 //! ### Authors
@@ -99,13 +130,19 @@
 // This means that advertising events can collide. In this case, we just defer one of the
 // advertisements. Because we add a pseudo random pad to the timer interval each time (as required
 // by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
+//
+// Events that need to answer a packet within the inter frame space, i.e. active scanning and
+// scannable advertising with scan response data, are exchanges on the `BleConnectionDriver`. The
+// radio keeps listening until it is stopped, so during these events the alarm is used to close
+// the receive windows instead of for the process timers.
 
+use crate::ble::scan_filter::{self, ScanFilter};
 use core::cell::Cell;
 use core::cmp;
-use kernel::common::cells::OptionalCell;
+use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::debug;
 use kernel::hil::ble_advertising;
-use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{BleConnectionDriver, RadioChannel, T_IFS_US};
 use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
@@ -119,6 +156,28 @@ pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const ADV_HEADER_RXADD_OFFSET: usize = 7;
+const ADV_DATA_MAX_LEN: usize = 31;
+
+/// How long each channel is scanned when scanning actively.
+const ACTIVE_SCAN_WINDOW_US: u32 = 10_000;
+/// How long to listen for a request after an advertisement was sent, counted
+/// from the end of the advertisement.
+const REQUEST_WINDOW_US: u32 = T_IFS_US + 100;
+
+/// Time on air, in microseconds, of a PDU of `len` bytes: preamble, access
+/// address, PDU and CRC at 1 Mbit/s.
+fn air_time_us(len: usize) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+fn next_channel(channel: RadioChannel) -> Option<RadioChannel> {
+    match channel {
+        RadioChannel::AdvertisingChannel37 => Some(RadioChannel::AdvertisingChannel38),
+        RadioChannel::AdvertisingChannel38 => Some(RadioChannel::AdvertisingChannel39),
+        _ => None,
+    }
+}
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
@@ -156,9 +215,7 @@ const ADV_IND: AdvPduType = 0b0000;
 #[allow(dead_code)]
 const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
-#[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
@@ -182,9 +239,16 @@ pub struct App {
     /// well.
     random_nonce: u32,
 
+    scan_response: Option<kernel::AppSlice<kernel::Shared, u8>>,
+
     // Scanning meta-data
     scan_buffer: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
+    active_scanning: bool,
+    allowlist: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    filter_pattern: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    filter_pattern_len: usize,
+    filter: ScanFilter,
 }
 
 impl Default for App {
@@ -196,6 +260,12 @@ impl Default for App {
             address: [0; PACKET_ADDR_LEN],
             pdu_type: ADV_NONCONN_IND,
             scan_callback: None,
+            scan_response: None,
+            active_scanning: false,
+            allowlist: None,
+            filter_pattern: None,
+            filter_pattern_len: 0,
+            filter: ScanFilter::default(),
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
             advertisement_interval_ms: 200,
@@ -234,6 +304,32 @@ impl App {
         ReturnCode::SUCCESS
     }
 
+    // Writes a PDU of type `pdu_type` with the app's address followed by `data` into `buf`,
+    // returning its length.
+    fn serialize_pdu(&self, pdu_type: AdvPduType, data: &[u8], buf: &mut [u8]) -> usize {
+        let data_len = cmp::min(buf.len() - PACKET_ADDR_LEN - 2, data.len());
+        let payload_len = data_len + PACKET_ADDR_LEN;
+        {
+            let (header, payload) = buf.split_at_mut(2);
+            header[0] = pdu_type;
+            match pdu_type {
+                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RESP => {
+                    // Set TxAdd because AdvA field is going to be a "random"
+                    // address
+                    header[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
+                }
+                _ => {}
+            }
+            // The LENGTH field is 6-bits wide, so make sure to truncate it
+            header[1] = (payload_len & 0x3f) as u8;
+
+            let (adva, payload_data) = payload.split_at_mut(6);
+            adva.copy_from_slice(&self.address);
+            payload_data[..data_len].copy_from_slice(&data[..data_len]);
+        }
+        cmp::min(PACKET_LENGTH, payload_len + 2)
+    }
+
     fn send_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, channel: RadioChannel) -> ReturnCode
     where
         B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
@@ -241,28 +337,7 @@ impl App {
     {
         self.adv_data.as_ref().map_or(ReturnCode::FAIL, |adv_data| {
             ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
-                let adv_data_len = cmp::min(kernel_tx.len() - PACKET_ADDR_LEN - 2, adv_data.len());
-                let adv_data_corrected = &adv_data.as_ref()[..adv_data_len];
-                let payload_len = adv_data_corrected.len() + PACKET_ADDR_LEN;
-                {
-                    let (header, payload) = kernel_tx.split_at_mut(2);
-                    header[0] = self.pdu_type;
-                    match self.pdu_type {
-                        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
-                            // Set TxAdd because AdvA field is going to be a "random"
-                            // address
-                            header[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
-                        }
-                        _ => {}
-                    }
-                    // The LENGTH field is 6-bits wide, so make sure to truncate it
-                    header[1] = (payload_len & 0x3f) as u8;
-
-                    let (adva, data) = payload.split_at_mut(6);
-                    adva.copy_from_slice(&self.address);
-                    data[..adv_data_len].copy_from_slice(adv_data_corrected);
-                }
-                let total_len = cmp::min(PACKET_LENGTH, payload_len + 2);
+                let total_len = self.serialize_pdu(self.pdu_type, adv_data.as_ref(), kernel_tx);
                 ble.radio
                     .transmit_advertisement(kernel_tx, total_len, channel);
                 ReturnCode::SUCCESS
@@ -270,6 +345,54 @@ impl App {
         })
     }
 
+    // Whether advertisements use an exchange to answer scan requests.
+    fn sends_scan_responses(&self) -> bool {
+        self.scan_response.is_some() && self.pdu_type != ADV_NONCONN_IND
+    }
+
+    // Whether the advertiser of `pdu` passes the address and RSSI rules, and the advertisement
+    // was not reported already.
+    fn accepts(&self, pdu: &[u8], rssi: Option<i8>) -> bool {
+        scan_filter::parse_pdu(pdu).map_or(false, |(pdu_type, address, data)| {
+            let allowlist = self.allowlist.as_ref().map(|allowlist| allowlist.as_ref());
+            self.filter.accepts_advertiser(&address, rssi, allowlist)
+                && !self.filter.is_duplicate(&address, pdu_type, data)
+        })
+    }
+
+    // Copies the advertisement `adv` and the scan response `rsp`, which may be empty, into the
+    // scanning buffer and schedules the callback, if they match the AD structure rule.
+    fn report(&mut self, adv: &[u8], rsp: &[u8]) {
+        let (pdu_type, address, adv_data) = match scan_filter::parse_pdu(adv) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        let rsp_data = scan_filter::parse_pdu(rsp).map_or(&[][..], |(_, _, data)| data);
+        let pattern_len = self.filter_pattern_len;
+        let pattern = self.filter_pattern.as_ref().map_or(&[][..], |pattern| {
+            &pattern.as_ref()[..cmp::min(pattern_len, pattern.len())]
+        });
+        if !self.filter.matches_data(adv_data, rsp_data, pattern) {
+            return;
+        }
+
+        let success = self
+            .scan_buffer
+            .as_mut()
+            .map(|userland| {
+                for (dst, src) in userland.iter_mut().zip(adv.iter().chain(rsp.iter())) {
+                    *dst = *src;
+                }
+            })
+            .is_some();
+        if success {
+            self.filter.reported(&address, pdu_type, adv_data);
+            self.scan_callback.map(|mut cb| {
+                cb.schedule(usize::from(ReturnCode::SUCCESS), adv.len(), rsp.len());
+            });
+        }
+    }
+
     // Returns a new pseudo-random number and updates the randomness state.
     //
     // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
@@ -305,6 +428,17 @@ where
     alarm: &'a A,
     sending_app: OptionalCell<kernel::AppId>,
     receiving_app: OptionalCell<kernel::AppId>,
+
+    exchange_radio: OptionalCell<&'a dyn BleConnectionDriver<'a>>,
+    // The app whose event is an exchange on `exchange_radio`, if any
+    exchange_app: OptionalCell<kernel::AppId>,
+    // Start and end of the receive window on the current channel when scanning actively
+    scan_window: Cell<(A::Ticks, A::Ticks)>,
+    // Whether a SCAN_REQ was sent and its response not received yet
+    awaiting_response: Cell<bool>,
+    // The advertisement the SCAN_REQ was sent for
+    pending_adv: MapCell<[u8; PACKET_LENGTH]>,
+    pending_adv_len: Cell<usize>,
 }
 
 impl<'a, B, A> BLE<'a, B, A>
@@ -326,9 +460,22 @@ where
             alarm: alarm,
             sending_app: OptionalCell::empty(),
             receiving_app: OptionalCell::empty(),
+            exchange_radio: OptionalCell::empty(),
+            exchange_app: OptionalCell::empty(),
+            scan_window: Cell::new((A::Ticks::from(0), A::Ticks::from(0))),
+            awaiting_response: Cell::new(false),
+            pending_adv: MapCell::new([0; PACKET_LENGTH]),
+            pending_adv_len: Cell::new(0),
         }
     }
 
+    /// Enables active scanning and scan responses, which need a radio that
+    /// can reply to packets within the inter frame space. The driver must
+    /// also be the radio's `ConnectionClient`.
+    pub fn set_exchange_radio(&self, radio: &'a dyn BleConnectionDriver<'a>) {
+        self.exchange_radio.set(radio);
+    }
+
     // Determines which app timer will expire next and sets the underlying alarm
     // to it.
     //
@@ -337,6 +484,10 @@ where
     // since any open grant will not be iterated over and the wrong timer will
     // likely be chosen.
     fn reset_active_alarm(&self) {
+        if self.exchange_app.is_some() {
+            // The alarm closes the receive window of the exchange.
+            return;
+        }
         let now = self.alarm.now();
         let mut next_ref = u32::max_value();
         let mut next_dt = u32::max_value();
@@ -360,6 +511,130 @@ where
                 .set_alarm(A::Ticks::from(next_ref), A::Ticks::from(next_dt));
         }
     }
+
+    // Sends a scannable advertisement of `app` on `channel` and listens for a request.
+    fn exchange_advertise(&self, app: &App, channel: RadioChannel) -> ReturnCode {
+        let mut pdu = [0; PACKET_LENGTH];
+        let len = match app.adv_data {
+            Some(ref adv_data) => app.serialize_pdu(app.pdu_type, adv_data.as_ref(), &mut pdu),
+            None => return ReturnCode::FAIL,
+        };
+        let rc = self.exchange_radio.map_or(ReturnCode::FAIL, |radio| {
+            radio.advertise(channel, &pdu[..len])
+        });
+        if rc == ReturnCode::SUCCESS {
+            let window = air_time_us(len) + REQUEST_WINDOW_US;
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_us(window));
+        }
+        rc
+    }
+
+    // Scans `channel` until the end of the current scan window.
+    fn exchange_scan(&self, channel: RadioChannel) -> ReturnCode {
+        let rc = self
+            .exchange_radio
+            .map_or(ReturnCode::FAIL, |radio| radio.scan(channel));
+        if rc == ReturnCode::SUCCESS {
+            let (_, end) = self.scan_window.get();
+            let now = self.alarm.now();
+            self.alarm.set_alarm(now, end.wrapping_sub(now));
+        }
+        rc
+    }
+
+    // Reports the advertisement whose scan response never arrived.
+    fn report_pending_adv(&self, app: &mut App) {
+        let len = self.pending_adv_len.replace(0);
+        if len > 0 {
+            let mut adv = [0; PACKET_LENGTH];
+            self.pending_adv.map(|pending| adv = *pending);
+            app.report(&adv[..len], &[]);
+        }
+    }
+
+    // Continues the event of the exchange app once an exchange is over, either because
+    // `exchange_done` was called or because the radio was stopped at the end of the window.
+    fn exchange_finished(&self) {
+        self.exchange_app.map(|appid| {
+            self.app
+                .enter(*appid, |app, _| {
+                    self.report_pending_adv(app);
+                    self.awaiting_response.set(false);
+                    let started = match app.process_status {
+                        Some(BLEState::Advertising(channel)) => {
+                            next_channel(channel).map_or(false, |channel| {
+                                app.process_status = Some(BLEState::Advertising(channel));
+                                self.exchange_advertise(app, channel) == ReturnCode::SUCCESS
+                            })
+                        }
+                        Some(BLEState::Scanning(channel)) => {
+                            let (start, end) = self.scan_window.get();
+                            let next = if self.alarm.now().within_range(start, end) {
+                                // Keep scanning for the rest of the window.
+                                Some(channel)
+                            } else {
+                                self.open_scan_window();
+                                next_channel(channel)
+                            };
+                            next.map_or(false, |channel| {
+                                app.process_status = Some(BLEState::Scanning(channel));
+                                self.exchange_scan(channel) == ReturnCode::SUCCESS
+                            })
+                        }
+                        _ => false,
+                    };
+                    if !started {
+                        self.end_exchange_event(app);
+                    }
+                })
+                .unwrap_or_else(|_| {
+                    // The process is gone.
+                    self.busy.set(false);
+                    self.exchange_app.clear();
+                });
+        });
+        self.reset_active_alarm();
+    }
+
+    // Starts the event of `app` as an exchange.
+    fn start_exchange(&self, appid: kernel::AppId, app: &mut App) {
+        self.exchange_app.set(appid);
+        self.radio.set_tx_power(app.tx_power);
+        let channel = RadioChannel::AdvertisingChannel37;
+        let rc = match app.process_status {
+            Some(BLEState::AdvertisingIdle) => {
+                app.process_status = Some(BLEState::Advertising(channel));
+                self.exchange_advertise(app, channel)
+            }
+            _ => {
+                app.process_status = Some(BLEState::Scanning(channel));
+                self.open_scan_window();
+                self.exchange_scan(channel)
+            }
+        };
+        if rc != ReturnCode::SUCCESS {
+            // Try again at the next interval.
+            self.end_exchange_event(app);
+        }
+    }
+
+    fn open_scan_window(&self) {
+        let now = self.alarm.now();
+        let end = now.wrapping_add(A::ticks_from_us(ACTIVE_SCAN_WINDOW_US));
+        self.scan_window.set((now, end));
+    }
+
+    // Ends the advertising or scanning event of the exchange app.
+    fn end_exchange_event(&self, app: &mut App) {
+        app.process_status = match app.process_status {
+            Some(BLEState::Advertising(_)) => Some(BLEState::AdvertisingIdle),
+            _ => Some(BLEState::ScanningIdle),
+        };
+        self.busy.set(false);
+        self.exchange_app.clear();
+        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+    }
 }
 
 // Timer alarm
@@ -381,6 +656,20 @@ where
     // TODO: perhaps break ties more fairly by prioritizing apps that have least
     // recently performed an operation.
     fn alarm(&self) {
+        if self.exchange_app.is_some() {
+            // A receive window of the current exchange closed.
+            let stopped = self
+                .exchange_radio
+                .map_or(ReturnCode::FAIL, |radio| radio.stop());
+            if stopped == ReturnCode::SUCCESS {
+                self.exchange_finished();
+            }
+            // Otherwise a packet is being received and `exchange_done` follows.
+            if self.exchange_app.is_some() {
+                return;
+            }
+        }
+
         let now = self.alarm.now();
 
         self.app.each(|app| {
@@ -402,6 +691,14 @@ where
                     app.alarm_data.expiration = Expiration::Disabled;
 
                     match app.process_status {
+                        Some(BLEState::AdvertisingIdle) if app.sends_scan_responses() => {
+                            self.busy.set(true);
+                            self.start_exchange(app.appid(), app);
+                        }
+                        Some(BLEState::ScanningIdle) if app.active_scanning => {
+                            self.busy.set(true);
+                            self.start_exchange(app.appid(), app);
+                        }
                         Some(BLEState::AdvertisingIdle) => {
                             self.busy.set(true);
                             app.process_status =
@@ -438,7 +735,7 @@ where
     B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
-    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode) {
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, rssi: Option<i8>, result: ReturnCode) {
         self.receiving_app.map(|appid| {
             let _ = self.app.enter(*appid, |app, _| {
                 // Validate the received data, because ordinary BLE packets can be bigger than 39
@@ -451,21 +748,9 @@ where
                 // only be sent on the other 37 RadioChannel channels.
 
                 if len <= PACKET_LENGTH as u8 && result == ReturnCode::SUCCESS {
-                    // write to buffer in userland
-                    let success = app
-                        .scan_buffer
-                        .as_mut()
-                        .map(|userland| {
-                            for (dst, src) in userland.iter_mut().zip(buf[0..len as usize].iter()) {
-                                *dst = *src;
-                            }
-                        })
-                        .is_some();
-
-                    if success {
-                        app.scan_callback.map(|mut cb| {
-                            cb.schedule(usize::from(result), len as usize, 0);
-                        });
+                    let pdu = &buf[0..len as usize];
+                    if app.accepts(pdu, rssi) {
+                        app.report(pdu, &[]);
                     }
                 }
 
@@ -499,6 +784,100 @@ where
     }
 }
 
+// Callbacks from the exchange radio
+impl<'a, B, A> ble_advertising::ConnectionClient for BLE<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    A: kernel::hil::time::Alarm<'a>,
+{
+    fn packet_received(
+        &self,
+        rx: &[u8],
+        rssi: Option<i8>,
+        result: ReturnCode,
+        tx: &mut [u8],
+    ) -> usize {
+        if result != ReturnCode::SUCCESS || rx.len() > PACKET_LENGTH {
+            return 0;
+        }
+        let (pdu_type, address, data) = match scan_filter::parse_pdu(rx) {
+            Some(parsed) => parsed,
+            None => return 0,
+        };
+        self.exchange_app.map_or(0, |appid| {
+            self.app
+                .enter(*appid, |app, _| match app.process_status {
+                    // A request to our advertisement
+                    Some(BLEState::Advertising(_)) => {
+                        let to_us = rx[0] & (1 << ADV_HEADER_RXADD_OFFSET) != 0
+                            && data.len() == PACKET_ADDR_LEN
+                            && data == app.address;
+                        match app.scan_response {
+                            Some(ref scan_response) if pdu_type == SCAN_REQ && to_us => {
+                                let len = cmp::min(scan_response.len(), ADV_DATA_MAX_LEN);
+                                app.serialize_pdu(SCAN_RESP, &scan_response.as_ref()[..len], tx)
+                            }
+                            _ => 0,
+                        }
+                    }
+                    // The response to our scan request
+                    Some(BLEState::Scanning(_)) if self.awaiting_response.get() => {
+                        self.awaiting_response.set(false);
+                        let len = self.pending_adv_len.get();
+                        let mut adv = [0; PACKET_LENGTH];
+                        self.pending_adv.map(|pending| adv = *pending);
+                        if pdu_type == SCAN_RESP && address == adv[2..2 + PACKET_ADDR_LEN] {
+                            self.pending_adv_len.set(0);
+                            app.report(&adv[..len], rx);
+                        }
+                        0
+                    }
+                    // An advertisement
+                    Some(BLEState::Scanning(_)) => {
+                        if !app.accepts(rx, rssi) {
+                            return 0;
+                        }
+                        match pdu_type {
+                            ADV_IND | ADV_SCAN_IND => {
+                                self.pending_adv.map(|pending| {
+                                    pending[..rx.len()].copy_from_slice(rx);
+                                });
+                                self.pending_adv_len.set(rx.len());
+                                self.awaiting_response.set(true);
+
+                                // ScanA is our address, followed by AdvA
+                                let len = app.serialize_pdu(SCAN_REQ, &address, tx);
+                                tx[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
+                                // RxAdd is the advertiser's TxAdd
+                                tx[0] |= (rx[0] >> ADV_HEADER_TXADD_OFFSET & 1)
+                                    << ADV_HEADER_RXADD_OFFSET;
+
+                                // Wait for the response rather than the end of the window.
+                                let window = air_time_us(len)
+                                    + T_IFS_US
+                                    + air_time_us(0)
+                                    + REQUEST_WINDOW_US;
+                                self.alarm
+                                    .set_alarm(self.alarm.now(), A::ticks_from_us(window));
+                                len
+                            }
+                            _ => {
+                                app.report(rx, &[]);
+                                0
+                            }
+                        }
+                    }
+                    _ => 0,
+                })
+                .unwrap_or(0)
+        })
+    }
+
+    fn exchange_done(&self, _result: ReturnCode) {
+        self.exchange_finished();
+    }
+}
+
 // Callback from the radio once a TX event occur
 impl<'a, B, A> ble_advertising::TxClient for BLE<'a, B, A>
 where
@@ -563,6 +942,12 @@ where
                         if let Some(BLEState::Initialized) = app.process_status {
                             let pdu_type = data as AdvPduType;
                             match pdu_type {
+                                ADV_IND | ADV_SCAN_IND
+                                    if app.scan_response.is_some()
+                                        && self.exchange_radio.is_none() =>
+                                {
+                                    ReturnCode::ENOSUPPORT
+                                }
                                 ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
                                     app.pdu_type = pdu_type;
                                     app.process_status = Some(BLEState::AdvertisingIdle);
@@ -628,13 +1013,21 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
-            // Passive scanning mode
+            // Passive or active scanning mode
             5 => {
+                let active = match data {
+                    0 => false,
+                    1 if self.exchange_radio.is_some() => true,
+                    1 => return ReturnCode::ENOSUPPORT,
+                    _ => return ReturnCode::EINVAL,
+                };
                 let ret = self
                     .app
                     .enter(appid, |app, _| {
                         if let Some(BLEState::Initialized) = app.process_status {
                             app.process_status = Some(BLEState::ScanningIdle);
+                            app.active_scanning = active;
+                            app.filter.reset();
                             app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
                             ReturnCode::SUCCESS
                         } else {
@@ -649,6 +1042,63 @@ where
                 ret
             }
 
+            // RSSI threshold, in dBm
+            6 => self
+                .app
+                .enter(appid, |app, _| {
+                    let threshold = data as i8;
+                    app.filter.set_rssi_threshold(if threshold < 0 {
+                        Some(threshold)
+                    } else {
+                        None
+                    });
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // AD structure filter
+            7 => self
+                .app
+                .enter(appid, |app, _| {
+                    let pattern_len = interval;
+                    let ad_type = data;
+                    let available = app.filter_pattern.as_ref().map_or(0, |p| p.len());
+                    if ad_type > 0xff || pattern_len > available {
+                        ReturnCode::EINVAL
+                    } else if ad_type == scan_filter::SERVICE_UUID as usize
+                        && pattern_len != 2
+                        && pattern_len != 16
+                    {
+                        ReturnCode::EINVAL
+                    } else {
+                        app.filter_pattern_len = pattern_len;
+                        app.filter.set_ad_type(Some(ad_type as u8));
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Remove the AD structure filter
+            8 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.filter.set_ad_type(None);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Duplicate suppression
+            9 => self
+                .app
+                .enter(appid, |app, _| match data {
+                    0 | 1 => {
+                        app.filter.set_suppress_duplicates(data == 1);
+                        ReturnCode::SUCCESS
+                    }
+                    _ => ReturnCode::EINVAL,
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -687,6 +1137,38 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Address allowlist
+            2 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.allowlist = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Pattern of the AD structure filter
+            3 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.filter_pattern = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Scan response data
+            4 => self
+                .app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::AdvertisingIdle) | Some(BLEState::Advertising(_)) => {
+                        ReturnCode::EBUSY
+                    }
+                    _ => {
+                        app.scan_response = slice;
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // Operation not supported
             _ => ReturnCode::ENOSUPPORT,
         }
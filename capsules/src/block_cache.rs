@@ -0,0 +1,800 @@
+//! Write-back RAM cache for block devices.
+//!
+//! `BlockCache` keeps recently used blocks of a `hil::block_storage` device
+//! in RAM. Reads of cached blocks don't access the device, and writes only
+//! update the cache until the block is evicted or `flush()` is called. The
+//! least recently used block is evicted when a block that isn't cached is
+//! needed. The size of the cache is set by the board with the number of
+//! `CacheLine`s and the size of the memory given to `new()`.
+//!
+//! Writes that are still in the cache are lost on a power failure, so
+//! clients that need data to be on the device call `flush()`. Erasing blocks
+//! drops any cached writes to them.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let cache = static_init!(
+//!     capsules::block_cache::BlockCache<'static>,
+//!     capsules::block_cache::BlockCache::new(
+//!         sd_blocks,
+//!         static_init!([capsules::block_cache::CacheLine; 8], Default::default()),
+//!         static_init!([u8; 8 * 512], [0; 8 * 512]),
+//!         static_init!([u8; 512], [0; 512]),
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! cache.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(cache)
+//!         .expect("no deferred call slot available for block cache"),
+//! );
+//! hil::block_storage::BlockStorage::set_client(sd_blocks, cache);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::block_storage::{self, BlockStorage, Geometry};
+use kernel::ReturnCode;
+
+/// The state of one cached block.
+#[derive(Default)]
+pub struct CacheLine {
+    block: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    last_used: Cell<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Read { block: u32, count: u32, done: u32 },
+    Write { block: u32, count: u32, done: u32 },
+    Erase { block: u32, count: u32 },
+    Flush,
+}
+
+/// What the device is doing for the cache.
+#[derive(Clone, Copy, PartialEq)]
+enum DeviceOperation {
+    Idle,
+    Fill(usize, u32),
+    WriteBack(usize),
+    Erase,
+}
+
+pub struct BlockCache<'a> {
+    device: &'a dyn BlockStorage<'a>,
+    lines: &'a [CacheLine],
+    memory: TakeCell<'static, [u8]>,
+    io_buffer: TakeCell<'static, [u8]>,
+    clock: Cell<u32>,
+    hits: Cell<u32>,
+    misses: Cell<u32>,
+
+    operation: Cell<Operation>,
+    device_operation: Cell<DeviceOperation>,
+    buffer: TakeCell<'static, [u8]>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    deferred_handle: OptionalCell<DeferredCallHandle>,
+    client: OptionalCell<&'a dyn block_storage::Client>,
+}
+
+impl<'a> BlockCache<'a> {
+    /// `memory` holds the cached blocks, one for each of `lines`.
+    /// `io_buffer` must hold one block.
+    pub fn new(
+        device: &'a dyn BlockStorage<'a>,
+        lines: &'a [CacheLine],
+        memory: &'static mut [u8],
+        io_buffer: &'static mut [u8],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> BlockCache<'a> {
+        let block_size = device.geometry().block_size;
+        let lines = &lines[..cmp::min(lines.len(), memory.len() / block_size)];
+        BlockCache {
+            device,
+            lines,
+            memory: TakeCell::new(memory),
+            io_buffer: TakeCell::new(io_buffer),
+            clock: Cell::new(0),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            operation: Cell::new(Operation::Idle),
+            device_operation: Cell::new(DeviceOperation::Idle),
+            buffer: TakeCell::empty(),
+            deferred_caller,
+            deferred_handle: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.deferred_handle.replace(handle);
+    }
+
+    /// The number of blocks read from the cache and from the device.
+    pub fn statistics(&self) -> (u32, u32) {
+        (self.hits.get(), self.misses.get())
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.geometry().block_size
+    }
+
+    /// Operations run from a deferred call, so clients are never called
+    /// back before the request returns.
+    fn start(&self, operation: Operation) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.lines.is_empty() {
+            return ReturnCode::ENOMEM;
+        }
+        self.operation.set(operation);
+        self.deferred_handle
+            .map(|handle| self.deferred_caller.set(*handle));
+        ReturnCode::SUCCESS
+    }
+
+    fn find(&self, block: u32) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|line| line.block.get() == Some(block))
+    }
+
+    /// The line to use for `block`: the line already holding it, an empty
+    /// line, or the least recently used line.
+    fn line_for(&self, block: u32) -> usize {
+        self.find(block)
+            .or_else(|| {
+                self.lines
+                    .iter()
+                    .position(|line| line.block.get().is_none())
+            })
+            .unwrap_or_else(|| {
+                let mut oldest = 0;
+                for (i, line) in self.lines.iter().enumerate() {
+                    let age = self.clock.get().wrapping_sub(line.last_used.get());
+                    let oldest_age = self
+                        .clock
+                        .get()
+                        .wrapping_sub(self.lines[oldest].last_used.get());
+                    if age > oldest_age {
+                        oldest = i;
+                    }
+                }
+                oldest
+            })
+    }
+
+    fn touch(&self, line: usize) {
+        self.clock.set(self.clock.get().wrapping_add(1));
+        self.lines[line].last_used.set(self.clock.get());
+    }
+
+    /// Copy between a cache line and the client's buffer.
+    fn copy_line(&self, line: usize, index: u32, to_line: bool) {
+        let block_size = self.block_size();
+        let cached = line * block_size;
+        let offset = index as usize * block_size;
+        self.memory.map(|memory| {
+            self.buffer.map(|buffer| {
+                if to_line {
+                    memory[cached..cached + block_size]
+                        .copy_from_slice(&buffer[offset..offset + block_size]);
+                } else {
+                    buffer[offset..offset + block_size]
+                        .copy_from_slice(&memory[cached..cached + block_size]);
+                }
+            });
+        });
+    }
+
+    /// Write a dirty line to the device.
+    fn write_back(&self, line: usize) -> ReturnCode {
+        let block = match self.lines[line].block.get() {
+            Some(block) => block,
+            None => return ReturnCode::FAIL,
+        };
+        let block_size = self.block_size();
+        self.io_buffer
+            .take()
+            .map_or(ReturnCode::EBUSY, |io_buffer| {
+                self.memory.map(|memory| {
+                    io_buffer[..block_size]
+                        .copy_from_slice(&memory[line * block_size..(line + 1) * block_size]);
+                });
+                match self.device.write(io_buffer, block, 1) {
+                    Ok(()) => {
+                        self.device_operation.set(DeviceOperation::WriteBack(line));
+                        ReturnCode::SUCCESS
+                    }
+                    Err((e, io_buffer)) => {
+                        self.io_buffer.replace(io_buffer);
+                        e
+                    }
+                }
+            })
+    }
+
+    fn fill(&self, line: usize, block: u32) -> ReturnCode {
+        self.misses.set(self.misses.get() + 1);
+        self.io_buffer
+            .take()
+            .map_or(ReturnCode::EBUSY, |io_buffer| {
+                match self.device.read(io_buffer, block, 1) {
+                    Ok(()) => {
+                        self.device_operation
+                            .set(DeviceOperation::Fill(line, block));
+                        ReturnCode::SUCCESS
+                    }
+                    Err((e, io_buffer)) => {
+                        self.io_buffer.replace(io_buffer);
+                        e
+                    }
+                }
+            })
+    }
+
+    /// Continue the current operation until it needs the device or is done.
+    fn run(&self) {
+        loop {
+            let ret = match self.operation.get() {
+                Operation::Idle => return,
+                Operation::Read { block, count, done } => {
+                    if done == count {
+                        return self.finish(Ok(()));
+                    }
+                    match self.find(block + done) {
+                        Some(line) => {
+                            self.hits.set(self.hits.get() + 1);
+                            self.copy_line(line, done, false);
+                            self.touch(line);
+                            self.operation.set(Operation::Read {
+                                block,
+                                count,
+                                done: done + 1,
+                            });
+                            continue;
+                        }
+                        None => {
+                            let line = self.line_for(block + done);
+                            if self.lines[line].dirty.get() {
+                                self.write_back(line)
+                            } else {
+                                self.fill(line, block + done)
+                            }
+                        }
+                    }
+                }
+                Operation::Write { block, count, done } => {
+                    if done == count {
+                        return self.finish(Ok(()));
+                    }
+                    let line = self.line_for(block + done);
+                    let cached = &self.lines[line];
+                    if cached.dirty.get() && cached.block.get() != Some(block + done) {
+                        self.write_back(line)
+                    } else {
+                        self.copy_line(line, done, true);
+                        cached.block.set(Some(block + done));
+                        cached.dirty.set(true);
+                        self.touch(line);
+                        self.operation.set(Operation::Write {
+                            block,
+                            count,
+                            done: done + 1,
+                        });
+                        continue;
+                    }
+                }
+                Operation::Erase { block, count } => {
+                    // Cached blocks, including unwritten changes, are
+                    // replaced by the erased data.
+                    for line in self.lines.iter() {
+                        if let Some(cached) = line.block.get() {
+                            if cached >= block && cached - block < count {
+                                line.block.set(None);
+                                line.dirty.set(false);
+                            }
+                        }
+                    }
+                    let ret = self.device.erase(block, count);
+                    if ret == ReturnCode::SUCCESS {
+                        self.device_operation.set(DeviceOperation::Erase);
+                    }
+                    ret
+                }
+                Operation::Flush => match self.lines.iter().position(|line| line.dirty.get()) {
+                    Some(line) => self.write_back(line),
+                    None => return self.finish(Ok(())),
+                },
+            };
+            if ret != ReturnCode::SUCCESS {
+                self.finish(Err(ret));
+            }
+            return;
+        }
+    }
+
+    fn finish(&self, result: Result<(), ReturnCode>) {
+        let operation = self.operation.replace(Operation::Idle);
+        match operation {
+            Operation::Read { .. } => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.read_complete(buffer, result));
+                });
+            }
+            Operation::Write { .. } => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_complete(buffer, result));
+                });
+            }
+            Operation::Erase { .. } => {
+                self.client.map(|client| client.erase_complete(result));
+            }
+            Operation::Flush => {
+                self.client.map(|client| client.flush_complete(result));
+            }
+            Operation::Idle => {}
+        }
+    }
+
+    fn check(&self, buffer: &[u8], block: u32, count: u32) -> ReturnCode {
+        let geometry = self.device.geometry();
+        if !geometry.contains(block, count) || buffer.len() < count as usize * geometry.block_size {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+}
+
+impl<'a> BlockStorage<'a> for BlockCache<'a> {
+    fn set_client(&self, client: &'a dyn block_storage::Client) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.device.geometry()
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let mut ret = self.check(buffer, block, count);
+        if ret == ReturnCode::SUCCESS {
+            ret = self.start(Operation::Read {
+                block,
+                count,
+                done: 0,
+            });
+        }
+        if ret != ReturnCode::SUCCESS {
+            return Err((ret, buffer));
+        }
+        self.buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let mut ret = self.check(buffer, block, count);
+        if ret == ReturnCode::SUCCESS {
+            ret = self.start(Operation::Write {
+                block,
+                count,
+                done: 0,
+            });
+        }
+        if ret != ReturnCode::SUCCESS {
+            return Err((ret, buffer));
+        }
+        self.buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn erase(&self, block: u32, count: u32) -> ReturnCode {
+        if !self.device.geometry().contains(block, count) {
+            return ReturnCode::EINVAL;
+        }
+        self.start(Operation::Erase { block, count })
+    }
+
+    fn flush(&self) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if !self.lines.iter().any(|line| line.dirty.get()) {
+            return ReturnCode::EALREADY;
+        }
+        self.start(Operation::Flush)
+    }
+}
+
+impl<'a> block_storage::Client for BlockCache<'a> {
+    fn read_complete(&self, io_buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        if let DeviceOperation::Fill(line, block) = self.device_operation.get() {
+            if result.is_ok() {
+                let block_size = self.block_size();
+                self.memory.map(|memory| {
+                    memory[line * block_size..(line + 1) * block_size]
+                        .copy_from_slice(&io_buffer[..block_size]);
+                });
+                self.lines[line].block.set(Some(block));
+                self.lines[line].dirty.set(false);
+                self.touch(line);
+                // Hand the block to the client here, so it isn't counted as
+                // a hit as well.
+                if let Operation::Read { block, count, done } = self.operation.get() {
+                    self.copy_line(line, done, false);
+                    self.operation.set(Operation::Read {
+                        block,
+                        count,
+                        done: done + 1,
+                    });
+                }
+            }
+        }
+        self.device_operation.set(DeviceOperation::Idle);
+        self.io_buffer.replace(io_buffer);
+        match result {
+            Ok(()) => self.run(),
+            Err(e) => self.finish(Err(e)),
+        }
+    }
+
+    fn write_complete(&self, io_buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        if let DeviceOperation::WriteBack(line) = self.device_operation.get() {
+            if result.is_ok() {
+                self.lines[line].dirty.set(false);
+            }
+        }
+        self.device_operation.set(DeviceOperation::Idle);
+        self.io_buffer.replace(io_buffer);
+        match result {
+            Ok(()) => self.run(),
+            Err(e) => self.finish(Err(e)),
+        }
+    }
+
+    fn erase_complete(&self, result: Result<(), ReturnCode>) {
+        self.device_operation.set(DeviceOperation::Idle);
+        self.finish(result);
+    }
+
+    fn flush_complete(&self, _result: Result<(), ReturnCode>) {}
+}
+
+impl<'a> DynamicDeferredCallClient for BlockCache<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.run();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    const BLOCK_SIZE: usize = 16;
+    const BLOCK_COUNT: u32 = 32;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Access {
+        Read(u32),
+        Write(u32),
+    }
+
+    /// A device that records its accesses and completes them when `run` is
+    /// called.
+    struct TestDevice {
+        blocks: RefCell<Vec<[u8; BLOCK_SIZE]>>,
+        accesses: RefCell<Vec<Access>>,
+        pending: Cell<Option<Access>>,
+        buffer: TakeCell<'static, [u8]>,
+        client: OptionalCell<&'static dyn block_storage::Client>,
+    }
+
+    impl TestDevice {
+        fn start(&self, access: Access, buffer: &'static mut [u8]) {
+            assert!(self.pending.get().is_none(), "device is busy");
+            self.accesses.borrow_mut().push(access);
+            self.pending.set(Some(access));
+            self.buffer.replace(buffer);
+        }
+
+        fn run(&self) -> bool {
+            let access = match self.pending.take() {
+                Some(access) => access,
+                None => return false,
+            };
+            let buffer = self.buffer.take().unwrap();
+            self.client.map(move |client| match access {
+                Access::Read(_) => client.read_complete(buffer, Ok(())),
+                Access::Write(_) => client.write_complete(buffer, Ok(())),
+            });
+            true
+        }
+    }
+
+    impl BlockStorage<'static> for TestDevice {
+        fn set_client(&self, client: &'static dyn block_storage::Client) {
+            self.client.set(client);
+        }
+
+        fn geometry(&self) -> Geometry {
+            Geometry {
+                block_size: BLOCK_SIZE,
+                block_count: BLOCK_COUNT,
+                erase_blocks: 1,
+                needs_erase: false,
+            }
+        }
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            block: u32,
+            count: u32,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            assert_eq!(count, 1);
+            buffer[..BLOCK_SIZE].copy_from_slice(&self.blocks.borrow()[block as usize]);
+            self.start(Access::Read(block), buffer);
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            block: u32,
+            count: u32,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            assert_eq!(count, 1);
+            self.blocks.borrow_mut()[block as usize].copy_from_slice(&buffer[..BLOCK_SIZE]);
+            self.start(Access::Write(block), buffer);
+            Ok(())
+        }
+
+        fn erase(&self, _block: u32, _count: u32) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn flush(&self) -> ReturnCode {
+            ReturnCode::EALREADY
+        }
+    }
+
+    struct TestClient {
+        result: Cell<Option<Result<(), ReturnCode>>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl block_storage::Client for TestClient {
+        fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+            self.buffer.replace(buffer);
+            self.result.set(Some(result));
+        }
+
+        fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+            self.buffer.replace(buffer);
+            self.result.set(Some(result));
+        }
+
+        fn erase_complete(&self, result: Result<(), ReturnCode>) {
+            self.result.set(Some(result));
+        }
+
+        fn flush_complete(&self, result: Result<(), ReturnCode>) {
+            self.result.set(Some(result));
+        }
+    }
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn leak_buf(size: usize) -> &'static mut [u8] {
+        Box::leak(vec![0; size].into_boxed_slice())
+    }
+
+    struct Test {
+        deferred_caller: &'static DynamicDeferredCall,
+        device: &'static TestDevice,
+        cache: &'static BlockCache<'static>,
+        client: &'static TestClient,
+    }
+
+    impl Test {
+        /// A cache of `lines` blocks.
+        fn new(lines: usize) -> Test {
+            let deferred_caller = leak(DynamicDeferredCall::new(Box::leak(Box::new([
+                DynamicDeferredCallClientState::default(),
+            ]))));
+            let device = leak(TestDevice {
+                blocks: RefCell::new(vec![[0; BLOCK_SIZE]; BLOCK_COUNT as usize]),
+                accesses: RefCell::new(Vec::new()),
+                pending: Cell::new(None),
+                buffer: TakeCell::empty(),
+                client: OptionalCell::empty(),
+            });
+            let lines: &'static [CacheLine] = Box::leak(
+                (0..lines)
+                    .map(|_| CacheLine::default())
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice(),
+            );
+            let cache = leak(BlockCache::new(
+                device,
+                lines,
+                leak_buf(lines.len() * BLOCK_SIZE),
+                leak_buf(BLOCK_SIZE),
+                deferred_caller,
+            ));
+            cache.initialize_callback_handle(deferred_caller.register(cache).unwrap());
+            device.set_client(cache);
+            let client = leak(TestClient {
+                result: Cell::new(None),
+                buffer: TakeCell::new(leak_buf(4 * BLOCK_SIZE)),
+            });
+            cache.set_client(client);
+            Test {
+                deferred_caller,
+                device,
+                cache,
+                client,
+            }
+        }
+
+        fn complete(&self) -> Result<(), ReturnCode> {
+            loop {
+                if self.device.run() {
+                    continue;
+                }
+                if !self.deferred_caller.has_pending() {
+                    break;
+                }
+                self.deferred_caller.call();
+            }
+            self.client
+                .result
+                .take()
+                .expect("operation did not complete")
+        }
+
+        /// Writes a block filled with each of `fills`, starting at `block`.
+        fn write(&self, block: u32, fills: &[u8]) -> Result<(), ReturnCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            for (chunk, fill) in buffer.chunks_mut(BLOCK_SIZE).zip(fills) {
+                chunk.iter_mut().for_each(|b| *b = *fill);
+            }
+            match self.cache.write(buffer, block, fills.len() as u32) {
+                Ok(()) => self.complete(),
+                Err((e, buffer)) => {
+                    self.client.buffer.replace(buffer);
+                    Err(e)
+                }
+            }
+        }
+
+        /// The first byte of each of `count` blocks starting at `block`.
+        fn read(&self, block: u32, count: u32) -> Vec<u8> {
+            let buffer = self.client.buffer.take().unwrap();
+            assert!(self.cache.read(buffer, block, count).is_ok());
+            assert_eq!(self.complete(), Ok(()));
+            self.client
+                .buffer
+                .map(|buffer| {
+                    buffer
+                        .chunks(BLOCK_SIZE)
+                        .take(count as usize)
+                        .map(|chunk| chunk[0])
+                        .collect()
+                })
+                .unwrap()
+        }
+
+        fn flush(&self) -> Result<(), ReturnCode> {
+            match self.cache.flush() {
+                ReturnCode::SUCCESS => self.complete(),
+                e => Err(e),
+            }
+        }
+
+        fn accesses(&self) -> Vec<Access> {
+            self.device.accesses.replace(Vec::new())
+        }
+    }
+
+    #[test]
+    fn dirty_blocks_are_evicted_least_recently_used_first() {
+        let t = Test::new(3);
+        assert_eq!(t.write(1, &[0x11]), Ok(()));
+        assert_eq!(t.write(2, &[0x22]), Ok(()));
+        assert_eq!(t.write(3, &[0x33]), Ok(()));
+        assert_eq!(t.accesses(), []);
+
+        // Using block 1 makes block 2 the least recently used.
+        assert_eq!(t.read(1, 1), [0x11]);
+        assert_eq!(t.write(4, &[0x44]), Ok(()));
+        assert_eq!(t.write(5, &[0x55]), Ok(()));
+        assert_eq!(t.accesses(), [Access::Write(2), Access::Write(3)]);
+        assert_eq!(t.device.blocks.borrow()[2], [0x22; BLOCK_SIZE]);
+        assert_eq!(t.device.blocks.borrow()[3], [0x33; BLOCK_SIZE]);
+
+        // A flush writes the rest, in the order of the lines.
+        assert_eq!(t.flush(), Ok(()));
+        assert_eq!(
+            t.accesses(),
+            [Access::Write(1), Access::Write(4), Access::Write(5)]
+        );
+        assert_eq!(t.flush(), Err(ReturnCode::EALREADY));
+    }
+
+    #[test]
+    fn clean_blocks_are_evicted_without_writing() {
+        let t = Test::new(2);
+        t.device.blocks.borrow_mut()[7] = [0x77; BLOCK_SIZE];
+        assert_eq!(t.read(7, 1), [0x77]);
+        assert_eq!(t.write(8, &[0x88]), Ok(()));
+        assert_eq!(t.write(9, &[0x99]), Ok(()));
+        assert_eq!(t.accesses(), [Access::Read(7)]);
+
+        // Block 8 is now the oldest, and is written before it is replaced.
+        assert_eq!(t.read(7, 1), [0x77]);
+        assert_eq!(t.accesses(), [Access::Write(8), Access::Read(7)]);
+        assert_eq!(t.cache.statistics(), (0, 2));
+    }
+
+    #[test]
+    fn requests_larger_than_the_cache() {
+        let t = Test::new(2);
+        assert_eq!(t.write(10, &[1, 2, 3, 4]), Ok(()));
+        assert_eq!(t.accesses(), [Access::Write(10), Access::Write(11)]);
+
+        assert_eq!(t.read(10, 4), [1, 2, 3, 4]);
+        assert_eq!(
+            t.accesses(),
+            [
+                Access::Write(12),
+                Access::Read(10),
+                Access::Write(13),
+                Access::Read(11),
+                Access::Read(12),
+                Access::Read(13)
+            ]
+        );
+        assert_eq!(t.flush(), Err(ReturnCode::EALREADY));
+    }
+
+    #[test]
+    fn cache_without_lines() {
+        let t = Test::new(0);
+        assert_eq!(t.write(0, &[1]), Err(ReturnCode::ENOMEM));
+        assert_eq!(t.flush(), Err(ReturnCode::EALREADY));
+    }
+}
@@ -0,0 +1,738 @@
+//! Adapters between `hil::block_storage` and the other storage HILs.
+//!
+//! Drivers for storage devices implement `hil::flash` or
+//! `hil::nonvolatile_storage`. `FlashBlocks` and `NonvolatileBlocks` turn
+//! them into block devices, which can then be cached with
+//! `capsules::block_cache` and split between several users with
+//! `capsules::virtual_block_storage`. `BlockFlash` and `BlockNonvolatile`
+//! go the other way, so capsules written for `hil::flash`, such as `log` and
+//! `tickv`, and for `hil::nonvolatile_storage`, such as `fat`, can use a
+//! region of a shared block device.
+//!
+//! Usage
+//! -----
+//!
+//! A log and a FAT filesystem sharing an SD card:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sd_blocks = static_init!(
+//!     capsules::block_storage::NonvolatileBlocks<'static>,
+//!     capsules::block_storage::NonvolatileBlocks::new(
+//!         sdcard_storage,
+//!         512,
+//!         card_blocks,
+//!         static_init!([u8; 512], [0; 512]),
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(sdcard_storage, sd_blocks);
+//! let mux = static_init!(
+//!     capsules::virtual_block_storage::MuxBlockStorage<'static>,
+//!     capsules::virtual_block_storage::MuxBlockStorage::new(sd_blocks)
+//! );
+//! hil::block_storage::BlockStorage::set_client(sd_blocks, mux);
+//!
+//! let log_region = static_init!(
+//!     capsules::virtual_block_storage::VirtualBlockStorage<'static>,
+//!     capsules::virtual_block_storage::VirtualBlockStorage::new(mux, 0, 256)
+//! );
+//! log_region.setup();
+//! let log_flash = static_init!(
+//!     capsules::block_storage::BlockFlash<'static, 512>,
+//!     capsules::block_storage::BlockFlash::new(log_region, static_init!([u8; 512], [0; 512]))
+//! );
+//! hil::block_storage::BlockStorage::set_client(log_region, log_flash);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::block_storage::{self, BlockStorage, Geometry};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ReturnCode;
+
+/// An operation of several blocks that is done one block at a time.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Read { block: u32, count: u32, done: u32 },
+    Write { block: u32, count: u32, done: u32 },
+    Erase { block: u32, count: u32, done: u32 },
+}
+
+fn flash_result(error: hil::flash::Error) -> Result<(), ReturnCode> {
+    match error {
+        hil::flash::Error::CommandComplete => Ok(()),
+        hil::flash::Error::FlashError => Err(ReturnCode::FAIL),
+    }
+}
+
+fn check_request(geometry: Geometry, buffer: &[u8], block: u32, count: u32) -> ReturnCode {
+    if !geometry.contains(block, count) || buffer.len() < count as usize * geometry.block_size {
+        ReturnCode::EINVAL
+    } else {
+        ReturnCode::SUCCESS
+    }
+}
+
+/// A block device made of the pages of a `hil::flash` device, such as the
+/// MX25R6435F or the flash of a microcontroller. Each page is a block and
+/// is erased on its own.
+pub struct FlashBlocks<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    page: TakeCell<'static, F::Page>,
+    geometry: Geometry,
+    operation: Cell<Operation>,
+    buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn block_storage::Client>,
+}
+
+impl<'a, F: hil::flash::Flash> FlashBlocks<'a, F> {
+    /// `page_count` is the number of pages of `flash` to use, starting at
+    /// page 0.
+    pub fn new(flash: &'a F, page: &'static mut F::Page, page_count: u32) -> FlashBlocks<'a, F> {
+        let block_size = page.as_mut().len();
+        FlashBlocks {
+            flash,
+            page: TakeCell::new(page),
+            geometry: Geometry {
+                block_size,
+                block_count: page_count,
+                erase_blocks: 1,
+                needs_erase: true,
+            },
+            operation: Cell::new(Operation::Idle),
+            buffer: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Start the flash operation for the next block.
+    fn step(&self) -> ReturnCode {
+        let block_size = self.geometry.block_size;
+        match self.operation.get() {
+            Operation::Idle => ReturnCode::FAIL,
+            Operation::Read { block, done, .. } => {
+                self.page.take().map_or(ReturnCode::EBUSY, |page| {
+                    match self.flash.read_page((block + done) as usize, page) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((e, page)) => {
+                            self.page.replace(page);
+                            e
+                        }
+                    }
+                })
+            }
+            Operation::Write { block, done, .. } => {
+                self.page.take().map_or(ReturnCode::EBUSY, |page| {
+                    self.buffer.map(|buffer| {
+                        let start = done as usize * block_size;
+                        page.as_mut()
+                            .copy_from_slice(&buffer[start..start + block_size]);
+                    });
+                    match self.flash.write_page((block + done) as usize, page) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((e, page)) => {
+                            self.page.replace(page);
+                            e
+                        }
+                    }
+                })
+            }
+            Operation::Erase { block, done, .. } => self.flash.erase_page((block + done) as usize),
+        }
+    }
+
+    /// Move to the next block after the current one completed.
+    fn advance(&self, result: Result<(), ReturnCode>) {
+        let operation = self.operation.get();
+        let next = match operation {
+            Operation::Idle => return,
+            Operation::Read { block, count, done } => Operation::Read {
+                block,
+                count,
+                done: done + 1,
+            },
+            Operation::Write { block, count, done } => Operation::Write {
+                block,
+                count,
+                done: done + 1,
+            },
+            Operation::Erase { block, count, done } => Operation::Erase {
+                block,
+                count,
+                done: done + 1,
+            },
+        };
+        let finished = match next {
+            Operation::Read { count, done, .. }
+            | Operation::Write { count, done, .. }
+            | Operation::Erase { count, done, .. } => done == count,
+            Operation::Idle => true,
+        };
+
+        let mut result = result;
+        if result.is_ok() && !finished {
+            self.operation.set(next);
+            let ret = self.step();
+            if ret == ReturnCode::SUCCESS {
+                return;
+            }
+            result = Err(ret);
+        }
+
+        self.operation.set(Operation::Idle);
+        match operation {
+            Operation::Read { .. } => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.read_complete(buffer, result));
+                });
+            }
+            Operation::Write { .. } => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_complete(buffer, result));
+                });
+            }
+            Operation::Erase { .. } => {
+                self.client.map(|client| client.erase_complete(result));
+            }
+            Operation::Idle => {}
+        }
+    }
+
+    fn start(
+        &self,
+        operation: Operation,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.operation.get() != Operation::Idle {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        let ret = check_request(self.geometry, buffer, block, count);
+        if ret != ReturnCode::SUCCESS {
+            return Err((ret, buffer));
+        }
+
+        self.buffer.replace(buffer);
+        self.operation.set(operation);
+        let ret = self.step();
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+            // The buffer was stored above
+            return Err((ret, self.buffer.take().unwrap_or(&mut [])));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, F: hil::flash::Flash> BlockStorage<'a> for FlashBlocks<'a, F> {
+    fn set_client(&self, client: &'a dyn block_storage::Client) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(
+            Operation::Read {
+                block,
+                count,
+                done: 0,
+            },
+            buffer,
+            block,
+            count,
+        )
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(
+            Operation::Write {
+                block,
+                count,
+                done: 0,
+            },
+            buffer,
+            block,
+            count,
+        )
+    }
+
+    fn erase(&self, block: u32, count: u32) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if !self.geometry.contains(block, count) {
+            return ReturnCode::EINVAL;
+        }
+        self.operation.set(Operation::Erase {
+            block,
+            count,
+            done: 0,
+        });
+        let ret = self.step();
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+        }
+        ret
+    }
+
+    fn flush(&self) -> ReturnCode {
+        ReturnCode::EALREADY
+    }
+}
+
+impl<'a, F: hil::flash::Flash> hil::flash::Client<F> for FlashBlocks<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        if let Operation::Read { done, .. } = self.operation.get() {
+            let block_size = self.geometry.block_size;
+            self.buffer.map(|buffer| {
+                let start = done as usize * block_size;
+                buffer[start..start + block_size].copy_from_slice(page.as_mut());
+            });
+        }
+        self.page.replace(page);
+        self.advance(flash_result(error));
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.page.replace(page);
+        self.advance(flash_result(error));
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        self.advance(flash_result(error));
+    }
+}
+
+/// A block device over a `hil::nonvolatile_storage` device, such as
+/// `capsules::sdcard::SDCardStorage` or the FM25CL FRAM. Blocks can be
+/// rewritten without erasing them. Erasing writes `0xFF` to each block.
+///
+/// `hil::nonvolatile_storage` doesn't give buffers back when it rejects a
+/// request. Requests are checked before they are passed on, but if the
+/// storage still rejects one the error is returned with an empty buffer.
+pub struct NonvolatileBlocks<'a> {
+    storage: &'a dyn NonvolatileStorage<'static>,
+    geometry: Geometry,
+    operation: Cell<Operation>,
+    erase_buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn block_storage::Client>,
+}
+
+impl<'a> NonvolatileBlocks<'a> {
+    /// `erase_buffer` must hold one block.
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'static>,
+        block_size: usize,
+        block_count: u32,
+        erase_buffer: &'static mut [u8],
+    ) -> NonvolatileBlocks<'a> {
+        NonvolatileBlocks {
+            storage,
+            geometry: Geometry {
+                block_size,
+                block_count,
+                erase_blocks: 1,
+                needs_erase: false,
+            },
+            operation: Cell::new(Operation::Idle),
+            erase_buffer: TakeCell::new(erase_buffer),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn erase_next(&self) -> ReturnCode {
+        match self.operation.get() {
+            Operation::Erase { block, done, .. } => {
+                let block_size = self.geometry.block_size;
+                self.erase_buffer
+                    .take()
+                    .map_or(ReturnCode::EBUSY, |buffer| {
+                        for b in buffer[..block_size].iter_mut() {
+                            *b = 0xFF;
+                        }
+                        self.storage
+                            .write(buffer, (block + done) as usize * block_size, block_size)
+                    })
+            }
+            _ => ReturnCode::FAIL,
+        }
+    }
+
+    fn start(
+        &self,
+        write: bool,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.operation.get() != Operation::Idle {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        let ret = check_request(self.geometry, buffer, block, count);
+        if ret != ReturnCode::SUCCESS {
+            return Err((ret, buffer));
+        }
+
+        let address = block as usize * self.geometry.block_size;
+        let length = count as usize * self.geometry.block_size;
+        let ret = if write {
+            self.operation.set(Operation::Write {
+                block,
+                count,
+                done: 0,
+            });
+            self.storage.write(buffer, address, length)
+        } else {
+            self.operation.set(Operation::Read {
+                block,
+                count,
+                done: 0,
+            });
+            self.storage.read(buffer, address, length)
+        };
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+            return Err((ret, &mut []));
+        }
+        Ok(())
+    }
+
+    fn result(&self, length: usize, expected: u32) -> Result<(), ReturnCode> {
+        if length == expected as usize * self.geometry.block_size {
+            Ok(())
+        } else {
+            Err(ReturnCode::FAIL)
+        }
+    }
+}
+
+impl<'a> BlockStorage<'a> for NonvolatileBlocks<'a> {
+    fn set_client(&self, client: &'a dyn block_storage::Client) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(false, buffer, block, count)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(true, buffer, block, count)
+    }
+
+    fn erase(&self, block: u32, count: u32) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if !self.geometry.contains(block, count) {
+            return ReturnCode::EINVAL;
+        }
+        self.operation.set(Operation::Erase {
+            block,
+            count,
+            done: 0,
+        });
+        let ret = self.erase_next();
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+        }
+        ret
+    }
+
+    fn flush(&self) -> ReturnCode {
+        ReturnCode::EALREADY
+    }
+}
+
+impl<'a> NonvolatileStorageClient<'static> for NonvolatileBlocks<'a> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        if let Operation::Read { count, .. } = self.operation.get() {
+            self.operation.set(Operation::Idle);
+            let result = self.result(length, count);
+            self.client
+                .map(move |client| client.read_complete(buffer, result));
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.operation.get() {
+            Operation::Write { count, .. } => {
+                self.operation.set(Operation::Idle);
+                let result = self.result(length, count);
+                self.client
+                    .map(move |client| client.write_complete(buffer, result));
+            }
+            Operation::Erase { block, count, done } => {
+                self.erase_buffer.replace(buffer);
+                let mut result = self.result(length, 1);
+                if result.is_ok() && done + 1 < count {
+                    self.operation.set(Operation::Erase {
+                        block,
+                        count,
+                        done: done + 1,
+                    });
+                    let ret = self.erase_next();
+                    if ret == ReturnCode::SUCCESS {
+                        return;
+                    }
+                    result = Err(ret);
+                }
+                self.operation.set(Operation::Idle);
+                self.client.map(|client| client.erase_complete(result));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A flash page of `S` bytes, for `BlockFlash`.
+pub struct BlockPage<const S: usize>(pub [u8; S]);
+
+impl<const S: usize> Default for BlockPage<S> {
+    fn default() -> Self {
+        BlockPage([0; S])
+    }
+}
+
+impl<const S: usize> AsMut<[u8]> for BlockPage<S> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// `hil::flash` over a block device, with pages of `S` bytes. `S` must be
+/// a multiple of the block size, and a multiple of the erase unit size for
+/// pages to be erased.
+pub struct BlockFlash<'a, const S: usize> {
+    device: &'a dyn BlockStorage<'a>,
+    buffer: TakeCell<'static, [u8]>,
+    page: TakeCell<'static, BlockPage<S>>,
+    client: OptionalCell<&'a dyn hil::flash::Client<BlockFlash<'a, S>>>,
+}
+
+impl<'a, const S: usize> BlockFlash<'a, S> {
+    /// `buffer` must hold `S` bytes.
+    pub fn new(device: &'a dyn BlockStorage<'a>, buffer: &'static mut [u8]) -> BlockFlash<'a, S> {
+        BlockFlash {
+            device,
+            buffer: TakeCell::new(buffer),
+            page: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// The first block and number of blocks of a page.
+    fn blocks(&self, page_number: usize) -> (u32, u32) {
+        let blocks = (S / self.device.geometry().block_size) as u32;
+        (page_number as u32 * blocks, blocks)
+    }
+
+    fn start(
+        &self,
+        write: bool,
+        page_number: usize,
+        page: &'static mut BlockPage<S>,
+    ) -> Result<(), (ReturnCode, &'static mut BlockPage<S>)> {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return Err((ReturnCode::EBUSY, page)),
+        };
+        let (block, count) = self.blocks(page_number);
+        let result = if write {
+            buffer[..S].copy_from_slice(&page.0);
+            self.device.write(buffer, block, count)
+        } else {
+            self.device.read(buffer, block, count)
+        };
+        match result {
+            Ok(()) => {
+                self.page.replace(page);
+                Ok(())
+            }
+            Err((e, buffer)) => {
+                self.buffer.replace(buffer);
+                Err((e, page))
+            }
+        }
+    }
+}
+
+impl<'a, const S: usize> hil::flash::Flash for BlockFlash<'a, S> {
+    type Page = BlockPage<S>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        self.start(false, page_number, buf)
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        self.start(true, page_number, buf)
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let (block, count) = self.blocks(page_number);
+        if count % cmp::max(self.device.geometry().erase_blocks, 1) != 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.device.erase(block, count)
+    }
+}
+
+impl<'a, C: hil::flash::Client<Self>, const S: usize> hil::flash::HasClient<'a, C>
+    for BlockFlash<'a, S>
+{
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<'a, const S: usize> block_storage::Client for BlockFlash<'a, S> {
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        let error = match result {
+            Ok(()) => hil::flash::Error::CommandComplete,
+            Err(_) => hil::flash::Error::FlashError,
+        };
+        self.page.take().map(|page| {
+            page.0.copy_from_slice(&buffer[..S]);
+            self.client
+                .map(move |client| client.read_complete(page, error));
+        });
+        self.buffer.replace(buffer);
+    }
+
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        self.buffer.replace(buffer);
+        let error = match result {
+            Ok(()) => hil::flash::Error::CommandComplete,
+            Err(_) => hil::flash::Error::FlashError,
+        };
+        self.page.take().map(|page| {
+            self.client
+                .map(move |client| client.write_complete(page, error));
+        });
+    }
+
+    fn erase_complete(&self, result: Result<(), ReturnCode>) {
+        let error = match result {
+            Ok(()) => hil::flash::Error::CommandComplete,
+            Err(_) => hil::flash::Error::FlashError,
+        };
+        self.client.map(|client| client.erase_complete(error));
+    }
+
+    fn flush_complete(&self, _result: Result<(), ReturnCode>) {}
+}
+
+/// `hil::nonvolatile_storage` over a block device. Addresses and lengths
+/// must be multiples of the block size.
+pub struct BlockNonvolatile<'a> {
+    device: &'a dyn BlockStorage<'a>,
+    length: Cell<usize>,
+    client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+}
+
+impl<'a> BlockNonvolatile<'a> {
+    pub fn new(device: &'a dyn BlockStorage<'a>) -> BlockNonvolatile<'a> {
+        BlockNonvolatile {
+            device,
+            length: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn blocks(&self, address: usize, length: usize) -> Option<(u32, u32)> {
+        let block_size = self.device.geometry().block_size;
+        if address % block_size != 0 || length % block_size != 0 {
+            return None;
+        }
+        Some(((address / block_size) as u32, (length / block_size) as u32))
+    }
+}
+
+impl<'a> NonvolatileStorage<'static> for BlockNonvolatile<'a> {
+    fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        match self.blocks(address, length) {
+            Some((block, count)) => {
+                self.length.set(length);
+                self.device
+                    .read(buffer, block, count)
+                    .map_or_else(|(e, _)| e, |()| ReturnCode::SUCCESS)
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        match self.blocks(address, length) {
+            Some((block, count)) => {
+                self.length.set(length);
+                self.device
+                    .write(buffer, block, count)
+                    .map_or_else(|(e, _)| e, |()| ReturnCode::SUCCESS)
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+}
+
+impl<'a> block_storage::Client for BlockNonvolatile<'a> {
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        let length = result.map_or(0, |()| self.length.get());
+        self.client
+            .map(move |client| client.read_done(buffer, length));
+    }
+
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        let length = result.map_or(0, |()| self.length.get());
+        self.client
+            .map(move |client| client.write_done(buffer, length));
+    }
+
+    fn erase_complete(&self, _result: Result<(), ReturnCode>) {}
+
+    fn flush_complete(&self, _result: Result<(), ReturnCode>) {}
+}
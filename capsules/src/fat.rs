@@ -0,0 +1,1437 @@
+//! FAT16 and FAT32 filesystem.
+//!
+//! `FatFs` stores files and directories using the FAT filesystems found on
+//! SD cards, so files written on a Tock board can be read on a PC. It works
+//! over any `hil::nonvolatile_storage::NonvolatileStorage` that can read and
+//! write whole 512 byte sectors, such as `capsules::sdcard::SDCardStorage`.
+//!
+//! ```text
+//! +-----------------------------------------+
+//! |     capsules::fat_driver (userspace)    |
+//! +-----------------------------------------+
+//!                fat::Client
+//! +-----------------------------------------+
+//! |        capsules::fat::FatFs (this)      |
+//! +-----------------------------------------+
+//!  hil::nonvolatile_storage::NonvolatileStorage
+//! +-----------------------------------------+
+//! |     capsules::sdcard::SDCardStorage     |
+//! +-----------------------------------------+
+//! ```
+//!
+//! The filesystem is found either at the start of the storage or in the
+//! first partition of an MBR partition table. Only short (8.3) names are
+//! supported. Long file names written by other systems are ignored, so those
+//! files are listed and opened by their short names. Paths use `/` to
+//! separate directories and start from the root directory.
+//!
+//! Up to `MAX_OPEN_FILES` files can be open at once. The clusters of a file
+//! are found by following its cluster chain in the FAT one entry at a time,
+//! so seeking backwards in a large file is slow.
+//!
+//! One operation runs at a time. A single sector buffer caches the last
+//! sector used, and modified sectors are written back before an operation
+//! completes. The directory entry of a file is updated after every write, so
+//! files don't need to be closed to be read correctly after a power loss.
+//! Callbacks are always delivered asynchronously.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let fat = static_init!(
+//!     capsules::fat::FatFs<'static>,
+//!     capsules::fat::FatFs::new(
+//!         sdcard_storage,
+//!         &mut capsules::fat::BUFFER,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! fat.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(fat)
+//!         .expect("no deferred call slot available for fat"),
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(sdcard_storage, fat);
+//! fat.set_client(fat_driver);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ReturnCode;
+
+/// The only sector size supported.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The number of files that can be open at once.
+pub const MAX_OPEN_FILES: usize = 4;
+
+/// The longest path that can be used.
+pub const MAX_PATH: usize = 64;
+
+/// Buffer for the sector cache, assigned in board `main.rs` files
+pub static mut BUFFER: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+
+/// The first byte of a directory entry that was deleted
+const ENTRY_FREE: u8 = 0xE5;
+/// The first byte of the first unused directory entry
+const ENTRY_END: u8 = 0x00;
+
+/// 1980-01-01, the earliest date FAT can store
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// A file or directory returned by `list_dir()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DirEntry {
+    /// The name, with a `.` before the extension if there is one
+    pub name: [u8; 12],
+    /// The number of bytes of `name` used
+    pub name_len: usize,
+    /// The size of a file in bytes
+    pub size: u32,
+    /// The entry is a directory
+    pub directory: bool,
+}
+
+impl DirEntry {
+    /// The name of the entry.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+pub trait Client {
+    /// The filesystem was mounted, or it couldn't be found.
+    fn mount_done(&self, result: Result<(), ReturnCode>);
+
+    /// A file was opened. On success the handle of the file is returned.
+    fn open_done(&self, result: Result<usize, ReturnCode>);
+
+    /// A directory was created.
+    fn mkdir_done(&self, result: Result<(), ReturnCode>);
+
+    /// `length` bytes were read from a file in to `buffer`. Fewer bytes than
+    /// requested are read at the end of the file.
+    fn read_done(&self, result: Result<(), ReturnCode>, buffer: &'static mut [u8], length: usize);
+
+    /// `length` bytes were written to a file from `buffer`.
+    fn write_done(&self, result: Result<(), ReturnCode>, buffer: &'static mut [u8], length: usize);
+
+    /// An entry of a directory was found. `ENOSUPPORT` indicates there are no
+    /// more entries.
+    fn list_done(&self, result: Result<DirEntry, ReturnCode>);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// The layout of a mounted filesystem. All sectors are from the start of the
+/// storage.
+#[derive(Clone, Copy)]
+struct Volume {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    num_fats: u32,
+    /// The fixed root directory of FAT16
+    root_start: u32,
+    root_sectors: u32,
+    /// The first cluster of the root directory of FAT32
+    root_cluster: u32,
+    data_start: u32,
+    clusters: u32,
+}
+
+impl Volume {
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    fn entry_size(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// The sector holding the FAT entry of `cluster` in FAT `copy`.
+    fn fat_sector(&self, cluster: u32, copy: u32) -> u32 {
+        self.fat_start + copy * self.fat_sectors + cluster * self.entry_size() / SECTOR_SIZE as u32
+    }
+
+    fn fat_offset(&self, cluster: u32) -> usize {
+        (cluster * self.entry_size()) as usize % SECTOR_SIZE
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Whether a FAT entry doesn't point to another cluster. Bad clusters
+    /// and entries outside of the volume also end a chain.
+    fn is_end(&self, value: u32) -> bool {
+        value < 2 || value >= self.clusters + 2
+    }
+
+    /// The position of the start of a directory. A first cluster of zero is
+    /// the root directory.
+    fn dir_start(&self, cluster: u32) -> DirPos {
+        let cluster = if cluster == 0 && self.fat_type == FatType::Fat32 {
+            self.root_cluster
+        } else {
+            cluster
+        };
+        DirPos {
+            cluster,
+            sector: 0,
+            entry: 0,
+        }
+    }
+
+    fn dir_sector(&self, pos: DirPos) -> u32 {
+        if pos.cluster == 0 {
+            self.root_start + pos.sector
+        } else {
+            self.cluster_sector(pos.cluster) + pos.sector
+        }
+    }
+}
+
+/// A position in a directory. A cluster of zero is the FAT16 root directory.
+#[derive(Clone, Copy, PartialEq)]
+struct DirPos {
+    cluster: u32,
+    /// The sector in the cluster, or in the FAT16 root directory
+    sector: u32,
+    /// The entry in the sector
+    entry: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+struct File {
+    open: bool,
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    /// A cluster of the file, zero if not known yet
+    cluster: u32,
+    /// The index of `cluster` in the cluster chain
+    index: u32,
+    /// The location of the directory entry of the file
+    entry_sector: u32,
+    entry_index: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Mount,
+    Open(bool),
+    Mkdir,
+    Read(usize),
+    Write(usize),
+    List,
+}
+
+/// Who continues after the next cluster in a chain is found
+#[derive(Clone, Copy, PartialEq)]
+enum Walk {
+    Search,
+    List,
+    Data,
+}
+
+/// What a newly allocated cluster is used for
+#[derive(Clone, Copy, PartialEq)]
+enum Purpose {
+    Data,
+    DirExtend,
+    NewDir,
+}
+
+#[derive(Clone, Copy)]
+struct Alloc {
+    /// The cluster to link the new cluster to, or zero
+    prev: u32,
+    new: u32,
+    zero: bool,
+    purpose: Purpose,
+}
+
+/// The current step of an operation. Every step except `Done` works on a
+/// single sector.
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Write back the cache and complete the operation
+    Done,
+    /// Read the boot sector or MBR in a sector
+    Boot(u32),
+    /// Look for the current path component in a directory
+    Search(DirPos),
+    /// Look for the next entry to list in a directory
+    List(DirPos),
+    /// Find the cluster after a cluster
+    Next(u32, Walk),
+    /// Look for a free cluster in a sector of the FAT
+    Free(u32),
+    /// Set the FAT entry of a cluster to a value in one copy of the FAT
+    SetFat(u32, u32, u32),
+    /// Clear a sector of a cluster
+    Zero(u32, u32),
+    /// Add the `.` and `..` entries to a new directory
+    Dots(u32),
+    /// Read or write the data at the position of the file
+    Data,
+    /// Write a directory entry
+    Entry(u32, usize),
+}
+
+pub struct FatFs<'a> {
+    storage: &'a dyn NonvolatileStorage<'static>,
+    buffer: TakeCell<'static, [u8]>,
+    /// The sector in `buffer`
+    cached: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    /// The sector being read
+    reading: Cell<u32>,
+    volume: Cell<Option<Volume>>,
+    files: [Cell<File>; MAX_OPEN_FILES],
+
+    operation: Cell<Operation>,
+    step: Cell<Step>,
+    result: Cell<Result<(), ReturnCode>>,
+
+    path: Cell<[u8; MAX_PATH]>,
+    path_len: Cell<usize>,
+    /// The start of the next path component
+    path_pos: Cell<usize>,
+    /// The current path component as a short name
+    name: Cell<[u8; 11]>,
+    /// The first cluster of the directory being searched, zero for the root
+    dir: Cell<u32>,
+    /// A free directory entry found while searching
+    free_slot: Cell<Option<(u32, usize)>>,
+    /// The last cluster of the directory being searched
+    last_cluster: Cell<u32>,
+    /// The first cluster of a new directory
+    new_dir: Cell<u32>,
+    alloc: Cell<Alloc>,
+    /// Where to start looking for free clusters
+    next_free: Cell<u32>,
+    /// The number of FAT sectors searched for a free cluster
+    scanned: Cell<u32>,
+    /// The number of entries to skip when listing
+    index: Cell<usize>,
+    entry: Cell<DirEntry>,
+
+    handle: Cell<usize>,
+    data: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    done: Cell<usize>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    deferred_handle: OptionalCell<DeferredCallHandle>,
+    client: OptionalCell<&'a dyn Client>,
+}
+
+impl<'a> FatFs<'a> {
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'static>,
+        buffer: &'static mut [u8; SECTOR_SIZE],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> FatFs<'a> {
+        FatFs {
+            storage,
+            buffer: TakeCell::new(buffer),
+            cached: Cell::new(None),
+            dirty: Cell::new(false),
+            reading: Cell::new(0),
+            volume: Cell::new(None),
+            files: <[Cell<File>; MAX_OPEN_FILES]>::default(),
+            operation: Cell::new(Operation::Idle),
+            step: Cell::new(Step::Done),
+            result: Cell::new(Ok(())),
+            path: Cell::new([0; MAX_PATH]),
+            path_len: Cell::new(0),
+            path_pos: Cell::new(0),
+            name: Cell::new([0; 11]),
+            dir: Cell::new(0),
+            free_slot: Cell::new(None),
+            last_cluster: Cell::new(0),
+            new_dir: Cell::new(0),
+            alloc: Cell::new(Alloc {
+                prev: 0,
+                new: 0,
+                zero: false,
+                purpose: Purpose::Data,
+            }),
+            next_free: Cell::new(2),
+            scanned: Cell::new(0),
+            index: Cell::new(0),
+            entry: Cell::new(DirEntry::default()),
+            handle: Cell::new(0),
+            data: TakeCell::empty(),
+            length: Cell::new(0),
+            done: Cell::new(0),
+            deferred_caller,
+            deferred_handle: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.deferred_handle.replace(handle);
+    }
+
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.volume.get().is_some()
+    }
+
+    /// Find the filesystem on the storage. Any open files are closed.
+    pub fn mount(&self) -> ReturnCode {
+        let ret = self.check_idle();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+
+        self.volume.set(None);
+        self.cached.set(None);
+        for file in self.files.iter() {
+            file.set(File::default());
+        }
+        self.start(Operation::Mount, Step::Boot(0));
+        ReturnCode::SUCCESS
+    }
+
+    /// Open the file at `path`. If `create` is true and the file doesn't
+    /// exist an empty file is created. The directories in the path must
+    /// already exist.
+    ///
+    /// Returns `ENOMEM` if too many files are open. `open_done()` returns
+    /// `ENOSUPPORT` if the file doesn't exist, or `EBUSY` if it is already
+    /// open.
+    pub fn open(&self, path: &[u8], create: bool) -> ReturnCode {
+        let ret = self.check_mounted();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+        if !self.files.iter().any(|file| !file.get().open) {
+            return ReturnCode::ENOMEM;
+        }
+
+        match self.set_path(path) {
+            Some(true) => {
+                self.start(Operation::Open(create), Step::Search(self.root()));
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Create a directory at `path`. `mkdir_done()` returns `EALREADY` if it
+    /// already exists.
+    pub fn mkdir(&self, path: &[u8]) -> ReturnCode {
+        let ret = self.check_mounted();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+
+        match self.set_path(path) {
+            Some(true) => {
+                self.start(Operation::Mkdir, Step::Search(self.root()));
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Find the entry at `index` in the directory at `path`, not including
+    /// `.` and `..`. An empty path is the root directory. The client gets
+    /// `EINVAL` if `path` isn't a directory.
+    pub fn list_dir(&self, path: &[u8], index: usize) -> ReturnCode {
+        let ret = self.check_mounted();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+
+        self.index.set(index);
+        match self.set_path(path) {
+            Some(true) => self.start(Operation::List, Step::Search(self.root())),
+            Some(false) => self.start(Operation::List, Step::List(self.root())),
+            None => return ReturnCode::EINVAL,
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Read up to `length` bytes from the current position of a file.
+    pub fn read(
+        &self,
+        handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut [u8], ReturnCode)> {
+        self.start_data(Operation::Read(handle), handle, buffer, length)
+    }
+
+    /// Write `length` bytes at the current position of a file, extending the
+    /// file if needed.
+    pub fn write(
+        &self,
+        handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut [u8], ReturnCode)> {
+        self.start_data(Operation::Write(handle), handle, buffer, length)
+    }
+
+    /// Move the position of a file. The position can't be past the end of
+    /// the file.
+    pub fn seek(&self, handle: usize, position: usize) -> ReturnCode {
+        self.with_file(handle, |file| {
+            if position > file.size as usize {
+                return ReturnCode::EINVAL;
+            }
+            file.position = position as u32;
+            ReturnCode::SUCCESS
+        })
+    }
+
+    /// The size of a file in bytes.
+    pub fn file_size(&self, handle: usize) -> Result<usize, ReturnCode> {
+        match self.files.get(handle).map(|file| file.get()) {
+            Some(file) if file.open => Ok(file.size as usize),
+            _ => Err(ReturnCode::EINVAL),
+        }
+    }
+
+    /// Close a file. Files are up to date on the storage after every write,
+    /// so this doesn't access the storage.
+    pub fn close(&self, handle: usize) -> ReturnCode {
+        self.with_file(handle, |file| {
+            file.open = false;
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn with_file<F: FnOnce(&mut File) -> ReturnCode>(&self, handle: usize, f: F) -> ReturnCode {
+        let cell = match self.files.get(handle) {
+            Some(cell) if cell.get().open => cell,
+            _ => return ReturnCode::EINVAL,
+        };
+        match self.operation.get() {
+            Operation::Read(h) | Operation::Write(h) if h == handle => return ReturnCode::EBUSY,
+            _ => {}
+        }
+        let mut file = cell.get();
+        let ret = f(&mut file);
+        cell.set(file);
+        ret
+    }
+
+    fn check_idle(&self) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            ReturnCode::EBUSY
+        } else if self.buffer.is_none() {
+            // The buffer was lost to an error in the storage
+            ReturnCode::ENOMEM
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn check_mounted(&self) -> ReturnCode {
+        if !self.is_mounted() {
+            return ReturnCode::ERESERVE;
+        }
+        self.check_idle()
+    }
+
+    fn start_data(
+        &self,
+        operation: Operation,
+        handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut [u8], ReturnCode)> {
+        let ret = self.check_mounted();
+        if ret != ReturnCode::SUCCESS {
+            return Err((buffer, ret));
+        }
+        match self.files.get(handle) {
+            Some(file) if file.get().open => {}
+            _ => return Err((buffer, ReturnCode::EINVAL)),
+        }
+        if length > buffer.len() {
+            return Err((buffer, ReturnCode::EINVAL));
+        }
+
+        self.handle.set(handle);
+        self.data.replace(buffer);
+        self.length.set(length);
+        self.done.set(0);
+        self.operation.set(operation);
+        self.result.set(Ok(()));
+        self.data_next();
+        self.deferred_handle
+            .map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+
+    fn start(&self, operation: Operation, step: Step) {
+        self.operation.set(operation);
+        self.result.set(Ok(()));
+        self.step.set(step);
+        self.deferred_handle
+            .map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn root(&self) -> DirPos {
+        self.dir.set(0);
+        self.free_slot.set(None);
+        self.volume.get().map_or(
+            DirPos {
+                cluster: 0,
+                sector: 0,
+                entry: 0,
+            },
+            |volume| volume.dir_start(0),
+        )
+    }
+
+    /// Store `path` and parse its first component. Returns whether it has a
+    /// component, or `None` if it is invalid.
+    fn set_path(&self, path: &[u8]) -> Option<bool> {
+        if path.len() > MAX_PATH {
+            return None;
+        }
+        if path
+            .split(|b| *b == b'/')
+            .any(|component| !component.is_empty() && short_name(component).is_none())
+        {
+            return None;
+        }
+
+        let mut stored = [0; MAX_PATH];
+        stored[..path.len()].copy_from_slice(path);
+        self.path.set(stored);
+        self.path_len.set(path.len());
+        self.path_pos.set(0);
+        Some(self.next_component())
+    }
+
+    /// Move to the next path component. Returns false if there are none.
+    fn next_component(&self) -> bool {
+        let path = self.path.get();
+        let path = &path[..self.path_len.get()];
+        let mut pos = self.path_pos.get();
+        while pos < path.len() && path[pos] == b'/' {
+            pos += 1;
+        }
+        if pos == path.len() {
+            self.path_pos.set(pos);
+            return false;
+        }
+
+        let end = path[pos..]
+            .iter()
+            .position(|b| *b == b'/')
+            .map_or(path.len(), |i| pos + i);
+        // Components were checked by `set_path()`
+        self.name
+            .set(short_name(&path[pos..end]).unwrap_or([b' '; 11]));
+        self.path_pos.set(end);
+        true
+    }
+
+    /// Whether the current path component is the last one.
+    fn last_component(&self) -> bool {
+        let path = self.path.get();
+        path[self.path_pos.get()..self.path_len.get()]
+            .iter()
+            .all(|b| *b == b'/')
+    }
+
+    fn volume(&self) -> Volume {
+        self.volume.get().expect("filesystem not mounted")
+    }
+
+    fn fail(&self, code: ReturnCode) {
+        self.result.set(Err(code));
+        match self.operation.get() {
+            // Record what was written
+            Operation::Write(handle) => {
+                let file = self.files[handle].get();
+                self.step
+                    .set(Step::Entry(file.entry_sector, file.entry_index));
+            }
+            _ => self.step.set(Step::Done),
+        }
+    }
+
+    fn sector_of(&self, step: Step) -> Option<u32> {
+        match step {
+            Step::Done => None,
+            Step::Boot(sector) => Some(sector),
+            _ => {
+                let volume = self.volume();
+                Some(match step {
+                    Step::Search(pos) | Step::List(pos) => volume.dir_sector(pos),
+                    Step::Next(cluster, _) => volume.fat_sector(cluster, 0),
+                    Step::Free(sector) => volume.fat_start + sector,
+                    Step::SetFat(cluster, _, copy) => volume.fat_sector(cluster, copy),
+                    Step::Zero(cluster, sector) => volume.cluster_sector(cluster) + sector,
+                    Step::Dots(cluster) => volume.cluster_sector(cluster),
+                    Step::Data => {
+                        let file = self.files[self.handle.get()].get();
+                        volume.cluster_sector(file.cluster)
+                            + (file.position % volume.cluster_bytes()) / SECTOR_SIZE as u32
+                    }
+                    Step::Entry(sector, _) => sector,
+                    Step::Done | Step::Boot(_) => unreachable!(),
+                })
+            }
+        }
+    }
+
+    /// Run steps until the storage needs to be accessed or the operation
+    /// completes.
+    fn run(&self) {
+        // The buffer is only missing while the storage is in use
+        while let Some(buffer) = self.buffer.take() {
+            let step = self.step.get();
+            let sector = self.sector_of(step);
+
+            if sector.is_some() && sector == self.cached.get() {
+                self.handle_step(step, buffer);
+                self.buffer.replace(buffer);
+            } else if self.dirty.get() {
+                let address = self.cached.get().unwrap_or(0) as usize * SECTOR_SIZE;
+                let ret = self.storage.write(buffer, address, SECTOR_SIZE);
+                if ret != ReturnCode::SUCCESS {
+                    self.storage_failed(ret);
+                }
+                return;
+            } else if let Some(sector) = sector {
+                if let Step::Zero(..) = step {
+                    // The whole sector is overwritten
+                    self.cached.set(Some(sector));
+                    self.handle_step(step, buffer);
+                    self.buffer.replace(buffer);
+                    continue;
+                }
+
+                self.cached.set(None);
+                self.reading.set(sector);
+                let ret = self
+                    .storage
+                    .read(buffer, sector as usize * SECTOR_SIZE, SECTOR_SIZE);
+                if ret != ReturnCode::SUCCESS {
+                    self.storage_failed(ret);
+                }
+                return;
+            } else {
+                self.buffer.replace(buffer);
+                self.complete();
+                return;
+            }
+        }
+    }
+
+    /// The storage failed to start an operation and kept the buffer.
+    fn storage_failed(&self, ret: ReturnCode) {
+        self.cached.set(None);
+        self.dirty.set(false);
+        self.result.set(Err(ret));
+        self.step.set(Step::Done);
+        self.complete();
+    }
+
+    fn complete(&self) {
+        let result = self.result.get();
+        match self.operation.replace(Operation::Idle) {
+            Operation::Idle => None,
+            Operation::Mount => self.client.map(|client| client.mount_done(result)),
+            Operation::Open(_) => self.client.map(|client| {
+                client.open_done(result.map(|()| self.handle.get()));
+            }),
+            Operation::Mkdir => self.client.map(|client| client.mkdir_done(result)),
+            operation @ Operation::Read(_) | operation @ Operation::Write(_) => {
+                let write = matches!(operation, Operation::Write(_));
+                self.data.take().and_then(|buffer| {
+                    self.client.map(move |client| {
+                        if write {
+                            client.write_done(result, buffer, self.done.get())
+                        } else {
+                            client.read_done(result, buffer, self.done.get())
+                        }
+                    })
+                })
+            }
+            Operation::List => self.client.map(|client| {
+                client.list_done(result.map(|()| self.entry.get()));
+            }),
+        };
+    }
+
+    fn handle_step(&self, step: Step, buffer: &mut [u8]) {
+        match step {
+            Step::Done => {}
+            Step::Boot(sector) => self.boot(sector, buffer),
+            Step::Search(pos) => self.search(pos, buffer),
+            Step::List(pos) => self.list(pos, buffer),
+            Step::Next(cluster, walk) => {
+                let volume = self.volume();
+                let offset = volume.fat_offset(cluster);
+                let value = match volume.fat_type {
+                    FatType::Fat16 => u16_at(buffer, offset) as u32,
+                    FatType::Fat32 => u32_at(buffer, offset) & 0x0FFF_FFFF,
+                };
+                if volume.is_end(value) {
+                    self.chain_end(cluster, walk);
+                } else {
+                    self.chain_next(value, walk);
+                }
+            }
+            Step::Free(sector) => self.free(sector, buffer),
+            Step::SetFat(cluster, value, copy) => {
+                let volume = self.volume();
+                let offset = volume.fat_offset(cluster);
+                match volume.fat_type {
+                    FatType::Fat16 => set_u16(buffer, offset, value as u16),
+                    FatType::Fat32 => {
+                        // The top bits are reserved
+                        let old = u32_at(buffer, offset) & 0xF000_0000;
+                        set_u32(buffer, offset, old | value);
+                    }
+                }
+                self.dirty.set(true);
+
+                if copy + 1 < volume.num_fats {
+                    self.step.set(Step::SetFat(cluster, value, copy + 1));
+                } else {
+                    self.fat_set(cluster);
+                }
+            }
+            Step::Zero(cluster, sector) => {
+                for b in buffer.iter_mut() {
+                    *b = 0;
+                }
+                self.dirty.set(true);
+
+                if sector + 1 < self.volume().sectors_per_cluster {
+                    self.step.set(Step::Zero(cluster, sector + 1));
+                } else {
+                    self.allocated(cluster);
+                }
+            }
+            Step::Dots(cluster) => {
+                let mut dot = [b' '; 11];
+                dot[0] = b'.';
+                write_entry(&mut buffer[..ENTRY_SIZE], dot, ATTR_DIRECTORY, cluster, 0);
+                dot[1] = b'.';
+                write_entry(
+                    &mut buffer[ENTRY_SIZE..2 * ENTRY_SIZE],
+                    dot,
+                    ATTR_DIRECTORY,
+                    self.dir.get(),
+                    0,
+                );
+                self.dirty.set(true);
+                self.place_entry();
+            }
+            Step::Data => self.transfer(buffer),
+            Step::Entry(sector, index) => {
+                let entry = &mut buffer[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+                match self.operation.get() {
+                    Operation::Write(handle) => {
+                        let file = self.files[handle].get();
+                        entry[11] |= ATTR_ARCHIVE;
+                        set_u16(entry, 20, (file.first_cluster >> 16) as u16);
+                        set_u16(entry, 26, file.first_cluster as u16);
+                        set_u32(entry, 28, file.size);
+                        self.step.set(Step::Done);
+                    }
+                    Operation::Mkdir => {
+                        write_entry(
+                            entry,
+                            self.name.get(),
+                            ATTR_DIRECTORY,
+                            self.new_dir.get(),
+                            0,
+                        );
+                        self.step.set(Step::Done);
+                    }
+                    _ => {
+                        write_entry(entry, self.name.get(), ATTR_ARCHIVE, 0, 0);
+                        self.open_file(sector, index, 0, 0);
+                    }
+                }
+                self.dirty.set(true);
+            }
+        }
+    }
+
+    fn boot(&self, sector: u32, buffer: &[u8]) {
+        if buffer[510] != 0x55 || buffer[511] != 0xAA {
+            return self.fail(ReturnCode::FAIL);
+        }
+
+        let is_boot_sector =
+            (buffer[0] == 0xEB || buffer[0] == 0xE9) && u16_at(buffer, 11) as usize == SECTOR_SIZE;
+        if !is_boot_sector {
+            // Look for the first partition in an MBR
+            let start = u32_at(buffer, 454);
+            if sector == 0 && buffer[450] != 0 && start != 0 {
+                self.step.set(Step::Boot(start));
+            } else {
+                self.fail(ReturnCode::FAIL);
+            }
+            return;
+        }
+
+        let sectors_per_cluster = buffer[13] as u32;
+        let reserved = u16_at(buffer, 14) as u32;
+        let num_fats = buffer[16] as u32;
+        let root_entries = u16_at(buffer, 17) as u32;
+        let total = match u16_at(buffer, 19) {
+            0 => u32_at(buffer, 32),
+            total => total as u32,
+        };
+        let fat_sectors = match u16_at(buffer, 22) {
+            0 => u32_at(buffer, 36),
+            fat_sectors => fat_sectors as u32,
+        };
+        let root_sectors =
+            (root_entries * ENTRY_SIZE as u32 + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
+
+        if !sectors_per_cluster.is_power_of_two() || num_fats == 0 || fat_sectors == 0 {
+            return self.fail(ReturnCode::FAIL);
+        }
+        // A corrupt boot sector can describe a volume that doesn't fit in
+        // 32-bit sector numbers.
+        let fats = num_fats.checked_mul(fat_sectors);
+        let meta = fats
+            .and_then(|fats| reserved.checked_add(fats))
+            .and_then(|sectors| sectors.checked_add(root_sectors));
+        let meta = match (meta, sector.checked_add(total)) {
+            (Some(meta), Some(_)) if total > meta => meta,
+            _ => return self.fail(ReturnCode::FAIL),
+        };
+        let clusters = (total - meta) / sectors_per_cluster;
+
+        let fat_type = if clusters < 4085 {
+            // FAT12 is only used on floppy disks
+            return self.fail(ReturnCode::ENOSUPPORT);
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        // These are below `sector + total`, so they don't overflow.
+        let fat_start = sector + reserved;
+        let root_start = fat_start + num_fats * fat_sectors;
+        self.volume.set(Some(Volume {
+            fat_type,
+            sectors_per_cluster,
+            fat_start,
+            fat_sectors,
+            num_fats,
+            root_start,
+            root_sectors,
+            root_cluster: match fat_type {
+                FatType::Fat16 => 0,
+                FatType::Fat32 => u32_at(buffer, 44),
+            },
+            data_start: root_start + root_sectors,
+            clusters,
+        }));
+        self.next_free.set(2);
+        self.step.set(Step::Done);
+    }
+
+    fn search(&self, pos: DirPos, buffer: &[u8]) {
+        let sector = self.volume().dir_sector(pos);
+        for index in pos.entry..ENTRIES_PER_SECTOR {
+            let entry = &buffer[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+            if entry[0] == ENTRY_END || entry[0] == ENTRY_FREE {
+                if self.free_slot.get().is_none() {
+                    self.free_slot.set(Some((sector, index)));
+                }
+                if entry[0] == ENTRY_END {
+                    return self.not_found(pos.cluster);
+                }
+                continue;
+            }
+            // This also skips long file name entries
+            if entry[11] & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            if entry[..11] == self.name.get() {
+                return self.found(sector, index, entry);
+            }
+        }
+        self.advance(pos, Walk::Search);
+    }
+
+    fn list(&self, pos: DirPos, buffer: &[u8]) {
+        for index in pos.entry..ENTRIES_PER_SECTOR {
+            let entry = &buffer[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+            if entry[0] == ENTRY_END {
+                return self.fail(ReturnCode::ENOSUPPORT);
+            }
+            if entry[0] == ENTRY_FREE || entry[0] == b'.' || entry[11] & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            if self.index.get() > 0 {
+                self.index.set(self.index.get() - 1);
+                continue;
+            }
+
+            let mut found = DirEntry {
+                size: u32_at(entry, 28),
+                directory: entry[11] & ATTR_DIRECTORY != 0,
+                ..DirEntry::default()
+            };
+            for (i, b) in entry[..11].iter().enumerate() {
+                if *b == b' ' {
+                    continue;
+                }
+                if i >= 8 && found.name[..found.name_len].iter().all(|b| *b != b'.') {
+                    found.name[found.name_len] = b'.';
+                    found.name_len += 1;
+                }
+                found.name[found.name_len] = *b;
+                found.name_len += 1;
+            }
+            self.entry.set(found);
+            self.step.set(Step::Done);
+            return;
+        }
+        self.advance(pos, Walk::List);
+    }
+
+    /// Move to the sector after `pos` in a directory.
+    fn advance(&self, pos: DirPos, walk: Walk) {
+        let volume = self.volume();
+        let sectors = if pos.cluster == 0 {
+            volume.root_sectors
+        } else {
+            volume.sectors_per_cluster
+        };
+
+        if pos.sector + 1 < sectors {
+            let next = DirPos {
+                cluster: pos.cluster,
+                sector: pos.sector + 1,
+                entry: 0,
+            };
+            self.step.set(match walk {
+                Walk::List => Step::List(next),
+                _ => Step::Search(next),
+            });
+        } else if pos.cluster != 0 {
+            self.step.set(Step::Next(pos.cluster, walk));
+        } else {
+            // The FAT16 root directory can't grow
+            self.chain_end(0, walk);
+        }
+    }
+
+    /// The cluster chain ends after `cluster`.
+    fn chain_end(&self, cluster: u32, walk: Walk) {
+        match walk {
+            Walk::Search => self.not_found(cluster),
+            Walk::List => self.fail(ReturnCode::ENOSUPPORT),
+            Walk::Data => {
+                if let Operation::Write(_) = self.operation.get() {
+                    self.allocate(cluster, false, Purpose::Data);
+                } else {
+                    // The file is shorter than its size
+                    self.fail(ReturnCode::FAIL);
+                }
+            }
+        }
+    }
+
+    /// `cluster` follows the previous cluster in a chain.
+    fn chain_next(&self, cluster: u32, walk: Walk) {
+        let pos = DirPos {
+            cluster,
+            sector: 0,
+            entry: 0,
+        };
+        match walk {
+            Walk::Search => self.step.set(Step::Search(pos)),
+            Walk::List => self.step.set(Step::List(pos)),
+            Walk::Data => {
+                let handle = self.handle.get();
+                let mut file = self.files[handle].get();
+                file.cluster = cluster;
+                file.index += 1;
+                self.files[handle].set(file);
+                self.data_next();
+            }
+        }
+    }
+
+    fn found(&self, sector: u32, index: usize, entry: &[u8]) {
+        let attr = entry[11];
+        let cluster = (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32;
+
+        if !self.last_component() || self.operation.get() == Operation::List {
+            if attr & ATTR_DIRECTORY == 0 {
+                return self.fail(ReturnCode::EINVAL);
+            }
+            self.dir.set(cluster);
+            self.free_slot.set(None);
+            let pos = self.volume().dir_start(cluster);
+            if self.next_component() {
+                self.step.set(Step::Search(pos));
+            } else {
+                self.step.set(Step::List(pos));
+            }
+            return;
+        }
+
+        match self.operation.get() {
+            Operation::Open(_) => {
+                if attr & ATTR_DIRECTORY != 0 {
+                    return self.fail(ReturnCode::EINVAL);
+                }
+                let open = self.files.iter().any(|file| {
+                    let file = file.get();
+                    file.open && file.entry_sector == sector && file.entry_index == index
+                });
+                if open {
+                    return self.fail(ReturnCode::EBUSY);
+                }
+                self.open_file(sector, index, cluster, u32_at(entry, 28));
+            }
+            _ => self.fail(ReturnCode::EALREADY),
+        }
+    }
+
+    /// The current path component isn't in the directory whose last cluster
+    /// is `last_cluster`.
+    fn not_found(&self, last_cluster: u32) {
+        self.last_cluster.set(last_cluster);
+        if self.operation.get() == Operation::List {
+            // Not to be confused with the end of the directory
+            return self.fail(ReturnCode::EINVAL);
+        }
+        if !self.last_component() {
+            return self.fail(ReturnCode::ENOSUPPORT);
+        }
+        match self.operation.get() {
+            Operation::Open(true) => self.place_entry(),
+            Operation::Mkdir => self.allocate(0, true, Purpose::NewDir),
+            _ => self.fail(ReturnCode::ENOSUPPORT),
+        }
+    }
+
+    /// Write a new directory entry to the free slot, or extend the
+    /// directory if there isn't one.
+    fn place_entry(&self) {
+        match self.free_slot.get() {
+            Some((sector, index)) => self.step.set(Step::Entry(sector, index)),
+            None if self.last_cluster.get() == 0 => self.fail(ReturnCode::ENOMEM),
+            None => self.allocate(self.last_cluster.get(), true, Purpose::DirExtend),
+        }
+    }
+
+    fn open_file(&self, sector: u32, index: usize, cluster: u32, size: u32) {
+        // A free handle was checked for by `open()`
+        if let Some(handle) = self.files.iter().position(|file| !file.get().open) {
+            self.files[handle].set(File {
+                open: true,
+                first_cluster: cluster,
+                size,
+                entry_sector: sector,
+                entry_index: index,
+                ..File::default()
+            });
+            self.handle.set(handle);
+        }
+        self.step.set(Step::Done);
+    }
+
+    /// Allocate a cluster, link it after `prev` if that isn't zero, and clear
+    /// it if `zero` is true.
+    fn allocate(&self, prev: u32, zero: bool, purpose: Purpose) {
+        let volume = self.volume();
+        self.alloc.set(Alloc {
+            prev,
+            new: 0,
+            zero,
+            purpose,
+        });
+        self.scanned.set(0);
+        let sector = self.next_free.get() * volume.entry_size() / SECTOR_SIZE as u32;
+        self.step
+            .set(Step::Free(cmp::min(sector, volume.fat_sectors - 1)));
+    }
+
+    fn free(&self, sector: u32, buffer: &[u8]) {
+        let volume = self.volume();
+        let per_sector = SECTOR_SIZE as u32 / volume.entry_size();
+
+        for i in 0..per_sector {
+            let cluster = sector * per_sector + i;
+            if cluster < 2 {
+                continue;
+            }
+            if cluster >= volume.clusters + 2 {
+                break;
+            }
+            let offset = volume.fat_offset(cluster);
+            let value = match volume.fat_type {
+                FatType::Fat16 => u16_at(buffer, offset) as u32,
+                FatType::Fat32 => u32_at(buffer, offset) & 0x0FFF_FFFF,
+            };
+            if value == 0 {
+                let mut alloc = self.alloc.get();
+                alloc.new = cluster;
+                self.alloc.set(alloc);
+                self.next_free.set(cluster + 1);
+                self.step
+                    .set(Step::SetFat(cluster, volume.end_of_chain(), 0));
+                return;
+            }
+        }
+
+        self.scanned.set(self.scanned.get() + 1);
+        if self.scanned.get() >= volume.fat_sectors {
+            return self.fail(ReturnCode::ENOMEM);
+        }
+        let next = sector + 1;
+        if next >= volume.fat_sectors || next * per_sector >= volume.clusters + 2 {
+            self.step.set(Step::Free(0));
+        } else {
+            self.step.set(Step::Free(next));
+        }
+    }
+
+    /// All copies of the FAT entry of `cluster` were updated.
+    fn fat_set(&self, cluster: u32) {
+        let alloc = self.alloc.get();
+        if cluster == alloc.new && alloc.prev != 0 {
+            self.step.set(Step::SetFat(alloc.prev, alloc.new, 0));
+        } else if alloc.zero {
+            self.step.set(Step::Zero(alloc.new, 0));
+        } else {
+            self.allocated(alloc.new);
+        }
+    }
+
+    fn allocated(&self, cluster: u32) {
+        let alloc = self.alloc.get();
+        match alloc.purpose {
+            Purpose::Data => {
+                let handle = self.handle.get();
+                let mut file = self.files[handle].get();
+                if alloc.prev == 0 {
+                    file.first_cluster = cluster;
+                    file.index = 0;
+                } else {
+                    file.index += 1;
+                }
+                file.cluster = cluster;
+                self.files[handle].set(file);
+                self.data_next();
+            }
+            Purpose::DirExtend => {
+                self.free_slot
+                    .set(Some((self.volume().cluster_sector(cluster), 0)));
+                self.place_entry();
+            }
+            Purpose::NewDir => {
+                self.new_dir.set(cluster);
+                self.step.set(Step::Dots(cluster));
+            }
+        }
+    }
+
+    /// Find the cluster for the position of the file being read or written.
+    fn data_next(&self) {
+        let handle = self.handle.get();
+        let mut file = self.files[handle].get();
+        let write = matches!(self.operation.get(), Operation::Write(_));
+
+        if self.done.get() == self.length.get() || (!write && file.position >= file.size) {
+            return if write {
+                self.step
+                    .set(Step::Entry(file.entry_sector, file.entry_index));
+            } else {
+                self.step.set(Step::Done);
+            };
+        }
+
+        let target = file.position / self.volume().cluster_bytes();
+        if file.cluster == 0 || file.index > target {
+            if file.first_cluster == 0 {
+                return self.allocate(0, false, Purpose::Data);
+            }
+            file.cluster = file.first_cluster;
+            file.index = 0;
+            self.files[handle].set(file);
+        }
+
+        if file.index < target {
+            self.step.set(Step::Next(file.cluster, Walk::Data));
+        } else {
+            self.step.set(Step::Data);
+        }
+    }
+
+    fn transfer(&self, buffer: &mut [u8]) {
+        let handle = self.handle.get();
+        let mut file = self.files[handle].get();
+        let write = matches!(self.operation.get(), Operation::Write(_));
+
+        let offset = file.position as usize % SECTOR_SIZE;
+        let done = self.done.get();
+        let mut count = cmp::min(SECTOR_SIZE - offset, self.length.get() - done);
+        if !write {
+            count = cmp::min(count, (file.size - file.position) as usize);
+        }
+
+        self.data.map(|data| {
+            if write {
+                buffer[offset..offset + count].copy_from_slice(&data[done..done + count]);
+            } else {
+                data[done..done + count].copy_from_slice(&buffer[offset..offset + count]);
+            }
+        });
+        if write {
+            self.dirty.set(true);
+        }
+
+        file.position += count as u32;
+        file.size = cmp::max(file.size, file.position);
+        self.files[handle].set(file);
+        self.done.set(done + count);
+        self.data_next();
+    }
+}
+
+impl<'a> NonvolatileStorageClient<'static> for FatFs<'a> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        if length < SECTOR_SIZE {
+            self.storage_failed(ReturnCode::FAIL);
+        } else {
+            self.cached.set(Some(self.reading.get()));
+            self.run();
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        if length < SECTOR_SIZE {
+            self.storage_failed(ReturnCode::FAIL);
+        } else {
+            self.dirty.set(false);
+            self.run();
+        }
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for FatFs<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.run();
+    }
+}
+
+/// Convert a file name to the space padded 8.3 form stored in directory
+/// entries. Returns `None` if it can't be stored as a short name.
+fn short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, extension) = match name.iter().rposition(|b| *b == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &name[name.len()..]),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    for (i, b) in base.iter().enumerate() {
+        short[i] = short_name_char(*b)?;
+    }
+    for (i, b) in extension.iter().enumerate() {
+        short[8 + i] = short_name_char(*b)?;
+    }
+    Some(short)
+}
+
+fn short_name_char(b: u8) -> Option<u8> {
+    if b.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&b) {
+        Some(b.to_ascii_uppercase())
+    } else {
+        None
+    }
+}
+
+fn write_entry(entry: &mut [u8], name: [u8; 11], attr: u8, cluster: u32, size: u32) {
+    for b in entry.iter_mut() {
+        *b = 0;
+    }
+    entry[..11].copy_from_slice(&name);
+    entry[11] = attr;
+    set_u16(entry, 16, DEFAULT_DATE);
+    set_u16(entry, 18, DEFAULT_DATE);
+    set_u16(entry, 20, (cluster >> 16) as u16);
+    set_u16(entry, 24, DEFAULT_DATE);
+    set_u16(entry, 26, cluster as u16);
+    set_u32(entry, 28, size);
+}
+
+fn u16_at(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buffer[offset],
+        buffer[offset + 1],
+        buffer[offset + 2],
+        buffer[offset + 3],
+    ])
+}
+
+fn set_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
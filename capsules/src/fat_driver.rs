@@ -0,0 +1,579 @@
+//! Syscall driver giving applications access to files in a FAT filesystem.
+//!
+//! Each application is confined to its own directory, `APPS/<NAME>`, which
+//! is created when the application first uses the driver. `<NAME>` is the
+//! package name from the TBF header if it is a valid 8 character FAT name,
+//! and otherwise `~` followed by a hash of the package name. Applications
+//! without a package name are named by a hash of their `AppId`, which does
+//! not survive a reboot. Paths given by applications are relative to their
+//! directory and can't contain `.` or `..`, so applications can't reach the
+//! files of other applications.
+//!
+//! Files are identified by the handles returned by `open`, which can only be
+//! used by the application that opened the file. The handles of applications
+//! that have exited are closed when another file is opened.
+//!
+//! Only one operation is in flight at a time. Each application can queue one
+//! operation while another application's operation is running.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let fat_driver_buffer = static_init!([u8; 512], [0; 512]);
+//! let fat_driver = static_init!(
+//!     capsules::fat_driver::FatDriver<'static>,
+//!     capsules::fat_driver::FatDriver::new(
+//!         fat,
+//!         board_kernel.create_grant(&grant_cap),
+//!         fat_driver_buffer,
+//!     )
+//! );
+//! fat.set_client(fat_driver);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::hash::Hasher;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::fat::{self, DirEntry, FatFs, MAX_OPEN_FILES, MAX_PATH};
+use crate::sip_hash::SipHasher24;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::FileSystem as usize;
+
+/// The directory holding the directories of applications.
+const APPS_DIR: &[u8] = b"APPS";
+
+/// The number of bytes `list` writes into the read buffer.
+pub const LIST_ENTRY_SIZE: usize = 17;
+
+#[derive(Clone, Copy, PartialEq)]
+enum UserOperation {
+    Open(bool),
+    Read(usize, usize),
+    Write(usize, usize),
+    List(usize),
+    Mkdir,
+}
+
+/// Work needed before an application's operation can run.
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Mount,
+    MakeAppsDir,
+    MakeHome,
+    Run,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    path: Option<AppSlice<Shared, u8>>,
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    write_buffer: Option<AppSlice<Shared, u8>>,
+    pending_command: Option<UserOperation>,
+    home_ready: bool,
+}
+
+pub struct FatDriver<'a> {
+    fat: &'a FatFs<'a>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+    operation: Cell<Option<UserOperation>>,
+    stage: Cell<Stage>,
+    owners: [Cell<Option<AppId>>; MAX_OPEN_FILES],
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a> FatDriver<'a> {
+    pub fn new(fat: &'a FatFs<'a>, grant: Grant<App>, buffer: &'static mut [u8]) -> FatDriver<'a> {
+        FatDriver {
+            fat,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            operation: Cell::new(None),
+            stage: Cell::new(Stage::Run),
+            owners: <[Cell<Option<AppId>>; MAX_OPEN_FILES]>::default(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    // Check to see if we are doing something. If not, go ahead and do this
+    // command. If so, this is queued and will be run when the pending command
+    // completes.
+    fn enqueue_command(&self, operation: UserOperation, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                let ready = match operation {
+                    UserOperation::Open(_) | UserOperation::Mkdir => app.path.is_some(),
+                    UserOperation::Read(..) => app.read_buffer.is_some(),
+                    UserOperation::Write(..) => app.write_buffer.is_some(),
+                    UserOperation::List(_) => app.read_buffer.is_some(),
+                };
+                if !ready {
+                    return ReturnCode::EINVAL;
+                }
+
+                if self.current_app.is_none() {
+                    let ret = self.start_command(operation, app, appid);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                    }
+                    ret
+                } else {
+                    // Queue this request for later.
+                    if app.pending_command.is_some() {
+                        ReturnCode::EBUSY
+                    } else {
+                        app.pending_command = Some(operation);
+                        ReturnCode::SUCCESS
+                    }
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Mount the filesystem and create the application's directory if that
+    /// hasn't been done yet, and then run the operation.
+    fn start_command(&self, operation: UserOperation, app: &mut App, appid: AppId) -> ReturnCode {
+        self.operation.set(Some(operation));
+        if !self.fat.is_mounted() {
+            self.stage.set(Stage::Mount);
+            self.fat.mount()
+        } else if !app.home_ready {
+            self.stage.set(Stage::MakeAppsDir);
+            self.fat.mkdir(APPS_DIR)
+        } else {
+            self.stage.set(Stage::Run);
+            self.run_command(operation, app, appid)
+        }
+    }
+
+    /// Continue the current operation after a stage completed.
+    fn continue_command(&self) {
+        let ret = self.current_app.map_or(ReturnCode::FAIL, |appid| {
+            let appid = *appid;
+            self.apps
+                .enter(appid, |app, _| match self.stage.get() {
+                    Stage::Mount => {
+                        self.stage.set(Stage::MakeAppsDir);
+                        self.fat.mkdir(APPS_DIR)
+                    }
+                    Stage::MakeAppsDir => {
+                        self.stage.set(Stage::MakeHome);
+                        let mut path = [0; MAX_PATH];
+                        let length = home_path(appid, &mut path);
+                        self.fat.mkdir(&path[..length])
+                    }
+                    Stage::MakeHome | Stage::Run => {
+                        app.home_ready = true;
+                        self.stage.set(Stage::Run);
+                        match self.operation.get() {
+                            Some(operation) => self.run_command(operation, app, appid),
+                            None => ReturnCode::FAIL,
+                        }
+                    }
+                })
+                .unwrap_or_else(|err| err.into())
+        });
+        if ret != ReturnCode::SUCCESS {
+            self.complete_command(Err(ret), 0);
+        }
+    }
+
+    fn run_command(&self, operation: UserOperation, app: &mut App, appid: AppId) -> ReturnCode {
+        match operation {
+            UserOperation::Open(create) => {
+                self.reclaim_handles();
+                let mut path = [0; MAX_PATH];
+                match app_path(app, appid, &mut path) {
+                    Some(length) => self.fat.open(&path[..length], create),
+                    None => ReturnCode::EINVAL,
+                }
+            }
+            UserOperation::Mkdir => {
+                let mut path = [0; MAX_PATH];
+                match app_path(app, appid, &mut path) {
+                    Some(length) => self.fat.mkdir(&path[..length]),
+                    None => ReturnCode::EINVAL,
+                }
+            }
+            UserOperation::List(index) => {
+                let mut path = [0; MAX_PATH];
+                match app_path(app, appid, &mut path) {
+                    Some(length) => self.fat.list_dir(&path[..length], index),
+                    None => ReturnCode::EINVAL,
+                }
+            }
+            UserOperation::Read(handle, length) => {
+                if !self.owns(handle, appid) {
+                    return ReturnCode::EINVAL;
+                }
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    let length = app.read_buffer.as_ref().map_or(0, |read_buffer| {
+                        cmp::min(length, cmp::min(read_buffer.len(), buffer.len()))
+                    });
+                    match self.fat.read(handle, buffer, length) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((buffer, e)) => {
+                            self.buffer.replace(buffer);
+                            e
+                        }
+                    }
+                })
+            }
+            UserOperation::Write(handle, length) => {
+                if !self.owns(handle, appid) {
+                    return ReturnCode::EINVAL;
+                }
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    // Larger writes are shortened to the size of the kernel
+                    // buffer, and the application is told how much was
+                    // written.
+                    let length = app.write_buffer.as_ref().map_or(0, |write_buffer| {
+                        let length = cmp::min(length, buffer.len());
+                        if length <= write_buffer.len() {
+                            buffer[..length].copy_from_slice(&write_buffer.as_ref()[..length]);
+                            length
+                        } else {
+                            0
+                        }
+                    });
+                    if length == 0 {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::EINVAL;
+                    }
+                    match self.fat.write(handle, buffer, length) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((buffer, e)) => {
+                            self.buffer.replace(buffer);
+                            e
+                        }
+                    }
+                })
+            }
+        }
+    }
+
+    /// Notify the current application and start the next queued command.
+    fn complete_command(&self, result: Result<(), ReturnCode>, value: usize) {
+        let ret = match result {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(e) => e,
+        };
+        self.operation.set(None);
+
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback.map(|mut cb| {
+                    cb.schedule(usize::from(ret), value, 0);
+                });
+            });
+        });
+
+        // Check if there are any pending events.
+        for cntr in self.apps.iter() {
+            let started_command = cntr.enter(|app, _| {
+                let appid = app.appid();
+                app.pending_command.take().map_or(false, |operation| {
+                    let ret = self.start_command(operation, app, appid);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                        true
+                    } else {
+                        self.operation.set(None);
+                        app.callback.map(|mut cb| {
+                            cb.schedule(usize::from(ret), 0, 0);
+                        });
+                        false
+                    }
+                })
+            });
+            if started_command {
+                break;
+            }
+        }
+    }
+
+    fn owns(&self, handle: usize, appid: AppId) -> bool {
+        self.owners
+            .get(handle)
+            .map_or(false, |owner| owner.get() == Some(appid))
+    }
+
+    /// Close the files of applications that no longer exist.
+    fn reclaim_handles(&self) {
+        for (handle, owner) in self.owners.iter().enumerate() {
+            let exited = owner
+                .get()
+                .map_or(false, |appid| self.apps.enter(appid, |_, _| ()).is_err());
+            if exited {
+                self.fat.close(handle);
+                owner.set(None);
+            }
+        }
+    }
+
+    /// Run a command that completes immediately on a file owned by `appid`.
+    fn with_file<F: FnOnce(usize) -> ReturnCode>(
+        &self,
+        handle: usize,
+        appid: AppId,
+        f: F,
+    ) -> ReturnCode {
+        if self.owns(handle, appid) {
+            f(handle)
+        } else {
+            ReturnCode::EINVAL
+        }
+    }
+}
+
+/// Write the path of the directory of `appid` into `path`, returning its
+/// length.
+fn home_path(appid: AppId, path: &mut [u8; MAX_PATH]) -> usize {
+    path[..APPS_DIR.len()].copy_from_slice(APPS_DIR);
+    path[APPS_DIR.len()] = b'/';
+    let start = APPS_DIR.len() + 1;
+
+    // The package name stays the same across reboots, so use it to name the
+    // directory where possible.
+    let name = appid.get_process_name().as_bytes();
+    let valid = !name.is_empty()
+        && name.len() <= 8
+        && name
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'_' || *b == b'-');
+    if valid {
+        for (dest, b) in path[start..].iter_mut().zip(name.iter()) {
+            *dest = b.to_ascii_uppercase();
+        }
+        return start + name.len();
+    }
+
+    let mut hasher = SipHasher24::new();
+    if name.is_empty() {
+        hasher.write(&appid.id().to_le_bytes());
+    } else {
+        hasher.write(name);
+    }
+    let hash = hasher.finish();
+    path[start] = b'~';
+    for i in 0..7 {
+        let digit = (hash >> (4 * (6 - i))) as u8 & 0xf;
+        path[start + 1 + i] = if digit < 10 {
+            b'0' + digit
+        } else {
+            b'A' + digit - 10
+        };
+    }
+    start + 8
+}
+
+/// Write the path given by the application, inside its directory, into
+/// `path`. The application's path ends at the first NUL byte, if there is
+/// one.
+fn app_path(app: &App, appid: AppId, path: &mut [u8; MAX_PATH]) -> Option<usize> {
+    let home = home_path(appid, path);
+    let relative = app.path.as_ref()?.as_ref();
+    let relative = &relative[..relative
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(relative.len())];
+    let start = relative
+        .iter()
+        .position(|b| *b != b'/')
+        .unwrap_or(relative.len());
+    let relative = &relative[start..];
+    if relative.is_empty() {
+        return Some(home);
+    }
+    if home + 1 + relative.len() > MAX_PATH {
+        return None;
+    }
+    path[home] = b'/';
+    path[home + 1..home + 1 + relative.len()].copy_from_slice(relative);
+    Some(home + 1 + relative.len())
+}
+
+impl fat::Client for FatDriver<'_> {
+    fn mount_done(&self, result: Result<(), ReturnCode>) {
+        match result {
+            Ok(()) => self.continue_command(),
+            Err(e) => self.complete_command(Err(e), 0),
+        }
+    }
+
+    fn open_done(&self, result: Result<usize, ReturnCode>) {
+        match result {
+            Ok(handle) => {
+                self.owners[handle].set(self.current_app.map(|appid| *appid));
+                self.complete_command(Ok(()), handle);
+            }
+            Err(e) => self.complete_command(Err(e), 0),
+        }
+    }
+
+    fn mkdir_done(&self, result: Result<(), ReturnCode>) {
+        match (self.stage.get(), result) {
+            // The directories of applications usually exist already
+            (Stage::MakeAppsDir, Ok(())) | (Stage::MakeAppsDir, Err(ReturnCode::EALREADY)) => {
+                self.continue_command()
+            }
+            (Stage::MakeHome, Ok(())) | (Stage::MakeHome, Err(ReturnCode::EALREADY)) => {
+                self.continue_command()
+            }
+            (_, result) => self.complete_command(result, 0),
+        }
+    }
+
+    fn read_done(&self, result: Result<(), ReturnCode>, buffer: &'static mut [u8], length: usize) {
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.read_buffer.as_mut().map(|read_buffer| {
+                    let copy = cmp::min(length, read_buffer.len());
+                    read_buffer.as_mut()[..copy].copy_from_slice(&buffer[..copy]);
+                });
+            });
+        });
+        self.buffer.replace(buffer);
+        self.complete_command(result, length);
+    }
+
+    fn write_done(&self, result: Result<(), ReturnCode>, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.complete_command(result, length);
+    }
+
+    fn list_done(&self, result: Result<DirEntry, ReturnCode>) {
+        match result {
+            Ok(entry) => {
+                self.current_app.map(|appid| {
+                    let _ = self.apps.enter(*appid, |app, _| {
+                        app.read_buffer.as_mut().map(|read_buffer| {
+                            let mut encoded = [0; LIST_ENTRY_SIZE];
+                            encoded[..entry.name_len].copy_from_slice(entry.name());
+                            encoded[12..16].copy_from_slice(&entry.size.to_le_bytes());
+                            encoded[16] = entry.directory as u8;
+                            let copy = cmp::min(LIST_ENTRY_SIZE, read_buffer.len());
+                            read_buffer.as_mut()[..copy].copy_from_slice(&encoded[..copy]);
+                        });
+                    });
+                });
+                self.complete_command(Ok(()), entry.name_len);
+            }
+            Err(e) => self.complete_command(Err(e), 0),
+        }
+    }
+}
+
+impl Driver for FatDriver<'_> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Set the path used by `open`, `list` and `mkdir`, relative to
+    ///        the application's directory. The path ends at the end of the
+    ///        buffer or at the first NUL byte.
+    /// - `1`: Set the buffer that `read` and `list` write into.
+    /// - `2`: Set the buffer holding the data for `write`.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.path = slice,
+                    1 => app.read_buffer = slice,
+                    2 => app.write_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Set the callback for completed operations. The callback receives
+    ///        the `ReturnCode` of the operation and a value that depends on
+    ///        the operation: the file handle for `open`, the number of bytes
+    ///        read or written, or the length of the name for `list`.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// File operations.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Open the file at the path. If bit 0 of `data` is set the file
+    ///        is created if it doesn't exist.
+    /// - `2`: Read up to `data2` bytes from file `data` into the `allow` 1
+    ///        buffer.
+    /// - `3`: Write `data2` bytes from the `allow` 2 buffer to file `data`.
+    /// - `4`: Move the position of file `data` to `data2`.
+    /// - `5`: Close file `data`.
+    /// - `6`: Get entry `data` of the directory at the path. The name
+    ///        (12 bytes, padded with NULs), size (4 bytes, little endian) and
+    ///        whether the entry is a directory (1 byte) are written to the
+    ///        `allow` 1 buffer. `ENOSUPPORT` indicates there are no more
+    ///        entries.
+    /// - `7`: Create a directory at the path.
+    /// - `8`: Get the size of file `data`.
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 =>
+            /* This driver exists. */
+            {
+                ReturnCode::SUCCESS
+            }
+
+            1 => self.enqueue_command(UserOperation::Open(data & 1 == 1), appid),
+            2 => self.enqueue_command(UserOperation::Read(data, data2), appid),
+            3 => self.enqueue_command(UserOperation::Write(data, data2), appid),
+            4 => self.with_file(data, appid, |handle| self.fat.seek(handle, data2)),
+            5 => self.with_file(data, appid, |handle| {
+                let ret = self.fat.close(handle);
+                if ret == ReturnCode::SUCCESS {
+                    self.owners[handle].set(None);
+                }
+                ret
+            }),
+            6 => self.enqueue_command(UserOperation::List(data), appid),
+            7 => self.enqueue_command(UserOperation::Mkdir, appid),
+            8 => self.with_file(data, appid, |handle| match self.fat.file_size(handle) {
+                Ok(size) => ReturnCode::SuccessWithValue { value: size },
+                Err(e) => e,
+            }),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
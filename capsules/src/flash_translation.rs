@@ -0,0 +1,888 @@
+//! Flash translation layer with wear levelling and bad page management.
+//!
+//! `FlashTranslation` implements `hil::flash` on top of a raw flash device,
+//! such as an external NOR flash, so that capsules which rewrite the same
+//! pages over and over don't wear out those pages. It keeps more physical
+//! pages than it offers logical pages, and every write of a logical page
+//! goes to a different physical page:
+//!
+//! - Writes go to the free physical page that has been erased the fewest
+//!   times. The old copy of the logical page is only released once the new
+//!   copy has been written, so losing power during a write leaves the old
+//!   contents in place.
+//! - When the least worn page holding data has been erased
+//!   `WEAR_LEVEL_THRESHOLD` fewer times than the most worn free page, that
+//!   cold data is moved to the worn page, so that its page can take its share
+//!   of the writes.
+//! - Every write is read back and checked. Pages that fail to erase, to
+//!   write or to read back are retired: they are marked as bad, if the flash
+//!   still allows it, and never used again.
+//!
+//! Each physical page starts with a `HEADER_SIZE` byte header holding the
+//! number of the logical page it stores, a sequence number, the erase count
+//! of the physical page and a checksum. Right after a page is erased, a
+//! header with only the erase count is written, and the copy is then written
+//! over it, so a page keeps its erase count even if power is lost before the
+//! copy is complete. `mount()` reads every physical page to rebuild the map
+//! of logical pages, using the copy with the highest sequence number. Logical pages are therefore `HEADER_SIZE` bytes smaller
+//! than physical pages, and are `capsules::block_storage::BlockPage`s of `S`
+//! bytes.
+//!
+//! Logical pages that have never been written read as erased. Erasing a
+//! logical page writes an erased copy of it, so that it stays erased after a
+//! reboot.
+//!
+//! Usage
+//! -----
+//!
+//! Use 64 pages of an MX25R6435F for 60 logical pages of 4080 bytes:
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//! # use capsules::block_storage::BlockPage;
+//! # use capsules::flash_translation::{FlashTranslation, PhysicalPage};
+//!
+//! let ftl = static_init!(
+//!     FlashTranslation<'static, Mx25r6435f, 4080>,
+//!     FlashTranslation::new(
+//!         mx25r6435f,
+//!         static_init!(Mx25r6435fSector, Mx25r6435fSector::new()),
+//!         static_init!([PhysicalPage; 64], [PhysicalPage::default(); 64]),
+//!         0,  // The first physical page used.
+//!         60, // The number of logical pages.
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(mx25r6435f, ftl);
+//! ftl.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ftl)
+//!         .expect("no deferred call slot available for the flash translation layer"),
+//! );
+//! ftl.set_mount_client(board_storage);
+//! ftl.mount();
+//! ```
+
+use core::cell::Cell;
+use core::convert::TryInto;
+use core::hash::Hasher;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::flash::{self, Flash};
+use kernel::ReturnCode;
+
+use crate::block_storage::BlockPage;
+use crate::sip_hash::SipHasher24;
+
+/// The bytes at the start of each physical page used by the translation
+/// layer.
+pub const HEADER_SIZE: usize = 16;
+/// How many more erases the most worn free page may have than the least
+/// worn page holding data before the data is moved.
+pub const WEAR_LEVEL_THRESHOLD: u32 = 16;
+
+/// What a physical page holds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageState {
+    /// Erased, or holding an old copy of a logical page
+    Free,
+    /// Holding the current copy of a logical page
+    Valid { logical: usize, sequence: u32 },
+    /// Retired
+    Bad,
+}
+
+/// The state of a physical page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhysicalPage {
+    pub state: PageState,
+    /// The number of times the page has been erased. Pages that are erased
+    /// outside of the translation layer start counting from zero.
+    pub erase_count: u32,
+}
+
+impl Default for PhysicalPage {
+    fn default() -> Self {
+        PhysicalPage {
+            state: PageState::Free,
+            erase_count: 0,
+        }
+    }
+}
+
+/// Implement `MountClient` to be told when the translation layer is ready.
+pub trait MountClient {
+    fn mount_done(&self, result: Result<(), ReturnCode>);
+}
+
+/// Where the data written to a physical page comes from.
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    /// The page passed to `write_page()`
+    Client,
+    /// An erased page
+    Erased,
+    /// The physical page holding cold data
+    Relocate(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Erase,
+    /// Write the erase count to the erased page
+    Mark,
+    /// Read the data to relocate
+    Load,
+    Write,
+    /// Read back what was written
+    Verify,
+    /// Mark a failed page as bad
+    Retire,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    /// Reading the physical page with this index
+    Mount(usize),
+    /// Reading the physical page with this index for the client
+    Read(usize),
+    /// Writing a copy of a logical page to the physical page `target`
+    Program {
+        logical: usize,
+        source: Source,
+        target: usize,
+        step: Step,
+    },
+    /// Waiting for a deferred call to the client
+    Callback,
+}
+
+/// The client callback due when the current operation finishes.
+#[derive(Clone, Copy, PartialEq)]
+enum Completion {
+    None,
+    Read,
+    Write,
+    Erase,
+}
+
+pub struct FlashTranslation<'a, F: Flash + 'static, const S: usize> {
+    flash: &'a F,
+    buffer: TakeCell<'static, F::Page>,
+    pages: TakeCell<'static, [PhysicalPage]>,
+    start_page: usize,
+    logical_pages: usize,
+    /// The sequence number of the next copy written
+    sequence: Cell<u32>,
+    mounted: Cell<bool>,
+    operation: Cell<Operation>,
+    completion: Cell<Completion>,
+    result: Cell<flash::Error>,
+    client_page: TakeCell<'static, BlockPage<S>>,
+    client: OptionalCell<&'a dyn flash::Client<FlashTranslation<'a, F, S>>>,
+    mount_client: OptionalCell<&'a dyn MountClient>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a, F: Flash, const S: usize> FlashTranslation<'a, F, S> {
+    /// Use the physical pages of `flash` from `start_page`, one for each
+    /// entry of `pages`, to store `logical_pages` logical pages. There must
+    /// be at least one more physical page than logical pages; every retired
+    /// page needs another spare page.
+    pub fn new(
+        flash: &'a F,
+        buffer: &'static mut F::Page,
+        pages: &'static mut [PhysicalPage],
+        start_page: usize,
+        logical_pages: usize,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> FlashTranslation<'a, F, S> {
+        FlashTranslation {
+            flash,
+            buffer: TakeCell::new(buffer),
+            pages: TakeCell::new(pages),
+            start_page,
+            logical_pages,
+            sequence: Cell::new(1),
+            mounted: Cell::new(false),
+            operation: Cell::new(Operation::Idle),
+            completion: Cell::new(Completion::None),
+            result: Cell::new(flash::Error::CommandComplete),
+            client_page: TakeCell::empty(),
+            client: OptionalCell::empty(),
+            mount_client: OptionalCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    pub fn set_mount_client(&self, client: &'a dyn MountClient) {
+        self.mount_client.set(client);
+    }
+
+    /// The number of logical pages.
+    pub fn logical_pages(&self) -> usize {
+        self.logical_pages
+    }
+
+    /// The state of physical page `index`, counted from `start_page`.
+    pub fn physical_page(&self, index: usize) -> Option<PhysicalPage> {
+        self.pages.map(|pages| pages.get(index).copied()).flatten()
+    }
+
+    /// The number of retired physical pages.
+    pub fn bad_pages(&self) -> usize {
+        self.pages.map_or(0, |pages| {
+            pages
+                .iter()
+                .filter(|page| page.state == PageState::Bad)
+                .count()
+        })
+    }
+
+    /// The lowest and highest erase counts of the physical pages that
+    /// haven't been retired.
+    pub fn erase_counts(&self) -> Option<(u32, u32)> {
+        self.pages
+            .map(|pages| {
+                let counts = pages
+                    .iter()
+                    .filter(|page| page.state != PageState::Bad)
+                    .map(|page| page.erase_count);
+                Some((counts.clone().min()?, counts.max()?))
+            })
+            .flatten()
+    }
+
+    /// Read every physical page to find the current copy of each logical
+    /// page. `mount_done()` is called when this completes.
+    pub fn mount(&self) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let physical_pages = self.pages.map_or(0, |pages| {
+            pages
+                .iter_mut()
+                .for_each(|page| *page = PhysicalPage::default());
+            pages.len()
+        });
+        if self.logical_pages >= physical_pages {
+            return ReturnCode::EINVAL;
+        }
+        let page_size = self.buffer.map_or(0, |buffer| buffer.as_mut().len());
+        if HEADER_SIZE + S > page_size {
+            return ReturnCode::ESIZE;
+        }
+        self.mounted.set(false);
+        self.sequence.set(1);
+        self.mount_read(0)
+    }
+
+    fn mount_read(&self, index: usize) -> ReturnCode {
+        self.operation.set(Operation::Mount(index));
+        let ret = self.read_physical(index);
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+        }
+        ret
+    }
+
+    /// Record what physical page `index` holds.
+    fn mount_page(&self, index: usize, page: &[u8]) {
+        let state = if page[..HEADER_SIZE].iter().all(|b| *b == 0) {
+            PhysicalPage {
+                state: PageState::Bad,
+                erase_count: 0,
+            }
+        } else {
+            match self.header(page) {
+                Some((logical, sequence, erase_count)) => {
+                    if sequence >= self.sequence.get() {
+                        self.sequence.set(sequence.wrapping_add(1));
+                    }
+                    let current = self.find(logical);
+                    let newer = current.map_or(true, |current| {
+                        self.physical_page(current)
+                            .map_or(true, |page| match page.state {
+                                PageState::Valid { sequence: old, .. } => sequence > old,
+                                _ => true,
+                            })
+                    });
+                    if logical >= self.logical_pages || !newer {
+                        PhysicalPage {
+                            state: PageState::Free,
+                            erase_count,
+                        }
+                    } else {
+                        current.map(|current| self.set_state(current, PageState::Free));
+                        PhysicalPage {
+                            state: PageState::Valid { logical, sequence },
+                            erase_count,
+                        }
+                    }
+                }
+                None => {
+                    // The page was erased, and may have been written in
+                    // part: its erase count is written first.
+                    let erase_count = u32::from_le_bytes(page[8..12].try_into().unwrap());
+                    PhysicalPage {
+                        state: PageState::Free,
+                        erase_count: if erase_count == u32::MAX {
+                            0
+                        } else {
+                            erase_count
+                        },
+                    }
+                }
+            }
+        };
+        self.pages.map(|pages| pages[index] = state);
+    }
+
+    /// The logical page number, sequence number and erase count of a
+    /// physical page, if it holds a complete copy of a logical page.
+    fn header(&self, page: &[u8]) -> Option<(usize, u32, u32)> {
+        let field = |i: usize| u32::from_le_bytes(page[i..i + 4].try_into().unwrap());
+        if field(4) == u32::MAX || field(12) != Self::checksum(page) {
+            return None;
+        }
+        Some((field(0) as usize, field(4), field(8)))
+    }
+
+    fn checksum(page: &[u8]) -> u32 {
+        let mut hasher = SipHasher24::new();
+        hasher.write(&page[..12]);
+        hasher.write(&page[HEADER_SIZE..HEADER_SIZE + S]);
+        hasher.finish() as u32
+    }
+
+    /// The physical page holding logical page `logical`.
+    fn find(&self, logical: usize) -> Option<usize> {
+        self.pages
+            .map(|pages| {
+                pages.iter().position(|page| match page.state {
+                    PageState::Valid { logical: l, .. } => l == logical,
+                    _ => false,
+                })
+            })
+            .flatten()
+    }
+
+    fn set_state(&self, index: usize, state: PageState) {
+        self.pages.map(|pages| pages[index].state = state);
+    }
+
+    /// The free page with the fewest erases, or with the most for moving
+    /// cold data.
+    fn pick_free(&self, most_worn: bool) -> Option<usize> {
+        self.pages
+            .map(|pages| {
+                let free = pages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, page)| page.state == PageState::Free);
+                if most_worn {
+                    free.max_by_key(|(_, page)| page.erase_count)
+                } else {
+                    free.min_by_key(|(_, page)| page.erase_count)
+                }
+                .map(|(index, _)| index)
+            })
+            .flatten()
+    }
+
+    fn read_physical(&self, index: usize) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            match self.flash.read_page(self.start_page + index, buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((e, buffer)) => {
+                    self.buffer.replace(buffer);
+                    e
+                }
+            }
+        })
+    }
+
+    fn write_physical(&self, index: usize, buffer: &'static mut F::Page) -> ReturnCode {
+        match self.flash.write_page(self.start_page + index, buffer) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((e, buffer)) => {
+                self.buffer.replace(buffer);
+                e
+            }
+        }
+    }
+
+    /// Check that an operation on logical page `page_number` can start.
+    fn ready(&self, page_number: usize) -> ReturnCode {
+        if !self.mounted.get() {
+            ReturnCode::EOFF
+        } else if self.operation.get() != Operation::Idle {
+            ReturnCode::EBUSY
+        } else if page_number >= self.logical_pages {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Start writing a new copy of logical page `logical`.
+    fn begin(&self, logical: usize, source: Source) -> ReturnCode {
+        let relocate = matches!(source, Source::Relocate(_));
+        let target = match self.pick_free(relocate) {
+            Some(target) => target,
+            None => return ReturnCode::ENOMEM,
+        };
+        self.operation.set(Operation::Program {
+            logical,
+            source,
+            target,
+            step: Step::Erase,
+        });
+        let ret = self.issue();
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(Operation::Idle);
+        }
+        ret
+    }
+
+    /// Start the flash operation for the current step.
+    fn issue(&self) -> ReturnCode {
+        let (logical, source, target, step) = match self.operation.get() {
+            Operation::Program {
+                logical,
+                source,
+                target,
+                step,
+            } => (logical, source, target, step),
+            _ => return ReturnCode::FAIL,
+        };
+        match (step, source) {
+            (Step::Load, Source::Relocate(from)) => self.read_physical(from),
+            (Step::Load, _) => ReturnCode::FAIL,
+            (Step::Erase, _) => self.flash.erase_page(self.start_page + target),
+            (Step::Mark, _) => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let erase_count = self
+                    .physical_page(target)
+                    .map_or(0, |page| page.erase_count);
+                let page = buffer.as_mut();
+                page.iter_mut().for_each(|b| *b = 0xFF);
+                page[8..12].copy_from_slice(&erase_count.to_le_bytes());
+                self.write_physical(target, buffer)
+            }),
+            (Step::Verify, _) => self.read_physical(target),
+            (Step::Write, _) => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                self.fill(buffer.as_mut(), logical, source, target);
+                self.write_physical(target, buffer)
+            }),
+            (Step::Retire, _) => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                buffer.as_mut().iter_mut().for_each(|b| *b = 0);
+                self.write_physical(target, buffer)
+            }),
+        }
+    }
+
+    /// Build the physical page holding the new copy of `logical`. Relocated
+    /// data is already in the buffer.
+    fn fill(&self, page: &mut [u8], logical: usize, source: Source, target: usize) {
+        let erase_count = self
+            .physical_page(target)
+            .map_or(0, |page| page.erase_count);
+        page[0..4].copy_from_slice(&(logical as u32).to_le_bytes());
+        page[4..8].copy_from_slice(&self.sequence.get().to_le_bytes());
+        page[8..12].copy_from_slice(&erase_count.to_le_bytes());
+        match source {
+            Source::Client => {
+                self.client_page.map(|client_page| {
+                    page[HEADER_SIZE..HEADER_SIZE + S].copy_from_slice(&client_page.0);
+                });
+            }
+            Source::Erased => page[HEADER_SIZE..HEADER_SIZE + S]
+                .iter_mut()
+                .for_each(|b| *b = 0xFF),
+            Source::Relocate(_) => {}
+        }
+        page[HEADER_SIZE + S..].iter_mut().for_each(|b| *b = 0xFF);
+        let sum = Self::checksum(page);
+        page[12..16].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    /// Continue with `step` of the current write.
+    fn next(&self, step: Step) {
+        if let Operation::Program {
+            logical,
+            source,
+            target,
+            ..
+        } = self.operation.get()
+        {
+            self.operation.set(Operation::Program {
+                logical,
+                source,
+                target,
+                step,
+            });
+            if self.issue() != ReturnCode::SUCCESS {
+                self.program_done(source, false);
+            }
+        }
+    }
+
+    /// The target page failed: retire it and try another.
+    fn failed(&self) {
+        if let Operation::Program { target, .. } = self.operation.get() {
+            self.set_state(target, PageState::Bad);
+            self.next(Step::Retire);
+        }
+    }
+
+    fn retry(&self) {
+        if let Operation::Program {
+            logical, source, ..
+        } = self.operation.get()
+        {
+            if self.begin(logical, source) != ReturnCode::SUCCESS {
+                self.program_done(source, false);
+            }
+        }
+    }
+
+    /// The new copy was written and checked, so it replaces the old one.
+    fn commit(&self) {
+        if let Operation::Program {
+            logical,
+            source,
+            target,
+            ..
+        } = self.operation.get()
+        {
+            if let Some(old) = self.find(logical) {
+                self.set_state(old, PageState::Free);
+            }
+            self.set_state(
+                target,
+                PageState::Valid {
+                    logical,
+                    sequence: self.sequence.get(),
+                },
+            );
+            self.sequence.set(self.sequence.get().wrapping_add(1));
+            self.program_done(source, true);
+        }
+    }
+
+    fn program_done(&self, source: Source, success: bool) {
+        if let Source::Relocate(_) = source {
+            // The client's write already succeeded.
+            self.finish();
+            return;
+        }
+        self.result.set(if success {
+            flash::Error::CommandComplete
+        } else {
+            flash::Error::FlashError
+        });
+        if !(success && self.relocate()) {
+            self.finish();
+        }
+    }
+
+    /// Move cold data if the wear has become too uneven. Returns whether
+    /// the data is being moved.
+    fn relocate(&self) -> bool {
+        let cold = self
+            .pages
+            .map(|pages| {
+                pages
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, page)| match page.state {
+                        PageState::Valid { logical, .. } => {
+                            Some((index, logical, page.erase_count))
+                        }
+                        _ => None,
+                    })
+                    .min_by_key(|(_, _, erase_count)| *erase_count)
+            })
+            .flatten();
+        let worn = self
+            .pick_free(true)
+            .and_then(|index| self.physical_page(index))
+            .map(|page| page.erase_count);
+        match (cold, worn) {
+            (Some((index, logical, erase_count)), Some(worn))
+                if worn >= erase_count.saturating_add(WEAR_LEVEL_THRESHOLD) =>
+            {
+                self.begin(logical, Source::Relocate(index)) == ReturnCode::SUCCESS
+            }
+            _ => false,
+        }
+    }
+
+    /// Finish the current operation and call back the client.
+    fn finish(&self) {
+        self.operation.set(Operation::Idle);
+        let error = self.result.get();
+        match self.completion.replace(Completion::None) {
+            Completion::Read => {
+                self.client_page.take().map(|page| {
+                    self.client
+                        .map(move |client| client.read_complete(page, error));
+                });
+            }
+            Completion::Write => {
+                self.client_page.take().map(|page| {
+                    self.client
+                        .map(move |client| client.write_complete(page, error));
+                });
+            }
+            Completion::Erase => {
+                self.client.map(|client| client.erase_complete(error));
+            }
+            Completion::None => {}
+        }
+    }
+
+    /// Call back the client without using the flash.
+    fn deferred_finish(&self, completion: Completion) {
+        self.completion.set(completion);
+        self.result.set(flash::Error::CommandComplete);
+        self.operation.set(Operation::Callback);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+}
+
+impl<'a, C: flash::Client<Self>, F: Flash, const S: usize> flash::HasClient<'a, C>
+    for FlashTranslation<'a, F, S>
+{
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<F: Flash, const S: usize> Flash for FlashTranslation<'_, F, S> {
+    type Page = BlockPage<S>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        let ret = self.ready(page_number);
+        if ret != ReturnCode::SUCCESS {
+            return Err((ret, buf));
+        }
+        match self.find(page_number) {
+            Some(index) => {
+                self.operation.set(Operation::Read(index));
+                match self.read_physical(index) {
+                    ReturnCode::SUCCESS => {
+                        self.client_page.replace(buf);
+                        self.completion.set(Completion::Read);
+                        Ok(())
+                    }
+                    e => {
+                        self.operation.set(Operation::Idle);
+                        Err((e, buf))
+                    }
+                }
+            }
+            None => {
+                // Never written, so it reads as erased.
+                buf.0.iter_mut().for_each(|b| *b = 0xFF);
+                self.client_page.replace(buf);
+                self.deferred_finish(Completion::Read);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        let ret = self.ready(page_number);
+        if ret != ReturnCode::SUCCESS {
+            return Err((ret, buf));
+        }
+        self.client_page.replace(buf);
+        match self.begin(page_number, Source::Client) {
+            ReturnCode::SUCCESS => {
+                self.completion.set(Completion::Write);
+                Ok(())
+            }
+            e => Err((e, self.client_page.take().unwrap())),
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let ret = self.ready(page_number);
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+        if self.find(page_number).is_none() {
+            self.deferred_finish(Completion::Erase);
+            return ReturnCode::SUCCESS;
+        }
+        let ret = self.begin(page_number, Source::Erased);
+        if ret == ReturnCode::SUCCESS {
+            self.completion.set(Completion::Erase);
+        }
+        ret
+    }
+}
+
+impl<F: Flash, const S: usize> flash::Client<F> for FlashTranslation<'_, F, S> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: flash::Error) {
+        match self.operation.get() {
+            Operation::Mount(index) => {
+                if error != flash::Error::CommandComplete {
+                    self.buffer.replace(buffer);
+                    self.operation.set(Operation::Idle);
+                    self.mount_client
+                        .map(|client| client.mount_done(Err(ReturnCode::FAIL)));
+                    return;
+                }
+                self.mount_page(index, buffer.as_mut());
+                self.buffer.replace(buffer);
+                let next = index + 1;
+                let physical_pages = self.pages.map_or(0, |pages| pages.len());
+                if next < physical_pages {
+                    let ret = self.mount_read(next);
+                    if ret != ReturnCode::SUCCESS {
+                        self.mount_client.map(|client| client.mount_done(Err(ret)));
+                    }
+                } else {
+                    self.operation.set(Operation::Idle);
+                    self.mounted.set(true);
+                    self.mount_client.map(|client| client.mount_done(Ok(())));
+                }
+            }
+            Operation::Read(index) => {
+                let logical = match self.physical_page(index).map(|page| page.state) {
+                    Some(PageState::Valid { logical, .. }) => Some(logical),
+                    _ => None,
+                };
+                let page = buffer.as_mut();
+                let valid = error == flash::Error::CommandComplete
+                    && self.header(page).map(|(l, _, _)| l) == logical;
+                if valid {
+                    self.client_page.map(|client_page| {
+                        client_page
+                            .0
+                            .copy_from_slice(&page[HEADER_SIZE..HEADER_SIZE + S]);
+                    });
+                    self.result.set(flash::Error::CommandComplete);
+                } else {
+                    self.result.set(flash::Error::FlashError);
+                }
+                self.buffer.replace(buffer);
+                self.finish();
+            }
+            Operation::Program {
+                logical,
+                source,
+                step: Step::Load,
+                ..
+            } => {
+                let loaded = error == flash::Error::CommandComplete
+                    && self.header(buffer.as_mut()).map(|(l, _, _)| l) == Some(logical);
+                self.buffer.replace(buffer);
+                if loaded {
+                    self.next(Step::Write);
+                } else {
+                    self.program_done(source, false);
+                }
+            }
+            Operation::Program {
+                logical,
+                target,
+                step: Step::Verify,
+                ..
+            } => {
+                let erase_count = self.physical_page(target).map(|page| page.erase_count);
+                let written = error == flash::Error::CommandComplete
+                    && self.header(buffer.as_mut()).map_or(false, |header| {
+                        Some(header) == erase_count.map(|e| (logical, self.sequence.get(), e))
+                    });
+                self.buffer.replace(buffer);
+                if written {
+                    self.commit();
+                } else {
+                    self.failed();
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: flash::Error) {
+        self.buffer.replace(buffer);
+        match self.operation.get() {
+            Operation::Program {
+                source,
+                step: Step::Mark,
+                ..
+            } => {
+                if error != flash::Error::CommandComplete {
+                    self.failed();
+                } else if let Source::Relocate(_) = source {
+                    // Relocated data is read into the buffer once the
+                    // target is ready.
+                    self.next(Step::Load);
+                } else {
+                    self.next(Step::Write);
+                }
+            }
+            Operation::Program {
+                step: Step::Write, ..
+            } => {
+                if error == flash::Error::CommandComplete {
+                    self.next(Step::Verify);
+                } else {
+                    self.failed();
+                }
+            }
+            Operation::Program {
+                step: Step::Retire, ..
+            } => self.retry(),
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        if let Operation::Program {
+            target,
+            step: Step::Erase,
+            ..
+        } = self.operation.get()
+        {
+            if error == flash::Error::CommandComplete {
+                self.pages.map(|pages| {
+                    pages[target].erase_count = pages[target].erase_count.saturating_add(1)
+                });
+                self.next(Step::Mark);
+            } else {
+                self.failed();
+            }
+        }
+    }
+}
+
+impl<F: Flash, const S: usize> DynamicDeferredCallClient for FlashTranslation<'_, F, S> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.finish();
+    }
+}
@@ -0,0 +1,723 @@
+//! Unslotted CSMA-CA MAC layer with software acknowledgements and
+//! retransmissions, for radios that do not implement these in hardware.
+//!
+//! This layer follows the nonbeacon-enabled channel access procedure of IEEE
+//! 802.15.4-2015 (section 6.2.5.1). Before each transmission attempt the
+//! transmitter waits a random number of unit backoff periods in the range
+//! `[0, 2^BE - 1]` and then performs a clear channel assessment. If the
+//! channel is busy, `BE` is increased (up to `macMaxBE`) and the procedure is
+//! repeated, up to `macMaxCSMABackoffs` times, after which the transmission is
+//! abandoned with `ReturnCode::EBUSY`.
+//!
+//! Once a frame that requests an acknowledgement has been sent, the layer waits
+//! `macAckWaitDuration` for an Imm-Ack carrying the frame's sequence number. If
+//! none arrives the frame is retransmitted (starting again with a fresh CSMA-CA
+//! procedure) up to `macMaxFrameRetries` times, after which the client is told
+//! `ReturnCode::ENOACK`. `acked` is only reported as true to the `TxClient`
+//! when an acknowledgement was actually received, either by the radio itself or
+//! by this layer.
+//!
+//! The generic radio HIL does not expose an energy-detection CCA, so the clear
+//! channel assessment is performed in software: the channel is considered busy
+//! if the radio reports itself busy or if a frame was received at any point
+//! during the backoff period.
+//!
+//! Usage
+//! -----
+//! `CsmaMac` implements `capsules::ieee802154::mac::Mac` and can be used
+//! anywhere `AwakeMac` is, for example as the backend of a
+//! `capsules::ieee802154::framer::Framer`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! type CsmaDevice = capsules::ieee802154::csma::CsmaMac<'static, RF233Device, VirtualMuxAlarm>;
+//!
+//! let csma: &CsmaDevice = static_init!(
+//!     CsmaDevice,
+//!     capsules::ieee802154::csma::CsmaMac::new(
+//!         rf233,
+//!         mac_alarm,
+//!         random,
+//!         capsules::ieee802154::csma::CsmaConfig::default()
+//!     )
+//! );
+//! mac_alarm.set_alarm_client(csma);
+//! rf233.set_transmit_client(csma);
+//! rf233.set_receive_client(csma, &mut RF233_RX_BUF);
+//!
+//! let mac_device = static_init!(
+//!     capsules::ieee802154::framer::Framer<'static, CsmaDevice>,
+//!     capsules::ieee802154::framer::Framer::new(csma));
+//! csma.set_transmit_client(mac_device);
+//! csma.set_receive_client(mac_device);
+//! csma.set_config_client(mac_device);
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{FrameType, Header, MacAddress};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::rng::Random;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// aUnitBackoffPeriod (20 symbols) for the 2.4 GHz O-QPSK PHY, in microseconds.
+pub const UNIT_BACKOFF_PERIOD_US: u32 = 320;
+/// macAckWaitDuration (54 symbols) for the 2.4 GHz O-QPSK PHY, in microseconds.
+pub const ACK_WAIT_DURATION_US: u32 = 864;
+
+/// Size of an Imm-Ack frame without its MFR: frame control and sequence number.
+const IMM_ACK_LEN: usize = 3;
+/// The short address that every node accepts frames for.
+const BROADCAST_SHORT_ADDR: u16 = 0xffff;
+
+/// MAC PIB attributes controlling channel access and retransmission.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CsmaConfig {
+    /// macMinBE: initial backoff exponent.
+    pub min_be: u8,
+    /// macMaxBE: maximum backoff exponent.
+    pub max_be: u8,
+    /// macMaxCSMABackoffs: number of busy CCAs tolerated before giving up.
+    pub max_csma_backoffs: u8,
+    /// macMaxFrameRetries: number of retransmissions after a missing ACK.
+    pub max_frame_retries: u8,
+}
+
+impl Default for CsmaConfig {
+    /// The defaults mandated by the standard.
+    fn default() -> CsmaConfig {
+        CsmaConfig {
+            min_be: 3,
+            max_be: 5,
+            max_csma_backoffs: 4,
+            max_frame_retries: 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CsmaState {
+    Idle,
+    Backoff,
+    Transmitting,
+    WaitAck,
+}
+
+pub struct CsmaMac<'a, R: radio::Radio, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    random: &'a dyn Random<'a>,
+    config: Cell<CsmaConfig>,
+
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    state: Cell<CsmaState>,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    // Sequence number of the frame being sent, if it requested an ACK.
+    tx_ack_seq: OptionalCell<u8>,
+
+    // NB, BE and the retransmission count of the current frame.
+    backoffs: Cell<u8>,
+    backoff_exponent: Cell<u8>,
+    retries: Cell<u8>,
+    // Set when a frame is received during a backoff period.
+    channel_busy: Cell<bool>,
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> CsmaMac<'a, R, A> {
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        random: &'a dyn Random<'a>,
+        config: CsmaConfig,
+    ) -> CsmaMac<'a, R, A> {
+        CsmaMac {
+            radio: radio,
+            alarm: alarm,
+            random: random,
+            config: Cell::new(config),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state: Cell::new(CsmaState::Idle),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_ack_seq: OptionalCell::empty(),
+            backoffs: Cell::new(0),
+            backoff_exponent: Cell::new(0),
+            retries: Cell::new(0),
+            channel_busy: Cell::new(false),
+        }
+    }
+
+    pub fn get_config(&self) -> CsmaConfig {
+        self.config.get()
+    }
+
+    /// Changes the channel access parameters. Fails with EBUSY while a frame
+    /// is being sent, and with EINVAL if `min_be` exceeds `max_be` or `max_be`
+    /// exceeds 8.
+    pub fn set_config(&self, config: CsmaConfig) -> ReturnCode {
+        if self.state.get() != CsmaState::Idle {
+            ReturnCode::EBUSY
+        } else if config.min_be > config.max_be || config.max_be > 8 {
+            ReturnCode::EINVAL
+        } else {
+            self.config.set(config);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    // Starts a new CSMA-CA procedure for the buffered frame.
+    fn start_csma(&self) {
+        self.backoffs.set(0);
+        self.backoff_exponent.set(self.config.get().min_be);
+        self.backoff();
+    }
+
+    fn backoff(&self) {
+        let periods = self.random.random() & ((1 << self.backoff_exponent.get()) - 1);
+        self.state.set(CsmaState::Backoff);
+        self.channel_busy.set(false);
+        self.alarm.set_alarm(
+            self.alarm.now(),
+            A::ticks_from_us(periods * UNIT_BACKOFF_PERIOD_US),
+        );
+    }
+
+    // Performs the clear channel assessment at the end of a backoff period and
+    // either transmits the frame or backs off again.
+    fn channel_access(&self) {
+        if self.radio.busy() || self.channel_busy.get() {
+            let config = self.config.get();
+            self.backoffs.set(self.backoffs.get() + 1);
+            self.backoff_exponent
+                .set(cmp::min(self.backoff_exponent.get() + 1, config.max_be));
+            if self.backoffs.get() > config.max_csma_backoffs {
+                self.tx_buf
+                    .take()
+                    .map(|buf| self.call_tx_client(buf, false, ReturnCode::EBUSY));
+            } else {
+                self.backoff();
+            }
+            return;
+        }
+
+        self.tx_buf.take().map(|buf| {
+            self.state.set(CsmaState::Transmitting);
+            let (rval, buf) = self.radio.transmit(buf, self.tx_len.get());
+            if rval != ReturnCode::SUCCESS {
+                match buf {
+                    Some(buf) => self.call_tx_client(buf, false, rval),
+                    None => {
+                        // The radio kept the frame, so there is nothing to
+                        // hand back, but the MAC must not stay Transmitting.
+                        self.state.set(CsmaState::Idle);
+                        self.tx_ack_seq.clear();
+                    }
+                }
+            }
+        });
+    }
+
+    // Retransmits the buffered frame if retries remain, otherwise reports
+    // ENOACK.
+    fn retransmit(&self) {
+        if self.retries.get() < self.config.get().max_frame_retries {
+            self.retries.set(self.retries.get() + 1);
+            self.start_csma();
+        } else {
+            self.tx_buf
+                .take()
+                .map(|buf| self.call_tx_client(buf, false, ReturnCode::ENOACK));
+        }
+    }
+
+    fn call_tx_client(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.state.set(CsmaState::Idle);
+        self.tx_ack_seq.clear();
+        self.tx_client.map(move |c| {
+            c.send_done(buf, acked, result);
+        });
+    }
+
+    // Returns the sequence number if the received frame is an Imm-Ack.
+    fn ack_seq(buf: &[u8], frame_len: usize) -> Option<u8> {
+        if frame_len < IMM_ACK_LEN || buf.len() < radio::PSDU_OFFSET + IMM_ACK_LEN {
+            return None;
+        }
+        let fcf = buf[radio::PSDU_OFFSET] as u16 | (buf[radio::PSDU_OFFSET + 1] as u16) << 8;
+        match FrameType::from_fcf(fcf) {
+            Some(FrameType::Acknowledgement) => Some(buf[radio::PSDU_OFFSET + 2]),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> Mac for CsmaMac<'a, R, A> {
+    fn initialize(&self, _mac_buf: &'static mut [u8]) -> ReturnCode {
+        // The frame is retained in the client's buffer between retries, so no
+        // additional buffer is needed.
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.radio.is_on()
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.radio.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != CsmaState::Idle || self.tx_buf.is_some() {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        } else if radio::PSDU_OFFSET + frame_len >= full_mac_frame.len() {
+            return (ReturnCode::ESIZE, Some(full_mac_frame));
+        }
+
+        match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
+            Some((_, (header, _))) => match header.seq {
+                // Broadcast frames are never acknowledged, even if requested.
+                Some(seq)
+                    if header.ack_requested
+                        && header.dst_addr != Some(MacAddress::Short(BROADCAST_SHORT_ADDR)) =>
+                {
+                    self.tx_ack_seq.set(seq)
+                }
+                _ => self.tx_ack_seq.clear(),
+            },
+            None => return (ReturnCode::FAIL, Some(full_mac_frame)),
+        }
+
+        self.tx_buf.replace(full_mac_frame);
+        self.tx_len.set(frame_len);
+        self.retries.set(0);
+        self.start_csma();
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> time::AlarmClient for CsmaMac<'a, R, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            CsmaState::Backoff => self.channel_access(),
+            // No acknowledgement arrived within macAckWaitDuration.
+            CsmaState::WaitAck => self.retransmit(),
+            CsmaState::Idle | CsmaState::Transmitting => {}
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::TxClient for CsmaMac<'a, R, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if self.state.get() != CsmaState::Transmitting {
+            return;
+        }
+
+        match result {
+            // Radios that retransmit in hardware report a missing ACK as
+            // ENOACK; retry at this layer as well.
+            ReturnCode::ENOACK if self.tx_ack_seq.is_some() => {
+                self.tx_buf.replace(buf);
+                self.retransmit();
+            }
+            ReturnCode::SUCCESS if self.tx_ack_seq.is_some() && !acked => {
+                // Wait for the acknowledgement in software.
+                self.tx_buf.replace(buf);
+                self.state.set(CsmaState::WaitAck);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_us(ACK_WAIT_DURATION_US));
+            }
+            _ => self.call_tx_client(buf, acked && self.tx_ack_seq.is_some(), result),
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::RxClient for CsmaMac<'a, R, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        result: ReturnCode,
+    ) {
+        // Any reception during a backoff means the channel is in use.
+        if self.state.get() == CsmaState::Backoff {
+            self.channel_busy.set(true);
+        }
+
+        // Acknowledgements are consumed by this layer.
+        if let Some(seq) = Self::ack_seq(buf, frame_len) {
+            self.radio.set_receive_buffer(buf);
+            if crc_valid && self.state.get() == CsmaState::WaitAck && self.tx_ack_seq.contains(&seq)
+            {
+                self.alarm.disarm();
+                self.tx_buf
+                    .take()
+                    .map(|buf| self.call_tx_client(buf, true, ReturnCode::SUCCESS));
+            }
+            return;
+        }
+
+        // Filter packets by destination because radio is in promiscuous mode
+        let mut addr_match = false;
+        if let Some((_, (header, _))) = Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
+            if let Some(dst_addr) = header.dst_addr {
+                addr_match = match dst_addr {
+                    MacAddress::Short(addr) => {
+                        addr == self.radio.get_address() || addr == BROADCAST_SHORT_ADDR
+                    }
+                    MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
+                };
+            }
+        }
+
+        if addr_match {
+            self.rx_client.map(move |c| {
+                c.receive(buf, frame_len, crc_valid, result);
+            });
+        } else {
+            self.radio.set_receive_buffer(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::net::ieee802154::{FrameVersion, Header, MacAddress};
+    use kernel::hil::time::{Freq1MHz, Ticks32, Time};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    struct TestRadio {
+        address: u16,
+        busy: Cell<bool>,
+        sent: TakeCell<'static, [u8]>,
+        tx_count: Cell<usize>,
+        // Result of `transmit`. On failure the radio keeps the frame.
+        tx_result: Cell<ReturnCode>,
+    }
+
+    impl radio::RadioConfig for TestRadio {
+        fn initialize(
+            &self,
+            _: &'static mut [u8],
+            _: &'static mut [u8],
+            _: &'static mut [u8],
+        ) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn reset(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn start(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn stop(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn is_on(&self) -> bool {
+            true
+        }
+        fn busy(&self) -> bool {
+            self.busy.get()
+        }
+        fn set_power_client(&self, _: &'static dyn radio::PowerClient) {}
+        fn config_commit(&self) {}
+        fn set_config_client(&self, _: &'static dyn radio::ConfigClient) {}
+        fn get_address(&self) -> u16 {
+            self.address
+        }
+        fn get_address_long(&self) -> [u8; 8] {
+            [0; 8]
+        }
+        fn get_pan(&self) -> u16 {
+            0xabcd
+        }
+        fn get_tx_power(&self) -> i8 {
+            0
+        }
+        fn get_channel(&self) -> u8 {
+            26
+        }
+        fn set_address(&self, _: u16) {}
+        fn set_address_long(&self, _: [u8; 8]) {}
+        fn set_pan(&self, _: u16) {}
+        fn set_tx_power(&self, _: i8) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn set_channel(&self, _: u8) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    impl radio::RadioData for TestRadio {
+        fn set_transmit_client(&self, _: &'static dyn radio::TxClient) {}
+        fn set_receive_client(&self, _: &'static dyn radio::RxClient, _: &'static mut [u8]) {}
+        fn set_receive_buffer(&self, _: &'static mut [u8]) {}
+        fn transmit(
+            &self,
+            buf: &'static mut [u8],
+            _frame_len: usize,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            self.tx_count.set(self.tx_count.get() + 1);
+            self.sent.replace(buf);
+            (self.tx_result.get(), None)
+        }
+    }
+
+    impl radio::Radio for TestRadio {}
+
+    struct TestAlarm {
+        armed: Cell<bool>,
+    }
+
+    impl Time for TestAlarm {
+        type Frequency = Freq1MHz;
+        type Ticks = Ticks32;
+        fn now(&self) -> Ticks32 {
+            0.into()
+        }
+    }
+
+    impl<'a> Alarm<'a> for TestAlarm {
+        fn set_alarm_client(&'a self, _: &'a dyn time::AlarmClient) {}
+        fn set_alarm(&self, _: Ticks32, _: Ticks32) {
+            self.armed.set(true);
+        }
+        fn get_alarm(&self) -> Ticks32 {
+            0.into()
+        }
+        fn disarm(&self) -> ReturnCode {
+            self.armed.set(false);
+            ReturnCode::SUCCESS
+        }
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+        fn minimum_dt(&self) -> Ticks32 {
+            1.into()
+        }
+    }
+
+    struct TestRandom;
+
+    impl<'a> Random<'a> for TestRandom {
+        fn initialize(&'a self) {}
+        fn reseed(&self, _: u32) {}
+        fn random(&self) -> u32 {
+            5
+        }
+    }
+
+    #[derive(Default)]
+    struct TestClient {
+        done: Cell<Option<(bool, ReturnCode)>>,
+    }
+
+    impl radio::TxClient for TestClient {
+        fn send_done(&self, _: &'static mut [u8], acked: bool, result: ReturnCode) {
+            self.done.set(Some((acked, result)));
+        }
+    }
+
+    type TestMac = CsmaMac<'static, TestRadio, TestAlarm>;
+
+    fn setup() -> (
+        &'static TestMac,
+        &'static TestRadio,
+        &'static TestAlarm,
+        &'static TestClient,
+    ) {
+        let radio: &'static TestRadio = Box::leak(Box::new(TestRadio {
+            address: 0x0001,
+            busy: Cell::new(false),
+            sent: TakeCell::empty(),
+            tx_count: Cell::new(0),
+            tx_result: Cell::new(ReturnCode::SUCCESS),
+        }));
+        let alarm: &'static TestAlarm = Box::leak(Box::new(TestAlarm {
+            armed: Cell::new(false),
+        }));
+        let mac: &'static TestMac = Box::leak(Box::new(CsmaMac::new(
+            radio,
+            alarm,
+            Box::leak(Box::new(TestRandom)),
+            CsmaConfig::default(),
+        )));
+        let client: &'static TestClient = Box::leak(Box::new(TestClient::default()));
+        mac.set_transmit_client(client);
+        (mac, radio, alarm, client)
+    }
+
+    fn frame(seq: u8, ack_requested: bool) -> (&'static mut [u8], usize) {
+        let header = Header {
+            frame_type: FrameType::Data,
+            frame_pending: false,
+            ack_requested: ack_requested,
+            version: FrameVersion::V2006,
+            seq: Some(seq),
+            dst_pan: Some(0xabcd),
+            dst_addr: Some(MacAddress::Short(0x0002)),
+            src_pan: Some(0xabcd),
+            src_addr: Some(MacAddress::Short(0x0001)),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        let buf = Box::leak(Box::new([0u8; radio::MAX_BUF_SIZE]));
+        let (len, _) = header
+            .encode(&mut buf[radio::PSDU_OFFSET..], true)
+            .done()
+            .unwrap();
+        (buf, len)
+    }
+
+    fn ack(seq: u8) -> &'static mut [u8] {
+        let mut buf: Vec<u8> = std::vec![0; radio::MAX_BUF_SIZE];
+        buf[radio::PSDU_OFFSET] = FrameType::Acknowledgement as u8;
+        buf[radio::PSDU_OFFSET + 2] = seq;
+        Box::leak(buf.into_boxed_slice())
+    }
+
+    // Completes a transmission in the radio the way hardware without ACK
+    // support would.
+    fn radio_done(mac: &TestMac, radio: &TestRadio) {
+        let buf = radio.sent.take().unwrap();
+        radio::TxClient::send_done(mac, buf, false, ReturnCode::SUCCESS);
+    }
+
+    #[test]
+    fn acked_after_software_ack() {
+        let (mac, radio, alarm, client) = setup();
+        let (buf, len) = frame(42, true);
+        assert_eq!(mac.transmit(buf, len).0, ReturnCode::SUCCESS);
+        assert!(alarm.is_armed());
+
+        time::AlarmClient::alarm(mac);
+        assert_eq!(radio.tx_count.get(), 1);
+        radio_done(mac, radio);
+        assert!(client.done.get().is_none());
+
+        // An ACK for a different frame is ignored.
+        radio::RxClient::receive(mac, ack(41), IMM_ACK_LEN, true, ReturnCode::SUCCESS);
+        assert!(client.done.get().is_none());
+        radio::RxClient::receive(mac, ack(42), IMM_ACK_LEN, true, ReturnCode::SUCCESS);
+        assert_eq!(client.done.get(), Some((true, ReturnCode::SUCCESS)));
+    }
+
+    #[test]
+    fn retransmits_until_max_frame_retries() {
+        let (mac, radio, _alarm, client) = setup();
+        let (buf, len) = frame(7, true);
+        mac.transmit(buf, len);
+
+        let attempts = CsmaConfig::default().max_frame_retries as usize + 1;
+        for _ in 0..attempts {
+            // Backoff expires, then the ACK wait times out.
+            time::AlarmClient::alarm(mac);
+            radio_done(mac, radio);
+            time::AlarmClient::alarm(mac);
+        }
+        assert_eq!(radio.tx_count.get(), attempts);
+        assert_eq!(client.done.get(), Some((false, ReturnCode::ENOACK)));
+    }
+
+    #[test]
+    fn channel_access_failure() {
+        let (mac, radio, _alarm, client) = setup();
+        radio.busy.set(true);
+        let (buf, len) = frame(1, true);
+        mac.transmit(buf, len);
+
+        for _ in 0..=CsmaConfig::default().max_csma_backoffs {
+            time::AlarmClient::alarm(mac);
+        }
+        assert_eq!(radio.tx_count.get(), 0);
+        assert_eq!(client.done.get(), Some((false, ReturnCode::EBUSY)));
+    }
+
+    #[test]
+    fn no_ack_requested() {
+        let (mac, radio, _alarm, client) = setup();
+        let (buf, len) = frame(3, false);
+        mac.transmit(buf, len);
+        time::AlarmClient::alarm(mac);
+        radio_done(mac, radio);
+        assert_eq!(client.done.get(), Some((false, ReturnCode::SUCCESS)));
+    }
+
+    #[test]
+    fn radio_transmit_failure_without_buffer() {
+        let (mac, radio, _alarm, client) = setup();
+        radio.tx_result.set(ReturnCode::FAIL);
+        let (buf, len) = frame(5, true);
+        mac.transmit(buf, len);
+        time::AlarmClient::alarm(mac);
+        assert_eq!(radio.tx_count.get(), 1);
+        assert!(client.done.get().is_none());
+
+        // The radio kept the frame, but the MAC accepts the next one.
+        radio.tx_result.set(ReturnCode::SUCCESS);
+        let (buf, len) = frame(6, false);
+        assert_eq!(mac.transmit(buf, len).0, ReturnCode::SUCCESS);
+        time::AlarmClient::alarm(mac);
+        radio_done(mac, radio);
+        assert_eq!(client.done.get(), Some((false, ReturnCode::SUCCESS)));
+    }
+}
@@ -0,0 +1,461 @@
+//! Software IEEE 802.15.4 radios connected through a simulated medium.
+//!
+//! `LoopbackRadio` implements `kernel::hil::radio::Radio` entirely in
+//! software. Any number of radios can be attached to one `LoopbackMedium`,
+//! which delivers each transmitted frame to every other radio that is powered
+//! on and tuned to the same channel. This allows the complete networking
+//! stack (`Framer`, 6LoWPAN, IPv6 and UDP) to run between several virtual
+//! nodes without radio hardware, for example in host tests.
+//!
+//! The medium models:
+//!
+//! - delivery delay: frames arrive `delay_us` after `transmit` is called,
+//! - frame loss: each delivery is dropped with probability `loss_percent`, if
+//!   a `Random` source has been provided,
+//! - received signal strength: frames arrive with an RSSI of the medium's
+//!   path RSSI plus the sender's transmit power, and radios discard frames
+//!   below their configured sensitivity.
+//!
+//! Radios acknowledge frames automatically, as most hardware does: a frame
+//! that requests an acknowledgement completes with `acked == true` if some
+//! radio whose address matches the destination received it.
+//!
+//! All callbacks, including `config_done` and power changes, are delivered
+//! from the medium's alarm and never synchronously.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! type Medium = capsules::ieee802154::loopback::LoopbackMedium<'static, VirtualMuxAlarm>;
+//! type Radio = capsules::ieee802154::loopback::LoopbackRadio<'static, VirtualMuxAlarm>;
+//!
+//! let medium = static_init!(Medium, Medium::new(medium_alarm));
+//! medium_alarm.set_alarm_client(medium);
+//! let radio1 = static_init!(Radio, Radio::new(medium));
+//! let radio2 = static_init!(Radio, Radio::new(medium));
+//! medium.add_radio(radio1);
+//! medium.add_radio(radio2);
+//! ```
+
+use crate::net::ieee802154::{Header, MacAddress};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::radio;
+use kernel::hil::rng::Random;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+/// Default RSSI, in dBm, of a frame sent at 0 dBm.
+pub const DEFAULT_PATH_RSSI: i8 = -50;
+/// Default receiver sensitivity in dBm.
+pub const DEFAULT_SENSITIVITY: i8 = -100;
+
+pub struct LoopbackMedium<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    radios: List<'a, LoopbackRadio<'a, A>>,
+    random: OptionalCell<&'a dyn Random<'a>>,
+    loss_percent: Cell<u8>,
+    delay_us: Cell<u32>,
+    path_rssi: Cell<i8>,
+}
+
+impl<'a, A: Alarm<'a>> LoopbackMedium<'a, A> {
+    pub fn new(alarm: &'a A) -> LoopbackMedium<'a, A> {
+        LoopbackMedium {
+            alarm: alarm,
+            radios: List::new(),
+            random: OptionalCell::empty(),
+            loss_percent: Cell::new(0),
+            delay_us: Cell::new(0),
+            path_rssi: Cell::new(DEFAULT_PATH_RSSI),
+        }
+    }
+
+    pub fn add_radio(&self, radio: &'a LoopbackRadio<'a, A>) {
+        self.radios.push_tail(radio);
+    }
+
+    /// Sets the source of randomness used to decide which frames are lost.
+    pub fn set_random(&self, random: &'a dyn Random<'a>) {
+        self.random.set(random);
+    }
+
+    /// Sets the percentage (0 to 100) of deliveries that are dropped.
+    pub fn set_loss(&self, loss_percent: u8) -> ReturnCode {
+        if loss_percent > 100 {
+            return ReturnCode::EINVAL;
+        }
+        self.loss_percent.set(loss_percent);
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets the time between a call to `transmit` and the frame's delivery.
+    pub fn set_delay_us(&self, delay_us: u32) {
+        self.delay_us.set(delay_us);
+    }
+
+    /// Sets the RSSI, in dBm, at which a frame sent at 0 dBm is received.
+    pub fn set_path_rssi(&self, rssi: i8) {
+        self.path_rssi.set(rssi);
+    }
+
+    fn lost(&self) -> bool {
+        let loss = self.loss_percent.get() as u32;
+        loss > 0
+            && self
+                .random
+                .map_or(false, |random| random.random() % 100 < loss)
+    }
+
+    // Returns the ticks remaining until `dt` ticks after `reference`, or
+    // `None` if that time has passed, however late the alarm fired.
+    fn remaining(&self, (reference, dt): (A::Ticks, A::Ticks), now: A::Ticks) -> Option<A::Ticks> {
+        let deadline = reference.wrapping_add(dt);
+        if now.within_range(reference, deadline) {
+            Some(deadline.wrapping_sub(now))
+        } else {
+            None
+        }
+    }
+
+    // Arms the alarm for the earliest pending event, if any.
+    fn rearm(&self) {
+        let now = self.alarm.now();
+        let next = self
+            .radios
+            .iter()
+            .filter_map(|radio| {
+                if radio.config_pending.get() || radio.power_pending.get() {
+                    Some(0.into())
+                } else {
+                    radio
+                        .tx_deadline
+                        .get()
+                        .map(|deadline| self.remaining(deadline, now).unwrap_or(0.into()))
+                }
+            })
+            .min();
+        match next {
+            Some(dt) => self.alarm.set_alarm(now, dt),
+            None => {
+                self.alarm.disarm();
+            }
+        }
+    }
+
+    // Delivers a frame from `sender` to every other radio that can hear it,
+    // returning whether an addressed recipient received it.
+    fn deliver(&self, sender: &LoopbackRadio<'a, A>, frame: &[u8], frame_len: usize) -> bool {
+        let rssi = self.path_rssi.get().saturating_add(sender.tx_power.get());
+        let (ack_requested, dst_pan, dst_addr) = match Header::decode(frame, false).done() {
+            Some((_, (header, _))) => (header.ack_requested, header.dst_pan, header.dst_addr),
+            None => (false, None, None),
+        };
+
+        let mut acked = false;
+        for radio in self.radios.iter() {
+            if radio as *const _ == sender as *const _
+                || !radio.on.get()
+                || radio.channel.get() != sender.channel.get()
+                || rssi < radio.sensitivity.get()
+                || self.lost()
+            {
+                continue;
+            }
+            if ack_requested && radio.accepts(dst_pan, dst_addr) {
+                acked = true;
+            }
+            radio.receive(frame, frame_len, rssi);
+        }
+        acked
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for LoopbackMedium<'a, A> {
+    fn alarm(&self) {
+        let now = self.alarm.now();
+        for radio in self.radios.iter() {
+            let tx_due = radio
+                .tx_deadline
+                .get()
+                .map_or(false, |deadline| self.remaining(deadline, now).is_none());
+            radio.handle_events(self, tx_due);
+        }
+        self.rearm();
+    }
+}
+
+pub struct LoopbackRadio<'a, A: Alarm<'a>> {
+    medium: &'a LoopbackMedium<'a, A>,
+    next: ListLink<'a, LoopbackRadio<'a, A>>,
+
+    on: Cell<bool>,
+    address: Cell<u16>,
+    address_long: Cell<[u8; 8]>,
+    pan: Cell<u16>,
+    tx_power: Cell<i8>,
+    channel: Cell<u8>,
+    sensitivity: Cell<i8>,
+    last_rssi: Cell<i8>,
+
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    config_client: OptionalCell<&'static dyn radio::ConfigClient>,
+    power_client: OptionalCell<&'static dyn radio::PowerClient>,
+
+    rx_buf: TakeCell<'static, [u8]>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    // When the pending frame was sent, and the ticks after which it is
+    // delivered.
+    tx_deadline: Cell<Option<(A::Ticks, A::Ticks)>>,
+    config_pending: Cell<bool>,
+    power_pending: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> ListNode<'a, LoopbackRadio<'a, A>> for LoopbackRadio<'a, A> {
+    fn next(&self) -> &'a ListLink<LoopbackRadio<'a, A>> {
+        &self.next
+    }
+}
+
+impl<'a, A: Alarm<'a>> LoopbackRadio<'a, A> {
+    pub fn new(medium: &'a LoopbackMedium<'a, A>) -> LoopbackRadio<'a, A> {
+        LoopbackRadio {
+            medium: medium,
+            next: ListLink::empty(),
+            on: Cell::new(false),
+            address: Cell::new(0),
+            address_long: Cell::new([0; 8]),
+            pan: Cell::new(0),
+            tx_power: Cell::new(0),
+            channel: Cell::new(26),
+            sensitivity: Cell::new(DEFAULT_SENSITIVITY),
+            last_rssi: Cell::new(0),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            config_client: OptionalCell::empty(),
+            power_client: OptionalCell::empty(),
+            rx_buf: TakeCell::empty(),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_deadline: Cell::new(None),
+            config_pending: Cell::new(false),
+            power_pending: Cell::new(false),
+        }
+    }
+
+    /// Sets the RSSI, in dBm, below which incoming frames are discarded.
+    pub fn set_sensitivity(&self, sensitivity: i8) {
+        self.sensitivity.set(sensitivity);
+    }
+
+    /// The RSSI, in dBm, of the most recently received frame.
+    pub fn last_rssi(&self) -> i8 {
+        self.last_rssi.get()
+    }
+
+    // Whether this radio's address filter accepts the given destination.
+    fn accepts(&self, dst_pan: Option<u16>, dst_addr: Option<MacAddress>) -> bool {
+        let pan_match = dst_pan.map_or(true, |pan| pan == 0xffff || pan == self.pan.get());
+        let addr_match = match dst_addr {
+            Some(MacAddress::Short(addr)) => addr == 0xffff || addr == self.address.get(),
+            Some(MacAddress::Long(addr)) => addr == self.address_long.get(),
+            None => false,
+        };
+        pan_match && addr_match
+    }
+
+    fn receive(&self, frame: &[u8], frame_len: usize, rssi: i8) {
+        self.rx_buf.take().map(|buf| {
+            if buf.len() < radio::PSDU_OFFSET + frame_len {
+                self.rx_buf.replace(buf);
+                return;
+            }
+            buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len]
+                .copy_from_slice(&frame[..frame_len]);
+            self.last_rssi.set(rssi);
+            if self.rx_client.is_some() {
+                self.rx_client
+                    .map(move |client| client.receive(buf, frame_len, true, ReturnCode::SUCCESS));
+            } else {
+                self.rx_buf.replace(buf);
+            }
+        });
+    }
+
+    fn handle_events(&self, medium: &LoopbackMedium<'a, A>, tx_due: bool) {
+        if self.config_pending.take() {
+            self.config_client
+                .map(|client| client.config_done(ReturnCode::SUCCESS));
+        }
+        if self.power_pending.take() {
+            let on = self.on.get();
+            self.power_client.map(|client| client.changed(on));
+        }
+        if !tx_due {
+            return;
+        }
+        self.tx_deadline.set(None);
+        self.tx_buf.take().map(|buf| {
+            let frame_len = self.tx_len.get();
+            let acked = if self.on.get() {
+                medium.deliver(
+                    self,
+                    &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len],
+                    frame_len,
+                )
+            } else {
+                false
+            };
+            let result = if self.on.get() {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::EOFF
+            };
+            self.tx_client
+                .map(move |client| client.send_done(buf, acked, result));
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> radio::RadioConfig for LoopbackRadio<'a, A> {
+    fn initialize(
+        &self,
+        _spi_buf: &'static mut [u8],
+        _reg_write: &'static mut [u8],
+        _reg_read: &'static mut [u8],
+    ) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn reset(&self) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn start(&self) -> ReturnCode {
+        self.on.set(true);
+        self.power_pending.set(true);
+        self.medium.rearm();
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.on.set(false);
+        self.power_pending.set(true);
+        self.medium.rearm();
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.on.get()
+    }
+
+    fn busy(&self) -> bool {
+        self.tx_buf.is_some()
+    }
+
+    fn set_power_client(&self, client: &'static dyn radio::PowerClient) {
+        self.power_client.set(client);
+    }
+
+    fn config_commit(&self) {
+        self.config_pending.set(true);
+        self.medium.rearm();
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.config_client.set(client);
+    }
+
+    fn get_address(&self) -> u16 {
+        self.address.get()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.address_long.get()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.pan.get()
+    }
+
+    fn get_tx_power(&self) -> i8 {
+        self.tx_power.get()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.channel.get()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.address.set(addr);
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.address_long.set(addr);
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.pan.set(id);
+    }
+
+    fn set_tx_power(&self, power: i8) -> ReturnCode {
+        self.tx_power.set(power);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        if chan < 11 || chan > 26 {
+            return ReturnCode::EINVAL;
+        }
+        self.channel.set(chan);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<'a, A: Alarm<'a>> radio::RadioData for LoopbackRadio<'a, A> {
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient, buffer: &'static mut [u8]) {
+        self.rx_client.set(client);
+        self.rx_buf.replace(buffer);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.rx_buf.replace(buffer);
+    }
+
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.on.get() {
+            return (ReturnCode::EOFF, Some(buf));
+        } else if self.tx_buf.is_some() {
+            return (ReturnCode::EBUSY, Some(buf));
+        } else if frame_len > radio::MAX_FRAME_SIZE || radio::PSDU_OFFSET + frame_len > buf.len() {
+            return (ReturnCode::ESIZE, Some(buf));
+        }
+
+        self.tx_buf.replace(buf);
+        self.tx_len.set(frame_len);
+        self.tx_deadline.set(Some((
+            self.medium.alarm.now(),
+            A::ticks_from_us(self.medium.delay_us.get()),
+        )));
+        self.medium.rearm();
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<'a, A: Alarm<'a>> radio::Radio for LoopbackRadio<'a, A> {}
+
+impl<'a, A: Alarm<'a>> radio::PromiscuousRadio for LoopbackRadio<'a, A> {}
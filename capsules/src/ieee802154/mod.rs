@@ -1,8 +1,11 @@
 //! Support for IEEE 802.15.4.
 
+pub mod csma;
 pub mod device;
 pub mod framer;
+pub mod loopback;
 pub mod mac;
+pub mod sniffer;
 pub mod virtual_mac;
 pub mod xmac;
 
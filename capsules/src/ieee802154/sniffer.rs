@@ -0,0 +1,200 @@
+//! IEEE 802.15.4 sniffer that streams every received frame to a host.
+//!
+//! The sniffer is attached directly to a radio, below any `Mac` layer, and
+//! forwards every frame the radio hears. The radio must implement
+//! `kernel::hil::radio::PromiscuousRadio`, so that it doesn't filter frames
+//! addressed to other devices. Each frame is timestamped on reception and
+//! written to a `kernel::hil::uart::Transmit` device, which may be a hardware
+//! UART or the USB CDC-ACM device in `capsules::usb::cdc`. The
+//! `tools/ieee802154-sniffer` host program converts the resulting byte stream
+//! into a pcap feed for Wireshark.
+//!
+//! Wire format
+//! -----------
+//!
+//! Each frame is sent as one record. Multi-byte fields are little-endian.
+//!
+//! ```text
+//! +------+------+-----+-------+---------+--------------+-------------+
+//! | 0xC3 | 0x15 | len | flags | dropped | timestamp_us | PSDU        |
+//! +------+------+-----+-------+---------+--------------+-------------+
+//!    1      1      1      1       2            8          len bytes
+//! ```
+//!
+//! - `len`: length of the PSDU that follows, excluding the FCS.
+//! - `flags`: bit 0 is set if the radio reported a valid FCS.
+//! - `dropped`: number of frames that were discarded since the previous
+//!   record because the UART was still busy, saturating at 0xFFFF.
+//! - `timestamp_us`: reception time in microseconds since the sniffer started.
+//!   The sniffer sets an alarm every half timer period to count wraparounds
+//!   of the timer, so timestamps stay correct when no frames arrive for a
+//!   long time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static mut SNIFFER_BUF: [u8; capsules::ieee802154::sniffer::BUF_SIZE] =
+//!     [0; capsules::ieee802154::sniffer::BUF_SIZE];
+//!
+//! let sniffer = static_init!(
+//!     capsules::ieee802154::sniffer::Sniffer<'static, RF233Device, VirtualMuxAlarm>,
+//!     capsules::ieee802154::sniffer::Sniffer::new(rf233, sniffer_alarm, uart, &mut SNIFFER_BUF)
+//! );
+//! sniffer_alarm.set_alarm_client(sniffer);
+//! hil::uart::Transmit::set_transmit_client(uart, sniffer);
+//! rf233.set_receive_client(sniffer, &mut RF233_RX_BUF);
+//! sniffer.start(26);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::TakeCell;
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+/// Marks the start of each record in the stream.
+pub const MAGIC: [u8; 2] = [0xC3, 0x15];
+/// Size of the record header preceding the PSDU.
+pub const HEADER_SIZE: usize = 14;
+/// Size of the buffer a board must provide to hold one record.
+pub const BUF_SIZE: usize = HEADER_SIZE + radio::MAX_MTU;
+
+const FLAG_CRC_VALID: u8 = 0x01;
+
+pub struct Sniffer<'a, R: radio::PromiscuousRadio, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    uart: &'a dyn uart::Transmit<'a>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    dropped: Cell<u16>,
+    // Used to extend the timer's ticks to 64 bits.
+    start_ticks: Cell<A::Ticks>,
+    last_ticks: Cell<A::Ticks>,
+    wraps: Cell<u64>,
+}
+
+impl<'a, R: radio::PromiscuousRadio, A: Alarm<'a>> Sniffer<'a, R, A> {
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        uart: &'a dyn uart::Transmit<'a>,
+        tx_buffer: &'static mut [u8],
+    ) -> Sniffer<'a, R, A> {
+        Sniffer {
+            radio: radio,
+            alarm: alarm,
+            uart: uart,
+            tx_buffer: TakeCell::new(tx_buffer),
+            dropped: Cell::new(0),
+            start_ticks: Cell::new(A::Ticks::from(0)),
+            last_ticks: Cell::new(A::Ticks::from(0)),
+            wraps: Cell::new(0),
+        }
+    }
+
+    /// Tunes the radio to `channel` and starts capturing frames.
+    pub fn start(&self, channel: u8) -> ReturnCode {
+        let rval = self.radio.set_channel(channel);
+        if rval != ReturnCode::SUCCESS {
+            return rval;
+        }
+        let now = self.alarm.now();
+        self.start_ticks.set(now);
+        self.last_ticks.set(now);
+        self.wraps.set(0);
+        self.set_wrap_alarm(now);
+        self.radio.config_commit();
+        if self.radio.is_on() {
+            ReturnCode::SUCCESS
+        } else {
+            self.radio.start()
+        }
+    }
+
+    /// Stops capturing frames.
+    pub fn stop(&self) -> ReturnCode {
+        self.alarm.disarm();
+        self.radio.stop()
+    }
+
+    // Checks the timer at least twice per period, so that no wraparound is
+    // missed between frames.
+    fn set_wrap_alarm(&self, reference: A::Ticks) {
+        let half_period = A::Ticks::from(A::Ticks::max_value().into_u32() / 2);
+        self.alarm.set_alarm(reference, half_period);
+    }
+
+    // Ticks elapsed since `start`, extended across timer wraparound.
+    fn elapsed_ticks(&self) -> u64 {
+        let now = self.alarm.now();
+        if now < self.last_ticks.get() {
+            self.wraps.set(self.wraps.get() + 1);
+        }
+        self.last_ticks.set(now);
+        let period = A::Ticks::max_value().into_u32() as u64 + 1;
+        self.wraps.get() * period + now.into_u32() as u64 - self.start_ticks.get().into_u32() as u64
+    }
+
+    // Microseconds elapsed since `start`.
+    fn timestamp_us(&self) -> u64 {
+        let ticks = self.elapsed_ticks();
+        let freq = A::Frequency::frequency() as u64;
+        (ticks / freq) * 1_000_000 + (ticks % freq) * 1_000_000 / freq
+    }
+
+    fn record_dropped(&self) {
+        self.dropped.set(self.dropped.get().saturating_add(1));
+    }
+}
+
+impl<'a, R: radio::PromiscuousRadio, A: Alarm<'a>> radio::RxClient for Sniffer<'a, R, A> {
+    fn receive(&self, buf: &'static mut [u8], frame_len: usize, crc_valid: bool, _: ReturnCode) {
+        let timestamp = self.timestamp_us();
+        let frame_len = core::cmp::min(
+            frame_len,
+            core::cmp::min(radio::MAX_MTU, buf.len().saturating_sub(radio::PSDU_OFFSET)),
+        );
+
+        match self.tx_buffer.take() {
+            Some(tx) => {
+                tx[0..2].copy_from_slice(&MAGIC);
+                tx[2] = frame_len as u8;
+                tx[3] = if crc_valid { FLAG_CRC_VALID } else { 0 };
+                tx[4..6].copy_from_slice(&self.dropped.get().to_le_bytes());
+                tx[6..14].copy_from_slice(&timestamp.to_le_bytes());
+                if frame_len > 0 {
+                    tx[HEADER_SIZE..HEADER_SIZE + frame_len]
+                        .copy_from_slice(&buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len]);
+                }
+
+                let (rval, tx) = self.uart.transmit_buffer(tx, HEADER_SIZE + frame_len);
+                if rval == ReturnCode::SUCCESS {
+                    self.dropped.set(0);
+                } else {
+                    tx.map(|tx| self.tx_buffer.replace(tx));
+                    self.record_dropped();
+                }
+            }
+            None => self.record_dropped(),
+        }
+
+        self.radio.set_receive_buffer(buf);
+    }
+}
+
+impl<'a, R: radio::PromiscuousRadio, A: Alarm<'a>> uart::TransmitClient for Sniffer<'a, R, A> {
+    fn transmitted_buffer(&self, buf: &'static mut [u8], _tx_len: usize, _rval: ReturnCode) {
+        self.tx_buffer.replace(buf);
+    }
+}
+
+impl<'a, R: radio::PromiscuousRadio, A: Alarm<'a>> time::AlarmClient for Sniffer<'a, R, A> {
+    fn alarm(&self) {
+        self.elapsed_ticks();
+        self.set_wrap_alarm(self.alarm.get_alarm());
+    }
+}
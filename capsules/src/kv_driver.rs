@@ -0,0 +1,313 @@
+//! Syscall driver giving applications access to a key-value store.
+//!
+//! Each application gets its own namespace in the store, derived from the
+//! package name in its TBF header. Applications without a package name fall
+//! back to their `AppId`, which does not survive a reboot, so those
+//! applications effectively only get volatile storage. Keys from different
+//! applications never refer to the same value, and values written by trusted
+//! kernel code cannot be modified from userspace.
+//!
+//! Only one operation is in flight at a time. Each application can queue one
+//! operation while another application's operation is running.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let kv_driver_buffer = static_init!([u8; 256], [0; 256]);
+//! let kv_driver = static_init!(
+//!     capsules::kv_driver::KVStoreDriver<'static>,
+//!     capsules::kv_driver::KVStoreDriver::new(
+//!         kv_store,
+//!         board_kernel.create_grant(&grant_cap),
+//!         kv_driver_buffer,
+//!     )
+//! );
+//! kernel::hil::kv_store::KVStore::set_client(kv_store, kv_driver);
+//! ```
+
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::kv_store::{self, StoragePermissions};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::KVStore as usize;
+
+#[derive(Clone, Copy, PartialEq)]
+enum UserOperation {
+    Get,
+    Set,
+    Delete,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    write_value: Option<AppSlice<Shared, u8>>,
+    read_value: Option<AppSlice<Shared, u8>>,
+    pending_command: Option<UserOperation>,
+}
+
+pub struct KVStoreDriver<'a> {
+    kv: &'a dyn kv_store::KVStore<'a>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a> KVStoreDriver<'a> {
+    pub fn new(
+        kv: &'a dyn kv_store::KVStore<'a>,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> KVStoreDriver<'a> {
+        KVStoreDriver {
+            kv,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    // Check to see if we are doing something. If not, go ahead and do this
+    // command. If so, this is queued and will be run when the pending command
+    // completes.
+    fn enqueue_command(&self, operation: UserOperation, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                if app.key.is_none()
+                    || (operation == UserOperation::Get && app.read_value.is_none())
+                    || (operation == UserOperation::Set && app.write_value.is_none())
+                {
+                    return ReturnCode::EINVAL;
+                }
+
+                if self.current_app.is_none() {
+                    let ret = self.start_command(operation, app, appid);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                    }
+                    ret
+                } else {
+                    // Queue this request for later.
+                    if app.pending_command.is_some() {
+                        ReturnCode::EBUSY
+                    } else {
+                        app.pending_command = Some(operation);
+                        ReturnCode::SUCCESS
+                    }
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn start_command(&self, operation: UserOperation, app: &mut App, appid: AppId) -> ReturnCode {
+        // The package name stays the same across reboots, so use it to
+        // identify the application's values where possible.
+        let name = appid.get_process_name();
+        let id = appid.id().to_le_bytes();
+        let namespace = if name.is_empty() {
+            &id[..]
+        } else {
+            name.as_bytes()
+        };
+        let permissions = StoragePermissions::for_app(namespace);
+
+        let key = match app.key.as_ref() {
+            Some(key) => key.as_ref(),
+            None => return ReturnCode::EINVAL,
+        };
+
+        match operation {
+            UserOperation::Get => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    match self.kv.get(permissions, key, buffer) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((buffer, e)) => {
+                            self.buffer.replace(buffer);
+                            e
+                        }
+                    }
+                })
+            }
+            UserOperation::Set => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let length = app.write_value.as_ref().map_or(0, |value| {
+                    let length = cmp::min(buffer.len(), value.len());
+                    buffer[..length].copy_from_slice(&value.as_ref()[..length]);
+                    length
+                });
+                if app.write_value.as_ref().map_or(0, |value| value.len()) > length {
+                    self.buffer.replace(buffer);
+                    return ReturnCode::ESIZE;
+                }
+
+                match self.kv.set(permissions, key, buffer, length) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((buffer, e)) => {
+                        self.buffer.replace(buffer);
+                        e
+                    }
+                }
+            }),
+            UserOperation::Delete => match self.kv.delete(permissions, key) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err(e) => e,
+            },
+        }
+    }
+
+    /// Notify the current application and start the next queued command.
+    fn complete_command(&self, result: Result<(), ReturnCode>, length: usize) {
+        let ret = match result {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(e) => e,
+        };
+
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback.map(|mut cb| {
+                    cb.schedule(usize::from(ret), length, 0);
+                });
+            });
+        });
+
+        // Check if there are any pending events.
+        for cntr in self.apps.iter() {
+            let started_command = cntr.enter(|app, _| {
+                let appid = app.appid();
+                app.pending_command.take().map_or(false, |operation| {
+                    let ret = self.start_command(operation, app, appid);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                        true
+                    } else {
+                        app.callback.map(|mut cb| {
+                            cb.schedule(usize::from(ret), 0, 0);
+                        });
+                        false
+                    }
+                })
+            });
+            if started_command {
+                break;
+            }
+        }
+    }
+}
+
+impl kv_store::StoreClient for KVStoreDriver<'_> {
+    fn get_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        value: &'static mut [u8],
+        length: usize,
+    ) {
+        // Copy as much of the value as fits into the application's buffer.
+        // If it does not fit the application is told the full length.
+        let mut result = result;
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.read_value.as_mut().map(|read_value| {
+                    let copy = cmp::min(cmp::min(length, value.len()), read_value.len());
+                    read_value.as_mut()[..copy].copy_from_slice(&value[..copy]);
+                    if result.is_ok() && length > read_value.len() {
+                        result = Err(ReturnCode::ESIZE);
+                    }
+                });
+            });
+        });
+        self.buffer.replace(value);
+        self.complete_command(result, length);
+    }
+
+    fn set_complete(&self, result: Result<(), ReturnCode>, value: &'static mut [u8]) {
+        self.buffer.replace(value);
+        self.complete_command(result, 0);
+    }
+
+    fn delete_complete(&self, result: Result<(), ReturnCode>) {
+        self.complete_command(result, 0);
+    }
+}
+
+impl Driver for KVStoreDriver<'_> {
+    /// Setup buffers for keys and values.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Set the key buffer. The whole buffer is used as the key.
+    /// - `1`: Set the buffer holding the value to store with `set`.
+    /// - `2`: Set the buffer that `get` writes the value into.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.key = slice,
+                    1 => app.write_value = slice,
+                    2 => app.read_value = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Set the callback for completed operations. The callback receives
+    ///        the `ReturnCode` of the operation and, for `get`, the length of
+    ///        the stored value.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Key-value store operations.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the value for the key into the `allow` 2 buffer.
+    /// - `2`: Set the key to the contents of the `allow` 1 buffer.
+    /// - `3`: Delete the key.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 =>
+            /* This driver exists. */
+            {
+                ReturnCode::SUCCESS
+            }
+
+            1 => self.enqueue_command(UserOperation::Get, appid),
+            2 => self.enqueue_command(UserOperation::Set, appid),
+            3 => self.enqueue_command(UserOperation::Delete, appid),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,1068 @@
+//! Authenticated encryption of values stored in a KV system.
+//!
+//! `EncryptedKVSystem` sits between `capsules::kv_store` (or any other
+//! `hil::kv_system` user) and the KV system that writes to flash, usually
+//! `capsules::tickv`. Every value is sealed with AES-128-CCM through the
+//! `AES128CCM` HIL before it is appended or staged in a transaction, and is
+//! verified and decrypted when it is read back, so values can not be read
+//! without the key, and modified values fail to verify.
+//!
+//! +-----------------------+
+//! |                       |
+//! |  K-V in Tock          |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_system
+//!
+//! +-----------------------+
+//! |                       |
+//! |  Encryption (here)    |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_system
+//!
+//! +-----------------------+
+//! |                       |
+//! |  TicKV                |
+//! |                       |
+//! +-----------------------+
+//!
+//! Each value is stored as
+//!
+//! ```text
+//! 0         1              3                   7             7 + K
+//! +---------+--------------+-------------------+-------------+------------+-----+
+//! | version | length (LE)  | counter (LE)      | hashed key  | ciphertext | MIC |
+//! +---------+--------------+-------------------+-------------+------------+-----+
+//! ```
+//!
+//! where everything before the ciphertext is authenticated as associated
+//! data. The hashed key is replaced by the key that was asked for before a
+//! value is verified, so a value moved to another key fails to verify.
+//!
+//! Keys must come from `generate_key()`, which clears the top bit of the
+//! first byte of the hashed key. Keys with that bit set are used by the
+//! freshness records described below, and are skipped by `next_key()`.
+//!
+//! Rollback protection
+//! -------------------
+//!
+//! Every append uses a new value of a 32-bit write counter, which forms part
+//! of the CCM nonce and of the associated data. The counter is persisted in
+//! two alternating counter records stored in the same KV system, each of
+//! which reserves the next `COUNTER_RESERVATION` values so the records only
+//! need to be rewritten occasionally. Values claiming a counter beyond the
+//! reservation are rejected. The counter records are skipped by `next_key()`
+//! but are included in `storage_stats()`.
+//!
+//! The counter records are themselves in flash, so an attacker that can
+//! rewrite the flash could restore old records along with old values, or
+//! remove the records so that the counter restarts and nonces are reused.
+//! Boards should therefore provide a `RollbackCounter` kept outside of the KV
+//! flash region, for example in OTP or a secure element. When one is set, the
+//! store refuses to start if the records are older than that counter.
+//!
+//! Every value also has a freshness record, stored under the same key with
+//! the top bit set, which holds the counter of the newest value written for
+//! the key. A value older than its freshness record fails to verify, so an
+//! older copy of a single value cannot be restored in place of the current
+//! one. This does not cover restoring a value together with its freshness
+//! record, or removing the freshness record: the record may be missing after
+//! a reset between writing a value and its record, so values without one are
+//! accepted. Like the counter records, freshness records are included in
+//! `storage_stats()`, and each value takes two objects in the KV system.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let crypt_buf = static_init!([u8; 280], [0; 280]);
+//! let counter_key = static_init!(capsules::tickv::TicKVKeyType, [0; 8]);
+//! let encrypted = static_init!(
+//!     capsules::kv_encryption::EncryptedKVSystem<'static, TicKV, AESCCM>,
+//!     capsules::kv_encryption::EncryptedKVSystem::new(
+//!         tickv,
+//!         ccm_client,
+//!         &BOARD_STORAGE_KEY,
+//!         crypt_buf,
+//!         counter_key,
+//!     )
+//! );
+//! tickv.set_client(encrypted);
+//! ccm_client.set_client(encrypted);
+//! encrypted.set_rollback_counter(otp_counter);
+//! encrypted.initialise();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::kv_system::{self, KVSystem, StorageStats};
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, CCM_NONCE_LENGTH};
+use kernel::ReturnCode;
+
+/// Length of the CCM message integrity code stored with each value.
+pub const MIC_LENGTH: usize = 8;
+
+/// Number of counter values reserved each time a counter record is written.
+pub const COUNTER_RESERVATION: u32 = 16;
+
+const VERSION: u8 = 1;
+
+/// Length of the version, length and counter fields.
+const FIXED_HEADER_LENGTH: usize = 7;
+
+/// Hashed key used for the counter records. The last byte selects the slot.
+const COUNTER_RECORD_KEY: [u8; 8] = *b"kv-aead\0";
+
+/// Set in the first byte of a key to get the key of its freshness record.
+const FRESHNESS_BIT: u8 = 0x80;
+
+/// A counter that is not stored with the rest of the KV data and so cannot
+/// be rolled back along with it.
+pub trait RollbackCounter {
+    /// Returns the current value of the counter.
+    fn get(&self) -> u32;
+
+    /// Increases the counter to `value`. The counter must never decrease.
+    fn advance(&self, value: u32) -> ReturnCode;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Uninitialised,
+    /// Reading the counter record in a slot.
+    InitRead(usize),
+    /// Verifying the counter record in a slot.
+    InitDecrypt(usize),
+    Ready,
+    /// The counter records were rolled back, all operations fail.
+    Failed,
+    /// Removing the stale counter record before writing a new one.
+    ReserveInvalidate,
+    ReserveEncrypt,
+    ReserveAppend,
+    AppendEncrypt,
+    Append,
+    /// Removing the freshness record of the value just written.
+    FreshInvalidate,
+    FreshEncrypt,
+    FreshAppend,
+    Get,
+    GetDecrypt,
+    /// Reading the freshness record of the value just verified.
+    GetFresh,
+    GetFreshDecrypt,
+    Invalidate,
+    InvalidateFresh,
+}
+
+/// The CCM nonce for a value written with `counter` under `key`.
+fn nonce(counter: u32, key: &[u8]) -> [u8; CCM_NONCE_LENGTH] {
+    let mut nonce = [0; CCM_NONCE_LENGTH];
+    nonce[0..4].copy_from_slice(&counter.to_le_bytes());
+    for (n, k) in nonce[4..12].iter_mut().zip(key.iter()) {
+        *n = *k;
+    }
+    nonce
+}
+
+pub struct EncryptedKVSystem<'a, S: KVSystem<'a>, A: AES128CCM<'a>> {
+    kv: &'a S,
+    ccm: &'a A,
+    secret: &'a [u8],
+    client: OptionalCell<&'a dyn kv_system::Client<S::K>>,
+    rollback_counter: OptionalCell<&'a dyn RollbackCounter>,
+
+    state: Cell<State>,
+    /// The last counter value used to seal a value.
+    counter: Cell<u32>,
+    /// The highest counter value covered by the newest counter record.
+    reserved: Cell<u32>,
+    /// The slot holding the newest counter record.
+    active_slot: Cell<usize>,
+
+    crypt_buf: TakeCell<'static, [u8]>,
+    counter_key: TakeCell<'static, S::K>,
+    /// The client's key for the current operation.
+    key: TakeCell<'static, S::K>,
+    /// The client's value or return buffer for the current operation.
+    value: TakeCell<'static, [u8]>,
+    value_length: Cell<usize>,
+    /// The counter of the value being read.
+    value_counter: Cell<u32>,
+    /// The client's value is staged in a transaction rather than appended.
+    staging: Cell<bool>,
+}
+
+impl<'a, S: KVSystem<'a>, A: AES128CCM<'a>> EncryptedKVSystem<'a, S, A> {
+    /// Create a new encryption layer over `kv`.
+    ///
+    /// `secret`: The AES-128 key used to seal values.
+    /// `crypt_buf`: Holds a sealed value. This limits the largest value that
+    ///              can be stored, see `max_value_length()`.
+    /// `counter_key`: A key buffer used for the counter records.
+    pub fn new(
+        kv: &'a S,
+        ccm: &'a A,
+        secret: &'a [u8],
+        crypt_buf: &'static mut [u8],
+        counter_key: &'static mut S::K,
+    ) -> EncryptedKVSystem<'a, S, A> {
+        EncryptedKVSystem {
+            kv,
+            ccm,
+            secret,
+            client: OptionalCell::empty(),
+            rollback_counter: OptionalCell::empty(),
+            state: Cell::new(State::Uninitialised),
+            counter: Cell::new(0),
+            reserved: Cell::new(0),
+            active_slot: Cell::new(0),
+            crypt_buf: TakeCell::new(crypt_buf),
+            counter_key: TakeCell::new(counter_key),
+            key: TakeCell::empty(),
+            value: TakeCell::empty(),
+            value_length: Cell::new(0),
+            value_counter: Cell::new(0),
+            staging: Cell::new(false),
+        }
+    }
+
+    /// Set the counter used to detect rollback of the counter records.
+    pub fn set_rollback_counter(&self, rollback_counter: &'a dyn RollbackCounter) {
+        self.rollback_counter.set(rollback_counter);
+    }
+
+    /// Read the counter records. Operations return `EBUSY` until this has
+    /// completed.
+    pub fn initialise(&self) {
+        if self.state.get() == State::Uninitialised {
+            self.read_record(0);
+        }
+    }
+
+    /// Whether a rollback of the counter records was detected. If so, every
+    /// operation fails with `FAIL`.
+    pub fn rollback_detected(&self) -> bool {
+        self.state.get() == State::Failed
+    }
+
+    /// The largest value that can be stored, limited by `crypt_buf`.
+    pub fn max_value_length(&self) -> usize {
+        let overhead = self.header_length() + MIC_LENGTH;
+        self.crypt_buf
+            .map_or(0, |buf| buf.len().saturating_sub(overhead))
+    }
+
+    fn header_length(&self) -> usize {
+        self.counter_key
+            .map_or(FIXED_HEADER_LENGTH + COUNTER_RECORD_KEY.len(), |key| {
+                FIXED_HEADER_LENGTH + key.as_ref().len()
+            })
+    }
+
+    fn check_ready(&self) -> Result<(), ReturnCode> {
+        match self.state.get() {
+            State::Ready => Ok(()),
+            State::Failed => Err(ReturnCode::FAIL),
+            _ => Err(ReturnCode::EBUSY),
+        }
+    }
+
+    /// Point the counter record key at `slot`.
+    fn select_slot(key: &mut S::K, slot: usize) {
+        for (k, p) in key
+            .as_mut()
+            .iter_mut()
+            .zip(COUNTER_RECORD_KEY.iter().cycle())
+        {
+            *k = *p;
+        }
+        let last = key.as_ref().len() - 1;
+        key.as_mut()[last] = slot as u8;
+    }
+
+    /// Whether `key` is one of the counter records, which are hidden from
+    /// `next_key()`.
+    fn is_counter_record(key: &S::K) -> bool {
+        let key = key.as_ref();
+        let last = key.len() - 1;
+        key[last] < 2
+            && key[..last]
+                .iter()
+                .zip(COUNTER_RECORD_KEY.iter().cycle())
+                .all(|(k, p)| k == p)
+    }
+
+    /// Point `key` at the freshness record of the value stored under
+    /// `value_key`.
+    fn select_freshness_record(key: &mut S::K, value_key: &S::K) {
+        key.as_mut().copy_from_slice(value_key.as_ref());
+        key.as_mut()[0] |= FRESHNESS_BIT;
+    }
+
+    fn is_freshness_record(key: &S::K) -> bool {
+        key.as_ref()[0] & FRESHNESS_BIT != 0
+    }
+
+    /// Seal the `length` bytes of plaintext already in `buf` after the header.
+    fn seal(
+        &self,
+        buf: &'static mut [u8],
+        counter: u32,
+        key: &[u8],
+        length: usize,
+    ) -> Result<(), &'static mut [u8]> {
+        let m_off = FIXED_HEADER_LENGTH + key.len();
+        buf[0] = VERSION;
+        buf[1..3].copy_from_slice(&(length as u16).to_le_bytes());
+        buf[3..7].copy_from_slice(&counter.to_le_bytes());
+        buf[7..m_off].copy_from_slice(key);
+
+        self.crypt(buf, counter, key, m_off, length, true)
+    }
+
+    /// Start verifying and decrypting the sealed value in `buf`, which was
+    /// read for `key`.
+    fn open(&self, buf: &'static mut [u8], key: &[u8]) -> Result<(), &'static mut [u8]> {
+        let m_off = FIXED_HEADER_LENGTH + key.len();
+        if buf.len() < m_off + MIC_LENGTH || buf[0] != VERSION {
+            return Err(buf);
+        }
+        let length = u16::from_le_bytes([buf[1], buf[2]]) as usize;
+        let counter = u32::from_le_bytes([buf[3], buf[4], buf[5], buf[6]]);
+        if m_off + length + MIC_LENGTH > buf.len() {
+            return Err(buf);
+        }
+        buf[7..m_off].copy_from_slice(key);
+
+        self.crypt(buf, counter, key, m_off, length, false)
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        counter: u32,
+        key: &[u8],
+        m_off: usize,
+        length: usize,
+        encrypting: bool,
+    ) -> Result<(), &'static mut [u8]> {
+        if self.ccm.set_key(self.secret) != ReturnCode::SUCCESS
+            || self.ccm.set_nonce(&nonce(counter, key)) != ReturnCode::SUCCESS
+        {
+            return Err(buf);
+        }
+
+        match self
+            .ccm
+            .crypt(buf, 0, m_off, length, MIC_LENGTH, true, encrypting)
+        {
+            (ReturnCode::SUCCESS, _) => Ok(()),
+            (_, Some(buf)) => Err(buf),
+            (_, None) => Ok(()),
+        }
+    }
+
+    fn read_record(&self, slot: usize) {
+        self.state.set(State::InitRead(slot));
+        match (self.counter_key.take(), self.crypt_buf.take()) {
+            (Some(key), Some(buf)) => {
+                Self::select_slot(key, slot);
+                if let Err((key, buf, e)) = self.kv.get_value(key, buf) {
+                    self.record_read(slot, Err(e), key, buf);
+                }
+            }
+            (key, buf) => {
+                key.map(|key| self.counter_key.replace(key));
+                buf.map(|buf| self.crypt_buf.replace(buf));
+                self.record_done(slot, None);
+            }
+        }
+    }
+
+    fn record_read(
+        &self,
+        slot: usize,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        buf: &'static mut [u8],
+    ) {
+        let key_bytes = *key;
+        self.counter_key.replace(key);
+
+        if result.is_err() {
+            self.crypt_buf.replace(buf);
+            self.record_done(slot, None);
+            return;
+        }
+
+        self.state.set(State::InitDecrypt(slot));
+        if let Err(buf) = self.open(buf, key_bytes.as_ref()) {
+            self.crypt_buf.replace(buf);
+            self.record_done(slot, None);
+        }
+    }
+
+    /// Record the reservation found in `slot`, then read the next slot or
+    /// finish initialisation.
+    fn record_done(&self, slot: usize, reserved: Option<u32>) {
+        if let Some(reserved) = reserved {
+            if reserved >= self.reserved.get() {
+                self.reserved.set(reserved);
+                self.active_slot.set(slot);
+            }
+        }
+
+        if slot == 0 {
+            self.read_record(1);
+            return;
+        }
+
+        let reserved = self.reserved.get();
+        if self
+            .rollback_counter
+            .map_or(false, |rollback_counter| rollback_counter.get() > reserved)
+        {
+            self.state.set(State::Failed);
+        } else {
+            // Skip any values reserved but not used before the last reset.
+            self.counter.set(reserved);
+            self.state.set(State::Ready);
+        }
+    }
+
+    /// Write a counter record reserving more counter values, then seal the
+    /// pending value.
+    fn reserve(&self) {
+        self.state.set(State::ReserveInvalidate);
+        match self.counter_key.take() {
+            Some(key) => {
+                Self::select_slot(key, 1 - self.active_slot.get());
+                if let Err((key, _)) = self.kv.invalidate_key(key) {
+                    // There is no stale record to remove yet.
+                    self.reserve_encrypt(key);
+                }
+            }
+            None => self.complete_append(Err(ReturnCode::FAIL)),
+        }
+    }
+
+    fn reserve_encrypt(&self, key: &'static mut S::K) {
+        let key_bytes = *key;
+        self.counter_key.replace(key);
+        self.state.set(State::ReserveEncrypt);
+
+        let reserved = self.counter.get() + COUNTER_RESERVATION;
+        match self.crypt_buf.take() {
+            Some(buf) => {
+                let m_off = FIXED_HEADER_LENGTH + key_bytes.as_ref().len();
+                buf[m_off..m_off + 4].copy_from_slice(&reserved.to_le_bytes());
+                if let Err(buf) = self.seal(buf, reserved, key_bytes.as_ref(), 4) {
+                    self.crypt_buf.replace(buf);
+                    self.complete_append(Err(ReturnCode::FAIL));
+                }
+            }
+            None => self.complete_append(Err(ReturnCode::FAIL)),
+        }
+    }
+
+    /// Seal the client's value with the next counter value.
+    fn encrypt_value(&self) {
+        self.state.set(State::AppendEncrypt);
+        let counter = self.counter.get() + 1;
+        self.counter.set(counter);
+
+        let length = self.value_length.get();
+        let key_bytes = match self.key.map(|key| *key) {
+            Some(key) => key,
+            None => return self.complete_append(Err(ReturnCode::FAIL)),
+        };
+        match self.crypt_buf.take() {
+            Some(buf) => {
+                let m_off = FIXED_HEADER_LENGTH + key_bytes.as_ref().len();
+                self.value.map(|value| {
+                    buf[m_off..m_off + length].copy_from_slice(&value[..length]);
+                });
+                if let Err(buf) = self.seal(buf, counter, key_bytes.as_ref(), length) {
+                    self.crypt_buf.replace(buf);
+                    self.complete_append(Err(ReturnCode::FAIL));
+                }
+            }
+            None => self.complete_append(Err(ReturnCode::FAIL)),
+        }
+    }
+
+    /// Seal `value` and append or stage it, reserving more counter values
+    /// first if needed.
+    fn start_append(
+        &self,
+        key: &'static mut S::K,
+        value: &'static mut [u8],
+        length: usize,
+        staging: bool,
+    ) -> Result<(), (&'static mut S::K, &'static mut [u8], ReturnCode)> {
+        if let Err(e) = self.check_ready() {
+            return Err((key, value, e));
+        }
+        if length > value.len() {
+            return Err((key, value, ReturnCode::EINVAL));
+        }
+        if length > self.max_value_length() {
+            return Err((key, value, ReturnCode::ESIZE));
+        }
+
+        self.key.replace(key);
+        self.value.replace(value);
+        self.value_length.set(length);
+        self.staging.set(staging);
+
+        if self.counter.get() >= self.reserved.get() {
+            self.reserve();
+        } else {
+            self.encrypt_value();
+        }
+        Ok(())
+    }
+
+    /// Record the counter of the value just written in its freshness
+    /// record, replacing the old record.
+    fn update_freshness(&self) {
+        let key = match (self.counter_key.take(), self.key.map(|key| *key)) {
+            (Some(key), Some(value_key)) => {
+                Self::select_freshness_record(key, &value_key);
+                key
+            }
+            (key, _) => {
+                key.map(|key| self.counter_key.replace(key));
+                return self.complete_append(Err(ReturnCode::FAIL));
+            }
+        };
+        if self.staging.get() {
+            // Committing the transaction replaces the old record.
+            return self.freshness_encrypt(key);
+        }
+        self.state.set(State::FreshInvalidate);
+        if let Err((key, _)) = self.kv.invalidate_key(key) {
+            // The key has no freshness record yet.
+            self.freshness_encrypt(key);
+        }
+    }
+
+    fn freshness_encrypt(&self, key: &'static mut S::K) {
+        let key_bytes = *key;
+        self.counter_key.replace(key);
+        self.state.set(State::FreshEncrypt);
+
+        match self.crypt_buf.take() {
+            Some(buf) => {
+                if let Err(buf) = self.seal(buf, self.counter.get(), key_bytes.as_ref(), 0) {
+                    self.crypt_buf.replace(buf);
+                    self.complete_append(Err(ReturnCode::FAIL));
+                }
+            }
+            None => self.complete_append(Err(ReturnCode::FAIL)),
+        }
+    }
+
+    /// Called once the client's value is appended or staged.
+    fn value_written(&self, result: Result<(), ReturnCode>, key: &'static mut S::K) {
+        self.key.replace(key);
+        match result {
+            Ok(()) => self.update_freshness(),
+            Err(e) => self.complete_append(Err(e)),
+        }
+    }
+
+    fn complete_append(&self, result: Result<(), ReturnCode>) {
+        self.state.set(State::Ready);
+        let staging = self.staging.get();
+        self.key.take().map(|key| {
+            self.value.take().map(|value| {
+                self.client.map(move |cb| {
+                    if staging {
+                        cb.stage_key_complete(result, key, value)
+                    } else {
+                        cb.append_key_complete(result, key, value)
+                    }
+                });
+            });
+        });
+    }
+
+    /// Read the freshness record of the value just verified.
+    fn read_freshness(&self) {
+        self.state.set(State::GetFresh);
+        match (
+            self.counter_key.take(),
+            self.crypt_buf.take(),
+            self.key.map(|key| *key),
+        ) {
+            (Some(key), Some(buf), Some(value_key)) => {
+                Self::select_freshness_record(key, &value_key);
+                if let Err((key, buf, e)) = self.kv.get_value(key, buf) {
+                    self.freshness_read(Err(e), key, buf);
+                }
+            }
+            (key, buf, _) => {
+                key.map(|key| self.counter_key.replace(key));
+                buf.map(|buf| self.crypt_buf.replace(buf));
+                self.complete_get(Err(ReturnCode::FAIL));
+            }
+        }
+    }
+
+    fn freshness_read(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        buf: &'static mut [u8],
+    ) {
+        let key_bytes = *key;
+        self.counter_key.replace(key);
+
+        if result.is_err() {
+            // Values written before a reset interrupted writing their
+            // record have none.
+            self.crypt_buf.replace(buf);
+            self.complete_get(Ok(()));
+            return;
+        }
+
+        self.state.set(State::GetFreshDecrypt);
+        if let Err(buf) = self.open(buf, key_bytes.as_ref()) {
+            self.crypt_buf.replace(buf);
+            self.complete_get(Err(ReturnCode::FAIL));
+        }
+    }
+
+    fn complete_invalidate(&self, result: Result<(), ReturnCode>) {
+        self.state.set(State::Ready);
+        self.key.take().map(|key| {
+            self.client
+                .map(move |cb| cb.invalidate_key_complete(result, key));
+        });
+    }
+
+    fn complete_get(&self, result: Result<(), ReturnCode>) {
+        self.state.set(State::Ready);
+        if result.is_err() {
+            // Do not hand out a value that failed to verify.
+            self.value.map(|ret_buf| {
+                for b in ret_buf.iter_mut() {
+                    *b = 0;
+                }
+            });
+        }
+        self.key.take().map(|key| {
+            self.value.take().map(|ret_buf| {
+                self.client
+                    .map(move |cb| cb.get_value_complete(result, key, ret_buf));
+            });
+        });
+    }
+}
+
+impl<'a, S: KVSystem<'a>, A: AES128CCM<'a>> KVSystem<'a> for EncryptedKVSystem<'a, S, A> {
+    type K = S::K;
+
+    fn set_client(&self, client: &'a dyn kv_system::Client<Self::K>) {
+        self.client.set(client);
+    }
+
+    fn generate_key(
+        &self,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut Self::K,
+    ) -> Result<(), (&'static mut [u8], &'static mut Self::K, ReturnCode)> {
+        self.kv.generate_key(unhashed_key, key_buf)
+    }
+
+    fn append_key(
+        &self,
+        key: &'static mut Self::K,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Self::K, &'static mut [u8], ReturnCode)> {
+        self.start_append(key, value, length, false)
+    }
+
+    fn get_value(
+        &self,
+        key: &'static mut Self::K,
+        ret_buf: &'static mut [u8],
+    ) -> Result<(), (&'static mut Self::K, &'static mut [u8], ReturnCode)> {
+        if let Err(e) = self.check_ready() {
+            return Err((key, ret_buf, e));
+        }
+        let buf = match self.crypt_buf.take() {
+            Some(buf) => buf,
+            None => return Err((key, ret_buf, ReturnCode::EBUSY)),
+        };
+
+        self.state.set(State::Get);
+        match self.kv.get_value(key, buf) {
+            Ok(()) => {
+                self.value.replace(ret_buf);
+                Ok(())
+            }
+            Err((key, buf, e)) => {
+                self.state.set(State::Ready);
+                self.crypt_buf.replace(buf);
+                Err((key, ret_buf, e))
+            }
+        }
+    }
+
+    fn invalidate_key(
+        &self,
+        key: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, ReturnCode)> {
+        if let Err(e) = self.check_ready() {
+            return Err((key, e));
+        }
+        self.state.set(State::Invalidate);
+        self.kv.invalidate_key(key).map_err(|(key, e)| {
+            self.state.set(State::Ready);
+            (key, e)
+        })
+    }
+
+    fn garbage_collect(&self) -> Result<usize, ReturnCode> {
+        self.check_ready()?;
+        self.kv.garbage_collect()
+    }
+
+    fn next_key(
+        &self,
+        cursor: usize,
+        key_buf: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, ReturnCode)> {
+        if let Err(e) = self.check_ready() {
+            return Err((key_buf, e));
+        }
+        self.kv.next_key(cursor, key_buf)
+    }
+
+    fn storage_stats(&self) -> Result<(), ReturnCode> {
+        self.check_ready()?;
+        self.kv.storage_stats()
+    }
+
+    fn check_consistency(&self, repair: bool) -> Result<(), ReturnCode> {
+        self.check_ready()?;
+        self.kv.check_consistency(repair)
+    }
+
+    fn begin_transaction(&self) -> Result<(), ReturnCode> {
+        self.check_ready()?;
+        self.kv.begin_transaction()
+    }
+
+    fn stage_key(
+        &self,
+        key: &'static mut Self::K,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Self::K, &'static mut [u8], ReturnCode)> {
+        self.start_append(key, value, length, true)
+    }
+
+    fn commit_transaction(&self) -> Result<(), ReturnCode> {
+        self.check_ready()?;
+        self.kv.commit_transaction()
+    }
+
+    fn abort_transaction(&self) -> Result<(), ReturnCode> {
+        self.check_ready()?;
+        self.kv.abort_transaction()
+    }
+}
+
+impl<'a, S: KVSystem<'a>, A: AES128CCM<'a>> kv_system::Client<S::K>
+    for EncryptedKVSystem<'a, S, A>
+{
+    fn generate_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut S::K,
+    ) {
+        key_buf.as_mut()[0] &= !FRESHNESS_BIT;
+        self.client
+            .map(move |cb| cb.generate_key_complete(result, unhashed_key, key_buf));
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        value: &'static mut [u8],
+    ) {
+        self.crypt_buf.replace(value);
+
+        match self.state.get() {
+            State::ReserveAppend => {
+                self.counter_key.replace(key);
+                match result {
+                    Ok(()) => {
+                        let reserved = self.counter.get() + COUNTER_RESERVATION;
+                        self.reserved.set(reserved);
+                        self.active_slot.set(1 - self.active_slot.get());
+                        self.rollback_counter
+                            .map(|rollback_counter| rollback_counter.advance(reserved));
+                        self.encrypt_value();
+                    }
+                    Err(e) => self.complete_append(Err(e)),
+                }
+            }
+            State::Append => self.value_written(result, key),
+            State::FreshAppend => {
+                self.counter_key.replace(key);
+                self.complete_append(result);
+            }
+            _ => {}
+        }
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        ret_buf: &'static mut [u8],
+    ) {
+        match self.state.get() {
+            State::InitRead(slot) => self.record_read(slot, result, key, ret_buf),
+            State::GetFresh => self.freshness_read(result, key, ret_buf),
+            State::Get => {
+                let key_bytes = *key;
+                self.key.replace(key);
+                if let Err(e) = result {
+                    self.crypt_buf.replace(ret_buf);
+                    self.complete_get(Err(e));
+                    return;
+                }
+
+                self.state.set(State::GetDecrypt);
+                if let Err(buf) = self.open(ret_buf, key_bytes.as_ref()) {
+                    self.crypt_buf.replace(buf);
+                    self.complete_get(Err(ReturnCode::FAIL));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ReturnCode>, key: &'static mut S::K) {
+        match self.state.get() {
+            // A missing stale record is expected the first time a slot is
+            // used, or a key is written.
+            State::ReserveInvalidate => self.reserve_encrypt(key),
+            State::FreshInvalidate => self.freshness_encrypt(key),
+            State::Invalidate => {
+                self.key.replace(key);
+                if result.is_err() {
+                    return self.complete_invalidate(result);
+                }
+                // Also remove the freshness record of the value.
+                self.state.set(State::InvalidateFresh);
+                match (self.counter_key.take(), self.key.map(|key| *key)) {
+                    (Some(fresh_key), Some(value_key)) => {
+                        Self::select_freshness_record(fresh_key, &value_key);
+                        if let Err((fresh_key, _)) = self.kv.invalidate_key(fresh_key) {
+                            self.counter_key.replace(fresh_key);
+                            self.complete_invalidate(result);
+                        }
+                    }
+                    (fresh_key, _) => {
+                        fresh_key.map(|fresh_key| self.counter_key.replace(fresh_key));
+                        self.complete_invalidate(result);
+                    }
+                }
+            }
+            State::InvalidateFresh => {
+                self.counter_key.replace(key);
+                self.complete_invalidate(Ok(()));
+            }
+            _ => {
+                self.client
+                    .map(move |cb| cb.invalidate_key_complete(result, key));
+            }
+        }
+    }
+
+    fn garbage_collect_complete(&self, result: Result<(), ReturnCode>) {
+        self.client.map(|cb| cb.garbage_collect_complete(result));
+    }
+
+    fn next_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key_buf: &'static mut S::K,
+        cursor: usize,
+    ) {
+        if result.is_ok()
+            && (Self::is_counter_record(key_buf) || Self::is_freshness_record(key_buf))
+        {
+            // Skip over the counter and freshness records, they aren't
+            // client values.
+            if let Err((key_buf, e)) = self.kv.next_key(cursor, key_buf) {
+                self.client
+                    .map(move |cb| cb.next_key_complete(Err(e), key_buf, 0));
+            }
+            return;
+        }
+        self.client
+            .map(move |cb| cb.next_key_complete(result, key_buf, cursor));
+    }
+
+    fn storage_stats_complete(&self, result: Result<(), ReturnCode>, stats: StorageStats) {
+        self.client
+            .map(|cb| cb.storage_stats_complete(result, stats));
+    }
+
+    fn check_consistency_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        found: usize,
+        repaired: usize,
+    ) {
+        self.client
+            .map(|cb| cb.check_consistency_complete(result, found, repaired));
+    }
+
+    fn begin_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.client.map(|cb| cb.begin_transaction_complete(result));
+    }
+
+    fn stage_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        value: &'static mut [u8],
+    ) {
+        self.crypt_buf.replace(value);
+        match self.state.get() {
+            State::Append => self.value_written(result, key),
+            State::FreshAppend => {
+                self.counter_key.replace(key);
+                self.complete_append(result);
+            }
+            _ => {}
+        }
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.client.map(|cb| cb.commit_transaction_complete(result));
+    }
+
+    fn abort_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.client.map(|cb| cb.abort_transaction_complete(result));
+    }
+}
+
+impl<'a, S: KVSystem<'a>, A: AES128CCM<'a>> CCMClient for EncryptedKVSystem<'a, S, A> {
+    fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        let valid = res == ReturnCode::SUCCESS && tag_is_valid;
+        let length = u16::from_le_bytes([buf[1], buf[2]]) as usize;
+        let counter = u32::from_le_bytes([buf[3], buf[4], buf[5], buf[6]]);
+        let m_off = self.header_length();
+
+        match self.state.get() {
+            State::InitDecrypt(slot) => {
+                let reserved = if valid && length == 4 {
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&buf[m_off..m_off + 4]);
+                    Some(u32::from_le_bytes(bytes))
+                } else {
+                    None
+                };
+                self.crypt_buf.replace(buf);
+                self.record_done(slot, reserved);
+            }
+            State::ReserveEncrypt | State::AppendEncrypt | State::FreshEncrypt => {
+                if !valid {
+                    self.crypt_buf.replace(buf);
+                    self.complete_append(Err(ReturnCode::FAIL));
+                    return;
+                }
+
+                let total = m_off + length + MIC_LENGTH;
+                let (key, staging) = match self.state.get() {
+                    State::ReserveEncrypt => {
+                        self.state.set(State::ReserveAppend);
+                        (self.counter_key.take(), false)
+                    }
+                    State::AppendEncrypt => {
+                        self.state.set(State::Append);
+                        (self.key.take(), self.staging.get())
+                    }
+                    _ => {
+                        self.state.set(State::FreshAppend);
+                        (self.counter_key.take(), self.staging.get())
+                    }
+                };
+                match key {
+                    Some(key) if staging => {
+                        if let Err((key, buf, e)) = self.kv.stage_key(key, buf, total) {
+                            kv_system::Client::stage_key_complete(self, Err(e), key, buf);
+                        }
+                    }
+                    Some(key) => {
+                        if let Err((key, buf, e)) = self.kv.append_key(key, buf, total) {
+                            kv_system::Client::append_key_complete(self, Err(e), key, buf);
+                        }
+                    }
+                    None => {
+                        self.crypt_buf.replace(buf);
+                        self.complete_append(Err(ReturnCode::FAIL));
+                    }
+                }
+            }
+            State::GetDecrypt => {
+                // A value sealed with a counter that was never reserved must
+                // come from a rolled back store.
+                let result = if !valid || counter > self.reserved.get() {
+                    Err(ReturnCode::FAIL)
+                } else {
+                    self.value.map_or(Err(ReturnCode::FAIL), |ret_buf| {
+                        if length > ret_buf.len() {
+                            Err(ReturnCode::ESIZE)
+                        } else {
+                            ret_buf[..length].copy_from_slice(&buf[m_off..m_off + length]);
+                            Ok(())
+                        }
+                    })
+                };
+                // Do not leave plaintext behind in the shared buffer.
+                for b in buf[m_off..m_off + length].iter_mut() {
+                    *b = 0;
+                }
+                self.crypt_buf.replace(buf);
+                match result {
+                    Ok(()) => {
+                        self.value_counter.set(counter);
+                        self.read_freshness();
+                    }
+                    Err(e) => self.complete_get(Err(e)),
+                }
+            }
+            State::GetFreshDecrypt => {
+                self.crypt_buf.replace(buf);
+                // An older value was put back in place of the newest one.
+                if !valid || counter > self.value_counter.get() {
+                    self.complete_get(Err(ReturnCode::FAIL));
+                } else {
+                    self.complete_get(Ok(()));
+                }
+            }
+            _ => {
+                self.crypt_buf.replace(buf);
+            }
+        }
+    }
+}
@@ -0,0 +1,576 @@
+//! Key-value store with per-caller namespaces and ownership checks.
+//!
+//! This capsule implements `hil::kv_store` on top of any `hil::kv_system`
+//! implementation, such as `capsules::tickv`.
+//!
+//! +-----------------------+
+//! |                       |
+//! |  Capsule using K-V    |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_store
+//!
+//! +-----------------------+
+//! |                       |
+//! |  K-V (this file)      |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_system
+//!
+//! +-----------------------+
+//! |                       |
+//! |  TicKV                |
+//! |                       |
+//! +-----------------------+
+//!
+//! Keys are prefixed with the caller's namespace before being hashed by the
+//! KV system, so the same key in two namespaces refers to two different
+//! values. The unhashed key is laid out as
+//!
+//! ```text
+//! [namespace length][namespace][key length][key][zero padding]
+//! ```
+//!
+//! and padded to the size of the `unhashed_key` buffer, which therefore
+//! limits the combined size of namespace and key.
+//!
+//! Every value is stored with an 8 byte header:
+//!
+//! ```text
+//! 0         1       2              4                        8
+//! +---------+-------+--------------+------------------------+----------
+//! | version | flags | length (LE)  | owner (LE)             | value ...
+//! +---------+-------+--------------+------------------------+----------
+//! ```
+//!
+//! `owner` is a hash of the namespace that wrote the value, which lets the
+//! store reject values that belong to another namespace if two keys ever hash
+//! to the same value. The `FLAG_PRIVILEGED` flag is set when the value was
+//! written by a caller holding `KVStorePrivilegedCapability`, and such values
+//! can then only be modified or deleted by privileged callers.
+//!
+//! Replacing a value stages the new value in a KV system transaction and
+//! commits it, so if writing the new value fails (for example because the
+//! store is full) the old value is kept.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let unhashed_key = static_init!([u8; 64], [0; 64]);
+//! let key = static_init!(capsules::tickv::TicKVKeyType, [0; 8]);
+//! let data = static_init!([u8; 264], [0; 264]);
+//!
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KVStore<'static, TicKVStore<'static, FlashUser<'static, F>>>,
+//!     capsules::kv_store::KVStore::new(tickv, unhashed_key, key, data)
+//! );
+//! tickv.set_client(kv_store);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::kv_store::{self, StoragePermissions, StoreClient};
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::ReturnCode;
+
+/// The length of the header stored in front of every value.
+pub const HEADER_LENGTH: usize = 8;
+
+const HEADER_VERSION: u8 = 1;
+
+/// The value was written by a privileged caller.
+const FLAG_PRIVILEGED: u8 = 0x01;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    None,
+    Get,
+    Set,
+    Delete,
+}
+
+#[derive(Clone, Copy)]
+struct Header {
+    flags: u8,
+    length: usize,
+    owner: u32,
+}
+
+impl Header {
+    fn decode(buf: &[u8]) -> Option<Header> {
+        if buf.len() < HEADER_LENGTH || buf[0] != HEADER_VERSION {
+            return None;
+        }
+
+        Some(Header {
+            flags: buf[1],
+            length: u16::from_le_bytes([buf[2], buf[3]]) as usize,
+            owner: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        })
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = HEADER_VERSION;
+        buf[1] = self.flags;
+        buf[2..4].copy_from_slice(&(self.length as u16).to_le_bytes());
+        buf[4..8].copy_from_slice(&self.owner.to_le_bytes());
+    }
+}
+
+/// FNV-1a hash of the namespace, used to tag values with their owner.
+fn namespace_owner(namespace: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for b in namespace.iter() {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+pub struct KVStore<'a, S: KVSystem<'a>> {
+    kv: &'a S,
+    client: OptionalCell<&'a dyn StoreClient>,
+
+    operation: Cell<Operation>,
+    privileged: Cell<bool>,
+    owner: Cell<u32>,
+    /// The value replaces an existing one, so it is written in a transaction.
+    replacing: Cell<bool>,
+    /// Why staging the new value failed, reported once the transaction has
+    /// been aborted.
+    stage_error: Cell<ReturnCode>,
+
+    unhashed_key: TakeCell<'static, [u8]>,
+    key: TakeCell<'static, S::K>,
+    /// Holds a header and value when reading from or writing to the KV system.
+    data: TakeCell<'static, [u8]>,
+    /// The client's buffer for the current get or set.
+    value: TakeCell<'static, [u8]>,
+    value_length: Cell<usize>,
+}
+
+impl<'a, S: KVSystem<'a>> KVStore<'a, S> {
+    pub fn new(
+        kv: &'a S,
+        unhashed_key: &'static mut [u8],
+        key: &'static mut S::K,
+        data: &'static mut [u8],
+    ) -> KVStore<'a, S> {
+        KVStore {
+            kv,
+            client: OptionalCell::empty(),
+            operation: Cell::new(Operation::None),
+            privileged: Cell::new(false),
+            owner: Cell::new(0),
+            replacing: Cell::new(false),
+            stage_error: Cell::new(ReturnCode::FAIL),
+            unhashed_key: TakeCell::new(unhashed_key),
+            key: TakeCell::new(key),
+            data: TakeCell::new(data),
+            value: TakeCell::empty(),
+            value_length: Cell::new(0),
+        }
+    }
+
+    /// The largest value that can be stored, limited by the `data` buffer.
+    pub fn max_value_length(&self) -> usize {
+        self.data.map_or(0, |data| {
+            cmp::min(
+                data.len().saturating_sub(HEADER_LENGTH),
+                u16::max_value() as usize,
+            )
+        })
+    }
+
+    /// Record who is performing `operation` and start hashing the key.
+    fn start(
+        &self,
+        operation: Operation,
+        permissions: &StoragePermissions,
+        key: &[u8],
+    ) -> Result<(), ReturnCode> {
+        if self.operation.get() != Operation::None {
+            return Err(ReturnCode::EBUSY);
+        }
+        if key.is_empty() {
+            return Err(ReturnCode::EINVAL);
+        }
+
+        let namespace = permissions.namespace();
+        let unhashed_key = self.unhashed_key.take().ok_or(ReturnCode::EBUSY)?;
+        if namespace.len() > u8::max_value() as usize
+            || key.len() > u8::max_value() as usize
+            || 2 + namespace.len() + key.len() > unhashed_key.len()
+        {
+            self.unhashed_key.replace(unhashed_key);
+            return Err(ReturnCode::ESIZE);
+        }
+        let key_buf = match self.key.take() {
+            Some(key_buf) => key_buf,
+            None => {
+                self.unhashed_key.replace(unhashed_key);
+                return Err(ReturnCode::EBUSY);
+            }
+        };
+
+        for b in unhashed_key.iter_mut() {
+            *b = 0;
+        }
+        let key_start = 2 + namespace.len();
+        unhashed_key[0] = namespace.len() as u8;
+        unhashed_key[1..key_start - 1].copy_from_slice(namespace);
+        unhashed_key[key_start - 1] = key.len() as u8;
+        unhashed_key[key_start..key_start + key.len()].copy_from_slice(key);
+
+        self.operation.set(operation);
+        self.privileged.set(permissions.is_privileged());
+        self.owner.set(namespace_owner(namespace));
+        self.replacing.set(false);
+
+        if let Err((unhashed_key, key_buf, e)) = self.kv.generate_key(unhashed_key, key_buf) {
+            self.operation.set(Operation::None);
+            self.unhashed_key.replace(unhashed_key);
+            self.key.replace(key_buf);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Check whether the current caller may modify or remove a value with
+    /// this header.
+    fn may_modify(&self, header: &Header) -> bool {
+        if self.privileged.get() {
+            return true;
+        }
+        header.owner == self.owner.get() && header.flags & FLAG_PRIVILEGED == 0
+    }
+
+    /// Write the header and the client's value into `data` and append it, or
+    /// stage it if it replaces an existing value.
+    fn append(&self, key: &'static mut S::K, data: &'static mut [u8]) {
+        let length = self.value_length.get();
+        let header = Header {
+            flags: if self.privileged.get() {
+                FLAG_PRIVILEGED
+            } else {
+                0
+            },
+            length,
+            owner: self.owner.get(),
+        };
+        header.encode(data);
+        self.value.map(|value| {
+            data[HEADER_LENGTH..HEADER_LENGTH + length].copy_from_slice(&value[..length]);
+        });
+
+        if self.replacing.get() {
+            if let Err((key, data, e)) = self.kv.stage_key(key, data, HEADER_LENGTH + length) {
+                kv_system::Client::stage_key_complete(self, Err(e), key, data);
+            }
+        } else if let Err((key, data, e)) = self.kv.append_key(key, data, HEADER_LENGTH + length) {
+            self.key.replace(key);
+            self.data.replace(data);
+            self.finish(Err(e), 0);
+        }
+    }
+
+    /// Start the transaction replacing the existing value.
+    fn replace(&self, key: &'static mut S::K) {
+        self.replacing.set(true);
+        self.key.replace(key);
+        if let Err(e) = self.kv.begin_transaction() {
+            self.finish(Err(e), 0);
+        }
+    }
+
+    fn invalidate(&self, key: &'static mut S::K) {
+        if let Err((key, e)) = self.kv.invalidate_key(key) {
+            self.key.replace(key);
+            self.finish(Err(e), 0);
+        }
+    }
+
+    /// Complete the current operation and notify the client.
+    fn finish(&self, result: Result<(), ReturnCode>, length: usize) {
+        let operation = self.operation.get();
+        self.operation.set(Operation::None);
+
+        match operation {
+            Operation::Get => {
+                self.value.take().map(|value| {
+                    self.client
+                        .map(move |cb| cb.get_complete(result, value, length));
+                });
+            }
+            Operation::Set => {
+                self.value.take().map(|value| {
+                    self.client.map(move |cb| cb.set_complete(result, value));
+                });
+            }
+            Operation::Delete => {
+                self.client.map(|cb| cb.delete_complete(result));
+            }
+            Operation::None => {}
+        }
+    }
+}
+
+impl<'a, S: KVSystem<'a>> kv_store::KVStore<'a> for KVStore<'a, S> {
+    fn set_client(&self, client: &'a dyn StoreClient) {
+        self.client.set(client);
+    }
+
+    fn get(
+        &self,
+        permissions: StoragePermissions,
+        key: &[u8],
+        value: &'static mut [u8],
+    ) -> Result<(), (&'static mut [u8], ReturnCode)> {
+        if self.operation.get() != Operation::None {
+            return Err((value, ReturnCode::EBUSY));
+        }
+
+        self.value.replace(value);
+        self.start(Operation::Get, &permissions, key)
+            .map_err(|e| (self.value.take().unwrap(), e))
+    }
+
+    fn set(
+        &self,
+        permissions: StoragePermissions,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut [u8], ReturnCode)> {
+        if length > value.len() {
+            return Err((value, ReturnCode::EINVAL));
+        }
+        if length > self.max_value_length() {
+            return Err((value, ReturnCode::ESIZE));
+        }
+
+        if self.operation.get() != Operation::None {
+            return Err((value, ReturnCode::EBUSY));
+        }
+
+        self.value.replace(value);
+        self.value_length.set(length);
+        self.start(Operation::Set, &permissions, key)
+            .map_err(|e| (self.value.take().unwrap(), e))
+    }
+
+    fn delete(&self, permissions: StoragePermissions, key: &[u8]) -> Result<(), ReturnCode> {
+        self.start(Operation::Delete, &permissions, key)
+    }
+}
+
+impl<'a, S: KVSystem<'a>> kv_system::Client<S::K> for KVStore<'a, S> {
+    fn generate_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut S::K,
+    ) {
+        self.unhashed_key.replace(unhashed_key);
+
+        if let Err(e) = result {
+            self.key.replace(key_buf);
+            self.finish(Err(e), 0);
+            return;
+        }
+
+        // Every operation starts by reading the existing value, either to
+        // return it or to check that the caller may replace it.
+        match self.data.take() {
+            Some(data) => {
+                if let Err((key_buf, data, e)) = self.kv.get_value(key_buf, data) {
+                    self.get_value_complete(Err(e), key_buf, data);
+                }
+            }
+            None => {
+                self.key.replace(key_buf);
+                self.finish(Err(ReturnCode::FAIL), 0);
+            }
+        }
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.data.replace(value);
+        self.finish(result, 0);
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        ret_buf: &'static mut [u8],
+    ) {
+        let header = match result {
+            Ok(()) => match Header::decode(ret_buf) {
+                Some(header) if header.length <= ret_buf.len() - HEADER_LENGTH => Some(header),
+                _ => {
+                    self.key.replace(key);
+                    self.data.replace(ret_buf);
+                    self.finish(Err(ReturnCode::FAIL), 0);
+                    return;
+                }
+            },
+            Err(ReturnCode::ENOSUPPORT) => None,
+            Err(e) => {
+                self.key.replace(key);
+                self.data.replace(ret_buf);
+                self.finish(Err(e), 0);
+                return;
+            }
+        };
+
+        match self.operation.get() {
+            Operation::Get => {
+                // A value owned by another namespace is reported as missing,
+                // so a collision never leaks another caller's data.
+                let result = match header {
+                    Some(header) if header.owner == self.owner.get() => {
+                        self.value.map(|value| {
+                            let copy = cmp::min(header.length, value.len());
+                            value[..copy]
+                                .copy_from_slice(&ret_buf[HEADER_LENGTH..HEADER_LENGTH + copy]);
+                        });
+                        let fits = self
+                            .value
+                            .map_or(false, |value| header.length <= value.len());
+                        if fits {
+                            (Ok(()), header.length)
+                        } else {
+                            (Err(ReturnCode::ESIZE), header.length)
+                        }
+                    }
+                    _ => (Err(ReturnCode::ENOSUPPORT), 0),
+                };
+                self.key.replace(key);
+                self.data.replace(ret_buf);
+                self.finish(result.0, result.1);
+            }
+            Operation::Set => match header {
+                Some(header) if !self.may_modify(&header) => {
+                    self.key.replace(key);
+                    self.data.replace(ret_buf);
+                    self.finish(Err(ReturnCode::ERESERVE), 0);
+                }
+                Some(_) => {
+                    self.data.replace(ret_buf);
+                    self.replace(key);
+                }
+                None => self.append(key, ret_buf),
+            },
+            Operation::Delete => {
+                self.data.replace(ret_buf);
+                match header {
+                    Some(header) if self.may_modify(&header) => self.invalidate(key),
+                    Some(_) => {
+                        self.key.replace(key);
+                        self.finish(Err(ReturnCode::ERESERVE), 0);
+                    }
+                    None => {
+                        self.key.replace(key);
+                        self.finish(Err(ReturnCode::ENOSUPPORT), 0);
+                    }
+                }
+            }
+            Operation::None => {
+                self.key.replace(key);
+                self.data.replace(ret_buf);
+            }
+        }
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ReturnCode>, key: &'static mut S::K) {
+        self.key.replace(key);
+        self.finish(result, 0);
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ReturnCode>) {}
+
+    fn next_key_complete(
+        &self,
+        _result: Result<(), ReturnCode>,
+        _key_buf: &'static mut S::K,
+        _cursor: usize,
+    ) {
+    }
+
+    fn storage_stats_complete(
+        &self,
+        _result: Result<(), ReturnCode>,
+        _stats: kv_system::StorageStats,
+    ) {
+    }
+
+    fn check_consistency_complete(
+        &self,
+        _result: Result<(), ReturnCode>,
+        _found: usize,
+        _repaired: usize,
+    ) {
+    }
+
+    fn begin_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        if let Err(e) = result {
+            self.finish(Err(e), 0);
+            return;
+        }
+
+        match (self.key.take(), self.data.take()) {
+            (Some(key), Some(data)) => self.append(key, data),
+            (key, data) => {
+                key.map(|key| self.key.replace(key));
+                data.map(|data| self.data.replace(data));
+                self.stage_error.set(ReturnCode::FAIL);
+                if self.kv.abort_transaction().is_err() {
+                    self.finish(Err(ReturnCode::FAIL), 0);
+                }
+            }
+        }
+    }
+
+    fn stage_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut S::K,
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.data.replace(value);
+
+        let ret = match result {
+            Ok(()) => self.kv.commit_transaction(),
+            Err(e) => {
+                // Drop the new value, keeping the old one.
+                self.stage_error.set(e);
+                self.kv.abort_transaction().map_err(|_| e)
+            }
+        };
+        if let Err(e) = ret {
+            self.finish(Err(e), 0);
+        }
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.finish(result, 0);
+    }
+
+    fn abort_transaction_complete(&self, _result: Result<(), ReturnCode>) {
+        self.finish(Err(self.stage_error.get()), 0);
+    }
+}
@@ -7,6 +7,7 @@ pub mod test;
 #[macro_use]
 pub mod net;
 
+pub mod ab_copies;
 pub mod adc;
 pub mod adc_microphone;
 pub mod alarm;
@@ -15,7 +16,10 @@ pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod ble;
 pub mod ble_advertising_driver;
+pub mod block_cache;
+pub mod block_storage;
 pub mod bus;
 pub mod button;
 pub mod buzzer_driver;
@@ -25,6 +29,9 @@ pub mod ctap;
 pub mod dac;
 pub mod debug_process_restart;
 pub mod driver;
+pub mod fat;
+pub mod fat_driver;
+pub mod flash_translation;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
@@ -37,10 +44,15 @@ pub mod i2c_master;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod kv_driver;
+pub mod kv_encryption;
+pub mod kv_store;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
 pub mod log;
+pub mod log_driver;
+pub mod log_manager;
 pub mod low_level_debug;
 pub mod lps25hb;
 pub mod lsm303agr;
@@ -52,6 +64,8 @@ pub mod mcp230xx;
 pub mod mlx90614;
 pub mod mx25r6435f;
 pub mod ninedof;
+pub mod nonvolatile_regions;
+pub mod nonvolatile_regions_driver;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
@@ -59,6 +73,7 @@ pub mod panic_button;
 pub mod pca9544a;
 pub mod process_console;
 pub mod proximity;
+pub mod public_key_crypto;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
@@ -67,6 +82,9 @@ pub mod sdcard;
 pub mod segger_rtt;
 pub mod sht3x;
 pub mod si7021;
+pub mod sip_hash;
+pub mod software_aes;
+pub mod software_sha;
 pub mod sound_pressure;
 pub mod spi_controller;
 pub mod spi_peripheral;
@@ -78,9 +96,11 @@ pub mod tickv;
 pub mod touch;
 pub mod tsl2561;
 pub mod usb;
+pub mod usb_hid_driver;
 pub mod virtual_adc;
 pub mod virtual_aes_ccm;
 pub mod virtual_alarm;
+pub mod virtual_block_storage;
 pub mod virtual_digest;
 pub mod virtual_flash;
 pub mod virtual_hmac;
@@ -3,7 +3,9 @@
 //! Data entries can be appended to the end of a log and read back in-order. Logs may be linear
 //! (denying writes when full) or circular (overwriting the oldest entries with the newest entries
 //! when the underlying flash volume is full). The storage volumes that logs operate upon are
-//! statically allocated at compile time and cannot be dynamically created at runtime.
+//! statically allocated at compile time and cannot be dynamically created at runtime. To give
+//! several applications their own logs, `capsules::log_manager` splits one volume into several
+//! named logs.
 //!
 //! Entries can be identified and seeked-to with their unique Entry IDs. Entry IDs maintain the
 //! ordering of the underlying entries, and an entry with a larger entry ID is newer and comes
@@ -432,13 +434,14 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         let append_entry_id = append_entry_id + length + ENTRY_HEADER_SIZE;
         self.append_entry_id.set(append_entry_id);
 
-        // Replace pagebuffer and callback client.
+        // Replace pagebuffer and callback client. The callback is deferred since this may be
+        // called from `append()`.
         self.pagebuffer.replace(pagebuffer);
         self.buffer.replace(buffer);
         self.records_lost
             .set(self.oldest_entry_id.get() != PAGE_HEADER_SIZE);
         self.error.set(ReturnCode::SUCCESS);
-        self.client_callback();
+        self.deferred_client_callback();
     }
 
     /// Flushes the pagebuffer to flash. Log state must be non-idle before calling, else data races
@@ -459,11 +462,14 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         // padding pointer points to start of the page following the one we want to flush after the
         // padding operation.
         let page_number = self.page_number(pad_ptr - self.page_size);
-        let overwritten_page = (pad_ptr - self.volume.len() - self.page_size) / self.page_size;
+        // No page is overwritten until a circular log wraps around.
+        let overwritten_page = pad_ptr
+            .checked_sub(self.volume.len() + self.page_size)
+            .map(|pos| pos / self.page_size);
 
         // Advance read and oldest entry IDs, if within flash page being overwritten.
         let read_entry_id = self.read_entry_id.get();
-        if read_entry_id / self.page_size == overwritten_page {
+        if Some(read_entry_id / self.page_size) == overwritten_page {
             // Move read entry ID to start of next page.
             self.read_entry_id.set(
                 read_entry_id + self.page_size + PAGE_HEADER_SIZE - read_entry_id % self.page_size,
@@ -471,7 +477,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         }
 
         let oldest_entry_id = self.oldest_entry_id.get();
-        if oldest_entry_id / self.page_size == overwritten_page {
+        if Some(oldest_entry_id / self.page_size) == overwritten_page {
             self.oldest_entry_id.set(oldest_entry_id + self.page_size);
         }
 
@@ -756,7 +762,7 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
         }
     }
 
-    /// Sync log to storage.
+    /// Sync log to storage. `sync_done` is called even if there was nothing to sync.
     /// ReturnCodes used:
     ///     * SUCCESS: flush started successfully.
     ///     * FAIL: flash driver not configured.
@@ -766,12 +772,15 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     ///     * SUCCESS: append succeeded.
     ///     * FAIL: write failed due to flash error.
     fn sync(&self) -> ReturnCode {
-        if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
-            // Pagebuffer empty, don't need to flush.
-            return ReturnCode::SUCCESS;
-        } else if self.state.get() != State::Idle {
+        if self.state.get() != State::Idle {
             // Log busy, try appending again later.
             return ReturnCode::EBUSY;
+        } else if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
+            // Pagebuffer empty, don't need to flush.
+            self.state.set(State::Sync);
+            self.error.set(ReturnCode::SUCCESS);
+            self.deferred_client_callback();
+            return ReturnCode::SUCCESS;
         }
 
         self.pagebuffer
@@ -872,7 +881,7 @@ impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
         match error {
             flash::Error::CommandComplete => {
                 let oldest_entry_id = self.oldest_entry_id.get();
-                if oldest_entry_id >= self.append_entry_id.get() - self.page_size {
+                if oldest_entry_id >= self.append_entry_id.get().saturating_sub(self.page_size) {
                     // Erased all pages. Reset state and callback client.
                     if self.reset() {
                         self.error.set(ReturnCode::SUCCESS);
@@ -0,0 +1,380 @@
+//! Syscall driver giving applications access to their logs.
+//!
+//! The logs are kept by a `capsules::log_manager::LogManager`. An
+//! application opens a log by name and gets a handle for it, which it uses
+//! for the other commands. Applications can only open the logs whose owner
+//! is their package name, so they can't read or change the logs of other
+//! applications.
+//!
+//! Only one operation is in flight at a time. Each application can queue one
+//! operation while another operation is running.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let log_driver_buffer = static_init!([u8; 512], [0; 512]);
+//! let log_driver = static_init!(
+//!     capsules::log_driver::LogDriver<'static>,
+//!     capsules::log_driver::LogDriver::new(
+//!         log_manager,
+//!         board_kernel.create_grant(&grant_cap),
+//!         log_driver_buffer,
+//!     )
+//! );
+//! log_manager.set_client(log_driver);
+//! ```
+
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::log_manager::{self, LogManager};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Log as usize;
+
+#[derive(Clone, Copy, PartialEq)]
+enum UserOperation {
+    Read,
+    Append(usize),
+    Seek(usize),
+    Sync,
+    Erase,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    name: Option<AppSlice<Shared, u8>>,
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    write_buffer: Option<AppSlice<Shared, u8>>,
+    pending_command: Option<(usize, UserOperation)>,
+}
+
+pub struct LogDriver<'a> {
+    manager: &'a LogManager<'a>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a> LogDriver<'a> {
+    pub fn new(
+        manager: &'a LogManager<'a>,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> LogDriver<'a> {
+        LogDriver {
+            manager,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    // Check to see if we are doing something. If not, go ahead and do this
+    // command. If so, this is queued and will be run when the pending command
+    // completes.
+    fn enqueue_command(&self, handle: usize, operation: UserOperation, appid: AppId) -> ReturnCode {
+        if !self.manager.owned_by(handle, appid.get_process_name()) {
+            return ReturnCode::EINVAL;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                let ready = match operation {
+                    UserOperation::Read => app.read_buffer.is_some(),
+                    UserOperation::Append(_) => app.write_buffer.is_some(),
+                    _ => true,
+                };
+                if !ready {
+                    return ReturnCode::EINVAL;
+                }
+
+                if self.current_app.is_none() {
+                    let ret = self.start_command(handle, operation, app);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                    }
+                    ret
+                } else {
+                    // Queue this request for later.
+                    if app.pending_command.is_some() {
+                        ReturnCode::EBUSY
+                    } else {
+                        app.pending_command = Some((handle, operation));
+                        ReturnCode::SUCCESS
+                    }
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn start_command(&self, handle: usize, operation: UserOperation, app: &mut App) -> ReturnCode {
+        let log = match self.manager.log(handle) {
+            Some(log) => log,
+            None => return ReturnCode::EINVAL,
+        };
+        match operation {
+            UserOperation::Read => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let length = app
+                    .read_buffer
+                    .as_ref()
+                    .map_or(0, |read_buffer| cmp::min(read_buffer.len(), buffer.len()));
+                match log.read(buffer, length) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((e, buffer)) => {
+                        buffer.map(|buffer| self.buffer.replace(buffer));
+                        e
+                    }
+                }
+            }),
+            UserOperation::Append(length) => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    // Entries can't be split, so they must fit in the kernel
+                    // buffer.
+                    if length > buffer.len() {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::ESIZE;
+                    }
+                    let copied = app.write_buffer.as_ref().map_or(false, |write_buffer| {
+                        if length <= write_buffer.len() {
+                            buffer[..length].copy_from_slice(&write_buffer.as_ref()[..length]);
+                            true
+                        } else {
+                            false
+                        }
+                    });
+                    if !copied {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::EINVAL;
+                    }
+                    match log.append(buffer, length) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((e, buffer)) => {
+                            buffer.map(|buffer| self.buffer.replace(buffer));
+                            e
+                        }
+                    }
+                })
+            }
+            UserOperation::Seek(entry_id) => log.seek(entry_id),
+            UserOperation::Sync => log.sync(),
+            UserOperation::Erase => log.erase(),
+        }
+    }
+
+    /// Notify the current application and start the next queued command.
+    fn complete_command(&self, ret: ReturnCode, value: usize, records_lost: bool) {
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback.map(|mut cb| {
+                    cb.schedule(usize::from(ret), value, records_lost as usize);
+                });
+            });
+        });
+
+        // Check if there are any pending events.
+        for cntr in self.apps.iter() {
+            let started_command = cntr.enter(|app, _| {
+                let appid = app.appid();
+                app.pending_command
+                    .take()
+                    .map_or(false, |(handle, operation)| {
+                        let ret = self.start_command(handle, operation, app);
+                        if ret == ReturnCode::SUCCESS {
+                            self.current_app.set(appid);
+                            true
+                        } else {
+                            app.callback.map(|mut cb| {
+                                cb.schedule(usize::from(ret), 0, 0);
+                            });
+                            false
+                        }
+                    })
+            });
+            if started_command {
+                break;
+            }
+        }
+    }
+
+    /// Get a position or the size of a log owned by `appid`.
+    fn log_value<F: FnOnce(&dyn log_manager::LogStorage<'a>) -> usize>(
+        &self,
+        handle: usize,
+        appid: AppId,
+        f: F,
+    ) -> ReturnCode {
+        if !self.manager.owned_by(handle, appid.get_process_name()) {
+            return ReturnCode::EINVAL;
+        }
+        self.manager
+            .log(handle)
+            .map_or(ReturnCode::EINVAL, |log| ReturnCode::SuccessWithValue {
+                value: f(log),
+            })
+    }
+
+    /// Find the log named by the `allow` 0 buffer.
+    fn open(&self, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                let name = match app.name.as_ref() {
+                    Some(name) => name.as_ref(),
+                    None => return ReturnCode::EINVAL,
+                };
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                self.manager
+                    .find(name, appid.get_process_name())
+                    .map_or(ReturnCode::EINVAL, |handle| ReturnCode::SuccessWithValue {
+                        value: handle,
+                    })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl log_manager::Client for LogDriver<'_> {
+    fn read_done(
+        &self,
+        _handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+        error: ReturnCode,
+    ) {
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.read_buffer.as_mut().map(|read_buffer| {
+                    let copy = cmp::min(length, read_buffer.len());
+                    read_buffer.as_mut()[..copy].copy_from_slice(&buffer[..copy]);
+                });
+            });
+        });
+        self.buffer.replace(buffer);
+        self.complete_command(error, length, false);
+    }
+
+    fn seek_done(&self, _handle: usize, error: ReturnCode) {
+        self.complete_command(error, 0, false);
+    }
+
+    fn append_done(
+        &self,
+        _handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    ) {
+        self.buffer.replace(buffer);
+        self.complete_command(error, length, records_lost);
+    }
+
+    fn sync_done(&self, _handle: usize, error: ReturnCode) {
+        self.complete_command(error, 0, false);
+    }
+
+    fn erase_done(&self, _handle: usize, error: ReturnCode) {
+        self.complete_command(error, 0, false);
+    }
+}
+
+impl Driver for LogDriver<'_> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Set the name of the log to open. The name ends at the end of
+    ///        the buffer or at the first NUL byte.
+    /// - `1`: Set the buffer that entries are read into.
+    /// - `2`: Set the buffer holding the entry to append.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.name = slice,
+                    1 => app.read_buffer = slice,
+                    2 => app.write_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Set the callback for completed operations. The callback receives
+    ///        the `ReturnCode` of the operation, the length of the entry read
+    ///        or appended, and for appends whether old entries were
+    ///        overwritten.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Log operations.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Open the log named by the `allow` 0 buffer. Returns the handle
+    ///        of the log.
+    /// - `2`: Read the next entry of log `data` into the `allow` 1 buffer.
+    ///        `FAIL` indicates there are no more entries.
+    /// - `3`: Append `data2` bytes from the `allow` 2 buffer to log `data`
+    ///        as one entry.
+    /// - `4`: Move the read position of log `data` to entry `data2`.
+    /// - `5`: Write the appended entries of log `data` to flash.
+    /// - `6`: Erase log `data`.
+    /// - `7`: Get the ID of the oldest entry of log `data`.
+    /// - `8`: Get the ID the next entry appended to log `data` will have.
+    /// - `9`: Get the ID of the next entry read from log `data`.
+    /// - `10`: Get the approximate capacity of log `data` in bytes.
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 =>
+            /* This driver exists. */
+            {
+                ReturnCode::SUCCESS
+            }
+
+            1 => self.open(appid),
+            2 => self.enqueue_command(data, UserOperation::Read, appid),
+            3 => self.enqueue_command(data, UserOperation::Append(data2), appid),
+            4 => self.enqueue_command(data, UserOperation::Seek(data2), appid),
+            5 => self.enqueue_command(data, UserOperation::Sync, appid),
+            6 => self.enqueue_command(data, UserOperation::Erase, appid),
+            7 => self.log_value(data, appid, |log| log.log_start()),
+            8 => self.log_value(data, appid, |log| log.log_end()),
+            9 => self.log_value(data, appid, |log| log.next_read_entry_id()),
+            10 => self.log_value(data, appid, |log| log.get_size()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Several named logs in one storage volume, each owned by an application.
+//!
+//! A board describes its logs with `LogConfig`s: the name applications use
+//! to open the log, the package name of the application that owns it, how
+//! many bytes of the volume it may use and whether it is circular or linear.
+//! `log_volume()` gives each log its own part of the volume, in the order of
+//! the configurations, and each part becomes a `capsules::log::Log`, usually
+//! on its own `FlashUser` of a shared `MuxFlash`. The size of a log is its
+//! quota: a circular log overwrites its own oldest entries when it is full
+//! and a linear log refuses further appends, but neither can take space from
+//! the other logs.
+//!
+//! `LogManager` finds logs by name and owner and passes on their callbacks
+//! together with the handle of the log. `capsules::log_driver` makes the logs
+//! available to applications.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init, storage_volume};
+//! # use capsules::log::Log;
+//! # use capsules::log_manager::{log_volume, LogConfig, LogManager, ManagedLog};
+//! # use capsules::virtual_flash::FlashUser;
+//!
+//! storage_volume!(LOGS, 32);
+//! const LOG_CONFIGS: [LogConfig; 2] = [
+//!     LogConfig { name: "events", owner: "sensor", size: 0x4000, circular: true },
+//!     LogConfig { name: "audit", owner: "monitor", size: 0x4000, circular: false },
+//! ];
+//!
+//! let events_flash = static_init!(
+//!     FlashUser<'static, nrf52840::nvmc::Nvmc>,
+//!     FlashUser::new(mux_flash)
+//! );
+//! let events_page = static_init!(
+//!     nrf52840::nvmc::NrfPage,
+//!     nrf52840::nvmc::NrfPage::default()
+//! );
+//! let events = static_init!(
+//!     Log<'static, FlashUser<'static, nrf52840::nvmc::Nvmc>>,
+//!     Log::new(
+//!         log_volume(&LOGS, 4096, &LOG_CONFIGS, 0).unwrap(),
+//!         events_flash,
+//!         events_page,
+//!         dynamic_deferred_caller,
+//!         LOG_CONFIGS[0].circular,
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(events_flash, events);
+//! events.initialize_callback_handle(dynamic_deferred_caller.register(events).unwrap());
+//! let managed_events = static_init!(
+//!     ManagedLog<'static>,
+//!     ManagedLog::new(events, LOG_CONFIGS[0])
+//! );
+//! // ... and the same for `audit`.
+//!
+//! let logs = static_init!(
+//!     [&'static ManagedLog<'static>; 2],
+//!     [managed_events, managed_audit]
+//! );
+//! let log_manager = static_init!(LogManager<'static>, LogManager::new(logs));
+//! log_manager.setup();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::ReturnCode;
+
+/// A log that can be read and appended to, with `usize` entry IDs like
+/// `capsules::log::Log`.
+pub trait LogStorage<'a>: LogRead<'a, EntryID = usize> + LogWrite<'a> {}
+
+impl<'a, T: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogStorage<'a> for T {}
+
+/// Describes one log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogConfig {
+    /// The name applications open the log with
+    pub name: &'static str,
+    /// The package name of the application that owns the log
+    pub owner: &'static str,
+    /// The number of bytes of the volume used by the log. Must be a multiple
+    /// of the flash page size.
+    pub size: usize,
+    /// Whether the oldest entries are overwritten when the log is full,
+    /// rather than refusing to append
+    pub circular: bool,
+}
+
+/// The part of `volume` used by the log described by `configs[index]`.
+/// `volume` must start on a flash page boundary. Returns `None` if the sizes
+/// of the logs up to `index` aren't multiples of `page_size` or don't fit in
+/// the volume.
+pub fn log_volume(
+    volume: &'static [u8],
+    page_size: usize,
+    configs: &[LogConfig],
+    index: usize,
+) -> Option<&'static [u8]> {
+    let mut start = 0;
+    for (i, config) in configs.iter().enumerate().take(index + 1) {
+        if page_size == 0 || config.size == 0 || config.size % page_size != 0 {
+            return None;
+        }
+        let end = start + config.size;
+        if end > volume.len() {
+            return None;
+        }
+        if i == index {
+            return Some(&volume[start..end]);
+        }
+        start = end;
+    }
+    None
+}
+
+/// Implement `Client` to receive callbacks from the logs of a `LogManager`.
+/// `handle` is the handle of the log, as returned by `LogManager::find()`.
+pub trait Client {
+    /// An entry was read into `buffer`.
+    fn read_done(&self, handle: usize, buffer: &'static mut [u8], length: usize, error: ReturnCode);
+
+    /// The read position was moved.
+    fn seek_done(&self, handle: usize, error: ReturnCode);
+
+    /// An entry was appended from `buffer`. `records_lost` is set if a
+    /// circular log overwrote old entries.
+    fn append_done(
+        &self,
+        handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    );
+
+    /// The log was synced to flash.
+    fn sync_done(&self, handle: usize, error: ReturnCode);
+
+    /// The log was erased.
+    fn erase_done(&self, handle: usize, error: ReturnCode);
+}
+
+/// A log and its configuration. Passes the callbacks of the log on to the
+/// `LogManager`.
+pub struct ManagedLog<'a> {
+    log: &'a dyn LogStorage<'a>,
+    config: LogConfig,
+    handle: Cell<usize>,
+    manager: OptionalCell<&'a LogManager<'a>>,
+}
+
+impl<'a> ManagedLog<'a> {
+    pub fn new(log: &'a dyn LogStorage<'a>, config: LogConfig) -> ManagedLog<'a> {
+        ManagedLog {
+            log,
+            config,
+            handle: Cell::new(0),
+            manager: OptionalCell::empty(),
+        }
+    }
+
+    fn client<F: FnOnce(&dyn Client, usize)>(&self, f: F) {
+        let handle = self.handle.get();
+        self.manager.map(move |manager| {
+            manager.client.map(move |client| f(*client, handle));
+        });
+    }
+}
+
+impl LogReadClient for ManagedLog<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode) {
+        self.client(move |client, handle| client.read_done(handle, buffer, length, error));
+    }
+
+    fn seek_done(&self, error: ReturnCode) {
+        self.client(|client, handle| client.seek_done(handle, error));
+    }
+}
+
+impl LogWriteClient for ManagedLog<'_> {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    ) {
+        self.client(move |client, handle| {
+            client.append_done(handle, buffer, length, records_lost, error)
+        });
+    }
+
+    fn sync_done(&self, error: ReturnCode) {
+        self.client(|client, handle| client.sync_done(handle, error));
+    }
+
+    fn erase_done(&self, error: ReturnCode) {
+        self.client(|client, handle| client.erase_done(handle, error));
+    }
+}
+
+/// Keeps the logs of a volume. A log's handle is its index in the list of
+/// logs.
+pub struct LogManager<'a> {
+    logs: &'a [&'a ManagedLog<'a>],
+    client: OptionalCell<&'a dyn Client>,
+}
+
+impl<'a> LogManager<'a> {
+    pub fn new(logs: &'a [&'a ManagedLog<'a>]) -> LogManager<'a> {
+        LogManager {
+            logs,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Make the logs call back through the manager.
+    pub fn setup(&'a self) {
+        for (handle, managed) in self.logs.iter().enumerate() {
+            managed.handle.set(handle);
+            managed.manager.set(self);
+            managed.log.set_read_client(*managed);
+            managed.log.set_append_client(*managed);
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    /// The handle of the log called `name` that is owned by the application
+    /// with package name `owner`. Applications without a package name don't
+    /// own any logs.
+    pub fn find(&self, name: &[u8], owner: &str) -> Option<usize> {
+        if owner.is_empty() {
+            return None;
+        }
+        self.logs.iter().position(|managed| {
+            managed.config.name.as_bytes() == name && managed.config.owner == owner
+        })
+    }
+
+    /// Whether the application with package name `owner` owns log `handle`.
+    pub fn owned_by(&self, handle: usize, owner: &str) -> bool {
+        self.config(handle)
+            .map_or(false, |config| !owner.is_empty() && config.owner == owner)
+    }
+
+    pub fn config(&self, handle: usize) -> Option<LogConfig> {
+        self.logs.get(handle).map(|managed| managed.config)
+    }
+
+    /// The log with handle `handle`. Its callbacks go to the client of the
+    /// manager.
+    pub fn log(&self, handle: usize) -> Option<&'a dyn LogStorage<'a>> {
+        self.logs.get(handle).map(|managed| managed.log)
+    }
+}
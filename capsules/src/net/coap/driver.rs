@@ -0,0 +1,384 @@
+//! CoAP userspace interface.
+//!
+//! Lets processes serve CoAP resources and make CoAP requests through a
+//! shared `Coap` endpoint. Each process can register up to `MAX_RESOURCES`
+//! resources by path. GET requests are answered by the kernel from the
+//! representation the process has shared for the resource, so the process
+//! only has to refresh that buffer and call `notify` when it changes. POST,
+//! PUT and DELETE requests are passed to the process.
+//!
+//! One process at a time can act as a client. Responses, and notifications
+//! for an observed resource, are copied into the process's read buffer. An
+//! exchange ends with its last response, when the process ends it, or when
+//! the process exits.
+
+use crate::net::coap::endpoint::{Coap, CoapClient, CoapServerClient, MAX_PATH_LEN};
+use crate::net::coap::message::{code, Message};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::util::host_slice_to_u16;
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::Alarm;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Coap as usize;
+
+/// Number of resources each process can register.
+pub const MAX_RESOURCES: usize = 4;
+
+// Length of the destination address and port at the start of the config
+// buffer for requests.
+const ENDPOINT_LEN: usize = 18;
+
+#[derive(Default)]
+pub struct App {
+    request_callback: Option<Callback>,
+    response_callback: Option<Callback>,
+    app_read: Option<AppSlice<Shared, u8>>,
+    app_write: Option<AppSlice<Shared, u8>>,
+    app_cfg: Option<AppSlice<Shared, u8>>,
+    paths: [Option<([u8; MAX_PATH_LEN], usize)>; MAX_RESOURCES],
+    representations: [Option<AppSlice<Shared, u8>>; MAX_RESOURCES],
+}
+
+pub struct CoapDriver<'a, A: Alarm<'a>> {
+    coap: &'a Coap<'a, A>,
+    apps: Grant<App>,
+    /// The process whose request is in progress.
+    client_app: OptionalCell<AppId>,
+}
+
+impl<'a, A: Alarm<'a>> CoapDriver<'a, A> {
+    pub fn new(coap: &'a Coap<'a, A>, grant: Grant<App>) -> CoapDriver<'a, A> {
+        CoapDriver {
+            coap: coap,
+            apps: grant,
+            client_app: OptionalCell::empty(),
+        }
+    }
+
+    #[inline]
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    // Runs `closure` on the app owning `resource` and the resource's slot.
+    fn with_resource<F, R>(&self, resource: usize, closure: F) -> Option<R>
+    where
+        F: FnOnce(&mut App, usize) -> R,
+        R: Copy,
+    {
+        let id = resource / MAX_RESOURCES;
+        let slot = resource % MAX_RESOURCES;
+        let mut closure = Some(closure);
+        let mut result = None;
+        for app in self.apps.iter() {
+            result = result.or_else(|| {
+                app.enter(|app, _| {
+                    if app.appid().id() == id {
+                        closure.take().map(|closure| closure(app, slot))
+                    } else {
+                        None
+                    }
+                })
+            });
+        }
+        result
+    }
+
+    fn register(&self, app: &mut App) -> ReturnCode {
+        let path = match app.app_cfg {
+            Some(ref cfg) if cfg.len() > 0 && cfg.len() <= MAX_PATH_LEN => {
+                let mut path = [0; MAX_PATH_LEN];
+                path[..cfg.len()].copy_from_slice(cfg.as_ref());
+                (path, cfg.len())
+            }
+            _ => return ReturnCode::EINVAL,
+        };
+        // Paths must be unique across all processes.
+        let same_path =
+            |other: &([u8; MAX_PATH_LEN], usize)| other.0[..other.1] == path.0[..path.1];
+        let taken = self
+            .apps
+            .iter_unentered_grants()
+            .any(|other| other.enter(|other, _| other.paths.iter().flatten().any(same_path)));
+        if taken || app.paths.iter().flatten().any(same_path) {
+            return ReturnCode::EALREADY;
+        }
+        match app.paths.iter().position(|p| p.is_none()) {
+            Some(slot) => {
+                app.paths[slot] = Some(path);
+                ReturnCode::SuccessWithValue { value: slot }
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    // Ends the exchange of the process whose request is in progress if that
+    // process has exited or faulted, as it can no longer end it itself. Must
+    // not be called with a grant region entered.
+    fn release_exited_client(&self) {
+        let exited = self
+            .client_app
+            .map_or(false, |owner| self.apps.enter(*owner, |_, _| ()).is_err());
+        if exited {
+            self.client_app.clear();
+            self.coap.cancel();
+        }
+    }
+
+    fn send_request(&self, appid: AppId, app: &mut App, arg1: usize) -> ReturnCode {
+        let method = (arg1 & 0xff) as u8;
+        let confirmable = arg1 & (1 << 8) != 0;
+        let observe = arg1 & (1 << 9) != 0;
+        let cfg = match app.app_cfg {
+            Some(ref cfg) if cfg.len() > ENDPOINT_LEN => cfg,
+            _ => return ReturnCode::EINVAL,
+        };
+        let cfg = cfg.as_ref();
+        let mut addr = IPAddr::new();
+        addr.0.copy_from_slice(&cfg[..16]);
+        let port = host_slice_to_u16(&cfg[16..ENDPOINT_LEN]);
+        let payload = app
+            .app_write
+            .as_ref()
+            .map_or(&[][..], |write| write.as_ref());
+        let rval = self.coap.request(
+            addr,
+            port,
+            method,
+            &cfg[ENDPOINT_LEN..],
+            payload,
+            confirmable,
+            observe,
+        );
+        if rval == ReturnCode::SUCCESS {
+            self.client_app.set(appid);
+        }
+        rval
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoapServerClient for CoapDriver<'a, A> {
+    fn resource(&self, request: &Message) -> Option<usize> {
+        let mut found = None;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                let id = app.appid().id();
+                for (slot, path) in app.paths.iter().enumerate() {
+                    if let Some((path, len)) = path {
+                        if found.is_none() && request.path_matches(&path[..*len]) {
+                            found = Some(id * MAX_RESOURCES + slot);
+                        }
+                    }
+                }
+            });
+        }
+        found
+    }
+
+    fn get(&self, resource: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        self.with_resource(resource, |app, slot| {
+            app.representations[slot]
+                .as_ref()
+                .map_or(Err(code::SERVICE_UNAVAILABLE), |rep| {
+                    if rep.len() > buf.len() {
+                        return Err(code::INTERNAL_SERVER_ERROR);
+                    }
+                    buf[..rep.len()].copy_from_slice(rep.as_ref());
+                    Ok(rep.len())
+                })
+        })
+        .unwrap_or(Err(code::NOT_FOUND))
+    }
+
+    fn update(&self, resource: usize, method: u8, payload: &[u8]) -> u8 {
+        self.with_resource(resource, |app, slot| {
+            let callback = match app.request_callback {
+                Some(ref mut cb) => cb,
+                None => return code::METHOD_NOT_ALLOWED,
+            };
+            let len = app.app_read.as_mut().map_or(0, |read| {
+                let len = cmp::min(read.len(), payload.len());
+                read.as_mut()[..len].copy_from_slice(&payload[..len]);
+                len
+            });
+            if len < payload.len() {
+                return code::REQUEST_ENTITY_TOO_LARGE;
+            }
+            callback.schedule(slot, method as usize, len);
+            if method == code::DELETE {
+                code::DELETED
+            } else {
+                code::CHANGED
+            }
+        })
+        .unwrap_or(code::NOT_FOUND)
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoapClient for CoapDriver<'a, A> {
+    fn response(&self, result: ReturnCode, code: u8, offset: usize, payload: &[u8], more: bool) {
+        let appid = match self.client_app.take() {
+            Some(appid) => appid,
+            None => return,
+        };
+        // The exchange stays open while blocks or notifications follow.
+        if more || (result == ReturnCode::SUCCESS && !self.coap.is_idle()) {
+            self.client_app.set(appid);
+        }
+        let delivered = self.apps.enter(appid, |app, _| {
+            let end = app.app_read.as_mut().map_or(0, |read| {
+                let start = cmp::min(offset, read.len());
+                let len = cmp::min(read.len() - start, payload.len());
+                read.as_mut()[start..start + len].copy_from_slice(&payload[..len]);
+                start + len
+            });
+            app.response_callback.map(|mut cb| {
+                cb.schedule(
+                    usize::from(result),
+                    code as usize | (more as usize) << 8,
+                    end,
+                )
+            });
+        });
+        if delivered.is_err() && self.client_app.is_some() {
+            // Nobody is left to end the observation or block transfer.
+            self.client_app.clear();
+            self.coap.cancel();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for CoapDriver<'a, A> {
+    /// Setup buffers to read/write from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Read buffer. Receives the payload of requests and responses.
+    /// - `1`: Write buffer. Contains the payload of requests.
+    /// - `2`: Config buffer. Contains the path of a resource to register, or
+    ///        the destination address (16 bytes), port (2 bytes) and path
+    ///        of a request.
+    /// - `3 + n`: Representation of resource `n`. Its whole length is served.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self.do_with_app(appid, |app| {
+                app.app_read = slice;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(appid, |app| {
+                app.app_write = slice;
+                ReturnCode::SUCCESS
+            }),
+            2 => self.do_with_app(appid, |app| {
+                app.app_cfg = slice;
+                ReturnCode::SUCCESS
+            }),
+            n if n >= 3 && n < 3 + MAX_RESOURCES => self.do_with_app(appid, |app| {
+                app.representations[n - 3] = slice;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: POST, PUT or DELETE request for one of the process's resources.
+    ///        The arguments are the resource, the method and the length of
+    ///        the payload copied into the read buffer.
+    /// - `1`: Response to a request. The arguments are the result, the
+    ///        response code (with bit 8 set if more blocks follow) and the
+    ///        end offset of the payload in the read buffer.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(app_id, |app| {
+                app.request_callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(app_id, |app| {
+                app.response_callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// CoAP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register a resource at the path in the config buffer. Returns
+    ///        the resource number, EALREADY if the path is taken or ENOMEM
+    ///        if the process has no free resource.
+    /// - `2`: Unregister resource `arg1`.
+    /// - `3`: Notify observers of resource `arg1` of its new representation.
+    ///        Returns EINVAL if the process has not registered it.
+    /// - `4`: Send a request as configured in the config buffer, with the
+    ///        payload in the write buffer. `arg1` holds the method in bits
+    ///        0-7, bit 8 requests a confirmable message and bit 9 registers
+    ///        as an observer. Returns EBUSY if a request is in progress.
+    /// - `5`: End the current request or observation.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.do_with_app(appid, |app| self.register(app)),
+            2 => self.do_with_app(appid, |app| match app.paths.get_mut(arg1) {
+                Some(path) if path.is_some() => {
+                    *path = None;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::EINVAL,
+            }),
+            3 => {
+                let registered = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        app.paths.get(arg1).map_or(false, |path| path.is_some())
+                    })
+                    .unwrap_or(false);
+                if !registered {
+                    return ReturnCode::EINVAL;
+                }
+                // Notifying reads the representation, which enters the grant.
+                self.coap.notify(appid.id() * MAX_RESOURCES + arg1);
+                ReturnCode::SUCCESS
+            }
+            4 => {
+                self.release_exited_client();
+                self.do_with_app(appid, |app| self.send_request(appid, app, arg1))
+            }
+            5 => {
+                self.release_exited_client();
+                if self.client_app.map_or(false, |client| *client == appid) {
+                    self.client_app.clear();
+                    self.coap.cancel();
+                    ReturnCode::SUCCESS
+                } else {
+                    ReturnCode::EALREADY
+                }
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
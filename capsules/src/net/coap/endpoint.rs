@@ -0,0 +1,818 @@
+//! A CoAP endpoint (RFC 7252) acting as both client and server over UDP.
+//!
+//! `Coap` sits on a `UDPSender` bound to the CoAP port and receives datagrams
+//! through a `UDPReceiver` bound to the same port. It handles the message
+//! layer (message IDs, piggybacked and separate responses, acknowledgements,
+//! resets and retransmission of confirmable requests) and implements two
+//! extensions:
+//!
+//! - Observe (RFC 7641): a GET carrying `Observe: 0` registers the requester,
+//!   and `notify` sends the resource's new representation to every observer.
+//! - Block-wise transfer (RFC 7959): representations larger than one block
+//!   are served in `Block2` slices, and the client fetches the remaining
+//!   blocks of a response automatically.
+//!
+//! Resources are provided by a `CoapServerClient`, which maps request paths
+//! to resource numbers and produces representations on demand. The client
+//! side supports one exchange at a time; its responses, one block at a time,
+//! are delivered to a `CoapClient`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let coap_send = static_init!(
+//!     UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, Rtc>>>,
+//!     UDPSendStruct::new(udp_send_mux, udp_vis)
+//! );
+//! let coap_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+//! udp_recv_mux.add_client(coap_recv);
+//! let socket = udp_port_table.create_socket().unwrap();
+//! let (tx_bind, rx_bind) = udp_port_table
+//!     .bind(socket, capsules::net::coap::COAP_PORT, net_cap)
+//!     .ok()
+//!     .unwrap();
+//! coap_send.set_binding(tx_bind);
+//! coap_recv.set_binding(rx_bind);
+//!
+//! let coap = static_init!(
+//!     capsules::net::coap::Coap<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::net::coap::Coap::new(
+//!         coap_send,
+//!         coap_alarm,
+//!         net_cap,
+//!         LeasableBuffer::new(&mut COAP_TX_BUF),
+//!         &mut COAP_REQUEST_BUF,
+//!         &mut COAP_SCRATCH_BUF,
+//!     )
+//! );
+//! coap_send.set_client(coap);
+//! coap_recv.set_client(coap);
+//! coap_alarm.set_alarm_client(coap);
+//! ```
+
+use crate::net::coap::message::{
+    code, option, Block, Header, Message, MessageType, MessageWriter, Token,
+};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// The default UDP port for CoAP.
+pub const COAP_PORT: u16 = 5683;
+/// Initial retransmission timeout for confirmable requests.
+pub const ACK_TIMEOUT_MS: u32 = 2000;
+/// Number of retransmissions before a confirmable request fails.
+pub const MAX_RETRANSMIT: u8 = 4;
+/// Number of observers the server can track.
+pub const MAX_OBSERVERS: usize = 4;
+/// Longest request path the client supports.
+pub const MAX_PATH_LEN: usize = 32;
+
+const TOKEN_LEN: usize = 4;
+// Room reserved in the transmit buffer for the header, token and options
+// when choosing a block size.
+const BLOCK_OVERHEAD: usize = 32;
+const OBSERVE_REGISTER: u32 = 0;
+const OBSERVE_DEREGISTER: u32 = 1;
+
+/// Provides the resources served by a `Coap` endpoint.
+pub trait CoapServerClient {
+    /// Returns the resource addressed by the request's Uri-Path options, or
+    /// `None` if there is no such resource.
+    fn resource(&self, request: &Message) -> Option<usize>;
+
+    /// Writes the current representation of `resource` into `buf`, returning
+    /// its length, or the response code of an error.
+    fn get(&self, resource: usize, buf: &mut [u8]) -> Result<usize, u8>;
+
+    /// Handles a POST, PUT or DELETE request, returning the response code.
+    fn update(&self, resource: usize, method: u8, payload: &[u8]) -> u8;
+}
+
+/// Receives the responses to requests made with `Coap::request`.
+pub trait CoapClient {
+    /// Delivers one block of a response. `offset` is the position of
+    /// `payload` within the representation and `more` is set if further
+    /// blocks follow. Notifications for an observed resource are delivered
+    /// the same way.
+    ///
+    /// `result` is `ENOACK` if a confirmable request was never acknowledged
+    /// and `FAIL` if the server rejected it with a reset; `code` is then
+    /// `code::EMPTY`.
+    fn response(&self, result: ReturnCode, code: u8, offset: usize, payload: &[u8], more: bool);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Peer {
+    addr: IPAddr,
+    port: u16,
+}
+
+#[derive(Copy, Clone)]
+struct Observer {
+    peer: Peer,
+    token: Token,
+    resource: usize,
+    // Message ID of the last notification, to match resets.
+    message_id: u16,
+    pending: bool,
+}
+
+/// State of the client's exchange.
+#[derive(Copy, Clone)]
+struct Exchange {
+    peer: Peer,
+    token: Token,
+    path: [u8; MAX_PATH_LEN],
+    path_len: usize,
+    confirmable: bool,
+    observe: bool,
+    // Message ID of the request awaiting an acknowledgement, if any.
+    unacked: Option<u16>,
+    // Encoded length of the request in `request_buffer`.
+    len: usize,
+    attempts: u8,
+    timeout_ms: u32,
+    // Whether the request still has to be (re)transmitted.
+    send_pending: bool,
+}
+
+pub struct Coap<'a, A: Alarm<'a>> {
+    sender: &'a dyn UDPSender<'a>,
+    alarm: &'a A,
+    net_cap: &'static NetworkCapability,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    request_buffer: TakeCell<'static, [u8]>,
+    scratch: TakeCell<'static, [u8]>,
+    server: OptionalCell<&'a dyn CoapServerClient>,
+    client: OptionalCell<&'a dyn CoapClient>,
+
+    next_message_id: Cell<u16>,
+    next_token: Cell<u32>,
+    observe_seq: Cell<u32>,
+    observers: MapCell<[Option<Observer>; MAX_OBSERVERS]>,
+    exchange: MapCell<Exchange>,
+    // The last piggybacked response, still held in `tx_buffer`, so duplicate
+    // confirmable requests can be answered without reprocessing them.
+    last_response: Cell<Option<(Peer, u16, usize)>>,
+}
+
+impl<'a, A: Alarm<'a>> Coap<'a, A> {
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        alarm: &'a A,
+        net_cap: &'static NetworkCapability,
+        tx_buffer: LeasableBuffer<'static, u8>,
+        request_buffer: &'static mut [u8],
+        scratch: &'static mut [u8],
+    ) -> Coap<'a, A> {
+        Coap {
+            sender: sender,
+            alarm: alarm,
+            net_cap: net_cap,
+            tx_buffer: MapCell::new(tx_buffer),
+            request_buffer: TakeCell::new(request_buffer),
+            scratch: TakeCell::new(scratch),
+            server: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            next_message_id: Cell::new(1),
+            next_token: Cell::new(1),
+            observe_seq: Cell::new(2),
+            observers: MapCell::new([None; MAX_OBSERVERS]),
+            exchange: MapCell::empty(),
+            last_response: Cell::new(None),
+        }
+    }
+
+    pub fn set_server_client(&self, server: &'a dyn CoapServerClient) {
+        self.server.set(server);
+    }
+
+    pub fn set_client(&self, client: &'a dyn CoapClient) {
+        self.client.set(client);
+    }
+
+    /// Seeds message IDs and tokens, which should differ across reboots.
+    pub fn set_seed(&self, seed: u32) {
+        self.next_message_id.set(seed as u16);
+        self.next_token.set(seed);
+    }
+
+    fn message_id(&self) -> u16 {
+        let id = self.next_message_id.get();
+        self.next_message_id.set(id.wrapping_add(1));
+        id
+    }
+
+    fn token(&self) -> Token {
+        let token = self.next_token.get();
+        self.next_token.set(token.wrapping_add(1));
+        Token::new(&token.to_be_bytes()[..TOKEN_LEN]).unwrap_or_default()
+    }
+
+    /// Largest block size exponent that fits in the transmit buffer.
+    fn max_szx(&self) -> u8 {
+        let len = self.tx_buffer.map_or(0, |buf| buf.len());
+        Block::szx_for(len.saturating_sub(BLOCK_OVERHEAD))
+    }
+
+    // Encodes a message with `build` and sends it to `peer`, returning its
+    // length.
+    fn send<F>(&self, peer: Peer, build: F) -> Result<usize, ReturnCode>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, ReturnCode>,
+    {
+        let mut buf = self.tx_buffer.take().ok_or(ReturnCode::EBUSY)?;
+        self.last_response.set(None);
+        let len = match build(&mut buf[..]) {
+            Ok(len) => len,
+            Err(e) => {
+                self.tx_buffer.replace(buf);
+                return Err(e);
+            }
+        };
+        buf.slice(0..len);
+        match self.sender.send_to(peer.addr, peer.port, buf, self.net_cap) {
+            Ok(()) => Ok(len),
+            Err(mut buf) => {
+                buf.reset();
+                self.tx_buffer.replace(buf);
+                Err(ReturnCode::FAIL)
+            }
+        }
+    }
+
+    // Sends an empty ACK or RST for `message_id`.
+    fn send_empty(&self, peer: Peer, msg_type: MessageType, message_id: u16) {
+        let _ = self.send(peer, |buf| {
+            let header = Header {
+                msg_type: msg_type,
+                code: code::EMPTY,
+                message_id: message_id,
+                token: Token::empty(),
+            };
+            MessageWriter::new(buf, &header).map(|writer| writer.len())
+        });
+    }
+
+    // Encodes a GET or other request for the current exchange into
+    // `request_buffer`, asking for block `block` of the response.
+    fn encode_request(
+        &self,
+        exchange: &mut Exchange,
+        method: u8,
+        payload: &[u8],
+        block: Option<Block>,
+    ) -> ReturnCode {
+        let message_id = self.message_id();
+        let observe = exchange.observe && block.is_none();
+        let rval = self.request_buffer.map_or(ReturnCode::ENOMEM, |buf| {
+            let header = Header {
+                msg_type: if exchange.confirmable {
+                    MessageType::Confirmable
+                } else {
+                    MessageType::NonConfirmable
+                },
+                code: method,
+                message_id: message_id,
+                token: exchange.token,
+            };
+            let mut writer = match MessageWriter::new(buf, &header) {
+                Ok(writer) => writer,
+                Err(e) => return e,
+            };
+            if observe {
+                let rval = writer.option_uint(option::OBSERVE, OBSERVE_REGISTER);
+                if rval != ReturnCode::SUCCESS {
+                    return rval;
+                }
+            }
+            let rval = writer.uri_path(&exchange.path[..exchange.path_len]);
+            if rval != ReturnCode::SUCCESS {
+                return rval;
+            }
+            if let Some(block) = block {
+                let rval = writer.option_uint(option::BLOCK2, block.value());
+                if rval != ReturnCode::SUCCESS {
+                    return rval;
+                }
+            }
+            let rval = writer.payload(payload);
+            exchange.len = writer.len();
+            rval
+        });
+        exchange.unacked = if exchange.confirmable {
+            Some(message_id)
+        } else {
+            None
+        };
+        exchange.attempts = 0;
+        // Randomize the initial timeout between ACK_TIMEOUT and 1.5 times
+        // ACK_TIMEOUT, as RFC 7252 requires, using the message ID as the
+        // source of variation.
+        exchange.timeout_ms = ACK_TIMEOUT_MS + (message_id as u32 % (ACK_TIMEOUT_MS / 2));
+        exchange.send_pending = true;
+        rval
+    }
+
+    // Sends the request in `request_buffer` if the transmit buffer is free.
+    // Otherwise it is sent from `send_done`.
+    fn transmit_request(&self, exchange: &mut Exchange) {
+        if !exchange.send_pending || !self.tx_buffer.is_some() {
+            return;
+        }
+        let len = exchange.len;
+        let sent = self.request_buffer.map_or(false, |request| {
+            self.send(exchange.peer, |buf| {
+                if buf.len() < len {
+                    return Err(ReturnCode::ESIZE);
+                }
+                buf[..len].copy_from_slice(&request[..len]);
+                Ok(len)
+            })
+            .is_ok()
+        });
+        if sent {
+            exchange.send_pending = false;
+            if exchange.unacked.is_some() {
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(exchange.timeout_ms));
+            }
+        }
+    }
+
+    /// Sends a request to `path` on the server at `addr` and `port`. The
+    /// response is delivered to the `CoapClient`, block by block if it is
+    /// large. If `observe` is set, the client registers for notifications,
+    /// which are delivered until `cancel` is called. Only one exchange can be
+    /// active at a time; otherwise `EBUSY` is returned.
+    pub fn request(
+        &self,
+        addr: IPAddr,
+        port: u16,
+        method: u8,
+        path: &[u8],
+        payload: &[u8],
+        confirmable: bool,
+        observe: bool,
+    ) -> ReturnCode {
+        if self.exchange.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if !code::is_request(method) || path.len() > MAX_PATH_LEN {
+            return ReturnCode::EINVAL;
+        }
+        if observe && method != code::GET {
+            return ReturnCode::EINVAL;
+        }
+        let mut exchange = Exchange {
+            peer: Peer {
+                addr: addr,
+                port: port,
+            },
+            token: self.token(),
+            path: [0; MAX_PATH_LEN],
+            path_len: path.len(),
+            confirmable: confirmable,
+            observe: observe,
+            unacked: None,
+            len: 0,
+            attempts: 0,
+            timeout_ms: 0,
+            send_pending: false,
+        };
+        exchange.path[..path.len()].copy_from_slice(path);
+        let rval = self.encode_request(&mut exchange, method, payload, None);
+        if rval != ReturnCode::SUCCESS {
+            return rval;
+        }
+        self.transmit_request(&mut exchange);
+        self.exchange.put(exchange);
+        ReturnCode::SUCCESS
+    }
+
+    /// Whether no client exchange or observation is in progress.
+    pub fn is_idle(&self) -> bool {
+        self.exchange.is_none()
+    }
+
+    /// Ends the client's exchange, including any observation. Notifications
+    /// that still arrive are rejected, which deregisters the client.
+    pub fn cancel(&self) {
+        if self.exchange.take().is_some() {
+            self.alarm.disarm();
+        }
+    }
+
+    // Ends the exchange and reports `result` with no payload.
+    fn fail_exchange(&self, result: ReturnCode) {
+        self.cancel();
+        self.client
+            .map(|client| client.response(result, code::EMPTY, 0, &[], false));
+    }
+
+    /// Sends the current representation of `resource` to all of its
+    /// observers.
+    pub fn notify(&self, resource: usize) {
+        self.observe_seq
+            .set((self.observe_seq.get() + 1) & 0x00ff_ffff);
+        self.observers.map(|observers| {
+            for observer in observers.iter_mut().flatten() {
+                if observer.resource == resource {
+                    observer.pending = true;
+                }
+            }
+        });
+        self.send_pending_notification();
+    }
+
+    // Sends the first pending notification if the transmit buffer is free.
+    fn send_pending_notification(&self) {
+        if !self.tx_buffer.is_some() {
+            return;
+        }
+        let next = self.observers.map_or(None, |observers| {
+            observers
+                .iter()
+                .position(|o| o.map_or(false, |o| o.pending))
+        });
+        let index = match next {
+            Some(index) => index,
+            None => return,
+        };
+        let observer = match self.observers.map_or(None, |observers| {
+            observers[index].as_mut().map(|o| {
+                o.pending = false;
+                *o
+            })
+        }) {
+            Some(observer) => observer,
+            None => return,
+        };
+
+        let message_id = self.message_id();
+        let header = Header {
+            msg_type: MessageType::NonConfirmable,
+            code: code::CONTENT,
+            message_id: message_id,
+            token: observer.token,
+        };
+        let sent = self.respond_get(observer.peer, header, observer.resource, None, true);
+        match sent {
+            Ok(_) => {
+                self.observers.map(|observers| {
+                    observers[index].as_mut().map(|o| o.message_id = message_id);
+                });
+            }
+            Err(ReturnCode::EBUSY) => {
+                self.observers.map(|observers| {
+                    observers[index].as_mut().map(|o| o.pending = true);
+                });
+            }
+            Err(_) => {
+                // The representation can no longer be produced.
+                self.observers.map(|observers| observers[index] = None);
+            }
+        }
+    }
+
+    // Registers or refreshes an observer, returning whether it is registered.
+    fn add_observer(&self, peer: Peer, token: Token, resource: usize) -> bool {
+        self.observers.map_or(false, |observers| {
+            let existing = observers
+                .iter()
+                .position(|o| o.map_or(false, |o| o.peer == peer && o.resource == resource));
+            let slot = existing.or_else(|| observers.iter().position(|o| o.is_none()));
+            slot.map_or(false, |slot| {
+                observers[slot] = Some(Observer {
+                    peer: peer,
+                    token: token,
+                    resource: resource,
+                    message_id: 0,
+                    pending: false,
+                });
+                true
+            })
+        })
+    }
+
+    fn remove_observers<F: Fn(&Observer) -> bool>(&self, matches: F) {
+        self.observers.map(|observers| {
+            for slot in observers.iter_mut() {
+                if slot.as_ref().map_or(false, |o| matches(o)) {
+                    *slot = None;
+                }
+            }
+        });
+    }
+
+    // Sends a response carrying the representation of `resource`, sliced to
+    // the block requested in `block`, if any. Returns the message length.
+    fn respond_get(
+        &self,
+        peer: Peer,
+        header: Header,
+        resource: usize,
+        block: Option<Block>,
+        observing: bool,
+    ) -> Result<usize, ReturnCode> {
+        let server = self
+            .server
+            .map_or(None, |server| Some(*server))
+            .ok_or(ReturnCode::FAIL)?;
+        let max_szx = self.max_szx();
+        let observe_seq = self.observe_seq.get();
+        self.scratch.map_or(Err(ReturnCode::ENOMEM), |scratch| {
+            let result = server.get(resource, scratch);
+            self.send(peer, |buf| {
+                let mut header = header;
+                let representation = match result {
+                    Ok(len) => &scratch[..len],
+                    Err(error) => {
+                        header.code = error;
+                        &scratch[..0]
+                    }
+                };
+                let mut writer = MessageWriter::new(buf, &header)?;
+                if observing && result.is_ok() {
+                    check(writer.option_uint(option::OBSERVE, observe_seq))?;
+                }
+                let block = block.or_else(|| {
+                    if representation.len() > writer.remaining() {
+                        Some(Block {
+                            num: 0,
+                            more: false,
+                            szx: max_szx,
+                        })
+                    } else {
+                        None
+                    }
+                });
+                let payload = match block {
+                    Some(block) => {
+                        let szx = core::cmp::min(block.szx, max_szx);
+                        let block = Block {
+                            num: (block.offset() >> (4 + szx)) as u32,
+                            more: false,
+                            szx: szx,
+                        };
+                        let start = block.offset();
+                        if start > representation.len() {
+                            return Err(ReturnCode::EINVAL);
+                        }
+                        let end = core::cmp::min(start + block.size(), representation.len());
+                        let block = Block {
+                            more: end < representation.len(),
+                            ..block
+                        };
+                        check(writer.option_uint(option::BLOCK2, block.value()))?;
+                        &representation[start..end]
+                    }
+                    None => representation,
+                };
+                check(writer.payload(payload))?;
+                Ok(writer.len())
+            })
+        })
+    }
+
+    // Handles an incoming request for the server.
+    fn handle_request(&self, peer: Peer, request: &Message) {
+        let header = request.header;
+        if header.msg_type == MessageType::Confirmable {
+            if let Some((last_peer, last_id, len)) = self.last_response.get() {
+                if last_peer == peer && last_id == header.message_id {
+                    // Duplicate: resend the response still in the buffer.
+                    let _ = self.send(peer, |_| Ok(len));
+                    self.last_response.set(Some((peer, header.message_id, len)));
+                    return;
+                }
+            }
+        }
+
+        let response = Header {
+            msg_type: if header.msg_type == MessageType::Confirmable {
+                MessageType::Acknowledgement
+            } else {
+                MessageType::NonConfirmable
+            },
+            code: code::CONTENT,
+            message_id: if header.msg_type == MessageType::Confirmable {
+                header.message_id
+            } else {
+                self.message_id()
+            },
+            token: header.token,
+        };
+        let resource = self.server.map_or(None, |server| server.resource(request));
+
+        let result = match resource {
+            None => self.respond_code(peer, response, code::NOT_FOUND),
+            Some(resource) if header.code == code::GET => {
+                let observing = match request.option_uint(option::OBSERVE) {
+                    Some(OBSERVE_REGISTER) => self.add_observer(peer, header.token, resource),
+                    Some(OBSERVE_DEREGISTER) => {
+                        self.remove_observers(|o| o.peer == peer && o.token == header.token);
+                        false
+                    }
+                    _ => false,
+                };
+                match request.option(option::BLOCK2) {
+                    Some(_) if request.block2().is_none() => {
+                        self.respond_code(peer, response, code::BAD_OPTION)
+                    }
+                    _ => self
+                        .respond_get(peer, response, resource, request.block2(), observing)
+                        .or_else(|e| match e {
+                            ReturnCode::EINVAL => {
+                                self.respond_code(peer, response, code::BAD_OPTION)
+                            }
+                            e => Err(e),
+                        }),
+                }
+            }
+            Some(resource) => {
+                let code = self.server.map_or(code::INTERNAL_SERVER_ERROR, |server| {
+                    server.update(resource, header.code, request.payload)
+                });
+                self.respond_code(peer, response, code)
+            }
+        };
+
+        if let Ok(len) = result {
+            if header.msg_type == MessageType::Confirmable {
+                self.last_response.set(Some((peer, header.message_id, len)));
+            }
+        }
+    }
+
+    fn respond_code(&self, peer: Peer, header: Header, code: u8) -> Result<usize, ReturnCode> {
+        self.send(peer, |buf| {
+            let header = Header {
+                code: code,
+                ..header
+            };
+            MessageWriter::new(buf, &header).map(|writer| writer.len())
+        })
+    }
+
+    // Handles a response or empty message for the client, or a reset of a
+    // notification.
+    fn handle_response(&self, peer: Peer, msg: &Message) {
+        let header = msg.header;
+
+        // Acknowledgements and resets refer to a message ID.
+        if header.msg_type == MessageType::Acknowledgement || header.msg_type == MessageType::Reset
+        {
+            let acked = self.exchange.map_or(false, |exchange| {
+                if exchange.peer == peer && exchange.unacked == Some(header.message_id) {
+                    exchange.unacked = None;
+                    true
+                } else {
+                    false
+                }
+            });
+            if acked {
+                self.alarm.disarm();
+                if header.msg_type == MessageType::Reset {
+                    self.fail_exchange(ReturnCode::FAIL);
+                    return;
+                }
+            } else if header.msg_type == MessageType::Reset {
+                self.remove_observers(|o| o.peer == peer && o.message_id == header.message_id);
+                return;
+            }
+            if header.code == code::EMPTY {
+                // A separate response follows.
+                return;
+            }
+        }
+
+        let matches = self.exchange.map_or(false, |exchange| {
+            exchange.peer.addr == peer.addr && exchange.token == header.token
+        });
+        if !matches {
+            if header.msg_type == MessageType::Confirmable
+                || header.msg_type == MessageType::NonConfirmable
+            {
+                self.send_empty(peer, MessageType::Reset, header.message_id);
+            }
+            return;
+        }
+        if header.msg_type == MessageType::Confirmable {
+            self.send_empty(peer, MessageType::Acknowledgement, header.message_id);
+        }
+
+        let observing = self.exchange.map_or(false, |exchange| exchange.observe)
+            && msg.option(option::OBSERVE).is_some();
+        let block = msg.block2();
+        let (offset, more) = block.map_or((0, false), |b| (b.offset(), b.more));
+
+        if more {
+            // Fetch the next block before handing this one to the client, so
+            // that the request buffer is free again when it returns.
+            let next = block.map(|b| Block {
+                num: b.num + 1,
+                more: false,
+                szx: b.szx,
+            });
+            let rval = self.exchange.map_or(ReturnCode::FAIL, |exchange| {
+                let rval = self.encode_request(exchange, code::GET, &[], next);
+                self.transmit_request(exchange);
+                rval
+            });
+            if rval != ReturnCode::SUCCESS {
+                self.cancel();
+            }
+        } else if !observing {
+            self.cancel();
+        } else {
+            self.exchange.map(|exchange| exchange.observe = true);
+        }
+
+        self.client.map(|client| {
+            client.response(ReturnCode::SUCCESS, header.code, offset, msg.payload, more)
+        });
+    }
+}
+
+fn check(rval: ReturnCode) -> Result<(), ReturnCode> {
+    match rval {
+        ReturnCode::SUCCESS => Ok(()),
+        e => Err(e),
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Coap<'a, A> {
+    fn alarm(&self) {
+        let failed = self.exchange.map_or(false, |exchange| {
+            if exchange.unacked.is_none() {
+                return false;
+            }
+            if exchange.attempts >= MAX_RETRANSMIT {
+                return true;
+            }
+            exchange.attempts += 1;
+            exchange.timeout_ms *= 2;
+            exchange.send_pending = true;
+            self.transmit_request(exchange);
+            false
+        });
+        if failed {
+            self.fail_exchange(ReturnCode::ENOACK);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for Coap<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+        self.exchange
+            .map(|exchange| self.transmit_request(exchange));
+        self.send_pending_notification();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for Coap<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let peer = Peer {
+            addr: src_addr,
+            port: src_port,
+        };
+        let msg = match Message::decode(payload) {
+            Some(msg) => msg,
+            None => return,
+        };
+        if code::is_request(msg.header.code) {
+            match msg.header.msg_type {
+                MessageType::Confirmable | MessageType::NonConfirmable => {
+                    self.handle_request(peer, &msg)
+                }
+                _ => {}
+            }
+        } else if msg.header.code == code::EMPTY && msg.header.msg_type == MessageType::Confirmable
+        {
+            // CoAP ping.
+            self.send_empty(peer, MessageType::Reset, msg.header.message_id);
+        } else {
+            self.handle_response(peer, &msg);
+        }
+    }
+}
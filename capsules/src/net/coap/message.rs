@@ -0,0 +1,517 @@
+//! Encoding and decoding of CoAP messages (RFC 7252).
+//!
+//! `MessageWriter` serializes a message into a caller-provided buffer.
+//! Options must be added in increasing order of option number, as CoAP
+//! encodes each option number as a delta from the previous one.
+//! `Message::decode` validates a received datagram and provides access to its
+//! header, options and payload without copying.
+
+use kernel::ReturnCode;
+
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 4;
+pub const MAX_TOKEN_LEN: usize = 8;
+pub const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Message codes, written as `class << 5 | detail`.
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8d;
+
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+    pub fn is_request(code: u8) -> bool {
+        code >= GET && code <= DELETE
+    }
+
+    pub fn is_response(code: u8) -> bool {
+        code >= CREATED
+    }
+}
+
+/// Option numbers.
+pub mod option {
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const BLOCK2: u16 = 23;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> MessageType {
+        match bits & 0b11 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Token {
+    len: u8,
+    bytes: [u8; MAX_TOKEN_LEN],
+}
+
+impl Token {
+    pub fn empty() -> Token {
+        Token::default()
+    }
+
+    /// Creates a token from at most `MAX_TOKEN_LEN` bytes.
+    pub fn new(bytes: &[u8]) -> Option<Token> {
+        if bytes.len() > MAX_TOKEN_LEN {
+            return None;
+        }
+        let mut token = Token {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_TOKEN_LEN],
+        };
+        token.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(token)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub msg_type: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Token,
+}
+
+/// The value of a Block1 or Block2 option (RFC 7959).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    /// Size exponent: blocks are `16 << szx` bytes long.
+    pub szx: u8,
+}
+
+impl Block {
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    pub fn from_value(value: u32) -> Option<Block> {
+        let szx = (value & 0x7) as u8;
+        if szx > Block::MAX_SZX {
+            return None;
+        }
+        Some(Block {
+            num: value >> 4,
+            more: value & 0x8 != 0,
+            szx: szx,
+        })
+    }
+
+    pub fn value(&self) -> u32 {
+        self.num << 4 | (self.more as u32) << 3 | self.szx as u32
+    }
+
+    /// The largest size exponent whose blocks fit in `len` bytes.
+    pub fn szx_for(len: usize) -> u8 {
+        let mut szx = Block::MAX_SZX;
+        while szx > 0 && (16 << szx) > len {
+            szx -= 1;
+        }
+        szx
+    }
+}
+
+// Extended encoding of option deltas and lengths.
+fn nibble(value: u16) -> (u8, usize) {
+    if value < 13 {
+        (value as u8, 0)
+    } else if value < 269 {
+        (13, 1)
+    } else {
+        (14, 2)
+    }
+}
+
+fn write_extended(buf: &mut [u8], value: u16, extra: usize) {
+    match extra {
+        1 => buf[0] = (value - 13) as u8,
+        2 => buf[..2].copy_from_slice(&(value - 269).to_be_bytes()),
+        _ => {}
+    }
+}
+
+fn read_extended(buf: &[u8], nibble: u8) -> Option<(u16, usize)> {
+    match nibble {
+        13 => buf.get(0).map(|&b| (b as u16 + 13, 1)),
+        14 => {
+            if buf.len() < 2 {
+                return None;
+            }
+            u16::from_be_bytes([buf[0], buf[1]])
+                .checked_add(269)
+                .map(|value| (value, 2))
+        }
+        15 => None,
+        n => Some((n as u16, 0)),
+    }
+}
+
+/// Returns `value` as a minimal-length big-endian unsigned integer option.
+fn uint_bytes(value: u32) -> ([u8; 4], usize) {
+    let bytes = value.to_be_bytes();
+    let len = 4 - (value.leading_zeros() / 8) as usize;
+    (bytes, len)
+}
+
+/// Serializes a message into a buffer.
+pub struct MessageWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    last_option: u16,
+    has_payload: bool,
+}
+
+impl<'b> MessageWriter<'b> {
+    /// Writes the fixed header and token. Fails with `ESIZE` if they do not
+    /// fit in `buf`.
+    pub fn new(buf: &'b mut [u8], header: &Header) -> Result<MessageWriter<'b>, ReturnCode> {
+        let token = header.token.as_slice();
+        if buf.len() < HEADER_LEN + token.len() {
+            return Err(ReturnCode::ESIZE);
+        }
+        buf[0] = VERSION << 6 | (header.msg_type as u8) << 4 | token.len() as u8;
+        buf[1] = header.code;
+        buf[2..4].copy_from_slice(&header.message_id.to_be_bytes());
+        buf[HEADER_LEN..HEADER_LEN + token.len()].copy_from_slice(token);
+        Ok(MessageWriter {
+            buf: buf,
+            len: HEADER_LEN + token.len(),
+            last_option: 0,
+            has_payload: false,
+        })
+    }
+
+    /// Appends an option. Returns `EINVAL` if `number` is lower than that of
+    /// the previous option or a payload was already added, and `ESIZE` if the
+    /// option does not fit.
+    pub fn option(&mut self, number: u16, value: &[u8]) -> ReturnCode {
+        if number < self.last_option || self.has_payload || value.len() > u16::MAX as usize {
+            return ReturnCode::EINVAL;
+        }
+        let delta = number - self.last_option;
+        let (delta_nibble, delta_extra) = nibble(delta);
+        let (len_nibble, len_extra) = nibble(value.len() as u16);
+        let total = 1 + delta_extra + len_extra + value.len();
+        if self.len + total > self.buf.len() {
+            return ReturnCode::ESIZE;
+        }
+
+        let mut off = self.len;
+        self.buf[off] = delta_nibble << 4 | len_nibble;
+        off += 1;
+        write_extended(&mut self.buf[off..], delta, delta_extra);
+        off += delta_extra;
+        write_extended(&mut self.buf[off..], value.len() as u16, len_extra);
+        off += len_extra;
+        self.buf[off..off + value.len()].copy_from_slice(value);
+
+        self.len += total;
+        self.last_option = number;
+        ReturnCode::SUCCESS
+    }
+
+    /// Appends an option holding an unsigned integer.
+    pub fn option_uint(&mut self, number: u16, value: u32) -> ReturnCode {
+        let (bytes, len) = uint_bytes(value);
+        self.option(number, &bytes[4 - len..])
+    }
+
+    /// Appends one Uri-Path option per '/'-separated segment of `path`.
+    pub fn uri_path(&mut self, path: &[u8]) -> ReturnCode {
+        for segment in path.split(|&b| b == b'/').filter(|s| !s.is_empty()) {
+            let rval = self.option(option::URI_PATH, segment);
+            if rval != ReturnCode::SUCCESS {
+                return rval;
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Appends the payload. Must be called at most once, after all options.
+    pub fn payload(&mut self, payload: &[u8]) -> ReturnCode {
+        if self.has_payload {
+            return ReturnCode::EINVAL;
+        }
+        if payload.is_empty() {
+            return ReturnCode::SUCCESS;
+        }
+        if self.len + 1 + payload.len() > self.buf.len() {
+            return ReturnCode::ESIZE;
+        }
+        self.buf[self.len] = PAYLOAD_MARKER;
+        self.buf[self.len + 1..self.len + 1 + payload.len()].copy_from_slice(payload);
+        self.len += 1 + payload.len();
+        self.has_payload = true;
+        ReturnCode::SUCCESS
+    }
+
+    /// Space left for a payload, accounting for the payload marker.
+    pub fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.len + 1)
+    }
+
+    /// Length of the encoded message.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// A decoded message borrowing from the received datagram.
+#[derive(Copy, Clone, Debug)]
+pub struct Message<'b> {
+    pub header: Header,
+    options: &'b [u8],
+    pub payload: &'b [u8],
+}
+
+impl<'b> Message<'b> {
+    /// Parses `buf`, returning `None` if it is not a well-formed message.
+    pub fn decode(buf: &'b [u8]) -> Option<Message<'b>> {
+        if buf.len() < HEADER_LEN || buf[0] >> 6 != VERSION {
+            return None;
+        }
+        let token_len = (buf[0] & 0x0f) as usize;
+        if token_len > MAX_TOKEN_LEN || buf.len() < HEADER_LEN + token_len {
+            return None;
+        }
+        let header = Header {
+            msg_type: MessageType::from_bits(buf[0] >> 4),
+            code: buf[1],
+            message_id: u16::from_be_bytes([buf[2], buf[3]]),
+            token: Token::new(&buf[HEADER_LEN..HEADER_LEN + token_len])?,
+        };
+
+        // Walk the options to find where they end.
+        let rest = &buf[HEADER_LEN + token_len..];
+        let mut iter = Options {
+            buf: rest,
+            number: 0,
+            error: false,
+        };
+        while iter.next().is_some() {}
+        if iter.error {
+            return None;
+        }
+        let options_len = rest.len() - iter.buf.len();
+        let payload = match iter.buf.split_first() {
+            None => &iter.buf[..0],
+            Some((&PAYLOAD_MARKER, payload)) if !payload.is_empty() => payload,
+            Some(_) => return None,
+        };
+
+        Some(Message {
+            header: header,
+            options: &rest[..options_len],
+            payload: payload,
+        })
+    }
+
+    /// Iterates over `(number, value)` pairs of all options.
+    pub fn options(&self) -> Options<'b> {
+        Options {
+            buf: self.options,
+            number: 0,
+            error: false,
+        }
+    }
+
+    /// The value of the first option with the given number.
+    pub fn option(&self, number: u16) -> Option<&'b [u8]> {
+        self.options()
+            .find(|&(n, _)| n == number)
+            .map(|(_, value)| value)
+    }
+
+    /// The value of the first option with the given number, as an unsigned
+    /// integer. Values longer than four bytes are rejected.
+    pub fn option_uint(&self, number: u16) -> Option<u32> {
+        self.option(number).and_then(|value| {
+            if value.len() > 4 {
+                None
+            } else {
+                Some(value.iter().fold(0, |acc, &b| acc << 8 | b as u32))
+            }
+        })
+    }
+
+    pub fn block2(&self) -> Option<Block> {
+        self.option_uint(option::BLOCK2).and_then(Block::from_value)
+    }
+
+    /// Whether the Uri-Path options, joined with '/', equal `path`. Leading
+    /// and trailing slashes in `path` are ignored.
+    pub fn path_matches(&self, path: &[u8]) -> bool {
+        let mut segments = path.split(|&b| b == b'/').filter(|s| !s.is_empty());
+        for (number, value) in self.options() {
+            if number != option::URI_PATH {
+                continue;
+            }
+            match segments.next() {
+                Some(segment) if segment == value => {}
+                _ => return false,
+            }
+        }
+        segments.next().is_none()
+    }
+}
+
+/// Iterator over the options of a `Message`.
+pub struct Options<'b> {
+    buf: &'b [u8],
+    number: u16,
+    error: bool,
+}
+
+impl<'b> Iterator for Options<'b> {
+    type Item = (u16, &'b [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'b [u8])> {
+        let (&first, rest) = self.buf.split_first()?;
+        if first == PAYLOAD_MARKER {
+            return None;
+        }
+        let parsed = read_extended(rest, first >> 4).and_then(|(delta, delta_extra)| {
+            read_extended(&rest[delta_extra..], first & 0x0f)
+                .map(|(len, len_extra)| (delta, len as usize, delta_extra + len_extra))
+        });
+        match parsed {
+            Some((delta, len, extra)) if rest.len() >= extra + len => {
+                let number = self.number.checked_add(delta)?;
+                let value = &rest[extra..extra + len];
+                self.number = number;
+                self.buf = &rest[extra + len..];
+                Some((number, value))
+            }
+            _ => {
+                self.error = true;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(msg_type: MessageType, code: u8, message_id: u16, token: &[u8]) -> Header {
+        Header {
+            msg_type: msg_type,
+            code: code,
+            message_id: message_id,
+            token: Token::new(token).unwrap(),
+        }
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let mut buf = [0; 64];
+        let hdr = header(MessageType::Confirmable, code::GET, 0x1234, &[0xab, 0xcd]);
+        let mut writer = MessageWriter::new(&mut buf, &hdr).unwrap();
+        assert_eq!(writer.option_uint(option::OBSERVE, 0), ReturnCode::SUCCESS);
+        assert_eq!(
+            writer.uri_path(b"/sensors/temperature"),
+            ReturnCode::SUCCESS
+        );
+        let block = Block {
+            num: 20,
+            more: false,
+            szx: 2,
+        };
+        assert_eq!(
+            writer.option_uint(option::BLOCK2, block.value()),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(writer.option(option::URI_PATH, b"x"), ReturnCode::EINVAL);
+        assert_eq!(writer.payload(b"hi"), ReturnCode::SUCCESS);
+        let len = writer.len();
+
+        let msg = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(msg.header, hdr);
+        assert_eq!(msg.option_uint(option::OBSERVE), Some(0));
+        assert!(msg.path_matches(b"sensors/temperature"));
+        assert!(!msg.path_matches(b"sensors"));
+        assert!(!msg.path_matches(b"sensors/temperature/x"));
+        assert_eq!(msg.block2(), Some(block));
+        assert_eq!(msg.payload, b"hi");
+    }
+
+    #[test]
+    fn rfc7252_example() {
+        // GET with a Uri-Path of "temperature" and a 1-byte option delta of
+        // 11, as in RFC 7252 Figure 16.
+        let mut datagram = [0x40, 0x01, 0x7d, 0x34, 0xbb];
+        let mut full = [0; 16];
+        full[..5].copy_from_slice(&datagram);
+        full[5..16].copy_from_slice(b"temperature");
+        let msg = Message::decode(&full).unwrap();
+        assert_eq!(msg.header.msg_type, MessageType::Confirmable);
+        assert_eq!(msg.header.message_id, 0x7d34);
+        assert!(msg.path_matches(b"temperature"));
+
+        // Truncated option value.
+        datagram[4] = 0xbf;
+        assert!(Message::decode(&datagram).is_none());
+    }
+
+    #[test]
+    fn extended_option_numbers() {
+        let mut buf = [0; 600];
+        let hdr = header(MessageType::NonConfirmable, code::CONTENT, 1, &[]);
+        let long = [0x55; 300];
+        let mut writer = MessageWriter::new(&mut buf, &hdr).unwrap();
+        assert_eq!(writer.option(20, &[1]), ReturnCode::SUCCESS);
+        assert_eq!(writer.option(400, &long), ReturnCode::SUCCESS);
+        let len = writer.len();
+
+        let msg = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(msg.option(20), Some(&[1][..]));
+        assert_eq!(msg.option(400), Some(&long[..]));
+        assert!(msg.payload.is_empty());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod driver;
+pub mod endpoint;
+pub mod message;
+
+pub use self::endpoint::{Coap, CoapClient, CoapServerClient, COAP_PORT};
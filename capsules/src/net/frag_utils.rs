@@ -40,6 +40,9 @@ impl Bitmap {
         if start_idx > end_idx {
             return false;
         }
+        if start_idx == end_idx {
+            return true;
+        }
         let start_byte_idx = start_idx / 8;
         let end_byte_idx = end_idx / 8;
         let first = 0xff << (start_idx % 8);
@@ -54,9 +57,13 @@ impl Bitmap {
             result
         } else {
             let mut result = (self.map[start_byte_idx] & first) == 0;
-            result = result && ((self.map[end_byte_idx] & second) == 0);
             self.map[start_byte_idx] |= first;
-            self.map[end_byte_idx] |= second;
+            // A range ending on a byte boundary doesn't touch the next byte,
+            // which is past the end of the map for a full size datagram.
+            if end_idx % 8 != 0 {
+                result = result && ((self.map[end_byte_idx] & second) == 0);
+                self.map[end_byte_idx] |= second;
+            }
             // Set all bytes between start and end bytes.
             for i in start_byte_idx + 1..end_byte_idx {
                 result = result && (self.map[i] == 0);
@@ -71,9 +78,35 @@ impl Bitmap {
         for i in 0..total_length / 8 {
             result = result && (self.map[i] == 0xff);
         }
-        // Check last byte.
-        let mask = 0xff >> (8 - (total_length % 8));
-        result = result && (self.map[total_length / 8] == mask);
+        // Check the last, partial byte. A length that fills whole bytes has
+        // none, and may cover the whole map.
+        if total_length % 8 != 0 {
+            let mask = 0xff >> (8 - (total_length % 8));
+            result = result && (self.map[total_length / 8] == mask);
+        }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_on_partial_byte() {
+        let mut bitmap = Bitmap::new();
+        assert!(bitmap.set_bits(0, 5));
+        assert!(!bitmap.is_complete(11));
+        assert!(bitmap.set_bits(5, 11));
+        assert!(bitmap.is_complete(11));
+    }
+
+    #[test]
+    fn complete_on_byte_boundary() {
+        let mut bitmap = Bitmap::new();
+        assert!(bitmap.set_bits(0, 8));
+        assert!(!bitmap.is_complete(16));
+        assert!(bitmap.set_bits(8, 16));
+        assert!(bitmap.is_complete(16));
+    }
+}
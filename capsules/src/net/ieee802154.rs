@@ -307,7 +307,7 @@ impl Security {
         let asn_in_nonce = (scf & security_control::ASN_IN_NONCE) != 0;
 
         // Frame counter field
-        let frame_counter_present = (scf & security_control::FRAME_COUNTER_SUPPRESSION) != 0;
+        let frame_counter_present = (scf & security_control::FRAME_COUNTER_SUPPRESSION) == 0;
         let (off, frame_counter) = if frame_counter_present {
             let (off, frame_counter_be) = dec_try!(buf, off; decode_u32);
             (off, Some(u32::from_be(frame_counter_be)))
@@ -857,3 +857,45 @@ impl Header<'_> {
         stream_done!(off, (dst_pan, dst_addr, src_pan, src_addr));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(security: Security) -> Security {
+        let mut buf = [0u8; 16];
+        let len = match security.encode(&mut buf) {
+            SResult::Done(len, ()) => len,
+            _ => panic!("failed to encode the auxiliary security header"),
+        };
+        match Security::decode(&buf[..len]) {
+            SResult::Done(off, decoded) => {
+                assert_eq!(off, len);
+                decoded
+            }
+            _ => panic!("failed to decode the auxiliary security header"),
+        }
+    }
+
+    #[test]
+    fn security_header_with_frame_counter() {
+        let security = Security {
+            level: SecurityLevel::EncMic32,
+            asn_in_nonce: false,
+            frame_counter: Some(0x01020304),
+            key_id: KeyId::Index(5),
+        };
+        assert_eq!(round_trip(security), security);
+    }
+
+    #[test]
+    fn security_header_with_suppressed_frame_counter() {
+        let security = Security {
+            level: SecurityLevel::Mic64,
+            asn_in_nonce: true,
+            frame_counter: None,
+            key_id: KeyId::Implicit,
+        };
+        assert_eq!(round_trip(security), security);
+    }
+}
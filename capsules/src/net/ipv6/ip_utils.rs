@@ -113,6 +113,13 @@ impl IPAddr {
     }
 }
 
+/// The unicast addresses currently assigned to a network interface, for
+/// interfaces whose addresses change at runtime.
+pub trait InterfaceAddresses {
+    /// Returns the address at `index`, or `None` past the last address.
+    fn address(&self, index: usize) -> Option<IPAddr>;
+}
+
 pub fn compute_udp_checksum(
     ip6_header: &IP6Header,
     udp_header: &UDPHeader,
@@ -224,6 +231,26 @@ pub fn compute_icmp_checksum(
     sum as u16
 }
 
+/// Verifies the checksum of a complete ICMPv6 message, of any type, received
+/// with `ip6_header`. Returns 0 if the checksum is correct.
+pub fn verify_icmp_checksum(ip6_header: &IP6Header, message: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for i in (0..16).step_by(2) {
+        sum += u16::from_be_bytes([ip6_header.src_addr.0[i], ip6_header.src_addr.0[i + 1]]) as u32;
+        sum += u16::from_be_bytes([ip6_header.dst_addr.0[i], ip6_header.dst_addr.0[i + 1]]) as u32;
+    }
+    sum += message.len() as u32;
+    sum += ip6_nh::ICMP as u32;
+    for chunk in message.chunks(2) {
+        let lsb = if chunk.len() == 2 { chunk[1] } else { 0 };
+        sum += u16::from_be_bytes([chunk[0], lsb]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    !(sum as u16)
+}
+
 pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
     let mut sum: u32 = 0;
 
@@ -67,7 +67,9 @@
 // (as required by 6LoWPAN) difficult.
 
 use crate::net::icmpv6::ICMP6Header;
-use crate::net::ipv6::ip_utils::{compute_icmp_checksum, compute_udp_checksum, ip6_nh, IPAddr};
+use crate::net::ipv6::ip_utils::{
+    compute_icmp_checksum, compute_udp_checksum, ip6_nh, verify_icmp_checksum, IPAddr,
+};
 use crate::net::stream::SResult;
 use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
 use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
@@ -281,17 +283,7 @@ impl IP6Header {
                 ReturnCode::SUCCESS
             }
             ip6_nh::ICMP => {
-                // Untested (10/5/18)
-                let mut icmp_header: [u8; ICMP_HDR_LEN] = [0; ICMP_HDR_LEN];
-                icmp_header.copy_from_slice(&buf[..ICMP_HDR_LEN]);
-                let checksum = match ICMP6Header::decode(&icmp_header).done() {
-                    Some((_offset, mut hdr)) => {
-                        hdr.set_len(buf.len() as u16);
-                        u16::from_be(compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]))
-                    }
-                    None => 0xffff, //Will be dropped, as ones comp -0 checksum is invalid
-                };
-                if checksum != 0 {
+                if buf.len() < ICMP_HDR_LEN || verify_icmp_checksum(&self, buf) != 0 {
                     return ReturnCode::FAIL; //Incorrect cksum
                 }
                 ReturnCode::SUCCESS
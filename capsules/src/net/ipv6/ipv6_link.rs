@@ -0,0 +1,46 @@
+//! This file contains the interface between the IPv6 layer and links that
+//! carry whole IPv6 packets, such as Ethernet.
+//!
+//! 802.15.4 frames are too small for IPv6, so over 802.15.4 the IPv6 layer
+//! compresses and fragments packets with 6LoWPAN. Links with an MTU of at least
+//! 1280 bytes (RFC 8200, section 5) carry each packet in a single frame
+//! instead. Such a link implements the [IP6Link](trait.IP6Link.html) trait:
+//! `IP6SendStruct` hands it encoded packets to transmit, and it passes the
+//! packets it receives to `IP6RecvStruct`. The link resolves the link-layer
+//! addresses of its neighbors itself.
+
+use kernel::ReturnCode;
+
+/// Receives the `transmit_done` callback of an `IP6Link`.
+pub trait IP6LinkTxClient {
+    /// Called when the packet passed to `IP6Link::transmit` was sent, or
+    /// could not be sent, returning the buffer.
+    fn transmit_done(&self, buf: &'static mut [u8], result: ReturnCode);
+}
+
+/// Receives the IPv6 packets that arrive on an `IP6Link`.
+pub trait IP6LinkRxClient {
+    /// Called with each received packet. `packet` holds exactly one IPv6
+    /// packet, starting with the IPv6 header.
+    fn packet_received(&self, packet: &[u8]);
+}
+
+/// A link that carries whole IPv6 packets.
+pub trait IP6Link<'a> {
+    fn set_transmit_client(&self, client: &'a dyn IP6LinkTxClient);
+
+    fn set_receive_client(&self, client: &'a dyn IP6LinkRxClient);
+
+    /// The largest IPv6 packet the link can carry.
+    fn mtu(&self) -> usize;
+
+    /// Sends the IPv6 packet in the first `len` bytes of `buf` to the neighbor
+    /// its destination address belongs to, or to all neighbors if it is a
+    /// multicast address. On success the buffer is returned in
+    /// `transmit_done`.
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
@@ -1,3 +1,4 @@
+use crate::net::ipv6::ipv6_link::IP6LinkRxClient;
 use crate::net::ipv6::IP6Header;
 use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
 use kernel::common::cells::OptionalCell;
@@ -25,6 +26,9 @@ use kernel::ReturnCode;
   udp_recv, a `UDPReceive` struct.
 - The UDPReceive struct is a field of the UDPDriver, which ultimately passes the
   packets up to userland.
+
+Links that carry whole IPv6 packets (e.g. `usb::cdc_ecm`) skip the MAC and
+6LoWPAN layers and pass packets to `IP6RecvStruct` as an `IP6LinkRxClient`.
 */
 
 pub trait IP6RecvClient {
@@ -56,14 +60,11 @@ impl<'a> IP6RecvStruct<'a> {
             client: OptionalCell::empty(),
         }
     }
-}
 
-impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
-    fn receive(&self, buf: &[u8], len: usize, result: ReturnCode) {
-        // TODO: Drop here?
-        if len > buf.len() || result != ReturnCode::SUCCESS {
-            return;
-        }
+    /// Passes the packet in `buf` to the client, unless its transport
+    /// checksum is wrong.
+    fn receive_packet(&self, buf: &[u8]) {
+        let len = buf.len();
         match IP6Header::decode(buf).done() {
             Some((offset, ip6_header)) => {
                 let checksum_result = ip6_header.check_transport_checksum(&buf[offset..len]);
@@ -84,3 +85,19 @@ impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
         }
     }
 }
+
+impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
+    fn receive(&self, buf: &[u8], len: usize, result: ReturnCode) {
+        // TODO: Drop here?
+        if len > buf.len() || result != ReturnCode::SUCCESS {
+            return;
+        }
+        self.receive_packet(&buf[..len]);
+    }
+}
+
+impl<'a> IP6LinkRxClient for IP6RecvStruct<'a> {
+    fn packet_received(&self, packet: &[u8]) {
+        self.receive_packet(packet);
+    }
+}
@@ -5,7 +5,8 @@
 //! when a transmission has completed.
 //!
 //! This file also includes an implementation of the `IP6Sender` trait, which
-//! sends an IPv6 packet using 6LoWPAN.
+//! sends an IPv6 packet using 6LoWPAN, or over an `IP6Link` that carries whole
+//! packets.
 
 // Additional Work and Known Problems
 // ----------------------------------
@@ -19,6 +20,7 @@
 use crate::ieee802154::device::{MacDevice, TxClient};
 use crate::net::ieee802154::MacAddress;
 use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6_link::{IP6Link, IP6LinkTxClient};
 use crate::net::ipv6::{IP6Header, IP6Packet, TransportHeader};
 use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
 use crate::net::sixlowpan::sixlowpan_state::TxState;
@@ -88,21 +90,32 @@ pub trait IP6Sender<'a> {
     ) -> ReturnCode;
 }
 
+/// The link an `IP6SendStruct` sends packets over.
+enum Link<'a, A: time::Alarm<'a>> {
+    /// 6LoWPAN over a `MacDevice`.
+    Sixlowpan {
+        alarm: &'a A, // Alarm so we can introduce a small delay between fragments to ensure
+        // successful reception on receivers with slow copies out of the radio buffer
+        // (imix)
+        sixlowpan: TxState<'a>,
+        radio: &'a dyn MacDevice<'a>,
+        dst_mac_addr: MacAddress,
+        src_mac_addr: MacAddress,
+    },
+    /// A link that carries whole packets.
+    Packet(&'a dyn IP6Link<'a>),
+}
+
 /// This struct is a specific implementation of the `IP6Sender` trait. This
-/// struct sends the packet using 6LoWPAN over a generic `MacDevice` object.
+/// struct sends the packet using 6LoWPAN over a generic `MacDevice` object,
+/// or over an `IP6Link`.
 pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
     // We want the ip6_packet field to be a TakeCell so that it is easy to mutate
     ip6_packet: TakeCell<'static, IP6Packet<'static>>,
-    alarm: &'a A, // Alarm so we can introduce a small delay between fragments to ensure
-    // successful reception on receivers with slow copies out of the radio buffer
-    // (imix)
     src_addr: Cell<IPAddr>,
     gateway: Cell<MacAddress>,
     tx_buf: TakeCell<'static, [u8]>,
-    sixlowpan: TxState<'a>,
-    radio: &'a dyn MacDevice<'a>,
-    dst_mac_addr: MacAddress,
-    src_mac_addr: MacAddress,
+    link: Link<'a, A>,
     client: OptionalCell<&'a dyn IP6SendClient>,
     ip_vis: &'static IpVisibilityCapability,
 }
@@ -135,15 +148,24 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
         if !net_cap.remote_addr_valid(dst, self.ip_vis) {
             return ReturnCode::FAIL;
         }
-        self.sixlowpan.init(
-            self.src_mac_addr,
-            self.dst_mac_addr,
-            self.radio.get_pan(),
-            None,
-        );
-        self.init_packet(dst, transport_header, payload);
-        let ret = self.send_next_fragment();
-        ret
+        match self.link {
+            Link::Sixlowpan {
+                ref sixlowpan,
+                radio,
+                dst_mac_addr,
+                src_mac_addr,
+                ..
+            } => {
+                sixlowpan.init(src_mac_addr, dst_mac_addr, radio.get_pan(), None);
+                self.init_packet(dst, transport_header, payload);
+                let ret = self.send_next_fragment();
+                ret
+            }
+            Link::Packet(link) => {
+                self.init_packet(dst, transport_header, payload);
+                self.send_packet(link)
+            }
+        }
     }
 }
 
@@ -160,14 +182,37 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
     ) -> IP6SendStruct<'a, A> {
         IP6SendStruct {
             ip6_packet: TakeCell::new(ip6_packet),
-            alarm: alarm,
             src_addr: Cell::new(IPAddr::new()),
             gateway: Cell::new(dst_mac_addr),
             tx_buf: TakeCell::new(tx_buf),
-            sixlowpan: sixlowpan,
-            radio: radio,
-            dst_mac_addr: dst_mac_addr,
-            src_mac_addr: src_mac_addr,
+            link: Link::Sixlowpan {
+                alarm: alarm,
+                sixlowpan: sixlowpan,
+                radio: radio,
+                dst_mac_addr: dst_mac_addr,
+                src_mac_addr: src_mac_addr,
+            },
+            client: OptionalCell::empty(),
+            ip_vis: ip_vis,
+        }
+    }
+
+    /// Creates an `IP6SendStruct` that sends whole packets over `link`, which
+    /// needs `tx_buf` to be as large as its MTU. The link must be given the
+    /// struct as its transmit client.
+    pub fn new_with_link(
+        ip6_packet: &'static mut IP6Packet<'static>,
+        tx_buf: &'static mut [u8],
+        link: &'a dyn IP6Link<'a>,
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> IP6SendStruct<'a, A> {
+        IP6SendStruct {
+            ip6_packet: TakeCell::new(ip6_packet),
+            src_addr: Cell::new(IPAddr::new()),
+            // The link resolves link-layer addresses itself.
+            gateway: Cell::new(MacAddress::Short(0xffff)),
+            tx_buf: TakeCell::new(tx_buf),
+            link: Link::Packet(link),
             client: OptionalCell::empty(),
             ip_vis: ip_vis,
         }
@@ -193,8 +238,50 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
         );
     }
 
+    /// Encodes the packet into `tx_buf` and hands it to `link`.
+    fn send_packet(&self, link: &'a dyn IP6Link<'a>) -> ReturnCode {
+        let tx_buf = match self.tx_buf.take() {
+            Some(tx_buf) => tx_buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let encoded = self
+            .ip6_packet
+            .map_or(Err(ReturnCode::ENOMEM), |ip6_packet| {
+                let len = ip6_packet.get_total_len() as usize;
+                if len > tx_buf.len() || len > link.mtu() {
+                    return Err(ReturnCode::ESIZE);
+                }
+                ip6_packet
+                    .encode(tx_buf)
+                    .done()
+                    .map(|(len, _)| len)
+                    .ok_or(ReturnCode::FAIL)
+            });
+        match encoded {
+            Ok(len) => match link.transmit(tx_buf, len) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((ret, tx_buf)) => {
+                    self.tx_buf.replace(tx_buf);
+                    ret
+                }
+            },
+            Err(ret) => {
+                self.tx_buf.replace(tx_buf);
+                ret
+            }
+        }
+    }
+
     // Returns EBUSY if the tx_buf is not there
     fn send_next_fragment(&self) -> ReturnCode {
+        let (sixlowpan, radio) = match self.link {
+            Link::Sixlowpan {
+                ref sixlowpan,
+                radio,
+                ..
+            } => (sixlowpan, radio),
+            Link::Packet(_) => return ReturnCode::EINVAL,
+        };
         // Originally send_complete() was called within the below closure.
         // However, this led to a race condition where when multiple apps transmitted
         // simultaneously, it was possible for send_complete to trigger another
@@ -206,7 +293,7 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             .ip6_packet
             .map(move |ip6_packet| match self.tx_buf.take() {
                 Some(tx_buf) => {
-                    let next_frame = self.sixlowpan.next_fragment(ip6_packet, tx_buf, self.radio);
+                    let next_frame = sixlowpan.next_fragment(ip6_packet, tx_buf, radio);
                     match next_frame {
                         Ok((is_done, frame)) => {
                             if is_done {
@@ -214,7 +301,7 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
                                 //self.send_completed(ReturnCode::SUCCESS);
                                 (ReturnCode::SUCCESS, true)
                             } else {
-                                let (err, _frame_option) = self.radio.transmit(frame);
+                                let (err, _frame_option) = radio.transmit(frame);
                                 (err, false)
                             }
                         }
@@ -271,8 +358,16 @@ impl<'a, A: time::Alarm<'a>> TxClient for IP6SendStruct<'a, A> {
             // One flaw with this is that we also introduce a delay after sending the last
             // fragment, before passing the send_done callback back to the client. This
             // could be optimized by checking if it is the last fragment before setting the timer.
-            self.alarm
-                .set_alarm(self.alarm.now(), A::ticks_from_ms(100));
+            if let Link::Sixlowpan { alarm, .. } = self.link {
+                alarm.set_alarm(alarm.now(), A::ticks_from_ms(100));
+            }
         }
     }
 }
+
+impl<'a, A: time::Alarm<'a>> IP6LinkTxClient for IP6SendStruct<'a, A> {
+    fn transmit_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        self.tx_buf.replace(buf);
+        self.send_completed(result);
+    }
+}
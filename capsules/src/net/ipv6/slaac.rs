@@ -0,0 +1,304 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862).
+//!
+//! `Slaac` assigns the interface a link-local address derived from the
+//! 802.15.4 extended address, and a global address for every on-link prefix
+//! advertised with the autonomous flag in a Router Advertisement (RFC 4861).
+//! Global addresses use the same EUI-64 based interface identifier, so
+//! duplicate address detection is not performed (RFC 6775, section 5.4). No
+//! DHCPv6 server is needed.
+//!
+//! Global addresses are kept for their valid lifetime and are deprecated once
+//! their preferred lifetime ends. The source address of the `IP6Sender` is
+//! kept up to date: the first preferred global address if there is one, the
+//! link-local address otherwise. All valid addresses are reported through
+//! `InterfaceAddresses`, which the UDP driver uses to expose them to
+//! userspace.
+//!
+//! `Slaac` sits on the receive path between the `IP6Receiver` and the upper
+//! layers: it consumes Router Advertisements and passes every other packet
+//! on to its client. Router Solicitations are not sent, so addresses are
+//! configured when the router next advertises.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let slaac = static_init!(
+//!     capsules::net::ipv6::slaac::Slaac<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::net::ipv6::slaac::Slaac::new(slaac_alarm, serial_num_bottom_eight)
+//! );
+//! slaac_alarm.set_alarm_client(slaac);
+//! slaac.set_sender(ip_send);
+//! ip_receive.set_client(slaac);
+//! slaac.set_client(udp_recv_mux);
+//! udp_driver.set_interface_addresses(slaac);
+//! ```
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr, InterfaceAddresses};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::IP6Sender;
+use crate::net::ipv6::IP6Header;
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+
+/// Number of global addresses the interface can hold.
+pub const MAX_PREFIXES: usize = 3;
+
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const RA_HEADER_LEN: usize = 16;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const PREFIX_INFORMATION_LEN: usize = 32;
+const FLAG_AUTONOMOUS: u8 = 0x40;
+// SLAAC only forms addresses from /64 prefixes.
+const PREFIX_LEN: u8 = 64;
+const LIFETIME_INFINITE: u32 = 0xffff_ffff;
+const TWO_HOURS: u32 = 2 * 60 * 60;
+// Longest time between updates of the clock, which must be shorter than the
+// time it takes the alarm's ticks to wrap around.
+const MAX_SLEEP_S: u32 = 60;
+
+#[derive(Copy, Clone)]
+struct Address {
+    addr: IPAddr,
+    /// Uptime in seconds at which the address becomes invalid, or `None` if
+    /// it never does.
+    valid_until: Option<u32>,
+    /// Uptime in seconds at which the address becomes deprecated.
+    preferred_until: Option<u32>,
+}
+
+impl Address {
+    fn preferred(&self, now: u32) -> bool {
+        self.preferred_until.map_or(true, |until| now < until)
+    }
+}
+
+pub struct Slaac<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    link_local: IPAddr,
+    sender: OptionalCell<&'a dyn IP6Sender<'a>>,
+    client: OptionalCell<&'a dyn IP6RecvClient>,
+    addresses: MapCell<[Option<Address>; MAX_PREFIXES]>,
+    /// Seconds since the clock was started.
+    uptime: Cell<u32>,
+    /// Alarm time corresponding to `uptime`.
+    reference: Cell<A::Ticks>,
+}
+
+impl<'a, A: Alarm<'a>> Slaac<'a, A> {
+    /// Creates an interface with the link-local address derived from
+    /// `long_address`, the 802.15.4 extended address.
+    pub fn new(alarm: &'a A, long_address: [u8; 8]) -> Slaac<'a, A> {
+        Slaac {
+            alarm: alarm,
+            link_local: IPAddr::generate_from_mac(MacAddress::Long(long_address)),
+            sender: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            addresses: MapCell::new([None; MAX_PREFIXES]),
+            uptime: Cell::new(0),
+            reference: Cell::new(alarm.now()),
+        }
+    }
+
+    /// Sets the `IP6Sender` whose source address is configured, and gives it
+    /// the current address.
+    pub fn set_sender(&self, sender: &'a dyn IP6Sender<'a>) {
+        self.sender.set(sender);
+        sender.set_addr(self.source_address());
+    }
+
+    /// Sets the client that receives all packets other than Router
+    /// Advertisements.
+    pub fn set_client(&self, client: &'a dyn IP6RecvClient) {
+        self.client.set(client);
+    }
+
+    pub fn link_local_address(&self) -> IPAddr {
+        self.link_local
+    }
+
+    /// The address to send from: the first preferred global address, or the
+    /// link-local address if there is none.
+    pub fn source_address(&self) -> IPAddr {
+        let now = self.uptime.get();
+        self.addresses
+            .map_or(None, |addresses| {
+                addresses
+                    .iter()
+                    .flatten()
+                    .find(|address| address.preferred(now))
+                    .map(|address| address.addr)
+            })
+            .unwrap_or(self.link_local)
+    }
+
+    /// Advances `uptime` by the whole seconds elapsed since `reference`.
+    fn update_clock(&self) -> u32 {
+        let now = self.alarm.now();
+        let elapsed = now.wrapping_sub(self.reference.get()).into_u32()
+            / <A::Frequency as Frequency>::frequency();
+        self.reference.set(
+            self.reference
+                .get()
+                .wrapping_add(A::ticks_from_seconds(elapsed)),
+        );
+        self.uptime.set(self.uptime.get().saturating_add(elapsed));
+        self.uptime.get()
+    }
+
+    /// Removes invalid addresses, updates the source address and sets the
+    /// alarm for the next lifetime to end.
+    fn refresh(&self) {
+        let now = self.update_clock();
+        let next = self.addresses.map_or(None, |addresses| {
+            let mut next: Option<u32> = None;
+            for slot in addresses.iter_mut() {
+                if let Some(address) = slot {
+                    if address.valid_until.map_or(false, |until| until <= now) {
+                        *slot = None;
+                        continue;
+                    }
+                    for until in [address.valid_until, address.preferred_until].iter() {
+                        if let Some(until) = until.filter(|&until| until > now) {
+                            next = Some(next.map_or(until, |next| next.min(until)));
+                        }
+                    }
+                }
+            }
+            next
+        });
+        match next {
+            Some(next) => {
+                let sleep = (next - now).min(MAX_SLEEP_S);
+                self.alarm
+                    .set_alarm(self.reference.get(), A::ticks_from_seconds(sleep));
+            }
+            None => {
+                self.alarm.disarm();
+            }
+        }
+        let source = self.source_address();
+        self.sender.map(|sender| sender.set_addr(source));
+    }
+
+    fn receive_router_advertisement(&self, ip_header: &IP6Header, message: &[u8]) {
+        // Validation from RFC 4861, section 6.1.2. The checksum has been
+        // verified by the IPv6 layer.
+        if ip_header.get_hop_limit() != 255
+            || !ip_header.get_src_addr().is_unicast_link_local()
+            || message.len() < RA_HEADER_LEN
+            || message[1] != 0
+        {
+            return;
+        }
+        self.update_clock();
+        let mut options = &message[RA_HEADER_LEN..];
+        while options.len() >= 2 {
+            let len = options[1] as usize * 8;
+            if len == 0 || len > options.len() {
+                // Malformed options invalidate the whole advertisement, but
+                // the prefixes already processed are valid on their own.
+                break;
+            }
+            if options[0] == OPTION_PREFIX_INFORMATION && len == PREFIX_INFORMATION_LEN {
+                self.prefix_information(&options[..len]);
+            }
+            options = &options[len..];
+        }
+        self.refresh();
+    }
+
+    /// Processes a Prefix Information option, as in RFC 4862, section 5.5.3.
+    fn prefix_information(&self, option: &[u8]) {
+        let prefix_len = option[2];
+        let flags = option[3];
+        let valid = u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+        let preferred = u32::from_be_bytes([option[8], option[9], option[10], option[11]]);
+        if flags & FLAG_AUTONOMOUS == 0 || prefix_len != PREFIX_LEN || preferred > valid {
+            return;
+        }
+        let mut addr = self.link_local;
+        addr.set_prefix(&option[16..32], prefix_len);
+        if addr.is_unicast_link_local() || addr.is_multicast() {
+            return;
+        }
+
+        let now = self.uptime.get();
+        let deadline = |lifetime: u32| {
+            if lifetime == LIFETIME_INFINITE {
+                None
+            } else {
+                Some(now.saturating_add(lifetime))
+            }
+        };
+        self.addresses.map(|addresses| {
+            let existing = addresses
+                .iter_mut()
+                .flatten()
+                .find(|address| address.addr == addr);
+            match existing {
+                Some(address) => {
+                    address.preferred_until = deadline(preferred);
+                    // The "two hours" rule protects against advertisements
+                    // that would end an address's lifetime prematurely.
+                    let remaining = address.valid_until.map(|until| until.saturating_sub(now));
+                    if valid > TWO_HOURS || remaining.map_or(false, |remaining| valid > remaining) {
+                        address.valid_until = deadline(valid);
+                    } else if remaining.map_or(true, |remaining| remaining > TWO_HOURS) {
+                        address.valid_until = deadline(TWO_HOURS);
+                    }
+                }
+                None => {
+                    if valid == 0 {
+                        return;
+                    }
+                    if let Some(slot) = addresses.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some(Address {
+                            addr: addr,
+                            valid_until: deadline(valid),
+                            preferred_until: deadline(preferred),
+                        });
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> InterfaceAddresses for Slaac<'a, A> {
+    /// The link-local address first, followed by the valid global addresses.
+    fn address(&self, index: usize) -> Option<IPAddr> {
+        if index == 0 {
+            return Some(self.link_local);
+        }
+        self.addresses.map_or(None, |addresses| {
+            addresses
+                .iter()
+                .flatten()
+                .nth(index - 1)
+                .map(|address| address.addr)
+        })
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Slaac<'a, A> {
+    fn alarm(&self) {
+        self.refresh();
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6RecvClient for Slaac<'a, A> {
+    fn receive(&self, ip_header: IP6Header, payload: &[u8]) {
+        if ip_header.get_next_header() == ip6_nh::ICMP
+            && payload.first() == Some(&ROUTER_ADVERTISEMENT)
+        {
+            self.receive_router_advertisement(&ip_header, payload);
+        } else {
+            self.client.map(|client| client.receive(ip_header, payload));
+        }
+    }
+}
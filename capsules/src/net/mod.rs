@@ -5,6 +5,7 @@ pub mod sixlowpan;
 pub mod util;
 #[macro_use]
 pub mod stream;
+pub mod coap;
 pub mod icmpv6;
 pub mod ieee802154;
 pub mod ipv6;
@@ -682,7 +682,7 @@ impl<'a> RxState<'a> {
 
     // Checks if a given RxState is free or expired (and thus, can be freed).
     // This function implements the reassembly timeout for 6LoWPAN lazily.
-    fn is_busy(&self, frequency: u32, current_time: u32) -> bool {
+    fn is_busy(&self, current_time: u32, frequency: u32) -> bool {
         let expired = current_time >= (self.start_time.get() + FRAG_TIMEOUT * frequency);
         if expired {
             self.end_receive(None, ReturnCode::FAIL);
@@ -720,7 +720,7 @@ impl<'a> RxState<'a> {
     ) -> Result<bool, ReturnCode> {
         let mut packet = self.packet.take().ok_or(ReturnCode::ENOMEM)?;
         let uncompressed_len = if dgram_offset == 0 {
-            let (consumed, written) = sixlowpan_compression::decompress(
+            let decompressed = sixlowpan_compression::decompress(
                 ctx_store,
                 &payload[0..payload_len as usize],
                 self.src_mac_addr.get(),
@@ -728,8 +728,14 @@ impl<'a> RxState<'a> {
                 &mut packet,
                 dgram_size,
                 true,
-            )
-            .map_err(|_| ReturnCode::FAIL)?;
+            );
+            let (consumed, written) = match decompressed {
+                Ok(result) => result,
+                Err(_) => {
+                    self.packet.replace(packet);
+                    return Err(ReturnCode::FAIL);
+                }
+            };
             let remaining = payload_len - consumed;
             packet[written..written + remaining]
                 .copy_from_slice(&payload[consumed..consumed + remaining]);
@@ -944,6 +950,8 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
                         state.dgram_size.set((written + remaining) as u16);
                     }
                     Err(_) => {
+                        state.packet.replace(packet);
+                        state.end_receive(None, ReturnCode::FAIL);
                         return (None, ReturnCode::FAIL);
                     }
                 }
@@ -1030,3 +1038,104 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         // TODO: Need to get buffer back from Mac layer on disassociation
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::net::sixlowpan::sixlowpan_compression::Context;
+    use kernel::hil::time::{Alarm, AlarmClient, Freq1KHz, Ticks32, Time};
+    use std::boxed::Box;
+    use std::vec;
+
+    const FREQUENCY: u32 = 1000;
+
+    // An IPHC header with an inline next header and a context based
+    // destination address in the reserved DAM mode, which fails to decompress.
+    const BAD_IPHC: [u8; 3] = [0x7b, 0x34, 17];
+
+    struct TestClock;
+
+    impl Time for TestClock {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+        fn now(&self) -> Ticks32 {
+            0.into()
+        }
+    }
+
+    impl<'a> Alarm<'a> for TestClock {
+        fn set_alarm_client(&'a self, _: &'a dyn AlarmClient) {}
+        fn set_alarm(&self, _: Ticks32, _: Ticks32) {}
+        fn get_alarm(&self) -> Ticks32 {
+            0.into()
+        }
+        fn disarm(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn is_armed(&self) -> bool {
+            false
+        }
+        fn minimum_dt(&self) -> Ticks32 {
+            1.into()
+        }
+    }
+
+    fn context() -> Context {
+        Context {
+            prefix: [0; 16],
+            prefix_len: 0,
+            id: 0,
+            compress: false,
+        }
+    }
+
+    fn rx_state() -> &'static RxState<'static> {
+        let packet = Box::leak(vec![0u8; 128].into_boxed_slice());
+        Box::leak(Box::new(RxState::new(packet)))
+    }
+
+    #[test]
+    fn rx_state_expires_after_timeout() {
+        let state = rx_state();
+        assert!(!state.is_busy(0, FREQUENCY));
+
+        state.start_receive(MacAddress::Short(1), MacAddress::Short(2), 100, 1, 50);
+        assert!(state.is_busy(50, FREQUENCY));
+        assert!(state.is_busy(50 + FRAG_TIMEOUT * FREQUENCY - 1, FREQUENCY));
+        assert!(!state.is_busy(50 + FRAG_TIMEOUT * FREQUENCY, FREQUENCY));
+    }
+
+    #[test]
+    fn failed_decompression_keeps_fragment_buffer() {
+        let state = rx_state();
+        state.start_receive(MacAddress::Short(1), MacAddress::Short(2), 100, 1, 0);
+        assert_eq!(
+            state.receive_next_frame(&BAD_IPHC, BAD_IPHC.len(), 100, 0, &context()),
+            Err(ReturnCode::FAIL)
+        );
+        assert!(state.packet.is_some());
+    }
+
+    #[test]
+    fn failed_decompression_frees_rx_state() {
+        let clock: &'static TestClock = Box::leak(Box::new(TestClock));
+        let sixlowpan = Box::leak(Box::new(Sixlowpan::new(context(), clock)));
+        let state = rx_state();
+        sixlowpan.add_rx_state(state);
+
+        for _ in 0..2 {
+            let (rx_state, result) = sixlowpan.receive_frame(
+                &BAD_IPHC,
+                BAD_IPHC.len(),
+                MacAddress::Short(1),
+                MacAddress::Short(2),
+            );
+            assert!(rx_state.is_none());
+            assert_eq!(result, ReturnCode::FAIL);
+            assert!(state.packet.is_some());
+            assert!(!state.busy.get());
+        }
+    }
+}
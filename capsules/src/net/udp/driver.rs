@@ -6,7 +6,7 @@
 //! Also exposes a list of interface addresses to the application (currently
 //! hard-coded).
 
-use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ip_utils::{IPAddr, InterfaceAddresses};
 use crate::net::network_capabilities::NetworkCapability;
 use crate::net::stream::encode_u16;
 use crate::net::stream::encode_u8;
@@ -18,7 +18,7 @@ use crate::net::util::host_slice_to_u16;
 use core::cell::Cell;
 use core::{cmp, mem};
 use kernel::capabilities::UdpDriverCapability;
-use kernel::common::cells::MapCell;
+use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
 use kernel::{debug, AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
@@ -85,6 +85,9 @@ pub struct UDPDriver<'a> {
     /// List of IP Addresses of the interfaces on the device
     interface_list: &'static [IPAddr],
 
+    /// Addresses assigned at runtime, which replace `interface_list` if set
+    interface_addresses: OptionalCell<&'a dyn InterfaceAddresses>,
+
     /// Maximum length payload that an app can transmit via this driver
     max_tx_pyld_len: usize,
 
@@ -114,6 +117,7 @@ impl<'a> UDPDriver<'a> {
             apps: grant,
             current_app: Cell::new(None),
             interface_list: interface_list,
+            interface_addresses: OptionalCell::empty(),
             max_tx_pyld_len: max_tx_pyld_len,
             port_table: port_table,
             kernel_buffer: MapCell::new(kernel_buffer),
@@ -122,6 +126,26 @@ impl<'a> UDPDriver<'a> {
         }
     }
 
+    /// Replaces the static interface list with addresses that can change at
+    /// runtime, such as those configured by `Slaac`.
+    pub fn set_interface_addresses(&self, addresses: &'a dyn InterfaceAddresses) {
+        self.interface_addresses.set(addresses);
+    }
+
+    /// Returns the address of interface `index`, if there is one.
+    fn interface_address(&self, index: usize) -> Option<IPAddr> {
+        self.interface_addresses.map_or_else(
+            || self.interface_list.get(index).copied(),
+            |addresses| addresses.address(index),
+        )
+    }
+
+    fn interface_count(&self) -> usize {
+        (0..)
+            .take_while(|&i| self.interface_address(i).is_some())
+            .count()
+    }
+
     /// Utility function to perform an action on an app in a system call.
     #[inline]
     fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
@@ -483,16 +507,16 @@ impl<'a> Driver for UDPDriver<'a> {
             //  Writes the requested number of network interface addresses
             // `arg1`: number of interfaces requested that will fit into the buffer
             1 => self.do_with_cfg_mut(appid, arg1 * mem::size_of::<IPAddr>(), |cfg| {
-                let n_ifaces_to_copy = cmp::min(arg1, self.interface_list.len());
+                let n_ifaces = self.interface_count();
+                let n_ifaces_to_copy = cmp::min(arg1, n_ifaces);
                 let iface_size = mem::size_of::<IPAddr>();
                 for i in 0..n_ifaces_to_copy {
-                    cfg[i * iface_size..(i + 1) * iface_size]
-                        .copy_from_slice(&self.interface_list[i].0);
+                    self.interface_address(i).map(|addr| {
+                        cfg[i * iface_size..(i + 1) * iface_size].copy_from_slice(&addr.0)
+                    });
                 }
                 // Returns total number of interfaces
-                ReturnCode::SuccessWithValue {
-                    value: self.interface_list.len(),
-                }
+                ReturnCode::SuccessWithValue { value: n_ifaces }
             }),
 
             // Transmits UDP packet stored in tx_buf
@@ -567,12 +591,8 @@ impl<'a> Driver for UDPDriver<'a> {
                                 return Ok(None);
                             }
                             // Check that requested addr is a local interface
-                            let mut requested_is_local = false;
-                            for i in 0..self.interface_list.len() {
-                                if requested_addr.addr == self.interface_list[i] {
-                                    requested_is_local = true;
-                                }
-                            }
+                            let requested_is_local = (0..self.interface_count())
+                                .any(|i| self.interface_address(i) == Some(requested_addr.addr));
                             if !requested_is_local {
                                 return Err(ReturnCode::EINVAL);
                             }
@@ -0,0 +1,681 @@
+//! Nonvolatile storage split into regions owned by applications.
+//!
+//! `NonvolatileRegions` gives each application at most one region of a
+//! range of a `hil::nonvolatile_storage` device. Regions belong to the
+//! package name of the application, so an application finds its region
+//! again after a reboot or after being reinstalled. Applications can only
+//! read and write inside their own region, and no region can be larger than
+//! the quota set by the board. Applications without a package name can't
+//! have a region.
+//!
+//! The table of regions is kept at the start of the range, in two copies
+//! that are written alternately, so losing power while the table is written
+//! leaves the previous table intact. The copy with a valid checksum and the
+//! highest sequence number is used. New regions are filled with zeros before
+//! they are added to the table, so applications never see data left behind
+//! by a previous owner of the space.
+//!
+//! The board calls `load()` once at boot. Until the table is loaded every
+//! operation returns `EOFF`. Only one operation runs at a time.
+//!
+//! Regions of applications that have been removed stay allocated until they
+//! are freed through the admin interface, `free_region()` and
+//! `reclaim_removed()`, which needs the `ProcessManagementCapability`.
+//!
+//! ```text
+//! start                2 * TABLE_SIZE                            start + length
+//! +----------+----------+-----------------+--------+-----------------+
+//! | table 0  | table 1  | region of app A |  free  | region of app B |
+//! +----------+----------+-----------------+--------+-----------------+
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let regions_buffer = static_init!(
+//!     [u8; capsules::nonvolatile_regions::TABLE_SIZE],
+//!     [0; capsules::nonvolatile_regions::TABLE_SIZE]
+//! );
+//! let nonvolatile_regions = static_init!(
+//!     capsules::nonvolatile_regions::NonvolatileRegions<'static>,
+//!     capsules::nonvolatile_regions::NonvolatileRegions::new(
+//!         fm25cl,      // The underlying storage driver.
+//!         0,           // The byte start address of the range.
+//!         0x8000,      // The length of the range.
+//!         0x1000,      // The largest region an application can have.
+//!         regions_buffer,
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_regions);
+//! nonvolatile_regions.load();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::hash::Hasher;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::{Kernel, ReturnCode};
+
+use crate::sip_hash::SipHasher24;
+
+/// The size of one copy of the table of regions. The buffer given to
+/// `NonvolatileRegions` must hold at least this many bytes.
+pub const TABLE_SIZE: usize = 512;
+/// The maximum number of regions.
+pub const MAX_REGIONS: usize = 15;
+/// The number of bytes of the package name kept for listing regions.
+pub const NAME_LENGTH: usize = 16;
+
+const MAGIC: &[u8; 4] = b"NVRG";
+const ENTRIES_START: usize = 8;
+const ENTRY_SIZE: usize = 32;
+const CHECKSUM_START: usize = TABLE_SIZE - 4;
+
+/// A region of storage owned by an application.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    /// Hash of the package name of the owner
+    pub owner: u64,
+    name: [u8; NAME_LENGTH],
+    /// The start of the region, relative to the end of the tables
+    pub offset: usize,
+    /// The size of the region in bytes
+    pub size: usize,
+}
+
+impl Region {
+    /// The start of the package name of the owner.
+    pub fn name(&self) -> &[u8] {
+        let length = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(NAME_LENGTH);
+        &self.name[..length]
+    }
+
+    fn decode(entry: &[u8]) -> Option<Region> {
+        let size = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+        if size == 0 {
+            return None;
+        }
+        let mut name = [0; NAME_LENGTH];
+        name.copy_from_slice(&entry[16..16 + NAME_LENGTH]);
+        Some(Region {
+            owner: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            name,
+            offset: u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize,
+            size,
+        })
+    }
+
+    fn encode(region: Option<Region>, entry: &mut [u8]) {
+        match region {
+            Some(region) => {
+                entry[0..8].copy_from_slice(&region.owner.to_le_bytes());
+                entry[8..12].copy_from_slice(&(region.offset as u32).to_le_bytes());
+                entry[12..16].copy_from_slice(&(region.size as u32).to_le_bytes());
+                entry[16..16 + NAME_LENGTH].copy_from_slice(&region.name);
+            }
+            None => entry.iter_mut().for_each(|b| *b = 0),
+        }
+    }
+}
+
+fn owner_hash(name: &str) -> u64 {
+    let mut hasher = SipHasher24::new();
+    hasher.write(name.as_bytes());
+    hasher.finish()
+}
+
+fn checksum(table: &[u8]) -> u32 {
+    let mut hasher = SipHasher24::new();
+    hasher.write(&table[..CHECKSUM_START]);
+    hasher.finish() as u32
+}
+
+/// Implement `Client` to receive callbacks from `NonvolatileRegions`.
+pub trait Client {
+    /// The table of regions was loaded.
+    fn load_done(&self, result: Result<(), ReturnCode>);
+
+    /// A region was allocated. Returns its size.
+    fn allocate_done(&self, result: Result<usize, ReturnCode>);
+
+    /// A region was freed by its owner.
+    fn free_done(&self, result: Result<(), ReturnCode>);
+
+    /// `length` bytes were read from a region into `buffer`.
+    fn read_done(&self, buffer: &'static mut [u8], length: usize);
+
+    /// `length` bytes were written to a region from `buffer`.
+    fn write_done(&self, buffer: &'static mut [u8], length: usize);
+}
+
+/// Implement `AdminClient` to receive callbacks for the admin interface.
+pub trait AdminClient {
+    /// Regions were freed. Returns how many.
+    fn reclaim_done(&self, result: Result<usize, ReturnCode>);
+}
+
+/// Changes to the table that are written before they take effect.
+#[derive(Clone, Copy, PartialEq)]
+enum Commit {
+    /// Add the staged region at this index
+    Allocate(usize),
+    /// Remove the regions in the mask, on behalf of the owner or an admin
+    Free { mask: u32, admin: bool },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Load {
+        copy: usize,
+        found: bool,
+    },
+    /// Zeroing the staged region at this index
+    Zero {
+        index: usize,
+        done: usize,
+    },
+    Commit(Commit),
+    Read,
+    Write,
+}
+
+pub struct NonvolatileRegions<'a> {
+    storage: &'a dyn NonvolatileStorage<'static>,
+    start: usize,
+    length: usize,
+    quota: usize,
+    regions: [Cell<Option<Region>>; MAX_REGIONS],
+    sequence: Cell<u32>,
+    loaded: Cell<bool>,
+    operation: Cell<Operation>,
+    /// A region being allocated
+    staged: Cell<Option<Region>>,
+    buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn Client>,
+    admin_client: OptionalCell<&'a dyn AdminClient>,
+}
+
+impl<'a> NonvolatileRegions<'a> {
+    /// Use `length` bytes of `storage` starting at `start`. No region can be
+    /// larger than `quota` bytes.
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'static>,
+        start: usize,
+        length: usize,
+        quota: usize,
+        buffer: &'static mut [u8],
+    ) -> NonvolatileRegions<'a> {
+        NonvolatileRegions {
+            storage,
+            start,
+            length,
+            quota,
+            regions: <[Cell<Option<Region>>; MAX_REGIONS]>::default(),
+            sequence: Cell::new(0),
+            loaded: Cell::new(false),
+            operation: Cell::new(Operation::Idle),
+            staged: Cell::new(None),
+            buffer: TakeCell::new(buffer),
+            client: OptionalCell::empty(),
+            admin_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    pub fn set_admin_client(&self, client: &'a dyn AdminClient) {
+        self.admin_client.set(client);
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.get()
+    }
+
+    /// The largest region an application can have.
+    pub fn quota(&self) -> usize {
+        self.quota
+    }
+
+    /// The address of the region data, after the tables.
+    fn data_start(&self) -> usize {
+        self.start + 2 * TABLE_SIZE
+    }
+
+    fn data_length(&self) -> usize {
+        self.length.saturating_sub(2 * TABLE_SIZE)
+    }
+
+    /// Check that an operation can start now.
+    fn ready(&self) -> ReturnCode {
+        if !self.loaded.get() {
+            ReturnCode::EOFF
+        } else if self.operation.get() != Operation::Idle {
+            ReturnCode::EBUSY
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn find(&self, owner: &str) -> Option<usize> {
+        if owner.is_empty() {
+            return None;
+        }
+        let hash = owner_hash(owner);
+        self.regions
+            .iter()
+            .position(|region| region.get().map_or(false, |region| region.owner == hash))
+    }
+
+    /// The region of the application with package name `owner`.
+    pub fn region(&self, owner: &str) -> Option<Region> {
+        self.find(owner).and_then(|index| self.regions[index].get())
+    }
+
+    /// The region in slot `index` of the table, for listing regions.
+    pub fn region_at(&self, index: usize) -> Option<Region> {
+        self.regions.get(index).and_then(|region| region.get())
+    }
+
+    /// Read the table of regions from storage.
+    pub fn load(&self) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.read_table(0, false)
+    }
+
+    fn read_table(&self, copy: usize, found: bool) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            self.operation.set(Operation::Load { copy, found });
+            let ret = self
+                .storage
+                .read(buffer, self.start + copy * TABLE_SIZE, TABLE_SIZE);
+            if ret != ReturnCode::SUCCESS {
+                self.operation.set(Operation::Idle);
+            }
+            ret
+        })
+    }
+
+    /// Use a copy of the table if it is valid and newer than the copy
+    /// already found.
+    fn load_table(&self, table: &[u8], found: bool) -> bool {
+        if &table[0..4] != MAGIC
+            || u32::from_le_bytes(table[CHECKSUM_START..TABLE_SIZE].try_into().unwrap())
+                != checksum(table)
+        {
+            return found;
+        }
+        let sequence = u32::from_le_bytes(table[4..8].try_into().unwrap());
+        if found && sequence <= self.sequence.get() {
+            return found;
+        }
+        self.sequence.set(sequence);
+        for (i, region) in self.regions.iter().enumerate() {
+            let entry = ENTRIES_START + i * ENTRY_SIZE;
+            region.set(Region::decode(&table[entry..entry + ENTRY_SIZE]));
+        }
+        true
+    }
+
+    /// Allocate a region of `size` bytes for the application with package
+    /// name `owner`. The region is filled with zeros.
+    pub fn allocate(&self, owner: &str, size: usize) -> ReturnCode {
+        let ret = self.ready();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+        if owner.is_empty() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.find(owner).is_some() {
+            return ReturnCode::EALREADY;
+        }
+        if size == 0 || size > self.quota {
+            return ReturnCode::ESIZE;
+        }
+        let index = match self
+            .regions
+            .iter()
+            .position(|region| region.get().is_none())
+        {
+            Some(index) => index,
+            None => return ReturnCode::ENOMEM,
+        };
+        let offset = match self.find_space(size) {
+            Some(offset) => offset,
+            None => return ReturnCode::ENOMEM,
+        };
+
+        let mut name = [0; NAME_LENGTH];
+        let length = cmp::min(owner.len(), NAME_LENGTH);
+        name[..length].copy_from_slice(&owner.as_bytes()[..length]);
+        self.staged.set(Some(Region {
+            owner: owner_hash(owner),
+            name,
+            offset,
+            size,
+        }));
+        self.operation.set(Operation::Zero { index, done: 0 });
+        let ret = self.zero_next();
+        if ret != ReturnCode::SUCCESS {
+            self.staged.set(None);
+            self.operation.set(Operation::Idle);
+        }
+        ret
+    }
+
+    /// The first free space of `size` bytes.
+    fn find_space(&self, size: usize) -> Option<usize> {
+        let mut offset = 0;
+        loop {
+            let end = offset + size;
+            if end > self.data_length() {
+                return None;
+            }
+            let overlap = self
+                .regions
+                .iter()
+                .filter_map(|region| region.get())
+                .find(|region| region.offset < end && offset < region.offset + region.size);
+            match overlap {
+                Some(region) => offset = region.offset + region.size,
+                None => return Some(offset),
+            }
+        }
+    }
+
+    /// Zero the next part of the region being allocated, or add it to the
+    /// table when it is all zeroed.
+    fn zero_next(&self) -> ReturnCode {
+        let (index, done) = match self.operation.get() {
+            Operation::Zero { index, done } => (index, done),
+            _ => return ReturnCode::FAIL,
+        };
+        let region = match self.staged.get() {
+            Some(region) => region,
+            None => return ReturnCode::FAIL,
+        };
+        if done == region.size {
+            return self.commit(Commit::Allocate(index));
+        }
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            let length = cmp::min(buffer.len(), region.size - done);
+            buffer[..length].iter_mut().for_each(|b| *b = 0);
+            self.storage
+                .write(buffer, self.data_start() + region.offset + done, length)
+        })
+    }
+
+    /// Free the region of the application with package name `owner`.
+    pub fn free(&self, owner: &str) -> ReturnCode {
+        let ret = self.ready();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+        match self.find(owner) {
+            Some(index) => self.commit(Commit::Free {
+                mask: 1 << index,
+                admin: false,
+            }),
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Free the region in slot `index` of the table, whoever owns it.
+    pub fn free_region(
+        &self,
+        index: usize,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> ReturnCode {
+        let ret = self.ready();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+        if self.region_at(index).is_none() {
+            return ReturnCode::EINVAL;
+        }
+        self.commit(Commit::Free {
+            mask: 1 << index,
+            admin: true,
+        })
+    }
+
+    /// Free the regions of applications that are no longer installed.
+    /// Returns `EALREADY` if there are none, in which case `reclaim_done()`
+    /// isn't called.
+    pub fn reclaim_removed(
+        &self,
+        kernel: &'static Kernel,
+        capability: &dyn ProcessManagementCapability,
+    ) -> ReturnCode {
+        let ret = self.ready();
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+        let installed = Cell::new(0u32);
+        kernel.process_each_capability(capability, |process| {
+            let name = process.get_process_name();
+            if let Some(index) = self.find(name) {
+                installed.set(installed.get() | 1 << index);
+            }
+        });
+        let mut mask = 0;
+        for (index, region) in self.regions.iter().enumerate() {
+            if region.get().is_some() && installed.get() & 1 << index == 0 {
+                mask |= 1 << index;
+            }
+        }
+        if mask == 0 {
+            return ReturnCode::EALREADY;
+        }
+        self.commit(Commit::Free { mask, admin: true })
+    }
+
+    /// Write the table with a change, which takes effect once it is written.
+    fn commit(&self, commit: Commit) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            let sequence = self.sequence.get().wrapping_add(1);
+            let table = &mut buffer[..TABLE_SIZE];
+            table[0..4].copy_from_slice(MAGIC);
+            table[4..8].copy_from_slice(&sequence.to_le_bytes());
+            for (i, region) in self.regions.iter().enumerate() {
+                let region = match commit {
+                    Commit::Allocate(index) if index == i => self.staged.get(),
+                    Commit::Free { mask, .. } if mask & 1 << i != 0 => None,
+                    _ => region.get(),
+                };
+                let entry = ENTRIES_START + i * ENTRY_SIZE;
+                Region::encode(region, &mut table[entry..entry + ENTRY_SIZE]);
+            }
+            let end = ENTRIES_START + MAX_REGIONS * ENTRY_SIZE;
+            table[end..CHECKSUM_START].iter_mut().for_each(|b| *b = 0);
+            let sum = checksum(table);
+            table[CHECKSUM_START..TABLE_SIZE].copy_from_slice(&sum.to_le_bytes());
+
+            self.operation.set(Operation::Commit(commit));
+            let copy = sequence as usize % 2;
+            let ret = self
+                .storage
+                .write(buffer, self.start + copy * TABLE_SIZE, TABLE_SIZE);
+            if ret != ReturnCode::SUCCESS {
+                self.operation.set(Operation::Idle);
+            }
+            ret
+        })
+    }
+
+    /// The table was written, so apply the change.
+    fn committed(&self, commit: Commit) {
+        self.sequence.set(self.sequence.get().wrapping_add(1));
+        self.operation.set(Operation::Idle);
+        match commit {
+            Commit::Allocate(index) => {
+                let region = self.staged.take();
+                self.regions[index].set(region);
+                let size = region.map_or(0, |region| region.size);
+                self.client.map(|client| client.allocate_done(Ok(size)));
+            }
+            Commit::Free { mask, admin } => {
+                for (i, region) in self.regions.iter().enumerate() {
+                    if mask & 1 << i != 0 {
+                        region.set(None);
+                    }
+                }
+                if admin {
+                    let count = mask.count_ones() as usize;
+                    self.admin_client
+                        .map(|client| client.reclaim_done(Ok(count)));
+                } else {
+                    self.client.map(|client| client.free_done(Ok(())));
+                }
+            }
+        }
+    }
+
+    /// Check an access to the region of `owner` and return the address of
+    /// its start.
+    fn address(
+        &self,
+        owner: &str,
+        buffer: &[u8],
+        offset: usize,
+        length: usize,
+    ) -> Result<usize, ReturnCode> {
+        let ret = self.ready();
+        if ret != ReturnCode::SUCCESS {
+            return Err(ret);
+        }
+        let region = self.region(owner).ok_or(ReturnCode::EINVAL)?;
+        match offset.checked_add(length) {
+            Some(end) if length > 0 && end <= region.size && length <= buffer.len() => {
+                Ok(self.data_start() + region.offset + offset)
+            }
+            _ => Err(ReturnCode::EINVAL),
+        }
+    }
+
+    /// Read `length` bytes at `offset` in the region of the application with
+    /// package name `owner`. The buffer is only lost if the storage fails.
+    pub fn read(
+        &self,
+        owner: &str,
+        buffer: &'static mut [u8],
+        offset: usize,
+        length: usize,
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+        let address = match self.address(owner, buffer, offset, length) {
+            Ok(address) => address,
+            Err(e) => return Err((e, Some(buffer))),
+        };
+        self.operation.set(Operation::Read);
+        match self.storage.read(buffer, address, length) {
+            ReturnCode::SUCCESS => Ok(()),
+            e => {
+                self.operation.set(Operation::Idle);
+                Err((e, None))
+            }
+        }
+    }
+
+    /// Write `length` bytes at `offset` in the region of the application
+    /// with package name `owner`. The buffer is only lost if the storage
+    /// fails.
+    pub fn write(
+        &self,
+        owner: &str,
+        buffer: &'static mut [u8],
+        offset: usize,
+        length: usize,
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+        let address = match self.address(owner, buffer, offset, length) {
+            Ok(address) => address,
+            Err(e) => return Err((e, Some(buffer))),
+        };
+        self.operation.set(Operation::Write);
+        match self.storage.write(buffer, address, length) {
+            ReturnCode::SUCCESS => Ok(()),
+            e => {
+                self.operation.set(Operation::Idle);
+                Err((e, None))
+            }
+        }
+    }
+}
+
+impl NonvolatileStorageClient<'static> for NonvolatileRegions<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.operation.get() {
+            Operation::Load { copy, found } => {
+                let found = self.load_table(&buffer[..TABLE_SIZE], found);
+                self.buffer.replace(buffer);
+                if copy == 0 {
+                    let ret = self.read_table(1, found);
+                    if ret != ReturnCode::SUCCESS {
+                        self.client.map(|client| client.load_done(Err(ret)));
+                    }
+                } else {
+                    if !found {
+                        // Nothing has been stored yet
+                        self.sequence.set(0);
+                        self.regions.iter().for_each(|region| region.set(None));
+                    }
+                    self.operation.set(Operation::Idle);
+                    self.loaded.set(true);
+                    self.client.map(|client| client.load_done(Ok(())));
+                }
+            }
+            Operation::Read => {
+                self.operation.set(Operation::Idle);
+                self.client
+                    .map(move |client| client.read_done(buffer, length));
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.operation.get() {
+            Operation::Zero { index, done } => {
+                self.buffer.replace(buffer);
+                self.operation.set(Operation::Zero {
+                    index,
+                    done: done + length,
+                });
+                let ret = self.zero_next();
+                if ret != ReturnCode::SUCCESS {
+                    self.staged.set(None);
+                    self.operation.set(Operation::Idle);
+                    self.client.map(|client| client.allocate_done(Err(ret)));
+                }
+            }
+            Operation::Commit(commit) => {
+                self.buffer.replace(buffer);
+                self.committed(commit);
+            }
+            Operation::Write => {
+                self.operation.set(Operation::Idle);
+                self.client
+                    .map(move |client| client.write_done(buffer, length));
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Syscall driver giving each application its own region of nonvolatile
+//! storage.
+//!
+//! The regions are kept by a `capsules::nonvolatile_regions::NonvolatileRegions`.
+//! An application allocates one region, up to the quota set by the board,
+//! and then reads and writes at offsets within it. The region belongs to the
+//! package name of the application, so it is still there after a reboot or a
+//! reinstall. Applications can't access the regions of other applications,
+//! and applications without a package name can't have a region.
+//!
+//! Only one operation is in flight at a time. Each application can queue one
+//! operation while another operation is running.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let regions_driver_buffer = static_init!([u8; 512], [0; 512]);
+//! let regions_driver = static_init!(
+//!     capsules::nonvolatile_regions_driver::NonvolatileRegionsDriver<'static>,
+//!     capsules::nonvolatile_regions_driver::NonvolatileRegionsDriver::new(
+//!         nonvolatile_regions,
+//!         board_kernel.create_grant(&grant_cap),
+//!         regions_driver_buffer,
+//!     )
+//! );
+//! nonvolatile_regions.set_client(regions_driver);
+//! ```
+
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::nonvolatile_regions::{self, NonvolatileRegions};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::NonvolatileRegions as usize;
+
+#[derive(Clone, Copy, PartialEq)]
+enum UserOperation {
+    Allocate(usize),
+    Free,
+    Read { offset: usize, length: usize },
+    Write { offset: usize, length: usize },
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    write_buffer: Option<AppSlice<Shared, u8>>,
+    pending_command: Option<UserOperation>,
+}
+
+pub struct NonvolatileRegionsDriver<'a> {
+    regions: &'a NonvolatileRegions<'a>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a> NonvolatileRegionsDriver<'a> {
+    pub fn new(
+        regions: &'a NonvolatileRegions<'a>,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> NonvolatileRegionsDriver<'a> {
+        NonvolatileRegionsDriver {
+            regions,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    // Check to see if we are doing something. If not, go ahead and do this
+    // command. If so, this is queued and will be run when the pending command
+    // completes.
+    fn enqueue_command(&self, operation: UserOperation, appid: AppId) -> ReturnCode {
+        if appid.get_process_name().is_empty() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                let ready = match operation {
+                    UserOperation::Read { length, .. } => app
+                        .read_buffer
+                        .as_ref()
+                        .map_or(false, |read_buffer| length <= read_buffer.len()),
+                    UserOperation::Write { length, .. } => app
+                        .write_buffer
+                        .as_ref()
+                        .map_or(false, |write_buffer| length <= write_buffer.len()),
+                    _ => true,
+                };
+                if !ready {
+                    return ReturnCode::EINVAL;
+                }
+
+                if self.current_app.is_none() {
+                    let ret = self.start_command(operation, appid, app);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                    }
+                    ret
+                } else {
+                    // Queue this request for later.
+                    if app.pending_command.is_some() {
+                        ReturnCode::EBUSY
+                    } else {
+                        app.pending_command = Some(operation);
+                        ReturnCode::SUCCESS
+                    }
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn start_command(&self, operation: UserOperation, appid: AppId, app: &mut App) -> ReturnCode {
+        let owner = appid.get_process_name();
+        match operation {
+            UserOperation::Allocate(size) => self.regions.allocate(owner, size),
+            UserOperation::Free => self.regions.free(owner),
+            UserOperation::Read { offset, length } => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    // Reads aren't split, so they must fit in the kernel
+                    // buffer.
+                    if length > buffer.len() {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::ESIZE;
+                    }
+                    match self.regions.read(owner, buffer, offset, length) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((e, buffer)) => {
+                            buffer.map(|buffer| self.buffer.replace(buffer));
+                            e
+                        }
+                    }
+                })
+            }
+            UserOperation::Write { offset, length } => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    if length > buffer.len() {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::ESIZE;
+                    }
+                    let copied = app.write_buffer.as_ref().map_or(false, |write_buffer| {
+                        if length <= write_buffer.len() {
+                            buffer[..length].copy_from_slice(&write_buffer.as_ref()[..length]);
+                            true
+                        } else {
+                            false
+                        }
+                    });
+                    if !copied {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::EINVAL;
+                    }
+                    match self.regions.write(owner, buffer, offset, length) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((e, buffer)) => {
+                            buffer.map(|buffer| self.buffer.replace(buffer));
+                            e
+                        }
+                    }
+                })
+            }
+        }
+    }
+
+    /// Notify the current application and start the next queued command.
+    fn complete_command(&self, ret: ReturnCode, value: usize) {
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback.map(|mut cb| {
+                    cb.schedule(usize::from(ret), value, 0);
+                });
+            });
+        });
+
+        // Check if there are any pending events.
+        for cntr in self.apps.iter() {
+            let started_command = cntr.enter(|app, _| {
+                let appid = app.appid();
+                app.pending_command.take().map_or(false, |operation| {
+                    let ret = self.start_command(operation, appid, app);
+                    if ret == ReturnCode::SUCCESS {
+                        self.current_app.set(appid);
+                        true
+                    } else {
+                        app.callback.map(|mut cb| {
+                            cb.schedule(usize::from(ret), 0, 0);
+                        });
+                        false
+                    }
+                })
+            });
+            if started_command {
+                break;
+            }
+        }
+    }
+}
+
+impl nonvolatile_regions::Client for NonvolatileRegionsDriver<'_> {
+    fn load_done(&self, _result: Result<(), ReturnCode>) {}
+
+    fn allocate_done(&self, result: Result<usize, ReturnCode>) {
+        match result {
+            Ok(size) => self.complete_command(ReturnCode::SUCCESS, size),
+            Err(e) => self.complete_command(e, 0),
+        }
+    }
+
+    fn free_done(&self, result: Result<(), ReturnCode>) {
+        self.complete_command(result.err().unwrap_or(ReturnCode::SUCCESS), 0);
+    }
+
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.read_buffer.as_mut().map(|read_buffer| {
+                    let copy = cmp::min(length, read_buffer.len());
+                    read_buffer.as_mut()[..copy].copy_from_slice(&buffer[..copy]);
+                });
+            });
+        });
+        self.buffer.replace(buffer);
+        self.complete_command(ReturnCode::SUCCESS, length);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.complete_command(ReturnCode::SUCCESS, length);
+    }
+}
+
+impl Driver for NonvolatileRegionsDriver<'_> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Set the buffer that the region is read into.
+    /// - `1`: Set the buffer holding the data to write to the region.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.read_buffer = slice,
+                    1 => app.write_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Set the callback for completed operations. The callback receives
+    ///        the `ReturnCode` of the operation and the size of the allocated
+    ///        region or the number of bytes read or written.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Region operations.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the size of the region of the application, or 0 if it
+    ///        doesn't have one.
+    /// - `2`: Allocate a region of `data` bytes.
+    /// - `3`: Free the region of the application.
+    /// - `4`: Read `data2` bytes at offset `data` of the region into the
+    ///        `allow` 0 buffer.
+    /// - `5`: Write `data2` bytes from the `allow` 1 buffer at offset `data`
+    ///        of the region.
+    /// - `6`: Get the largest region an application can allocate.
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 =>
+            /* This driver exists. */
+            {
+                ReturnCode::SUCCESS
+            }
+
+            1 => ReturnCode::SuccessWithValue {
+                value: self
+                    .regions
+                    .region(appid.get_process_name())
+                    .map_or(0, |region| region.size),
+            },
+            2 => self.enqueue_command(UserOperation::Allocate(data), appid),
+            3 => self.enqueue_command(UserOperation::Free, appid),
+            4 => self.enqueue_command(
+                UserOperation::Read {
+                    offset: data,
+                    length: data2,
+                },
+                appid,
+            ),
+            5 => self.enqueue_command(
+                UserOperation::Write {
+                    offset: data,
+                    length: data2,
+                },
+                appid,
+            ),
+            6 => ReturnCode::SuccessWithValue {
+                value: self.regions.quota(),
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
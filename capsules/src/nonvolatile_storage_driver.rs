@@ -2,8 +2,10 @@
 //!
 //! This is an initial implementation that does not provide safety for
 //! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! the entire memory space that has been provided to userland. Boards that
+//! need to keep applications apart should use
+//! `capsules::nonvolatile_regions_driver` instead, which gives each
+//! application its own region.
 //!
 //! However, the kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
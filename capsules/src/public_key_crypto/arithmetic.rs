@@ -0,0 +1,305 @@
+//! Arithmetic on 256-bit numbers and elliptic curve points.
+//!
+//! Numbers are stored as eight 32-bit limbs, least significant first, so
+//! that the products fit in a `u64` on 32-bit chips. `Modulus` implements
+//! arithmetic modulo an odd number in the Montgomery domain, which all the
+//! field and scalar arithmetic of the curves uses.
+//!
+//! None of this runs in constant time. It's only used to verify signatures,
+//! where all the inputs are public.
+
+use core::ops::Range;
+
+pub type U256 = [u32; 8];
+
+pub const ZERO: U256 = [0; 8];
+
+/// The number of scalar bits handled in each step of `double_multiply`.
+pub const BITS_PER_STEP: usize = 16;
+
+/// Writes a number given as eight big-endian words, as constants are
+/// usually written, as limbs.
+pub const fn be_words(w: [u32; 8]) -> U256 {
+    [w[7], w[6], w[5], w[4], w[3], w[2], w[1], w[0]]
+}
+
+/// Reads a big-endian number of up to 32 bytes.
+pub fn from_be_bytes(bytes: &[u8]) -> U256 {
+    let mut a = ZERO;
+    for (i, b) in bytes.iter().rev().enumerate() {
+        a[i / 4] |= (*b as u32) << (8 * (i % 4));
+    }
+    a
+}
+
+/// Reads a little-endian number of up to 32 bytes.
+pub fn from_le_bytes(bytes: &[u8]) -> U256 {
+    let mut a = ZERO;
+    for (i, b) in bytes.iter().enumerate() {
+        a[i / 4] |= (*b as u32) << (8 * (i % 4));
+    }
+    a
+}
+
+pub fn to_le_bytes(a: &U256) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (chunk, limb) in bytes.chunks_mut(4).zip(a.iter()) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    bytes
+}
+
+pub fn is_zero(a: &U256) -> bool {
+    a.iter().all(|limb| *limb == 0)
+}
+
+pub fn less_than(a: &U256, b: &U256) -> bool {
+    for i in (0..8).rev() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+    false
+}
+
+pub fn bit(a: &U256, i: usize) -> bool {
+    (a[i / 32] >> (i % 32)) & 1 == 1
+}
+
+/// Returns `a + b` and the carry.
+fn add_carry(a: &U256, b: &U256) -> (U256, bool) {
+    let mut sum = ZERO;
+    let mut carry = 0u64;
+    for i in 0..8 {
+        let s = a[i] as u64 + b[i] as u64 + carry;
+        sum[i] = s as u32;
+        carry = s >> 32;
+    }
+    (sum, carry != 0)
+}
+
+/// Returns `a - b` and the borrow.
+fn sub_borrow(a: &U256, b: &U256) -> (U256, bool) {
+    let mut difference = ZERO;
+    let mut borrow = 0i64;
+    for i in 0..8 {
+        let d = a[i] as i64 - b[i] as i64 + borrow;
+        difference[i] = d as u32;
+        borrow = d >> 32;
+    }
+    (difference, borrow != 0)
+}
+
+/// Arithmetic modulo an odd number `m`. Apart from `to_mont()`, all the
+/// arguments must be less than `m`.
+#[derive(Clone, Copy)]
+pub struct Modulus {
+    pub m: U256,
+    /// -m^-1 mod 2^32
+    m_inv: u32,
+    /// R^2 mod m, where R = 2^256
+    r2: U256,
+}
+
+impl Modulus {
+    pub fn new(m: U256) -> Modulus {
+        // Newton's iteration doubles the number of correct bits each time.
+        let mut inv = 1u32;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(m[0].wrapping_mul(inv)));
+        }
+
+        let mut modulus = Modulus {
+            m,
+            m_inv: inv.wrapping_neg(),
+            r2: ZERO,
+        };
+        let mut r2 = [1, 0, 0, 0, 0, 0, 0, 0];
+        for _ in 0..512 {
+            r2 = modulus.add(&r2, &r2);
+        }
+        modulus.r2 = r2;
+        modulus
+    }
+
+    /// Whether `a` is a reduced number.
+    pub fn contains(&self, a: &U256) -> bool {
+        less_than(a, &self.m)
+    }
+
+    pub fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (sum, carry) = add_carry(a, b);
+        if carry || !less_than(&sum, &self.m) {
+            sub_borrow(&sum, &self.m).0
+        } else {
+            sum
+        }
+    }
+
+    pub fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (difference, borrow) = sub_borrow(a, b);
+        if borrow {
+            add_carry(&difference, &self.m).0
+        } else {
+            difference
+        }
+    }
+
+    pub fn neg(&self, a: &U256) -> U256 {
+        self.sub(&ZERO, a)
+    }
+
+    /// Montgomery multiplication, `a * b / R mod m`.
+    pub fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let mut t = [0u32; 10];
+        for i in 0..8 {
+            let mut carry = 0u64;
+            for j in 0..8 {
+                let s = t[j] as u64 + a[j] as u64 * b[i] as u64 + carry;
+                t[j] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[8] as u64 + carry;
+            t[8] = s as u32;
+            t[9] = (s >> 32) as u32;
+
+            // Add a multiple of m that clears the lowest limb, and shift.
+            let u = t[0].wrapping_mul(self.m_inv) as u64;
+            let mut carry = (t[0] as u64 + u * self.m[0] as u64) >> 32;
+            for j in 1..8 {
+                let s = t[j] as u64 + u * self.m[j] as u64 + carry;
+                t[j - 1] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[8] as u64 + carry;
+            t[7] = s as u32;
+            t[8] = t[9] + (s >> 32) as u32;
+        }
+
+        let mut result = ZERO;
+        result.copy_from_slice(&t[..8]);
+        if t[8] != 0 || !less_than(&result, &self.m) {
+            sub_borrow(&result, &self.m).0
+        } else {
+            result
+        }
+    }
+
+    pub fn square(&self, a: &U256) -> U256 {
+        self.mul(a, a)
+    }
+
+    /// Converts any 256-bit number to the Montgomery domain, reducing it.
+    pub fn to_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    pub fn from_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &[1, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    /// One in the Montgomery domain.
+    pub fn one(&self) -> U256 {
+        self.to_mont(&[1, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    pub fn pow(&self, a: &U256, exponent: &U256) -> U256 {
+        let mut result = self.one();
+        for i in (0..256).rev() {
+            result = self.square(&result);
+            if bit(exponent, i) {
+                result = self.mul(&result, a);
+            }
+        }
+        result
+    }
+
+    /// The inverse of a nonzero number when `m` is prime.
+    pub fn invert(&self, a: &U256) -> U256 {
+        let two = [2, 0, 0, 0, 0, 0, 0, 0];
+        self.pow(a, &sub_borrow(&self.m, &two).0)
+    }
+}
+
+/// The points of an elliptic curve.
+pub trait Group {
+    type Point: Copy;
+
+    fn identity(&self) -> Self::Point;
+    fn add(&self, p: &Self::Point, q: &Self::Point) -> Self::Point;
+    fn double(&self, p: &Self::Point) -> Self::Point;
+}
+
+/// Computes the bits in `bits` of `a * P + b * Q`, using Shamir's trick.
+///
+/// `table` holds `P`, `Q` and `P + Q`. Computing the whole product takes
+/// calls for each `BITS_PER_STEP` bits from the top, passing the result of
+/// one call as `acc` to the next, starting from the identity.
+pub fn double_multiply<G: Group>(
+    group: &G,
+    mut acc: G::Point,
+    table: &[G::Point; 3],
+    scalars: &[U256; 2],
+    bits: Range<usize>,
+) -> G::Point {
+    for i in bits.rev() {
+        acc = group.double(&acc);
+        let index = bit(&scalars[0], i) as usize | (bit(&scalars[1], i) as usize) << 1;
+        if index != 0 {
+            acc = group.add(&acc, &table[index - 1]);
+        }
+    }
+    acc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The order of the P-256 group.
+    const N: U256 = be_words([
+        0xffffffff, 0x00000000, 0xffffffff, 0xffffffff, 0xbce6faad, 0xa7179e84, 0xf3b9cac2,
+        0xfc632551,
+    ]);
+
+    #[test]
+    fn montgomery() {
+        let n = Modulus::new(N);
+        let a = from_be_bytes(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x11]);
+        let b = sub_borrow(&N, &[5, 0, 0, 0, 0, 0, 0, 0]).0;
+
+        let a_mont = n.to_mont(&a);
+        let b_mont = n.to_mont(&b);
+        assert_eq!(n.from_mont(&a_mont), a);
+
+        // b = -5, so a * b = -5a.
+        let product = n.from_mont(&n.mul(&a_mont, &b_mont));
+        let mut five_a = ZERO;
+        for _ in 0..5 {
+            five_a = n.add(&five_a, &a);
+        }
+        assert_eq!(product, n.neg(&five_a));
+
+        let inverse = n.invert(&a_mont);
+        assert_eq!(n.mul(&inverse, &a_mont), n.one());
+
+        // Numbers above the modulus are reduced.
+        let reduced = n.from_mont(&n.to_mont(&[u32::MAX; 8]));
+        assert_eq!(reduced, sub_borrow(&[u32::MAX; 8], &N).0);
+    }
+
+    #[test]
+    fn bytes() {
+        let mut bytes = [0; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let a = from_le_bytes(&bytes);
+        assert_eq!((a[0], a[7]), (0x03020100, 0x1f1e1d1c));
+        assert_eq!(to_le_bytes(&a), bytes);
+        bytes.reverse();
+        assert_eq!(from_be_bytes(&bytes), a);
+        assert_eq!(from_be_bytes(&[1, 2]), [0x0102, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(bit(&a, 8) && !bit(&a, 0));
+    }
+}
@@ -0,0 +1,368 @@
+//! Software ECDSA signature verification on the NIST P-256 curve.
+//!
+//! `EcdsaP256` implements `kernel::hil::public_key_crypto::SignatureVerify`
+//! as specified in FIPS 186-4, for chips without public key hardware.
+//!
+//! - Public keys are 64 bytes: the big-endian X and Y coordinates of the
+//!   point, as in an uncompressed SEC1 point without the leading `0x04`.
+//! - Signatures are 64 bytes: the big-endian `r` and `s`.
+//! - The message is the digest of the signed data, usually its SHA-256
+//!   digest. Only the leftmost 256 bits of longer digests are used.
+//!
+//! A verification takes one deferred call to compute the scalars, one for
+//! each `BITS_PER_STEP` bits of the scalar multiplication and one to check
+//! the result, so it doesn't hold up the rest of the kernel for long.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ecdsa = static_init!(
+//!     capsules::public_key_crypto::ecdsa_p256::EcdsaP256<'static>,
+//!     capsules::public_key_crypto::ecdsa_p256::EcdsaP256::new(dynamic_deferred_caller)
+//! );
+//! ecdsa.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ecdsa)
+//!         .expect("no deferred call slot available for ecdsa"),
+//! );
+//! ecdsa.set_verify_client(client);
+//! ecdsa.set_public_key(&PUBLIC_KEY);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::public_key_crypto::{ClientVerify, SignatureVerify};
+use kernel::ReturnCode;
+
+use super::arithmetic::{
+    be_words, double_multiply, from_be_bytes, is_zero, Group, Modulus, BITS_PER_STEP, U256, ZERO,
+};
+
+pub const PUBLIC_KEY_SIZE: usize = 64;
+pub const SIGNATURE_SIZE: usize = 64;
+
+const P: U256 = be_words([
+    0xffffffff, 0x00000001, 0x00000000, 0x00000000, 0x00000000, 0xffffffff, 0xffffffff, 0xffffffff,
+]);
+const N: U256 = be_words([
+    0xffffffff, 0x00000000, 0xffffffff, 0xffffffff, 0xbce6faad, 0xa7179e84, 0xf3b9cac2, 0xfc632551,
+]);
+const B: U256 = be_words([
+    0x5ac635d8, 0xaa3a93e7, 0xb3ebbd55, 0x769886bc, 0x651d06b0, 0xcc53b0f6, 0x3bce3c3e, 0x27d2604b,
+]);
+const GX: U256 = be_words([
+    0x6b17d1f2, 0xe12c4247, 0xf8bce6e5, 0x63a440f2, 0x77037d81, 0x2deb33a0, 0xf4a13945, 0xd898c296,
+]);
+const GY: U256 = be_words([
+    0x4fe342e2, 0xfe1a7f9b, 0x8ee7eb4a, 0x7c0f9e16, 0x2bce3357, 0x6b315ece, 0xcbb64068, 0x37bf51f5,
+]);
+
+/// A point in Jacobian coordinates, in the Montgomery domain. The point at
+/// infinity has `z == 0`.
+#[derive(Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+/// The curve y^2 = x^3 - 3x + b over the integers modulo `P`.
+struct Curve {
+    p: Modulus,
+    b: U256,
+}
+
+impl Curve {
+    /// Returns the point with affine coordinates `x` and `y`, if it is on
+    /// the curve.
+    fn point(&self, x: &U256, y: &U256) -> Option<Point> {
+        let f = &self.p;
+        if !f.contains(x) || !f.contains(y) {
+            return None;
+        }
+        let x = f.to_mont(x);
+        let y = f.to_mont(y);
+        let x3 = f.mul(&f.square(&x), &x);
+        let three_x = f.add(&f.add(&x, &x), &x);
+        let rhs = f.add(&f.sub(&x3, &three_x), &self.b);
+        if f.square(&y) != rhs {
+            return None;
+        }
+        Some(Point { x, y, z: f.one() })
+    }
+
+    /// The affine X coordinate of a point other than infinity.
+    fn affine_x(&self, point: &Point) -> U256 {
+        let f = &self.p;
+        let z_inv = f.invert(&point.z);
+        f.from_mont(&f.mul(&point.x, &f.square(&z_inv)))
+    }
+}
+
+impl Group for Curve {
+    type Point = Point;
+
+    fn identity(&self) -> Point {
+        Point {
+            x: self.p.one(),
+            y: self.p.one(),
+            z: ZERO,
+        }
+    }
+
+    // dbl-2001-b from the Explicit-Formulas Database.
+    fn double(&self, p1: &Point) -> Point {
+        let f = &self.p;
+        if is_zero(&p1.z) {
+            return *p1;
+        }
+        let delta = f.square(&p1.z);
+        let gamma = f.square(&p1.y);
+        let beta = f.mul(&p1.x, &gamma);
+        let t = f.mul(&f.sub(&p1.x, &delta), &f.add(&p1.x, &delta));
+        let alpha = f.add(&f.add(&t, &t), &t);
+        let beta2 = f.add(&beta, &beta);
+        let beta4 = f.add(&beta2, &beta2);
+        let beta8 = f.add(&beta4, &beta4);
+        let x = f.sub(&f.square(&alpha), &beta8);
+        let z = f.sub(&f.sub(&f.square(&f.add(&p1.y, &p1.z)), &gamma), &delta);
+        let gamma_sq = f.square(&gamma);
+        let gamma_sq2 = f.add(&gamma_sq, &gamma_sq);
+        let gamma_sq4 = f.add(&gamma_sq2, &gamma_sq2);
+        let gamma_sq8 = f.add(&gamma_sq4, &gamma_sq4);
+        let y = f.sub(&f.mul(&alpha, &f.sub(&beta4, &x)), &gamma_sq8);
+        Point { x, y, z }
+    }
+
+    // add-2007-bl from the Explicit-Formulas Database.
+    fn add(&self, p1: &Point, p2: &Point) -> Point {
+        let f = &self.p;
+        if is_zero(&p1.z) {
+            return *p2;
+        }
+        if is_zero(&p2.z) {
+            return *p1;
+        }
+        let z1z1 = f.square(&p1.z);
+        let z2z2 = f.square(&p2.z);
+        let u1 = f.mul(&p1.x, &z2z2);
+        let u2 = f.mul(&p2.x, &z1z1);
+        let s1 = f.mul(&f.mul(&p1.y, &p2.z), &z2z2);
+        let s2 = f.mul(&f.mul(&p2.y, &p1.z), &z1z1);
+        let h = f.sub(&u2, &u1);
+        let r = f.sub(&s2, &s1);
+        if is_zero(&h) {
+            return if is_zero(&r) {
+                self.double(p1)
+            } else {
+                self.identity()
+            };
+        }
+        let h2 = f.add(&h, &h);
+        let i = f.square(&h2);
+        let j = f.mul(&h, &i);
+        let r = f.add(&r, &r);
+        let v = f.mul(&u1, &i);
+        let x = f.sub(&f.sub(&f.square(&r), &j), &f.add(&v, &v));
+        let s1j = f.mul(&s1, &j);
+        let y = f.sub(&f.mul(&r, &f.sub(&v, &x)), &f.add(&s1j, &s1j));
+        let z = f.mul(
+            &f.sub(&f.sub(&f.square(&f.add(&p1.z, &p2.z)), &z1z1), &z2z2),
+            &h,
+        );
+        Point { x, y, z }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Idle,
+    /// Computing the scalars from the signature and the message
+    Scalars,
+    /// Computing the bits of the scalar multiplication below this one
+    Multiply(usize),
+    /// Comparing the result with the signature
+    Compare,
+}
+
+pub struct EcdsaP256<'a> {
+    client: OptionalCell<&'a dyn ClientVerify<'a>>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    curve: Curve,
+    n: Modulus,
+    public_key: Cell<Option<Point>>,
+
+    message: TakeCell<'static, [u8]>,
+    signature: TakeCell<'static, [u8]>,
+    step: Cell<Step>,
+    // The state of the scalar multiplication.
+    table: Cell<[Point; 3]>,
+    scalars: Cell<[U256; 2]>,
+    acc: Cell<Point>,
+}
+
+impl<'a> EcdsaP256<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> EcdsaP256<'a> {
+        let p = Modulus::new(P);
+        let curve = Curve {
+            b: p.to_mont(&B),
+            p,
+        };
+        let identity = curve.identity();
+        EcdsaP256 {
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            curve,
+            n: Modulus::new(N),
+            public_key: Cell::new(None),
+            message: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            step: Cell::new(Step::Idle),
+            table: Cell::new([identity; 3]),
+            scalars: Cell::new([ZERO; 2]),
+            acc: Cell::new(identity),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule(&self, step: Step) {
+        self.step.set(step);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn done(&self, result: Result<bool, ReturnCode>) {
+        self.step.set(Step::Idle);
+        if let (Some(message), Some(signature)) = (self.message.take(), self.signature.take()) {
+            self.client
+                .map(move |client| client.verification_done(result, message, signature));
+        }
+    }
+
+    /// Computes `u1 = e / s` and `u2 = r / s`, or returns `None` if the
+    /// signature is out of range.
+    fn scalars(&self, message: &[u8], signature: &[u8]) -> Option<[U256; 2]> {
+        let n = &self.n;
+        let r = from_be_bytes(&signature[..32]);
+        let s = from_be_bytes(&signature[32..64]);
+        if is_zero(&r) || is_zero(&s) || !n.contains(&r) || !n.contains(&s) {
+            return None;
+        }
+        let e = from_be_bytes(&message[..core::cmp::min(message.len(), 32)]);
+        let w = n.invert(&n.to_mont(&s));
+        Some([
+            n.from_mont(&n.mul(&n.to_mont(&e), &w)),
+            n.from_mont(&n.mul(&n.to_mont(&r), &w)),
+        ])
+    }
+}
+
+impl<'a> SignatureVerify<'a> for EcdsaP256<'a> {
+    fn set_verify_client(&'a self, client: &'a dyn ClientVerify<'a>) {
+        self.client.set(client);
+    }
+
+    fn set_public_key(&self, public_key: &[u8]) -> Result<(), ReturnCode> {
+        if self.step.get() != Step::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.public_key.set(None);
+        if public_key.len() != PUBLIC_KEY_SIZE {
+            return Err(ReturnCode::EINVAL);
+        }
+        let x = from_be_bytes(&public_key[..32]);
+        let y = from_be_bytes(&public_key[32..]);
+        let point = self.curve.point(&x, &y).ok_or(ReturnCode::EINVAL)?;
+        self.public_key.set(Some(point));
+        Ok(())
+    }
+
+    fn verify(
+        &'a self,
+        message: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if self.step.get() != Step::Idle {
+            return Err((ReturnCode::EBUSY, message, signature));
+        }
+        if self.public_key.get().is_none() {
+            return Err((ReturnCode::EOFF, message, signature));
+        }
+        if signature.len() != SIGNATURE_SIZE {
+            return Err((ReturnCode::ESIZE, message, signature));
+        }
+        if self.handle.is_none() {
+            return Err((ReturnCode::FAIL, message, signature));
+        }
+        self.message.replace(message);
+        self.signature.replace(signature);
+        self.schedule(Step::Scalars);
+        Ok(())
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for EcdsaP256<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        match self.step.get() {
+            Step::Idle => {}
+            Step::Scalars => {
+                let scalars = self
+                    .message
+                    .map(|message| {
+                        self.signature
+                            .map(|signature| self.scalars(message, signature))
+                    })
+                    .flatten()
+                    .flatten();
+                match (scalars, self.public_key.get()) {
+                    (Some(scalars), Some(q)) => {
+                        let g = self.curve.point(&GX, &GY).unwrap();
+                        self.table.set([g, q, self.curve.add(&g, &q)]);
+                        self.scalars.set(scalars);
+                        self.acc.set(self.curve.identity());
+                        self.schedule(Step::Multiply(256));
+                    }
+                    _ => self.done(Ok(false)),
+                }
+            }
+            Step::Multiply(high) => {
+                let low = high - BITS_PER_STEP;
+                let acc = double_multiply(
+                    &self.curve,
+                    self.acc.get(),
+                    &self.table.get(),
+                    &self.scalars.get(),
+                    low..high,
+                );
+                self.acc.set(acc);
+                self.schedule(if low == 0 {
+                    Step::Compare
+                } else {
+                    Step::Multiply(low)
+                });
+            }
+            Step::Compare => {
+                let acc = self.acc.get();
+                let valid = !is_zero(&acc.z)
+                    && self.signature.map_or(false, |signature| {
+                        // The X coordinate is reduced modulo N, which is
+                        // smaller than P.
+                        let x = self.curve.affine_x(&acc);
+                        self.n.from_mont(&self.n.to_mont(&x)) == from_be_bytes(&signature[..32])
+                    });
+                self.done(Ok(valid));
+            }
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! Software Ed25519 signature verification.
+//!
+//! `Ed25519` implements `kernel::hil::public_key_crypto::SignatureVerify` as
+//! specified in RFC 8032, for chips without public key hardware.
+//!
+//! - Public keys are the 32-byte encoded points of RFC 8032. They are
+//!   decoded, and checked to be on the curve, when they are set.
+//! - Signatures are 64 bytes: the encoded point `R` and the little-endian
+//!   scalar `S`.
+//! - The message is the signed data itself, which is hashed with SHA-512 as
+//!   part of the verification.
+//!
+//! A verification takes one deferred call for each `BYTES_PER_CALL` bytes
+//! of the message, one for each `BITS_PER_STEP` bits of the scalar
+//! multiplication and one to check the result, so it doesn't hold up the
+//! rest of the kernel for long. The result is checked by encoding
+//! `[S]B - [k]A` and comparing it with `R`, without the cofactor.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ed25519 = static_init!(
+//!     capsules::public_key_crypto::ed25519::Ed25519<'static>,
+//!     capsules::public_key_crypto::ed25519::Ed25519::new(dynamic_deferred_caller)
+//! );
+//! ed25519.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ed25519)
+//!         .expect("no deferred call slot available for ed25519"),
+//! );
+//! ed25519.set_verify_client(client);
+//! ed25519.set_public_key(&PUBLIC_KEY);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::public_key_crypto::{ClientVerify, SignatureVerify};
+use kernel::ReturnCode;
+
+use super::arithmetic::{
+    be_words, double_multiply, from_le_bytes, is_zero, to_le_bytes, Group, Modulus, BITS_PER_STEP,
+    U256, ZERO,
+};
+use crate::software_sha::{Sha2, Sha512, BYTES_PER_CALL};
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// 2^255 - 19
+const P: U256 = be_words([
+    0x7fffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffed,
+]);
+/// (P - 5) / 8, for computing square roots
+const P_5_8: U256 = be_words([
+    0x0fffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xfffffffd,
+]);
+/// The order of the base point
+const L: U256 = be_words([
+    0x10000000, 0x00000000, 0x00000000, 0x00000000, 0x14def9de, 0xa2f79cd6, 0x5812631a, 0x5cf5d3ed,
+]);
+/// -121665 / 121666
+const D: U256 = be_words([
+    0x52036cee, 0x2b6ffe73, 0x8cc74079, 0x7779e898, 0x00700a4d, 0x4141d8ab, 0x75eb4dca, 0x135978a3,
+]);
+/// A square root of -1
+const SQRT_M1: U256 = be_words([
+    0x2b832480, 0x4fc1df0b, 0x2b4d0099, 0x3dfbd7a7, 0x2f431806, 0xad2fe478, 0xc4ee1b27, 0x4a0ea0b0,
+]);
+/// The encoded base point
+const BASE: [u8; 32] = [
+    0x58, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+    0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+];
+
+/// A point in extended coordinates, in the Montgomery domain.
+#[derive(Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+    t: U256,
+}
+
+/// The twisted Edwards curve -x^2 + y^2 = 1 + d x^2 y^2 over the integers
+/// modulo `P`.
+struct Curve {
+    p: Modulus,
+    d: U256,
+    d2: U256,
+}
+
+impl Curve {
+    fn new() -> Curve {
+        let p = Modulus::new(P);
+        let d = p.to_mont(&D);
+        Curve {
+            d2: p.add(&d, &d),
+            d,
+            p,
+        }
+    }
+
+    /// Decodes a point, as in section 5.1.3 of RFC 8032.
+    fn decode(&self, bytes: &[u8]) -> Option<Point> {
+        let f = &self.p;
+        let sign = bytes[31] >> 7;
+        let mut y = from_le_bytes(bytes);
+        y[7] &= 0x7fff_ffff;
+        if !f.contains(&y) {
+            return None;
+        }
+
+        // x^2 = u / v
+        let y = f.to_mont(&y);
+        let yy = f.square(&y);
+        let u = f.sub(&yy, &f.one());
+        let v = f.add(&f.mul(&self.d, &yy), &f.one());
+        let v3 = f.mul(&f.square(&v), &v);
+        let v7 = f.mul(&f.square(&v3), &v);
+        let mut x = f.mul(&f.mul(&u, &v3), &f.pow(&f.mul(&u, &v7), &P_5_8));
+        let vxx = f.mul(&v, &f.square(&x));
+        if vxx != u {
+            if vxx != f.neg(&u) {
+                return None;
+            }
+            x = f.mul(&x, &f.to_mont(&SQRT_M1));
+        }
+
+        let x_parity = (f.from_mont(&x)[0] & 1) as u8;
+        if is_zero(&x) && sign == 1 {
+            return None;
+        }
+        if x_parity != sign {
+            x = f.neg(&x);
+        }
+        Some(Point {
+            x,
+            y,
+            z: f.one(),
+            t: f.mul(&x, &y),
+        })
+    }
+
+    fn encode(&self, point: &Point) -> [u8; 32] {
+        let f = &self.p;
+        let z_inv = f.invert(&point.z);
+        let x = f.from_mont(&f.mul(&point.x, &z_inv));
+        let y = f.from_mont(&f.mul(&point.y, &z_inv));
+        let mut bytes = to_le_bytes(&y);
+        bytes[31] |= ((x[0] & 1) as u8) << 7;
+        bytes
+    }
+
+    fn neg(&self, point: &Point) -> Point {
+        Point {
+            x: self.p.neg(&point.x),
+            t: self.p.neg(&point.t),
+            ..*point
+        }
+    }
+}
+
+impl Group for Curve {
+    type Point = Point;
+
+    fn identity(&self) -> Point {
+        Point {
+            x: ZERO,
+            y: self.p.one(),
+            z: self.p.one(),
+            t: ZERO,
+        }
+    }
+
+    // add-2008-hwcd-3 from the Explicit-Formulas Database, which also works
+    // for doubling.
+    fn add(&self, p1: &Point, p2: &Point) -> Point {
+        let f = &self.p;
+        let a = f.mul(&f.sub(&p1.y, &p1.x), &f.sub(&p2.y, &p2.x));
+        let b = f.mul(&f.add(&p1.y, &p1.x), &f.add(&p2.y, &p2.x));
+        let c = f.mul(&f.mul(&p1.t, &self.d2), &p2.t);
+        let zz = f.mul(&p1.z, &p2.z);
+        let d = f.add(&zz, &zz);
+        let e = f.sub(&b, &a);
+        let ff = f.sub(&d, &c);
+        let g = f.add(&d, &c);
+        let h = f.add(&b, &a);
+        Point {
+            x: f.mul(&e, &ff),
+            y: f.mul(&g, &h),
+            t: f.mul(&e, &h),
+            z: f.mul(&ff, &g),
+        }
+    }
+
+    fn double(&self, p: &Point) -> Point {
+        self.add(p, p)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Idle,
+    /// Hashing the message from this offset
+    Hash(usize),
+    /// Computing the bits of the scalar multiplication below this one
+    Multiply(usize),
+    /// Comparing the result with the signature
+    Compare,
+}
+
+pub struct Ed25519<'a> {
+    client: OptionalCell<&'a dyn ClientVerify<'a>>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    curve: Curve,
+    l: Modulus,
+    /// The encoded and decoded public key
+    public_key: Cell<Option<([u8; PUBLIC_KEY_SIZE], Point)>>,
+
+    message: TakeCell<'static, [u8]>,
+    signature: TakeCell<'static, [u8]>,
+    step: Cell<Step>,
+    sha: Cell<Sha512>,
+    // The state of the scalar multiplication.
+    table: Cell<[Point; 3]>,
+    scalars: Cell<[U256; 2]>,
+    acc: Cell<Point>,
+}
+
+impl<'a> Ed25519<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> Ed25519<'a> {
+        let curve = Curve::new();
+        let identity = curve.identity();
+        Ed25519 {
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            curve,
+            l: Modulus::new(L),
+            public_key: Cell::new(None),
+            message: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            step: Cell::new(Step::Idle),
+            sha: Cell::new(Sha512::new()),
+            table: Cell::new([identity; 3]),
+            scalars: Cell::new([ZERO; 2]),
+            acc: Cell::new(identity),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule(&self, step: Step) {
+        self.step.set(step);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn done(&self, result: Result<bool, ReturnCode>) {
+        self.step.set(Step::Idle);
+        if let (Some(message), Some(signature)) = (self.message.take(), self.signature.take()) {
+            self.client
+                .map(move |client| client.verification_done(result, message, signature));
+        }
+    }
+
+    /// Hashes the next part of the message, and sets up the scalar
+    /// multiplication once all of it is hashed.
+    fn hash(&self, offset: usize) {
+        let (encoded_key, key) = match self.public_key.get() {
+            Some(public_key) => public_key,
+            None => return self.done(Err(ReturnCode::EOFF)),
+        };
+        let signature = match self.signature.map(|signature| {
+            let mut copy = [0; SIGNATURE_SIZE];
+            copy.copy_from_slice(signature);
+            copy
+        }) {
+            Some(signature) => signature,
+            None => return,
+        };
+
+        let mut sha = self.sha.get();
+        if offset == 0 {
+            // S must be reduced.
+            if !self.l.contains(&from_le_bytes(&signature[32..])) {
+                return self.done(Ok(false));
+            }
+            sha = Sha512::new();
+            sha.update(&signature[..32]);
+            sha.update(&encoded_key);
+        }
+        let length = self.message.map_or(0, |message| {
+            let end = cmp::min(offset + BYTES_PER_CALL, message.len());
+            sha.update(&message[offset..end]);
+            message.len()
+        });
+        if offset + BYTES_PER_CALL < length {
+            self.sha.set(sha);
+            return self.schedule(Step::Hash(offset + BYTES_PER_CALL));
+        }
+
+        // k is the hash as a little-endian number modulo L. The upper half
+        // is multiplied by 2^256, which is what converting it to the
+        // Montgomery domain does.
+        let mut hash = [0; 64];
+        sha.finish(&mut hash);
+        let l = &self.l;
+        let low = l.from_mont(&l.to_mont(&from_le_bytes(&hash[..32])));
+        let high = l.to_mont(&from_le_bytes(&hash[32..]));
+        let k = l.add(&low, &high);
+
+        let base = self.curve.decode(&BASE).unwrap();
+        let neg_key = self.curve.neg(&key);
+        self.table
+            .set([base, neg_key, self.curve.add(&base, &neg_key)]);
+        self.scalars.set([from_le_bytes(&signature[32..]), k]);
+        self.acc.set(self.curve.identity());
+        self.schedule(Step::Multiply(256));
+    }
+}
+
+impl<'a> SignatureVerify<'a> for Ed25519<'a> {
+    fn set_verify_client(&'a self, client: &'a dyn ClientVerify<'a>) {
+        self.client.set(client);
+    }
+
+    fn set_public_key(&self, public_key: &[u8]) -> Result<(), ReturnCode> {
+        if self.step.get() != Step::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.public_key.set(None);
+        if public_key.len() != PUBLIC_KEY_SIZE {
+            return Err(ReturnCode::EINVAL);
+        }
+        let point = self.curve.decode(public_key).ok_or(ReturnCode::EINVAL)?;
+        let mut encoded = [0; PUBLIC_KEY_SIZE];
+        encoded.copy_from_slice(public_key);
+        self.public_key.set(Some((encoded, point)));
+        Ok(())
+    }
+
+    fn verify(
+        &'a self,
+        message: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if self.step.get() != Step::Idle {
+            return Err((ReturnCode::EBUSY, message, signature));
+        }
+        if self.public_key.get().is_none() {
+            return Err((ReturnCode::EOFF, message, signature));
+        }
+        if signature.len() != SIGNATURE_SIZE {
+            return Err((ReturnCode::ESIZE, message, signature));
+        }
+        if self.handle.is_none() {
+            return Err((ReturnCode::FAIL, message, signature));
+        }
+        self.message.replace(message);
+        self.signature.replace(signature);
+        self.schedule(Step::Hash(0));
+        Ok(())
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for Ed25519<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        match self.step.get() {
+            Step::Idle => {}
+            Step::Hash(offset) => self.hash(offset),
+            Step::Multiply(high) => {
+                let low = high - BITS_PER_STEP;
+                let acc = double_multiply(
+                    &self.curve,
+                    self.acc.get(),
+                    &self.table.get(),
+                    &self.scalars.get(),
+                    low..high,
+                );
+                self.acc.set(acc);
+                self.schedule(if low == 0 {
+                    Step::Compare
+                } else {
+                    Step::Multiply(low)
+                });
+            }
+            Step::Compare => {
+                let encoded = self.curve.encode(&self.acc.get());
+                let valid = self
+                    .signature
+                    .map_or(false, |signature| signature[..32] == encoded);
+                self.done(Ok(valid));
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+mod arithmetic;
+pub mod ecdsa_p256;
+pub mod ed25519;
@@ -1164,6 +1164,9 @@ impl<'a, S: spi::SpiMasterDevice> RF233<'a, S> {
 
 impl<S: spi::SpiMasterDevice> radio::Radio for RF233<'_, S> {}
 
+// The radio is configured with `XAH_CTRL_1_AACK_PROM_MODE`.
+impl<S: spi::SpiMasterDevice> radio::PromiscuousRadio for RF233<'_, S> {}
+
 impl<S: spi::SpiMasterDevice> radio::RadioConfig for RF233<'_, S> {
     fn initialize(
         &self,
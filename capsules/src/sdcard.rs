@@ -33,6 +33,16 @@
 //!     capsules::sdcard::SDCardDriver::new(sdcard, &mut capsules::sdcard::KERNEL_BUFFER));
 //! sdcard.set_client(sdcard_driver);
 //! ```
+//!
+//! Instead of `SDCardDriver`, the card can be used as block storage by other
+//! capsules, such as `capsules::fat`:
+//!
+//! ```rust
+//! let sdcard_storage = static_init!(
+//!     capsules::sdcard::SDCardStorage<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::sdcard::SDCardStorage::new(sdcard));
+//! sdcard.set_client(sdcard_storage);
+//! ```
 
 // Resources for SD Card API:
 //  * elm-chan.org/docs/mmc/mmc_e.html
@@ -1194,6 +1204,11 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         self.is_initialized.get()
     }
 
+    /// Take back the buffer of a read or write that ended with an error.
+    pub fn take_client_buffer(&self) -> Option<&'static mut [u8]> {
+        self.client_buffer.take()
+    }
+
     /// watches SD card detect pin for changes, sends callback on change
     pub fn detect_changes(&self) {
         self.detect_pin.get().map(|pin| {
@@ -1376,6 +1391,136 @@ impl<'a, A: hil::time::Alarm<'a>> hil::gpio::Client for SDCard<'a, A> {
     }
 }
 
+/// Block storage on an SD card, for capsules such as `fat` that are built
+/// on `hil::nonvolatile_storage`. Addresses and lengths must be multiples of
+/// the 512 byte block size and only one block can be written at a time. The
+/// card is initialized before it is first used.
+pub struct SDCardStorage<'a, A: hil::time::Alarm<'a>> {
+    sdcard: &'a SDCard<'a, A>,
+    /// Whether the pending operation is a write, its first block and its
+    /// length
+    operation: Cell<Option<(bool, u32, usize)>>,
+    /// The buffer of an operation waiting for the card to be initialized
+    buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'static dyn hil::nonvolatile_storage::NonvolatileStorageClient<'static>>,
+}
+
+impl<'a, A: hil::time::Alarm<'a>> SDCardStorage<'a, A> {
+    pub fn new(sdcard: &'a SDCard<'a, A>) -> SDCardStorage<'a, A> {
+        SDCardStorage {
+            sdcard: sdcard,
+            operation: Cell::new(None),
+            buffer: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn request(
+        &self,
+        write: bool,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> ReturnCode {
+        if self.operation.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if address % 512 != 0 || length % 512 != 0 || length == 0 || length > buffer.len() {
+            return ReturnCode::EINVAL;
+        }
+        if write && length != 512 {
+            return ReturnCode::ENOSUPPORT;
+        }
+
+        self.operation
+            .set(Some((write, (address / 512) as u32, length)));
+        let ret = if self.sdcard.is_initialized() {
+            self.start(buffer)
+        } else {
+            self.buffer.replace(buffer);
+            self.sdcard.initialize()
+        };
+        if ret != ReturnCode::SUCCESS {
+            self.operation.set(None);
+            self.buffer.take();
+        }
+        ret
+    }
+
+    fn start(&self, buffer: &'static mut [u8]) -> ReturnCode {
+        match self.operation.get() {
+            Some((true, block, _)) => self.sdcard.write_blocks(buffer, block, 1),
+            Some((false, block, length)) => {
+                self.sdcard
+                    .read_blocks(buffer, block, (length / 512) as u32)
+            }
+            None => ReturnCode::FAIL,
+        }
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> hil::nonvolatile_storage::NonvolatileStorage<'static>
+    for SDCardStorage<'a, A>
+{
+    fn set_client(
+        &self,
+        client: &'static dyn hil::nonvolatile_storage::NonvolatileStorageClient<'static>,
+    ) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.request(false, buffer, address, length)
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.request(true, buffer, address, length)
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> SDCardClient for SDCardStorage<'a, A> {
+    fn card_detection_changed(&self, _installed: bool) {}
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        self.buffer.take().map(|buffer| {
+            if self.start(buffer) != ReturnCode::SUCCESS {
+                self.operation.set(None);
+            }
+        });
+    }
+
+    fn read_done(&self, data: &'static mut [u8], len: usize) {
+        self.operation.set(None);
+        self.client.map(move |client| client.read_done(data, len));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        if let Some((_, _, length)) = self.operation.take() {
+            self.client
+                .map(move |client| client.write_done(buffer, length));
+        }
+    }
+
+    fn error(&self, _error: u32) {
+        // Return the buffer with nothing read or written
+        if let Some((write, _, _)) = self.operation.take() {
+            let buffer = self
+                .buffer
+                .take()
+                .or_else(|| self.sdcard.take_client_buffer());
+            buffer.map(|buffer| {
+                self.client.map(move |client| {
+                    if write {
+                        client.write_done(buffer, 0);
+                    } else {
+                        client.read_done(buffer, 0);
+                    }
+                });
+            });
+        }
+    }
+}
+
 /// Application driver for SD Card capsule, layers on top of SD Card capsule
 /// This is used if the SDCard is going to be attached directly to userspace
 /// syscalls. SDCardDriver can be ignored if another capsule is going to build
@@ -0,0 +1,470 @@
+//! Software implementation of AES-128 in ECB, CBC and CTR modes.
+//!
+//! `SoftwareAes128` implements the `AES128` family of traits in
+//! `kernel::hil::symmetric_encryption`, so it can stand in for an AES
+//! peripheral on chips that lack one (for example underneath
+//! `capsules::virtual_aes_ccm::MuxAES128CCM`), and in host simulations. Each
+//! call to `crypt` is computed immediately and the `crypt_done` callback is
+//! delivered from a deferred call, as it would be from a hardware interrupt.
+//!
+//! This implementation uses lookup tables indexed by secret data and is
+//! therefore not hardened against cache-timing side channels.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let aes = static_init!(
+//!     capsules::software_aes::SoftwareAes128<'static>,
+//!     capsules::software_aes::SoftwareAes128::new(dynamic_deferred_caller)
+//! );
+//! aes.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(aes)
+//!         .expect("no deferred call slot available for software aes"),
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::symmetric_encryption::{
+    self, AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::ReturnCode;
+
+const ROUNDS: usize = 10;
+const ROUND_KEYS_SIZE: usize = AES128_BLOCK_SIZE * (ROUNDS + 1);
+
+#[rustfmt::skip]
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+#[rustfmt::skip]
+const INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+const RCON: [u8; ROUNDS] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+type Block = [u8; AES128_BLOCK_SIZE];
+
+fn xtime(x: u8) -> u8 {
+    (x << 1) ^ (((x >> 7) & 1) * 0x1b)
+}
+
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    p
+}
+
+/// Expands a 128-bit key into the 11 round keys.
+fn expand_key(key: &[u8]) -> [u8; ROUND_KEYS_SIZE] {
+    let mut w = [0u8; ROUND_KEYS_SIZE];
+    w[..AES128_KEY_SIZE].copy_from_slice(&key[..AES128_KEY_SIZE]);
+    for i in 4..4 * (ROUNDS + 1) {
+        let mut t = [w[4 * i - 4], w[4 * i - 3], w[4 * i - 2], w[4 * i - 1]];
+        if i % 4 == 0 {
+            t = [
+                SBOX[t[1] as usize] ^ RCON[i / 4 - 1],
+                SBOX[t[2] as usize],
+                SBOX[t[3] as usize],
+                SBOX[t[0] as usize],
+            ];
+        }
+        for j in 0..4 {
+            w[4 * i + j] = w[4 * (i - 4) + j] ^ t[j];
+        }
+    }
+    w
+}
+
+fn add_round_key(state: &mut Block, round_keys: &[u8], round: usize) {
+    for (s, k) in state
+        .iter_mut()
+        .zip(round_keys[round * AES128_BLOCK_SIZE..].iter())
+    {
+        *s ^= k;
+    }
+}
+
+// The state is stored column-major, as in FIPS-197: byte `r + 4c` is row `r`
+// of column `c`.
+fn shift_rows(state: &mut Block) {
+    let s = *state;
+    for r in 1..4 {
+        for c in 0..4 {
+            state[r + 4 * c] = s[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn inv_shift_rows(state: &mut Block) {
+    let s = *state;
+    for r in 1..4 {
+        for c in 0..4 {
+            state[r + 4 * ((c + r) % 4)] = s[r + 4 * c];
+        }
+    }
+}
+
+fn mix_columns(state: &mut Block) {
+    for col in state.chunks_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+        for r in 0..4 {
+            col[r] = a[r] ^ all ^ xtime(a[r] ^ a[(r + 1) % 4]);
+        }
+    }
+}
+
+fn inv_mix_columns(state: &mut Block) {
+    for col in state.chunks_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        for r in 0..4 {
+            col[r] = gmul(a[r], 14)
+                ^ gmul(a[(r + 1) % 4], 11)
+                ^ gmul(a[(r + 2) % 4], 13)
+                ^ gmul(a[(r + 3) % 4], 9);
+        }
+    }
+}
+
+/// Encrypts a single block in place with the expanded key.
+fn encrypt_block(round_keys: &[u8], block: &mut Block) {
+    add_round_key(block, round_keys, 0);
+    for round in 1..=ROUNDS {
+        for b in block.iter_mut() {
+            *b = SBOX[*b as usize];
+        }
+        shift_rows(block);
+        if round != ROUNDS {
+            mix_columns(block);
+        }
+        add_round_key(block, round_keys, round);
+    }
+}
+
+/// Decrypts a single block in place with the expanded key.
+fn decrypt_block(round_keys: &[u8], block: &mut Block) {
+    add_round_key(block, round_keys, ROUNDS);
+    for round in (0..ROUNDS).rev() {
+        inv_shift_rows(block);
+        for b in block.iter_mut() {
+            *b = INV_SBOX[*b as usize];
+        }
+        add_round_key(block, round_keys, round);
+        if round != 0 {
+            inv_mix_columns(block);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Mode {
+    ECB,
+    CBC,
+    CTR,
+}
+
+pub struct SoftwareAes128<'a> {
+    client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    round_keys: Cell<[u8; ROUND_KEYS_SIZE]>,
+    iv: Cell<Block>,
+    // The CBC chaining value or the CTR counter of the current message.
+    chain: Cell<Block>,
+    mode: Cell<Mode>,
+    encrypting: Cell<bool>,
+
+    source: TakeCell<'a, [u8]>,
+    dest: TakeCell<'a, [u8]>,
+    busy: Cell<bool>,
+}
+
+impl<'a> SoftwareAes128<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareAes128<'a> {
+        SoftwareAes128 {
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            round_keys: Cell::new([0; ROUND_KEYS_SIZE]),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            chain: Cell::new([0; AES128_BLOCK_SIZE]),
+            mode: Cell::new(Mode::ECB),
+            encrypting: Cell::new(true),
+            source: TakeCell::empty(),
+            dest: TakeCell::empty(),
+            busy: Cell::new(false),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    // Transforms `data` block by block according to the current mode,
+    // carrying the chaining value over to the next call.
+    fn process(&self, data: &mut [u8]) {
+        let round_keys = self.round_keys.get();
+        let mut chain = self.chain.get();
+        for chunk in data.chunks_mut(AES128_BLOCK_SIZE) {
+            let mut block = [0; AES128_BLOCK_SIZE];
+            block.copy_from_slice(chunk);
+            match self.mode.get() {
+                Mode::ECB => {
+                    if self.encrypting.get() {
+                        encrypt_block(&round_keys, &mut block);
+                    } else {
+                        decrypt_block(&round_keys, &mut block);
+                    }
+                }
+                Mode::CBC => {
+                    if self.encrypting.get() {
+                        for (b, c) in block.iter_mut().zip(chain.iter()) {
+                            *b ^= c;
+                        }
+                        encrypt_block(&round_keys, &mut block);
+                        chain = block;
+                    } else {
+                        let ciphertext = block;
+                        decrypt_block(&round_keys, &mut block);
+                        for (b, c) in block.iter_mut().zip(chain.iter()) {
+                            *b ^= c;
+                        }
+                        chain = ciphertext;
+                    }
+                }
+                Mode::CTR => {
+                    let mut keystream = chain;
+                    encrypt_block(&round_keys, &mut keystream);
+                    for (b, k) in block.iter_mut().zip(keystream.iter()) {
+                        *b ^= k;
+                    }
+                    // Increment the counter as a 128-bit big-endian integer.
+                    for c in chain.iter_mut().rev() {
+                        *c = c.wrapping_add(1);
+                        if *c != 0 {
+                            break;
+                        }
+                    }
+                }
+            }
+            chunk.copy_from_slice(&block);
+        }
+        self.chain.set(chain);
+    }
+}
+
+impl<'a> AES128<'a> for SoftwareAes128<'a> {
+    fn enable(&self) {}
+
+    fn disable(&self) {}
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        self.round_keys.set(expand_key(key));
+        ReturnCode::SUCCESS
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        if iv.len() != AES128_BLOCK_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block.copy_from_slice(iv);
+        self.iv.set(block);
+        ReturnCode::SUCCESS
+    }
+
+    fn start_message(&self) {
+        if self.busy.get() {
+            return;
+        }
+        self.chain.set(self.iv.get());
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.busy.get() {
+            return Some((ReturnCode::EBUSY, source, dest));
+        }
+        if start_index > stop_index
+            || stop_index > dest.len()
+            || (stop_index - start_index) % AES128_BLOCK_SIZE != 0
+            || source
+                .as_ref()
+                .map_or(false, |src| src.len() != stop_index - start_index)
+        {
+            return Some((ReturnCode::EINVAL, source, dest));
+        }
+        if self.handle.is_none() {
+            return Some((ReturnCode::FAIL, source, dest));
+        }
+
+        let data = &mut dest[start_index..stop_index];
+        if let Some(ref src) = source {
+            data.copy_from_slice(src);
+        }
+        self.process(data);
+
+        self.busy.set(true);
+        self.source.put(source);
+        self.dest.replace(dest);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        None
+    }
+}
+
+impl AES128Ctr for SoftwareAes128<'_> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) {
+        self.mode.set(Mode::CTR);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl AES128CBC for SoftwareAes128<'_> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) {
+        self.mode.set(Mode::CBC);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl AES128ECB for SoftwareAes128<'_> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) {
+        self.mode.set(Mode::ECB);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareAes128<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.busy.set(false);
+        let source = self.source.take();
+        self.dest.take().map(|dest| {
+            self.client
+                .map(move |client| client.crypt_done(source, dest));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use std::boxed::Box;
+
+    // FIPS-197 Appendix C.1
+    const KEY: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ];
+    const PLAINTEXT: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+    const CIPHERTEXT: [u8; 16] = [
+        0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5,
+        0x5a,
+    ];
+
+    #[test]
+    fn fips197_block() {
+        let round_keys = expand_key(&KEY);
+        let mut block = PLAINTEXT;
+        encrypt_block(&round_keys, &mut block);
+        assert_eq!(block, CIPHERTEXT);
+        decrypt_block(&round_keys, &mut block);
+        assert_eq!(block, PLAINTEXT);
+    }
+
+    // NIST SP 800-38A F.5.1, first two blocks of CTR-AES128.Encrypt
+    #[test]
+    fn sp800_38a_ctr() {
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let counter = [
+            0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd,
+            0xfe, 0xff,
+        ];
+        let mut data = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac,
+            0x45, 0xaf, 0x8e, 0x51,
+        ];
+        let expected = [
+            0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d,
+            0xb6, 0xce, 0x98, 0x06, 0xf6, 0x6b, 0x79, 0x70, 0xfd, 0xff, 0x86, 0x17, 0x18, 0x7b,
+            0xb9, 0xff, 0xfd, 0xff,
+        ];
+
+        let clients = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = DynamicDeferredCall::new(clients);
+        let aes = SoftwareAes128::new(&ddc);
+        aes.set_key(&key);
+        aes.set_iv(&counter);
+        aes.set_mode_aes128ctr(true);
+        aes.start_message();
+        aes.process(&mut data);
+        assert_eq!(data[..], expected[..]);
+    }
+}
@@ -0,0 +1,560 @@
+//! Software implementations of SHA-256 and SHA-512.
+//!
+//! `SoftwareSha256` and `SoftwareSha512` implement `kernel::hil::digest::Digest`
+//! for chips without hash hardware, and `SoftwareSha256` also implements
+//! `HMACSha256`, so it can be used underneath `capsules::hmac::HmacDriver`.
+//! Data passed to `add_data` is hashed from deferred calls, `BYTES_PER_CALL`
+//! bytes at a time, so hashing a long message doesn't hold up the rest of the
+//! kernel. `add_data_done` and `hash_done` are delivered from deferred calls,
+//! as they would be from a hardware interrupt.
+//!
+//! The hash functions only use additions, rotations and logic operations on
+//! the data, with no table lookups or branches that depend on it, so they run
+//! in constant time for a given message length.
+//!
+//! The `Sha256` and `Sha512` engines can also be used directly to hash data
+//! synchronously.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sha = static_init!(
+//!     capsules::software_sha::SoftwareSha256<'static>,
+//!     capsules::software_sha::SoftwareSha256::new(dynamic_deferred_caller)
+//! );
+//! sha.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(sha)
+//!         .expect("no deferred call slot available for software sha"),
+//! );
+//! digest::Digest::set_client(sha, hmac_driver);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest::{self, DigestType};
+use kernel::ReturnCode;
+
+/// The number of bytes hashed in each deferred call.
+pub const BYTES_PER_CALL: usize = 512;
+
+/// The largest block size of the supported hash functions.
+const MAX_BLOCK_SIZE: usize = 128;
+
+/// The largest output size of the supported hash functions.
+const MAX_OUTPUT_SIZE: usize = 64;
+
+/// A hash function of the SHA-2 family.
+pub trait Sha2: Copy {
+    /// The digest of this hash function.
+    type Output: DigestType + 'static;
+
+    const BLOCK_SIZE: usize;
+    const OUTPUT_SIZE: usize;
+
+    /// Start hashing a new message.
+    fn new() -> Self;
+
+    /// Add `data` to the message.
+    fn update(&mut self, data: &[u8]);
+
+    /// Pad the message and write its digest to the start of `digest`.
+    fn finish(self, digest: &mut [u8]);
+}
+
+const SHA256_INITIAL: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[rustfmt::skip]
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const SHA512_INITIAL: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+#[rustfmt::skip]
+const SHA512_K: [u64; 80] = [
+    0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc,
+    0x3956c25bf348b538, 0x59f111f1b605d019, 0x923f82a4af194f9b, 0xab1c5ed5da6d8118,
+    0xd807aa98a3030242, 0x12835b0145706fbe, 0x243185be4ee4b28c, 0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f, 0x80deb1fe3b1696b1, 0x9bdc06a725c71235, 0xc19bf174cf692694,
+    0xe49b69c19ef14ad2, 0xefbe4786384f25e3, 0x0fc19dc68b8cd5b5, 0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275, 0x4a7484aa6ea6e483, 0x5cb0a9dcbd41fbd4, 0x76f988da831153b5,
+    0x983e5152ee66dfab, 0xa831c66d2db43210, 0xb00327c898fb213f, 0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2, 0xd5a79147930aa725, 0x06ca6351e003826f, 0x142929670a0e6e70,
+    0x27b70a8546d22ffc, 0x2e1b21385c26c926, 0x4d2c6dfc5ac42aed, 0x53380d139d95b3df,
+    0x650a73548baf63de, 0x766a0abb3c77b2a8, 0x81c2c92e47edaee6, 0x92722c851482353b,
+    0xa2bfe8a14cf10364, 0xa81a664bbc423001, 0xc24b8b70d0f89791, 0xc76c51a30654be30,
+    0xd192e819d6ef5218, 0xd69906245565a910, 0xf40e35855771202a, 0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8, 0x1e376c085141ab53, 0x2748774cdf8eeb99, 0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63, 0x4ed8aa4ae3418acb, 0x5b9cca4f7763e373, 0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc, 0x78a5636f43172f60, 0x84c87814a1f0ab72, 0x8cc702081a6439ec,
+    0x90befffa23631e28, 0xa4506cebde82bde9, 0xbef9a3f7b2c67915, 0xc67178f2e372532b,
+    0xca273eceea26619c, 0xd186b8c721c0c207, 0xeada7dd6cde0eb1e, 0xf57d4f7fee6ed178,
+    0x06f067aa72176fba, 0x0a637dc5a2c898a6, 0x113f9804bef90dae, 0x1b710b35131c471b,
+    0x28db77f523047d84, 0x32caab7b40c72493, 0x3c9ebe0a15c9bebc, 0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6, 0x597f299cfc657e2a, 0x5fcb6fab3ad6faec, 0x6c44198c4a475817,
+];
+
+/// SHA-256, as specified in FIPS 180-4.
+#[derive(Clone, Copy)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    /// Number of bytes in `block`.
+    used: usize,
+    /// Total number of bytes hashed.
+    length: u64,
+}
+
+impl Sha256 {
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(self.block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
+
+impl Sha2 for Sha256 {
+    type Output = [u8; 32];
+
+    const BLOCK_SIZE: usize = 64;
+    const OUTPUT_SIZE: usize = 32;
+
+    fn new() -> Sha256 {
+        Sha256 {
+            state: SHA256_INITIAL,
+            block: [0; 64],
+            used: 0,
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = cmp::min(Self::BLOCK_SIZE - self.used, data.len());
+            self.block[self.used..self.used + n].copy_from_slice(&data[..n]);
+            self.used += n;
+            self.length = self.length.wrapping_add(n as u64);
+            data = &data[n..];
+            if self.used == Self::BLOCK_SIZE {
+                self.compress();
+                self.used = 0;
+            }
+        }
+    }
+
+    fn finish(mut self, digest: &mut [u8]) {
+        let bits = self.length.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.used != Self::BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+}
+
+/// SHA-512, as specified in FIPS 180-4.
+#[derive(Clone, Copy)]
+pub struct Sha512 {
+    state: [u64; 8],
+    block: [u8; 128],
+    /// Number of bytes in `block`.
+    used: usize,
+    /// Total number of bytes hashed.
+    length: u64,
+}
+
+impl Sha512 {
+    fn compress(&mut self) {
+        let mut w = [0u64; 80];
+        for (word, bytes) in w.iter_mut().zip(self.block.chunks(8)) {
+            let mut be = [0; 8];
+            be.copy_from_slice(bytes);
+            *word = u64::from_be_bytes(be);
+        }
+        for i in 16..80 {
+            let s0 = w[i - 15].rotate_right(1) ^ w[i - 15].rotate_right(8) ^ (w[i - 15] >> 7);
+            let s1 = w[i - 2].rotate_right(19) ^ w[i - 2].rotate_right(61) ^ (w[i - 2] >> 6);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..80 {
+            let s1 = e.rotate_right(14) ^ e.rotate_right(18) ^ e.rotate_right(41);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA512_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
+
+impl Sha2 for Sha512 {
+    type Output = [u8; 64];
+
+    const BLOCK_SIZE: usize = 128;
+    const OUTPUT_SIZE: usize = 64;
+
+    fn new() -> Sha512 {
+        Sha512 {
+            state: SHA512_INITIAL,
+            block: [0; 128],
+            used: 0,
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = cmp::min(Self::BLOCK_SIZE - self.used, data.len());
+            self.block[self.used..self.used + n].copy_from_slice(&data[..n]);
+            self.used += n;
+            self.length = self.length.wrapping_add(n as u64);
+            data = &data[n..];
+            if self.used == Self::BLOCK_SIZE {
+                self.compress();
+                self.used = 0;
+            }
+        }
+    }
+
+    fn finish(mut self, digest: &mut [u8]) {
+        let bits = (self.length as u128).wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.used != Self::BLOCK_SIZE - 16 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        for (bytes, word) in digest.chunks_mut(8).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+}
+
+/// The inner or outer key block of an HMAC.
+fn hmac_pad(key: &[u8], pad: u8) -> [u8; MAX_BLOCK_SIZE] {
+    let mut block = [pad; MAX_BLOCK_SIZE];
+    for (b, k) in block.iter_mut().zip(key.iter()) {
+        *b ^= k;
+    }
+    block
+}
+
+pub type SoftwareSha256<'a> = SoftwareSha<'a, Sha256>;
+pub type SoftwareSha512<'a> = SoftwareSha<'a, Sha512>;
+
+pub struct SoftwareSha<'a, S: Sha2> {
+    client: OptionalCell<&'a dyn digest::Client<'a, S::Output>>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    state: Cell<S>,
+    // The HMAC key, if computing an HMAC rather than a plain hash.
+    key: Cell<Option<[u8; 32]>>,
+
+    data: MapCell<LeasableBuffer<'static, u8>>,
+    // The number of bytes of `data` already hashed.
+    data_index: Cell<usize>,
+    digest: TakeCell<'static, S::Output>,
+}
+
+impl<'a, S: Sha2> SoftwareSha<'a, S> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareSha<'a, S> {
+        SoftwareSha {
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            state: Cell::new(S::new()),
+            key: Cell::new(None),
+            data: MapCell::empty(),
+            data_index: Cell::new(0),
+            digest: TakeCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn busy(&self) -> bool {
+        self.data.is_some() || self.digest.is_some()
+    }
+
+    // Starts a new message, keyed with the HMAC key if there is one.
+    fn start_message(&self) {
+        let mut state = S::new();
+        if let Some(key) = self.key.get() {
+            state.update(&hmac_pad(&key, 0x36)[..S::BLOCK_SIZE]);
+        }
+        self.state.set(state);
+    }
+
+    // Writes the digest of the current message and starts the next one.
+    fn finish_message(&self, digest: &mut [u8]) {
+        let state = self.state.get();
+        match self.key.get() {
+            Some(key) => {
+                let mut inner = [0; MAX_OUTPUT_SIZE];
+                state.finish(&mut inner);
+                let mut outer = S::new();
+                outer.update(&hmac_pad(&key, 0x5c)[..S::BLOCK_SIZE]);
+                outer.update(&inner[..S::OUTPUT_SIZE]);
+                outer.finish(digest);
+            }
+            None => state.finish(digest),
+        }
+        self.start_message();
+    }
+}
+
+impl<'a, S: Sha2> digest::Digest<'a, S::Output> for SoftwareSha<'a, S> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, S::Output>) {
+        self.client.set(client);
+    }
+
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ReturnCode, &'static mut [u8])> {
+        if self.busy() {
+            return Err((ReturnCode::EBUSY, data.take()));
+        }
+        if self.handle.is_none() {
+            return Err((ReturnCode::FAIL, data.take()));
+        }
+        let length = data.len();
+        self.data.put(data);
+        self.schedule();
+        Ok(length)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut S::Output,
+    ) -> Result<(), (ReturnCode, &'static mut S::Output)> {
+        if self.digest.is_some() {
+            return Err((ReturnCode::EBUSY, digest));
+        }
+        if self.handle.is_none() {
+            return Err((ReturnCode::FAIL, digest));
+        }
+        // Data that is still being added is hashed first.
+        self.digest.replace(digest);
+        self.schedule();
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        self.key.set(None);
+        self.start_message();
+    }
+}
+
+impl digest::HMACSha256 for SoftwareSha<'_, Sha256> {
+    fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ReturnCode> {
+        if self.busy() {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.key.set(Some(*key));
+        self.start_message();
+        Ok(())
+    }
+}
+
+impl<'a, S: Sha2> DynamicDeferredCallClient for SoftwareSha<'a, S> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Some(data) = self.data.take() {
+            let index = self.data_index.get();
+            let end = cmp::min(index + BYTES_PER_CALL, data.len());
+            let mut state = self.state.get();
+            state.update(&data[index..end]);
+            self.state.set(state);
+
+            if end < data.len() {
+                self.data_index.set(end);
+                self.data.put(data);
+                self.schedule();
+            } else {
+                self.data_index.set(0);
+                if self.digest.is_some() {
+                    self.schedule();
+                }
+                self.client
+                    .map(move |client| client.add_data_done(Ok(()), data.take()));
+            }
+        } else if let Some(digest) = self.digest.take() {
+            self.finish_message(digest.as_mut());
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash<S: Sha2>(data: &[u8]) -> [u8; MAX_OUTPUT_SIZE] {
+        let mut sha = S::new();
+        sha.update(data);
+        let mut digest = [0; MAX_OUTPUT_SIZE];
+        sha.finish(&mut digest);
+        digest
+    }
+
+    fn hex(digest: &[u8]) -> [u8; MAX_OUTPUT_SIZE] {
+        let mut bytes = [0; MAX_OUTPUT_SIZE];
+        for (i, b) in digest.chunks(2).enumerate() {
+            let nibble = |c: u8| (c as char).to_digit(16).unwrap() as u8;
+            bytes[i] = nibble(b[0]) << 4 | nibble(b[1]);
+        }
+        bytes
+    }
+
+    const TWO_BLOCK_256: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+    const TWO_BLOCK_512: &[u8] = b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                                   hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+
+    // FIPS 180-4 examples from the NIST Cryptographic Standards and
+    // Guidelines.
+    #[test]
+    fn sha256_nist() {
+        assert_eq!(
+            hash::<Sha256>(b"abc"),
+            hex(b"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            hash::<Sha256>(b""),
+            hex(b"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(
+            hash::<Sha256>(TWO_BLOCK_256),
+            hex(b"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+    }
+
+    #[test]
+    fn sha512_nist() {
+        assert_eq!(
+            hash::<Sha512>(b"abc"),
+            hex(
+                b"ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                  2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            )
+        );
+        assert_eq!(
+            hash::<Sha512>(b""),
+            hex(
+                b"cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+                  47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"
+            )
+        );
+        assert_eq!(
+            hash::<Sha512>(TWO_BLOCK_512),
+            hex(
+                b"8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018\
+                  501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909"
+            )
+        );
+    }
+
+    // Splitting the message at any point gives the same digest.
+    #[test]
+    fn split_updates() {
+        let expected = hash::<Sha512>(TWO_BLOCK_512);
+        for split in 0..TWO_BLOCK_512.len() {
+            let mut sha = Sha512::new();
+            sha.update(&TWO_BLOCK_512[..split]);
+            sha.update(&TWO_BLOCK_512[split..]);
+            let mut digest = [0; MAX_OUTPUT_SIZE];
+            sha.finish(&mut digest);
+            assert_eq!(digest, expected);
+        }
+    }
+}
@@ -45,6 +45,8 @@
 //! Unable to find key: [18, 52, 86, 120, 154, 188, 222, 240]
 //! Let's start a garbage collection
 //! Finished garbage collection
+//! Storage stats: StorageStats { keys: 0, used: 15, invalidated: 0, free: ..., reclaimable: 0 }
+//! Consistency check found 0 problems
 //! ---Finished TicKV Tests---
 //! ```
 
@@ -86,8 +88,8 @@ impl<'a, S: KVSystem<'static, K = T>, T: KeyType + core::fmt::Debug> kv_system::
     fn generate_key_complete(
         &self,
         _result: Result<(), ReturnCode>,
-        _unhashed_key: &'static [u8],
-        _key_buf: &'static T,
+        _unhashed_key: &'static mut [u8],
+        _key_buf: &'static mut T,
     ) {
         unimplemented!()
     }
@@ -96,7 +98,7 @@ impl<'a, S: KVSystem<'static, K = T>, T: KeyType + core::fmt::Debug> kv_system::
         &self,
         result: Result<(), ReturnCode>,
         key: &'static mut T,
-        value: &'static [u8],
+        value: &'static mut [u8],
     ) {
         match result {
             Ok(()) => {
@@ -160,11 +162,74 @@ impl<'a, S: KVSystem<'static, K = T>, T: KeyType + core::fmt::Debug> kv_system::
         match result {
             Ok(()) => {
                 debug!("Finished garbage collection");
-                debug!("---Finished TicKV Tests---");
+                self.kv_system.storage_stats().unwrap();
             }
             Err(e) => {
                 panic!("Error running garbage collection: {:?}", e);
             }
         }
     }
+
+    fn next_key_complete(
+        &self,
+        _result: Result<(), ReturnCode>,
+        _key_buf: &'static mut T,
+        _cursor: usize,
+    ) {
+        unimplemented!()
+    }
+
+    fn storage_stats_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        stats: kv_system::StorageStats,
+    ) {
+        match result {
+            Ok(()) => {
+                debug!("Storage stats: {:?}", stats);
+                self.kv_system.check_consistency(false).unwrap();
+            }
+            Err(e) => {
+                panic!("Error measuring storage: {:?}", e);
+            }
+        }
+    }
+
+    fn check_consistency_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        found: usize,
+        _repaired: usize,
+    ) {
+        match result {
+            Ok(()) => {
+                debug!("Consistency check found {} problems", found);
+                debug!("---Finished TicKV Tests---");
+            }
+            Err(e) => {
+                panic!("Error checking consistency: {:?}", e);
+            }
+        }
+    }
+
+    fn begin_transaction_complete(&self, _result: Result<(), ReturnCode>) {
+        unimplemented!()
+    }
+
+    fn stage_key_complete(
+        &self,
+        _result: Result<(), ReturnCode>,
+        _key: &'static mut T,
+        _value: &'static mut [u8],
+    ) {
+        unimplemented!()
+    }
+
+    fn commit_transaction_complete(&self, _result: Result<(), ReturnCode>) {
+        unimplemented!()
+    }
+
+    fn abort_transaction_complete(&self, _result: Result<(), ReturnCode>) {
+        unimplemented!()
+    }
 }
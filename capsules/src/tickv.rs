@@ -29,13 +29,52 @@
 //! +-----------------------+
 //!
 //!    hil::flash
+//!
+//! Keys are hashed with SipHash-2-4 (see `capsules::sip_hash`). As hashing
+//! does not touch flash, `generate_key()` can be called while another
+//! operation is in progress; its callback is delivered from a deferred call.
+//!
+//! Transactions (`begin_transaction()`, `stage_key()`, `commit_transaction()`)
+//! replace several keys at once, see the TicKV library documentation.
+//! `initalise()` finishes any transaction interrupted by a power loss, and
+//! transactions can't be started until it has completed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let tickv = static_init!(
+//!     capsules::tickv::TicKVStore<'static, FlashUser<'static, F>>,
+//!     capsules::tickv::TicKVStore::new(
+//!         virtual_flash,
+//!         tickfs_read_buf,
+//!         flash_read_buffer,
+//!         region_offset,
+//!         flash_size,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! tickv.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(tickv)
+//!         .expect("no deferred call slot available for tickv"),
+//! );
+//! virtual_flash.set_client(tickv);
+//! tickv.initalise();
+//! ```
 
+use crate::sip_hash::SipHasher24;
 use core::cell::Cell;
+use core::hash::Hasher;
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
 use kernel::hil::flash::{self, Flash};
-use kernel::hil::kv_system::{self, KVSystem};
+use kernel::hil::kv_system::{self, KVSystem, StorageStats};
 use kernel::ReturnCode;
-use tickv::{self, AsyncTicKV, ErrorCode};
+use tickv::success_codes::SuccessCode;
+use tickv::{self, AsyncTicKV, ErrorCode, RegionCheck, RegionUsage};
 
 #[derive(Clone, Copy, PartialEq)]
 enum Operation {
@@ -45,6 +84,13 @@ enum Operation {
     AppendKey,
     InvalidateKey,
     GarbageCollect,
+    NextKey,
+    StorageStats,
+    CheckConsistency,
+    BeginTransaction,
+    StageKey,
+    CommitTransaction,
+    AbortTransaction,
 }
 
 pub struct TickFSFlastCtrl<'a, F: Flash + 'static> {
@@ -97,7 +143,7 @@ impl<'a, F: Flash> tickv::flash_controller::FlashController<512> for TickFSFlast
 
         if self
             .flash
-            .write_page((0x20040000 + address) / 512, data_buf)
+            .write_page(self.region_offset + address / 512, data_buf)
             .is_err()
         {
             return Err(tickv::error_codes::ErrorCode::WriteFail);
@@ -115,15 +161,46 @@ impl<'a, F: Flash> tickv::flash_controller::FlashController<512> for TickFSFlast
 
 pub type TicKVKeyType = [u8; 8];
 
+/// Convert a TicKV error into the `ReturnCode` documented by
+/// `hil::kv_system`.
+fn tickv_error_to_returncode(error: ErrorCode) -> ReturnCode {
+    match error {
+        ErrorCode::KeyNotFound | ErrorCode::KeyAlreadyExists => ReturnCode::ENOSUPPORT,
+        ErrorCode::RegionFull | ErrorCode::FlashFull => ReturnCode::ENOMEM,
+        ErrorCode::BufferTooSmall(_) => ReturnCode::ESIZE,
+        ErrorCode::NoTransaction => ReturnCode::EINVAL,
+        ErrorCode::TransactionInProgress => ReturnCode::EALREADY,
+        _ => ReturnCode::FAIL,
+    }
+}
+
 pub struct TicKVStore<'a, F: Flash + 'static> {
     tickv: AsyncTicKV<'a, TickFSFlastCtrl<'a, F>, 512>,
     operation: Cell<Operation>,
     next_operation: Cell<Operation>,
+    /// The operation continues once the pending write completes, rather
+    /// than being complete.
+    write_continues: Cell<bool>,
 
-    value_buffer: Cell<Option<&'static [u8]>>,
+    value_buffer: TakeCell<'static, [u8]>,
+    value_length: Cell<usize>,
     key_buffer: TakeCell<'static, [u8; 8]>,
     ret_buffer: TakeCell<'static, [u8]>,
 
+    /// The cursor for a `next_key()` queued during init.
+    cursor: Cell<usize>,
+    /// The region being measured or checked.
+    region: Cell<usize>,
+    stats: Cell<StorageStats>,
+    repair: Cell<bool>,
+    found: Cell<usize>,
+    repaired: Cell<usize>,
+
+    unhashed_key_buffer: TakeCell<'static, [u8]>,
+    hashed_key_buffer: TakeCell<'static, [u8; 8]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    deferred_handle: OptionalCell<DeferredCallHandle>,
+
     client: OptionalCell<&'a dyn kv_system::Client<TicKVKeyType>>,
 }
 
@@ -134,6 +211,7 @@ impl<'a, F: Flash> TicKVStore<'a, F> {
         flash_read_buffer: &'static mut F::Page,
         region_offset: usize,
         flash_size: usize,
+        deferred_caller: &'a DynamicDeferredCall,
     ) -> TicKVStore<'a, F> {
         let tickv = AsyncTicKV::<TickFSFlastCtrl<F>, 512>::new(
             TickFSFlastCtrl::new(flash, flash_read_buffer, region_offset),
@@ -145,26 +223,121 @@ impl<'a, F: Flash> TicKVStore<'a, F> {
             tickv,
             operation: Cell::new(Operation::None),
             next_operation: Cell::new(Operation::None),
-            value_buffer: Cell::new(None),
+            write_continues: Cell::new(false),
+            value_buffer: TakeCell::empty(),
+            value_length: Cell::new(0),
             key_buffer: TakeCell::empty(),
             ret_buffer: TakeCell::empty(),
+            cursor: Cell::new(0),
+            region: Cell::new(0),
+            stats: Cell::new(StorageStats::default()),
+            repair: Cell::new(false),
+            found: Cell::new(0),
+            repaired: Cell::new(0),
+            unhashed_key_buffer: TakeCell::empty(),
+            hashed_key_buffer: TakeCell::empty(),
+            deferred_caller,
+            deferred_handle: OptionalCell::empty(),
             client: OptionalCell::empty(),
         }
     }
 
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.deferred_handle.replace(handle);
+    }
+
+    /// Set up the flash for TicKV if needed, and finish any transaction
+    /// interrupted by a power loss.
     pub fn initalise(&self) {
-        let _ret = self.tickv.initalise(0x7bc9f7ff4f76f244);
         self.operation.set(Operation::Init);
+        let ret = self.tickv.initalise(0x7bc9f7ff4f76f244);
+        self.step(ret);
+    }
+
+    /// Handle the result of a step of an operation that can take several
+    /// reads and writes: initialising, or any of the transaction operations.
+    fn step(&self, ret: Result<SuccessCode, ErrorCode>) {
+        match ret {
+            Err(ErrorCode::ReadNotReady(_)) | Err(ErrorCode::EraseNotReady(_)) => {}
+            Err(ErrorCode::WriteNotReady(_)) => self.write_continues.set(true),
+            // The last write of the operation is pending
+            Ok(SuccessCode::Queued) => self.write_continues.set(false),
+            Ok(_) => self.complete_step(Ok(())),
+            Err(e) => self.complete_step(Err(tickv_error_to_returncode(e))),
+        }
+    }
+
+    fn complete_step(&self, result: Result<(), ReturnCode>) {
+        let operation = self.operation.get();
+        if operation == Operation::Init {
+            self.complete_init();
+            return;
+        }
+
+        self.operation.set(Operation::None);
+        match operation {
+            Operation::BeginTransaction => {
+                self.client.map(|cb| cb.begin_transaction_complete(result));
+            }
+            Operation::StageKey => {
+                self.client.map(|cb| {
+                    cb.stage_key_complete(
+                        result,
+                        self.key_buffer.take().unwrap(),
+                        self.tickv.get_stored_value_buffer().unwrap(),
+                    );
+                });
+            }
+            Operation::CommitTransaction => {
+                self.client.map(|cb| cb.commit_transaction_complete(result));
+            }
+            Operation::AbortTransaction => {
+                self.client.map(|cb| cb.abort_transaction_complete(result));
+            }
+            _ => {}
+        }
+    }
+
+    /// Start `operation`, one of the transaction operations, with the
+    /// result of calling into TicKV.
+    fn start_step(
+        &self,
+        operation: Operation,
+        start: impl FnOnce() -> Result<SuccessCode, ErrorCode>,
+    ) -> Result<(), ReturnCode> {
+        if self.operation.get() != Operation::None {
+            return Err(ReturnCode::EBUSY);
+        }
+
+        self.operation.set(operation);
+        match start() {
+            Err(e @ ErrorCode::ReadNotReady(_))
+            | Err(e @ ErrorCode::WriteNotReady(_))
+            | Err(e @ ErrorCode::EraseNotReady(_)) => self.step(Err(e)),
+            Ok(ret) => self.step(Ok(ret)),
+            Err(e) => {
+                self.operation.set(Operation::None);
+                return Err(tickv_error_to_returncode(e));
+            }
+        }
+        Ok(())
     }
 
     fn complete_init(&self) {
         self.operation.set(Operation::None);
         match self.next_operation.get() {
-            Operation::None | Operation::Init => {}
+            // Transactions can't be started until initialisation completes
+            Operation::None
+            | Operation::Init
+            | Operation::BeginTransaction
+            | Operation::StageKey
+            | Operation::CommitTransaction
+            | Operation::AbortTransaction => {}
             Operation::AppendKey => {
                 match self.append_key(
                     self.key_buffer.take().unwrap(),
                     self.value_buffer.take().unwrap(),
+                    self.value_length.get(),
                 ) {
                     Err((key, value, error)) => {
                         self.client.map(move |cb| {
@@ -205,9 +378,146 @@ impl<'a, F: Flash> TicKVStore<'a, F> {
                 }
                 _ => {}
             },
+            Operation::NextKey => {
+                match self.next_key(self.cursor.get(), self.key_buffer.take().unwrap()) {
+                    Err((key, error)) => {
+                        self.client.map(move |cb| {
+                            cb.next_key_complete(Err(error), key, 0);
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Operation::StorageStats => match self.storage_stats() {
+                Err(error) => {
+                    self.client.map(move |cb| {
+                        cb.storage_stats_complete(Err(error), StorageStats::default());
+                    });
+                }
+                _ => {}
+            },
+            Operation::CheckConsistency => match self.check_consistency(self.repair.get()) {
+                Err(error) => {
+                    self.client.map(move |cb| {
+                        cb.check_consistency_complete(Err(error), 0, 0);
+                    });
+                }
+                _ => {}
+            },
         }
         self.next_operation.set(Operation::None);
     }
+
+    fn complete_next_key(&self, ret: Result<(u64, usize), ErrorCode>) {
+        self.operation.set(Operation::None);
+        let key = self.key_buffer.take().unwrap();
+        let (result, cursor) = match ret {
+            Ok((hash, cursor)) => {
+                *key = hash.to_le_bytes();
+                (Ok(()), cursor)
+            }
+            Err(e) => (Err(tickv_error_to_returncode(e)), 0),
+        };
+        self.client.map(move |cb| {
+            cb.next_key_complete(result, key, cursor);
+        });
+    }
+
+    /// Measure the remaining regions, starting from `self.region`.
+    fn measure_regions(&self) {
+        while self.region.get() < self.tickv.tickv.num_regions() {
+            let ret = self.tickv.region_usage(self.region.get());
+            if !self.region_measured(ret) {
+                return;
+            }
+        }
+
+        self.operation.set(Operation::None);
+        self.client.map(|cb| {
+            cb.storage_stats_complete(Ok(()), self.stats.get());
+        });
+    }
+
+    /// Add the usage of the current region to the stats. Returns `true` if
+    /// the next region should be measured.
+    fn region_measured(&self, ret: Result<RegionUsage, ErrorCode>) -> bool {
+        match ret {
+            Ok(usage) => {
+                let mut stats = self.stats.get();
+                stats.keys += usage.keys;
+                stats.used += usage.used;
+                stats.invalidated += usage.invalidated;
+                stats.free += usage.free;
+                stats.reclaimable += usage.reclaimable;
+                self.stats.set(stats);
+            }
+            Err(ErrorCode::ReadNotReady(_)) => return false,
+            // A region that can't be walked can't be used either, it will
+            // be reported by `check_consistency()`.
+            Err(ErrorCode::CorruptData) | Err(ErrorCode::UnsupportedVersion) => {}
+            Err(e) => {
+                self.operation.set(Operation::None);
+                self.client.map(|cb| {
+                    cb.storage_stats_complete(
+                        Err(tickv_error_to_returncode(e)),
+                        StorageStats::default(),
+                    );
+                });
+                return false;
+            }
+        }
+        self.region.set(self.region.get() + 1);
+        true
+    }
+
+    /// Check the remaining regions, starting from `self.region`.
+    fn check_regions(&self) {
+        while self.region.get() < self.tickv.tickv.num_regions() {
+            let ret = self
+                .tickv
+                .check_region(self.region.get(), self.repair.get());
+            if !self.region_checked(ret) {
+                return;
+            }
+        }
+
+        self.operation.set(Operation::None);
+        self.client.map(|cb| {
+            cb.check_consistency_complete(Ok(()), self.found.get(), self.repaired.get());
+        });
+    }
+
+    /// Count the problems found in the current region. Returns `true` if
+    /// the next region should be checked.
+    fn region_checked(&self, ret: Result<RegionCheck, ErrorCode>) -> bool {
+        match ret {
+            Ok(check) if check.repaired => {
+                // Check the region again once the repair has been written,
+                // there may be more to fix.
+                self.found.set(self.found.get() + 1);
+                self.repaired.set(self.repaired.get() + 1);
+                false
+            }
+            Ok(check) => {
+                self.found
+                    .set(self.found.get() + check.corrupt_objects + check.damaged as usize);
+                self.region.set(self.region.get() + 1);
+                true
+            }
+            Err(ErrorCode::ReadNotReady(_)) | Err(ErrorCode::EraseNotReady(_)) => false,
+            Err(e) => {
+                self.complete_check(Err(tickv_error_to_returncode(e)));
+                false
+            }
+        }
+    }
+
+    fn complete_check(&self, result: Result<(), ReturnCode>) {
+        self.operation.set(Operation::None);
+        self.client.map(|cb| {
+            cb.check_consistency_complete(result, self.found.get(), self.repaired.get());
+        });
+    }
 }
 
 impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
@@ -225,13 +535,11 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
         });
 
         match self.operation.get() {
-            Operation::Init => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
-                    self.operation.set(Operation::None)
-                }
-                _ => {}
-            },
+            Operation::Init
+            | Operation::BeginTransaction
+            | Operation::StageKey
+            | Operation::CommitTransaction
+            | Operation::AbortTransaction => self.step(ret),
             Operation::GetKey => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
@@ -245,11 +553,11 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                     });
                 }
                 Err(tickv::error_codes::ErrorCode::EraseNotReady(_)) | Ok(_) => {}
-                _ => {
+                Err(e) => {
                     self.operation.set(Operation::None);
                     self.client.map(|cb| {
                         cb.get_value_complete(
-                            Err(ReturnCode::FAIL),
+                            Err(tickv_error_to_returncode(e)),
                             self.key_buffer.take().unwrap(),
                             self.ret_buffer.take().unwrap(),
                         );
@@ -261,14 +569,39 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
+                Err(ErrorCode::ReadNotReady(_))
+                | Err(ErrorCode::WriteNotReady(_))
+                | Err(ErrorCode::EraseNotReady(_))
+                | Ok(_) => {}
+                Err(e) => {
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.append_key_complete(
+                            Err(tickv_error_to_returncode(e)),
+                            self.key_buffer.take().unwrap(),
+                            self.tickv.get_stored_value_buffer().unwrap(),
+                        );
+                    });
+                }
             },
             Operation::InvalidateKey => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
+                Err(ErrorCode::ReadNotReady(_))
+                | Err(ErrorCode::WriteNotReady(_))
+                | Err(ErrorCode::EraseNotReady(_))
+                | Ok(_) => {}
+                Err(e) => {
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.invalidate_key_complete(
+                            Err(tickv_error_to_returncode(e)),
+                            self.key_buffer.take().unwrap(),
+                        );
+                    });
+                }
             },
             Operation::GarbageCollect => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
@@ -280,11 +613,28 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                 }
                 _ => {}
             },
+            Operation::NextKey => match ret {
+                Ok(_) => self.complete_next_key(Ok(self.tickv.get_stored_key().unwrap())),
+                Err(ErrorCode::ReadNotReady(_)) => {}
+                Err(e) => self.complete_next_key(Err(e)),
+            },
+            Operation::StorageStats => {
+                let ret = ret.map(|_| self.tickv.get_stored_region_usage().unwrap());
+                if self.region_measured(ret) {
+                    self.measure_regions();
+                }
+            }
+            Operation::CheckConsistency => {
+                let ret = ret.map(|_| self.tickv.get_stored_region_check().unwrap());
+                if self.region_checked(ret) {
+                    self.check_regions();
+                }
+            }
             _ => unreachable!(),
         }
     }
 
-    fn write_complete(&self, pagebuffer: &'static mut F::Page, _error: flash::Error) {
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
         self.tickv
             .tickv
             .controller
@@ -292,8 +642,19 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
             .replace(pagebuffer);
 
         match self.operation.get() {
-            Operation::Init => {
-                self.complete_init();
+            Operation::Init
+            | Operation::BeginTransaction
+            | Operation::StageKey
+            | Operation::CommitTransaction
+            | Operation::AbortTransaction => {
+                if error != flash::Error::CommandComplete {
+                    self.complete_step(Err(ReturnCode::FAIL));
+                } else if self.write_continues.get() {
+                    let (ret, _) = self.tickv.continue_operation();
+                    self.step(ret);
+                } else {
+                    self.complete_step(Ok(()));
+                }
             }
             Operation::AppendKey => {
                 self.operation.set(Operation::None);
@@ -311,11 +672,18 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                     cb.invalidate_key_complete(Ok(()), self.key_buffer.take().unwrap());
                 });
             }
+            Operation::CheckConsistency => {
+                if error == flash::Error::CommandComplete {
+                    self.check_regions();
+                } else {
+                    self.complete_check(Err(ReturnCode::FAIL));
+                }
+            }
             _ => unreachable!(),
         }
     }
 
-    fn erase_complete(&self, _error: flash::Error) {
+    fn erase_complete(&self, error: flash::Error) {
         let (ret, buf_buffer) = self.tickv.continue_operation();
 
         buf_buffer.map(|buf| {
@@ -323,13 +691,11 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
         });
 
         match self.operation.get() {
-            Operation::Init => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
-                    self.complete_init();
-                }
-                _ => {}
-            },
+            Operation::Init
+            | Operation::BeginTransaction
+            | Operation::StageKey
+            | Operation::CommitTransaction
+            | Operation::AbortTransaction => self.step(ret),
             Operation::GarbageCollect => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
@@ -340,11 +706,36 @@ impl<'a, F: Flash> flash::Client<F> for TicKVStore<'a, F> {
                 }
                 _ => {}
             },
+            Operation::CheckConsistency => match ret {
+                Ok(_) if error == flash::Error::CommandComplete => {
+                    // The damaged region is now empty, check it again.
+                    self.tickv
+                        .get_stored_region_check()
+                        .map(|check| self.region_checked(Ok(check)));
+                    self.check_regions();
+                }
+                Ok(_) => self.complete_check(Err(ReturnCode::FAIL)),
+                Err(e) => {
+                    self.region_checked(Err(e));
+                }
+            },
             _ => unreachable!(),
         }
     }
 }
 
+impl<'a, F: Flash> DynamicDeferredCallClient for TicKVStore<'a, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.unhashed_key_buffer.take().map(|unhashed_key| {
+            self.hashed_key_buffer.take().map(|key_buf| {
+                self.client.map(move |cb| {
+                    cb.generate_key_complete(Ok(()), unhashed_key, key_buf);
+                });
+            });
+        });
+    }
+}
+
 impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
     type K = TicKVKeyType;
 
@@ -354,32 +745,58 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
 
     fn generate_key(
         &self,
-        _unhashed_key: &'static mut [u8],
-        _key_buf: &'static mut Self::K,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut Self::K,
     ) -> Result<(), (&'static mut [u8], &'static mut Self::K, ReturnCode)> {
-        unimplemented!()
+        if self.deferred_handle.is_none() {
+            return Err((unhashed_key, key_buf, ReturnCode::ENODEVICE));
+        }
+        if self.unhashed_key_buffer.is_some() {
+            return Err((unhashed_key, key_buf, ReturnCode::EBUSY));
+        }
+
+        let mut hasher = SipHasher24::new();
+        hasher.write(unhashed_key);
+        *key_buf = hasher.finish().to_le_bytes();
+
+        self.unhashed_key_buffer.replace(unhashed_key);
+        self.hashed_key_buffer.replace(key_buf);
+        self.deferred_handle
+            .map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
     }
 
     fn append_key(
         &self,
         key: &'static mut Self::K,
-        value: &'static [u8],
-    ) -> Result<(), (&'static mut Self::K, &'static [u8], ReturnCode)> {
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Self::K, &'static mut [u8], ReturnCode)> {
+        if length > value.len() {
+            return Err((key, value, ReturnCode::EINVAL));
+        }
+
         match self.operation.get() {
             Operation::None => {
                 self.operation.set(Operation::AppendKey);
 
-                match self.tickv.append_key(u64::from_le_bytes(*key), value) {
+                match self
+                    .tickv
+                    .append_key(u64::from_le_bytes(*key), value, length)
+                {
                     Ok(_ret) => {
                         self.key_buffer.replace(key);
                         Ok(())
                     }
-                    Err(e) => match e {
+                    Err((value, e)) => match e {
                         ErrorCode::ReadNotReady(_) | ErrorCode::WriteNotReady(_) => {
                             self.key_buffer.replace(key);
                             Ok(())
                         }
-                        _ => Err((key, value, ReturnCode::FAIL)),
+                        _ => {
+                            self.operation.set(Operation::None);
+                            Err((key, value.unwrap(), tickv_error_to_returncode(e)))
+                        }
                     },
                 }
             }
@@ -388,7 +805,8 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                 // We can save this request and start it after init
                 self.next_operation.set(Operation::AppendKey);
                 self.key_buffer.replace(key);
-                self.value_buffer.replace(Some(value));
+                self.value_buffer.replace(value);
+                self.value_length.set(length);
                 Ok(())
             }
             _ => {
@@ -417,7 +835,10 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                             self.key_buffer.replace(key);
                             Ok(())
                         }
-                        _ => Err((key, buf.unwrap(), ReturnCode::FAIL)),
+                        _ => {
+                            self.operation.set(Operation::None);
+                            Err((key, buf.unwrap(), tickv_error_to_returncode(e)))
+                        }
                     },
                 }
             }
@@ -454,7 +875,10 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                             self.key_buffer.replace(key);
                             Ok(())
                         }
-                        _ => Err((key, ReturnCode::FAIL)),
+                        _ => {
+                            self.operation.set(Operation::None);
+                            Err((key, tickv_error_to_returncode(e)))
+                        }
                     },
                 }
             }
@@ -481,7 +905,10 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
                     Ok(freed) => Ok(freed),
                     Err(e) => match e {
                         ErrorCode::ReadNotReady(_) | ErrorCode::WriteNotReady(_) => Ok(0),
-                        _ => Err(ReturnCode::FAIL),
+                        _ => {
+                            self.operation.set(Operation::None);
+                            Err(tickv_error_to_returncode(e))
+                        }
                     },
                 }
             }
@@ -497,4 +924,143 @@ impl<'a, F: Flash> KVSystem<'a> for TicKVStore<'a, F> {
             }
         }
     }
+
+    fn next_key(
+        &self,
+        cursor: usize,
+        key_buf: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, ReturnCode)> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::NextKey);
+
+                match self.tickv.next_key(cursor) {
+                    Ok(_ret) => {
+                        self.key_buffer.replace(key_buf);
+                        Ok(())
+                    }
+                    Err(ErrorCode::ReadNotReady(_)) => {
+                        self.key_buffer.replace(key_buf);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.operation.set(Operation::None);
+                        Err((key_buf, tickv_error_to_returncode(e)))
+                    }
+                }
+            }
+            Operation::Init => {
+                // The init process is still occuring.
+                // We can save this request and start it after init
+                self.next_operation.set(Operation::NextKey);
+                self.cursor.set(cursor);
+                self.key_buffer.replace(key_buf);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err((key_buf, ReturnCode::EBUSY))
+            }
+        }
+    }
+
+    fn storage_stats(&self) -> Result<(), ReturnCode> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::StorageStats);
+                self.region.set(0);
+                self.stats.set(StorageStats::default());
+                self.measure_regions();
+                Ok(())
+            }
+            Operation::Init => {
+                // The init process is still occuring.
+                // We can save this request and start it after init
+                self.next_operation.set(Operation::StorageStats);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err(ReturnCode::EBUSY)
+            }
+        }
+    }
+
+    fn check_consistency(&self, repair: bool) -> Result<(), ReturnCode> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::CheckConsistency);
+                self.region.set(0);
+                self.repair.set(repair);
+                self.found.set(0);
+                self.repaired.set(0);
+                self.check_regions();
+                Ok(())
+            }
+            Operation::Init => {
+                // The init process is still occuring.
+                // We can save this request and start it after init
+                self.next_operation.set(Operation::CheckConsistency);
+                self.repair.set(repair);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err(ReturnCode::EBUSY)
+            }
+        }
+    }
+
+    fn begin_transaction(&self) -> Result<(), ReturnCode> {
+        self.start_step(Operation::BeginTransaction, || {
+            self.tickv.begin_transaction()
+        })
+    }
+
+    fn stage_key(
+        &self,
+        key: &'static mut Self::K,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Self::K, &'static mut [u8], ReturnCode)> {
+        if length > value.len() {
+            return Err((key, value, ReturnCode::EINVAL));
+        }
+        if self.operation.get() != Operation::None {
+            return Err((key, value, ReturnCode::EBUSY));
+        }
+
+        self.operation.set(Operation::StageKey);
+        match self
+            .tickv
+            .stage_key(u64::from_le_bytes(*key), value, length)
+        {
+            Ok(ret) => {
+                self.key_buffer.replace(key);
+                self.step(Ok(ret));
+                Ok(())
+            }
+            Err((None, e)) => {
+                self.key_buffer.replace(key);
+                self.step(Err(e));
+                Ok(())
+            }
+            Err((Some(value), e)) => {
+                self.operation.set(Operation::None);
+                Err((key, value, tickv_error_to_returncode(e)))
+            }
+        }
+    }
+
+    fn commit_transaction(&self) -> Result<(), ReturnCode> {
+        self.start_step(Operation::CommitTransaction, || {
+            self.tickv.commit_transaction()
+        })
+    }
+
+    fn abort_transaction(&self) -> Result<(), ReturnCode> {
+        self.start_step(Operation::AbortTransaction, || {
+            self.tickv.abort_transaction()
+        })
+    }
 }
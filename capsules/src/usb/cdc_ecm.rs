@@ -0,0 +1,941 @@
+//! Ethernet Adapter Class Device for USB
+//!
+//! This capsule makes the device appear to a USB host as an Ethernet adapter,
+//! using the Ethernet Control Model (ECM) of the Communications Device Class.
+//! It is an `IP6Link` for the IPv6 stack, so the host reaches the device over
+//! the USB cable with standard IP tooling, e.g. `ping fe80::...%usb0`.
+//!
+//! The host and the device are the only nodes on the link. The host's adapter
+//! has the MAC address the device reports in its descriptors, so every unicast
+//! packet goes to that address. The device answers Neighbor Solicitations for
+//! the addresses given by `InterfaceAddresses` (RFC 4861), so the host can find
+//! the device's MAC address. Frames that do not carry IPv6 are dropped.
+//!
+//! The host starts the link by selecting the second alternate setting of the
+//! data interface, after which the device reports that it is connected. Until
+//! then `transmit` fails with `EOFF`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ecm = static_init!(
+//!     capsules::usb::cdc_ecm::CdcEcm<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::cdc_ecm::CdcEcm::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915, // Nordic Semiconductor
+//!         0x503a, // lowRISC generic FS USB
+//!         strings, // The fourth string is the host's MAC address
+//!         mac_address,
+//!         host_mac_address,
+//!         &mut capsules::usb::cdc_ecm::RX_BUFFER,
+//!     )
+//! );
+//! nrf52840::usbd::USBD.set_client(ecm);
+//! ecm.set_receive_client(ip_receive);
+//! ecm.set_transmit_client(ip_send);
+//! ecm.set_interface_addresses(slaac);
+//! ecm.enable();
+//! ecm.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::CdcEthernetNetworkingDescriptor;
+use super::descriptors::CdcInterfaceDescriptor;
+use super::descriptors::CdcInterfaceDescriptorSubType;
+use super::descriptors::Descriptor;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+use crate::net::ipv6::ip_utils::{ip6_nh, verify_icmp_checksum, IPAddr, InterfaceAddresses};
+use crate::net::ipv6::ipv6_link::{IP6Link, IP6LinkRxClient, IP6LinkTxClient};
+use crate::net::ipv6::IP6Header;
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell, VolatileCell};
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Interrupt endpoint for notifying the host of the connection state.
+const ENDPOINT_NOTIFY_NUM: usize = 1;
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 2;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 3;
+
+const NOTIFY_BUFFER: usize = 0;
+const IN_BUFFER: usize = 1;
+const OUT_BUFFER: usize = 2;
+
+const N_ENDPOINTS: usize = 3;
+
+const COMMUNICATIONS_INTERFACE: u16 = 0;
+const DATA_INTERFACE: u16 = 1;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Index of the string holding the MAC address of the host's adapter.
+const MAC_ADDRESS_STRING: u8 = 4;
+
+/// Largest IPv6 packet carried over the link.
+pub const MTU: usize = 1500;
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Largest Ethernet frame, without the CRC which USB does not carry.
+pub const MAX_FRAME_LEN: usize = ETHERNET_HEADER_LEN + MTU;
+
+/// Buffer for received frames, assigned in board `main.rs` files.
+pub static mut RX_BUFFER: [u8; MAX_FRAME_LEN] = [0; MAX_FRAME_LEN];
+
+const ETHERTYPE_IPV6: [u8; 2] = [0x86, 0xdd];
+const IP6_HEADER_LEN: usize = 40;
+
+// Class-specific requests of ECM.
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+
+// Standard interface requests.
+const GET_INTERFACE: u8 = 0x0a;
+const SET_INTERFACE: u8 = 0x0b;
+
+// Notifications.
+const NETWORK_CONNECTION: u8 = 0x00;
+const CONNECTION_SPEED_CHANGE: u8 = 0x2a;
+/// Bit rate reported to the host, the one of a full-speed device.
+const BIT_RATE: u32 = 12_000_000;
+
+// Neighbor Discovery (RFC 4861).
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const SOLICITATION_LEN: usize = 24;
+const ADVERTISEMENT_LEN: usize = 32;
+const ADVERTISEMENT_FRAME_LEN: usize = ETHERNET_HEADER_LEN + IP6_HEADER_LEN + ADVERTISEMENT_LEN;
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+const ALL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+/// States of the Control Endpoint related to the adapter.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CtrlState {
+    /// No ongoing ctrl transaction.
+    Idle,
+    /// Host has asked for the alternate setting of an interface.
+    GetInterface(u8),
+}
+
+/// Notifications to send to the host, in order.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Notification {
+    None,
+    Connection,
+    Speed,
+}
+
+/// The frames the device sends.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Frame {
+    /// The packet given to `transmit`.
+    Packet,
+    /// A Neighbor Advertisement for the host.
+    Advertisement,
+}
+
+/// States of the Bulk IN endpoint.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Sending {
+    Idle,
+    /// Sending bytes `offset..` of `frame`. `last` is set once the packet that
+    /// ends the frame, which is shorter than the maximum, has been handed over.
+    Frame {
+        frame: Frame,
+        offset: usize,
+        last: bool,
+    },
+}
+
+/// Formats `mac_address` as the 12 hex digits of the MAC address string the
+/// host reads from the descriptors.
+pub fn format_mac_address<'b>(mac_address: &[u8; 6], buf: &'b mut [u8; 12]) -> &'b str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for (i, byte) in mac_address.iter().enumerate() {
+        buf[2 * i] = HEX[(byte >> 4) as usize];
+        buf[2 * i + 1] = HEX[(byte & 0x0f) as usize];
+    }
+    // Only ASCII digits were written.
+    core::str::from_utf8(buf).unwrap_or("")
+}
+
+/// The EUI-64 of a MAC address (RFC 4291, appendix A), from which `Slaac`
+/// derives the interface identifier of the device's addresses.
+pub fn eui64(mac_address: &[u8; 6]) -> [u8; 8] {
+    [
+        mac_address[0],
+        mac_address[1],
+        mac_address[2],
+        0xff,
+        0xfe,
+        mac_address[3],
+        mac_address[4],
+        mac_address[5],
+    ]
+}
+
+/// The Ethernet header for sending the IPv6 packet `packet` from `src` to the
+/// host at `host`, or to the multicast MAC address of its destination
+/// (RFC 2464, section 7).
+fn ethernet_header(packet: &[u8], src: &[u8; 6], host: &[u8; 6]) -> [u8; ETHERNET_HEADER_LEN] {
+    let mut header = [0; ETHERNET_HEADER_LEN];
+    if packet.len() >= IP6_HEADER_LEN && packet[24] == 0xff {
+        header[0..2].copy_from_slice(&[0x33, 0x33]);
+        header[2..6].copy_from_slice(&packet[36..40]);
+    } else {
+        header[0..6].copy_from_slice(host);
+    }
+    header[6..12].copy_from_slice(src);
+    header[12..14].copy_from_slice(&ETHERTYPE_IPV6);
+    header
+}
+
+/// Writes to `frame` the Neighbor Advertisement answering the solicitation in
+/// `solicitation` for `target`, an address of the device at `mac_address`.
+fn neighbor_advertisement(
+    frame: &mut [u8; ADVERTISEMENT_FRAME_LEN],
+    solicitation: &IP6Header,
+    target: IPAddr,
+    mac_address: &[u8; 6],
+    host_mac_address: &[u8; 6],
+) {
+    // A solicitation from the unspecified address is duplicate address
+    // detection, which is answered to all nodes (RFC 4861, section 7.2.4).
+    let solicited = !solicitation.src_addr.is_unspecified();
+    let mut header = IP6Header::default();
+    header.src_addr = target;
+    header.dst_addr = if solicited {
+        solicitation.src_addr
+    } else {
+        ALL_NODES
+    };
+    header.set_next_header(ip6_nh::ICMP);
+    header.set_payload_len(ADVERTISEMENT_LEN as u16);
+    header.set_hop_limit(255);
+
+    let (ethernet, rest) = frame.split_at_mut(ETHERNET_HEADER_LEN);
+    let (ip6, message) = rest.split_at_mut(IP6_HEADER_LEN);
+    let mut dst = [0; IP6_HEADER_LEN];
+    dst[24..40].copy_from_slice(&header.dst_addr.0);
+    ethernet.copy_from_slice(&ethernet_header(&dst, mac_address, host_mac_address));
+    let _ = header.encode(ip6);
+
+    message[0] = NEIGHBOR_ADVERTISEMENT;
+    message[1] = 0;
+    message[2..4].copy_from_slice(&[0, 0]);
+    message[4] = FLAG_OVERRIDE | if solicited { FLAG_SOLICITED } else { 0 };
+    message[5..8].copy_from_slice(&[0, 0, 0]);
+    message[8..24].copy_from_slice(&target.0);
+    message[24] = OPTION_TARGET_LINK_LAYER_ADDRESS;
+    message[25] = 1; // In units of 8 bytes
+    message[26..32].copy_from_slice(mac_address);
+    // With the checksum field zeroed, this is the checksum to put there.
+    let checksum = verify_icmp_checksum(&header, message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Copies the bytes from `offset` on of the frame made of `header` and
+/// `payload` to `packet`, returning how many were copied.
+fn copy_frame(header: &[u8], payload: &[u8], offset: usize, packet: &[VolatileCell<u8>]) -> usize {
+    let bytes = header.iter().chain(payload.iter()).skip(offset);
+    let mut len = 0;
+    for (cell, byte) in packet.iter().zip(bytes) {
+        cell.set(*byte);
+        len += 1;
+    }
+    len
+}
+
+/// Implementation of the Ethernet Control Model of the Communications Device
+/// Class over USB.
+pub struct CdcEcm<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    mac_address: [u8; 6],
+    host_mac_address: [u8; 6],
+    /// The addresses of the device, which it answers solicitations for.
+    addresses: OptionalCell<&'a dyn InterfaceAddresses>,
+
+    tx_client: OptionalCell<&'a dyn IP6LinkTxClient>,
+    rx_client: OptionalCell<&'a dyn IP6LinkRxClient>,
+
+    /// Whether the host selected the data interface setting with endpoints.
+    connected: Cell<bool>,
+    ctrl_state: Cell<CtrlState>,
+    notification: Cell<Notification>,
+
+    /// The frame being received, and how many bytes of it arrived so far.
+    /// Frames longer than the buffer are dropped.
+    rx_frame: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+
+    /// The packet to send, kept until it was sent, and its length.
+    tx_packet: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// The Neighbor Advertisement to send, and whether it is waiting.
+    advertisement: MapCell<[u8; ADVERTISEMENT_FRAME_LEN]>,
+    advertisement_pending: Cell<bool>,
+    sending: Cell<Sending>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CdcEcm<'a, U> {
+    /// `strings` are the manufacturer, product, serial number and the MAC
+    /// address of the host's adapter as formatted by `format_mac_address`,
+    /// which must be `host_mac_address`. `mac_address` is the device's.
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 4],
+        mac_address: [u8; 6],
+        host_mac_address: [u8; 6],
+        rx_buffer: &'static mut [u8; MAX_FRAME_LEN],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [
+            InterfaceDescriptor {
+                interface_number: COMMUNICATIONS_INTERFACE as u8,
+                interface_class: 0x02,    // Communications
+                interface_subclass: 0x06, // Ethernet Control Model
+                interface_protocol: 0x00,
+                ..InterfaceDescriptor::default()
+            },
+            // The default setting of the data interface has no endpoints, so
+            // no traffic flows until the host selects the other one.
+            InterfaceDescriptor {
+                interface_number: DATA_INTERFACE as u8,
+                alternate_setting: 0,
+                interface_class: 0x0a, // Data
+                interface_subclass: 0x00,
+                interface_protocol: 0x00,
+                ..InterfaceDescriptor::default()
+            },
+            InterfaceDescriptor {
+                interface_number: DATA_INTERFACE as u8,
+                alternate_setting: 1,
+                interface_class: 0x0a, // Data
+                interface_subclass: 0x00,
+                interface_protocol: 0x00,
+                ..InterfaceDescriptor::default()
+            },
+        ];
+
+        let cdc_descriptors: &[CdcInterfaceDescriptor] = &[
+            CdcInterfaceDescriptor {
+                subtype: CdcInterfaceDescriptorSubType::Header,
+                field1: 0x10, // CDC 1.10
+                field2: 0x01, // CDC 1.10
+            },
+            CdcInterfaceDescriptor {
+                subtype: CdcInterfaceDescriptorSubType::Union,
+                field1: COMMUNICATIONS_INTERFACE as u8, // Interface 0
+                field2: DATA_INTERFACE as u8,           // Interface 1
+            },
+        ];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[
+            &[EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NOTIFY_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 16,
+                interval: 32,
+            }],
+            &[],
+            &[
+                EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new_const(
+                        ENDPOINT_IN_NUM,
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+                EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new_const(
+                        ENDPOINT_OUT_NUM,
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+            ],
+        ];
+
+        let (device_descriptor_buffer, mut other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x02, // Class: CDC
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                None, // No HID descriptor
+                Some(cdc_descriptors),
+            );
+
+        // The Ethernet Networking descriptor follows the Union descriptor,
+        // after the configuration and the communications interface.
+        other_descriptor_buffer.insert(
+            9 + 9 + cdc_descriptors.iter().map(|d| d.size()).sum::<usize>(),
+            &CdcEthernetNetworkingDescriptor {
+                mac_address_string: MAC_ADDRESS_STRING,
+                max_segment_size: MAX_FRAME_LEN as u16,
+                number_mc_filters: 0,
+            },
+        );
+        // Alternate settings do not count as interfaces.
+        other_descriptor_buffer.buf[4].set(2);
+
+        CdcEcm {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [
+                Buffer64::default(),
+                Buffer64::default(),
+                Buffer64::default(),
+            ],
+            mac_address,
+            host_mac_address,
+            addresses: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            connected: Cell::new(false),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            notification: Cell::new(Notification::None),
+            rx_frame: TakeCell::new(rx_buffer),
+            rx_len: Cell::new(0),
+            tx_packet: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            advertisement: MapCell::new([0; ADVERTISEMENT_FRAME_LEN]),
+            advertisement_pending: Cell::new(false),
+            sending: Cell::new(Sending::Idle),
+        }
+    }
+
+    /// Sets the addresses the device answers Neighbor Solicitations for.
+    pub fn set_interface_addresses(&self, addresses: &'a dyn InterfaceAddresses) {
+        self.addresses.set(addresses);
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Starts the link and tells the host it is up.
+    fn connect(&self) {
+        self.connected.set(true);
+        self.notification.set(Notification::Connection);
+        self.controller().endpoint_resume_in(ENDPOINT_NOTIFY_NUM);
+        self.start_frame();
+    }
+
+    /// Stops the link, dropping whatever is being sent or received.
+    fn disconnect(&self) {
+        self.connected.set(false);
+        self.notification.set(Notification::None);
+        self.sending.set(Sending::Idle);
+        self.advertisement_pending.set(false);
+        self.rx_len.set(0);
+        if let Some(packet) = self.tx_packet.take() {
+            self.tx_client
+                .map(move |client| client.transmit_done(packet, ReturnCode::ECANCEL));
+        }
+    }
+
+    /// Starts sending the next frame, if the IN endpoint is free.
+    fn start_frame(&self) {
+        if !self.connected.get() || self.sending.get() != Sending::Idle {
+            return;
+        }
+        let frame = if self.advertisement_pending.replace(false) {
+            Frame::Advertisement
+        } else if self.tx_packet.is_some() {
+            Frame::Packet
+        } else {
+            return;
+        };
+        self.sending.set(Sending::Frame {
+            frame,
+            offset: 0,
+            last: false,
+        });
+        self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+    }
+
+    /// Called when the frame being sent is done.
+    fn frame_sent(&self, frame: Frame) {
+        self.sending.set(Sending::Idle);
+        let packet = match frame {
+            Frame::Packet => self.tx_packet.take(),
+            Frame::Advertisement => None,
+        };
+        self.start_frame();
+        if let Some(packet) = packet {
+            self.tx_client
+                .map(move |client| client.transmit_done(packet, ReturnCode::SUCCESS));
+        }
+    }
+
+    /// Handles a complete frame from the host.
+    fn frame_received(&self, frame: &[u8]) {
+        if frame.len() < ETHERNET_HEADER_LEN + IP6_HEADER_LEN
+            || frame[12..14] != ETHERTYPE_IPV6
+            || (frame[0] & 0x01 == 0 && frame[0..6] != self.mac_address)
+        {
+            return;
+        }
+        let packet = &frame[ETHERNET_HEADER_LEN..];
+        // The host may pad the frame.
+        let len = IP6_HEADER_LEN + u16::from_be_bytes([packet[4], packet[5]]) as usize;
+        let packet = &packet[..cmp::min(len, packet.len())];
+        if !self.neighbor_solicitation(packet) {
+            self.rx_client.map(|client| client.packet_received(packet));
+        }
+    }
+
+    /// Answers `packet` if it is a Neighbor Solicitation for one of our
+    /// addresses. Returns whether it was a Neighbor Solicitation, which are
+    /// not passed on.
+    fn neighbor_solicitation(&self, packet: &[u8]) -> bool {
+        let header = match IP6Header::decode(packet).done() {
+            Some((_, header)) => header,
+            None => return false,
+        };
+        let message = &packet[IP6_HEADER_LEN..];
+        if header.get_next_header() != ip6_nh::ICMP
+            || message.len() < SOLICITATION_LEN
+            || message[0] != NEIGHBOR_SOLICITATION
+        {
+            return false;
+        }
+        if header.get_hop_limit() != 255
+            || message[1] != 0
+            || verify_icmp_checksum(&header, message) != 0
+        {
+            // Invalid solicitations are dropped (RFC 4861, section 7.1.1).
+            return true;
+        }
+
+        let mut target = IPAddr::new();
+        target.0.copy_from_slice(&message[8..24]);
+        let is_ours = self.addresses.map_or(false, |addresses| {
+            (0..)
+                .map(|i| addresses.address(i))
+                .take_while(|address| address.is_some())
+                .any(|address| address == Some(target))
+        });
+        let advertising = matches!(
+            self.sending.get(),
+            Sending::Frame {
+                frame: Frame::Advertisement,
+                ..
+            }
+        );
+        // If we are still sending the last advertisement, the host will ask
+        // again.
+        if is_ours && !advertising {
+            self.advertisement.map(|frame| {
+                neighbor_advertisement(
+                    frame,
+                    &header,
+                    target,
+                    &self.mac_address,
+                    &self.host_mac_address,
+                )
+            });
+            self.advertisement_pending.set(true);
+            self.start_frame();
+        }
+        true
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> IP6Link<'a> for CdcEcm<'a, U> {
+    fn set_transmit_client(&self, client: &'a dyn IP6LinkTxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn IP6LinkRxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.connected.get() {
+            return Err((ReturnCode::EOFF, buf));
+        }
+        if self.tx_packet.is_some() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        if len > MTU || len > buf.len() {
+            return Err((ReturnCode::ESIZE, buf));
+        }
+        self.tx_len.set(len);
+        self.tx_packet.replace(buf);
+        self.start_frame();
+        Ok(())
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CdcEcm<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_NOTIFY_NUM, &self.buffers[NOTIFY_BUFFER].buf);
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, ENDPOINT_NOTIFY_NUM);
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.disconnect();
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// Besides the standard requests, this handles the selection of the
+    /// alternate setting of the data interface, which starts and stops the
+    /// link, and the packet filter the host sets.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let setup_data = match descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf) {
+            Some(setup_data) => setup_data,
+            None => return self.client_ctrl.ctrl_setup(endpoint),
+        };
+
+        match (
+            setup_data.request_type.request_type(),
+            setup_data.request_type.recipient(),
+        ) {
+            (RequestType::Standard, Recipient::Device) => {
+                if let Some(descriptors::StandardRequest::SetConfiguration { .. }) =
+                    setup_data.get_standard_request()
+                {
+                    // This selects the default setting of the data interface.
+                    self.disconnect();
+                }
+                self.client_ctrl.ctrl_setup(endpoint)
+            }
+            (RequestType::Standard, Recipient::Interface) => {
+                match (setup_data.request_code, setup_data.index, setup_data.value) {
+                    (SET_INTERFACE, COMMUNICATIONS_INTERFACE, 0) => hil::usb::CtrlSetupResult::Ok,
+                    (SET_INTERFACE, DATA_INTERFACE, 0) => {
+                        self.disconnect();
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    (SET_INTERFACE, DATA_INTERFACE, 1) => {
+                        self.disconnect();
+                        self.connect();
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    (GET_INTERFACE, COMMUNICATIONS_INTERFACE, _) => {
+                        self.ctrl_state.set(CtrlState::GetInterface(0));
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    (GET_INTERFACE, DATA_INTERFACE, _) => {
+                        let alternate_setting = if self.connected.get() { 1 } else { 0 };
+                        self.ctrl_state
+                            .set(CtrlState::GetInterface(alternate_setting));
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    (SET_INTERFACE, _, _) => hil::usb::CtrlSetupResult::ErrGeneric,
+                    _ => self.client_ctrl.ctrl_setup(endpoint),
+                }
+            }
+            (RequestType::Class, Recipient::Interface) => match setup_data.request_code {
+                // The device only sends frames for the host, so there is
+                // nothing to filter.
+                SET_ETHERNET_PACKET_FILTER => hil::usb::CtrlSetupResult::Ok,
+                _ => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.replace(CtrlState::Idle) {
+            CtrlState::GetInterface(alternate_setting) => {
+                self.client_ctrl.ctrl_buffer.buf[0].set(alternate_setting);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::Idle => self.client_ctrl.ctrl_in(endpoint),
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk/Interrupt IN transaction.
+    ///
+    /// Frames are split into packets of the maximum size, and end with a
+    /// shorter, possibly empty, packet.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                let packet = &self.buffers[NOTIFY_BUFFER].buf;
+                let mut notification = [0; 16];
+                notification[0] = 0xa1; // Class request to the interface
+                notification[4] = COMMUNICATIONS_INTERFACE as u8;
+                let len = match self.notification.get() {
+                    Notification::Connection => {
+                        notification[1] = NETWORK_CONNECTION;
+                        notification[2] = 1; // Connected
+                        8
+                    }
+                    Notification::Speed => {
+                        notification[1] = CONNECTION_SPEED_CHANGE;
+                        notification[6] = 8;
+                        notification[8..12].copy_from_slice(&BIT_RATE.to_le_bytes());
+                        notification[12..16].copy_from_slice(&BIT_RATE.to_le_bytes());
+                        16
+                    }
+                    Notification::None => return hil::usb::InResult::Delay,
+                };
+                for (cell, byte) in packet.iter().zip(notification[..len].iter()) {
+                    cell.set(*byte);
+                }
+                hil::usb::InResult::Packet(len)
+            }
+            TransferType::Bulk => {
+                let (frame, offset) = match self.sending.get() {
+                    Sending::Frame {
+                        frame,
+                        offset,
+                        last: false,
+                    } => (frame, offset),
+                    _ => return hil::usb::InResult::Delay,
+                };
+                let packet = &self.buffers[IN_BUFFER].buf;
+                let len = match frame {
+                    Frame::Packet => self.tx_packet.map_or(0, |buf| {
+                        let payload = &buf[..self.tx_len.get()];
+                        let header =
+                            ethernet_header(payload, &self.mac_address, &self.host_mac_address);
+                        copy_frame(&header, payload, offset, packet)
+                    }),
+                    Frame::Advertisement => self
+                        .advertisement
+                        .map_or(0, |buf| copy_frame(&[], buf, offset, packet)),
+                };
+                self.sending.set(Sending::Frame {
+                    frame,
+                    offset: offset + len,
+                    last: len < packet.len(),
+                });
+                hil::usb::InResult::Packet(len)
+            }
+            TransferType::Control | TransferType::Isochronous => hil::usb::InResult::Delay,
+        }
+    }
+
+    /// Handle a Bulk OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                let packet = &self.buffers[OUT_BUFFER].buf;
+                let packet_bytes = cmp::min(packet_bytes as usize, packet.len());
+                self.rx_frame.map(|frame| {
+                    let offset = self.rx_len.get();
+                    if offset + packet_bytes <= frame.len() {
+                        for i in 0..packet_bytes {
+                            frame[offset + i] = packet[i].get();
+                        }
+                    }
+                    // Past the end of the buffer, this only marks the frame
+                    // as too long.
+                    let len = offset.saturating_add(packet_bytes);
+                    if packet_bytes < packet.len() {
+                        // A short packet ends the frame.
+                        self.rx_len.set(0);
+                        if len <= frame.len() && self.connected.get() {
+                            self.frame_received(&frame[..len]);
+                        }
+                    } else {
+                        self.rx_len.set(len);
+                    }
+                });
+                hil::usb::OutResult::Ok
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        match endpoint {
+            ENDPOINT_NOTIFY_NUM => match self.notification.get() {
+                Notification::Connection => {
+                    self.notification.set(Notification::Speed);
+                    self.controller().endpoint_resume_in(ENDPOINT_NOTIFY_NUM);
+                }
+                Notification::Speed | Notification::None => {
+                    self.notification.set(Notification::None);
+                }
+            },
+            ENDPOINT_IN_NUM => match self.sending.get() {
+                Sending::Frame {
+                    frame, last: true, ..
+                } => self.frame_sent(frame),
+                Sending::Frame { .. } => {
+                    self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                }
+                Sending::Idle => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+    const HOST_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x57];
+
+    #[test]
+    fn mac_address_string() {
+        let mut buf = [0; 12];
+        assert_eq!(format_mac_address(&HOST_MAC, &mut buf), "020000123457");
+        assert_eq!(
+            eui64(&MAC),
+            [0x02, 0x00, 0x00, 0xff, 0xfe, 0x12, 0x34, 0x56]
+        );
+    }
+
+    #[test]
+    fn ethernet_destination() {
+        let mut packet = [0; IP6_HEADER_LEN];
+        packet[24..40].copy_from_slice(&ALL_NODES.0);
+        let header = ethernet_header(&packet, &MAC, &HOST_MAC);
+        assert_eq!(header[0..6], [0x33, 0x33, 0, 0, 0, 1]);
+        assert_eq!(header[6..12], MAC);
+        assert_eq!(header[12..14], ETHERTYPE_IPV6);
+
+        packet[24] = 0xfe;
+        let header = ethernet_header(&packet, &MAC, &HOST_MAC);
+        assert_eq!(header[0..6], HOST_MAC);
+    }
+
+    #[test]
+    fn advertisement_answers_solicitation() {
+        let target =
+            IPAddr::generate_from_mac(crate::net::ieee802154::MacAddress::Long(eui64(&MAC)));
+        let mut solicitation = IP6Header::default();
+        solicitation.src_addr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7]);
+
+        let mut frame = [0; ADVERTISEMENT_FRAME_LEN];
+        neighbor_advertisement(&mut frame, &solicitation, target, &MAC, &HOST_MAC);
+        assert_eq!(frame[0..6], HOST_MAC);
+        assert_eq!(frame[6..12], MAC);
+
+        let (_, header) = IP6Header::decode(&frame[ETHERNET_HEADER_LEN..])
+            .done()
+            .unwrap();
+        assert_eq!(header.src_addr, target);
+        assert_eq!(header.dst_addr, solicitation.src_addr);
+        assert_eq!(header.get_payload_len() as usize, ADVERTISEMENT_LEN);
+        let message = &frame[ETHERNET_HEADER_LEN + IP6_HEADER_LEN..];
+        assert_eq!(message[0], NEIGHBOR_ADVERTISEMENT);
+        assert_eq!(message[4], FLAG_SOLICITED | FLAG_OVERRIDE);
+        assert_eq!(message[8..24], target.0);
+        assert_eq!(message[26..32], MAC);
+        assert_eq!(verify_icmp_checksum(&header, message), 0);
+
+        // Duplicate address detection is answered to all nodes.
+        solicitation.src_addr = IPAddr::new();
+        neighbor_advertisement(&mut frame, &solicitation, target, &MAC, &HOST_MAC);
+        assert_eq!(frame[0..6], [0x33, 0x33, 0, 0, 0, 1]);
+        assert_eq!(
+            frame[ETHERNET_HEADER_LEN + IP6_HEADER_LEN + 4],
+            FLAG_OVERRIDE
+        );
+    }
+}
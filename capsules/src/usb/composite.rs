@@ -0,0 +1,940 @@
+//! Composite USB device combining several classes on one controller
+//!
+//! Every USB class capsule (CDC-ACM, CTAP, HID, ...) expects to own a whole
+//! `hil::usb::UsbController`. `CompositeDevice` is the client of the real
+//! controller and hands each class a `UsbFunction`, a virtual controller that
+//! the class uses as if it was the hardware.
+//!
+//! The composite device answers the standard device requests itself. It
+//! builds its configuration descriptor by asking each function for its own,
+//! and renumbers the interfaces and endpoints of the functions so that they do
+//! not collide. Functions with several interfaces, like CDC-ACM, are grouped
+//! with an Interface Association Descriptor. Requests to an interface or an
+//! endpoint are passed on to the function that owns it, with the interface or
+//! endpoint number translated back, and so are the packets on its endpoints.
+//!
+//! The device, manufacturer and product strings of the functions are replaced
+//! by those of the composite device, and functions are in the configuration
+//! descriptor in the order they were added. A function whose descriptors do
+//! not fit in the descriptor storage, or for which no endpoints are left, is
+//! left out.
+//!
+//! ```
+//!             CdcAcm     HidDevice
+//!               |            |
+//!          UsbFunction   UsbFunction
+//!                 \        /
+//!               CompositeDevice
+//!                     |
+//!               UsbController
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let composite = components::usb_composite::UsbCompositeComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915, // Nordic Semiconductor
+//!     0x503a, // lowRISC generic FS USB
+//!     strings,
+//! )
+//! .finalize(components::usb_composite_component_helper!(nrf52::usbd::Usbd));
+//!
+//! let cdc_function = components::usb_composite::UsbFunctionComponent::new(composite)
+//!     .finalize(components::usb_function_component_helper!(nrf52::usbd::Usbd));
+//! let cdc = components::cdc::CdcAcmComponent::new(cdc_function, ...)
+//!     .finalize(components::usb_cdc_acm_component_helper!(
+//!         capsules::usb::composite::UsbFunction<'static, nrf52::usbd::Usbd>,
+//!         nrf52::rtc::Rtc
+//!     ));
+//!
+//! let hid_function = components::usb_composite::UsbFunctionComponent::new(composite)
+//!     .finalize(components::usb_function_component_helper!(nrf52::usbd::Usbd));
+//! let (hid, hid_driver) = components::usb_hid::UsbHidComponent::new(hid_function, ...)
+//!     .finalize(components::usb_hid_component_helper!(
+//!         capsules::usb::composite::UsbFunction<'static, nrf52::usbd::Usbd>
+//!     ));
+//!
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+
+use super::descriptors::Buffer64;
+use super::descriptors::ConfigurationDescriptor;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::DeviceDescriptor;
+use super::descriptors::LanguagesDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::StringDescriptor;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Number of endpoints of the controller, including the control endpoint.
+const N_ENDPOINTS: usize = 8;
+
+/// Number of endpoints a function can use, including the control endpoint.
+const N_FUNCTION_ENDPOINTS: usize = 16;
+
+/// Descriptor type of an Interface Association Descriptor.
+const INTERFACE_ASSOCIATION: u8 = 0x0b;
+
+/// Descriptor type of class-specific interface descriptors.
+const CS_INTERFACE: u8 = 0x24;
+/// CDC functional descriptor subtypes that refer to interface numbers.
+const CDC_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_UNION: u8 = 0x06;
+
+/// States of the control endpoint for requests the composite device handles.
+#[derive(Copy, Clone, PartialEq)]
+enum CtrlState {
+    Idle,
+
+    /// We are sending bytes `start..end` of the descriptor storage.
+    CtrlIn(usize, usize),
+
+    /// We are sending the current configuration value.
+    Configuration,
+
+    SetAddress,
+
+    /// The function in `ctrl_function` handles the request.
+    Forwarded,
+}
+
+/// Appends the descriptors of a function to a configuration descriptor.
+///
+/// `src` is the configuration descriptor of the function, with its interface
+/// and endpoint descriptors. The descriptors are written to `dst` without the
+/// configuration descriptor, with the interface numbers offset by
+/// `first_interface` and the endpoint numbers replaced by what
+/// `physical_endpoint` returns for them. An Interface Association Descriptor is
+/// prepended if the function has several interfaces.
+///
+/// Returns the number of bytes written and the number of interfaces of the
+/// function, or `None` if `src` is truncated, `dst` is too short or
+/// `physical_endpoint` returns 0 for an endpoint.
+fn append_function_descriptors(
+    src: &[u8],
+    dst: &mut [u8],
+    first_interface: u8,
+    physical_endpoint: &mut dyn FnMut(u8) -> u8,
+) -> Option<(usize, u8)> {
+    // Split the descriptors, and stop at a malformed one.
+    let descriptors = || {
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            let len = *src.get(offset)? as usize;
+            if len < 2 || offset + len > src.len() {
+                return None;
+            }
+            offset += len;
+            Some(&src[offset - len..offset])
+        })
+    };
+    let interfaces =
+        || descriptors().filter(|d| d[1] == DescriptorType::Interface as u8 && d.len() >= 9);
+
+    // The descriptors must have been read completely.
+    if src.len() < 4 || (src[2] as usize | (src[3] as usize) << 8) > src.len() {
+        return None;
+    }
+
+    let num_interfaces = interfaces().filter(|d| d[3] == 0).count() as u8;
+    let mut len = 0;
+    if num_interfaces > 1 {
+        let first = interfaces().next()?;
+        let iad = dst.get_mut(0..8)?;
+        iad.copy_from_slice(&[
+            8,
+            INTERFACE_ASSOCIATION,
+            first_interface,
+            num_interfaces,
+            first[5], // Function class
+            first[6], // Function subclass
+            first[7], // Function protocol
+            0,        // No string
+        ]);
+        len = 8;
+    }
+
+    for d in descriptors() {
+        if d[1] == DescriptorType::Configuration as u8 {
+            continue;
+        }
+        let out = dst.get_mut(len..len + d.len())?;
+        out.copy_from_slice(d);
+        match d[1] {
+            t if t == DescriptorType::Interface as u8 && d.len() >= 9 => {
+                out[2] = out[2].wrapping_add(first_interface);
+                // The strings are those of the composite device.
+                out[8] = 0;
+            }
+            t if t == DescriptorType::Endpoint as u8 && d.len() >= 3 => {
+                let endpoint = physical_endpoint(d[2] & 0xf);
+                if endpoint == 0 {
+                    return None;
+                }
+                out[2] = (d[2] & 0x80) | endpoint;
+            }
+            CS_INTERFACE if d.len() >= 5 && d[2] == CDC_CALL_MANAGEMENT => {
+                out[4] = out[4].wrapping_add(first_interface);
+            }
+            CS_INTERFACE if d.len() >= 4 && d[2] == CDC_UNION => {
+                for interface in out[3..].iter_mut() {
+                    *interface = interface.wrapping_add(first_interface);
+                }
+            }
+            _ => {}
+        }
+        len += d.len();
+    }
+    Some((len, num_interfaces))
+}
+
+/// A class driver in a composite device. This is the USB controller of the
+/// class.
+pub struct UsbFunction<'a, U: hil::usb::UsbController<'a>> {
+    device: &'a CompositeDevice<'a, U>,
+    next: ListLink<'a, UsbFunction<'a, U>>,
+    client: OptionalCell<&'a dyn hil::usb::Client<'a>>,
+
+    /// The control endpoint buffer of the class.
+    ctrl_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+
+    /// The endpoint of the controller used for each endpoint of the class, or
+    /// 0 if none was assigned yet.
+    endpoints: [Cell<u8>; N_FUNCTION_ENDPOINTS],
+
+    /// The interfaces of the class are numbered from here.
+    first_interface: Cell<u8>,
+    num_interfaces: Cell<u8>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> ListNode<'a, UsbFunction<'a, U>> for UsbFunction<'a, U> {
+    fn next(&self) -> &'a ListLink<UsbFunction<'a, U>> {
+        &self.next
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a, U> {
+    pub fn new(device: &'a CompositeDevice<'a, U>) -> Self {
+        UsbFunction {
+            device,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            ctrl_buffer: OptionalCell::empty(),
+            endpoints: Default::default(),
+            first_interface: Cell::new(0),
+            num_interfaces: Cell::new(0),
+        }
+    }
+
+    /// Adds the function to the composite device. This must be done before
+    /// the composite device is enabled.
+    pub fn setup(&'a self) {
+        self.device.functions.push_tail(self);
+    }
+
+    /// Returns the endpoint of the controller for the endpoint `endpoint` of
+    /// the class, assigning one if needed, or 0 if there are none left.
+    fn physical_endpoint(&'a self, endpoint: usize) -> usize {
+        if endpoint == 0 || endpoint >= N_FUNCTION_ENDPOINTS {
+            return 0;
+        }
+        let assigned = self.endpoints[endpoint].get() as usize;
+        if assigned != 0 {
+            return assigned;
+        }
+        let physical = self.device.assign_endpoint(self);
+        self.endpoints[endpoint].set(physical as u8);
+        physical
+    }
+
+    /// Returns the endpoint of the class that uses the endpoint `physical` of
+    /// the controller.
+    fn local_endpoint(&self, physical: usize) -> Option<usize> {
+        self.endpoints
+            .iter()
+            .position(|endpoint| endpoint.get() as usize == physical && physical != 0)
+    }
+
+    fn owns_interface(&self, interface: u8) -> bool {
+        interface >= self.first_interface.get()
+            && interface - self.first_interface.get() < self.num_interfaces.get()
+    }
+
+    /// Passes the setup packet in the control buffer of the composite device
+    /// on to the class, with byte 4 (the low byte of wIndex) replaced by
+    /// `index`.
+    fn forward_setup(&self, setup: &[VolatileCell<u8>], index: u8) -> hil::usb::CtrlSetupResult {
+        self.ctrl_buffer
+            .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |buf| {
+                for (to, from) in buf.iter().zip(setup[..8].iter()) {
+                    to.set(from.get());
+                }
+                buf[4].set(index);
+                self.client
+                    .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |client| {
+                        client.ctrl_setup(0)
+                    })
+            })
+    }
+
+    /// Writes the configuration descriptor of the class into `buf` by sending
+    /// it a GET_DESCRIPTOR request.
+    fn read_configuration(&self, buf: &mut [u8]) -> usize {
+        let (ctrl_buffer, client) = match (
+            self.ctrl_buffer.map(|buf| *buf),
+            self.client.map(|client| *client),
+        ) {
+            (Some(ctrl_buffer), Some(client)) => (ctrl_buffer, client),
+            _ => return 0,
+        };
+        let request_len = min(buf.len(), 0xffff) as u16;
+        let request = [
+            0x80, // Device to host, standard, device
+            6,    // GET_DESCRIPTOR
+            0,
+            DescriptorType::Configuration as u8,
+            0,
+            0,
+            request_len as u8,
+            (request_len >> 8) as u8,
+        ];
+        for (to, from) in ctrl_buffer.iter().zip(request.iter()) {
+            to.set(*from);
+        }
+
+        let mut len = 0;
+        if let hil::usb::CtrlSetupResult::Ok = client.ctrl_setup(0) {
+            while let hil::usb::CtrlInResult::Packet(size, last) = client.ctrl_in(0) {
+                let size = min(size, buf.len() - len);
+                for (to, from) in buf[len..len + size].iter_mut().zip(ctrl_buffer.iter()) {
+                    *to = from.get();
+                }
+                len += size;
+                if last || size == 0 {
+                    break;
+                }
+            }
+        }
+        client.ctrl_status_complete(0);
+        len
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::UsbController<'a> for UsbFunction<'a, U> {
+    fn set_client(&self, client: &'a dyn hil::usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, buf: &'a [VolatileCell<u8>]) {
+        self.ctrl_buffer.set(buf);
+    }
+
+    fn endpoint_set_in_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.device.with_function(self, |function| {
+            let physical = function.physical_endpoint(endpoint);
+            if physical != 0 {
+                self.device.controller.endpoint_set_in_buffer(physical, buf);
+            }
+        });
+    }
+
+    fn endpoint_set_out_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.device.with_function(self, |function| {
+            let physical = function.physical_endpoint(endpoint);
+            if physical != 0 {
+                self.device
+                    .controller
+                    .endpoint_set_out_buffer(physical, buf);
+            }
+        });
+    }
+
+    fn enable_as_device(&self, _speed: hil::usb::DeviceSpeed) {
+        // The composite device enables the controller.
+    }
+
+    fn attach(&self) {
+        // The composite device attaches once all functions are ready.
+    }
+
+    fn detach(&self) {}
+
+    fn set_address(&self, _addr: u16) {
+        // Addressing is handled by the composite device.
+    }
+
+    fn enable_address(&self) {}
+
+    fn endpoint_in_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.device.with_function(self, |function| {
+            let physical = function.physical_endpoint(endpoint);
+            if physical != 0 {
+                self.device
+                    .controller
+                    .endpoint_in_enable(transfer_type, physical);
+            }
+        });
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.device.with_function(self, |function| {
+            let physical = function.physical_endpoint(endpoint);
+            if physical != 0 {
+                self.device
+                    .controller
+                    .endpoint_out_enable(transfer_type, physical);
+            }
+        });
+    }
+
+    fn endpoint_in_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.device.with_function(self, |function| {
+            let physical = function.physical_endpoint(endpoint);
+            if physical != 0 {
+                self.device
+                    .controller
+                    .endpoint_in_out_enable(transfer_type, physical);
+            }
+        });
+    }
+
+    fn endpoint_resume_in(&self, endpoint: usize) {
+        let physical = self.endpoints.get(endpoint).map_or(0, |e| e.get() as usize);
+        if physical != 0 {
+            self.device.controller.endpoint_resume_in(physical);
+        }
+    }
+
+    fn endpoint_resume_out(&self, endpoint: usize) {
+        let physical = self.endpoints.get(endpoint).map_or(0, |e| e.get() as usize);
+        if physical != 0 {
+            self.device.controller.endpoint_resume_out(physical);
+        }
+    }
+}
+
+/// The client of the USB controller, dispatching to the functions.
+pub struct CompositeDevice<'a, U: hil::usb::UsbController<'a>> {
+    controller: &'a U,
+    functions: List<'a, UsbFunction<'a, U>>,
+
+    /// The function using each endpoint of the controller.
+    endpoints: [OptionalCell<&'a UsbFunction<'a, U>>; N_ENDPOINTS],
+
+    /// A 64-byte buffer for the control endpoint to be passed to the USB
+    /// driver.
+    ctrl_buffer: Buffer64,
+    ctrl_state: Cell<CtrlState>,
+    /// The function handling the current control transfer.
+    ctrl_function: OptionalCell<&'a UsbFunction<'a, U>>,
+    configuration: Cell<u8>,
+
+    /// Storage for composing responses to descriptor requests.
+    descriptor_storage: TakeCell<'a, [u8]>,
+
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'a [&'a str; 3],
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CompositeDevice<'a, U> {
+    /// `descriptor_storage` must hold the configuration descriptor of all
+    /// functions together, 256 bytes are enough for most devices.
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'a [&'a str; 3],
+        descriptor_storage: &'a mut [u8],
+    ) -> Self {
+        CompositeDevice {
+            controller,
+            functions: List::new(),
+            endpoints: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            ctrl_buffer: Buffer64::default(),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            ctrl_function: OptionalCell::empty(),
+            configuration: Cell::new(0),
+            descriptor_storage: TakeCell::new(descriptor_storage),
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+        }
+    }
+
+    /// Calls `f` with the `'a` reference to `function`, which is needed to
+    /// assign it endpoints.
+    fn with_function<F: FnOnce(&'a UsbFunction<'a, U>)>(
+        &self,
+        function: &UsbFunction<'a, U>,
+        f: F,
+    ) {
+        if let Some(function) = self.functions.iter().find(|f| core::ptr::eq(*f, function)) {
+            f(function);
+        }
+    }
+
+    /// Reserves a free endpoint of the controller for `function`, or returns 0
+    /// if there are none left.
+    fn assign_endpoint(&self, function: &'a UsbFunction<'a, U>) -> usize {
+        self.endpoints
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, owner)| owner.is_none())
+            .map_or(0, |(endpoint, owner)| {
+                owner.set(function);
+                endpoint
+            })
+    }
+
+    /// Writes the configuration descriptor of the device into `buf`, and
+    /// assigns the functions their interfaces and endpoints.
+    fn write_configuration(&self, buf: &mut [u8]) -> usize {
+        let config_len = ConfigurationDescriptor::default().size();
+        let mut len = config_len;
+        let mut num_interfaces = 0;
+        for function in self.functions.iter() {
+            // The configuration descriptor of the function is read into the
+            // second half of the free space, and its descriptors are then
+            // moved into place. They only grow by the IAD.
+            let (dst, src) = buf.split_at_mut(len + (buf.len() - len) / 2);
+            let src_len = function.read_configuration(src);
+            let mut assign = |endpoint| function.physical_endpoint(endpoint as usize) as u8;
+            match append_function_descriptors(
+                &src[..src_len],
+                &mut dst[len..],
+                num_interfaces,
+                &mut assign,
+            ) {
+                Some((function_len, function_interfaces)) => {
+                    function.first_interface.set(num_interfaces);
+                    function.num_interfaces.set(function_interfaces);
+                    len += function_len;
+                    num_interfaces += function_interfaces;
+                }
+                None => {
+                    function.num_interfaces.set(0);
+                }
+            }
+        }
+
+        let configuration = ConfigurationDescriptor {
+            num_interfaces,
+            related_descriptor_length: len - config_len,
+            ..ConfigurationDescriptor::default()
+        };
+        configuration.write_to(Cell::from_mut(&mut buf[..config_len]).as_slice_of_cells());
+        len
+    }
+
+    fn handle_standard_device_request(
+        &'a self,
+        request: StandardRequest,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            StandardRequest::GetDescriptor {
+                descriptor_type,
+                descriptor_index,
+                lang_id,
+                requested_length,
+            } => {
+                let len = self.descriptor_storage.map_or(None, |buf| {
+                    let cells = Cell::from_mut(&mut buf[..]).as_slice_of_cells();
+                    match (descriptor_type, descriptor_index) {
+                        (DescriptorType::Device, 0) => Some(
+                            DeviceDescriptor {
+                                vendor_id: self.vendor_id,
+                                product_id: self.product_id,
+                                manufacturer_string: 1,
+                                product_string: 2,
+                                serial_number_string: 3,
+                                // Miscellaneous device class, the functions
+                                // are described by Interface Association
+                                // Descriptors.
+                                class: 0xef,
+                                subclass: 0x02,
+                                protocol: 0x01,
+                                max_packet_size_ep0: self.max_ctrl_packet_size,
+                                ..DeviceDescriptor::default()
+                            }
+                            .write_to(cells),
+                        ),
+                        (DescriptorType::Configuration, 0) => Some(self.write_configuration(buf)),
+                        (DescriptorType::String, 0) => {
+                            Some(LanguagesDescriptor { langs: LANGUAGES }.write_to(cells))
+                        }
+                        (DescriptorType::String, i)
+                            if (i as usize) <= self.strings.len() && lang_id == LANGUAGES[0] =>
+                        {
+                            Some(
+                                StringDescriptor {
+                                    string: self.strings[i as usize - 1],
+                                }
+                                .write_to(cells),
+                            )
+                        }
+                        _ => None,
+                    }
+                });
+                match len {
+                    Some(len) => {
+                        let end = min(len, requested_length as usize);
+                        self.ctrl_state.set(CtrlState::CtrlIn(0, end));
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    None => match descriptor_type {
+                        DescriptorType::Device => hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex,
+                        DescriptorType::Configuration => {
+                            hil::usb::CtrlSetupResult::ErrInvalidConfigurationIndex
+                        }
+                        DescriptorType::String => hil::usb::CtrlSetupResult::ErrInvalidStringIndex,
+                        // We are full-speed only, so we must respond with a
+                        // request error.
+                        DescriptorType::DeviceQualifier => {
+                            hil::usb::CtrlSetupResult::ErrNoDeviceQualifier
+                        }
+                        _ => hil::usb::CtrlSetupResult::ErrUnrecognizedDescriptorType,
+                    },
+                }
+            }
+            StandardRequest::SetAddress { device_address } => {
+                // Load the address we've been assigned, and enable it when
+                // this request gets to the Status stage.
+                self.controller.set_address(device_address);
+                self.ctrl_state.set(CtrlState::SetAddress);
+                hil::usb::CtrlSetupResult::OkSetAddress
+            }
+            StandardRequest::SetConfiguration {
+                configuration_value,
+            } => {
+                self.configuration.set(configuration_value);
+                // Let every function know, some classes start working then.
+                for function in self.functions.iter() {
+                    function.forward_setup(&self.ctrl_buffer.buf, 0);
+                    function.client.map(|client| client.ctrl_status_complete(0));
+                }
+                hil::usb::CtrlSetupResult::Ok
+            }
+            StandardRequest::GetConfiguration => {
+                self.ctrl_state.set(CtrlState::Configuration);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CompositeDevice<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.controller
+            .endpoint_set_ctrl_buffer(&self.ctrl_buffer.buf);
+        self.controller
+            .enable_as_device(hil::usb::DeviceSpeed::Full); // must be Full for Bulk transfers
+        self.controller
+            .endpoint_out_enable(TransferType::Control, 0);
+
+        for function in self.functions.iter() {
+            function.client.map(|client| client.enable());
+        }
+
+        // Assign the interfaces and all endpoints now, before the host asks.
+        self.descriptor_storage
+            .map(|buf| self.write_configuration(buf));
+    }
+
+    fn attach(&'a self) {
+        for function in self.functions.iter() {
+            function.client.map(|client| client.attach());
+        }
+        self.controller.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.configuration.set(0);
+        for function in self.functions.iter() {
+            function.client.map(|client| client.bus_reset());
+        }
+    }
+
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        if endpoint != 0 {
+            return hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex;
+        }
+        self.ctrl_state.set(CtrlState::Idle);
+        self.ctrl_function.clear();
+
+        let setup_data = match SetupData::get(&self.ctrl_buffer.buf) {
+            Some(setup_data) => setup_data,
+            None => return hil::usb::CtrlSetupResult::ErrNoParse,
+        };
+        let index = setup_data.index as u8;
+        // Find the function the request is for, and the number of the
+        // interface or endpoint in that function.
+        let target = match setup_data.request_type.recipient() {
+            Recipient::Device => {
+                if !matches!(
+                    setup_data.request_type.request_type(),
+                    RequestType::Standard
+                ) {
+                    return hil::usb::CtrlSetupResult::ErrNonstandardRequest;
+                }
+                return setup_data.get_standard_request().map_or(
+                    hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+                    |request| self.handle_standard_device_request(request),
+                );
+            }
+            Recipient::Interface => self
+                .functions
+                .iter()
+                .find(|function| function.owns_interface(index))
+                .map(|function| (function, index - function.first_interface.get())),
+            Recipient::Endpoint => self.endpoints[..]
+                .get(index as usize & 0xf)
+                .and_then(|owner| owner.map(|function| *function))
+                .and_then(|function| {
+                    function
+                        .local_endpoint(index as usize & 0xf)
+                        .map(|endpoint| (function, (index & 0x80) | endpoint as u8))
+                }),
+            _ => None,
+        };
+
+        match target {
+            Some((function, index)) => {
+                let result = function.forward_setup(&self.ctrl_buffer.buf, index);
+                if let hil::usb::CtrlSetupResult::Ok = result {
+                    self.ctrl_state.set(CtrlState::Forwarded);
+                    self.ctrl_function.set(function);
+                }
+                result
+            }
+            None => match setup_data.request_type.recipient() {
+                Recipient::Interface => hil::usb::CtrlSetupResult::ErrInvalidInterfaceIndex,
+                _ => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+        }
+    }
+
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        let buf = &self.ctrl_buffer.buf;
+        match self.ctrl_state.get() {
+            CtrlState::CtrlIn(start, end) => {
+                let packet_bytes = min(buf.len(), end.saturating_sub(start));
+                self.descriptor_storage.map(|storage| {
+                    for (to, from) in buf.iter().zip(storage[start..start + packet_bytes].iter()) {
+                        to.set(*from);
+                    }
+                });
+                let start = start + packet_bytes;
+                self.ctrl_state.set(CtrlState::CtrlIn(start, end));
+                hil::usb::CtrlInResult::Packet(packet_bytes, start >= end)
+            }
+            CtrlState::Configuration => {
+                buf[0].set(self.configuration.get());
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::Forwarded => {
+                self.ctrl_function
+                    .map_or(hil::usb::CtrlInResult::Error, |function| {
+                        let result = function
+                            .client
+                            .map_or(hil::usb::CtrlInResult::Error, |client| {
+                                client.ctrl_in(endpoint)
+                            });
+                        if let hil::usb::CtrlInResult::Packet(size, _) = result {
+                            function.ctrl_buffer.map(|function_buf| {
+                                for (to, from) in buf.iter().zip(function_buf[..size].iter()) {
+                                    to.set(from.get());
+                                }
+                            });
+                        }
+                        result
+                    })
+            }
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::Forwarded => {
+                self.ctrl_function
+                    .map_or(hil::usb::CtrlOutResult::Halted, |function| {
+                        function.ctrl_buffer.map(|function_buf| {
+                            for (to, from) in function_buf
+                                .iter()
+                                .zip(self.ctrl_buffer.buf[..packet_bytes as usize].iter())
+                            {
+                                to.set(from.get());
+                            }
+                        });
+                        function
+                            .client
+                            .map_or(hil::usb::CtrlOutResult::Halted, |client| {
+                                client.ctrl_out(endpoint, packet_bytes)
+                            })
+                    })
+            }
+            _ => hil::usb::CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        if self.ctrl_state.get() == CtrlState::Forwarded {
+            self.ctrl_function
+                .map(|function| function.client.map(|client| client.ctrl_status(endpoint)));
+        }
+    }
+
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        match self.ctrl_state.get() {
+            CtrlState::SetAddress => self.controller.enable_address(),
+            CtrlState::Forwarded => {
+                self.ctrl_function.take().map(|function| {
+                    function
+                        .client
+                        .map(|client| client.ctrl_status_complete(endpoint))
+                });
+            }
+            _ => {}
+        }
+        self.ctrl_state.set(CtrlState::Idle);
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        self.endpoints
+            .get(endpoint)
+            .and_then(|owner| owner.map(|function| *function))
+            .and_then(|function| {
+                let local = function.local_endpoint(endpoint)?;
+                function
+                    .client
+                    .map(|client| client.packet_in(transfer_type, local))
+            })
+            .unwrap_or(hil::usb::InResult::Error)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        self.endpoints
+            .get(endpoint)
+            .and_then(|owner| owner.map(|function| *function))
+            .and_then(|function| {
+                let local = function.local_endpoint(endpoint)?;
+                function
+                    .client
+                    .map(|client| client.packet_out(transfer_type, local, packet_bytes))
+            })
+            .unwrap_or(hil::usb::OutResult::Error)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.endpoints
+            .get(endpoint)
+            .and_then(|owner| owner.map(|function| *function))
+            .map(|function| {
+                function.local_endpoint(endpoint).map(|local| {
+                    function
+                        .client
+                        .map(|client| client.packet_transmitted(local))
+                })
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration descriptor of a CDC-ACM function, as built by `CdcAcm`.
+    const CDC_ACM: &[u8] = &[
+        9, 2, 67, 0, 2, 1, 0, 0xc0, 0, // Configuration
+        9, 4, 0, 0, 1, 2, 2, 1, 0, // Communication interface
+        5, 0x24, 0x00, 0x10, 0x11, // Header
+        5, 0x24, 0x01, 0x00, 0x01, // Call management
+        4, 0x24, 0x02, 0x06, // Abstract control management
+        5, 0x24, 0x06, 0x00, 0x01, // Union
+        7, 5, 0x84, 3, 8, 0, 16, // Notification endpoint
+        9, 4, 1, 0, 2, 0x0a, 0, 0, 0, // Data interface
+        7, 5, 0x82, 2, 64, 0, 0, // Bulk IN
+        7, 5, 0x03, 2, 64, 0, 0, // Bulk OUT
+    ];
+
+    #[test]
+    fn renumbers_functions() {
+        let mut buf = [0; 128];
+        let mut assigned = [0; 16];
+        let mut next = 3;
+        let mut assign = |endpoint: u8| {
+            if assigned[endpoint as usize] == 0 {
+                assigned[endpoint as usize] = next;
+                next += 1;
+            }
+            assigned[endpoint as usize]
+        };
+
+        let (len, interfaces) =
+            append_function_descriptors(CDC_ACM, &mut buf, 1, &mut assign).unwrap();
+        assert_eq!(interfaces, 2);
+        assert_eq!(len, CDC_ACM.len() - 9 + 8);
+
+        // Interface Association Descriptor for interfaces 1 and 2.
+        assert_eq!(buf[..8], [8, 0x0b, 1, 2, 2, 2, 1, 0]);
+        let out = &buf[8..len];
+        assert_eq!(out[2], 1); // Communication interface
+        assert_eq!(out[9 + 5 + 4], 2); // Call management data interface
+        assert_eq!(out[9 + 5 + 5 + 4 + 3..9 + 5 + 5 + 4 + 5], [1, 2]); // Union
+        assert_eq!(out[9 + 19 + 2], 0x83); // Notification endpoint 4 -> 3
+        assert_eq!(out[9 + 19 + 7 + 2], 2); // Data interface
+        assert_eq!(out[9 + 19 + 7 + 9 + 2], 0x84); // Bulk IN 2 -> 4
+        assert_eq!(out[9 + 19 + 7 + 9 + 7 + 2], 0x05); // Bulk OUT 3 -> 5
+    }
+
+    #[test]
+    fn rejects_what_does_not_fit() {
+        let mut buf = [0; 32];
+        assert!(append_function_descriptors(CDC_ACM, &mut buf, 0, &mut |e| e).is_none());
+
+        let mut buf = [0; 128];
+        assert!(append_function_descriptors(CDC_ACM, &mut buf, 0, &mut |_| 0).is_none());
+    }
+}
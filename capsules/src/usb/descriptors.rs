@@ -406,6 +406,25 @@ impl DescriptorBuffer {
         }
         self.len
     }
+
+    /// Inserts `descriptor` at `offset`, moving the descriptors after it and
+    /// updating the total length in the configuration descriptor. This adds
+    /// descriptors `create_descriptor_buffers()` does not know about. Returns
+    /// `false` if the descriptor does not fit.
+    pub fn insert(&mut self, offset: usize, descriptor: &dyn Descriptor) -> bool {
+        let size = descriptor.size();
+        if offset > self.len || self.len + size > self.buf.len() {
+            return false;
+        }
+        for i in (offset..self.len).rev() {
+            self.buf[i + size].set(self.buf[i].get());
+        }
+        descriptor.write_to_unchecked(&self.buf[offset..]);
+        self.len += size;
+        self.buf[2].set(self.len as u8);
+        self.buf[3].set((self.len >> 8) as u8);
+        true
+    }
 }
 
 /// Transform descriptor structs into descriptor buffers that can be
@@ -859,6 +878,37 @@ impl Descriptor for CdcInterfaceDescriptor {
     }
 }
 
+/// The Ethernet Networking Functional Descriptor of a CDC-ECM communications
+/// interface (ECM 1.2, section 5.4).
+pub struct CdcEthernetNetworkingDescriptor {
+    /// Index of the string holding the MAC address as 12 hex digits.
+    pub mac_address_string: u8,
+    /// Largest Ethernet frame, including the header but not the CRC.
+    pub max_segment_size: u16,
+    pub number_mc_filters: u16,
+}
+
+impl Descriptor for CdcEthernetNetworkingDescriptor {
+    fn size(&self) -> usize {
+        13
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(13);
+        buf[1].set(DescriptorType::CdcInterface as u8);
+        buf[2].set(CdcInterfaceDescriptorSubType::EthernetNetworking as u8);
+        buf[3].set(self.mac_address_string);
+        // No Ethernet statistics.
+        for cell in &buf[4..8] {
+            cell.set(0);
+        }
+        put_u16(&buf[8..10], self.max_segment_size);
+        put_u16(&buf[10..12], self.number_mc_filters);
+        buf[12].set(0); // No power filters
+        13
+    }
+}
+
 /// The data structure sent in a CDC-ACM Set Line Coding message.
 #[derive(Debug, Copy, Clone)]
 pub struct CdcAcmSetLineCodingData {
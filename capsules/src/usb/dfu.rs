@@ -0,0 +1,834 @@
+//! Device Firmware Upgrade (DFU 1.1) class for USB
+//!
+//! This lets a host update the device with a standard tool like `dfu-util`.
+//! Each alternate setting of the DFU interface is a target: a region of flash
+//! that either stages a kernel image for the bootloader or holds one TBF app.
+//! The host selects a target with `dfu-util -a <n>`, and the names of the
+//! targets are the interface strings.
+//!
+//! Images are written a page at a time through `hil::flash::Flash`, and every
+//! block is checked to be inside its target, like the app flash driver checks
+//! the writes of apps. The first page of an app is only written if it starts
+//! with a valid TBF header for an app that fits in the slot, and the download
+//! only completes once the whole app was received. Until then the header is
+//! written with a bad checksum, so the kernel never loads a partial app after
+//! an aborted or failed download; the checksum is restored during
+//! manifestation. Regions must be page aligned. Uploads are not supported.
+//!
+//! The device is manifestation tolerant: after a download it goes back to
+//! `dfuIDLE` and tells its client, which can then reboot into the bootloader
+//! or restart the updated app.
+//!
+//! The device starts either in DFU mode, or in runtime mode if it is part of
+//! firmware that only enters DFU mode on request. In runtime mode, the host
+//! sends DFU_DETACH and resets the bus, and the device then enumerates in DFU
+//! mode.
+//!
+//! The data of a control write must fit in a single packet of the control
+//! endpoint, so the host sends blocks of at most 64 bytes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static STRINGS: &'static [&str] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpboard",  // Product
+//!     "Serial No. 5",   // Serial number
+//!     "kernel",         // Target 0
+//!     "app",            // Target 1
+//! ];
+//! static TARGETS: &'static [capsules::usb::dfu::Target] = &[
+//!     capsules::usb::dfu::Target {
+//!         kind: capsules::usb::dfu::TargetKind::Kernel,
+//!         start: 0x80000,
+//!         len: 0x30000,
+//!     },
+//!     capsules::usb::dfu::Target {
+//!         kind: capsules::usb::dfu::TargetKind::App,
+//!         start: 0x40000,
+//!         len: 0x10000,
+//!     },
+//! ];
+//! let dfu = components::dfu::DfuComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915,
+//!     0x521f,
+//!     STRINGS,
+//!     &nrf52::nvmc::NVMC,
+//!     TARGETS,
+//!     false, // Start in DFU mode
+//! )
+//! .finalize(components::dfu_component_helper!(
+//!     nrf52::usbd::Usbd,
+//!     nrf52::nvmc::Nvmc
+//! ));
+//!
+//! dfu.enable();
+//! dfu.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Most targets that fit in the descriptor buffer of `ClientCtrl`.
+pub const MAX_TARGETS: usize = 8;
+
+/// Largest block the host sends in one DFU_DNLOAD request.
+const TRANSFER_SIZE: u16 = 64;
+/// How long the host waits for us to detach after DFU_DETACH, in ms.
+const DETACH_TIMEOUT_MS: u16 = 1000;
+/// How long the host waits before asking for the status again while we
+/// write flash, in ms.
+const POLL_TIMEOUT_MS: u32 = 100;
+
+/// Descriptor type of the DFU functional descriptor.
+const DFU_FUNCTIONAL: u8 = 0x21;
+/// bitCanDnload | bitManifestationTolerant
+const DFU_ATTRIBUTES: u8 = 0x01 | 0x04;
+const RUNTIME_PROTOCOL: u8 = 0x01;
+const DFU_MODE_PROTOCOL: u8 = 0x02;
+
+// DFU class requests
+const DFU_DETACH: u8 = 0x00;
+const DFU_DNLOAD: u8 = 0x01;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_CLRSTATUS: u8 = 0x04;
+const DFU_GETSTATE: u8 = 0x05;
+const DFU_ABORT: u8 = 0x06;
+
+// Standard interface requests we handle ourselves
+const GET_INTERFACE: u8 = 0x0a;
+const SET_INTERFACE: u8 = 0x0b;
+
+/// What a target holds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TargetKind {
+    /// A kernel image, staged for the bootloader to install.
+    Kernel,
+    /// A single TBF app.
+    App,
+}
+
+/// A region of flash the host can write, selected by an alternate setting.
+pub struct Target {
+    pub kind: TargetKind,
+    /// Address of the region, aligned to a flash page.
+    pub start: usize,
+    /// Length of the region, a multiple of the flash page size.
+    pub len: usize,
+}
+
+pub trait Client {
+    /// A download of `len` bytes to target `target` completed.
+    fn download_complete(&self, target: usize, len: usize);
+}
+
+/// States of the DFU state machine, numbered as in the specification.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    Error = 10,
+}
+
+/// Status codes reported by DFU_GETSTATUS.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Status {
+    Ok = 0x00,
+    ErrTarget = 0x01,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrErase = 0x04,
+    ErrProg = 0x06,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrStalledPkt = 0x0f,
+}
+
+/// States of the Control Endpoint related to DFU.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CtrlState {
+    /// No ongoing ctrl transaction.
+    Idle,
+    /// Host is sending a block of the image.
+    Download,
+    /// Host asked for the status, answered with this state and poll timeout.
+    GetStatus(State, u32),
+    GetState,
+    GetInterface,
+}
+
+/// Returns the total length of the TBF app whose header starts `image`, if
+/// the header is valid and the app fits in `slot_len` bytes.
+fn tbf_app_length(image: &[u8], slot_len: usize) -> Option<usize> {
+    let word = |i: usize| u32::from_le_bytes([image[i], image[i + 1], image[i + 2], image[i + 3]]);
+    if image.len() < 16 {
+        return None;
+    }
+    let version = word(0) & 0xffff;
+    let header_len = (word(0) >> 16) as usize;
+    let total_len = word(4) as usize;
+    if version != 2
+        || header_len < 16
+        || header_len > image.len()
+        || total_len < header_len
+        || total_len > slot_len
+    {
+        return None;
+    }
+
+    // The checksum is the XOR of the words of the header, except itself.
+    let checksum = image[..header_len]
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(i, _)| *i != 3)
+        .fold(0, |checksum, (_, chunk)| {
+            checksum ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])
+        });
+    if checksum == word(12) {
+        Some(total_len)
+    } else {
+        None
+    }
+}
+
+pub struct Dfu<'a, U: 'a, F: hil::flash::Flash + 'static> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    flash: &'a F,
+    /// Buffer for the page of the image being received.
+    page: TakeCell<'static, F::Page>,
+    page_size: usize,
+    /// The page being erased and written.
+    flushing_page: Cell<usize>,
+
+    targets: &'a [Target],
+    /// The selected target, i.e. the alternate setting.
+    target: Cell<usize>,
+
+    client: OptionalCell<&'a dyn Client>,
+
+    ctrl_state: Cell<CtrlState>,
+    state: Cell<State>,
+    status: Cell<Status>,
+
+    /// Number of bytes of the image received so far.
+    offset: Cell<usize>,
+    /// Number of bytes of the image in the page buffer.
+    fill: Cell<usize>,
+    /// Length of the app given by its TBF header.
+    app_len: Cell<usize>,
+    /// Checksum of the app's TBF header, while the header in flash has a bad
+    /// one.
+    app_checksum: Cell<Option<u32>>,
+    /// Whether the image being manifested was written completely.
+    manifested: Cell<bool>,
+    /// Whether to tell the client about a completed download.
+    notify_pending: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, F: hil::flash::Flash> Dfu<'a, U, F> {
+    /// `strings` holds the manufacturer, product and serial number strings,
+    /// followed by the name of each target. The device starts in runtime
+    /// mode if `runtime` is set, and in DFU mode otherwise.
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        flash: &'a F,
+        page: &'static mut F::Page,
+        targets: &'a [Target],
+        runtime: bool,
+    ) -> Self {
+        let n_targets = cmp::min(targets.len(), MAX_TARGETS);
+
+        // Each target is an alternate setting of the DFU interface.
+        let mut interfaces: [InterfaceDescriptor; MAX_TARGETS] = Default::default();
+        for (i, interface) in interfaces.iter_mut().enumerate() {
+            *interface = InterfaceDescriptor {
+                interface_number: 0,
+                alternate_setting: i as u8,
+                interface_class: 0xfe,    // Application specific
+                interface_subclass: 0x01, // Device firmware upgrade
+                interface_protocol: if runtime {
+                    RUNTIME_PROTOCOL
+                } else {
+                    DFU_MODE_PROTOCOL
+                },
+                string_index: if strings.len() > 3 + i {
+                    4 + i as u8
+                } else {
+                    0
+                },
+                ..InterfaceDescriptor::default()
+            };
+        }
+        let endpoints: [&[EndpointDescriptor]; MAX_TARGETS] = [&[]; MAX_TARGETS];
+
+        let (device_descriptor_buffer, mut other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class: defined by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                &mut interfaces[..n_targets],
+                &endpoints[..n_targets],
+                None, // No HID descriptor
+                None, // No CDC descriptor
+            );
+
+        // The DFU functional descriptor follows the alternate settings, which
+        // all belong to a single interface.
+        let functional = [
+            9,
+            DFU_FUNCTIONAL,
+            DFU_ATTRIBUTES,
+            DETACH_TIMEOUT_MS as u8,
+            (DETACH_TIMEOUT_MS >> 8) as u8,
+            TRANSFER_SIZE as u8,
+            (TRANSFER_SIZE >> 8) as u8,
+            0x10, // DFU 1.1
+            0x01,
+        ];
+        let len = other_descriptor_buffer.len;
+        for (cell, byte) in other_descriptor_buffer.buf[len..]
+            .iter()
+            .zip(functional.iter())
+        {
+            cell.set(*byte);
+        }
+        other_descriptor_buffer.len = len + functional.len();
+        let total_len = other_descriptor_buffer.len as u16;
+        other_descriptor_buffer.buf[2].set(total_len as u8);
+        other_descriptor_buffer.buf[3].set((total_len >> 8) as u8);
+        other_descriptor_buffer.buf[4].set(1); // Number of interfaces
+
+        let page_size = page.as_mut().len();
+        Dfu {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            flash,
+            page: TakeCell::new(page),
+            page_size,
+            flushing_page: Cell::new(0),
+            targets: &targets[..n_targets],
+            target: Cell::new(0),
+            client: OptionalCell::empty(),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            state: Cell::new(if runtime {
+                State::AppIdle
+            } else {
+                State::DfuIdle
+            }),
+            status: Cell::new(Status::Ok),
+            offset: Cell::new(0),
+            fill: Cell::new(0),
+            app_len: Cell::new(0),
+            app_checksum: Cell::new(None),
+            manifested: Cell::new(false),
+            notify_pending: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    /// Changes the protocol of the DFU interface in the configuration
+    /// descriptor, to switch between runtime and DFU mode.
+    fn set_protocol(&self, protocol: u8) {
+        let descriptors = self.client_ctrl.other_descriptor_buffer();
+        let mut i = 0;
+        while i + 1 < descriptors.len {
+            let len = descriptors.buf[i].get() as usize;
+            if len < 2 {
+                break;
+            }
+            if descriptors.buf[i + 1].get() == descriptors::DescriptorType::Interface as u8 {
+                descriptors.buf[i + 7].set(protocol);
+            }
+            i += len;
+        }
+    }
+
+    fn in_runtime_mode(&self) -> bool {
+        matches!(self.state.get(), State::AppIdle | State::AppDetach)
+    }
+
+    /// Rejects a request. Outside of runtime mode, this moves to the error
+    /// state until the host clears the status.
+    fn fail(&self, status: Status) -> hil::usb::CtrlSetupResult {
+        if !self.in_runtime_mode() {
+            self.status.set(status);
+            self.state.set(State::Error);
+        }
+        hil::usb::CtrlSetupResult::ErrGeneric
+    }
+
+    /// Drops the download in progress.
+    fn reset_download(&self) {
+        self.offset.set(0);
+        self.fill.set(0);
+        self.app_len.set(0);
+        self.app_checksum.set(None);
+        self.manifested.set(false);
+    }
+
+    fn set_interface(&self, alternate_setting: usize) -> hil::usb::CtrlSetupResult {
+        if alternate_setting < self.targets.len()
+            && matches!(self.state.get(), State::AppIdle | State::DfuIdle)
+        {
+            self.target.set(alternate_setting);
+            hil::usb::CtrlSetupResult::Ok
+        } else {
+            hil::usb::CtrlSetupResult::ErrGeneric
+        }
+    }
+
+    /// Handles a DFU_DNLOAD request with `len` bytes of data.
+    fn download(&self, len: usize) -> hil::usb::CtrlSetupResult {
+        let target = match self.targets.get(self.target.get()) {
+            Some(target) => target,
+            None => return self.fail(Status::ErrTarget),
+        };
+
+        if len == 0 {
+            // The end of the image.
+            return if self.state.get() == State::DnloadIdle {
+                self.state.set(State::ManifestSync);
+                hil::usb::CtrlSetupResult::Ok
+            } else {
+                self.fail(Status::ErrStalledPkt)
+            };
+        }
+
+        if self.state.get() == State::DfuIdle {
+            if target.start % self.page_size != 0 || target.len % self.page_size != 0 {
+                return self.fail(Status::ErrTarget);
+            }
+            self.reset_download();
+        }
+
+        // The block must be inside the target, and in a single page.
+        if len > TRANSFER_SIZE as usize
+            || self.offset.get() + len > target.len
+            || self.fill.get() + len > self.page_size
+        {
+            return self.fail(Status::ErrAddress);
+        }
+
+        self.ctrl_state.set(CtrlState::Download);
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    /// Handles a DFU_GETSTATUS request, which moves the state machine on.
+    /// Returns the state to report and how long the host should wait before
+    /// asking again.
+    fn get_status(&self) -> (State, u32) {
+        match self.state.get() {
+            State::DnloadSync => {
+                if self.fill.get() == self.page_size {
+                    self.state.set(State::DnBusy);
+                    self.flush();
+                } else {
+                    self.state.set(State::DnloadIdle);
+                }
+            }
+            State::ManifestSync => {
+                if self.manifested.get() {
+                    self.state.set(State::DfuIdle);
+                    self.notify_pending.set(true);
+                } else {
+                    self.state.set(State::Manifest);
+                    if self.fill.get() > 0 {
+                        self.flush();
+                    } else {
+                        self.finish_manifest();
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let state = self.state.get();
+        match state {
+            State::DnBusy | State::Manifest => (state, POLL_TIMEOUT_MS),
+            _ => (state, 0),
+        }
+    }
+
+    /// Writes the page buffer to flash, padded with 0xff.
+    fn flush(&self) {
+        let target = &self.targets[self.target.get()];
+        let page_offset = self.offset.get() - self.fill.get();
+        let fill = self.fill.get();
+
+        let header_ok = self.page.map_or(false, |page| {
+            let page = page.as_mut();
+            for byte in page[fill..].iter_mut() {
+                *byte = 0xff;
+            }
+
+            // An app slot is only written if the app is valid and fits, and
+            // its header stays invalid until the whole app is written.
+            if target.kind == TargetKind::App && page_offset == 0 {
+                match tbf_app_length(&page[..fill], target.len) {
+                    Some(app_len) => self.app_len.set(app_len),
+                    None => return false,
+                }
+                let mut checksum = [0; 4];
+                checksum.copy_from_slice(&page[12..16]);
+                let checksum = u32::from_le_bytes(checksum);
+                self.app_checksum.set(Some(checksum));
+                page[12..16].copy_from_slice(&(!checksum).to_le_bytes());
+            }
+            true
+        });
+        if !header_ok {
+            self.fail(Status::ErrFile);
+            return;
+        }
+
+        let page_number = (target.start + page_offset) / self.page_size;
+        self.flushing_page.set(page_number);
+        if self.flash.erase_page(page_number) != ReturnCode::SUCCESS {
+            self.fail(Status::ErrErase);
+        }
+    }
+
+    fn finish_manifest(&self) {
+        let target = &self.targets[self.target.get()];
+        if target.kind == TargetKind::App && self.offset.get() < self.app_len.get() {
+            self.fail(Status::ErrNotDone);
+        } else if self.app_checksum.get().is_some() {
+            // Read the first page back to restore the checksum of the header.
+            let page_number = target.start / self.page_size;
+            let read = self.page.take().map_or(false, |page| {
+                match self.flash.read_page(page_number, page) {
+                    Ok(()) => true,
+                    Err((_, page)) => {
+                        self.page.replace(page);
+                        false
+                    }
+                }
+            });
+            if !read {
+                self.fail(Status::ErrProg);
+            }
+        } else {
+            self.manifested.set(true);
+            self.state.set(State::ManifestSync);
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, F: hil::flash::Flash> hil::usb::Client<'a>
+    for Dfu<'a, U, F>
+{
+    fn enable(&'a self) {
+        // DFU only uses the default control endpoint.
+        self.client_ctrl.enable();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        match self.state.get() {
+            State::AppDetach => {
+                // The host reset us after DFU_DETACH, so it now looks for a
+                // device in DFU mode.
+                self.set_protocol(DFU_MODE_PROTOCOL);
+                self.reset_download();
+                self.state.set(State::DfuIdle);
+            }
+            // The flash operation in progress finishes on its own.
+            State::AppIdle | State::DnBusy | State::Manifest => {}
+            _ => {
+                self.reset_download();
+                self.status.set(Status::Ok);
+                self.state.set(State::DfuIdle);
+            }
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// Besides the standard requests, this handles the DFU class requests and
+    /// the selection of the target through the alternate setting.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let request =
+            descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).filter(|setup_data| {
+                matches!(setup_data.request_type.recipient(), Recipient::Interface)
+            });
+        let setup_data = match request {
+            Some(setup_data) => setup_data,
+            None => return self.client_ctrl.ctrl_setup(endpoint),
+        };
+
+        match setup_data.request_type.request_type() {
+            RequestType::Standard => match setup_data.request_code {
+                SET_INTERFACE => self.set_interface(setup_data.value as usize),
+                GET_INTERFACE => {
+                    self.ctrl_state.set(CtrlState::GetInterface);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                _ => self.client_ctrl.ctrl_setup(endpoint),
+            },
+            RequestType::Class => match (setup_data.request_code, self.state.get()) {
+                (DFU_DETACH, State::AppIdle) => {
+                    self.state.set(State::AppDetach);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                (DFU_DNLOAD, State::DfuIdle) | (DFU_DNLOAD, State::DnloadIdle) => {
+                    self.download(setup_data.length as usize)
+                }
+                (DFU_GETSTATUS, _) => {
+                    let (state, poll_timeout) = self.get_status();
+                    self.ctrl_state
+                        .set(CtrlState::GetStatus(state, poll_timeout));
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                (DFU_CLRSTATUS, State::Error) => {
+                    self.reset_download();
+                    self.status.set(Status::Ok);
+                    self.state.set(State::DfuIdle);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                (DFU_GETSTATE, _) => {
+                    self.ctrl_state.set(CtrlState::GetState);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                (DFU_ABORT, state)
+                    if matches!(
+                        state,
+                        State::DfuIdle
+                            | State::DnloadSync
+                            | State::DnloadIdle
+                            | State::ManifestSync
+                    ) =>
+                {
+                    self.reset_download();
+                    self.state.set(State::DfuIdle);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                _ => self.fail(Status::ErrStalledPkt),
+            },
+            _ => hil::usb::CtrlSetupResult::ErrGeneric,
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        let buf = &self.client_ctrl.ctrl_buffer.buf;
+        let len = match self.ctrl_state.get() {
+            CtrlState::GetStatus(state, poll_timeout) => {
+                buf[0].set(self.status.get() as u8);
+                buf[1].set(poll_timeout as u8);
+                buf[2].set((poll_timeout >> 8) as u8);
+                buf[3].set((poll_timeout >> 16) as u8);
+                buf[4].set(state as u8);
+                buf[5].set(0); // No status string
+                6
+            }
+            CtrlState::GetState => {
+                buf[0].set(self.state.get() as u8);
+                1
+            }
+            CtrlState::GetInterface => {
+                buf[0].set(self.target.get() as u8);
+                1
+            }
+            CtrlState::Idle | CtrlState::Download => return self.client_ctrl.ctrl_in(endpoint),
+        };
+        self.ctrl_state.set(CtrlState::Idle);
+        hil::usb::CtrlInResult::Packet(len, true)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        if self.ctrl_state.get() != CtrlState::Download {
+            return self.client_ctrl.ctrl_out(endpoint, packet_bytes);
+        }
+
+        let packet = &self.client_ctrl.ctrl_buffer.buf;
+        self.page.map_or(hil::usb::CtrlOutResult::Halted, |page| {
+            let page = page.as_mut();
+            let fill = self.fill.get();
+            let len = cmp::min(packet_bytes as usize, page.len() - fill);
+            for (byte, cell) in page[fill..fill + len].iter_mut().zip(packet.iter()) {
+                *byte = cell.get();
+            }
+            self.fill.set(fill + len);
+            self.offset.set(self.offset.get() + len);
+            hil::usb::CtrlOutResult::Ok
+        })
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        if self.ctrl_state.get() == CtrlState::Download {
+            self.state.set(State::DnloadSync);
+        }
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_status_complete(endpoint);
+
+        // The host got the status, so the client may now reset the device.
+        if self.notify_pending.get() {
+            self.notify_pending.set(false);
+            let len = self.offset.get();
+            self.client
+                .map(|client| client.download_complete(self.target.get(), len));
+        }
+    }
+
+    fn packet_in(&'a self, _transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        hil::usb::InResult::Error
+    }
+
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {}
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, F: hil::flash::Flash> hil::flash::Client<F>
+    for Dfu<'a, U, F>
+{
+    fn read_complete(&self, read_buffer: &'static mut F::Page, error: hil::flash::Error) {
+        // Only the first page of an app is read, to restore its header.
+        let checksum = self.app_checksum.take().unwrap_or(0);
+        read_buffer.as_mut()[12..16].copy_from_slice(&checksum.to_le_bytes());
+        self.page.replace(read_buffer);
+        if error != hil::flash::Error::CommandComplete {
+            self.fail(Status::ErrProg);
+            return;
+        }
+
+        let page_number = self.targets[self.target.get()].start / self.page_size;
+        self.flushing_page.set(page_number);
+        if self.flash.erase_page(page_number) != ReturnCode::SUCCESS {
+            self.fail(Status::ErrErase);
+        }
+    }
+
+    fn write_complete(&self, write_buffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.page.replace(write_buffer);
+        self.fill.set(0);
+
+        if error != hil::flash::Error::CommandComplete {
+            self.fail(Status::ErrProg);
+            return;
+        }
+        match self.state.get() {
+            State::DnBusy => self.state.set(State::DnloadSync),
+            State::Manifest => self.finish_manifest(),
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.fail(Status::ErrErase);
+            return;
+        }
+        let written = self.page.take().map_or(false, |page| {
+            match self.flash.write_page(self.flushing_page.get(), page) {
+                Ok(()) => true,
+                Err((_, page)) => {
+                    self.page.replace(page);
+                    false
+                }
+            }
+        });
+        if !written {
+            self.fail(Status::ErrWrite);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16-byte TBF header with no TLVs, for an app of `total_len` bytes.
+    fn header(total_len: u32) -> [u8; 16] {
+        let version_and_len = 2 | 16 << 16;
+        let flags = 1;
+        let checksum = version_and_len ^ total_len ^ flags;
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&version_and_len.to_le_bytes());
+        header[4..8].copy_from_slice(&total_len.to_le_bytes());
+        header[8..12].copy_from_slice(&flags.to_le_bytes());
+        header[12..16].copy_from_slice(&checksum.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn tbf_header_check() {
+        assert_eq!(tbf_app_length(&header(0x800), 0x1000), Some(0x800));
+        // Does not fit in the slot.
+        assert_eq!(tbf_app_length(&header(0x2000), 0x1000), None);
+
+        let mut corrupt = header(0x800);
+        corrupt[9] ^= 1;
+        assert_eq!(tbf_app_length(&corrupt, 0x1000), None);
+
+        assert_eq!(tbf_app_length(&header(0x800)[..12], 0x1000), None);
+    }
+}
@@ -0,0 +1,543 @@
+//! Generic Human Interface Device class for USB
+//!
+//! Unlike the CTAP capsule, this device does not fix the report descriptor:
+//! the board passes one in, together with the lengths of the input reports
+//! (sent to the host) and output reports (sent by the host, e.g. the LED state
+//! of a keyboard). Reports are exchanged with the client through
+//! `hil::usb_hid::UsbHid`, in 64-byte buffers of which only the first
+//! `input_report_len` bytes are sent, and output reports are padded with
+//! zeros. Report descriptors for a boot keyboard and a mouse are provided.
+//!
+//! Output reports are accepted both on the interrupt OUT endpoint and through
+//! SET_REPORT requests on the control endpoint, as hosts use either.
+//!
+//! The report descriptor must fit in the 128-byte descriptor buffer of
+//! `ClientCtrl`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let (hid, hid_driver) = components::usb_hid::UsbHidComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915, // Nordic Semiconductor
+//!     0x503a, // lowRISC generic FS USB
+//!     strings,
+//!     board_kernel,
+//!     capsules::usb::hid::KEYBOARD_REPORT_DESCRIPTOR,
+//!     capsules::usb::hid::BootProtocol::Keyboard,
+//!     8, // Input report length
+//!     1, // Output report length
+//! )
+//! .finalize(components::usb_hid_component_helper!(nrf52::usbd::Usbd));
+//!
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::ReportDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Use 1 Interrupt transfer IN/OUT endpoint
+const ENDPOINT_NUM: usize = 1;
+
+const OUT_BUFFER: usize = 0;
+const IN_BUFFER: usize = 1;
+
+const N_ENDPOINTS: usize = 2;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// How often the host polls for input reports, in ms.
+const POLL_INTERVAL_MS: u8 = 10;
+
+// HID class requests.
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+
+/// The report descriptor of a keyboard using the boot protocol report format:
+/// 8-byte input reports (modifiers, reserved byte, 6 key codes) and 1-byte
+/// output reports (LEDs).
+pub static KEYBOARD_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xE0, //   Usage Minimum (224)
+    0x29, 0xE7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute), modifier byte
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant), reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute), LED report
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant), LED report padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array), key arrays (6 bytes)
+    0xC0, // End Collection
+];
+
+/// The report descriptor of a mouse with three buttons and a wheel: 4-byte
+/// input reports (buttons, X, Y, wheel) and no output reports.
+pub static MOUSE_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xA1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Buttons)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute), buttons
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant), padding
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7F, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x06, //     Input (Data, Variable, Relative), X, Y and wheel
+    0xC0, //   End Collection
+    0xC0, // End Collection
+];
+
+/// Boot protocol of the device, which lets a BIOS use keyboards and mice
+/// without parsing the report descriptor. Devices using one must also use its
+/// report format.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BootProtocol {
+    None = 0,
+    Keyboard = 1,
+    Mouse = 2,
+}
+
+/// States of the Control Endpoint related to HID.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CtrlState {
+    /// No ongoing ctrl transaction.
+    Idle,
+    /// Host has asked for the idle rate.
+    GetIdle,
+    /// Host has asked for the protocol in use.
+    GetProtocol,
+    /// Host is sending an output report.
+    SetReport,
+}
+
+/// Implementation of a HID (Human Interface Device) with a report descriptor
+/// given by the board.
+pub struct HidDevice<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    input_report_len: usize,
+    output_report_len: usize,
+
+    client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; 64]>>,
+
+    /// A buffer to hold the report we want to send
+    send_buffer: TakeCell<'static, [u8; 64]>,
+
+    /// A holder for the buffer to receive output reports into.
+    recv_buffer: TakeCell<'static, [u8; 64]>,
+    /// Length of the output report waiting in the OUT endpoint buffer because
+    /// the client could not take it, if any.
+    out_pending: OptionalCell<usize>,
+
+    ctrl_state: Cell<CtrlState>,
+    /// Protocol selected by the host, 0 for boot and 1 for report.
+    protocol: Cell<u8>,
+    /// Idle rate set by the host, in units of 4 ms.
+    idle_rate: Cell<u8>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> HidDevice<'a, U> {
+    /// Creates a device with the report descriptor in `report_descriptor`,
+    /// which `hid_descriptor` must list. Reports are at most 64 bytes long,
+    /// and an output report length of 0 means the device has no OUT endpoint.
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        hid_descriptor: &'static HIDDescriptor<'static>,
+        report_descriptor: &'static ReportDescriptor<'static>,
+        boot_protocol: BootProtocol,
+        input_report_len: usize,
+        output_report_len: usize,
+    ) -> Self {
+        let input_report_len = cmp::min(input_report_len, 64);
+        let output_report_len = cmp::min(output_report_len, 64);
+
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x03, // HID
+            interface_subclass: if boot_protocol == BootProtocol::None {
+                0x00 // No subclass
+            } else {
+                0x01 // Boot interface
+            },
+            interface_protocol: boot_protocol as u8,
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: input_report_len as u16,
+                interval: POLL_INTERVAL_MS,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: output_report_len as u16,
+                interval: POLL_INTERVAL_MS,
+            },
+        ];
+        let n_endpoints = if output_report_len > 0 { 2 } else { 1 };
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class: defined by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                &[&endpoints[..n_endpoints]],
+                Some(hid_descriptor),
+                None, // No CDC descriptor
+            );
+
+        HidDevice {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                Some(hid_descriptor),
+                Some(report_descriptor),
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            input_report_len,
+            output_report_len,
+            client: OptionalCell::empty(),
+            send_buffer: TakeCell::empty(),
+            recv_buffer: TakeCell::empty(),
+            out_pending: OptionalCell::empty(),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            protocol: Cell::new(1),
+            idle_rate: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; 64]>) {
+        self.client.set(client);
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.recv_buffer.is_some() && self.client.map_or(false, |client| client.can_receive())
+    }
+
+    /// Passes the first `len` bytes of `packet` to the client as an output
+    /// report, if it can take one.
+    fn deliver_report(&'a self, packet: &[VolatileCell<u8>], len: usize) -> bool {
+        if !self.can_receive() {
+            return false;
+        }
+        self.recv_buffer.take().map_or(false, |buf| {
+            let len = cmp::min(len, self.output_report_len);
+            for i in 0..buf.len() {
+                buf[i] = if i < len { packet[i].get() } else { 0 };
+            }
+            self.client.map(move |client| {
+                client.packet_received(ReturnCode::SUCCESS, buf, ENDPOINT_NUM);
+            });
+            true
+        })
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for HidDevice<'a, U> {
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; 64],
+    ) -> Result<usize, (ReturnCode, &'static mut [u8; 64])> {
+        if self.send_buffer.is_some() {
+            return Err((ReturnCode::EBUSY, send));
+        }
+
+        self.send_buffer.replace(send);
+        self.controller().endpoint_resume_in(ENDPOINT_NUM);
+
+        Ok(self.input_report_len)
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; 64], ReturnCode> {
+        match self.send_buffer.take() {
+            Some(buf) => Ok(buf),
+            None => Err(ReturnCode::EINVAL),
+        }
+    }
+
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; 64],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; 64])> {
+        if self.recv_buffer.is_some() {
+            return Err((ReturnCode::EBUSY, recv));
+        }
+        self.recv_buffer.replace(recv);
+
+        if let Some(len) = self.out_pending.take() {
+            // Pass the report we held back, and accept the next one.
+            let packet = &self.buffers[OUT_BUFFER].buf;
+            if self.deliver_report(packet, len) {
+                self.controller().endpoint_resume_out(ENDPOINT_NUM);
+            } else {
+                self.out_pending.set(len);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; 64], ReturnCode> {
+        match self.recv_buffer.take() {
+            Some(buf) => Ok(buf),
+            None => Err(ReturnCode::EINVAL),
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for HidDevice<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
+        if self.output_report_len > 0 {
+            self.controller()
+                .endpoint_set_out_buffer(ENDPOINT_NUM, &self.buffers[OUT_BUFFER].buf);
+            self.controller()
+                .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+        } else {
+            self.controller()
+                .endpoint_in_enable(TransferType::Interrupt, ENDPOINT_NUM);
+        }
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.protocol.set(1);
+        self.idle_rate.set(0);
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// Besides the standard requests, this handles the HID class requests
+    /// for the idle rate, the protocol and output reports.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let request =
+            descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).filter(|setup_data| {
+                matches!(setup_data.request_type.request_type(), RequestType::Class)
+                    && matches!(setup_data.request_type.recipient(), Recipient::Interface)
+            });
+
+        match request {
+            Some(setup_data) => match setup_data.request_code {
+                GET_IDLE => {
+                    self.ctrl_state.set(CtrlState::GetIdle);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                GET_PROTOCOL => {
+                    self.ctrl_state.set(CtrlState::GetProtocol);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                SET_REPORT => {
+                    self.ctrl_state.set(CtrlState::SetReport);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                SET_IDLE => {
+                    self.idle_rate.set((setup_data.value >> 8) as u8);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                SET_PROTOCOL => {
+                    self.protocol.set(setup_data.value as u8);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                _ => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+            None => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        let value = match self.ctrl_state.get() {
+            CtrlState::GetIdle => self.idle_rate.get(),
+            CtrlState::GetProtocol => self.protocol.get(),
+            CtrlState::Idle | CtrlState::SetReport => return self.client_ctrl.ctrl_in(endpoint),
+        };
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_buffer.buf[0].set(value);
+        hil::usb::CtrlInResult::Packet(1, true)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        if self.ctrl_state.get() == CtrlState::SetReport {
+            // Output reports that the client cannot take are dropped, like
+            // an LED state nobody looks at.
+            self.deliver_report(&self.client_ctrl.ctrl_buffer.buf, packet_bytes as usize);
+            hil::usb::CtrlOutResult::Ok
+        } else {
+            self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk/Interrupt IN transaction.
+    ///
+    /// This is called after we resumed the IN endpoint because the client
+    /// gave us a report to send.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => self.send_buffer.map_or(hil::usb::InResult::Delay, |buf| {
+                let packet = &self.buffers[IN_BUFFER].buf;
+                for i in 0..self.input_report_len {
+                    packet[i].set(buf[i]);
+                }
+                hil::usb::InResult::Packet(self.input_report_len)
+            }),
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    ///
+    /// This is an output report going from the host to the device (us)
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                let packet = &self.buffers[OUT_BUFFER].buf;
+                if self.deliver_report(packet, packet_bytes as usize) {
+                    hil::usb::OutResult::Ok
+                } else {
+                    // Keep the report in the endpoint buffer and apply back
+                    // pressure until the client gives us a buffer.
+                    self.out_pending.set(packet_bytes as usize);
+                    hil::usb::OutResult::Delay
+                }
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                hil::usb::OutResult::Error
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.send_buffer.take().map(|buf| {
+            self.client.map(move |client| {
+                client.packet_transmitted(ReturnCode::SUCCESS, buf, endpoint);
+            });
+        });
+    }
+}
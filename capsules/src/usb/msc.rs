@@ -0,0 +1,862 @@
+//! Mass Storage Class Device for USB
+//!
+//! This capsule exposes a region of a `hil::nonvolatile_storage` device to a
+//! USB host as a drive, using the Bulk-Only Transport and the SCSI transparent
+//! command set. Any computer can then read or write the region like a USB
+//! stick, for example to pull logs off a board.
+//!
+//! The region is presented as one logical unit of `BLOCK_SIZE`-byte blocks.
+//! Blocks are transferred one at a time through a single kernel buffer, so
+//! throughput is limited by the speed of the storage.
+//!
+//! If the storage fails to start a read or write it keeps the buffer, and the
+//! drive reports that it is not ready from then on.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let msc = static_init!(
+//!     capsules::usb::msc::MassStorage<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::msc::MassStorage::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915, // Nordic Semiconductor
+//!         0x503a, // lowRISC generic FS USB
+//!         strings,
+//!         nonvolatile_storage,
+//!         0x60000, // Start of the region in the storage
+//!         0x20000, // Length of the region
+//!         false,   // Writable
+//!         &mut capsules::usb::msc::BUFFER,
+//!     )
+//! );
+//! nrf52840::usbd::USBD.set_client(msc);
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nonvolatile_storage, msc);
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::TakeCell;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 1;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 2;
+
+const IN_BUFFER: usize = 0;
+const OUT_BUFFER: usize = 1;
+
+const N_ENDPOINTS: usize = 2;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Size of the blocks of the drive.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Block buffer for the mass storage device, assigned in board `main.rs` files.
+pub static mut BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+// Class-specific requests of the Bulk-Only Transport.
+const GET_MAX_LUN: u8 = 0xfe;
+const BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LEN: usize = 13;
+
+// Status of a command status wrapper.
+const STATUS_PASSED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_PHASE_ERROR: u8 = 2;
+
+// SCSI operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+
+/// SCSI sense data: the key, additional sense code and qualifier describing
+/// why the last command failed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Sense(u8, u8, u8);
+
+const NO_SENSE: Sense = Sense(0x00, 0x00, 0x00);
+const NOT_READY: Sense = Sense(0x02, 0x04, 0x00);
+const READ_ERROR: Sense = Sense(0x03, 0x11, 0x00);
+const WRITE_ERROR: Sense = Sense(0x03, 0x0c, 0x00);
+const INVALID_COMMAND: Sense = Sense(0x05, 0x20, 0x00);
+const LBA_OUT_OF_RANGE: Sense = Sense(0x05, 0x21, 0x00);
+const INVALID_FIELD: Sense = Sense(0x05, 0x24, 0x00);
+const WRITE_PROTECTED: Sense = Sense(0x07, 0x27, 0x00);
+
+/// The command block wrapper the host sends to start a command.
+#[derive(Debug, PartialEq)]
+struct CommandBlockWrapper {
+    tag: u32,
+    /// How many bytes the host expects to transfer in the data phase.
+    data_length: u32,
+    /// Whether the data phase goes from the device to the host.
+    data_in: bool,
+    /// The SCSI command block, padded with zeros.
+    command: [u8; 16],
+}
+
+impl CommandBlockWrapper {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() != CBW_LEN || get_u32_le(&packet[0..4]) != CBW_SIGNATURE {
+            return None;
+        }
+        let command_length = packet[14] as usize;
+        if command_length == 0 || command_length > 16 {
+            return None;
+        }
+        let mut command = [0; 16];
+        command[..command_length].copy_from_slice(&packet[15..15 + command_length]);
+        Some(CommandBlockWrapper {
+            tag: get_u32_le(&packet[4..8]),
+            data_length: get_u32_le(&packet[8..12]),
+            data_in: packet[12] & 0x80 != 0,
+            command,
+        })
+    }
+}
+
+fn get_u32_le(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn get_u32_be(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// States of the Bulk-Only Transport.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for a command block wrapper.
+    Command,
+    /// Sending bytes `offset..len` of the buffer to the host.
+    DataIn { offset: usize, len: usize },
+    /// Sending a zero-length packet to end a data phase that was shorter than
+    /// the host expected.
+    ZeroLength,
+    /// Receiving a block from the host, `offset` bytes of it so far.
+    DataOut { offset: usize },
+    /// Throwing away the rest of the data the host sends.
+    Discard,
+    /// The storage is reading or writing a block.
+    Storage,
+    /// Sending the command status wrapper.
+    Status,
+}
+
+/// States of the Control Endpoint related to mass storage.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CtrlState {
+    /// No ongoing ctrl transaction.
+    Idle,
+    /// Host has asked for the number of the last logical unit.
+    GetMaxLun,
+}
+
+/// Implementation of the Bulk-Only Transport of the Mass Storage Class over
+/// USB.
+pub struct MassStorage<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    /// The storage holding the drive, at `start` and `blocks` blocks long.
+    storage: &'a dyn NonvolatileStorage<'static>,
+    start: usize,
+    blocks: u32,
+    read_only: bool,
+
+    /// Holds responses and the block being transferred.
+    buffer: TakeCell<'static, [u8]>,
+
+    state: Cell<State>,
+    ctrl_state: Cell<CtrlState>,
+
+    /// Tag of the current command, to return in its status.
+    tag: Cell<u32>,
+    /// How many bytes the host expects in the data phase of the command.
+    data_length: Cell<u32>,
+    /// Whether the host expects the data phase to go to it.
+    data_in: Cell<bool>,
+    /// How many of the expected bytes have not been transferred yet.
+    residue: Cell<u32>,
+    status: Cell<u8>,
+    /// Sense data of the last failed command, for REQUEST SENSE.
+    sense: Cell<Sense>,
+
+    /// Next block to read or write, and how many are left.
+    lba: Cell<u32>,
+    remaining_blocks: Cell<u32>,
+
+    /// Whether we returned `OutResult::Delay` and the OUT endpoint must be
+    /// resumed before the host can send more.
+    out_paused: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> MassStorage<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'a dyn NonvolatileStorage<'static>,
+        start: usize,
+        length: usize,
+        read_only: bool,
+        buffer: &'static mut [u8; BLOCK_SIZE],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x08,    // Mass storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-Only Transport
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_IN_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_OUT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class: defined by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                None, // No HID descriptor
+                None, // No CDC descriptor
+            );
+
+        MassStorage {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            storage,
+            start,
+            blocks: (length / BLOCK_SIZE) as u32,
+            read_only,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Command),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            tag: Cell::new(0),
+            data_length: Cell::new(0),
+            data_in: Cell::new(false),
+            residue: Cell::new(0),
+            status: Cell::new(STATUS_PASSED),
+            sense: Cell::new(NO_SENSE),
+            lba: Cell::new(0),
+            remaining_blocks: Cell::new(0),
+            out_paused: Cell::new(false),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Lets the host send the next OUT packet, if we held it back.
+    fn receive(&self) {
+        if self.out_paused.replace(false) {
+            self.controller().endpoint_resume_out(ENDPOINT_OUT_NUM);
+        }
+    }
+
+    /// What to tell the controller after handling an OUT packet.
+    fn out_result(&self) -> hil::usb::OutResult {
+        match self.state.get() {
+            State::DataOut { .. } | State::Discard => hil::usb::OutResult::Ok,
+            _ => {
+                // Hold back the next packet until the command is done.
+                self.out_paused.set(true);
+                hil::usb::OutResult::Delay
+            }
+        }
+    }
+
+    fn fail(&self, sense: Sense) {
+        self.sense.set(sense);
+        self.status.set(STATUS_FAILED);
+        self.remaining_blocks.set(0);
+    }
+
+    /// Handles the SCSI command in `cbw`.
+    fn command(&self, cbw: &CommandBlockWrapper) {
+        self.tag.set(cbw.tag);
+        self.data_length.set(cbw.data_length);
+        self.data_in.set(cbw.data_in);
+        self.residue.set(cbw.data_length);
+        self.status.set(STATUS_PASSED);
+
+        let cb = &cbw.command;
+        match cb[0] {
+            TEST_UNIT_READY
+            | START_STOP_UNIT
+            | PREVENT_ALLOW_MEDIUM_REMOVAL
+            | VERIFY_10
+            | SYNCHRONIZE_CACHE_10 => {
+                if self.buffer.is_none() {
+                    self.fail(NOT_READY);
+                }
+                self.finish_data();
+            }
+            REQUEST_SENSE => {
+                let Sense(key, asc, ascq) = self.sense.replace(NO_SENSE);
+                self.respond(cb[4] as usize, |response| {
+                    response[..18].copy_from_slice(&[
+                        0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, ascq, 0, 0, 0, 0,
+                    ]);
+                    18
+                });
+            }
+            INQUIRY => {
+                if cb[1] & 0x01 != 0 {
+                    // No vital product data pages.
+                    self.fail(INVALID_FIELD);
+                    self.finish_data();
+                    return;
+                }
+                let allocation_length = u16::from_be_bytes([cb[3], cb[4]]) as usize;
+                self.respond(allocation_length, |response| {
+                    response[..8].copy_from_slice(&[
+                        0x00, // Direct access block device
+                        0x80, // Removable
+                        0x04, // SPC-2
+                        0x02, // Response data format
+                        31,   // Additional length
+                        0, 0, 0,
+                    ]);
+                    response[8..16].copy_from_slice(b"Tock    ");
+                    response[16..32].copy_from_slice(b"Mass Storage    ");
+                    response[32..36].copy_from_slice(b"1.0 ");
+                    36
+                });
+            }
+            MODE_SENSE_6 => {
+                let write_protect = if self.read_only { 0x80 } else { 0 };
+                self.respond(cb[4] as usize, |response| {
+                    response[..4].copy_from_slice(&[3, 0, write_protect, 0]);
+                    4
+                });
+            }
+            MODE_SENSE_10 => {
+                let write_protect = if self.read_only { 0x80 } else { 0 };
+                let allocation_length = u16::from_be_bytes([cb[7], cb[8]]) as usize;
+                self.respond(allocation_length, |response| {
+                    response[..8].copy_from_slice(&[0, 6, 0, write_protect, 0, 0, 0, 0]);
+                    8
+                });
+            }
+            READ_FORMAT_CAPACITIES => {
+                let allocation_length = u16::from_be_bytes([cb[7], cb[8]]) as usize;
+                let blocks = self.blocks;
+                self.respond(allocation_length, |response| {
+                    response[..4].copy_from_slice(&[0, 0, 0, 8]);
+                    response[4..8].copy_from_slice(&blocks.to_be_bytes());
+                    // Formatted media, then the block length in 3 bytes.
+                    response[8..12]
+                        .copy_from_slice(&(0x0200_0000 | BLOCK_SIZE as u32).to_be_bytes());
+                    12
+                });
+            }
+            READ_CAPACITY_10 => {
+                let last_lba = self.blocks.saturating_sub(1);
+                self.respond(8, |response| {
+                    response[..4].copy_from_slice(&last_lba.to_be_bytes());
+                    response[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                    8
+                });
+            }
+            READ_10 | WRITE_10 => {
+                let lba = get_u32_be(&cb[2..6]);
+                let blocks = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+                self.transfer_blocks(cb[0] == READ_10, lba, blocks);
+            }
+            _ => {
+                self.fail(INVALID_COMMAND);
+                self.finish_data();
+            }
+        }
+    }
+
+    /// Sends a response of at most `allocation_length` bytes, which `fill`
+    /// writes into the buffer and returns the length of.
+    fn respond<F: FnOnce(&mut [u8]) -> usize>(&self, allocation_length: usize, fill: F) {
+        if !self.data_in.get() && self.data_length.get() > 0 {
+            self.status.set(STATUS_PHASE_ERROR);
+            self.finish_data();
+            return;
+        }
+        let len = self
+            .buffer
+            .map(|buffer| cmp::min(fill(buffer), allocation_length))
+            .unwrap_or_else(|| {
+                self.fail(NOT_READY);
+                0
+            });
+        self.send_data(len);
+    }
+
+    /// Starts sending the first `len` bytes of the buffer.
+    fn send_data(&self, len: usize) {
+        let len = cmp::min(len, self.residue.get() as usize);
+        if len == 0 {
+            self.finish_data();
+        } else {
+            self.state.set(State::DataIn { offset: 0, len });
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+
+    fn transfer_blocks(&self, read: bool, lba: u32, blocks: u32) {
+        let bytes = blocks as usize * BLOCK_SIZE;
+        if blocks > 0 && (read != self.data_in.get() || (self.data_length.get() as usize) < bytes) {
+            self.status.set(STATUS_PHASE_ERROR);
+        } else if lba
+            .checked_add(blocks)
+            .map_or(true, |end| end > self.blocks)
+        {
+            self.fail(LBA_OUT_OF_RANGE);
+        } else if !read && self.read_only {
+            self.fail(WRITE_PROTECTED);
+        } else if blocks > 0 {
+            self.lba.set(lba);
+            self.remaining_blocks.set(blocks);
+            if read {
+                self.read_block();
+            } else {
+                self.state.set(State::DataOut { offset: 0 });
+            }
+            return;
+        }
+        self.finish_data();
+    }
+
+    fn block_address(&self) -> usize {
+        self.start + self.lba.get() as usize * BLOCK_SIZE
+    }
+
+    fn read_block(&self) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                self.state.set(State::Storage);
+                let rc = self.storage.read(buffer, self.block_address(), BLOCK_SIZE);
+                if rc != ReturnCode::SUCCESS {
+                    self.fail(NOT_READY);
+                    self.finish_data();
+                }
+            }
+            None => {
+                self.fail(NOT_READY);
+                self.finish_data();
+            }
+        }
+    }
+
+    fn write_block(&self) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                self.state.set(State::Storage);
+                let rc = self.storage.write(buffer, self.block_address(), BLOCK_SIZE);
+                if rc != ReturnCode::SUCCESS {
+                    self.fail(NOT_READY);
+                    self.finish_data();
+                }
+            }
+            None => {
+                self.fail(NOT_READY);
+                self.finish_data();
+            }
+        }
+    }
+
+    /// Ends the data phase, dealing with any data the host expects that the
+    /// command did not transfer, and then sends the status.
+    fn finish_data(&self) {
+        let residue = self.residue.get();
+        if residue == 0 {
+            self.send_status();
+        } else if self.data_in.get() {
+            if (self.data_length.get() - residue) as usize % self.buffers[IN_BUFFER].buf.len() == 0
+            {
+                // The last packet was full, so the host waits for more.
+                self.state.set(State::ZeroLength);
+                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+            } else {
+                self.send_status();
+            }
+        } else {
+            self.state.set(State::Discard);
+            self.receive();
+        }
+    }
+
+    fn send_status(&self) {
+        self.state.set(State::Status);
+        self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+    }
+
+    /// Aborts the current command and waits for the next one.
+    fn reset(&self) {
+        self.state.set(State::Command);
+        self.remaining_blocks.set(0);
+        self.receive();
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for MassStorage<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.state.set(State::Command);
+        self.remaining_blocks.set(0);
+        self.out_paused.set(false);
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// The Bulk-Only Transport adds two class requests to the interface, one
+    /// for the number of logical units and one to abort the current command.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let request = descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf)
+            .filter(|setup_data| {
+                matches!(setup_data.request_type.request_type(), RequestType::Class)
+                    && matches!(setup_data.request_type.recipient(), Recipient::Interface)
+            })
+            .map(|setup_data| setup_data.request_code);
+
+        match request {
+            Some(GET_MAX_LUN) => {
+                self.ctrl_state.set(CtrlState::GetMaxLun);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            Some(BULK_ONLY_RESET) => {
+                self.reset();
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        if self.ctrl_state.replace(CtrlState::Idle) == CtrlState::GetMaxLun {
+            // We only have logical unit 0.
+            self.client_ctrl.ctrl_buffer.buf[0].set(0);
+            hil::usb::CtrlInResult::Packet(1, true)
+        } else {
+            self.client_ctrl.ctrl_in(endpoint)
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk IN transaction.
+    ///
+    /// This sends the next packet of the response or block, the zero-length
+    /// packet or the command status, depending on the state.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                let packet = &self.buffers[IN_BUFFER].buf;
+                match self.state.get() {
+                    State::DataIn { offset, len } if offset < len => {
+                        self.buffer.map_or(hil::usb::InResult::Delay, |buffer| {
+                            let to_send = cmp::min(packet.len(), len - offset);
+                            for i in 0..to_send {
+                                packet[i].set(buffer[offset + i]);
+                            }
+                            self.residue.set(self.residue.get() - to_send as u32);
+                            self.state.set(State::DataIn {
+                                offset: offset + to_send,
+                                len,
+                            });
+                            hil::usb::InResult::Packet(to_send)
+                        })
+                    }
+                    State::ZeroLength => hil::usb::InResult::Packet(0),
+                    State::Status => {
+                        let mut csw = [0; CSW_LEN];
+                        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+                        csw[4..8].copy_from_slice(&self.tag.get().to_le_bytes());
+                        csw[8..12].copy_from_slice(&self.residue.get().to_le_bytes());
+                        csw[12] = self.status.get();
+                        for (i, byte) in csw.iter().enumerate() {
+                            packet[i].set(*byte);
+                        }
+                        hil::usb::InResult::Packet(CSW_LEN)
+                    }
+                    _ => hil::usb::InResult::Delay,
+                }
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                // Nothing to do for mass storage.
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle a Bulk OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                let packet = &self.buffers[OUT_BUFFER].buf;
+                let packet_bytes = cmp::min(packet_bytes as usize, packet.len());
+                match self.state.get() {
+                    State::Command => {
+                        let mut cbw = [0; CBW_LEN];
+                        if packet_bytes != CBW_LEN {
+                            return hil::usb::OutResult::Error;
+                        }
+                        for (i, byte) in cbw.iter_mut().enumerate() {
+                            *byte = packet[i].get();
+                        }
+                        match CommandBlockWrapper::parse(&cbw) {
+                            Some(cbw) => self.command(&cbw),
+                            None => return hil::usb::OutResult::Error,
+                        }
+                    }
+                    State::DataOut { offset } => {
+                        let len = cmp::min(packet_bytes, BLOCK_SIZE - offset);
+                        self.buffer.map(|buffer| {
+                            for i in 0..len {
+                                buffer[offset + i] = packet[i].get();
+                            }
+                        });
+                        self.residue
+                            .set(self.residue.get().saturating_sub(packet_bytes as u32));
+                        if offset + len == BLOCK_SIZE {
+                            self.write_block();
+                        } else {
+                            self.state.set(State::DataOut {
+                                offset: offset + len,
+                            });
+                        }
+                    }
+                    State::Discard => {
+                        let residue = self.residue.get().saturating_sub(packet_bytes as u32);
+                        self.residue.set(residue);
+                        if residue == 0 {
+                            self.send_status();
+                        }
+                    }
+                    State::DataIn { .. } | State::ZeroLength | State::Storage | State::Status => {
+                        // The host is not supposed to send anything now.
+                        return hil::usb::OutResult::Error;
+                    }
+                }
+                self.out_result()
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                // Nothing to do for mass storage.
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        match self.state.get() {
+            State::DataIn { offset, len } if offset < len => {
+                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+            }
+            State::DataIn { .. } => {
+                if self.remaining_blocks.get() > 0 {
+                    self.read_block();
+                } else {
+                    self.finish_data();
+                }
+            }
+            State::ZeroLength => self.send_status(),
+            State::Status => {
+                self.state.set(State::Command);
+                self.receive();
+            }
+            State::Command | State::DataOut { .. } | State::Discard | State::Storage => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> NonvolatileStorageClient<'static> for MassStorage<'a, U> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        if self.state.get() != State::Storage {
+            // The command was aborted.
+            return;
+        }
+        if length < BLOCK_SIZE {
+            self.fail(READ_ERROR);
+            self.finish_data();
+        } else {
+            self.lba.set(self.lba.get() + 1);
+            self.remaining_blocks.set(self.remaining_blocks.get() - 1);
+            self.send_data(BLOCK_SIZE);
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        if self.state.get() != State::Storage {
+            // The command was aborted.
+            return;
+        }
+        if length < BLOCK_SIZE {
+            self.fail(WRITE_ERROR);
+            self.finish_data();
+        } else {
+            self.lba.set(self.lba.get() + 1);
+            self.remaining_blocks.set(self.remaining_blocks.get() - 1);
+            if self.remaining_blocks.get() > 0 {
+                self.state.set(State::DataOut { offset: 0 });
+                self.receive();
+            } else {
+                self.finish_data();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_block_wrapper() {
+        let mut packet = [0; CBW_LEN];
+        packet[0..4].copy_from_slice(b"USBC");
+        packet[4..8].copy_from_slice(&0x1234u32.to_le_bytes());
+        packet[8..12].copy_from_slice(&1024u32.to_le_bytes());
+        packet[12] = 0x80;
+        packet[14] = 10;
+        packet[15] = READ_10;
+        packet[24] = 0xff;
+        packet[25] = 0xff; // Beyond the command length.
+
+        let cbw = CommandBlockWrapper::parse(&packet).unwrap();
+        assert_eq!(cbw.tag, 0x1234);
+        assert_eq!(cbw.data_length, 1024);
+        assert!(cbw.data_in);
+        assert_eq!(cbw.command[0], READ_10);
+        assert_eq!(cbw.command[9], 0xff);
+        assert_eq!(cbw.command[10], 0);
+
+        packet[14] = 17;
+        assert_eq!(CommandBlockWrapper::parse(&packet), None);
+        packet[14] = 10;
+        packet[0] = b'X';
+        assert_eq!(CommandBlockWrapper::parse(&packet), None);
+        assert_eq!(CommandBlockWrapper::parse(&packet[..30]), None);
+    }
+}
@@ -0,0 +1,537 @@
+//! A USB device controller implemented in software, driven by a simulated host.
+//!
+//! `SoftwareUsbController` implements `kernel::hil::usb::UsbController`
+//! without any hardware. Instead of a bus, it offers methods that play the
+//! role of the host: resetting the bus, issuing control transfers on the
+//! default endpoint and moving packets on bulk and interrupt endpoints. This
+//! allows the USB classes in this crate to be enumerated and exercised in
+//! host tests.
+//!
+//! The controller calls its client the way the nRF52 USBD driver does:
+//!
+//! - A control transfer starts with `ctrl_setup`, with the SETUP packet in the
+//!   control endpoint buffer. An IN data stage then calls `ctrl_in` until the
+//!   client marks a packet as the last one or the host has read `wLength`
+//!   bytes. An OUT data stage copies each packet into the control endpoint
+//!   buffer and calls `ctrl_out`. Finally the status stage calls `ctrl_status`
+//!   and `ctrl_status_complete`. Any error result from the client stalls the
+//!   transfer.
+//! - `set_address` only takes effect once `enable_address` is called, which
+//!   `ClientCtrl` does in the status stage of SET_ADDRESS.
+//! - `endpoint_resume_in` calls `packet_in` right away if the endpoint is not
+//!   holding a packet. Otherwise the resume is remembered and `packet_in` is
+//!   called after the host has read the packet and `packet_transmitted` has
+//!   been called.
+//! - `packet_out` returning `OutResult::Delay` NAKs the packet, as the HIL
+//!   describes, and pauses the endpoint until `endpoint_resume_out` is called.
+//!   The host has to send the packet again after that. Some classes copy the
+//!   packet before returning `Delay`, only to hold back the next one; hosts
+//!   of those check `out_paused` before sending instead of resending.
+//!
+//! Everything happens synchronously inside the host-side calls, so no alarm
+//! or deferred call is needed to drive the controller.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let usb = static_init!(SoftwareUsbController<'static>, SoftwareUsbController::new());
+//! let client = static_init!(Client<'static, SoftwareUsbController<'static>>, Client::new(usb, 64));
+//! usb.set_client(client);
+//! client.enable();
+//! client.attach();
+//!
+//! usb.reset();
+//! usb.set_address(5)?;
+//! let mut device_descriptor = [0; 18];
+//! usb.get_descriptor(DescriptorType::Device, 0, 0, &mut device_descriptor)?;
+//! usb.set_configuration(1)?;
+//! ```
+
+use super::descriptors::DescriptorType;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, VolatileCell};
+use kernel::hil;
+use kernel::hil::usb::{
+    CtrlInResult, CtrlOutResult, CtrlSetupResult, DeviceSpeed, InResult, OutResult, TransferType,
+    UsbController,
+};
+
+/// Number of endpoints, including the default control endpoint.
+pub const NUM_ENDPOINTS: usize = 8;
+
+/// bmRequestType of a standard request to the device, host to device.
+pub const REQUEST_TYPE_STANDARD_OUT: u8 = 0x00;
+/// bmRequestType of a standard request to the device, device to host.
+pub const REQUEST_TYPE_STANDARD_IN: u8 = 0x80;
+/// bmRequestType of a class request to an interface, host to device.
+pub const REQUEST_TYPE_CLASS_INTERFACE_OUT: u8 = 0x21;
+/// bmRequestType of a class request to an interface, device to host.
+pub const REQUEST_TYPE_CLASS_INTERFACE_IN: u8 = 0xa1;
+
+const GET_DESCRIPTOR: u8 = 6;
+const SET_ADDRESS: u8 = 5;
+const SET_CONFIGURATION: u8 = 9;
+
+/// Why a transfer issued by the host did not complete.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    /// The device is not attached to the bus.
+    Detached,
+    /// The endpoint has not been enabled, or has no buffer.
+    Disabled,
+    /// The device answered with NAK: it is not ready to send or accept data.
+    Nak,
+    /// The device answered with STALL.
+    Stall,
+}
+
+/// The SETUP packet of a control transfer.
+#[derive(Copy, Clone, Debug)]
+pub struct Setup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl Setup {
+    fn write_to(&self, buf: &[VolatileCell<u8>]) {
+        let bytes = [
+            self.request_type,
+            self.request,
+            self.value as u8,
+            (self.value >> 8) as u8,
+            self.index as u8,
+            (self.index >> 8) as u8,
+            self.length as u8,
+            (self.length >> 8) as u8,
+        ];
+        for (cell, byte) in buf.iter().zip(bytes.iter()) {
+            cell.set(*byte);
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum InState {
+    /// No packet is waiting for the host.
+    Idle,
+    /// A packet of the given length is waiting for the host.
+    Ready(usize),
+    Stalled,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum OutState {
+    /// The endpoint accepts packets.
+    Idle,
+    /// The client asked to pause the endpoint until it is resumed.
+    Delayed,
+    Stalled,
+}
+
+struct Endpoint<'a> {
+    in_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    out_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    in_type: OptionalCell<TransferType>,
+    out_type: OptionalCell<TransferType>,
+    in_state: Cell<InState>,
+    out_state: Cell<OutState>,
+    /// `endpoint_resume_in` was called while a packet was waiting.
+    resume_in_pending: Cell<bool>,
+}
+
+impl Endpoint<'_> {
+    const fn new() -> Self {
+        Endpoint {
+            in_buffer: OptionalCell::empty(),
+            out_buffer: OptionalCell::empty(),
+            in_type: OptionalCell::empty(),
+            out_type: OptionalCell::empty(),
+            in_state: Cell::new(InState::Idle),
+            out_state: Cell::new(OutState::Idle),
+            resume_in_pending: Cell::new(false),
+        }
+    }
+}
+
+pub struct SoftwareUsbController<'a> {
+    client: OptionalCell<&'a dyn hil::usb::Client<'a>>,
+    ctrl_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    endpoints: [Endpoint<'a>; NUM_ENDPOINTS],
+    speed: OptionalCell<DeviceSpeed>,
+    attached: Cell<bool>,
+    pending_address: Cell<u16>,
+    address: Cell<u16>,
+}
+
+impl<'a> SoftwareUsbController<'a> {
+    pub const fn new() -> SoftwareUsbController<'a> {
+        SoftwareUsbController {
+            client: OptionalCell::empty(),
+            ctrl_buffer: OptionalCell::empty(),
+            endpoints: [
+                Endpoint::new(),
+                Endpoint::new(),
+                Endpoint::new(),
+                Endpoint::new(),
+                Endpoint::new(),
+                Endpoint::new(),
+                Endpoint::new(),
+                Endpoint::new(),
+            ],
+            speed: OptionalCell::empty(),
+            attached: Cell::new(false),
+            pending_address: Cell::new(0),
+            address: Cell::new(0),
+        }
+    }
+
+    /// Whether the device has been enabled and attached to the bus.
+    pub fn is_attached(&self) -> bool {
+        self.attached.get()
+    }
+
+    /// The speed the device was enabled with.
+    pub fn speed(&self) -> Option<DeviceSpeed> {
+        self.speed.map(|speed| *speed)
+    }
+
+    /// The address the device responds to. This is 0 until SET_ADDRESS has
+    /// completed.
+    pub fn address(&self) -> u16 {
+        self.address.get()
+    }
+
+    /// Resets the bus, which returns the device to the default address and
+    /// clears all endpoint state.
+    pub fn reset(&self) {
+        self.address.set(0);
+        self.pending_address.set(0);
+        for endpoint in self.endpoints.iter() {
+            endpoint.in_state.set(InState::Idle);
+            endpoint.out_state.set(OutState::Idle);
+            endpoint.resume_in_pending.set(false);
+        }
+        self.client.map(|client| client.bus_reset());
+    }
+
+    /// Performs a control transfer with an IN data stage (or none, if
+    /// `setup.length` is 0) and returns the number of bytes read into `data`.
+    /// At most `setup.length` bytes are read.
+    pub fn control_read(&self, setup: Setup, data: &mut [u8]) -> Result<usize, TransferError> {
+        let ctrl_buffer = self.start_control(setup)?;
+        let client = self.client()?;
+
+        let length = cmp::min(setup.length as usize, data.len());
+        let mut received = 0;
+        while received < length {
+            match client.ctrl_in(0) {
+                CtrlInResult::Packet(size, last) => {
+                    let size = cmp::min(size, ctrl_buffer.len());
+                    let copy = cmp::min(size, length - received);
+                    for (byte, cell) in data[received..received + copy]
+                        .iter_mut()
+                        .zip(ctrl_buffer.iter())
+                    {
+                        *byte = cell.get();
+                    }
+                    received += copy;
+                    // A short packet also ends the data stage.
+                    if last || size < ctrl_buffer.len() {
+                        break;
+                    }
+                }
+                CtrlInResult::Delay => return Err(TransferError::Nak),
+                CtrlInResult::Error => return Err(TransferError::Stall),
+            }
+        }
+
+        self.complete_control(client);
+        Ok(received)
+    }
+
+    /// Performs a control transfer with an OUT data stage carrying `data` (or
+    /// none, if `data` is empty). `setup.length` should equal `data.len()`.
+    pub fn control_write(&self, setup: Setup, data: &[u8]) -> Result<(), TransferError> {
+        let ctrl_buffer = self.start_control(setup)?;
+        let client = self.client()?;
+
+        for packet in data.chunks(ctrl_buffer.len()) {
+            for (cell, byte) in ctrl_buffer.iter().zip(packet.iter()) {
+                cell.set(*byte);
+            }
+            match client.ctrl_out(0, packet.len() as u32) {
+                CtrlOutResult::Ok => {}
+                CtrlOutResult::Delay => return Err(TransferError::Nak),
+                CtrlOutResult::Halted => return Err(TransferError::Stall),
+            }
+        }
+
+        self.complete_control(client);
+        Ok(())
+    }
+
+    /// Sends the SETUP packet and checks the client's response.
+    fn start_control(&self, setup: Setup) -> Result<&'a [VolatileCell<u8>], TransferError> {
+        if !self.attached.get() {
+            return Err(TransferError::Detached);
+        }
+        let ctrl_buffer = self
+            .ctrl_buffer
+            .map_or(Err(TransferError::Disabled), |buf| Ok(*buf))?;
+        let client = self.client()?;
+
+        setup.write_to(ctrl_buffer);
+        match client.ctrl_setup(0) {
+            CtrlSetupResult::Ok | CtrlSetupResult::OkSetAddress => Ok(ctrl_buffer),
+            _ => Err(TransferError::Stall),
+        }
+    }
+
+    fn complete_control(&self, client: &'a dyn hil::usb::Client<'a>) {
+        client.ctrl_status(0);
+        client.ctrl_status_complete(0);
+    }
+
+    /// Reads the packet waiting on IN endpoint `endpoint` into `data`,
+    /// returning its length. Bytes that do not fit in `data` are lost.
+    pub fn in_packet(&self, endpoint: usize, data: &mut [u8]) -> Result<usize, TransferError> {
+        let ep = self.enabled_endpoint(endpoint)?;
+        let buffer = ep
+            .in_buffer
+            .map_or(Err(TransferError::Disabled), |buf| Ok(*buf))?;
+        if ep.in_type.is_none() {
+            return Err(TransferError::Disabled);
+        }
+
+        match ep.in_state.get() {
+            InState::Idle => Err(TransferError::Nak),
+            InState::Stalled => Err(TransferError::Stall),
+            InState::Ready(size) => {
+                let size = cmp::min(size, buffer.len());
+                for (byte, cell) in data.iter_mut().zip(buffer[..size].iter()) {
+                    *byte = cell.get();
+                }
+                ep.in_state.set(InState::Idle);
+                self.client
+                    .map(|client| client.packet_transmitted(endpoint));
+                if ep.resume_in_pending.replace(false) {
+                    self.endpoint_resume_in(endpoint);
+                }
+                Ok(size)
+            }
+        }
+    }
+
+    /// Writes `data` as one packet to OUT endpoint `endpoint`. If the device
+    /// answers with NAK, the packet has not been accepted and has to be sent
+    /// again.
+    pub fn out_packet(&self, endpoint: usize, data: &[u8]) -> Result<(), TransferError> {
+        let ep = self.enabled_endpoint(endpoint)?;
+        let buffer = ep
+            .out_buffer
+            .map_or(Err(TransferError::Disabled), |buf| Ok(*buf))?;
+        let transfer_type = ep
+            .out_type
+            .map_or(Err(TransferError::Disabled), |ty| Ok(*ty))?;
+        let client = self.client()?;
+
+        match ep.out_state.get() {
+            OutState::Delayed => Err(TransferError::Nak),
+            OutState::Stalled => Err(TransferError::Stall),
+            OutState::Idle => {
+                let size = cmp::min(data.len(), buffer.len());
+                for (cell, byte) in buffer.iter().zip(data[..size].iter()) {
+                    cell.set(*byte);
+                }
+                match client.packet_out(transfer_type, endpoint, size as u32) {
+                    OutResult::Ok => Ok(()),
+                    OutResult::Delay => {
+                        ep.out_state.set(OutState::Delayed);
+                        Err(TransferError::Nak)
+                    }
+                    OutResult::Error => {
+                        ep.out_state.set(OutState::Stalled);
+                        Err(TransferError::Stall)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether a packet is waiting to be read from IN endpoint `endpoint`.
+    pub fn in_packet_ready(&self, endpoint: usize) -> bool {
+        self.endpoints
+            .get(endpoint)
+            .map_or(false, |ep| matches!(ep.in_state.get(), InState::Ready(_)))
+    }
+
+    /// Whether OUT endpoint `endpoint` is paused until the client resumes it.
+    pub fn out_paused(&self, endpoint: usize) -> bool {
+        self.endpoints
+            .get(endpoint)
+            .map_or(false, |ep| ep.out_state.get() == OutState::Delayed)
+    }
+
+    /// Clears a halted endpoint, as CLEAR_FEATURE(ENDPOINT_HALT) would.
+    pub fn clear_halt(&self, endpoint: usize) {
+        if let Some(ep) = self.endpoints.get(endpoint) {
+            if ep.in_state.get() == InState::Stalled {
+                ep.in_state.set(InState::Idle);
+            }
+            if ep.out_state.get() == OutState::Stalled {
+                ep.out_state.set(OutState::Idle);
+            }
+        }
+    }
+
+    fn client(&self) -> Result<&'a dyn hil::usb::Client<'a>, TransferError> {
+        self.client
+            .map_or(Err(TransferError::Disabled), |client| Ok(*client))
+    }
+
+    fn enabled_endpoint(&self, endpoint: usize) -> Result<&Endpoint<'a>, TransferError> {
+        if !self.attached.get() {
+            return Err(TransferError::Detached);
+        }
+        match self.endpoints.get(endpoint) {
+            Some(ep) if endpoint != 0 => Ok(ep),
+            _ => Err(TransferError::Disabled),
+        }
+    }
+
+    /// Issues GET_DESCRIPTOR to the device and returns the number of bytes
+    /// read into `data`.
+    pub fn get_descriptor(
+        &self,
+        descriptor_type: DescriptorType,
+        index: u8,
+        lang_id: u16,
+        data: &mut [u8],
+    ) -> Result<usize, TransferError> {
+        self.control_read(
+            Setup {
+                request_type: REQUEST_TYPE_STANDARD_IN,
+                request: GET_DESCRIPTOR,
+                value: (descriptor_type as u16) << 8 | index as u16,
+                index: lang_id,
+                length: data.len() as u16,
+            },
+            data,
+        )
+    }
+
+    /// Issues SET_ADDRESS to the device.
+    pub fn set_address(&self, address: u16) -> Result<(), TransferError> {
+        self.control_write(
+            Setup {
+                request_type: REQUEST_TYPE_STANDARD_OUT,
+                request: SET_ADDRESS,
+                value: address,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        )
+    }
+
+    /// Issues SET_CONFIGURATION to the device.
+    pub fn set_configuration(&self, configuration: u8) -> Result<(), TransferError> {
+        self.control_write(
+            Setup {
+                request_type: REQUEST_TYPE_STANDARD_OUT,
+                request: SET_CONFIGURATION,
+                value: configuration as u16,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        )
+    }
+}
+
+impl<'a> hil::usb::UsbController<'a> for SoftwareUsbController<'a> {
+    fn set_client(&self, client: &'a dyn hil::usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, buf: &'a [VolatileCell<u8>]) {
+        if buf.len() < 8 {
+            panic!("Endpoint buffer must be at least 8 bytes");
+        }
+        self.ctrl_buffer.set(buf);
+    }
+
+    fn endpoint_set_in_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.endpoints[endpoint].in_buffer.set(buf);
+    }
+
+    fn endpoint_set_out_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.endpoints[endpoint].out_buffer.set(buf);
+    }
+
+    fn enable_as_device(&self, speed: DeviceSpeed) {
+        self.speed.set(speed);
+    }
+
+    fn attach(&self) {
+        self.attached.set(self.speed.is_some());
+    }
+
+    fn detach(&self) {
+        self.attached.set(false);
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.pending_address.set(addr);
+    }
+
+    fn enable_address(&self) {
+        self.address.set(self.pending_address.get());
+    }
+
+    fn endpoint_in_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoints[endpoint].in_type.set(transfer_type);
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoints[endpoint].out_type.set(transfer_type);
+    }
+
+    fn endpoint_in_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoint_in_enable(transfer_type, endpoint);
+        self.endpoint_out_enable(transfer_type, endpoint);
+    }
+
+    fn endpoint_resume_in(&self, endpoint: usize) {
+        let ep = &self.endpoints[endpoint];
+        if ep.in_state.get() != InState::Idle {
+            ep.resume_in_pending.set(true);
+            return;
+        }
+        let transfer_type = match ep.in_type.map(|ty| *ty) {
+            Some(ty) => ty,
+            None => return,
+        };
+        self.client.map(|client| {
+            match client.packet_in(transfer_type, endpoint) {
+                InResult::Packet(size) => ep.in_state.set(InState::Ready(size)),
+                InResult::Delay => {}
+                InResult::Error => ep.in_state.set(InState::Stalled),
+            };
+        });
+    }
+
+    fn endpoint_resume_out(&self, endpoint: usize) {
+        let ep = &self.endpoints[endpoint];
+        if ep.out_state.get() == OutState::Delayed {
+            ep.out_state.set(OutState::Idle);
+        }
+    }
+}
@@ -165,6 +165,13 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
         self.controller
     }
 
+    /// The configuration descriptor and the descriptors following it, for
+    /// classes that change them while running.
+    #[inline]
+    pub fn other_descriptor_buffer(&self) -> &DescriptorBuffer {
+        &self.other_descriptor_buffer
+    }
+
     #[inline]
     fn descriptor_buf(&'a self) -> &'a [Cell<u8>] {
         &self.descriptor_storage
@@ -0,0 +1,266 @@
+//! Provides userspace with access to a USB HID device, such as a keyboard, a
+//! mouse or a custom vendor device.
+//!
+//! The process sends input reports to the host and receives the output reports
+//! the host sends, e.g. the LED state of a keyboard. The format of the reports
+//! is given by the report descriptor the board configured the device with. The
+//! first process to use the driver owns it until it exits.
+//!
+//! Setup
+//! -----
+//!
+//! You need a device that provides the `hil::usb_hid::UsbHid` trait, like
+//! `capsules::usb::hid::HidDevice`. See the `usb_hid` component.
+//!
+//! Syscall interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! * 0: The input report to send.
+//! * 1: Buffer for received output reports.
+//!
+//! ### Subscribe
+//!
+//! * 0: Callback `fn(event, len, 0)`. Event 0 means the input report was
+//!      sent, event 1 means an output report of `len` bytes was written to
+//!      buffer 1.
+//!
+//! ### Command
+//!
+//! * 0: Driver check.
+//! * 1: Send the input report in buffer 0. Returns EBUSY if the previous one
+//!      was not sent yet.
+//! * 2: Start receiving output reports.
+//! * 3: Stop receiving output reports.
+
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::usb_hid;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::UsbHid as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    input_report: Option<AppSlice<Shared, u8>>,
+    output_report: Option<AppSlice<Shared, u8>>,
+    receiving: bool,
+}
+
+pub struct UsbHidDriver<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> {
+    usb: &'a U,
+
+    apps: Grant<App>,
+    /// The process using the device.
+    owner: OptionalCell<AppId>,
+
+    send_buffer: TakeCell<'static, [u8; 64]>,
+    recv_buffer: TakeCell<'static, [u8; 64]>,
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> UsbHidDriver<'a, U> {
+    pub fn new(
+        usb: &'a U,
+        send_buffer: &'static mut [u8; 64],
+        recv_buffer: &'static mut [u8; 64],
+        grant: Grant<App>,
+    ) -> UsbHidDriver<'a, U> {
+        UsbHidDriver {
+            usb: usb,
+            apps: grant,
+            owner: OptionalCell::empty(),
+            send_buffer: TakeCell::new(send_buffer),
+            recv_buffer: TakeCell::new(recv_buffer),
+        }
+    }
+
+    /// Makes `appid` the owner of the device, unless another process that is
+    /// still alive owns it.
+    fn claim(&self, appid: AppId) -> bool {
+        let owned_by_other = self.owner.map_or(false, |owner| {
+            *owner != appid && self.apps.enter(*owner, |_, _| ()).is_ok()
+        });
+        if !owned_by_other {
+            self.owner.set(appid);
+        }
+        !owned_by_other
+    }
+
+    fn send(&self, app: &App) -> ReturnCode {
+        let report = match app.input_report.as_ref() {
+            Some(report) => report,
+            None => return ReturnCode::ERESERVE,
+        };
+        self.send_buffer.take().map_or(ReturnCode::EBUSY, |buf| {
+            let len = cmp::min(report.len(), buf.len());
+            buf[..len].copy_from_slice(&report.as_ref()[..len]);
+            for byte in buf[len..].iter_mut() {
+                *byte = 0;
+            }
+            match self.usb.send_buffer(buf) {
+                Ok(_) => ReturnCode::SUCCESS,
+                Err((err, buf)) => {
+                    self.send_buffer.replace(buf);
+                    err
+                }
+            }
+        })
+    }
+
+    fn start_receiving(&self) -> ReturnCode {
+        self.recv_buffer.take().map_or(ReturnCode::SUCCESS, |buf| {
+            match self.usb.receive_buffer(buf) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((err, buf)) => {
+                    self.recv_buffer.replace(buf);
+                    err
+                }
+            }
+        })
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> usb_hid::Client<'a, [u8; 64]> for UsbHidDriver<'a, U> {
+    fn packet_received(
+        &'a self,
+        _result: ReturnCode,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        let receiving = self.owner.map_or(false, |owner| {
+            self.apps
+                .enter(*owner, |app, _| {
+                    let len = app.output_report.as_mut().map_or(0, |report| {
+                        let len = cmp::min(report.len(), buffer.len());
+                        report.as_mut()[..len].copy_from_slice(&buffer[..len]);
+                        len
+                    });
+                    app.callback.map(|mut cb| cb.schedule(1, len, 0));
+                    app.receiving
+                })
+                .unwrap_or(false)
+        });
+
+        self.recv_buffer.replace(buffer);
+        if receiving {
+            self.start_receiving();
+        }
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        _result: ReturnCode,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        self.send_buffer.replace(buffer);
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                app.callback.map(|mut cb| cb.schedule(0, 0, 0));
+            });
+        });
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.owner.map_or(false, |owner| {
+            self.apps
+                .enter(*owner, |app, _| app.receiving)
+                .unwrap_or(false)
+        })
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> Driver for UsbHidDriver<'a, U> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.input_report = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.output_report = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        _data1: usize,
+        _data2: usize,
+        appid: AppId,
+    ) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 3 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if !self.claim(appid) {
+            return ReturnCode::EBUSY;
+        }
+
+        let result = self
+            .apps
+            .enter(appid, |app, _| match command_num {
+                1 => self.send(app),
+                2 => {
+                    app.receiving = true;
+                    ReturnCode::SUCCESS
+                }
+                _ => {
+                    app.receiving = false;
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+
+        // The device may ask whether we can receive, so this happens outside
+        // of the grant.
+        match (result, command_num) {
+            (ReturnCode::SUCCESS, 2) => self.start_receiving(),
+            (ReturnCode::SUCCESS, 3) => {
+                if let Ok(buf) = self.usb.receive_cancel() {
+                    self.recv_buffer.replace(buf);
+                }
+                ReturnCode::SUCCESS
+            }
+            _ => result,
+        }
+    }
+}
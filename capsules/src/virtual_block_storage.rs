@@ -0,0 +1,583 @@
+//! Virtualize a block device.
+//!
+//! `MuxBlockStorage` shares one `hil::block_storage` device between several
+//! kernel users, for instance a log, a TicKV store and a FAT filesystem on
+//! one SD card or SPI flash chip. Each user gets a `VirtualBlockStorage`,
+//! which is a region of the device that looks like a whole device starting
+//! at block 0. Users can't access blocks outside their region. Regions
+//! should start and end on erase unit boundaries.
+//!
+//! Requests from different users are run one at a time, in the order of
+//! the list of users. `flush()` writes the cached writes of all users.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let mux = static_init!(
+//!     capsules::virtual_block_storage::MuxBlockStorage<'static>,
+//!     capsules::virtual_block_storage::MuxBlockStorage::new(cache)
+//! );
+//! hil::block_storage::BlockStorage::set_client(cache, mux);
+//!
+//! // Blocks 0 to 255
+//! let log_region = static_init!(
+//!     capsules::virtual_block_storage::VirtualBlockStorage<'static>,
+//!     capsules::virtual_block_storage::VirtualBlockStorage::new(mux, 0, 256)
+//! );
+//! log_region.setup();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::ptr;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::block_storage::{self, BlockStorage, Geometry};
+use kernel::ReturnCode;
+
+/// Keeps the list of users of a block device and runs their requests one at
+/// a time.
+pub struct MuxBlockStorage<'a> {
+    device: &'a dyn BlockStorage<'a>,
+    users: List<'a, VirtualBlockStorage<'a>>,
+    inflight: OptionalCell<&'a VirtualBlockStorage<'a>>,
+}
+
+impl<'a> MuxBlockStorage<'a> {
+    pub const fn new(device: &'a dyn BlockStorage<'a>) -> MuxBlockStorage<'a> {
+        MuxBlockStorage {
+            device,
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    /// Start the next pending request, if the device is idle. If the request
+    /// of `caller` can't be started its error is returned, so it can be
+    /// returned from the request instead of calling the client back.
+    fn do_next_op(&self, caller: Option<&VirtualBlockStorage<'a>>) -> ReturnCode {
+        let mut caller_ret = ReturnCode::SUCCESS;
+        while self.inflight.is_none() {
+            let node = match self
+                .users
+                .iter()
+                .find(|node| node.operation.get() != Op::Idle)
+            {
+                Some(node) => node,
+                None => break,
+            };
+
+            let operation = node.operation.replace(Op::Idle);
+            let ret = self.start(node, operation);
+            if ret == ReturnCode::SUCCESS {
+                node.inflight.set(operation);
+                self.inflight.set(node);
+            } else if caller.map_or(false, |caller| ptr::eq(caller, node)) {
+                caller_ret = ret;
+            } else {
+                node.complete(operation, Err(ret));
+            }
+        }
+        caller_ret
+    }
+
+    fn start(&self, node: &VirtualBlockStorage<'a>, operation: Op) -> ReturnCode {
+        let pass = |result: Result<(), (ReturnCode, &'static mut [u8])>| match result {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((e, buffer)) => {
+                node.buffer.replace(buffer);
+                e
+            }
+        };
+        match operation {
+            Op::Idle => ReturnCode::FAIL,
+            Op::Read(block, count) => node.buffer.take().map_or(ReturnCode::FAIL, |buffer| {
+                pass(self.device.read(buffer, node.start + block, count))
+            }),
+            Op::Write(block, count) => node.buffer.take().map_or(ReturnCode::FAIL, |buffer| {
+                pass(self.device.write(buffer, node.start + block, count))
+            }),
+            Op::Erase(block, count) => self.device.erase(node.start + block, count),
+            Op::Flush => self.device.flush(),
+        }
+    }
+
+    fn complete(&self, result: Result<(), ReturnCode>, buffer: Option<&'static mut [u8]>) {
+        self.inflight.take().map(|node| {
+            let operation = node.inflight.replace(Op::Idle);
+            if let Some(buffer) = buffer {
+                node.buffer.replace(buffer);
+            }
+            node.complete(operation, result);
+        });
+        self.do_next_op(None);
+    }
+}
+
+impl<'a> block_storage::Client for MuxBlockStorage<'a> {
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        self.complete(result, Some(buffer));
+    }
+
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        self.complete(result, Some(buffer));
+    }
+
+    fn erase_complete(&self, result: Result<(), ReturnCode>) {
+        self.complete(result, None);
+    }
+
+    fn flush_complete(&self, result: Result<(), ReturnCode>) {
+        self.complete(result, None);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Idle,
+    Read(u32, u32),
+    Write(u32, u32),
+    Erase(u32, u32),
+    Flush,
+}
+
+/// A region of a shared block device.
+pub struct VirtualBlockStorage<'a> {
+    mux: &'a MuxBlockStorage<'a>,
+    start: u32,
+    count: u32,
+    buffer: TakeCell<'static, [u8]>,
+    /// A request waiting for the device
+    operation: Cell<Op>,
+    /// The request the device is running
+    inflight: Cell<Op>,
+    next: ListLink<'a, VirtualBlockStorage<'a>>,
+    client: OptionalCell<&'a dyn block_storage::Client>,
+}
+
+impl<'a> VirtualBlockStorage<'a> {
+    /// A region of `count` blocks starting at block `start` of the device.
+    pub fn new(mux: &'a MuxBlockStorage<'a>, start: u32, count: u32) -> VirtualBlockStorage<'a> {
+        VirtualBlockStorage {
+            mux,
+            start,
+            count,
+            buffer: TakeCell::empty(),
+            operation: Cell::new(Op::Idle),
+            inflight: Cell::new(Op::Idle),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Add this region to the users of the mux.
+    pub fn setup(&'a self) {
+        self.mux.users.push_head(self);
+    }
+
+    fn busy(&self) -> bool {
+        self.operation.get() != Op::Idle || self.inflight.get() != Op::Idle
+    }
+
+    fn request(
+        &self,
+        operation: Op,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.busy() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        let geometry = self.geometry();
+        if !geometry.contains(block, count) || buffer.len() < count as usize * geometry.block_size {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+
+        self.buffer.replace(buffer);
+        self.operation.set(operation);
+        let ret = self.mux.do_next_op(Some(self));
+        if ret != ReturnCode::SUCCESS {
+            // The buffer was stored above
+            return Err((ret, self.buffer.take().unwrap_or(&mut [])));
+        }
+        Ok(())
+    }
+
+    fn complete(&self, operation: Op, result: Result<(), ReturnCode>) {
+        match operation {
+            Op::Read(..) => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.read_complete(buffer, result));
+                });
+            }
+            Op::Write(..) => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_complete(buffer, result));
+                });
+            }
+            Op::Erase(..) => {
+                self.client.map(|client| client.erase_complete(result));
+            }
+            Op::Flush => {
+                // Another user may have flushed the device in the meantime
+                let result = match result {
+                    Err(ReturnCode::EALREADY) => Ok(()),
+                    result => result,
+                };
+                self.client.map(|client| client.flush_complete(result));
+            }
+            Op::Idle => {}
+        }
+    }
+}
+
+impl<'a> ListNode<'a, VirtualBlockStorage<'a>> for VirtualBlockStorage<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualBlockStorage<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> BlockStorage<'a> for VirtualBlockStorage<'a> {
+    fn set_client(&self, client: &'a dyn block_storage::Client) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> Geometry {
+        let device = self.mux.device.geometry();
+        Geometry {
+            block_count: cmp::min(self.count, device.block_count.saturating_sub(self.start)),
+            ..device
+        }
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.request(Op::Read(block, count), buffer, block, count)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.request(Op::Write(block, count), buffer, block, count)
+    }
+
+    fn erase(&self, block: u32, count: u32) -> ReturnCode {
+        if self.busy() {
+            return ReturnCode::EBUSY;
+        }
+        if !self.geometry().contains(block, count) {
+            return ReturnCode::EINVAL;
+        }
+        self.operation.set(Op::Erase(block, count));
+        self.mux.do_next_op(Some(self))
+    }
+
+    fn flush(&self) -> ReturnCode {
+        if self.busy() {
+            return ReturnCode::EBUSY;
+        }
+        self.operation.set(Op::Flush);
+        self.mux.do_next_op(Some(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec;
+    use std::vec::Vec;
+
+    const BLOCK_SIZE: usize = 16;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Access {
+        Read(u32, u32),
+        Write(u32, u32),
+        Erase(u32, u32),
+        Flush,
+    }
+
+    /// A device with a write cache that records its accesses and completes
+    /// them when `run` is called.
+    struct TestDevice {
+        accesses: RefCell<Vec<Access>>,
+        pending: Cell<Option<Access>>,
+        buffer: TakeCell<'static, [u8]>,
+        dirty: Cell<bool>,
+        client: OptionalCell<&'static dyn block_storage::Client>,
+    }
+
+    impl TestDevice {
+        fn start(&self, access: Access) {
+            assert!(self.pending.get().is_none(), "device is busy");
+            self.accesses.borrow_mut().push(access);
+            self.pending.set(Some(access));
+        }
+
+        fn run(&self) -> bool {
+            let access = match self.pending.take() {
+                Some(access) => access,
+                None => return false,
+            };
+            self.client.map(|client| match access {
+                Access::Read(..) => client.read_complete(self.buffer.take().unwrap(), Ok(())),
+                Access::Write(..) => client.write_complete(self.buffer.take().unwrap(), Ok(())),
+                Access::Erase(..) => client.erase_complete(Ok(())),
+                Access::Flush => client.flush_complete(Ok(())),
+            });
+            true
+        }
+    }
+
+    impl BlockStorage<'static> for TestDevice {
+        fn set_client(&self, client: &'static dyn block_storage::Client) {
+            self.client.set(client);
+        }
+
+        fn geometry(&self) -> Geometry {
+            Geometry {
+                block_size: BLOCK_SIZE,
+                block_count: 64,
+                erase_blocks: 1,
+                needs_erase: false,
+            }
+        }
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            block: u32,
+            count: u32,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.start(Access::Read(block, count));
+            self.buffer.replace(buffer);
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            block: u32,
+            count: u32,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.start(Access::Write(block, count));
+            self.buffer.replace(buffer);
+            self.dirty.set(true);
+            Ok(())
+        }
+
+        fn erase(&self, block: u32, count: u32) -> ReturnCode {
+            self.start(Access::Erase(block, count));
+            ReturnCode::SUCCESS
+        }
+
+        fn flush(&self) -> ReturnCode {
+            if !self.dirty.replace(false) {
+                return ReturnCode::EALREADY;
+            }
+            self.start(Access::Flush);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    type Log = RefCell<Vec<(usize, Result<(), ReturnCode>)>>;
+
+    /// Records which user each callback is for.
+    struct TestClient {
+        user: usize,
+        log: &'static Log,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl block_storage::Client for TestClient {
+        fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+            self.buffer.replace(buffer);
+            self.log.borrow_mut().push((self.user, result));
+        }
+
+        fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+            self.buffer.replace(buffer);
+            self.log.borrow_mut().push((self.user, result));
+        }
+
+        fn erase_complete(&self, result: Result<(), ReturnCode>) {
+            self.log.borrow_mut().push((self.user, result));
+        }
+
+        fn flush_complete(&self, result: Result<(), ReturnCode>) {
+            self.log.borrow_mut().push((self.user, result));
+        }
+    }
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    struct Test {
+        device: &'static TestDevice,
+        users: Vec<&'static VirtualBlockStorage<'static>>,
+        clients: Vec<&'static TestClient>,
+        log: &'static Log,
+    }
+
+    impl Test {
+        /// Users with the regions `(start, count)`. Requests of later users
+        /// run first.
+        fn new(regions: &[(u32, u32)]) -> Test {
+            let device = leak(TestDevice {
+                accesses: RefCell::new(Vec::new()),
+                pending: Cell::new(None),
+                buffer: TakeCell::empty(),
+                dirty: Cell::new(false),
+                client: OptionalCell::empty(),
+            });
+            let mux = leak(MuxBlockStorage::new(device));
+            device.set_client(mux);
+            let log = leak(RefCell::new(Vec::new()));
+            let mut users = Vec::new();
+            let mut clients = Vec::new();
+            for (i, (start, count)) in regions.iter().enumerate() {
+                let user = leak(VirtualBlockStorage::new(mux, *start, *count));
+                user.setup();
+                let client = leak(TestClient {
+                    user: i,
+                    log,
+                    buffer: TakeCell::new(Box::leak(vec![0; 4 * BLOCK_SIZE].into_boxed_slice())),
+                });
+                user.set_client(client);
+                users.push(&*user);
+                clients.push(&*client);
+            }
+            Test {
+                device,
+                users,
+                clients,
+                log,
+            }
+        }
+
+        fn read(&self, user: usize, block: u32, count: u32) -> ReturnCode {
+            let buffer = self.clients[user].buffer.take().unwrap();
+            match self.users[user].read(buffer, block, count) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((e, buffer)) => {
+                    self.clients[user].buffer.replace(buffer);
+                    e
+                }
+            }
+        }
+
+        fn write(&self, user: usize, block: u32, count: u32) -> ReturnCode {
+            let buffer = self.clients[user].buffer.take().unwrap();
+            match self.users[user].write(buffer, block, count) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((e, buffer)) => {
+                    self.clients[user].buffer.replace(buffer);
+                    e
+                }
+            }
+        }
+
+        /// Completes device accesses until there are none, and returns the
+        /// accesses and callbacks.
+        fn run(&self) -> (Vec<Access>, Vec<(usize, Result<(), ReturnCode>)>) {
+            while self.device.run() {}
+            (
+                self.device.accesses.replace(Vec::new()),
+                self.log.replace(Vec::new()),
+            )
+        }
+    }
+
+    #[test]
+    fn regions_are_offset_and_bounded() {
+        let t = Test::new(&[(0, 16), (16, 16), (56, 16)]);
+        assert_eq!(t.users[0].geometry().block_count, 16);
+        assert_eq!(t.users[2].geometry().block_count, 8);
+
+        assert_eq!(t.write(1, 3, 2), ReturnCode::SUCCESS);
+        assert_eq!(t.run(), (vec![Access::Write(19, 2)], vec![(1, Ok(()))]));
+        assert_eq!(t.users[2].erase(7, 1), ReturnCode::SUCCESS);
+        assert_eq!(t.run(), (vec![Access::Erase(63, 1)], vec![(2, Ok(()))]));
+
+        assert_eq!(t.read(0, 15, 2), ReturnCode::EINVAL);
+        assert_eq!(t.read(2, 8, 1), ReturnCode::EINVAL);
+        assert_eq!(t.users[1].erase(16, 1), ReturnCode::EINVAL);
+        assert_eq!(t.read(0, 0, 5), ReturnCode::EINVAL);
+        assert_eq!(t.run(), (vec![], vec![]));
+    }
+
+    #[test]
+    fn requests_wait_for_the_device() {
+        let t = Test::new(&[(0, 16), (16, 16), (32, 16)]);
+        assert_eq!(t.read(0, 1, 1), ReturnCode::SUCCESS);
+        assert_eq!(t.write(1, 2, 1), ReturnCode::SUCCESS);
+        assert_eq!(t.read(2, 3, 1), ReturnCode::SUCCESS);
+
+        // Each user has one request at a time.
+        assert_eq!(t.users[1].erase(0, 1), ReturnCode::EBUSY);
+        assert_eq!(t.users[1].flush(), ReturnCode::EBUSY);
+
+        assert_eq!(
+            t.run(),
+            (
+                vec![
+                    Access::Read(1, 1),
+                    Access::Read(35, 1),
+                    Access::Write(18, 1)
+                ],
+                vec![(0, Ok(())), (2, Ok(())), (1, Ok(()))]
+            )
+        );
+    }
+
+    #[test]
+    fn flush_while_another_user_is_queued() {
+        let t = Test::new(&[(0, 16), (16, 16), (32, 16)]);
+        assert_eq!(t.write(0, 0, 1), ReturnCode::SUCCESS);
+        assert_eq!(t.read(1, 0, 1), ReturnCode::SUCCESS);
+        assert_eq!(t.users[2].flush(), ReturnCode::SUCCESS);
+
+        // The flush covers the write that was running when it was asked
+        // for, and the queued read runs after it.
+        assert_eq!(
+            t.run(),
+            (
+                vec![Access::Write(0, 1), Access::Flush, Access::Read(16, 1)],
+                vec![(0, Ok(())), (2, Ok(())), (1, Ok(()))]
+            )
+        );
+    }
+
+    #[test]
+    fn flush_with_nothing_to_write() {
+        let t = Test::new(&[(0, 16), (16, 16)]);
+        assert_eq!(t.users[1].flush(), ReturnCode::EALREADY);
+
+        // A flush that waited while only reads ran still succeeds, since
+        // another user may have flushed the device in the meantime.
+        assert_eq!(t.read(0, 0, 1), ReturnCode::SUCCESS);
+        assert_eq!(t.users[1].flush(), ReturnCode::SUCCESS);
+        assert_eq!(
+            t.run(),
+            (vec![Access::Read(0, 1)], vec![(0, Ok(())), (1, Ok(()))])
+        );
+    }
+}
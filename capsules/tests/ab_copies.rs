@@ -0,0 +1,347 @@
+//! Host tests for atomic A/B copies.
+//!
+//! The copies are kept in a `RamDisk`, which can lose power in the middle of
+//! an update to check that the previous copy survives.
+
+mod common;
+
+use capsules::ab_copies::{self, AbCopies, Copy, Slots, HEADER_SIZE};
+use common::ramdisk::RamDisk;
+use common::Device;
+use common::{leak, leak_buf};
+use kernel::common::cells::TakeCell;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+const SLOT_A: usize = 0x2000;
+const SLOT_B: usize = 0x3000;
+const SLOT_SIZE: usize = 300;
+const SLOTS: Slots = [(SLOT_A, SLOT_SIZE), (SLOT_B, SLOT_SIZE)];
+const CAPACITY: usize = SLOT_SIZE - HEADER_SIZE;
+
+/// Supplies the data of updates and collects the data of reads.
+struct TestClient {
+    data: RefCell<Vec<u8>>,
+    delivered: RefCell<Vec<u8>>,
+    result: Cell<Option<Result<Copy, ReturnCode>>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl ab_copies::Client for TestClient {
+    fn fill(&self, offset: usize, chunk: &mut [u8]) {
+        chunk.copy_from_slice(&self.data.borrow()[offset..offset + chunk.len()]);
+    }
+
+    fn deliver(&self, offset: usize, chunk: &[u8]) {
+        let mut delivered = self.delivered.borrow_mut();
+        if delivered.len() < offset + chunk.len() {
+            delivered.resize(offset + chunk.len(), 0);
+        }
+        delivered[offset..offset + chunk.len()].copy_from_slice(chunk);
+    }
+
+    fn read_done(&self, buffer: Option<&'static mut [u8]>, result: Result<Copy, ReturnCode>) {
+        self.buffer.replace(buffer.unwrap());
+        self.result.set(Some(result));
+    }
+
+    fn update_done(&self, buffer: Option<&'static mut [u8]>, result: Result<Copy, ReturnCode>) {
+        self.buffer.replace(buffer.unwrap());
+        self.result.set(Some(result));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.result.set(Some(Ok(Copy {
+            slot: 0,
+            sequence: 0,
+            length,
+        })));
+    }
+}
+
+struct Harness {
+    disk: &'static RamDisk,
+    copies: &'static AbCopies<'static>,
+    client: &'static TestClient,
+}
+
+impl Harness {
+    /// Boots a new instance on `disk`.
+    fn boot(disk: &'static RamDisk) -> Harness {
+        let copies = leak(AbCopies::new(disk));
+        disk.set_client(copies);
+        let client = leak(TestClient {
+            data: RefCell::new(Vec::new()),
+            delivered: RefCell::new(Vec::new()),
+            result: Cell::new(None),
+            // Smaller than a copy, so copies take several accesses.
+            buffer: TakeCell::new(leak_buf(64)),
+        });
+        copies.set_client(client);
+        Harness {
+            disk,
+            copies,
+            client,
+        }
+    }
+
+    /// Completes the pending operations and returns the result.
+    fn finish(&self) -> Result<Copy, ReturnCode> {
+        self.client.result.set(None);
+        while self.disk.run() {}
+        self.client
+            .result
+            .take()
+            .expect("operation did not complete")
+    }
+
+    fn update(&self, slots: Slots, data: &[u8]) -> Result<Copy, ReturnCode> {
+        *self.client.data.borrow_mut() = data.to_vec();
+        let buffer = self.client.buffer.take().unwrap();
+        match self.copies.update(slots, data.len(), buffer) {
+            Ok(()) => self.finish(),
+            Err((e, buffer)) => {
+                self.client.buffer.replace(buffer.unwrap());
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts an update and loses power after `writes` writes.
+    fn torn_update(&self, data: &[u8], writes: usize) {
+        *self.client.data.borrow_mut() = data.to_vec();
+        let buffer = self.client.buffer.take().unwrap();
+        assert_eq!(self.copies.update(SLOTS, data.len(), buffer), Ok(()));
+        let start = self.disk.writes.get();
+        while self.disk.writes.get() - start < writes {
+            assert!(self.disk.run());
+        }
+        self.disk.cut_power();
+    }
+
+    fn read(&self, length: usize) -> Result<(Copy, Vec<u8>), ReturnCode> {
+        self.client.delivered.borrow_mut().clear();
+        let buffer = self.client.buffer.take().unwrap();
+        match self.copies.read(SLOTS, length, buffer) {
+            Ok(()) => {
+                let copy = self.finish()?;
+                Ok((copy, self.client.delivered.borrow().clone()))
+            }
+            Err((e, buffer)) => {
+                self.client.buffer.replace(buffer.unwrap());
+                Err(e)
+            }
+        }
+    }
+}
+
+fn pattern(seed: u8, length: usize) -> Vec<u8> {
+    (0..length).map(|i| seed.wrapping_add(i as u8)).collect()
+}
+
+/// Boots on a new disk and writes two copies: 100 bytes of `pattern(1)`,
+/// then 120 bytes of `pattern(2)`.
+fn two_copies() -> Harness {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    h.update(SLOTS, &pattern(1, 100)).unwrap();
+    h.update(SLOTS, &pattern(2, 120)).unwrap();
+    h
+}
+
+#[test]
+fn no_copies() {
+    let disk = leak(RamDisk::byte_addressable());
+    // Leftovers of an earlier user of the flash.
+    disk.write_bytes(SLOT_A, &[0xff; SLOT_SIZE]);
+    let h = Harness::boot(disk);
+    assert_eq!(AbCopies::capacity(SLOTS), Some(CAPACITY));
+    assert_eq!(h.read(CAPACITY), Err(ReturnCode::FAIL));
+}
+
+#[test]
+fn updates_alternate() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+
+    let first = pattern(1, 200);
+    assert_eq!(
+        h.update(SLOTS, &first),
+        Ok(Copy {
+            slot: 0,
+            sequence: 1,
+            length: 200
+        })
+    );
+    let (copy, data) = h.read(CAPACITY).unwrap();
+    assert_eq!((copy.slot, copy.sequence), (0, 1));
+    assert_eq!(data, first);
+
+    // The next update goes to the other slot, and the first copy stays.
+    let second = pattern(50, 10);
+    assert_eq!(
+        h.update(SLOTS, &second),
+        Ok(Copy {
+            slot: 1,
+            sequence: 2,
+            length: 10
+        })
+    );
+    let mut raw = vec![0; 200];
+    disk.read_bytes(SLOT_A + HEADER_SIZE, &mut raw);
+    assert_eq!(raw, first);
+    assert_eq!(h.read(CAPACITY).unwrap().1, second);
+    assert_eq!(h.update(SLOTS, &first).map(|copy| copy.slot), Ok(0));
+}
+
+#[test]
+fn reads_deliver_requested_length() {
+    let h = Harness::boot(leak(RamDisk::byte_addressable()));
+    let data = pattern(90, CAPACITY);
+    assert_eq!(h.update(SLOTS, &data).map(|copy| copy.slot), Ok(0));
+    assert_eq!(h.read(100).unwrap().1, data[..100].to_vec());
+}
+
+#[test]
+fn empty_copy() {
+    let h = two_copies();
+    // An empty copy is still a copy.
+    assert_eq!(h.update(SLOTS, &[]).map(|copy| copy.sequence), Ok(3));
+    let (copy, data) = h.read(CAPACITY).unwrap();
+    assert_eq!((copy.slot, copy.length), (0, 0));
+    assert!(data.is_empty());
+}
+
+#[test]
+fn sequence_continues_after_reboot() {
+    let h = two_copies();
+    let h = Harness::boot(h.disk);
+    assert_eq!(
+        h.update(SLOTS, &pattern(3, 200)),
+        Ok(Copy {
+            slot: 0,
+            sequence: 3,
+            length: 200
+        })
+    );
+}
+
+#[test]
+fn power_loss_during_data() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    let first = pattern(1, 200);
+    h.update(SLOTS, &first).unwrap();
+
+    // Lose power after each write of an update to the other slot. The data
+    // takes four writes and the header one more.
+    for writes in 1..5 {
+        let h = Harness::boot(disk);
+        h.torn_update(&pattern(7, 250), writes);
+        let h = Harness::boot(disk);
+        let (copy, data) = h.read(CAPACITY).unwrap();
+        assert_eq!((copy.slot, copy.sequence), (0, 1));
+        assert_eq!(data, first);
+    }
+}
+
+#[test]
+fn header_completes_update() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    h.update(SLOTS, &pattern(1, 200)).unwrap();
+
+    // Once the header is written the new copy is complete.
+    h.torn_update(&pattern(7, 250), 5);
+    let h = Harness::boot(disk);
+    let (copy, data) = h.read(CAPACITY).unwrap();
+    assert_eq!((copy.slot, copy.sequence), (1, 2));
+    assert_eq!(data, pattern(7, 250));
+}
+
+#[test]
+fn power_loss_over_older_copy() {
+    let h = two_copies();
+
+    // A torn update over the older copy doesn't bring that copy back.
+    h.torn_update(&pattern(9, 40), 1);
+    let h = Harness::boot(h.disk);
+    let (copy, data) = h.read(CAPACITY).unwrap();
+    assert_eq!((copy.slot, copy.sequence), (1, 2));
+    assert_eq!(data, pattern(2, 120));
+    assert_eq!(
+        h.update(SLOTS, &pattern(3, 10)).map(|copy| copy.slot),
+        Ok(0)
+    );
+}
+
+#[test]
+fn corrupt_copy_falls_back() {
+    let h = two_copies();
+
+    // A flipped bit in the newest copy falls back to the older one. The
+    // reader saw the corrupt data first, then the older copy.
+    h.disk.write_bytes(SLOT_B + HEADER_SIZE + 110, &[0]);
+    let (copy, data) = h.read(CAPACITY).unwrap();
+    assert_eq!((copy.slot, copy.sequence), (0, 1));
+    assert_eq!(data[..100].to_vec(), pattern(1, 100));
+
+    // The next update replaces the corrupt copy.
+    let second = pattern(2, 120);
+    assert_eq!(h.update(SLOTS, &second).map(|copy| copy.slot), Ok(1));
+    assert_eq!(h.read(CAPACITY).unwrap().1, second);
+}
+
+#[test]
+fn oversized_header() {
+    let h = two_copies();
+    // A header that claims more than fits in the slot is ignored.
+    h.disk
+        .write_bytes(SLOT_B + 8, &(SLOT_SIZE as u32).to_le_bytes());
+    assert_eq!(h.read(CAPACITY).unwrap().1[..100].to_vec(), pattern(1, 100));
+}
+
+#[test]
+fn both_copies_corrupt() {
+    let h = two_copies();
+    h.disk.write_bytes(SLOT_B, b"XXXX");
+    h.disk.write_bytes(SLOT_A, b"XXXX");
+    assert_eq!(h.read(CAPACITY), Err(ReturnCode::FAIL));
+}
+
+#[test]
+fn slot_arguments() {
+    let h = Harness::boot(leak(RamDisk::byte_addressable()));
+
+    let overlapping = [(SLOT_A, SLOT_SIZE), (SLOT_A + 100, SLOT_SIZE)];
+    assert_eq!(AbCopies::capacity(overlapping), None);
+    assert_eq!(
+        AbCopies::capacity([(SLOT_A, HEADER_SIZE), (SLOT_B, SLOT_SIZE)]),
+        None
+    );
+    assert_eq!(
+        AbCopies::capacity([(SLOT_A, SLOT_SIZE), (SLOT_B, 100)]),
+        Some(100 - HEADER_SIZE)
+    );
+    assert_eq!(h.update(overlapping, &[1; 4]), Err(ReturnCode::EINVAL));
+    assert_eq!(
+        h.update(SLOTS, &vec![1; CAPACITY + 1]),
+        Err(ReturnCode::ESIZE)
+    );
+}
+
+#[test]
+fn plain_writes() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    // Plain writes go straight to the storage.
+    let buffer = h.client.buffer.take().unwrap();
+    buffer[..4].copy_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(h.copies.write(buffer, 0x100, 4), ReturnCode::SUCCESS);
+    assert_eq!(h.finish().map(|copy| copy.length), Ok(4));
+    let mut raw = [0; 4];
+    disk.read_bytes(0x100, &mut raw);
+    assert_eq!(raw, [1, 2, 3, 4]);
+}
@@ -0,0 +1,534 @@
+//! A simulated central connecting to the BLE link layer, discovering the GATT
+//! database, reading, writing and subscribing to characteristics, updating
+//! the channel map and finally letting the connection time out.
+
+mod common;
+
+use capsules::ble::gatt::{properties, GattServer, GattServerClient, Uuid};
+use capsules::ble::l2cap::L2cap;
+use capsules::ble::link_layer::{reason, LinkLayer};
+use capsules::virtual_alarm::VirtualMuxAlarm;
+use common::{leak, Sim, VAlarm};
+use kernel::common::cells::OptionalCell;
+use kernel::hil::ble_advertising::{
+    BleConnectionDriver, ConnectionClient, RadioChannel, ADVERTISING_ACCESS_ADDRESS,
+};
+use kernel::hil::time::{self, Alarm, Ticks, Time};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+const ADDRESS: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0xc6];
+const CENTRAL_ADDRESS: [u8; 6] = [0x11, 0x12, 0x13, 0x14, 0x15, 0xd6];
+const ACCESS_ADDRESS: u32 = 0x50654a3b;
+const INTERVAL_US: u32 = 30_000;
+const TIMEOUT_US: u32 = 1_000_000;
+const HOP: u8 = 7;
+
+const BATTERY: usize = 0;
+const CONTROL: usize = 1;
+const CONTROL_UUID: [u8; 16] = [
+    0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, 0x01, 0x00, 0x40, 0x6e,
+];
+
+fn air_time_us(len: usize) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum RadioState {
+    Off,
+    Listening(u8, u32),
+    Receiving,
+}
+
+/// Radio whose packets are delivered by the test.
+struct FakeRadio {
+    client: OptionalCell<&'static dyn ConnectionClient>,
+    state: Cell<RadioState>,
+    advertisements: RefCell<Vec<(u8, Vec<u8>)>>,
+}
+
+impl FakeRadio {
+    /// A packet starts. Returns false if the radio is not listening for it.
+    fn begin(&self, channel: u8, access_address: u32) -> bool {
+        if self.state.get() == RadioState::Listening(channel, access_address) {
+            self.state.set(RadioState::Receiving);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The packet started with `begin` ends. Returns the reply.
+    fn finish(&self, pdu: &[u8]) -> Vec<u8> {
+        assert_eq!(self.state.get(), RadioState::Receiving);
+        self.state.set(RadioState::Off);
+        let mut tx = [0; 257];
+        let len = self.client.map_or(0, |client| {
+            client.packet_received(pdu, None, ReturnCode::SUCCESS, &mut tx)
+        });
+        self.client
+            .map(|client| client.exchange_done(ReturnCode::SUCCESS));
+        tx[..len].to_vec()
+    }
+}
+
+impl BleConnectionDriver<'static> for FakeRadio {
+    fn advertise(&self, channel: RadioChannel, pdu: &[u8]) -> ReturnCode {
+        let channel = channel.get_channel_index() as u8;
+        self.advertisements
+            .borrow_mut()
+            .push((channel, pdu.to_vec()));
+        self.state
+            .set(RadioState::Listening(channel, ADVERTISING_ACCESS_ADDRESS));
+        ReturnCode::SUCCESS
+    }
+
+    fn listen(&self, channel: RadioChannel, access_address: u32, _crc_init: u32) -> ReturnCode {
+        self.state.set(RadioState::Listening(
+            channel.get_channel_index() as u8,
+            access_address,
+        ));
+        ReturnCode::SUCCESS
+    }
+
+    fn scan(&self, channel: RadioChannel) -> ReturnCode {
+        self.state.set(RadioState::Listening(
+            channel.get_channel_index() as u8,
+            ADVERTISING_ACCESS_ADDRESS,
+        ));
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        match self.state.get() {
+            RadioState::Receiving => ReturnCode::EBUSY,
+            _ => {
+                self.state.set(RadioState::Off);
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    fn set_connection_client(&self, client: &'static dyn ConnectionClient) {
+        self.client.set(client);
+    }
+}
+
+/// Master side of the connection, with the same channel selection and
+/// acknowledgement scheme as the link layer.
+struct Central {
+    radio: &'static FakeRadio,
+    alarm: &'static VAlarm,
+    running: Cell<bool>,
+    anchor: Cell<u32>,
+    in_event: Cell<bool>,
+    channel_map: Cell<[u8; 5]>,
+    map_update: Cell<Option<(u16, [u8; 5])>>,
+    unmapped_channel: Cell<u8>,
+    channel: Cell<u8>,
+    counter: Cell<u16>,
+    sn: Cell<bool>,
+    nesn: Cell<bool>,
+    unacknowledged: RefCell<Option<Vec<u8>>>,
+    queue: RefCell<VecDeque<Vec<u8>>>,
+    att: RefCell<Vec<Vec<u8>>>,
+    control: RefCell<Vec<Vec<u8>>>,
+    missed: Cell<usize>,
+    channels_used: RefCell<Vec<u8>>,
+}
+
+impl Central {
+    fn new(radio: &'static FakeRadio, alarm: &'static VAlarm) -> Central {
+        Central {
+            radio: radio,
+            alarm: alarm,
+            running: Cell::new(false),
+            anchor: Cell::new(0),
+            in_event: Cell::new(false),
+            channel_map: Cell::new([0xff, 0xff, 0xff, 0xff, 0x1f]),
+            map_update: Cell::new(None),
+            unmapped_channel: Cell::new(0),
+            channel: Cell::new(0),
+            counter: Cell::new(0),
+            sn: Cell::new(false),
+            nesn: Cell::new(false),
+            unacknowledged: RefCell::new(None),
+            queue: RefCell::new(VecDeque::new()),
+            att: RefCell::new(Vec::new()),
+            control: RefCell::new(Vec::new()),
+            missed: Cell::new(0),
+            channels_used: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Connects with the first anchor `offset_us` after the end of
+    /// CONNECT_IND.
+    fn connect(&self, offset_us: u32) {
+        let mut ll_data = Vec::new();
+        ll_data.extend_from_slice(&ACCESS_ADDRESS.to_le_bytes());
+        ll_data.extend_from_slice(&[0x55, 0x55, 0x55]);
+        ll_data.push(2); // WinSize
+        ll_data.extend_from_slice(&0u16.to_le_bytes()); // WinOffset
+        ll_data.extend_from_slice(&((INTERVAL_US / 1250) as u16).to_le_bytes());
+        ll_data.extend_from_slice(&0u16.to_le_bytes()); // Latency
+        ll_data.extend_from_slice(&((TIMEOUT_US / 10_000) as u16).to_le_bytes());
+        ll_data.extend_from_slice(&self.channel_map.get());
+        ll_data.push(HOP); // SCA 0, 500 ppm
+        let mut pdu = vec![0x05 | 0xc0, 34];
+        pdu.extend_from_slice(&CENTRAL_ADDRESS);
+        pdu.extend_from_slice(&ADDRESS);
+        pdu.extend_from_slice(&ll_data);
+
+        let channel = self.radio.advertisements.borrow().last().unwrap().0;
+        assert!(self.radio.begin(channel, ADVERTISING_ACCESS_ADDRESS));
+        assert!(self.radio.finish(&pdu).is_empty());
+
+        self.running.set(true);
+        self.hop();
+        self.anchor
+            .set(self.alarm.now().into_u32() + 1250 + offset_us);
+        self.schedule();
+    }
+
+    fn hop(&self) {
+        let unmapped = (self.unmapped_channel.get() + HOP) % 37;
+        self.unmapped_channel.set(unmapped);
+        let map = self.channel_map.get();
+        let used: Vec<u8> = (0..37)
+            .filter(|&c| map[c as usize / 8] & (1 << (c % 8)) != 0)
+            .collect();
+        if used.contains(&unmapped) {
+            self.channel.set(unmapped);
+        } else {
+            self.channel.set(used[unmapped as usize % used.len()]);
+        }
+    }
+
+    fn schedule(&self) {
+        let now = self.alarm.now();
+        let when = time::Ticks32::from(self.anchor.get());
+        self.alarm.set_alarm(now, when.wrapping_sub(now));
+    }
+
+    fn send_att(&self, pdu: &[u8]) {
+        let mut frame = vec![0b10, (4 + pdu.len()) as u8];
+        frame.extend_from_slice(&(pdu.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&4u16.to_le_bytes());
+        frame.extend_from_slice(pdu);
+        self.queue.borrow_mut().push_back(frame);
+    }
+
+    fn send_control(&self, payload: &[u8]) {
+        let mut pdu = vec![0b11, payload.len() as u8];
+        pdu.extend_from_slice(payload);
+        self.queue.borrow_mut().push_back(pdu);
+    }
+
+    /// The PDU to send in this event, with SN and NESN set.
+    fn next_pdu(&self) -> Vec<u8> {
+        let mut unacknowledged = self.unacknowledged.borrow_mut();
+        if unacknowledged.is_none() {
+            let pdu = self
+                .queue
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_else(|| vec![0b01, 0]);
+            *unacknowledged = Some(pdu);
+        }
+        let mut pdu = unacknowledged.clone().unwrap();
+        pdu[0] &= 0b11;
+        if self.nesn.get() {
+            pdu[0] |= 1 << 2;
+        }
+        if self.sn.get() {
+            pdu[0] |= 1 << 3;
+        }
+        pdu
+    }
+
+    fn reply(&self, reply: &[u8]) {
+        assert!(reply.len() >= 2);
+        if (reply[0] & (1 << 2) != 0) != self.sn.get() {
+            self.sn.set(!self.sn.get());
+            self.unacknowledged.replace(None);
+        }
+        if (reply[0] & (1 << 3) != 0) == self.nesn.get() {
+            self.nesn.set(!self.nesn.get());
+            let payload = reply[2..2 + reply[1] as usize].to_vec();
+            match reply[0] & 0b11 {
+                0b10 => {
+                    assert_eq!(&payload[2..4], &4u16.to_le_bytes());
+                    self.att.borrow_mut().push(payload[4..].to_vec());
+                }
+                0b11 => self.control.borrow_mut().push(payload),
+                _ => assert!(payload.is_empty()),
+            }
+        }
+    }
+
+    fn end_event(&self) {
+        self.counter.set(self.counter.get().wrapping_add(1));
+        match self.map_update.get() {
+            Some((instant, map)) if instant == self.counter.get() => {
+                self.channel_map.set(map);
+                self.map_update.set(None);
+            }
+            _ => {}
+        }
+        self.hop();
+        self.anchor.set(self.anchor.get() + INTERVAL_US);
+        self.schedule();
+    }
+}
+
+impl time::AlarmClient for Central {
+    fn alarm(&self) {
+        if !self.running.get() {
+            return;
+        }
+        if self.in_event.take() {
+            let pdu = self.next_pdu();
+            let reply = self.radio.finish(&pdu);
+            self.reply(&reply);
+            self.end_event();
+        } else if self.radio.begin(self.channel.get(), ACCESS_ADDRESS) {
+            self.channels_used.borrow_mut().push(self.channel.get());
+            self.in_event.set(true);
+            let len = self.next_pdu().len();
+            let now = self.alarm.now();
+            self.alarm
+                .set_alarm(now, time::Ticks32::from(air_time_us(len)));
+        } else {
+            self.missed.set(self.missed.get() + 1);
+            self.end_event();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Values {
+    battery: Cell<u8>,
+    control: RefCell<Vec<u8>>,
+    notifications: RefCell<Vec<(usize, bool)>>,
+    connected: Cell<bool>,
+    disconnections: RefCell<Vec<u8>>,
+}
+
+impl GattServerClient for Values {
+    fn read(&self, key: usize, offset: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        let value = match key {
+            BATTERY => vec![self.battery.get()],
+            _ => self.control.borrow().clone(),
+        };
+        let len = value.len() - offset;
+        buf[..len].copy_from_slice(&value[offset..]);
+        Ok(len)
+    }
+
+    fn write(&self, key: usize, value: &[u8]) -> Result<(), u8> {
+        assert_eq!(key, CONTROL);
+        *self.control.borrow_mut() = value.to_vec();
+        Ok(())
+    }
+
+    fn notifications_changed(&self, key: usize, enabled: bool) {
+        self.notifications.borrow_mut().push((key, enabled));
+    }
+
+    fn connected(&self) {
+        self.connected.set(true);
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.connected.set(false);
+        self.disconnections.borrow_mut().push(reason);
+    }
+
+    fn notify_ready(&self) {}
+}
+
+fn request(sim: &Sim, central: &Central, pdu: &[u8]) -> Vec<u8> {
+    let received = central.att.borrow().len();
+    central.send_att(pdu);
+    assert!(sim.run(|| central.att.borrow().len() > received));
+    central.att.borrow().last().unwrap().clone()
+}
+
+#[test]
+fn ble_gatt_connection() {
+    let sim = Sim::new();
+    let radio = leak(FakeRadio {
+        client: OptionalCell::empty(),
+        state: Cell::new(RadioState::Off),
+        advertisements: RefCell::new(Vec::new()),
+    });
+    let ll_alarm = leak(VirtualMuxAlarm::new(sim.mux_alarm));
+    let link_layer = leak(LinkLayer::new(&*radio, &*ll_alarm));
+    ll_alarm.set_alarm_client(link_layer);
+    radio.set_connection_client(link_layer);
+    link_layer.set_address(ADDRESS);
+    let l2cap = leak(L2cap::new(link_layer));
+    link_layer.set_client(l2cap);
+    let gatt = leak(GattServer::new(l2cap, b"Tock"));
+    l2cap.set_client(gatt);
+    let values = leak(Values::default());
+    values.battery.set(87);
+    gatt.set_client(values);
+
+    assert_eq!(gatt.add_service(Uuid::Uuid16(0x180f)), Ok(7));
+    let battery = gatt
+        .add_characteristic(
+            Uuid::Uuid16(0x2a19),
+            properties::READ | properties::NOTIFY,
+            BATTERY,
+        )
+        .unwrap();
+    assert_eq!(battery, 9);
+    let control = gatt
+        .add_characteristic(
+            Uuid::Uuid128(CONTROL_UUID),
+            properties::READ | properties::WRITE,
+            CONTROL,
+        )
+        .unwrap();
+    assert_eq!(control, 12);
+
+    // Advertising on all three channels.
+    let adv_data = [0x02, 0x01, 0x06];
+    assert_eq!(
+        link_layer.set_advertising_data(&adv_data),
+        ReturnCode::SUCCESS
+    );
+    assert_eq!(link_layer.start_advertising(100), ReturnCode::SUCCESS);
+    assert!(sim.run(|| radio.advertisements.borrow().len() >= 3));
+    {
+        let advertisements = radio.advertisements.borrow();
+        let channels: Vec<u8> = advertisements.iter().map(|(c, _)| *c).collect();
+        assert_eq!(channels, vec![37, 38, 39]);
+        let pdu = &advertisements[0].1;
+        assert_eq!(pdu[0] & 0x0f, 0x00);
+        assert_eq!(pdu[1] as usize, 6 + adv_data.len());
+        assert_eq!(&pdu[2..8], &ADDRESS);
+        assert_eq!(&pdu[8..], &adv_data);
+    }
+
+    // A scan request is answered.
+    let count = radio.advertisements.borrow().len();
+    assert!(sim.run(|| radio.advertisements.borrow().len() > count));
+    let channel = radio.advertisements.borrow().last().unwrap().0;
+    let mut scan_req = vec![0x03 | 0xc0, 12];
+    scan_req.extend_from_slice(&CENTRAL_ADDRESS);
+    scan_req.extend_from_slice(&ADDRESS);
+    assert!(radio.begin(channel, ADVERTISING_ACCESS_ADDRESS));
+    let scan_rsp = radio.finish(&scan_req);
+    assert_eq!(scan_rsp[0] & 0x0f, 0x04);
+    assert_eq!(&scan_rsp[2..8], &ADDRESS);
+
+    // Connect.
+    let count = radio.advertisements.borrow().len();
+    assert!(sim.run(|| radio.advertisements.borrow().len() > count));
+    let central_alarm = leak(VirtualMuxAlarm::new(sim.mux_alarm));
+    let central = leak(Central::new(radio, central_alarm));
+    central_alarm.set_alarm_client(central);
+    central.connect(1000);
+    assert!(values.connected.get());
+    assert!(link_layer.is_connected());
+    assert_eq!(
+        gatt.add_service(Uuid::Uuid16(0x180a)),
+        Err(ReturnCode::EBUSY)
+    );
+
+    // Feature exchange.
+    central.send_control(&[0x08, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert!(sim.run(|| !central.control.borrow().is_empty()));
+    assert_eq!(central.control.borrow()[0][0], 0x09);
+
+    // Service discovery.
+    let services = request(&sim, central, &[0x10, 0x01, 0x00, 0xff, 0xff, 0x00, 0x28]);
+    assert_eq!(
+        services,
+        vec![
+            0x11, 6, 0x01, 0x00, 0x05, 0x00, 0x00, 0x18, 0x06, 0x00, 0x06, 0x00, 0x01, 0x18, 0x07,
+            0x00, 0x0c, 0x00, 0x0f, 0x18
+        ]
+    );
+    let characteristics = request(&sim, central, &[0x08, 0x07, 0x00, 0x0c, 0x00, 0x03, 0x28]);
+    assert_eq!(
+        characteristics,
+        vec![0x09, 7, 0x08, 0x00, 0x12, 0x09, 0x00, 0x19, 0x2a]
+    );
+    let characteristics = request(&sim, central, &[0x08, 0x0a, 0x00, 0x0c, 0x00, 0x03, 0x28]);
+    let mut expected = vec![0x09, 21, 0x0b, 0x00, 0x0a, 0x0c, 0x00];
+    expected.extend_from_slice(&CONTROL_UUID);
+    assert_eq!(characteristics, expected);
+
+    // Reading and writing.
+    assert_eq!(request(&sim, central, &[0x0a, 0x09, 0x00]), vec![0x0b, 87]);
+    assert_eq!(
+        request(&sim, central, &[0x0a, 0x03, 0x00]),
+        b"\x0bTock".to_vec()
+    );
+    assert_eq!(
+        request(&sim, central, &[0x12, 0x0c, 0x00, 1, 2, 3]),
+        vec![0x13]
+    );
+    assert_eq!(*values.control.borrow(), vec![1, 2, 3]);
+    assert_eq!(
+        request(&sim, central, &[0x12, 0x09, 0x00, 1]),
+        vec![0x01, 0x12, 0x09, 0x00, 0x03]
+    );
+    assert_eq!(
+        request(&sim, central, &[0x0a, 0x30, 0x00]),
+        vec![0x01, 0x0a, 0x30, 0x00, 0x01]
+    );
+
+    // Notifications.
+    assert_eq!(gatt.notify(battery), ReturnCode::EOFF);
+    assert_eq!(
+        request(&sim, central, &[0x12, 0x0a, 0x00, 0x01, 0x00]),
+        vec![0x13]
+    );
+    assert_eq!(*values.notifications.borrow(), vec![(BATTERY, true)]);
+    values.battery.set(86);
+    assert_eq!(gatt.notify(battery), ReturnCode::SUCCESS);
+    let received = central.att.borrow().len();
+    assert!(sim.run(|| central.att.borrow().len() > received));
+    assert_eq!(
+        *central.att.borrow().last().unwrap(),
+        vec![0x1b, 0x09, 0x00, 86]
+    );
+
+    // Moving to channels 0 to 7 only.
+    let map = [0xff, 0, 0, 0, 0];
+    let instant = central.counter.get() + 6;
+    let [instant_lo, instant_hi] = instant.to_le_bytes();
+    central.send_control(&[0x01, map[0], 0, 0, 0, 0, instant_lo, instant_hi]);
+    central.map_update.set(Some((instant, map)));
+    assert!(sim.run(|| central.counter.get() > instant + 10));
+    assert!(central
+        .channels_used
+        .borrow()
+        .iter()
+        .rev()
+        .take(10)
+        .all(|&c| c < 8));
+    assert_eq!(central.missed.get(), 0);
+    assert_eq!(request(&sim, central, &[0x0a, 0x09, 0x00]), vec![0x0b, 86]);
+
+    // The central disappears.
+    central.running.set(false);
+    let lost = sim.now_us();
+    assert!(sim.run(|| !values.connected.get()));
+    assert_eq!(
+        *values.disconnections.borrow(),
+        vec![reason::CONNECTION_TIMEOUT]
+    );
+    assert!(sim.now_us() - lost >= TIMEOUT_US - INTERVAL_US);
+    assert!(sim.now_us() - lost <= TIMEOUT_US + 2 * INTERVAL_US);
+
+    // Advertising resumes.
+    let count = radio.advertisements.borrow().len();
+    assert!(sim.run(|| radio.advertisements.borrow().len() > count));
+}
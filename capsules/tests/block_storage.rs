@@ -0,0 +1,486 @@
+//! Host tests for `hil::block_storage`: the adapters in
+//! `capsules::block_storage`, the write-back cache and the virtualizer.
+//!
+//! An SD card like `RamDisk` is used through `NonvolatileBlocks`, a
+//! `BlockCache` and a `MuxBlockStorage` with two regions, and a NOR flash
+//! like `MockFlash` through `FlashBlocks`.
+
+mod common;
+
+use capsules::block_cache::{BlockCache, CacheLine};
+use capsules::block_storage::{
+    BlockFlash, BlockNonvolatile, BlockPage, FlashBlocks, NonvolatileBlocks,
+};
+use capsules::virtual_block_storage::{MuxBlockStorage, VirtualBlockStorage};
+use common::ramdisk::{RamDisk, SECTOR_SIZE};
+use common::{leak, leak_buf, Device, Sim};
+use kernel::common::cells::TakeCell;
+use kernel::hil::block_storage::{self, BlockStorage, Geometry};
+use kernel::hil::flash::{self, Flash, HasClient};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ReturnCode;
+use std::cell::Cell;
+
+const FLASH_PAGE: usize = 256;
+
+type MockFlash = common::flash::MockFlash<FLASH_PAGE>;
+
+/// Records the result of the last block storage operation.
+struct Recorder {
+    result: Cell<Option<Result<(), ReturnCode>>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl Recorder {
+    fn new() -> &'static Recorder {
+        leak(Recorder {
+            result: Cell::new(None),
+            buffer: TakeCell::new(leak_buf(8 * SECTOR_SIZE)),
+        })
+    }
+}
+
+impl block_storage::Client for Recorder {
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        self.buffer.replace(buffer);
+        self.result.set(Some(result));
+    }
+
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>) {
+        self.buffer.replace(buffer);
+        self.result.set(Some(result));
+    }
+
+    fn erase_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn flush_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+}
+
+/// Records the result of the last flash operation.
+struct FlashRecorder {
+    result: Cell<Option<flash::Error>>,
+    page: TakeCell<'static, BlockPage<SECTOR_SIZE>>,
+}
+
+impl flash::Client<BlockFlash<'static, SECTOR_SIZE>> for FlashRecorder {
+    fn read_complete(&self, page: &'static mut BlockPage<SECTOR_SIZE>, error: flash::Error) {
+        self.page.replace(page);
+        self.result.set(Some(error));
+    }
+
+    fn write_complete(&self, page: &'static mut BlockPage<SECTOR_SIZE>, error: flash::Error) {
+        self.page.replace(page);
+        self.result.set(Some(error));
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        self.result.set(Some(error));
+    }
+}
+
+/// Records the result of the last nonvolatile storage operation.
+struct StorageRecorder {
+    length: Cell<Option<usize>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl NonvolatileStorageClient<'static> for StorageRecorder {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.length.set(Some(length));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.length.set(Some(length));
+    }
+}
+
+/// Runs requests to a block device built on `device`.
+struct Devices<'a> {
+    sim: &'a Sim,
+    device: &'static dyn Device,
+}
+
+impl<'a> Devices<'a> {
+    /// Run the devices and deferred calls until everything is idle.
+    fn pump(&self) {
+        self.sim.pump(&[self.device]);
+    }
+
+    fn complete(&self, recorder: &Recorder) -> Result<(), ReturnCode> {
+        self.pump();
+        recorder.result.take().expect("operation did not complete")
+    }
+
+    fn write(
+        &self,
+        device: &dyn BlockStorage<'static>,
+        recorder: &Recorder,
+        block: u32,
+        data: &[u8],
+    ) -> Result<(), ReturnCode> {
+        let block_size = device.geometry().block_size;
+        let buffer = recorder.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        match device.write(buffer, block, (data.len() / block_size) as u32) {
+            Ok(()) => self.complete(recorder),
+            Err((e, buffer)) => {
+                recorder.buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    fn read(
+        &self,
+        device: &dyn BlockStorage<'static>,
+        recorder: &Recorder,
+        block: u32,
+        count: u32,
+    ) -> Result<Vec<u8>, ReturnCode> {
+        let length = count as usize * device.geometry().block_size;
+        let buffer = recorder.buffer.take().unwrap();
+        match device.read(buffer, block, count) {
+            Ok(()) => {
+                self.complete(recorder)?;
+                Ok(recorder.buffer.map(|b| b[..length].to_vec()).unwrap())
+            }
+            Err((e, buffer)) => {
+                recorder.buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    fn erase(
+        &self,
+        device: &dyn BlockStorage<'static>,
+        recorder: &Recorder,
+        block: u32,
+        count: u32,
+    ) -> Result<(), ReturnCode> {
+        match device.erase(block, count) {
+            ReturnCode::SUCCESS => self.complete(recorder),
+            e => Err(e),
+        }
+    }
+
+    fn flush(
+        &self,
+        device: &dyn BlockStorage<'static>,
+        recorder: &Recorder,
+    ) -> Result<(), ReturnCode> {
+        match device.flush() {
+            ReturnCode::SUCCESS => self.complete(recorder),
+            e => Err(e),
+        }
+    }
+}
+
+fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length)
+        .map(|i| (i as u8).wrapping_mul(13).wrapping_add(seed))
+        .collect()
+}
+
+fn disk_block(disk: &RamDisk, block: u32) -> Vec<u8> {
+    let mut data = vec![0; SECTOR_SIZE];
+    disk.read_bytes(block as usize * SECTOR_SIZE, &mut data);
+    data
+}
+
+/// An SD card, cached and split into two regions.
+struct Sd<'a> {
+    d: Devices<'a>,
+    disk: &'static RamDisk,
+    cache: &'static BlockCache<'static>,
+    a: &'static VirtualBlockStorage<'static>,
+    b: &'static VirtualBlockStorage<'static>,
+    ra: &'static Recorder,
+    rb: &'static Recorder,
+}
+
+impl<'a> Sd<'a> {
+    fn new(sim: &'a Sim) -> Sd<'a> {
+        let disk = leak(RamDisk::new());
+        let sd = leak(NonvolatileBlocks::new(
+            disk,
+            SECTOR_SIZE,
+            1024,
+            leak_buf(SECTOR_SIZE),
+        ));
+        disk.set_client(sd);
+        let lines: &'static [CacheLine] = leak(<[CacheLine; 4]>::default());
+        let cache = leak(BlockCache::new(
+            sd,
+            lines,
+            leak_buf(4 * SECTOR_SIZE),
+            leak_buf(SECTOR_SIZE),
+            sim.deferred_caller,
+        ));
+        cache.initialize_callback_handle(sim.deferred_caller.register(cache).unwrap());
+        sd.set_client(cache);
+        let mux = leak(MuxBlockStorage::new(cache));
+        cache.set_client(mux);
+        let a = leak(VirtualBlockStorage::new(mux, 0, 256));
+        a.setup();
+        let b = leak(VirtualBlockStorage::new(mux, 256, 1024));
+        b.setup();
+        let ra = Recorder::new();
+        let rb = Recorder::new();
+        a.set_client(ra);
+        b.set_client(rb);
+        Sd {
+            d: Devices { sim, device: disk },
+            disk,
+            cache,
+            a,
+            b,
+            ra,
+            rb,
+        }
+    }
+}
+
+#[test]
+fn region_geometry() {
+    let sim = Sim::new();
+    let sd = Sd::new(&sim);
+    assert_eq!(
+        sd.a.geometry(),
+        Geometry {
+            block_size: SECTOR_SIZE,
+            block_count: 256,
+            erase_blocks: 1,
+            needs_erase: false,
+        }
+    );
+    assert_eq!(sd.b.geometry().block_count, 768);
+    assert_eq!(sd.b.geometry().size(), 768 * SECTOR_SIZE);
+}
+
+#[test]
+fn writes_stay_cached_until_flushed() {
+    let sim = Sim::new();
+    let Sd {
+        d,
+        disk,
+        cache,
+        a,
+        b,
+        ra,
+        rb,
+    } = Sd::new(&sim);
+    let one = pattern(SECTOR_SIZE, 1);
+    assert_eq!(d.write(a, ra, 3, &one), Ok(()));
+    assert_eq!(disk.writes.get(), 0);
+    assert_eq!(d.read(a, ra, 3, 1), Ok(one.clone()));
+    assert_eq!(cache.statistics(), (1, 0));
+    assert_eq!(d.read(b, rb, 3, 1), Ok(vec![0; SECTOR_SIZE]));
+    assert_eq!(cache.statistics(), (1, 1));
+
+    // Any region can flush the writes of the others
+    assert_eq!(d.flush(b, rb), Ok(()));
+    assert_eq!(disk_block(disk, 3), one);
+    assert_eq!(d.flush(a, ra), Err(ReturnCode::EALREADY));
+}
+
+#[test]
+fn regions_are_isolated() {
+    let sim = Sim::new();
+    let Sd {
+        d,
+        disk,
+        a,
+        b,
+        ra,
+        rb,
+        ..
+    } = Sd::new(&sim);
+    let one = pattern(SECTOR_SIZE, 1);
+    let two = pattern(SECTOR_SIZE, 2);
+    assert_eq!(d.write(a, ra, 3, &one), Ok(()));
+    assert_eq!(d.write(b, rb, 3, &two), Ok(()));
+    assert_eq!(d.flush(b, rb), Ok(()));
+    assert_eq!(disk_block(disk, 3), one);
+    assert_eq!(disk_block(disk, 259), two);
+    assert_eq!(d.read(a, ra, 256, 1), Err(ReturnCode::EINVAL));
+    assert_eq!(d.read(a, ra, 255, 2), Err(ReturnCode::EINVAL));
+    assert_eq!(d.read(a, ra, 0, 0), Err(ReturnCode::EINVAL));
+    assert_eq!(d.erase(b, rb, 0, 769), Err(ReturnCode::EINVAL));
+}
+
+#[test]
+fn full_cache_evicts_oldest_blocks() {
+    let sim = Sim::new();
+    let Sd { d, disk, a, ra, .. } = Sd::new(&sim);
+    let many = pattern(6 * SECTOR_SIZE, 3);
+    for i in 0..6 {
+        let block = &many[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE];
+        assert_eq!(d.write(a, ra, 10 + i as u32, block), Ok(()));
+    }
+    assert_eq!(disk.writes.get(), 2);
+    assert_eq!(disk_block(disk, 10), many[..SECTOR_SIZE].to_vec());
+    assert_eq!(d.flush(a, ra), Ok(()));
+    assert_eq!(disk.writes.get(), 6);
+    assert_eq!(d.read(a, ra, 10, 6), Ok(many.clone()));
+    for i in 0..6 {
+        assert_eq!(
+            disk_block(disk, 10 + i),
+            many[i as usize * SECTOR_SIZE..(i as usize + 1) * SECTOR_SIZE]
+        );
+    }
+}
+
+#[test]
+fn erase_drops_cached_writes() {
+    let sim = Sim::new();
+    let Sd { d, disk, a, ra, .. } = Sd::new(&sim);
+    assert_eq!(d.write(a, ra, 20, &pattern(SECTOR_SIZE, 1)), Ok(()));
+    assert_eq!(d.erase(a, ra, 20, 2), Ok(()));
+    assert_eq!(d.read(a, ra, 20, 2), Ok(vec![0xFF; 2 * SECTOR_SIZE]));
+    assert_eq!(d.flush(a, ra), Err(ReturnCode::EALREADY));
+    assert_eq!(disk_block(disk, 20), vec![0xFF; SECTOR_SIZE]);
+}
+
+#[test]
+fn regions_queue_requests() {
+    let sim = Sim::new();
+    let Sd {
+        d, a, b, ra, rb, ..
+    } = Sd::new(&sim);
+    let two = pattern(SECTOR_SIZE, 2);
+    let buffer = ra.buffer.take().unwrap();
+    buffer[..SECTOR_SIZE].copy_from_slice(&two);
+    assert!(a.write(buffer, 30, 1).is_ok());
+    let buffer = rb.buffer.take().unwrap();
+    assert!(b.read(buffer, 30, 1).is_ok());
+    assert_eq!(b.flush(), ReturnCode::EBUSY);
+    assert_eq!(d.complete(ra), Ok(()));
+    assert_eq!(rb.result.take(), Some(Ok(())));
+    assert_eq!(d.read(a, ra, 30, 1), Ok(two));
+}
+
+#[test]
+fn flash_on_a_region() {
+    let sim = Sim::new();
+    let Sd { d, a, .. } = Sd::new(&sim);
+    let one = pattern(SECTOR_SIZE, 1);
+    let page_flash = leak(BlockFlash::<SECTOR_SIZE>::new(a, leak_buf(SECTOR_SIZE)));
+    a.set_client(page_flash);
+    let flash_recorder = leak(FlashRecorder {
+        result: Cell::new(None),
+        page: TakeCell::new(leak(BlockPage::default())),
+    });
+    page_flash.set_client(flash_recorder);
+    assert_eq!(page_flash.erase_page(40), ReturnCode::SUCCESS);
+    d.pump();
+    assert_eq!(
+        flash_recorder.result.take(),
+        Some(flash::Error::CommandComplete)
+    );
+    let page = flash_recorder.page.take().unwrap();
+    page.0.copy_from_slice(&one);
+    assert!(page_flash.write_page(40, page).is_ok());
+    d.pump();
+    assert_eq!(
+        flash_recorder.result.take(),
+        Some(flash::Error::CommandComplete)
+    );
+    let page = flash_recorder.page.take().unwrap();
+    page.0 = [0; SECTOR_SIZE];
+    assert!(page_flash.read_page(40, page).is_ok());
+    d.pump();
+    assert_eq!(
+        flash_recorder.result.take(),
+        Some(flash::Error::CommandComplete)
+    );
+    assert_eq!(flash_recorder.page.map(|page| page.0.to_vec()), Some(one));
+    assert!(page_flash
+        .read_page(256, flash_recorder.page.take().unwrap())
+        .is_err());
+}
+
+#[test]
+fn nonvolatile_storage_on_a_region() {
+    let sim = Sim::new();
+    let Sd { d, disk, b, rb, .. } = Sd::new(&sim);
+    let storage = leak(BlockNonvolatile::new(b));
+    b.set_client(storage);
+    let storage_recorder = leak(StorageRecorder {
+        length: Cell::new(None),
+        buffer: TakeCell::new(leak_buf(2 * SECTOR_SIZE)),
+    });
+    storage.set_client(storage_recorder);
+    let data = pattern(2 * SECTOR_SIZE, 4);
+    let buffer = storage_recorder.buffer.take().unwrap();
+    buffer.copy_from_slice(&data);
+    assert_eq!(
+        storage.write(buffer, 100 * SECTOR_SIZE, 2 * SECTOR_SIZE),
+        ReturnCode::SUCCESS
+    );
+    d.pump();
+    assert_eq!(storage_recorder.length.take(), Some(2 * SECTOR_SIZE));
+    let buffer = storage_recorder.buffer.take().unwrap();
+    buffer.iter_mut().for_each(|b| *b = 0);
+    assert_eq!(
+        storage.read(buffer, 100 * SECTOR_SIZE, 2 * SECTOR_SIZE),
+        ReturnCode::SUCCESS
+    );
+    d.pump();
+    assert_eq!(storage_recorder.length.take(), Some(2 * SECTOR_SIZE));
+    assert_eq!(
+        storage_recorder.buffer.map(|b| b.to_vec()),
+        Some(data.clone())
+    );
+    assert_eq!(
+        storage.read(storage_recorder.buffer.take().unwrap(), 100, SECTOR_SIZE),
+        ReturnCode::EINVAL
+    );
+    b.set_client(rb);
+    assert_eq!(d.flush(b, rb), Ok(()));
+    assert_eq!(disk_block(disk, 356), data[..SECTOR_SIZE].to_vec());
+}
+
+#[test]
+fn nor_flash_blocks() {
+    let sim = Sim::new();
+    let flash = leak(MockFlash::new(16));
+    let d = Devices {
+        sim: &sim,
+        device: flash,
+    };
+    let nor = leak(FlashBlocks::new(flash, leak(BlockPage::default()), 16));
+    flash.set_client(nor);
+    let rn = Recorder::new();
+    nor.set_client(rn);
+    assert_eq!(
+        nor.geometry(),
+        Geometry {
+            block_size: FLASH_PAGE,
+            block_count: 16,
+            erase_blocks: 1,
+            needs_erase: true,
+        }
+    );
+    let data = pattern(3 * FLASH_PAGE, 5);
+    assert_eq!(d.write(nor, rn, 2, &data), Ok(()));
+    assert_eq!(d.read(nor, rn, 2, 3), Ok(data.clone()));
+    assert_eq!(
+        flash.pages.borrow()[3].to_vec(),
+        data[FLASH_PAGE..2 * FLASH_PAGE].to_vec()
+    );
+
+    // Without an erase only bits that are set can be cleared
+    assert_eq!(d.write(nor, rn, 2, &[0x0F; FLASH_PAGE]), Ok(()));
+    let expected: Vec<u8> = data[..FLASH_PAGE].iter().map(|b| b & 0x0F).collect();
+    assert_eq!(d.read(nor, rn, 2, 1), Ok(expected));
+    assert_eq!(d.erase(nor, rn, 2, 2), Ok(()));
+    assert_eq!(d.read(nor, rn, 2, 2), Ok(vec![0xFF; 2 * FLASH_PAGE]));
+    assert_eq!(d.read(nor, rn, 15, 2), Err(ReturnCode::EINVAL));
+}
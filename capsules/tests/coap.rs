@@ -0,0 +1,423 @@
+//! CoAP requests, block-wise transfers, observation and retransmission
+//! between two nodes over loopback radios.
+
+mod common;
+
+use capsules::net::coap::driver::{App, CoapDriver};
+use capsules::net::coap::message::{code, Message};
+use capsules::net::coap::{Coap, CoapClient, CoapServerClient, COAP_PORT};
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::udp::udp_send::UDPSender;
+use capsules::virtual_alarm::VirtualMuxAlarm;
+use common::process::TestProcess;
+use common::{leak, leak_buf, link_local, Node, Sim, VAlarm};
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::create_capability;
+use kernel::hil::rng::Random;
+use kernel::hil::time::Alarm;
+use kernel::procs::ProcessType;
+use kernel::{Driver, Grant, Kernel, ReturnCode};
+use std::cell::{Cell, RefCell};
+
+const TX_LEN: usize = 256;
+const LARGE_LEN: usize = 600;
+
+const SMALL: usize = 0;
+const LARGE: usize = 1;
+
+struct Resources {
+    temperature: Cell<u8>,
+    large: Vec<u8>,
+    updates: RefCell<Vec<(u8, Vec<u8>)>>,
+}
+
+impl CoapServerClient for Resources {
+    fn resource(&self, request: &Message) -> Option<usize> {
+        if request.path_matches(b"sensors/temp") {
+            Some(SMALL)
+        } else if request.path_matches(b"large") {
+            Some(LARGE)
+        } else {
+            None
+        }
+    }
+
+    fn get(&self, resource: usize, buf: &mut [u8]) -> Result<usize, u8> {
+        match resource {
+            SMALL => {
+                buf[0] = self.temperature.get();
+                Ok(1)
+            }
+            _ => {
+                buf[..self.large.len()].copy_from_slice(&self.large);
+                Ok(self.large.len())
+            }
+        }
+    }
+
+    fn update(&self, _resource: usize, method: u8, payload: &[u8]) -> u8 {
+        self.updates.borrow_mut().push((method, payload.to_vec()));
+        code::CHANGED
+    }
+}
+
+#[derive(Default)]
+struct Responses {
+    received: RefCell<Vec<(ReturnCode, u8, usize, Vec<u8>, bool)>>,
+}
+
+impl Responses {
+    fn last_done(&self) -> bool {
+        self.received.borrow().last().map_or(false, |r| !r.4)
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (_, _, offset, payload, _) in self.received.borrow().iter() {
+            assert_eq!(*offset, body.len());
+            body.extend_from_slice(payload);
+        }
+        body
+    }
+}
+
+impl CoapClient for Responses {
+    fn response(&self, result: ReturnCode, code: u8, offset: usize, payload: &[u8], more: bool) {
+        self.received
+            .borrow_mut()
+            .push((result, code, offset, payload.to_vec(), more));
+    }
+}
+
+struct AlwaysZero;
+
+impl<'a> Random<'a> for AlwaysZero {
+    fn initialize(&'a self) {}
+    fn reseed(&self, _seed: u32) {}
+    fn random(&self) -> u32 {
+        0
+    }
+}
+
+fn endpoint(sim: &Sim, node: &Node, seed: u32) -> &'static Coap<'static, VAlarm> {
+    let alarm = leak(VirtualMuxAlarm::new(sim.mux_alarm));
+    let coap = leak(Coap::new(
+        node.udp_sender,
+        alarm,
+        node.net_cap,
+        LeasableBuffer::new(leak_buf(TX_LEN)),
+        leak_buf(TX_LEN),
+        leak_buf(LARGE_LEN),
+    ));
+    coap.set_seed(seed);
+    node.udp_sender.set_client(coap);
+    node.udp_receiver.set_client(coap);
+    alarm.set_alarm_client(coap);
+    node.bind(COAP_PORT);
+    coap
+}
+
+/// A client and a server endpoint on two nodes.
+struct Network {
+    sim: Sim,
+    client: &'static Coap<'static, VAlarm>,
+    server: &'static Coap<'static, VAlarm>,
+    server_addr: IPAddr,
+    responses: &'static Responses,
+    resources: &'static Resources,
+}
+
+fn network() -> Network {
+    let sim = Sim::new();
+    sim.medium.set_delay_us(200);
+    let node1 = Node::new(&sim, 0x0001, 0x0002);
+    let node2 = Node::new(&sim, 0x0002, 0x0001);
+
+    let client = endpoint(&sim, &node1, 0x1000);
+    let server = endpoint(&sim, &node2, 0x2000);
+    let responses = leak(Responses::default());
+    let resources = leak(Resources {
+        temperature: Cell::new(21),
+        large: (0..LARGE_LEN).map(|i| i as u8).collect(),
+        updates: RefCell::new(Vec::new()),
+    });
+    client.set_client(responses);
+    server.set_server_client(resources);
+    sim.run(|| false);
+    Network {
+        sim: sim,
+        client: client,
+        server: server,
+        server_addr: link_local(node2.address),
+        responses: responses,
+        resources: resources,
+    }
+}
+
+impl Network {
+    fn request(&self, method: u8, path: &[u8], payload: &[u8], confirmable: bool) -> ReturnCode {
+        self.client.request(
+            self.server_addr,
+            COAP_PORT,
+            method,
+            path,
+            payload,
+            confirmable,
+            false,
+        )
+    }
+
+    fn observe(&self, path: &[u8]) -> ReturnCode {
+        self.client.request(
+            self.server_addr,
+            COAP_PORT,
+            code::GET,
+            path,
+            &[],
+            true,
+            true,
+        )
+    }
+}
+
+#[test]
+fn requests_and_responses() {
+    let net = network();
+    let responses = net.responses;
+
+    // A confirmable GET answered with a piggybacked response.
+    assert_eq!(
+        net.request(code::GET, b"sensors/temp", &[], true),
+        ReturnCode::SUCCESS
+    );
+    assert_eq!(
+        net.request(code::GET, b"large", &[], true),
+        ReturnCode::EBUSY
+    );
+    assert!(net.sim.run(|| responses.last_done()));
+    assert_eq!(
+        responses.received.replace(Vec::new()),
+        vec![(ReturnCode::SUCCESS, code::CONTENT, 0, vec![21], false)]
+    );
+    assert!(net.client.is_idle());
+
+    // Unknown resources.
+    net.request(code::GET, b"missing", &[], false);
+    assert!(net.sim.run(|| responses.last_done()));
+    assert_eq!(responses.received.borrow()[0].1, code::NOT_FOUND);
+    responses.received.replace(Vec::new());
+
+    // Updates are passed to the server client.
+    net.request(code::PUT, b"sensors/temp", b"22", true);
+    assert!(net.sim.run(|| responses.last_done()));
+    assert_eq!(responses.received.borrow()[0].1, code::CHANGED);
+    assert_eq!(
+        *net.resources.updates.borrow(),
+        vec![(code::PUT, b"22".to_vec())]
+    );
+}
+
+#[test]
+fn confirmable_requests_are_retransmitted() {
+    let net = network();
+    let (sim, responses) = (&net.sim, net.responses);
+
+    // Requests fail once the server never answers, after retransmissions
+    // with an increasing timeout.
+    sim.medium.set_random(leak(AlwaysZero));
+    assert_eq!(sim.medium.set_loss(100), ReturnCode::SUCCESS);
+    let start = sim.now_us();
+    net.request(code::GET, b"sensors/temp", &[], true);
+    assert!(sim.run(|| responses.last_done()));
+    assert_eq!(
+        responses.received.replace(Vec::new()),
+        vec![(ReturnCode::ENOACK, code::EMPTY, 0, vec![], false)]
+    );
+    // 2 + 4 + 8 + 16 + 32 seconds.
+    assert!(sim.now_us() - start >= 62_000_000);
+
+    // A lost request is recovered by a retransmission.
+    let start = sim.now_us();
+    net.request(code::GET, b"sensors/temp", &[], true);
+    sim.run(|| sim.now_us() - start >= 1_000_000);
+    assert!(responses.received.borrow().is_empty());
+    assert_eq!(sim.medium.set_loss(0), ReturnCode::SUCCESS);
+    assert!(sim.run(|| responses.last_done()));
+    assert_eq!(responses.received.borrow()[0].0, ReturnCode::SUCCESS);
+    assert_eq!(responses.received.borrow()[0].3, vec![21]);
+}
+
+#[test]
+fn blockwise_transfer() {
+    let net = network();
+    let responses = net.responses;
+
+    // A representation larger than a datagram arrives in several blocks.
+    net.request(code::GET, b"large", &[], true);
+    assert!(net.sim.run(|| responses.last_done()));
+    assert!(responses.received.borrow().len() > 1);
+    assert!(
+        responses.received.borrow()[..responses.received.borrow().len() - 1]
+            .iter()
+            .all(|r| r.4)
+    );
+    assert_eq!(responses.body(), net.resources.large);
+    assert!(net.client.is_idle());
+}
+
+#[test]
+fn observe() {
+    let net = network();
+    let (sim, responses) = (&net.sim, net.responses);
+
+    // The registration response, then one notification per change, until
+    // the client cancels.
+    assert_eq!(net.observe(b"sensors/temp"), ReturnCode::SUCCESS);
+    assert!(sim.run(|| responses.received.borrow().len() == 1));
+    assert!(!net.client.is_idle());
+    for temperature in 22..25 {
+        net.resources.temperature.set(temperature);
+        net.server.notify(SMALL);
+        assert!(sim.run(|| responses.received.borrow().last().unwrap().3 == vec![temperature]));
+    }
+    assert_eq!(responses.received.borrow().len(), 4);
+    net.client.cancel();
+    assert!(net.client.is_idle());
+
+    // The next notification is rejected, which removes the observer.
+    net.server.notify(SMALL);
+    sim.run(|| false);
+    net.server.notify(SMALL);
+    sim.run(|| false);
+    assert_eq!(responses.received.borrow().len(), 4);
+}
+
+type Processes = (&'static Kernel, Vec<&'static TestProcess>);
+
+fn coap_driver(
+    coap: &'static Coap<'static, VAlarm>,
+    processes: &Processes,
+) -> &'static CoapDriver<'static, VAlarm> {
+    let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+    let grant: Grant<App> = processes.0.create_grant(&grant_cap);
+    leak(CoapDriver::new(coap, grant))
+}
+
+/// Config buffer contents for a request to `path` on `net`'s server.
+fn request_config(net: &Network, path: &[u8]) -> Vec<u8> {
+    let mut cfg = net.server_addr.0.to_vec();
+    cfg.extend_from_slice(&COAP_PORT.to_ne_bytes());
+    cfg.extend_from_slice(path);
+    cfg
+}
+
+const GET_CONFIRMABLE_OBSERVE: usize = code::GET as usize | 1 << 8 | 1 << 9;
+
+#[test]
+fn driver_rejects_path_collisions() {
+    let net = network();
+    let processes = TestProcess::create(2);
+    let driver = coap_driver(net.server, &processes);
+    net.server.set_server_client(driver);
+    let (a, b) = (processes.1[0], processes.1[1]);
+
+    let register = |process: &TestProcess, path: &[u8]| {
+        let appid = process.appid();
+        driver.allow(appid, 2, Some(process.slice(path)));
+        driver.command(1, 0, 0, appid)
+    };
+    assert_eq!(
+        register(a, b"sensors/temp"),
+        ReturnCode::SuccessWithValue { value: 0 }
+    );
+    assert_eq!(register(b, b"sensors/temp"), ReturnCode::EALREADY);
+    assert_eq!(register(a, b"sensors/temp"), ReturnCode::EALREADY);
+    assert_eq!(
+        register(b, b"sensors/humidity"),
+        ReturnCode::SuccessWithValue { value: 0 }
+    );
+
+    // The kernel serves GET requests from the representation.
+    driver.allow(a.appid(), 3, Some(a.slice(&[21])));
+    net.request(code::GET, b"sensors/temp", &[], true);
+    assert!(net.sim.run(|| net.responses.last_done()));
+    assert_eq!(
+        net.responses.received.replace(Vec::new()),
+        vec![(ReturnCode::SUCCESS, code::CONTENT, 0, vec![21], false)]
+    );
+
+    // Only registered resources can be notified.
+    assert_eq!(driver.command(3, 0, 0, a.appid()), ReturnCode::SUCCESS);
+    assert_eq!(driver.command(3, 1, 0, a.appid()), ReturnCode::EINVAL);
+    assert_eq!(driver.command(3, 9, 0, a.appid()), ReturnCode::EINVAL);
+    assert_eq!(driver.command(2, 0, 0, b.appid()), ReturnCode::SUCCESS);
+    assert_eq!(driver.command(3, 0, 0, b.appid()), ReturnCode::EINVAL);
+
+    // Unregistering frees the path.
+    assert_eq!(driver.command(2, 0, 0, a.appid()), ReturnCode::SUCCESS);
+    assert_eq!(
+        register(b, b"sensors/temp"),
+        ReturnCode::SuccessWithValue { value: 0 }
+    );
+}
+
+#[test]
+fn driver_releases_exchange_of_exited_process() {
+    let net = network();
+    let processes = TestProcess::create(2);
+    let driver = coap_driver(net.client, &processes);
+    net.client.set_client(driver);
+    let (a, b) = (processes.1[0], processes.1[1]);
+    for process in processes.1.iter() {
+        let cfg = process.slice(&request_config(&net, b"sensors/temp"));
+        driver.allow(process.appid(), 2, Some(cfg));
+    }
+
+    // An observation stays open, so other processes have to wait.
+    assert_eq!(
+        driver.command(4, GET_CONFIRMABLE_OBSERVE, 0, a.appid()),
+        ReturnCode::SUCCESS
+    );
+    net.sim.run(|| false);
+    assert!(!net.client.is_idle());
+    assert_eq!(
+        driver.command(4, GET_CONFIRMABLE_OBSERVE, 0, b.appid()),
+        ReturnCode::EBUSY
+    );
+    assert_eq!(driver.command(5, 0, 0, b.appid()), ReturnCode::EALREADY);
+
+    // Once its owner exits, the next request ends the observation.
+    a.restart();
+    assert_eq!(
+        driver.command(4, GET_CONFIRMABLE_OBSERVE, 0, b.appid()),
+        ReturnCode::SUCCESS
+    );
+    net.sim.run(|| false);
+    assert_eq!(driver.command(5, 0, 0, b.appid()), ReturnCode::SUCCESS);
+    assert!(net.client.is_idle());
+}
+
+#[test]
+fn driver_ends_observation_when_observer_exits() {
+    let net = network();
+    let processes = TestProcess::create(1);
+    let driver = coap_driver(net.client, &processes);
+    net.client.set_client(driver);
+    let a = processes.1[0];
+    let cfg = a.slice(&request_config(&net, b"sensors/temp"));
+    driver.allow(a.appid(), 2, Some(cfg));
+
+    assert_eq!(
+        driver.command(4, GET_CONFIRMABLE_OBSERVE, 0, a.appid()),
+        ReturnCode::SUCCESS
+    );
+    net.sim.run(|| false);
+    assert!(!net.client.is_idle());
+
+    // A notification for a process that has exited ends the observation.
+    a.restart();
+    net.server.notify(SMALL);
+    net.sim.run(|| false);
+    assert!(net.client.is_idle());
+}
@@ -0,0 +1,245 @@
+//! In-memory `hil::flash` devices for testing storage capsules.
+//!
+//! `MockFlash` is a NOR flash, where writes can only clear bits, that counts
+//! erases per page and can be made to fail: pages can lose bits when
+//! written, refuse to erase, or have a write cut short by a power loss.
+//! `MappedFlash` is also readable as memory, like internal flash, for
+//! capsules that read their volume directly.
+
+use super::{leak_buf, Device};
+use capsules::block_storage::BlockPage;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::flash::{self, Flash, HasClient};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+enum FlashOp<const PAGE: usize> {
+    Read(&'static mut BlockPage<PAGE>),
+    Write(&'static mut BlockPage<PAGE>),
+    Erase(flash::Error),
+}
+
+impl<const PAGE: usize> FlashOp<PAGE> {
+    fn complete<F: Flash<Page = BlockPage<PAGE>>>(self, client: &dyn flash::Client<F>) {
+        match self {
+            FlashOp::Read(page) => client.read_complete(page, flash::Error::CommandComplete),
+            FlashOp::Write(page) => client.write_complete(page, flash::Error::CommandComplete),
+            FlashOp::Erase(error) => client.erase_complete(error),
+        }
+    }
+}
+
+/// NOR flash that completes operations when `run` is called.
+pub struct MockFlash<const PAGE: usize> {
+    pub pages: RefCell<Vec<[u8; PAGE]>>,
+    pub erases: RefCell<Vec<u32>>,
+    /// Pages whose last byte can't be written
+    pub worn: RefCell<HashSet<usize>>,
+    /// Pages that fail to erase
+    pub stuck: RefCell<HashSet<usize>>,
+    /// Only write the first half of the next page
+    pub tear: Cell<bool>,
+    /// Pages below this one must not be used
+    reserved: usize,
+    pending: RefCell<Option<FlashOp<PAGE>>>,
+    client: OptionalCell<&'static dyn flash::Client<MockFlash<PAGE>>>,
+}
+
+impl<const PAGE: usize> MockFlash<PAGE> {
+    pub fn new(pages: usize) -> MockFlash<PAGE> {
+        MockFlash::with_reserved(pages, 0)
+    }
+
+    /// A flash whose first `reserved` pages belong to something else, so
+    /// accessing them fails the test.
+    pub fn with_reserved(pages: usize, reserved: usize) -> MockFlash<PAGE> {
+        MockFlash {
+            pages: RefCell::new(vec![[0xFF; PAGE]; pages]),
+            erases: RefCell::new(vec![0; pages]),
+            worn: RefCell::new(HashSet::new()),
+            stuck: RefCell::new(HashSet::new()),
+            tear: Cell::new(false),
+            reserved,
+            pending: RefCell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// The lowest and highest erase counts of the pages that may be used.
+    pub fn erase_counts(&self) -> (u32, u32) {
+        let erases = self.erases.borrow();
+        let used = &erases[self.reserved..];
+        (*used.iter().min().unwrap(), *used.iter().max().unwrap())
+    }
+
+    /// Drops the pending operation without calling the client, as if power
+    /// was lost. Returns whether that was a write, which has already reached
+    /// the flash.
+    pub fn cut_power(&self) -> bool {
+        matches!(self.pending.borrow_mut().take(), Some(FlashOp::Write(_)))
+    }
+
+    fn start(&self, page_number: usize, op: FlashOp<PAGE>) {
+        assert!(self.pending.borrow().is_none(), "flash is busy");
+        assert!(page_number >= self.reserved, "access to a reserved page");
+        *self.pending.borrow_mut() = Some(op);
+    }
+}
+
+impl<const PAGE: usize> Device for MockFlash<PAGE> {
+    fn run(&self) -> bool {
+        let pending = self.pending.borrow_mut().take();
+        match pending {
+            Some(op) => {
+                let client = self.client.unwrap_or_else(|| panic!("no flash client"));
+                op.complete(client);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<const PAGE: usize> Flash for MockFlash<PAGE> {
+    type Page = BlockPage<PAGE>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        buf.0 = self.pages.borrow()[page_number];
+        self.start(page_number, FlashOp::Read(buf));
+        Ok(())
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        let length = if self.tear.take() {
+            PAGE / 2
+        } else if self.worn.borrow().contains(&page_number) {
+            PAGE - 1
+        } else {
+            PAGE
+        };
+        for (stored, b) in self.pages.borrow_mut()[page_number][..length]
+            .iter_mut()
+            .zip(buf.0.iter())
+        {
+            *stored &= *b;
+        }
+        self.start(page_number, FlashOp::Write(buf));
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let error = if self.stuck.borrow().contains(&page_number) {
+            flash::Error::FlashError
+        } else {
+            self.pages.borrow_mut()[page_number] = [0xFF; PAGE];
+            self.erases.borrow_mut()[page_number] += 1;
+            flash::Error::CommandComplete
+        };
+        self.start(page_number, FlashOp::Erase(error));
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<C: flash::Client<MockFlash<PAGE>>, const PAGE: usize> HasClient<'static, C>
+    for MockFlash<PAGE>
+{
+    fn set_client(&'static self, client: &'static C) {
+        self.client.set(client);
+    }
+}
+
+/// Memory-mapped flash. Like on hardware, capsules read the volume directly
+/// and change it through the flash interface.
+pub struct MappedFlash<const PAGE: usize> {
+    memory: *mut u8,
+    size: usize,
+    pending: RefCell<Option<FlashOp<PAGE>>>,
+    client: OptionalCell<&'static dyn flash::Client<MappedFlash<PAGE>>>,
+}
+
+impl<const PAGE: usize> MappedFlash<PAGE> {
+    /// Flash of `size` bytes starting on a page boundary, and the volume
+    /// mapping it.
+    pub fn new(size: usize) -> (MappedFlash<PAGE>, &'static [u8]) {
+        let memory = leak_buf(size + PAGE);
+        let offset = PAGE - memory.as_ptr() as usize % PAGE;
+        let memory = memory[offset..offset + size].as_mut_ptr();
+        let flash = MappedFlash {
+            memory,
+            size,
+            pending: RefCell::new(None),
+            client: OptionalCell::empty(),
+        };
+        (flash, unsafe { std::slice::from_raw_parts(memory, size) })
+    }
+
+    fn page(&self, page_number: usize) -> *mut u8 {
+        let offset = page_number * PAGE - self.memory as usize;
+        assert!(offset + PAGE <= self.size, "page outside of the flash");
+        unsafe { self.memory.add(offset) }
+    }
+
+    fn start(&self, op: FlashOp<PAGE>) {
+        assert!(self.pending.borrow().is_none(), "flash is busy");
+        *self.pending.borrow_mut() = Some(op);
+    }
+}
+
+impl<const PAGE: usize> Device for MappedFlash<PAGE> {
+    fn run(&self) -> bool {
+        let pending = self.pending.borrow_mut().take();
+        match pending {
+            Some(op) => {
+                let client = self.client.unwrap_or_else(|| panic!("no flash client"));
+                op.complete(client);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<const PAGE: usize> Flash for MappedFlash<PAGE> {
+    type Page = BlockPage<PAGE>;
+
+    fn read_page(
+        &self,
+        _page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        Err((ReturnCode::ENOSUPPORT, buf))
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        unsafe { std::ptr::copy_nonoverlapping(buf.0.as_ptr(), self.page(page_number), PAGE) };
+        self.start(FlashOp::Write(buf));
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        unsafe { std::ptr::write_bytes(self.page(page_number), 0xFF, PAGE) };
+        self.start(FlashOp::Erase(flash::Error::CommandComplete));
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<C: flash::Client<MappedFlash<PAGE>>, const PAGE: usize> HasClient<'static, C>
+    for MappedFlash<PAGE>
+{
+    fn set_client(&'static self, client: &'static C) {
+        self.client.set(client);
+    }
+}
@@ -0,0 +1,265 @@
+//! An in-memory `hil::kv_system` for testing capsules layered on top of it.
+
+use super::Device;
+use capsules::sip_hash::SipHasher24;
+use core::hash::Hasher;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+pub type Key = [u8; 8];
+
+enum Pending {
+    Generate(&'static mut [u8], &'static mut Key),
+    Get(Result<(), ReturnCode>, &'static mut Key, &'static mut [u8]),
+    Append(Result<(), ReturnCode>, &'static mut Key, &'static mut [u8]),
+    Invalidate(Result<(), ReturnCode>, &'static mut Key),
+    NextKey(Result<(), ReturnCode>, &'static mut Key, usize),
+    Stats(kv_system::StorageStats),
+    Check,
+    Begin,
+    Stage(Result<(), ReturnCode>, &'static mut Key, &'static mut [u8]),
+    Commit,
+    Abort,
+}
+
+/// An in-memory KV system that completes operations when `run` is called.
+///
+/// Keys are hashed with `SipHasher24`, and an existing key makes
+/// `append_key` fail with `ENOSUPPORT` like `capsules::tickv`. Staged values
+/// count towards `capacity` until the transaction ends.
+pub struct MockKV {
+    pub entries: RefCell<Vec<(Key, Vec<u8>)>>,
+    /// The values staged in the open transaction, if any.
+    pub staged: RefCell<Option<Vec<(Key, Vec<u8>)>>>,
+    pending: RefCell<Option<Pending>>,
+    pub client: OptionalCell<&'static dyn kv_system::Client<Key>>,
+    /// Hash every key to the same value.
+    pub collide: Cell<bool>,
+    pub capacity: Cell<usize>,
+}
+
+impl MockKV {
+    pub fn new() -> MockKV {
+        MockKV {
+            entries: RefCell::new(Vec::new()),
+            staged: RefCell::new(None),
+            pending: RefCell::new(None),
+            client: OptionalCell::empty(),
+            collide: Cell::new(false),
+            capacity: Cell::new(16),
+        }
+    }
+
+    fn position(&self, key: &Key) -> Option<usize> {
+        self.entries.borrow().iter().position(|(k, _)| k == key)
+    }
+
+    fn used(&self) -> usize {
+        self.entries.borrow().len() + self.staged.borrow().as_ref().map_or(0, Vec::len)
+    }
+}
+
+impl Device for MockKV {
+    /// Completes all pending operations, including ones started from the
+    /// callbacks.
+    fn run(&self) -> bool {
+        let mut ran = false;
+        loop {
+            let pending = self.pending.borrow_mut().take();
+            let client = self.client.map(|client| *client).unwrap();
+            match pending {
+                None => return ran,
+                Some(Pending::Generate(unhashed_key, key)) => {
+                    client.generate_key_complete(Ok(()), unhashed_key, key)
+                }
+                Some(Pending::Get(result, key, buf)) => client.get_value_complete(result, key, buf),
+                Some(Pending::Append(result, key, value)) => {
+                    client.append_key_complete(result, key, value)
+                }
+                Some(Pending::Invalidate(result, key)) => {
+                    client.invalidate_key_complete(result, key)
+                }
+                Some(Pending::NextKey(result, key, cursor)) => {
+                    client.next_key_complete(result, key, cursor)
+                }
+                Some(Pending::Stats(stats)) => client.storage_stats_complete(Ok(()), stats),
+                Some(Pending::Check) => client.check_consistency_complete(Ok(()), 0, 0),
+                Some(Pending::Begin) => client.begin_transaction_complete(Ok(())),
+                Some(Pending::Stage(result, key, value)) => {
+                    client.stage_key_complete(result, key, value)
+                }
+                Some(Pending::Commit) => client.commit_transaction_complete(Ok(())),
+                Some(Pending::Abort) => client.abort_transaction_complete(Ok(())),
+            }
+            ran = true;
+        }
+    }
+}
+
+impl<'a> KVSystem<'a> for MockKV {
+    type K = Key;
+
+    fn set_client(&self, _client: &'a dyn kv_system::Client<Key>) {}
+
+    fn generate_key(
+        &self,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut Key,
+    ) -> Result<(), (&'static mut [u8], &'static mut Key, ReturnCode)> {
+        if self.collide.get() {
+            *key_buf = [0; 8];
+        } else {
+            let mut hasher = SipHasher24::new();
+            hasher.write(unhashed_key);
+            *key_buf = hasher.finish().to_le_bytes();
+        }
+        *self.pending.borrow_mut() = Some(Pending::Generate(unhashed_key, key_buf));
+        Ok(())
+    }
+
+    fn append_key(
+        &self,
+        key: &'static mut Key,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Key, &'static mut [u8], ReturnCode)> {
+        let result = if self.position(key).is_some() {
+            Err(ReturnCode::ENOSUPPORT)
+        } else if self.used() >= self.capacity.get() {
+            Err(ReturnCode::ENOMEM)
+        } else {
+            self.entries
+                .borrow_mut()
+                .push((*key, value[..length].to_vec()));
+            Ok(())
+        };
+        *self.pending.borrow_mut() = Some(Pending::Append(result, key, value));
+        Ok(())
+    }
+
+    fn get_value(
+        &self,
+        key: &'static mut Key,
+        ret_buf: &'static mut [u8],
+    ) -> Result<(), (&'static mut Key, &'static mut [u8], ReturnCode)> {
+        let result = match self.position(key) {
+            Some(i) => {
+                let entries = self.entries.borrow();
+                let value = &entries[i].1;
+                if value.len() > ret_buf.len() {
+                    Err(ReturnCode::ESIZE)
+                } else {
+                    ret_buf[..value.len()].copy_from_slice(value);
+                    Ok(())
+                }
+            }
+            None => Err(ReturnCode::ENOSUPPORT),
+        };
+        *self.pending.borrow_mut() = Some(Pending::Get(result, key, ret_buf));
+        Ok(())
+    }
+
+    fn invalidate_key(&self, key: &'static mut Key) -> Result<(), (&'static mut Key, ReturnCode)> {
+        let result = match self.position(key) {
+            Some(i) => {
+                self.entries.borrow_mut().remove(i);
+                Ok(())
+            }
+            None => Err(ReturnCode::ENOSUPPORT),
+        };
+        *self.pending.borrow_mut() = Some(Pending::Invalidate(result, key));
+        Ok(())
+    }
+
+    fn garbage_collect(&self) -> Result<usize, ReturnCode> {
+        Ok(0)
+    }
+
+    fn next_key(
+        &self,
+        cursor: usize,
+        key_buf: &'static mut Key,
+    ) -> Result<(), (&'static mut Key, ReturnCode)> {
+        // The cursor is the index of the next entry.
+        let pending = match self.entries.borrow().get(cursor) {
+            Some((key, _)) => {
+                *key_buf = *key;
+                Pending::NextKey(Ok(()), key_buf, cursor + 1)
+            }
+            None => Pending::NextKey(Err(ReturnCode::ENOSUPPORT), key_buf, 0),
+        };
+        *self.pending.borrow_mut() = Some(pending);
+        Ok(())
+    }
+
+    fn storage_stats(&self) -> Result<(), ReturnCode> {
+        let entries = self.entries.borrow();
+        let stats = kv_system::StorageStats {
+            keys: entries.len(),
+            used: entries.iter().map(|(_, value)| value.len()).sum(),
+            ..kv_system::StorageStats::default()
+        };
+        *self.pending.borrow_mut() = Some(Pending::Stats(stats));
+        Ok(())
+    }
+
+    fn check_consistency(&self, _repair: bool) -> Result<(), ReturnCode> {
+        *self.pending.borrow_mut() = Some(Pending::Check);
+        Ok(())
+    }
+
+    fn begin_transaction(&self) -> Result<(), ReturnCode> {
+        let mut staged = self.staged.borrow_mut();
+        if staged.is_some() {
+            return Err(ReturnCode::EALREADY);
+        }
+        *staged = Some(Vec::new());
+        *self.pending.borrow_mut() = Some(Pending::Begin);
+        Ok(())
+    }
+
+    fn stage_key(
+        &self,
+        key: &'static mut Key,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Key, &'static mut [u8], ReturnCode)> {
+        if self.staged.borrow().is_none() {
+            return Err((key, value, ReturnCode::EINVAL));
+        }
+        let result = if self.used() >= self.capacity.get() {
+            Err(ReturnCode::ENOMEM)
+        } else {
+            let mut staged = self.staged.borrow_mut();
+            let staged = staged.as_mut().unwrap();
+            if staged.iter().any(|(k, _)| k == key) {
+                Err(ReturnCode::ENOSUPPORT)
+            } else {
+                staged.push((*key, value[..length].to_vec()));
+                Ok(())
+            }
+        };
+        *self.pending.borrow_mut() = Some(Pending::Stage(result, key, value));
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> Result<(), ReturnCode> {
+        let staged = self.staged.borrow_mut().take().ok_or(ReturnCode::EINVAL)?;
+        for (key, value) in staged {
+            if let Some(i) = self.position(&key) {
+                self.entries.borrow_mut().remove(i);
+            }
+            self.entries.borrow_mut().push((key, value));
+        }
+        *self.pending.borrow_mut() = Some(Pending::Commit);
+        Ok(())
+    }
+
+    fn abort_transaction(&self) -> Result<(), ReturnCode> {
+        self.staged.borrow_mut().take().ok_or(ReturnCode::EINVAL)?;
+        *self.pending.borrow_mut() = Some(Pending::Abort);
+        Ok(())
+    }
+}
@@ -0,0 +1,453 @@
+//! Shared setup for host tests that run the networking stack over
+//! `capsules::ieee802154::loopback`.
+//!
+//! `SimAlarm` is a 1MHz alarm whose clock only advances when `run` finds
+//! nothing else to do, so simulations are deterministic. Each `Sim` has its
+//! own deferred call instance rather than the global one, so a test binary
+//! can hold several tests, which run on their own threads. Each `Node` is a full
+//! stack (`LoopbackRadio`, `AwakeMac`, a secured `Framer` backed by
+//! `SoftwareAes128`, `MuxMac`, 6LoWPAN, IPv6, UDP and the UDP driver) attached to a shared
+//! `LoopbackMedium`.
+
+#![allow(dead_code)]
+
+pub mod flash;
+pub mod kv;
+pub mod process;
+pub mod ramdisk;
+
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::framer::{DeviceProcedure, Framer, KeyProcedure};
+use capsules::ieee802154::loopback::{LoopbackMedium, LoopbackRadio};
+use capsules::ieee802154::mac::AwakeMac;
+use capsules::ieee802154::virtual_mac::{MacUser, MuxMac};
+use capsules::net::ieee802154::{KeyId, MacAddress, SecurityLevel};
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
+use capsules::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::network_capabilities::{
+    AddrRange, IpVisibilityCapability, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::sixlowpan::sixlowpan_compression::Context;
+use capsules::net::sixlowpan::sixlowpan_state::{RxState, Sixlowpan, SixlowpanState, TxState};
+use capsules::net::udp::udp_port_table::{SocketBindingEntry, UdpPortManager, MAX_NUM_BOUND_PORTS};
+use capsules::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::net::udp::{UDPDriver, UDPHeader};
+use capsules::software_aes::SoftwareAes128;
+use capsules::virtual_aes_ccm::{MuxAES128CCM, VirtualAES128CCM};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::common::cells::OptionalCell;
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::create_capability;
+use kernel::hil::radio::{self, RadioConfig, RadioData};
+use kernel::hil::symmetric_encryption::{AES128CCM, AES128_BLOCK_SIZE};
+use kernel::hil::time::{self, Alarm, Freq1MHz, Ticks, Ticks32, Time};
+use kernel::{Kernel, ReturnCode};
+use std::cell::Cell;
+
+pub const PAN_ID: u16 = 0xABCD;
+pub const CHANNEL: u8 = 26;
+pub const KEY: [u8; 16] = [
+    0xC0, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xCB, 0xCC, 0xCD, 0xCE, 0xCF,
+];
+
+const DEFERRED_CALL_SLOTS: usize = 16;
+pub use capsules::ieee802154::loopback::DEFAULT_PATH_RSSI;
+
+const MAX_ITERATIONS: usize = 100_000;
+const SIXLOWPAN_RX_SIZE: usize = 1280;
+/// The payload of the largest datagram the nodes can reassemble.
+pub const MAX_UDP_PAYLOAD: usize = SIXLOWPAN_RX_SIZE - 40 - 8;
+
+pub fn leak<T>(value: T) -> &'static mut T {
+    Box::leak(Box::new(value))
+}
+
+pub fn leak_buf(len: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; len].into_boxed_slice())
+}
+
+/// Alarm driven by a simulated clock.
+pub struct SimAlarm<'a> {
+    now: Cell<u32>,
+    expiry: Cell<Option<u32>>,
+    client: OptionalCell<&'a dyn time::AlarmClient>,
+}
+
+impl<'a> SimAlarm<'a> {
+    pub fn new() -> SimAlarm<'a> {
+        SimAlarm {
+            now: Cell::new(0),
+            expiry: Cell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Advances the clock by `us` without firing the alarm, as if its
+    /// interrupt were held off.
+    pub fn advance(&self, us: u32) {
+        self.now.set(self.now.get().wrapping_add(us));
+    }
+
+    /// Advances the clock to the armed expiry and fires the alarm, returning
+    /// `false` if the alarm was not armed.
+    fn fire(&self) -> bool {
+        match self.expiry.take() {
+            Some(expiry) => {
+                let now = Ticks32::from(self.now.get());
+                let remaining = Ticks32::from(expiry).wrapping_sub(now);
+                // Alarms up to half the timer period ahead are in the
+                // future, anything further has already expired.
+                if remaining.into_u32() <= u32::MAX / 2 {
+                    self.now.set(expiry);
+                }
+                self.client.map(|client| client.alarm());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Time for SimAlarm<'_> {
+    type Frequency = Freq1MHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32::from(self.now.get())
+    }
+}
+
+impl<'a> Alarm<'a> for SimAlarm<'a> {
+    fn set_alarm_client(&'a self, client: &'a dyn time::AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.expiry.set(Some(reference.wrapping_add(dt).into_u32()));
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        Ticks32::from(self.expiry.get().unwrap_or(0))
+    }
+
+    fn disarm(&self) -> ReturnCode {
+        self.expiry.set(None);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_armed(&self) -> bool {
+        self.expiry.get().is_some()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        Ticks32::from(1)
+    }
+}
+
+pub type VAlarm = VirtualMuxAlarm<'static, SimAlarm<'static>>;
+pub type Radio = LoopbackRadio<'static, VAlarm>;
+pub type Aes = SoftwareAes128<'static>;
+pub type NodeFramer = Framer<'static, AwakeMac<'static, Radio>, VirtualAES128CCM<'static, Aes>>;
+pub type IpSender = IP6SendStruct<'static, VAlarm>;
+
+/// The simulated world: one clock, one deferred call instance and one medium.
+pub struct Sim {
+    pub alarm: &'static SimAlarm<'static>,
+    pub mux_alarm: &'static MuxAlarm<'static, SimAlarm<'static>>,
+    pub deferred_caller: &'static DynamicDeferredCall,
+    pub medium: &'static LoopbackMedium<'static, VAlarm>,
+    pub kernel: &'static Kernel,
+}
+
+/// A simulated device that completes its pending operation when `run` is
+/// called, so that tests decide when callbacks happen.
+pub trait Device {
+    /// Completes the pending operation, returning false if there was none.
+    fn run(&self) -> bool;
+}
+
+impl Sim {
+    /// Creates the simulation.
+    pub fn new() -> Sim {
+        let alarm = leak(SimAlarm::new());
+        let mux_alarm = leak(MuxAlarm::new(alarm));
+        alarm.set_alarm_client(mux_alarm);
+
+        let states: Vec<DynamicDeferredCallClientState> = (0..DEFERRED_CALL_SLOTS)
+            .map(|_| DynamicDeferredCallClientState::default())
+            .collect();
+        let deferred_caller = leak(DynamicDeferredCall::new(Box::leak(
+            states.into_boxed_slice(),
+        )));
+
+        let medium_alarm = leak(VirtualMuxAlarm::new(mux_alarm));
+        let medium = leak(LoopbackMedium::new(medium_alarm));
+        medium_alarm.set_alarm_client(medium);
+
+        let kernel = leak(Kernel::new(leak([])));
+
+        Sim {
+            kernel: kernel,
+            alarm: alarm,
+            mux_alarm: mux_alarm,
+            deferred_caller: deferred_caller,
+            medium: medium,
+        }
+    }
+
+    /// Current simulated time in microseconds.
+    pub fn now_us(&self) -> u32 {
+        self.alarm.now().into_u32()
+    }
+
+    /// Runs the pending deferred calls once, returning false if there were
+    /// none.
+    pub fn run_deferred(&self) -> bool {
+        if self.deferred_caller.has_pending() {
+            self.deferred_caller.call();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Runs deferred calls until none are pending, and returns how many
+    /// rounds of calls there were.
+    pub fn run_all_deferred(&self) -> usize {
+        let mut rounds = 0;
+        while self.run_deferred() {
+            rounds += 1;
+        }
+        rounds
+    }
+
+    /// Completes the operations of `devices` and runs deferred calls until
+    /// everything is idle. The clock doesn't advance.
+    pub fn pump(&self, devices: &[&dyn Device]) {
+        while devices.iter().any(|device| device.run()) || self.run_deferred() {}
+    }
+
+    /// Runs deferred calls and alarms until `done` returns true or nothing is
+    /// left to do. Returns the final value of `done`.
+    pub fn run<F: Fn() -> bool>(&self, done: F) -> bool {
+        for _ in 0..MAX_ITERATIONS {
+            if done() {
+                return true;
+            }
+            if !self.run_deferred() && !self.alarm.fire() {
+                break;
+            }
+        }
+        done()
+    }
+}
+
+/// Answers the framer's key and neighbor lookups. Every node shares `KEY`,
+/// and a node's long address is derived from its short address.
+pub struct StaticKeys;
+
+impl KeyProcedure for StaticKeys {
+    fn lookup_key(&self, _level: SecurityLevel, _key_id: KeyId) -> Option<[u8; 16]> {
+        Some(KEY)
+    }
+}
+
+impl DeviceProcedure for StaticKeys {
+    fn lookup_addr_long(&self, addr: MacAddress) -> Option<[u8; 8]> {
+        match addr {
+            MacAddress::Short(short) => Some(long_address(short)),
+            MacAddress::Long(long) => Some(long),
+        }
+    }
+}
+
+pub fn long_address(short: u16) -> [u8; 8] {
+    let [hi, lo] = short.to_be_bytes();
+    [0x02, 0, 0, 0, 0, 0, hi, lo]
+}
+
+/// The link-local IPv6 address that 6LoWPAN derives from a short address.
+pub fn link_local(short: u16) -> IPAddr {
+    IPAddr::generate_from_mac(MacAddress::Short(short))
+}
+
+/// One simulated device running the full network stack.
+pub struct Node {
+    pub address: u16,
+    pub radio: &'static Radio,
+    pub framer: &'static NodeFramer,
+    pub mac_user: &'static MacUser<'static>,
+    pub ip_send: &'static IpSender,
+    pub ip_receive: &'static IP6RecvStruct<'static>,
+    pub udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    pub udp_driver: &'static UDPDriver<'static>,
+    pub udp_sender: &'static UDPSendStruct<'static, IpSender>,
+    pub udp_receiver: &'static UDPReceiver<'static>,
+    pub port_table: &'static UdpPortManager,
+    pub net_cap: &'static NetworkCapability,
+}
+
+impl Node {
+    /// Builds a node with short address `address` that sends all IPv6 traffic
+    /// to the node with short address `peer`.
+    pub fn new(sim: &Sim, address: u16, peer: u16) -> Node {
+        let radio = leak(LoopbackRadio::new(sim.medium));
+        sim.medium.add_radio(radio);
+
+        // Software AES-CCM for link layer security.
+        let aes = leak(SoftwareAes128::new(sim.deferred_caller));
+        aes.initialize_callback_handle(sim.deferred_caller.register(aes).unwrap());
+        let aes_mux = leak(MuxAES128CCM::new(aes, sim.deferred_caller));
+        aes_mux.initialize_callback_handle(sim.deferred_caller.register(aes_mux).unwrap());
+        kernel::hil::symmetric_encryption::AES128::set_client(aes, aes_mux);
+        let aes_ccm = leak(VirtualAES128CCM::new(
+            aes_mux,
+            leak_buf(3 * AES128_BLOCK_SIZE + radio::MAX_BUF_SIZE),
+        ));
+        aes_ccm.setup();
+        aes_mux.enable();
+
+        let awake_mac = leak(AwakeMac::new(radio));
+        radio.set_transmit_client(awake_mac);
+        radio.set_receive_client(awake_mac, leak_buf(radio::MAX_BUF_SIZE));
+
+        let framer = leak(Framer::new(awake_mac, aes_ccm));
+        aes_ccm.set_client(framer);
+        capsules::ieee802154::mac::Mac::set_transmit_client(awake_mac, framer);
+        capsules::ieee802154::mac::Mac::set_receive_client(awake_mac, framer);
+        capsules::ieee802154::mac::Mac::set_config_client(awake_mac, framer);
+        let keys = leak(StaticKeys);
+        framer.set_key_procedure(keys);
+        framer.set_device_procedure(keys);
+
+        let mux_mac = leak(MuxMac::new(framer));
+        framer.set_transmit_client(mux_mac);
+        framer.set_receive_client(mux_mac);
+
+        let mac_user = leak(MacUser::new(mux_mac));
+        mux_mac.add_user(mac_user);
+
+        // 6LoWPAN, IPv6 and UDP, as in `components::udp_mux`.
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = leak(UdpVisibilityCapability::new(&create_cap));
+        let ip_vis = leak(IpVisibilityCapability::new(&create_cap));
+        let net_cap = leak(NetworkCapability::new(
+            AddrRange::Any,
+            PortRange::Any,
+            PortRange::Any,
+            &create_cap,
+        ));
+
+        let udp_mac = leak(MacUser::new(mux_mac));
+        mux_mac.add_user(udp_mac);
+
+        let ip_alarm = leak(VirtualMuxAlarm::new(sim.mux_alarm));
+        let sixlowpan = leak(Sixlowpan::new(
+            Context {
+                prefix: [0; 16],
+                prefix_len: 0,
+                id: 0,
+                compress: false,
+            },
+            ip_alarm,
+        ));
+        let sixlowpan_state = sixlowpan as &dyn SixlowpanState;
+        let rx_state = leak(RxState::new(leak_buf(SIXLOWPAN_RX_SIZE)));
+        sixlowpan_state.add_rx_state(rx_state);
+        udp_mac.set_receive_client(sixlowpan);
+
+        let ip6_dg = leak(IP6Packet::new(IPPayload {
+            header: TransportHeader::UDP(UDPHeader::new()),
+            payload: leak_buf(MAX_UDP_PAYLOAD),
+        }));
+        let ip_send = leak(IP6SendStruct::new(
+            ip6_dg,
+            ip_alarm,
+            leak_buf(radio::MAX_BUF_SIZE),
+            TxState::new(sixlowpan_state),
+            udp_mac,
+            MacAddress::Short(peer),
+            MacAddress::Short(address),
+            ip_vis,
+        ));
+        ip_alarm.set_alarm_client(ip_send);
+        ip_send.set_addr(link_local(address));
+        udp_mac.set_transmit_client(ip_send);
+
+        let ip_receive = leak(IP6RecvStruct::new());
+        sixlowpan_state.set_rx_client(ip_receive);
+        let udp_recv_mux = leak(MuxUdpReceiver::new());
+        ip_receive.set_client(udp_recv_mux);
+
+        let udp_send_mux = leak(MuxUdpSender::new(ip_send));
+        ip_send.set_client(udp_send_mux);
+
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_table_cap = create_capability!(capabilities::CreatePortTableCapability);
+        let used_ports: &'static mut [Option<SocketBindingEntry>] =
+            leak([None; MAX_NUM_BOUND_PORTS]);
+        let port_table = leak(UdpPortManager::new(&create_table_cap, used_ports, udp_vis));
+
+        // The userspace UDP driver, as in `components::udp_driver`. No
+        // processes exist, but the port table requires it to answer queries.
+        let driver_sender = leak(UDPSendStruct::new(udp_send_mux, udp_vis));
+        let driver_cap = leak(create_capability!(capabilities::UdpDriverCapability));
+        let udp_driver = leak(UDPDriver::new(
+            driver_sender,
+            sim.kernel.create_grant(&grant_cap),
+            leak([link_local(address)]),
+            MAX_UDP_PAYLOAD,
+            port_table,
+            LeasableBuffer::new(leak_buf(MAX_UDP_PAYLOAD)),
+            driver_cap,
+            net_cap,
+        ));
+        driver_sender.set_client(udp_driver);
+        port_table.set_user_ports(udp_driver, driver_cap);
+        let driver_receiver = leak(UDPReceiver::new());
+        udp_recv_mux.set_driver(udp_driver);
+        udp_recv_mux.add_client(driver_receiver);
+
+        let udp_sender = leak(UDPSendStruct::new(udp_send_mux, udp_vis));
+        let udp_receiver = leak(UDPReceiver::new());
+        udp_recv_mux.add_client(udp_receiver);
+
+        mac_user.set_pan(PAN_ID);
+        mac_user.set_address(address);
+        mac_user.set_address_long(long_address(address));
+        mac_user.config_commit();
+        radio.set_channel(CHANNEL);
+        radio.start();
+
+        Node {
+            address: address,
+            radio: radio,
+            framer: framer,
+            mac_user: mac_user,
+            ip_send: ip_send,
+            ip_receive: ip_receive,
+            udp_recv_mux: udp_recv_mux,
+            udp_driver: udp_driver,
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            net_cap: net_cap,
+        }
+    }
+
+    /// Binds this node's UDP sender and receiver to `port`.
+    pub fn bind(&self, port: u16) {
+        let socket = self.port_table.create_socket().unwrap();
+        let (send_binding, recv_binding) = self
+            .port_table
+            .bind(socket, port, self.net_cap)
+            .unwrap_or_else(|_| panic!("failed to bind port {}", port));
+        self.udp_sender.set_binding(send_binding);
+        self.udp_receiver.set_binding(recv_binding);
+    }
+}
@@ -0,0 +1,226 @@
+//! Stand-in processes, so drivers can enter grants and take buffers from
+//! processes that never run.
+//!
+//! Each `TestProcess` lives in a `Kernel` of its own making. Restarting one
+//! gives it a new `AppId` and fresh grant regions, which is what a driver
+//! sees when a process exits or faults and comes back.
+
+use kernel::capabilities;
+use kernel::common::cells::OptionalCell;
+use kernel::create_capability;
+use kernel::procs::{Error, FunctionCall, ProcessType, State, Task};
+use kernel::syscall::{ContextSwitchReason, Syscall};
+use kernel::{mpu, AppId, AppSlice, CallbackId, Kernel, ReturnCode, Shared};
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+use std::ptr::{self, NonNull};
+
+const MAX_GRANTS: usize = 32;
+// Added to the identifier on restart, keeping identifiers unique as long as
+// there are fewer processes than this.
+const RESTART_STRIDE: usize = 1000;
+
+pub struct TestProcess {
+    kernel: OptionalCell<&'static Kernel>,
+    index: usize,
+    identifier: Cell<usize>,
+    grants: RefCell<[*mut u8; MAX_GRANTS]>,
+}
+
+impl TestProcess {
+    /// Creates a kernel with `count` processes.
+    pub fn create(count: usize) -> (&'static Kernel, Vec<&'static TestProcess>) {
+        let processes: Vec<&'static TestProcess> = (0..count)
+            .map(|index| {
+                &*Box::leak(Box::new(TestProcess {
+                    kernel: OptionalCell::empty(),
+                    index: index,
+                    identifier: Cell::new(index),
+                    grants: RefCell::new([ptr::null_mut(); MAX_GRANTS]),
+                }))
+            })
+            .collect();
+        let table: Vec<Option<&'static dyn ProcessType>> = processes
+            .iter()
+            .map(|&process| Some(process as &'static dyn ProcessType))
+            .collect();
+        let kernel: &'static Kernel =
+            Box::leak(Box::new(Kernel::new(Box::leak(table.into_boxed_slice()))));
+        for process in processes.iter() {
+            process.kernel.set(kernel);
+        }
+        (kernel, processes)
+    }
+
+    /// Restarts the process. Its old `AppId`, buffers and grant regions are
+    /// no longer valid.
+    pub fn restart(&self) {
+        self.identifier.set(self.identifier.get() + RESTART_STRIDE);
+        *self.grants.borrow_mut() = [ptr::null_mut(); MAX_GRANTS];
+    }
+
+    /// Shares a copy of `data` with the kernel, as `allow` would.
+    pub fn slice(&self, data: &[u8]) -> AppSlice<Shared, u8> {
+        let cap = create_capability!(capabilities::ExternalProcessCapability);
+        let buf: &'static mut [u8] = Box::leak(data.to_vec().into_boxed_slice());
+        let len = buf.len();
+        let ptr = NonNull::new(buf.as_mut_ptr()).unwrap();
+        unsafe { AppSlice::new_external(ptr, len, self.appid(), &cap) }
+    }
+}
+
+impl ProcessType for TestProcess {
+    fn appid(&self) -> AppId {
+        let cap = create_capability!(capabilities::ExternalProcessCapability);
+        AppId::new_external(
+            self.kernel.expect("process without a kernel"),
+            self.identifier.get(),
+            self.index,
+            &cap,
+        )
+    }
+
+    fn enqueue_task(&self, _task: Task) -> bool {
+        true
+    }
+
+    fn ready(&self) -> bool {
+        false
+    }
+
+    fn dequeue_task(&self) -> Option<Task> {
+        None
+    }
+
+    fn remove_pending_callbacks(&self, _callback_id: CallbackId) {}
+
+    fn get_state(&self) -> State {
+        State::Yielded
+    }
+
+    fn set_yielded_state(&self) {}
+
+    fn stop(&self) {}
+
+    fn resume(&self) {}
+
+    fn set_fault_state(&self) {
+        self.restart();
+    }
+
+    fn get_restart_count(&self) -> usize {
+        0
+    }
+
+    fn get_process_name(&self) -> &'static str {
+        "test"
+    }
+
+    fn brk(&self, _new_break: *const u8) -> Result<*const u8, Error> {
+        Err(Error::OutOfMemory)
+    }
+
+    fn sbrk(&self, _increment: isize) -> Result<*const u8, Error> {
+        Err(Error::OutOfMemory)
+    }
+
+    fn mem_start(&self) -> *const u8 {
+        ptr::null()
+    }
+
+    fn mem_end(&self) -> *const u8 {
+        ptr::null()
+    }
+
+    fn flash_start(&self) -> *const u8 {
+        ptr::null()
+    }
+
+    fn flash_end(&self) -> *const u8 {
+        ptr::null()
+    }
+
+    fn kernel_memory_break(&self) -> *const u8 {
+        ptr::null()
+    }
+
+    fn number_writeable_flash_regions(&self) -> usize {
+        0
+    }
+
+    fn get_writeable_flash_region(&self, _region_index: usize) -> (u32, u32) {
+        (0, 0)
+    }
+
+    fn update_stack_start_pointer(&self, _stack_pointer: *const u8) {}
+
+    fn update_heap_start_pointer(&self, _heap_pointer: *const u8) {}
+
+    fn allow(
+        &self,
+        _buf_start_addr: *const u8,
+        _size: usize,
+    ) -> Result<Option<AppSlice<Shared, u8>>, ReturnCode> {
+        Err(ReturnCode::ENOSUPPORT)
+    }
+
+    fn flash_non_protected_start(&self) -> *const u8 {
+        ptr::null()
+    }
+
+    fn setup_mpu(&self) {}
+
+    fn add_mpu_region(
+        &self,
+        _unallocated_memory_start: *const u8,
+        _unallocated_memory_size: usize,
+        _min_region_size: usize,
+    ) -> Option<mpu::Region> {
+        None
+    }
+
+    fn alloc(&self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        // Grant regions are never freed, like on a device.
+        let layout = Layout::from_size_align(size.max(1), align).ok()?;
+        NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+    }
+
+    unsafe fn free(&self, _: *mut u8) {}
+
+    fn get_grant_ptr(&self, grant_num: usize) -> Option<*mut u8> {
+        self.grants.borrow().get(grant_num).copied()
+    }
+
+    unsafe fn set_grant_ptr(&self, grant_num: usize, grant_ptr: *mut u8) {
+        self.grants.borrow_mut()[grant_num] = grant_ptr;
+    }
+
+    unsafe fn set_syscall_return_value(&self, _return_value: isize) {}
+
+    unsafe fn set_process_function(&self, _callback: FunctionCall) {}
+
+    unsafe fn switch_to(&self) -> Option<ContextSwitchReason> {
+        None
+    }
+
+    unsafe fn print_memory_map(&self, _writer: &mut dyn Write) {}
+
+    unsafe fn print_full_process(&self, _writer: &mut dyn Write) {}
+
+    fn debug_syscall_count(&self) -> usize {
+        0
+    }
+
+    fn debug_dropped_callback_count(&self) -> usize {
+        0
+    }
+
+    fn debug_timeslice_expiration_count(&self) -> usize {
+        0
+    }
+
+    fn debug_timeslice_expired(&self) {}
+
+    fn debug_syscall_called(&self, _last_syscall: Syscall) {}
+}
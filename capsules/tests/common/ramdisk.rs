@@ -0,0 +1,118 @@
+//! An in-memory `hil::nonvolatile_storage` for testing block-based capsules.
+
+use super::Device;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+pub const SECTOR_SIZE: usize = 512;
+
+enum Pending {
+    Read(&'static mut [u8], usize),
+    Write(&'static mut [u8], usize),
+}
+
+/// A sparse disk of 512 byte sectors that completes operations when `run`
+/// is called. Sectors that were never written read as zeros.
+pub struct RamDisk {
+    pub sectors: RefCell<HashMap<u32, [u8; SECTOR_SIZE]>>,
+    pending: RefCell<Option<Pending>>,
+    client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+    pub reads: Cell<usize>,
+    pub writes: Cell<usize>,
+    /// Whether accesses must start on a sector boundary
+    aligned: bool,
+}
+
+impl RamDisk {
+    pub fn new() -> RamDisk {
+        RamDisk {
+            sectors: RefCell::new(HashMap::new()),
+            pending: RefCell::new(None),
+            client: OptionalCell::empty(),
+            reads: Cell::new(0),
+            writes: Cell::new(0),
+            aligned: true,
+        }
+    }
+
+    /// A disk that allows accesses at any byte address, like most
+    /// `hil::nonvolatile_storage` devices.
+    pub fn byte_addressable() -> RamDisk {
+        RamDisk {
+            aligned: false,
+            ..RamDisk::new()
+        }
+    }
+
+    /// Drops the pending operation without calling the client, as if power
+    /// was lost. A pending write has already reached the disk.
+    pub fn cut_power(&self) {
+        self.pending.borrow_mut().take();
+    }
+
+    pub fn read_bytes(&self, address: usize, buf: &mut [u8]) {
+        let sectors = self.sectors.borrow();
+        for (i, b) in buf.iter_mut().enumerate() {
+            let address = address + i;
+            *b = sectors
+                .get(&((address / SECTOR_SIZE) as u32))
+                .map_or(0, |sector| sector[address % SECTOR_SIZE]);
+        }
+    }
+
+    pub fn write_bytes(&self, address: usize, buf: &[u8]) {
+        let mut sectors = self.sectors.borrow_mut();
+        for (i, b) in buf.iter().enumerate() {
+            let address = address + i;
+            sectors
+                .entry((address / SECTOR_SIZE) as u32)
+                .or_insert([0; SECTOR_SIZE])[address % SECTOR_SIZE] = *b;
+        }
+    }
+}
+
+impl Device for RamDisk {
+    fn run(&self) -> bool {
+        let pending = self.pending.borrow_mut().take();
+        match pending {
+            None => false,
+            Some(Pending::Read(buffer, length)) => {
+                self.client
+                    .map(move |client| client.read_done(buffer, length));
+                true
+            }
+            Some(Pending::Write(buffer, length)) => {
+                self.client
+                    .map(move |client| client.write_done(buffer, length));
+                true
+            }
+        }
+    }
+}
+
+impl NonvolatileStorage<'static> for RamDisk {
+    fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        assert!(self.pending.borrow().is_none(), "storage is busy");
+        assert!(!self.aligned || address % SECTOR_SIZE == 0);
+        self.read_bytes(address, &mut buffer[..length]);
+        self.reads.set(self.reads.get() + 1);
+        *self.pending.borrow_mut() = Some(Pending::Read(buffer, length));
+        ReturnCode::SUCCESS
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        assert!(self.pending.borrow().is_none(), "storage is busy");
+        assert!(!self.aligned || address % SECTOR_SIZE == 0);
+        self.write_bytes(address, &buffer[..length]);
+        self.writes.set(self.writes.get() + 1);
+        *self.pending.borrow_mut() = Some(Pending::Write(buffer, length));
+        ReturnCode::SUCCESS
+    }
+}
@@ -0,0 +1,572 @@
+//! Host tests for the FAT filesystem capsule.
+//!
+//! Filesystems are formatted in a `RamDisk`, used through `capsules::fat` and
+//! then read back with a separate minimal FAT reader, to check that what the
+//! capsule writes can be read by other systems.
+
+mod common;
+
+use capsules::fat::{self, DirEntry, FatFs, MAX_OPEN_FILES};
+use common::ramdisk::{RamDisk, SECTOR_SIZE};
+use common::{leak, leak_buf, Sim};
+use kernel::common::cells::TakeCell;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::ReturnCode;
+use std::cell::Cell;
+
+/// Records the result of the last operation.
+struct TestClient {
+    result: Cell<Option<Result<usize, ReturnCode>>>,
+    entry: Cell<Option<DirEntry>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl fat::Client for TestClient {
+    fn mount_done(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result.map(|()| 0)));
+    }
+
+    fn open_done(&self, result: Result<usize, ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn mkdir_done(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result.map(|()| 0)));
+    }
+
+    fn read_done(&self, result: Result<(), ReturnCode>, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.result.set(Some(result.map(|()| length)));
+    }
+
+    fn write_done(&self, result: Result<(), ReturnCode>, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.result.set(Some(result.map(|()| length)));
+    }
+
+    fn list_done(&self, result: Result<DirEntry, ReturnCode>) {
+        self.entry.set(result.ok());
+        self.result.set(Some(result.map(|_| 0)));
+    }
+}
+
+struct Harness<'a> {
+    sim: &'a Sim,
+    disk: &'static RamDisk,
+    fat: &'static FatFs<'static>,
+    client: &'static TestClient,
+}
+
+impl<'a> Harness<'a> {
+    fn new(sim: &'a Sim, disk: &'static RamDisk) -> Harness<'a> {
+        let fat = leak(FatFs::new(
+            disk,
+            leak([0; fat::SECTOR_SIZE]),
+            sim.deferred_caller,
+        ));
+        fat.initialize_callback_handle(sim.deferred_caller.register(fat).unwrap());
+        disk.set_client(fat);
+        let client = leak(TestClient {
+            result: Cell::new(None),
+            entry: Cell::new(None),
+            buffer: TakeCell::new(leak_buf(4096)),
+        });
+        fat.set_client(client);
+        Harness {
+            sim,
+            disk,
+            fat,
+            client,
+        }
+    }
+
+    /// Run the disk and deferred calls until both are idle.
+    fn complete(&self) -> Result<usize, ReturnCode> {
+        self.sim.pump(&[self.disk]);
+        self.client
+            .result
+            .take()
+            .expect("operation did not complete")
+    }
+
+    fn started(&self, ret: ReturnCode) -> Result<usize, ReturnCode> {
+        if ret == ReturnCode::SUCCESS {
+            self.complete()
+        } else {
+            Err(ret)
+        }
+    }
+
+    fn mount(&self) -> Result<usize, ReturnCode> {
+        self.started(self.fat.mount())
+    }
+
+    fn open(&self, path: &str, create: bool) -> Result<usize, ReturnCode> {
+        self.started(self.fat.open(path.as_bytes(), create))
+    }
+
+    fn mkdir(&self, path: &str) -> Result<usize, ReturnCode> {
+        self.started(self.fat.mkdir(path.as_bytes()))
+    }
+
+    fn write(&self, handle: usize, data: &[u8]) -> Result<usize, ReturnCode> {
+        let buffer = self.client.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        match self.fat.write(handle, buffer, data.len()) {
+            Ok(()) => self.complete(),
+            Err((buffer, e)) => {
+                self.client.buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    fn read(&self, handle: usize, length: usize) -> Result<Vec<u8>, ReturnCode> {
+        let buffer = self.client.buffer.take().unwrap();
+        match self.fat.read(handle, buffer, length) {
+            Ok(()) => {
+                let length = self.complete()?;
+                Ok(self.client.buffer.map(|b| b[..length].to_vec()).unwrap())
+            }
+            Err((buffer, e)) => {
+                self.client.buffer.replace(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    /// The names of the entries in a directory, with a `/` after
+    /// directories, and the sizes of files.
+    fn list(&self, path: &str) -> Result<Vec<(String, u32)>, ReturnCode> {
+        let mut entries = Vec::new();
+        loop {
+            match self.started(self.fat.list_dir(path.as_bytes(), entries.len())) {
+                Ok(_) => {
+                    let entry = self.client.entry.take().unwrap();
+                    let mut name = String::from_utf8(entry.name().to_vec()).unwrap();
+                    if entry.directory {
+                        name.push('/');
+                    }
+                    entries.push((name, entry.size));
+                }
+                Err(ReturnCode::ENOSUPPORT) => return Ok(entries),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length)
+        .map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+        .collect()
+}
+
+fn set_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+/// Format a FAT16 or FAT32 filesystem of `total` sectors with one sector
+/// per cluster, starting at sector `start`.
+fn format(disk: &RamDisk, start: u32, total: u32, fat32: bool) {
+    let reserved: u32 = if fat32 { 32 } else { 1 };
+    let root_entries: u32 = if fat32 { 0 } else { 512 };
+    let root_sectors = root_entries * 32 / SECTOR_SIZE as u32;
+    let entry_size = if fat32 { 4 } else { 2 };
+    let clusters = total - reserved - root_sectors;
+    let fat_sectors = ((clusters + 2) * entry_size + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
+
+    let mut boot = [0; SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    set_u16(&mut boot, 11, SECTOR_SIZE as u16);
+    boot[13] = 1;
+    set_u16(&mut boot, 14, reserved as u16);
+    boot[16] = 2;
+    set_u16(&mut boot, 17, root_entries as u16);
+    boot[21] = 0xF8;
+    set_u32(&mut boot, 28, start);
+    set_u32(&mut boot, 32, total);
+    if fat32 {
+        set_u32(&mut boot, 36, fat_sectors);
+        set_u32(&mut boot, 44, 2);
+        set_u16(&mut boot, 48, 1);
+        boot[66] = 0x29;
+        boot[82..90].copy_from_slice(b"FAT32   ");
+    } else {
+        set_u16(&mut boot, 22, fat_sectors as u16);
+        boot[38] = 0x29;
+        boot[54..62].copy_from_slice(b"FAT16   ");
+    }
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    disk.write_bytes(start as usize * SECTOR_SIZE, &boot);
+
+    for copy in 0..2 {
+        let fat = (start + reserved + copy * fat_sectors) as usize * SECTOR_SIZE;
+        if fat32 {
+            // The root directory is in cluster 2
+            disk.write_bytes(fat, &0x0FFF_FFF8u32.to_le_bytes());
+            disk.write_bytes(fat + 4, &0x0FFF_FFFFu32.to_le_bytes());
+            disk.write_bytes(fat + 8, &0x0FFF_FFFFu32.to_le_bytes());
+        } else {
+            disk.write_bytes(fat, &[0xF8, 0xFF, 0xFF, 0xFF]);
+        }
+    }
+}
+
+/// A minimal FAT reader, independent of the capsule.
+struct Image<'a> {
+    disk: &'a RamDisk,
+    fat32: bool,
+    fat_start: u32,
+    fat_sectors: u32,
+    root_start: u32,
+    data_start: u32,
+    root_cluster: u32,
+}
+
+impl<'a> Image<'a> {
+    fn new(disk: &'a RamDisk, start: u32) -> Image<'a> {
+        let mut boot = [0; SECTOR_SIZE];
+        disk.read_bytes(start as usize * SECTOR_SIZE, &mut boot);
+        let reserved = get_u16(&boot, 14) as u32;
+        let root_sectors = get_u16(&boot, 17) as u32 * 32 / SECTOR_SIZE as u32;
+        let fat32 = get_u16(&boot, 22) == 0;
+        let fat_sectors = if fat32 {
+            get_u32(&boot, 36)
+        } else {
+            get_u16(&boot, 22) as u32
+        };
+        let fat_start = start + reserved;
+        let root_start = fat_start + 2 * fat_sectors;
+        Image {
+            disk,
+            fat32,
+            fat_start,
+            fat_sectors,
+            root_start,
+            data_start: root_start + root_sectors,
+            root_cluster: if fat32 { get_u32(&boot, 44) } else { 0 },
+        }
+    }
+
+    fn sector(&self, sector: u32) -> Vec<u8> {
+        let mut buf = vec![0; SECTOR_SIZE];
+        self.disk
+            .read_bytes(sector as usize * SECTOR_SIZE, &mut buf);
+        buf
+    }
+
+    fn fat_entry(&self, copy: u32, cluster: u32) -> u32 {
+        let size = if self.fat32 { 4 } else { 2 };
+        let mut buf = [0; 4];
+        let address = (self.fat_start + copy * self.fat_sectors) as usize * SECTOR_SIZE
+            + (cluster * size) as usize;
+        self.disk.read_bytes(address, &mut buf[..size as usize]);
+        if self.fat32 {
+            u32::from_le_bytes(buf) & 0x0FFF_FFFF
+        } else {
+            u32::from_le_bytes(buf)
+        }
+    }
+
+    fn chain(&self, first: u32) -> Vec<u32> {
+        let end = if self.fat32 { 0x0FFF_FFF8 } else { 0xFFF8 };
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster >= 2 && cluster < end {
+            assert_eq!(self.fat_entry(0, cluster), self.fat_entry(1, cluster));
+            chain.push(cluster);
+            cluster = self.fat_entry(0, cluster);
+        }
+        chain
+    }
+
+    /// The directory entries of the directory starting at `cluster`.
+    fn entries(&self, cluster: u32) -> Vec<Vec<u8>> {
+        let sectors: Vec<u32> = if cluster == 0 && !self.fat32 {
+            (self.root_start..self.data_start).collect()
+        } else {
+            let cluster = if cluster == 0 {
+                self.root_cluster
+            } else {
+                cluster
+            };
+            self.chain(cluster)
+                .iter()
+                .map(|c| self.data_start + c - 2)
+                .collect()
+        };
+
+        let mut entries = Vec::new();
+        for sector in sectors {
+            for entry in self.sector(sector).chunks(32) {
+                if entry[0] == 0 {
+                    return entries;
+                }
+                if entry[0] != 0xE5 {
+                    entries.push(entry.to_vec());
+                }
+            }
+        }
+        entries
+    }
+
+    fn find(&self, path: &str) -> Vec<u8> {
+        let mut cluster = 0;
+        let mut found = Vec::new();
+        for name in path.split('/') {
+            let (base, ext) = match name.find('.') {
+                Some(dot) => (&name[..dot], &name[dot + 1..]),
+                None => (name, ""),
+            };
+            let short = format!("{:8}{:3}", base, ext);
+            found = self
+                .entries(cluster)
+                .into_iter()
+                .find(|entry| entry[..11] == *short.as_bytes())
+                .unwrap_or_else(|| panic!("{} not found", path));
+            cluster = (get_u16(&found, 20) as u32) << 16 | get_u16(&found, 26) as u32;
+        }
+        found
+    }
+
+    fn read(&self, path: &str) -> Vec<u8> {
+        let entry = self.find(path);
+        let cluster = (get_u16(&entry, 20) as u32) << 16 | get_u16(&entry, 26) as u32;
+        let size = get_u32(&entry, 28) as usize;
+        let mut data: Vec<u8> = self
+            .chain(cluster)
+            .iter()
+            .flat_map(|c| self.sector(self.data_start + c - 2))
+            .collect();
+        assert!(data.len() >= size);
+        data.truncate(size);
+        data
+    }
+}
+
+/// The first sector, the number of sectors and whether it is FAT32 of each
+/// supported layout: FAT16, FAT32 and FAT16 in the first partition of an MBR.
+const VOLUMES: [(u32, u32, bool); 3] = [(0, 8192, false), (0, 70000, true), (2048, 8192, false)];
+
+/// Calls `f` with a mounted filesystem and its first sector for each of
+/// `VOLUMES`.
+fn each_volume<F: Fn(&Harness, u32)>(f: F) {
+    for &(start, total, fat32) in VOLUMES.iter() {
+        let sim = Sim::new();
+        let disk = leak(RamDisk::new());
+        if start != 0 {
+            let mut mbr = [0; SECTOR_SIZE];
+            mbr[446 + 4] = 0x06;
+            mbr[446 + 8..446 + 12].copy_from_slice(&start.to_le_bytes());
+            mbr[446 + 12..446 + 16].copy_from_slice(&total.to_le_bytes());
+            mbr[510] = 0x55;
+            mbr[511] = 0xAA;
+            disk.write_bytes(0, &mbr);
+        }
+        format(disk, start, total, fat32);
+        let h = Harness::new(&sim, disk);
+        assert_eq!(h.mount(), Ok(0));
+        f(&h, start);
+    }
+}
+
+/// The cluster a directory entry starts at.
+fn first_cluster(entry: &[u8]) -> u32 {
+    (get_u16(entry, 20) as u32) << 16 | get_u16(entry, 26) as u32
+}
+
+#[test]
+fn unformatted_disk() {
+    let sim = Sim::new();
+    let h = Harness::new(&sim, leak(RamDisk::new()));
+    assert_eq!(h.mount(), Err(ReturnCode::FAIL));
+    assert_eq!(h.open("LOG.TXT", true), Err(ReturnCode::ERESERVE));
+}
+
+#[test]
+fn boot_sector_overflow() {
+    // FAT sizes whose sum doesn't fit in 32-bit sector numbers.
+    for &(num_fats, fat_sectors) in [(255u8, 0x0200_0000u32), (1, 0xFFFF_FFF0)].iter() {
+        let sim = Sim::new();
+        let disk = leak(RamDisk::new());
+        format(disk, 0, 70000, true);
+        disk.write_bytes(16, &[num_fats]);
+        disk.write_bytes(36, &fat_sectors.to_le_bytes());
+        let h = Harness::new(&sim, disk);
+        assert_eq!(h.mount(), Err(ReturnCode::FAIL));
+    }
+}
+
+#[test]
+fn write_read_and_seek() {
+    each_volume(|h, start| {
+        assert_eq!(h.open("LOG.TXT", false), Err(ReturnCode::ENOSUPPORT));
+        let log = h.open("log.txt", true).unwrap();
+        assert_eq!(h.fat.file_size(log), Ok(0));
+        assert_eq!(h.open("LOG.TXT", false), Err(ReturnCode::EBUSY));
+
+        let data = pattern(1500, 1);
+        assert_eq!(h.write(log, &data[..1300]), Ok(1300));
+        assert_eq!(h.write(log, &data[1300..]), Ok(200));
+        assert_eq!(h.fat.file_size(log), Ok(1500));
+        assert_eq!(h.read(log, 100), Ok(vec![]));
+
+        assert_eq!(h.fat.seek(log, 1501), ReturnCode::EINVAL);
+        assert_eq!(h.fat.seek(log, 0), ReturnCode::SUCCESS);
+        assert_eq!(h.read(log, 2000), Ok(data.clone()));
+        assert_eq!(h.fat.seek(log, 510), ReturnCode::SUCCESS);
+        assert_eq!(h.read(log, 10), Ok(data[510..520].to_vec()));
+        assert_eq!(h.fat.close(log), ReturnCode::SUCCESS);
+        assert_eq!(h.fat.close(log), ReturnCode::EINVAL);
+
+        assert_eq!(Image::new(h.disk, start).read("LOG.TXT"), data);
+    });
+}
+
+#[test]
+fn overwrite_across_sectors() {
+    each_volume(|h, start| {
+        let log = h.open("LOG.TXT", true).unwrap();
+        let mut data = pattern(1500, 1);
+        assert_eq!(h.write(log, &data), Ok(1500));
+        assert_eq!(h.fat.seek(log, 1020), ReturnCode::SUCCESS);
+        assert_eq!(h.write(log, &[0xAA; 8]), Ok(8));
+        assert_eq!(h.fat.close(log), ReturnCode::SUCCESS);
+        data[1020..1028].copy_from_slice(&[0xAA; 8]);
+
+        let log = h.open("/LOG.TXT", false).unwrap();
+        assert_eq!(h.fat.file_size(log), Ok(1500));
+        assert_eq!(h.read(log, 1500), Ok(data.clone()));
+        assert_eq!(Image::new(h.disk, start).read("LOG.TXT"), data);
+    });
+}
+
+#[test]
+fn invalid_paths() {
+    each_volume(|h, _| {
+        let log = h.open("LOG.TXT", true).unwrap();
+        assert_eq!(h.fat.close(log), ReturnCode::SUCCESS);
+
+        assert_eq!(h.open("TOOLONGNAME.TXT", true), Err(ReturnCode::EINVAL));
+        assert_eq!(h.open("../LOG.TXT", false), Err(ReturnCode::EINVAL));
+        assert_eq!(h.open("A B.TXT", true), Err(ReturnCode::EINVAL));
+        assert_eq!(h.open("", true), Err(ReturnCode::EINVAL));
+        assert_eq!(h.open("NODIR/LOG.TXT", true), Err(ReturnCode::ENOSUPPORT));
+        assert_eq!(h.open("LOG.TXT/X", true), Err(ReturnCode::EINVAL));
+    });
+}
+
+#[test]
+fn nested_directories() {
+    each_volume(|h, start| {
+        assert_eq!(h.mkdir("APPS"), Ok(0));
+        assert_eq!(h.mkdir("APPS"), Err(ReturnCode::EALREADY));
+        assert_eq!(h.mkdir("APPS/BLINK"), Ok(0));
+        assert_eq!(h.open("APPS", false), Err(ReturnCode::EINVAL));
+        let blink = h.open("APPS/BLINK/DATA.BIN", true).unwrap();
+        let big = pattern(3000, 2);
+        assert_eq!(h.write(blink, &big), Ok(3000));
+        assert_eq!(h.fat.close(blink), ReturnCode::SUCCESS);
+
+        let image = Image::new(h.disk, start);
+        assert_eq!(image.read("APPS/BLINK/DATA.BIN"), big);
+        let dots = image.entries(first_cluster(&image.find("APPS/BLINK")));
+        assert_eq!(&dots[0][..11], b".          ");
+        assert_eq!(&dots[1][..11], b"..         ");
+        assert_eq!(first_cluster(&dots[1]), first_cluster(&image.find("APPS")));
+    });
+}
+
+#[test]
+fn directory_grows_past_a_cluster() {
+    each_volume(|h, start| {
+        assert_eq!(h.mkdir("APPS"), Ok(0));
+        for i in 0..20 {
+            let file = h.open(&format!("APPS/F{}", i), true).unwrap();
+            assert_eq!(h.write(file, &[i as u8; 3]), Ok(3));
+            assert_eq!(h.fat.close(file), ReturnCode::SUCCESS);
+        }
+
+        let listed = h.list("APPS").unwrap();
+        assert_eq!(listed.len(), 20);
+        assert_eq!(listed[19], ("F19".to_string(), 3));
+        let image = Image::new(h.disk, start);
+        assert!(image.chain(first_cluster(&image.find("APPS"))).len() > 1);
+        assert_eq!(image.read("APPS/F7"), vec![7; 3]);
+    });
+}
+
+#[test]
+fn open_file_limit() {
+    each_volume(|h, _| {
+        let files: Vec<usize> = (0..MAX_OPEN_FILES)
+            .map(|i| h.open(&format!("F{}", i), true).unwrap())
+            .collect();
+        assert_eq!(h.open("EXTRA", true), Err(ReturnCode::ENOMEM));
+        assert_eq!(h.fat.close(files[0]), ReturnCode::SUCCESS);
+        let extra = h.open("EXTRA", true).unwrap();
+        assert_eq!(h.fat.close(extra), ReturnCode::SUCCESS);
+        for &file in &files[1..] {
+            assert_eq!(h.fat.close(file), ReturnCode::SUCCESS);
+        }
+    });
+}
+
+#[test]
+fn list_directories() {
+    each_volume(|h, _| {
+        let log = h.open("LOG.TXT", true).unwrap();
+        assert_eq!(h.write(log, &pattern(1500, 1)), Ok(1500));
+        assert_eq!(h.fat.close(log), ReturnCode::SUCCESS);
+        assert_eq!(h.mkdir("APPS"), Ok(0));
+        assert_eq!(h.mkdir("APPS/BLINK"), Ok(0));
+
+        assert_eq!(
+            h.list(""),
+            Ok(vec![
+                ("LOG.TXT".to_string(), 1500),
+                ("APPS/".to_string(), 0)
+            ])
+        );
+        assert_eq!(h.list("APPS"), Ok(vec![("BLINK/".to_string(), 0)]));
+        assert_eq!(h.list("APPS/BLINK"), Ok(vec![]));
+        assert_eq!(h.list("LOG.TXT"), Err(ReturnCode::EINVAL));
+        assert_eq!(h.list("NODIR/APPS"), Err(ReturnCode::EINVAL));
+        assert_eq!(h.list("MISSING"), Err(ReturnCode::EINVAL));
+    });
+}
+
+#[test]
+fn remount() {
+    each_volume(|h, _| {
+        let log = h.open("LOG.TXT", true).unwrap();
+        assert_eq!(h.write(log, &pattern(1500, 1)), Ok(1500));
+        assert_eq!(h.fat.close(log), ReturnCode::SUCCESS);
+
+        let again = Harness::new(h.sim, h.disk);
+        assert_eq!(again.mount(), Ok(0));
+        let log = again.open("LOG.TXT", false).unwrap();
+        assert_eq!(again.fat.file_size(log), Ok(1500));
+        assert_eq!(again.read(log, 10), Ok(pattern(10, 1)));
+    });
+}
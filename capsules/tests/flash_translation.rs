@@ -0,0 +1,420 @@
+//! Host tests for the flash translation layer, on a `MockFlash` that can
+//! lose bits, refuse to erase and have writes cut short by a power loss.
+
+mod common;
+
+use capsules::block_storage::BlockPage;
+use capsules::flash_translation::{
+    FlashTranslation, MountClient, PageState, PhysicalPage, WEAR_LEVEL_THRESHOLD,
+};
+use common::{leak, Device, Sim};
+use kernel::common::cells::TakeCell;
+use kernel::hil::flash::{self, Flash, HasClient};
+use kernel::ReturnCode;
+use std::cell::Cell;
+
+const FLASH_PAGE: usize = 256;
+const PAGE: usize = FLASH_PAGE - capsules::flash_translation::HEADER_SIZE;
+const PHYSICAL_PAGES: usize = 16;
+const LOGICAL_PAGES: usize = 12;
+/// The FTL uses the physical pages after this one.
+const START_PAGE: usize = 2;
+
+type MockFlash = common::flash::MockFlash<FLASH_PAGE>;
+type Ftl = FlashTranslation<'static, MockFlash, PAGE>;
+
+/// Records the result of the last operation.
+struct TestClient {
+    result: Cell<Option<flash::Error>>,
+    mounted: Cell<Option<Result<(), ReturnCode>>>,
+    page: TakeCell<'static, BlockPage<PAGE>>,
+}
+
+impl flash::Client<Ftl> for TestClient {
+    fn read_complete(&self, page: &'static mut BlockPage<PAGE>, error: flash::Error) {
+        self.page.replace(page);
+        self.result.set(Some(error));
+    }
+
+    fn write_complete(&self, page: &'static mut BlockPage<PAGE>, error: flash::Error) {
+        self.page.replace(page);
+        self.result.set(Some(error));
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        self.result.set(Some(error));
+    }
+}
+
+impl MountClient for TestClient {
+    fn mount_done(&self, result: Result<(), ReturnCode>) {
+        self.mounted.set(Some(result));
+    }
+}
+
+struct Harness<'a> {
+    sim: &'a Sim,
+    flash: &'static MockFlash,
+    ftl: &'static Ftl,
+    client: &'static TestClient,
+}
+
+impl<'a> Harness<'a> {
+    /// Creates a new FTL on `flash`, as after a reboot.
+    fn new(sim: &'a Sim, flash: &'static MockFlash) -> Harness<'a> {
+        let ftl = leak(FlashTranslation::new(
+            flash,
+            leak(BlockPage::default()),
+            leak([PhysicalPage::default(); PHYSICAL_PAGES]),
+            START_PAGE,
+            LOGICAL_PAGES,
+            sim.deferred_caller,
+        ));
+        flash.set_client(ftl);
+        ftl.initialize_callback_handle(sim.deferred_caller.register(ftl).unwrap());
+        let client = leak(TestClient {
+            result: Cell::new(None),
+            mounted: Cell::new(None),
+            page: TakeCell::new(leak(BlockPage::default())),
+        });
+        ftl.set_client(client);
+        ftl.set_mount_client(client);
+        Harness {
+            sim,
+            flash,
+            ftl,
+            client,
+        }
+    }
+
+    fn boot(sim: &'a Sim, flash: &'static MockFlash) -> Harness<'a> {
+        let h = Harness::new(sim, flash);
+        assert_eq!(h.ftl.mount(), ReturnCode::SUCCESS);
+        h.run();
+        assert_eq!(h.client.mounted.take(), Some(Ok(())));
+        h
+    }
+
+    /// Completes flash operations and deferred calls until there are none.
+    fn run(&self) {
+        self.sim.pump(&[self.flash]);
+    }
+
+    fn finish(&self) -> flash::Error {
+        self.run();
+        self.client
+            .result
+            .take()
+            .expect("operation did not complete")
+    }
+
+    fn write(&self, logical: usize, fill: u8) -> Result<flash::Error, ReturnCode> {
+        let page = self.client.page.take().unwrap();
+        page.0
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = fill ^ i as u8);
+        match self.ftl.write_page(logical, page) {
+            Ok(()) => Ok(self.finish()),
+            Err((e, page)) => {
+                self.client.page.replace(page);
+                Err(e)
+            }
+        }
+    }
+
+    /// Reads a page and checks that it was written with `fill`.
+    fn check(&self, logical: usize, fill: u8) {
+        let page = self.client.page.take().unwrap();
+        assert!(self.ftl.read_page(logical, page).is_ok());
+        assert_eq!(self.finish(), flash::Error::CommandComplete);
+        self.client.page.map(|page| {
+            for (i, b) in page.0.iter().enumerate() {
+                assert_eq!(*b, fill ^ i as u8, "page {} byte {}", logical, i);
+            }
+        });
+    }
+
+    fn check_erased(&self, logical: usize) {
+        let page = self.client.page.take().unwrap();
+        assert!(self.ftl.read_page(logical, page).is_ok());
+        assert_eq!(self.finish(), flash::Error::CommandComplete);
+        self.client
+            .page
+            .map(|page| assert!(page.0.iter().all(|b| *b == 0xFF)));
+    }
+
+    fn erase(&self, logical: usize) -> flash::Error {
+        assert_eq!(self.ftl.erase_page(logical), ReturnCode::SUCCESS);
+        self.finish()
+    }
+
+    /// The physical pages that are free, counted from `START_PAGE`.
+    fn free_pages(&self) -> Vec<usize> {
+        (0..PHYSICAL_PAGES)
+            .filter(|i| self.ftl.physical_page(*i).unwrap().state == PageState::Free)
+            .collect()
+    }
+}
+
+fn new_flash() -> &'static MockFlash {
+    leak(MockFlash::with_reserved(
+        START_PAGE + PHYSICAL_PAGES,
+        START_PAGE,
+    ))
+}
+
+/// Boots on a new flash and writes every logical page with `fill`.
+fn filled(sim: &Sim, fill: u8) -> Harness {
+    let h = Harness::boot(sim, new_flash());
+    for logical in 0..LOGICAL_PAGES {
+        assert_eq!(h.write(logical, fill), Ok(flash::Error::CommandComplete));
+    }
+    h
+}
+
+/// Makes the first three free pages lose bits or fail to erase, and writes
+/// page 7, which retires them. Returns the retired pages.
+fn retire_three(h: &Harness) -> Vec<usize> {
+    let free = h.free_pages();
+    assert_eq!(free.len(), PHYSICAL_PAGES - LOGICAL_PAGES);
+    h.flash.worn.borrow_mut().insert(START_PAGE + free[0]);
+    h.flash.worn.borrow_mut().insert(START_PAGE + free[1]);
+    h.flash.stuck.borrow_mut().insert(START_PAGE + free[2]);
+    assert_eq!(h.write(7, 0x77), Ok(flash::Error::CommandComplete));
+    free[..3].to_vec()
+}
+
+#[test]
+fn requests_before_mount() {
+    let sim = Sim::new();
+    let h = Harness::new(&sim, new_flash());
+    assert_eq!(h.write(0, 1), Err(ReturnCode::EOFF));
+    assert_eq!(h.ftl.mount(), ReturnCode::SUCCESS);
+    h.run();
+    assert_eq!(h.client.mounted.take(), Some(Ok(())));
+    assert_eq!(h.write(0, 1), Ok(flash::Error::CommandComplete));
+}
+
+#[test]
+fn unwritten_pages_read_erased() {
+    let sim = Sim::new();
+    let h = Harness::boot(&sim, new_flash());
+    // Pages that were never written read as erased, without using the
+    // flash.
+    h.check_erased(0);
+    h.check_erased(LOGICAL_PAGES - 1);
+    assert!(h.flash.erases.borrow().iter().all(|e| *e == 0));
+}
+
+#[test]
+fn read_write() {
+    let sim = Sim::new();
+    let h = Harness::boot(&sim, new_flash());
+    for logical in 0..LOGICAL_PAGES {
+        assert_eq!(
+            h.write(logical, logical as u8),
+            Ok(flash::Error::CommandComplete)
+        );
+    }
+    assert_eq!(h.write(LOGICAL_PAGES, 0), Err(ReturnCode::EINVAL));
+    for logical in 0..LOGICAL_PAGES {
+        h.check(logical, logical as u8);
+    }
+}
+
+#[test]
+fn rewrite_moves_page() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0x50);
+    let before = (0..PHYSICAL_PAGES)
+        .position(|i| {
+            matches!(
+                h.ftl.physical_page(i).unwrap().state,
+                PageState::Valid { logical: 3, .. }
+            )
+        })
+        .unwrap();
+    assert_eq!(h.write(3, 0x33), Ok(flash::Error::CommandComplete));
+    assert_eq!(h.ftl.physical_page(before).unwrap().state, PageState::Free);
+    h.check(3, 0x33);
+    h.check(4, 0x50);
+}
+
+#[test]
+fn erase() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0x50);
+    assert_eq!(h.erase(3), flash::Error::CommandComplete);
+    h.check_erased(3);
+    h.check(4, 0x50);
+}
+
+#[test]
+fn pages_survive_reboot() {
+    let sim = Sim::new();
+    let flash = new_flash();
+    let h = Harness::boot(&sim, flash);
+    for logical in 0..LOGICAL_PAGES {
+        assert_eq!(
+            h.write(logical, logical as u8),
+            Ok(flash::Error::CommandComplete)
+        );
+    }
+    assert_eq!(h.write(4, 0x44), Ok(flash::Error::CommandComplete));
+    assert_eq!(h.erase(3), flash::Error::CommandComplete);
+
+    let h = Harness::boot(&sim, flash);
+    h.check_erased(3);
+    h.check(4, 0x44);
+    for logical in (0..LOGICAL_PAGES).filter(|l| *l != 3 && *l != 4) {
+        h.check(logical, logical as u8);
+    }
+}
+
+#[test]
+fn wear_levelling() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0xC0);
+
+    // Hammer one page while the others hold cold data.
+    const WRITES: u32 = 2000;
+    for i in 0..WRITES {
+        assert_eq!(h.write(0, i as u8), Ok(flash::Error::CommandComplete));
+    }
+
+    // Without wear levelling one page would have been erased for every
+    // write. The cold data has been moved around so that every page has
+    // taken its share.
+    let (min, max) = h.flash.erase_counts();
+    let average = WRITES / PHYSICAL_PAGES as u32;
+    assert!(max - min <= 2 * WEAR_LEVEL_THRESHOLD, "{} {}", min, max);
+    assert!(
+        max <= average + 2 * WEAR_LEVEL_THRESHOLD,
+        "{} {}",
+        average,
+        max
+    );
+    assert_eq!(h.ftl.erase_counts(), Some((min, max)));
+
+    h.check(0, (WRITES - 1) as u8);
+    for logical in 1..LOGICAL_PAGES {
+        h.check(logical, 0xC0);
+    }
+
+    // The erase counts are kept in the pages, so they survive a reboot.
+    let h = Harness::boot(&sim, h.flash);
+    assert_eq!(h.ftl.erase_counts(), Some((min, max)));
+    h.check(0, (WRITES - 1) as u8);
+}
+
+#[test]
+fn torn_write() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0x50);
+
+    // Power is lost in the middle of writing a new copy, after the erase.
+    let page = h.client.page.take().unwrap();
+    page.0 = [0xAA; PAGE];
+    assert!(h.ftl.write_page(5, page).is_ok());
+    assert!(h.flash.run());
+    h.flash.tear.set(true);
+    assert!(h.flash.run());
+    assert!(h.flash.cut_power());
+
+    let h = Harness::boot(&sim, h.flash);
+    h.check(5, 0x50);
+    assert_eq!(h.ftl.bad_pages(), 0);
+    assert_eq!(h.write(5, 0x55), Ok(flash::Error::CommandComplete));
+    h.check(5, 0x55);
+}
+
+#[test]
+fn erase_counts_survive_reboot() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0x50);
+    for i in 0..200 {
+        assert_eq!(h.write(i % 2, i as u8), Ok(flash::Error::CommandComplete));
+    }
+
+    // Power is lost right after a page is erased, and in the middle of
+    // writing a copy to another page.
+    let page = h.client.page.take().unwrap();
+    assert!(h.ftl.write_page(2, page).is_ok());
+    assert!(h.flash.run());
+    assert!(h.flash.cut_power());
+    let h = Harness::boot(&sim, h.flash);
+    let page = h.client.page.take().unwrap();
+    assert!(h.ftl.write_page(3, page).is_ok());
+    assert!(h.flash.run());
+    h.flash.tear.set(true);
+    assert!(h.flash.run());
+    assert!(h.flash.cut_power());
+
+    // Free pages, written or not, keep their erase counts.
+    let h = Harness::boot(&sim, h.flash);
+    let (min, max) = h.flash.erase_counts();
+    assert!(min > 0);
+    assert_eq!(h.ftl.erase_counts(), Some((min, max)));
+    let erases = h.flash.erases.borrow();
+    for index in 0..PHYSICAL_PAGES {
+        assert_eq!(
+            h.ftl.physical_page(index).unwrap().erase_count,
+            erases[START_PAGE + index],
+            "page {}",
+            index
+        );
+    }
+    h.check(2, 0x50);
+    h.check(3, 0x50);
+}
+
+#[test]
+fn bad_pages_are_retired() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0x50);
+
+    // Pages that lose bits or don't erase are retired, and the write goes
+    // to a good page.
+    let bad = retire_three(&h);
+    h.check(7, 0x77);
+    assert_eq!(h.ftl.bad_pages(), 3);
+    for bad in &bad {
+        assert_eq!(h.ftl.physical_page(*bad).unwrap().state, PageState::Bad);
+    }
+}
+
+#[test]
+fn retired_pages_survive_reboot() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0x50);
+    let bad = retire_three(&h);
+
+    // Pages that could be marked stay retired after a reboot. The page that
+    // doesn't erase could still be marked, since NOR flash writes can clear
+    // bits.
+    let h = Harness::boot(&sim, h.flash);
+    assert_eq!(h.ftl.bad_pages(), 3);
+    for bad in &bad {
+        assert_eq!(h.ftl.physical_page(*bad).unwrap().state, PageState::Bad);
+    }
+    h.check(7, 0x77);
+}
+
+#[test]
+fn last_spare_page_fails() {
+    let sim = Sim::new();
+    let h = filled(&sim, 0x50);
+    retire_three(&h);
+
+    // When the last spare page fails the write fails, and the old copy is
+    // kept.
+    let free = h.free_pages();
+    assert_eq!(free.len(), 1);
+    h.flash.worn.borrow_mut().insert(START_PAGE + free[0]);
+    assert_eq!(h.write(8, 0x88), Ok(flash::Error::FlashError));
+    h.check(8, 0x50);
+    assert_eq!(h.write(8, 0x88), Err(ReturnCode::ENOMEM));
+    assert_eq!(h.ftl.bad_pages(), 4);
+    h.check(8, 0x50);
+}
@@ -0,0 +1,456 @@
+//! Runs `capsules::kv_encryption` over an in-memory `hil::kv_system` and the
+//! software AES-CCM implementation, and checks that stored values are
+//! confidential, bound to their key and protected against rollback.
+
+mod common;
+
+use capsules::kv_encryption::{EncryptedKVSystem, RollbackCounter, COUNTER_RESERVATION};
+use capsules::software_aes::SoftwareAes128;
+use capsules::virtual_aes_ccm::{MuxAES128CCM, VirtualAES128CCM};
+use common::kv::{Key, MockKV};
+use common::{leak, leak_buf, Sim};
+use kernel::common::cells::TakeCell;
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::hil::symmetric_encryption::{AES128, AES128CCM};
+use kernel::ReturnCode;
+use std::cell::Cell;
+
+type Ccm = VirtualAES128CCM<'static, SoftwareAes128<'static>>;
+type Layer = EncryptedKVSystem<'static, MockKV, Ccm>;
+
+const SECRET: [u8; 16] = [
+    0x10, 0x32, 0x54, 0x76, 0x98, 0xBA, 0xDC, 0xFE, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
+];
+
+struct Counter(Cell<u32>);
+
+impl RollbackCounter for Counter {
+    fn get(&self) -> u32 {
+        self.0.get()
+    }
+
+    fn advance(&self, value: u32) -> ReturnCode {
+        if value < self.0.get() {
+            return ReturnCode::EINVAL;
+        }
+        self.0.set(value);
+        ReturnCode::SUCCESS
+    }
+}
+
+struct TestClient {
+    result: Cell<Option<Result<(), ReturnCode>>>,
+    cursor: Cell<usize>,
+    key: TakeCell<'static, Key>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl kv_system::Client<Key> for TestClient {
+    fn generate_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        _unhashed_key: &'static mut [u8],
+        key_buf: &'static mut Key,
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key_buf);
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut Key,
+        value: &'static mut [u8],
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+        self.buffer.replace(value);
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut Key,
+        ret_buf: &'static mut [u8],
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+        self.buffer.replace(ret_buf);
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ReturnCode>, key: &'static mut Key) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+    }
+
+    fn garbage_collect_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn next_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key_buf: &'static mut Key,
+        cursor: usize,
+    ) {
+        self.result.set(Some(result));
+        self.cursor.set(cursor);
+        self.key.replace(key_buf);
+    }
+
+    fn storage_stats_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        _stats: kv_system::StorageStats,
+    ) {
+        self.result.set(Some(result));
+    }
+
+    fn check_consistency_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        _found: usize,
+        _repaired: usize,
+    ) {
+        self.result.set(Some(result));
+    }
+
+    fn begin_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn stage_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut Key,
+        value: &'static mut [u8],
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+        self.buffer.replace(value);
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn abort_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+}
+
+/// An encryption layer over `kv`, as it would be after a reboot.
+struct Harness<'a> {
+    sim: &'a Sim,
+    kv: &'static MockKV,
+    layer: &'static Layer,
+    client: &'static TestClient,
+}
+
+impl<'a> Harness<'a> {
+    fn new(
+        sim: &'a Sim,
+        ccm: &'static Ccm,
+        kv: &'static MockKV,
+        rollback_counter: Option<&'static Counter>,
+    ) -> Harness<'a> {
+        let layer = leak(EncryptedKVSystem::new(
+            kv,
+            ccm,
+            &SECRET,
+            leak_buf(64),
+            leak([0; 8]),
+        ));
+        let client = leak(TestClient {
+            result: Cell::new(None),
+            cursor: Cell::new(0),
+            key: TakeCell::new(leak([0; 8])),
+            buffer: TakeCell::new(leak_buf(64)),
+        });
+        kv.client.set(layer);
+        ccm.set_client(layer);
+        layer.set_client(client);
+        if let Some(rollback_counter) = rollback_counter {
+            layer.set_rollback_counter(rollback_counter);
+        }
+
+        let h = Harness {
+            sim,
+            kv,
+            layer,
+            client,
+        };
+        layer.initialise();
+        h.pump();
+        h
+    }
+
+    /// Run the KV system and the AES deferred calls until both are idle.
+    fn pump(&self) {
+        self.sim.pump(&[self.kv]);
+    }
+
+    fn complete(&self) -> ReturnCode {
+        self.pump();
+        match self
+            .client
+            .result
+            .take()
+            .expect("operation did not complete")
+        {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(e) => e,
+        }
+    }
+
+    fn append(&self, key: Key, value: &[u8]) -> ReturnCode {
+        let key_buf = self.client.key.take().unwrap();
+        let buffer = self.client.buffer.take().unwrap();
+        *key_buf = key;
+        buffer[..value.len()].copy_from_slice(value);
+        if let Err((key_buf, buffer, e)) = self.layer.append_key(key_buf, buffer, value.len()) {
+            self.client.key.replace(key_buf);
+            self.client.buffer.replace(buffer);
+            return e;
+        }
+        self.complete()
+    }
+
+    fn stage(&self, key: Key, value: &[u8]) -> ReturnCode {
+        let key_buf = self.client.key.take().unwrap();
+        let buffer = self.client.buffer.take().unwrap();
+        *key_buf = key;
+        buffer[..value.len()].copy_from_slice(value);
+        if let Err((key_buf, buffer, e)) = self.layer.stage_key(key_buf, buffer, value.len()) {
+            self.client.key.replace(key_buf);
+            self.client.buffer.replace(buffer);
+            return e;
+        }
+        self.complete()
+    }
+
+    fn get(&self, key: Key) -> (ReturnCode, Vec<u8>) {
+        let key_buf = self.client.key.take().unwrap();
+        let buffer = self.client.buffer.take().unwrap();
+        *key_buf = key;
+        for b in buffer.iter_mut() {
+            *b = 0;
+        }
+        if let Err((key_buf, buffer, e)) = self.layer.get_value(key_buf, buffer) {
+            self.client.key.replace(key_buf);
+            self.client.buffer.replace(buffer);
+            return (e, Vec::new());
+        }
+        let ret = self.complete();
+        let value = self.client.buffer.map(|buf| buf.to_vec()).unwrap();
+        (ret, value)
+    }
+
+    fn invalidate(&self, key: Key) -> ReturnCode {
+        let key_buf = self.client.key.take().unwrap();
+        *key_buf = key;
+        if let Err((key_buf, e)) = self.layer.invalidate_key(key_buf) {
+            self.client.key.replace(key_buf);
+            return e;
+        }
+        self.complete()
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let key_buf = self.client.key.take().unwrap();
+            if let Err((key_buf, e)) = self.layer.next_key(cursor, key_buf) {
+                self.client.key.replace(key_buf);
+                panic!("next_key failed: {:?}", e);
+            }
+            if self.complete() != ReturnCode::SUCCESS {
+                return keys;
+            }
+            keys.push(self.client.key.map(|key| *key).unwrap());
+            cursor = self.client.cursor.get();
+        }
+    }
+
+    fn stored(&self, key: &Key) -> Vec<u8> {
+        let entries = self.kv.entries.borrow();
+        entries.iter().find(|(k, _)| k == key).unwrap().1.clone()
+    }
+}
+
+fn padded(value: &[u8]) -> Vec<u8> {
+    let mut padded = value.to_vec();
+    padded.resize(64, 0);
+    padded
+}
+
+fn new_ccm(sim: &Sim) -> &'static Ccm {
+    let aes = leak(SoftwareAes128::new(sim.deferred_caller));
+    aes.initialize_callback_handle(sim.deferred_caller.register(aes).unwrap());
+    let aes_mux = leak(MuxAES128CCM::new(aes, sim.deferred_caller));
+    aes_mux.initialize_callback_handle(sim.deferred_caller.register(aes_mux).unwrap());
+    AES128::set_client(aes, aes_mux);
+    let ccm: &'static Ccm = leak(VirtualAES128CCM::new(aes_mux, leak_buf(128)));
+    ccm.setup();
+    aes_mux.enable();
+    ccm
+}
+
+#[test]
+fn encrypted_values() {
+    let sim = Sim::new();
+    let ccm = new_ccm(&sim);
+
+    let config = *b"config\0\0";
+    let other = *b"other\0\0\0";
+    let kv: &'static MockKV = leak(MockKV::new());
+    let counter: &'static Counter = leak(Counter(Cell::new(0)));
+
+    // Values round trip and are not stored in the clear.
+    let h = Harness::new(&sim, ccm, kv, Some(counter));
+    assert_eq!(h.layer.max_value_length(), 64 - 15 - 8);
+    assert_eq!(h.get(config).0, ReturnCode::ENOSUPPORT);
+    assert_eq!(h.append(config, b"secret value"), ReturnCode::SUCCESS);
+    assert_eq!(
+        h.get(config),
+        (ReturnCode::SUCCESS, padded(b"secret value"))
+    );
+    let stored = h.stored(&config);
+    assert_eq!(stored.len(), 15 + 12 + 8);
+    assert!(!stored.windows(6).any(|w| w == b"secret"));
+    assert_eq!(counter.get(), COUNTER_RESERVATION);
+    assert_eq!(h.append(config, b"x"), ReturnCode::ENOSUPPORT);
+    assert_eq!(h.append(other, &[0; 42]), ReturnCode::ESIZE);
+
+    // Listing keys skips the counter and freshness records.
+    assert_eq!(kv.entries.borrow().len(), 3);
+    assert_eq!(h.keys(), vec![config]);
+
+    // Invalidation is passed through to the KV system.
+    assert_eq!(h.append(other, b"other value"), ReturnCode::SUCCESS);
+    assert_eq!(h.invalidate(other), ReturnCode::SUCCESS);
+    assert_eq!(h.get(other).0, ReturnCode::ENOSUPPORT);
+
+    // Modified values fail to verify.
+    {
+        let mut entries = kv.entries.borrow_mut();
+        let entry = entries.iter_mut().find(|(k, _)| *k == config).unwrap();
+        entry.1[16] ^= 0x01;
+    }
+    assert_eq!(h.get(config).0, ReturnCode::FAIL);
+    {
+        let mut entries = kv.entries.borrow_mut();
+        let entry = entries.iter_mut().find(|(k, _)| *k == config).unwrap();
+        entry.1[16] ^= 0x01;
+    }
+    assert_eq!(h.get(config).0, ReturnCode::SUCCESS);
+
+    // A value copied to another key fails to verify.
+    kv.entries.borrow_mut().push((other, stored.clone()));
+    assert_eq!(h.get(other).0, ReturnCode::FAIL);
+    assert_eq!(h.invalidate(other), ReturnCode::SUCCESS);
+
+    // After a reboot the values can still be read and new values use
+    // counters that have not been used before.
+    let snapshot = kv.entries.borrow().clone();
+    let h = Harness::new(&sim, ccm, kv, Some(counter));
+    assert!(!h.layer.rollback_detected());
+    assert_eq!(
+        h.get(config),
+        (ReturnCode::SUCCESS, padded(b"secret value"))
+    );
+    assert_eq!(h.append(other, b"after reboot"), ReturnCode::SUCCESS);
+    assert_eq!(counter.get(), 2 * COUNTER_RESERVATION);
+    assert_ne!(h.stored(&other)[3..7], stored[3..7]);
+
+    // Restoring an old copy of the flash is detected by the rollback counter.
+    *kv.entries.borrow_mut() = snapshot;
+    let h = Harness::new(&sim, ccm, kv, Some(counter));
+    assert!(h.layer.rollback_detected());
+    assert_eq!(h.get(config).0, ReturnCode::FAIL);
+    assert_eq!(h.append(other, b"x"), ReturnCode::FAIL);
+    assert_eq!(h.invalidate(config), ReturnCode::FAIL);
+
+    // So is removing the counter records.
+    kv.entries.borrow_mut().retain(|(k, _)| *k == config);
+    let h = Harness::new(&sim, ccm, kv, Some(counter));
+    assert!(h.layer.rollback_detected());
+
+    // A value sealed with a counter that was never reserved cannot have been
+    // written by this store.
+    let h = Harness::new(&sim, ccm, leak(MockKV::new()), None);
+    h.kv.entries.borrow_mut().push((config, stored));
+    assert_eq!(h.get(config).0, ReturnCode::FAIL);
+}
+
+#[test]
+fn restored_values_fail_to_verify() {
+    let sim = Sim::new();
+    let ccm = new_ccm(&sim);
+    let config = *b"config\0\0";
+    let other = *b"other\0\0\0";
+    let kv: &'static MockKV = leak(MockKV::new());
+    let counter: &'static Counter = leak(Counter(Cell::new(0)));
+    let h = Harness::new(&sim, ccm, kv, Some(counter));
+
+    assert_eq!(h.append(config, b"old value"), ReturnCode::SUCCESS);
+    let old = h.stored(&config);
+    assert_eq!(h.invalidate(config), ReturnCode::SUCCESS);
+    assert_eq!(kv.entries.borrow().len(), 1, "only the counter record");
+    assert_eq!(h.append(config, b"new value"), ReturnCode::SUCCESS);
+    assert_eq!(h.append(other, b"other value"), ReturnCode::SUCCESS);
+
+    // Putting the older copy of one value back in place of the newest one
+    // is detected, before and after a reboot, and the other values are
+    // unaffected.
+    {
+        let mut entries = kv.entries.borrow_mut();
+        let entry = entries.iter_mut().find(|(k, _)| *k == config).unwrap();
+        entry.1 = old;
+    }
+    assert_eq!(h.get(config), (ReturnCode::FAIL, vec![0; 64]));
+    let h = Harness::new(&sim, ccm, kv, Some(counter));
+    assert!(!h.layer.rollback_detected());
+    assert_eq!(h.get(config).0, ReturnCode::FAIL);
+    assert_eq!(h.get(other), (ReturnCode::SUCCESS, padded(b"other value")));
+
+    // Writing the key again makes it readable.
+    assert_eq!(h.invalidate(config), ReturnCode::SUCCESS);
+    assert_eq!(h.append(config, b"newest value"), ReturnCode::SUCCESS);
+    assert_eq!(
+        h.get(config),
+        (ReturnCode::SUCCESS, padded(b"newest value"))
+    );
+}
+
+#[test]
+fn staged_values() {
+    let sim = Sim::new();
+    let ccm = new_ccm(&sim);
+    let config = *b"config\0\0";
+    let kv: &'static MockKV = leak(MockKV::new());
+    let h = Harness::new(&sim, ccm, kv, None);
+    assert_eq!(h.append(config, b"old value"), ReturnCode::SUCCESS);
+
+    // Staged values are sealed, and replace the old value on commit.
+    assert_eq!(h.stage(config, b"new value"), ReturnCode::EINVAL);
+    assert_eq!(h.layer.begin_transaction(), Ok(()));
+    assert_eq!(h.complete(), ReturnCode::SUCCESS);
+    assert_eq!(h.stage(config, b"new value"), ReturnCode::SUCCESS);
+    let staged = kv.staged.borrow().as_ref().unwrap()[0].1.clone();
+    assert!(!staged.windows(9).any(|w| w == b"new value"));
+    assert_eq!(h.get(config), (ReturnCode::SUCCESS, padded(b"old value")));
+    assert_eq!(h.layer.commit_transaction(), Ok(()));
+    assert_eq!(h.complete(), ReturnCode::SUCCESS);
+    assert_eq!(h.get(config), (ReturnCode::SUCCESS, padded(b"new value")));
+
+    // Aborting discards the staged values.
+    assert_eq!(h.layer.begin_transaction(), Ok(()));
+    assert_eq!(h.complete(), ReturnCode::SUCCESS);
+    assert_eq!(h.stage(config, b"aborted"), ReturnCode::SUCCESS);
+    assert_eq!(h.layer.abort_transaction(), Ok(()));
+    assert_eq!(h.complete(), ReturnCode::SUCCESS);
+    assert_eq!(h.get(config), (ReturnCode::SUCCESS, padded(b"new value")));
+    assert_eq!(h.layer.commit_transaction(), Err(ReturnCode::EINVAL));
+}
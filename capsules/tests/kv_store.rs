@@ -0,0 +1,241 @@
+//! Runs `capsules::kv_store` over an in-memory `hil::kv_system` and checks
+//! namespacing, ownership and permission handling.
+
+mod common;
+
+use capsules::kv_store::KVStore;
+use common::kv::MockKV;
+use common::Device;
+use kernel::capabilities;
+use kernel::common::cells::TakeCell;
+use kernel::create_capability;
+use kernel::hil::kv_store::{KVStore as _, StoragePermissions, StoreClient};
+use kernel::ReturnCode;
+use std::cell::Cell;
+use std::cmp;
+
+struct TestClient {
+    result: Cell<Option<Result<(), ReturnCode>>>,
+    length: Cell<usize>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl StoreClient for TestClient {
+    fn get_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        value: &'static mut [u8],
+        length: usize,
+    ) {
+        self.result.set(Some(result));
+        self.length.set(length);
+        self.buffer.replace(value);
+    }
+
+    fn set_complete(&self, result: Result<(), ReturnCode>, value: &'static mut [u8]) {
+        self.result.set(Some(result));
+        self.buffer.replace(value);
+    }
+
+    fn delete_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+}
+
+struct Harness {
+    kv: &'static MockKV,
+    store: &'static KVStore<'static, MockKV>,
+    client: &'static TestClient,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        let kv: &'static MockKV = Box::leak(Box::new(MockKV::new()));
+        let store: &'static KVStore<'static, MockKV> = Box::leak(Box::new(KVStore::new(
+            kv,
+            Box::leak(Box::new([0; 32])),
+            Box::leak(Box::new([0; 8])),
+            Box::leak(Box::new([0; 24])),
+        )));
+        let client: &'static TestClient = Box::leak(Box::new(TestClient {
+            result: Cell::new(None),
+            length: Cell::new(0),
+            buffer: TakeCell::new(Box::leak(Box::new([0; 16]))),
+        }));
+        kv.client.set(store);
+        store.set_client(client);
+        Harness { kv, store, client }
+    }
+
+    fn set(&self, permissions: StoragePermissions, key: &[u8], value: &[u8]) -> ReturnCode {
+        let buffer = self.client.buffer.take().unwrap();
+        buffer[..value.len()].copy_from_slice(value);
+        if let Err((buffer, e)) = self.store.set(permissions, key, buffer, value.len()) {
+            self.client.buffer.replace(buffer);
+            return e;
+        }
+        self.complete()
+    }
+
+    fn get(&self, permissions: StoragePermissions, key: &[u8]) -> (ReturnCode, Vec<u8>) {
+        let buffer = self.client.buffer.take().unwrap();
+        if let Err((buffer, e)) = self.store.get(permissions, key, buffer) {
+            self.client.buffer.replace(buffer);
+            return (e, Vec::new());
+        }
+        let ret = self.complete();
+        let length = self.client.length.get();
+        let value = self
+            .client
+            .buffer
+            .map(|buf| buf[..cmp::min(length, buf.len())].to_vec());
+        (ret, value.unwrap())
+    }
+
+    fn delete(&self, permissions: StoragePermissions, key: &[u8]) -> ReturnCode {
+        if let Err(e) = self.store.delete(permissions, key) {
+            return e;
+        }
+        self.complete()
+    }
+
+    fn complete(&self) -> ReturnCode {
+        self.kv.run();
+        match self
+            .client
+            .result
+            .take()
+            .expect("operation did not complete")
+        {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(e) => e,
+        }
+    }
+}
+
+#[test]
+fn set_get_delete() {
+    let h = Harness::new();
+    let app = StoragePermissions::for_app(b"org.tockos.app");
+
+    assert_eq!(h.get(app, b"config").0, ReturnCode::ENOSUPPORT);
+    assert_eq!(h.set(app, b"config", b"hello"), ReturnCode::SUCCESS);
+    assert_eq!(
+        h.get(app, b"config"),
+        (ReturnCode::SUCCESS, b"hello".to_vec())
+    );
+
+    assert_eq!(h.set(app, b"config", b"bye"), ReturnCode::SUCCESS);
+    assert_eq!(
+        h.get(app, b"config"),
+        (ReturnCode::SUCCESS, b"bye".to_vec())
+    );
+    assert_eq!(h.kv.entries.borrow().len(), 1);
+
+    assert_eq!(h.delete(app, b"config"), ReturnCode::SUCCESS);
+    assert_eq!(h.get(app, b"config").0, ReturnCode::ENOSUPPORT);
+    assert_eq!(h.delete(app, b"config"), ReturnCode::ENOSUPPORT);
+}
+
+#[test]
+fn namespaces_are_isolated() {
+    let h = Harness::new();
+    let one = StoragePermissions::for_app(b"one");
+    let two = StoragePermissions::for_app(b"two");
+
+    assert_eq!(h.set(one, b"key", b"1"), ReturnCode::SUCCESS);
+    assert_eq!(h.set(two, b"key", b"2"), ReturnCode::SUCCESS);
+    assert_eq!(h.get(one, b"key"), (ReturnCode::SUCCESS, b"1".to_vec()));
+    assert_eq!(h.get(two, b"key"), (ReturnCode::SUCCESS, b"2".to_vec()));
+
+    // The namespace and key are length prefixed, so moving bytes between
+    // them gives a different key.
+    assert_eq!(
+        h.get(StoragePermissions::for_app(b"on"), b"ekey").0,
+        ReturnCode::ENOSUPPORT
+    );
+}
+
+#[test]
+fn colliding_keys_respect_owner() {
+    let h = Harness::new();
+    h.kv.collide.set(true);
+    let one = StoragePermissions::for_app(b"one");
+    let two = StoragePermissions::for_app(b"two");
+
+    assert_eq!(h.set(one, b"a", b"secret"), ReturnCode::SUCCESS);
+    assert_eq!(h.get(two, b"b").0, ReturnCode::ENOSUPPORT);
+    assert_eq!(h.set(two, b"b", b"x"), ReturnCode::ERESERVE);
+    assert_eq!(h.delete(two, b"b"), ReturnCode::ERESERVE);
+    assert_eq!(h.get(one, b"a"), (ReturnCode::SUCCESS, b"secret".to_vec()));
+}
+
+#[test]
+fn privileged_values_are_protected() {
+    let h = Harness::new();
+    let app = StoragePermissions::for_app(b"app");
+    let kernel = StoragePermissions::for_kernel(
+        b"app",
+        &create_capability!(capabilities::KVStorePrivilegedCapability),
+    );
+
+    assert_eq!(h.set(kernel, b"limit", b"10"), ReturnCode::SUCCESS);
+    assert_eq!(h.get(app, b"limit"), (ReturnCode::SUCCESS, b"10".to_vec()));
+    assert_eq!(h.set(app, b"limit", b"99"), ReturnCode::ERESERVE);
+    assert_eq!(h.delete(app, b"limit"), ReturnCode::ERESERVE);
+
+    // Privileged callers can replace values written by the app.
+    assert_eq!(h.set(app, b"name", b"a"), ReturnCode::SUCCESS);
+    assert_eq!(h.set(kernel, b"name", b"b"), ReturnCode::SUCCESS);
+    assert_eq!(h.get(app, b"name"), (ReturnCode::SUCCESS, b"b".to_vec()));
+    assert_eq!(h.delete(kernel, b"limit"), ReturnCode::SUCCESS);
+}
+
+#[test]
+fn size_limits() {
+    let h = Harness::new();
+    let app = StoragePermissions::for_app(b"app");
+
+    assert_eq!(h.store.max_value_length(), 16);
+    assert_eq!(h.set(app, b"", b"x"), ReturnCode::EINVAL);
+    assert_eq!(h.set(app, &[0x41; 28], b"x"), ReturnCode::ESIZE);
+    assert_eq!(h.set(app, &[0x41; 27], b"x"), ReturnCode::SUCCESS);
+
+    // A value that does not fit the caller's buffer is truncated and the
+    // full length is reported.
+    assert_eq!(h.set(app, b"big", &[0x55; 16]), ReturnCode::SUCCESS);
+    let buffer = h.client.buffer.take().unwrap();
+    h.client.buffer.replace(buffer.split_at_mut(4).0);
+    assert_eq!(h.get(app, b"big"), (ReturnCode::ESIZE, vec![0x55; 4]));
+    assert_eq!(h.client.length.get(), 16);
+}
+
+#[test]
+fn failed_append_is_reported() {
+    let h = Harness::new();
+    h.kv.capacity.set(1);
+    let app = StoragePermissions::for_app(b"app");
+
+    assert_eq!(h.set(app, b"one", b"1"), ReturnCode::SUCCESS);
+    assert_eq!(h.set(app, b"two", b"2"), ReturnCode::ENOMEM);
+    assert_eq!(h.get(app, b"one"), (ReturnCode::SUCCESS, b"1".to_vec()));
+}
+
+#[test]
+fn failed_replace_keeps_old_value() {
+    let h = Harness::new();
+    h.kv.capacity.set(1);
+    let app = StoragePermissions::for_app(b"app");
+
+    // The new value is staged in a transaction, which is aborted when the
+    // store is full.
+    assert_eq!(h.set(app, b"one", b"1"), ReturnCode::SUCCESS);
+    assert_eq!(h.set(app, b"one", b"2"), ReturnCode::ENOMEM);
+    assert!(h.kv.staged.borrow().is_none());
+    assert_eq!(h.get(app, b"one"), (ReturnCode::SUCCESS, b"1".to_vec()));
+
+    h.kv.capacity.set(2);
+    assert_eq!(h.set(app, b"one", b"3"), ReturnCode::SUCCESS);
+    assert_eq!(h.get(app, b"one"), (ReturnCode::SUCCESS, b"3".to_vec()));
+    assert_eq!(h.kv.entries.borrow().len(), 1);
+}
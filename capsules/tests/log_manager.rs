@@ -0,0 +1,434 @@
+//! Host tests for `capsules::log_manager`: several `capsules::log::Log`s on
+//! parts of one volume, sharing a flash through `MuxFlash`.
+
+mod common;
+
+use capsules::block_storage::BlockPage;
+use capsules::log::Log;
+use capsules::log_manager::{self, log_volume, LogConfig, LogManager, ManagedLog};
+use capsules::virtual_flash::{FlashUser, MuxFlash};
+use common::{leak, leak_buf, Sim};
+use kernel::common::cells::TakeCell;
+use kernel::hil::flash::HasClient;
+use kernel::hil::log::{LogRead, LogReadClient};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+const PAGE: usize = 64;
+
+type MappedFlash = common::flash::MappedFlash<PAGE>;
+
+const CONFIGS: [LogConfig; 3] = [
+    LogConfig {
+        name: "events",
+        owner: "sensor",
+        size: 4 * PAGE,
+        circular: true,
+    },
+    LogConfig {
+        name: "audit",
+        owner: "monitor",
+        size: 2 * PAGE,
+        circular: false,
+    },
+    LogConfig {
+        name: "trace",
+        owner: "sensor",
+        size: 2 * PAGE,
+        circular: true,
+    },
+];
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Read(usize, ReturnCode, usize),
+    Seek(usize, ReturnCode),
+    Append(usize, ReturnCode, usize, bool),
+    Sync(usize, ReturnCode),
+    Erase(usize, ReturnCode),
+}
+
+struct Recorder {
+    events: RefCell<Vec<Event>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl log_manager::Client for Recorder {
+    fn read_done(
+        &self,
+        handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+        error: ReturnCode,
+    ) {
+        self.buffer.replace(buffer);
+        self.events
+            .borrow_mut()
+            .push(Event::Read(handle, error, length));
+    }
+
+    fn seek_done(&self, handle: usize, error: ReturnCode) {
+        self.events.borrow_mut().push(Event::Seek(handle, error));
+    }
+
+    fn append_done(
+        &self,
+        handle: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    ) {
+        self.buffer.replace(buffer);
+        self.events
+            .borrow_mut()
+            .push(Event::Append(handle, error, length, records_lost));
+    }
+
+    fn sync_done(&self, handle: usize, error: ReturnCode) {
+        self.events.borrow_mut().push(Event::Sync(handle, error));
+    }
+
+    fn erase_done(&self, handle: usize, error: ReturnCode) {
+        self.events.borrow_mut().push(Event::Erase(handle, error));
+    }
+}
+
+/// Reads a log directly, without a manager.
+struct DirectReader {
+    done: Cell<Option<(ReturnCode, usize)>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl LogReadClient for DirectReader {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode) {
+        self.buffer.replace(buffer);
+        self.done.set(Some((error, length)));
+    }
+
+    fn seek_done(&self, _error: ReturnCode) {}
+}
+
+struct Test<'a> {
+    sim: &'a Sim,
+    flash: &'static MappedFlash,
+    mux: &'static MuxFlash<'static, MappedFlash>,
+    volumes: Vec<&'static [u8]>,
+    manager: &'static LogManager<'static>,
+    recorder: &'static Recorder,
+}
+
+impl<'a> Test<'a> {
+    /// A manager of `CONFIGS` on a volume with a spare page at the end.
+    fn new(sim: &'a Sim) -> Test<'a> {
+        let total: usize = CONFIGS.iter().map(|config| config.size).sum();
+        let (flash, volume) = MappedFlash::new(total + PAGE);
+        let flash = leak(flash);
+        let volumes: Vec<&'static [u8]> = (0..CONFIGS.len())
+            .map(|i| log_volume(volume, PAGE, &CONFIGS, i).unwrap())
+            .collect();
+
+        let mux = leak(MuxFlash::new(flash));
+        flash.set_client(mux);
+        let mut managed = Vec::new();
+        for (config, volume) in CONFIGS.iter().zip(volumes.iter()) {
+            let log: &'static Log<'static, FlashUser<'static, MappedFlash>> =
+                new_log(sim, mux, volume, config.circular);
+            managed.push(&*leak(ManagedLog::new(log, *config)));
+        }
+        let logs: &'static [&'static ManagedLog<'static>] = managed.leak();
+        let manager = leak(LogManager::new(logs));
+        manager.setup();
+        let recorder = leak(Recorder {
+            events: RefCell::new(Vec::new()),
+            buffer: TakeCell::new(leak_buf(PAGE)),
+        });
+        manager.set_client(recorder);
+        Test {
+            sim,
+            flash,
+            mux,
+            volumes,
+            manager,
+            recorder,
+        }
+    }
+
+    fn pump(&self) {
+        self.sim.pump(&[self.flash]);
+    }
+
+    fn event(&self) -> Event {
+        self.pump();
+        let mut events = self.recorder.events.borrow_mut();
+        assert_eq!(events.len(), 1, "expected one callback: {:?}", *events);
+        events.pop().unwrap()
+    }
+
+    fn append(&self, handle: usize, data: &[u8]) -> Result<bool, ReturnCode> {
+        let log = self.manager.log(handle).unwrap();
+        let buffer = self.recorder.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        match log.append(buffer, data.len()) {
+            Ok(()) => match self.event() {
+                Event::Append(h, ReturnCode::SUCCESS, length, lost) => {
+                    assert_eq!((h, length), (handle, data.len()));
+                    Ok(lost)
+                }
+                Event::Append(_, e, _, _) => Err(e),
+                event => panic!("unexpected {:?}", event),
+            },
+            Err((e, buffer)) => {
+                self.recorder.buffer.replace(buffer.unwrap());
+                Err(e)
+            }
+        }
+    }
+
+    fn read(&self, handle: usize) -> Result<Vec<u8>, ReturnCode> {
+        let log = self.manager.log(handle).unwrap();
+        let buffer = self.recorder.buffer.take().unwrap();
+        let length = buffer.len();
+        match log.read(buffer, length) {
+            Ok(()) => match self.event() {
+                Event::Read(h, ReturnCode::SUCCESS, length) => {
+                    assert_eq!(h, handle);
+                    Ok(self.recorder.buffer.map(|b| b[..length].to_vec()).unwrap())
+                }
+                Event::Read(_, e, _) => Err(e),
+                event => panic!("unexpected {:?}", event),
+            },
+            Err((e, buffer)) => {
+                self.recorder.buffer.replace(buffer.unwrap());
+                Err(e)
+            }
+        }
+    }
+
+    fn read_all(&self, handle: usize) -> Vec<Vec<u8>> {
+        let mut entries = Vec::new();
+        loop {
+            match self.read(handle) {
+                Ok(entry) => entries.push(entry),
+                Err(ReturnCode::FAIL) => return entries,
+                Err(e) => panic!("read failed: {:?}", e),
+            }
+        }
+    }
+
+    fn sync(&self, handle: usize) {
+        assert_eq!(
+            self.manager.log(handle).unwrap().sync(),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(self.event(), Event::Sync(handle, ReturnCode::SUCCESS));
+    }
+}
+
+fn entry(i: usize, length: usize) -> Vec<u8> {
+    (0..length).map(|j| (i * 31 + j) as u8).collect()
+}
+
+fn new_log(
+    sim: &Sim,
+    mux: &'static MuxFlash<'static, MappedFlash>,
+    volume: &'static [u8],
+    circular: bool,
+) -> &'static Log<'static, FlashUser<'static, MappedFlash>> {
+    let user = leak(FlashUser::new(mux));
+    let log = leak(Log::new(
+        volume,
+        user,
+        leak(BlockPage::default()),
+        sim.deferred_caller,
+        circular,
+    ));
+    user.set_client(log);
+    log.initialize_callback_handle(sim.deferred_caller.register(log).unwrap());
+    log
+}
+
+/// The contents of all volumes but `volume`.
+fn others(volumes: &[&[u8]], volume: usize) -> Vec<u8> {
+    volumes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != volume)
+        .flat_map(|(_, v)| v.iter().copied())
+        .collect()
+}
+
+#[test]
+fn volume_layout() {
+    let total: usize = CONFIGS.iter().map(|config| config.size).sum();
+    let (_, volume) = MappedFlash::new(total + PAGE);
+
+    // Logs are laid out one after the other, and must be whole pages
+    assert_eq!(
+        log_volume(volume, PAGE, &CONFIGS, 0).unwrap().as_ptr(),
+        volume.as_ptr()
+    );
+    assert_eq!(
+        log_volume(volume, PAGE, &CONFIGS, 1).unwrap().as_ptr() as usize,
+        volume.as_ptr() as usize + 4 * PAGE
+    );
+    assert_eq!(
+        log_volume(volume, PAGE, &CONFIGS, 2).unwrap().len(),
+        2 * PAGE
+    );
+    assert_eq!(log_volume(volume, PAGE, &CONFIGS, 3), None);
+    assert_eq!(log_volume(&volume[..6 * PAGE], PAGE, &CONFIGS, 2), None);
+    let odd = [LogConfig {
+        size: PAGE + 1,
+        ..CONFIGS[0]
+    }];
+    assert_eq!(log_volume(volume, PAGE, &odd, 0), None);
+}
+
+#[test]
+fn find_logs() {
+    let sim = Sim::new();
+    let manager = Test::new(&sim).manager;
+    assert_eq!(manager.find(b"events", "sensor"), Some(0));
+    assert_eq!(manager.find(b"trace", "sensor"), Some(2));
+    assert_eq!(manager.find(b"audit", "monitor"), Some(1));
+    assert_eq!(manager.find(b"audit", "sensor"), None);
+    assert_eq!(manager.find(b"events", ""), None);
+    assert_eq!(manager.find(b"event", "sensor"), None);
+    assert!(manager.owned_by(2, "sensor"));
+    assert!(!manager.owned_by(1, "sensor"));
+    assert!(!manager.owned_by(3, "sensor"));
+    assert_eq!(manager.config(1), Some(CONFIGS[1]));
+    assert!(manager.log(3).is_none());
+}
+
+#[test]
+fn separate_entries() {
+    let sim = Sim::new();
+    let t = Test::new(&sim);
+
+    // Each log has its own entries, and calls back with its handle
+    assert_eq!(t.append(0, b"boot"), Ok(false));
+    assert_eq!(t.append(2, b"trace 1"), Ok(false));
+    assert_eq!(t.append(0, b"door open"), Ok(false));
+    assert_eq!(t.append(1, b"login"), Ok(false));
+    assert_eq!(t.read_all(0), vec![b"boot".to_vec(), b"door open".to_vec()]);
+    assert_eq!(t.read_all(1), vec![b"login".to_vec()]);
+    assert_eq!(t.read_all(2), vec![b"trace 1".to_vec()]);
+    let start = t.manager.log(0).unwrap().log_start();
+    assert_eq!(t.manager.log(0).unwrap().seek(start), ReturnCode::SUCCESS);
+    assert_eq!(t.event(), Event::Seek(0, ReturnCode::SUCCESS));
+    assert_eq!(t.read(0), Ok(b"boot".to_vec()));
+}
+
+#[test]
+fn sync_without_entries() {
+    let sim = Sim::new();
+    let t = Test::new(&sim);
+
+    // Syncing calls back even when there is nothing to write
+    t.sync(0);
+    assert_eq!(t.append(0, b"boot"), Ok(false));
+    t.sync(0);
+    t.sync(0);
+}
+
+#[test]
+fn full_linear_log() {
+    let sim = Sim::new();
+    let t = Test::new(&sim);
+
+    // A full linear log refuses entries and leaves the other logs alone
+    let before = others(&t.volumes, 1);
+    let mut appended = 0;
+    let error = loop {
+        match t.append(1, &entry(appended, 20)) {
+            Ok(lost) => {
+                assert!(!lost);
+                appended += 1;
+            }
+            Err(e) => break e,
+        }
+    };
+    assert!(error == ReturnCode::FAIL || error == ReturnCode::ECANCEL);
+    assert!(appended >= 3);
+    t.sync(1);
+    assert_eq!(others(&t.volumes, 1), before);
+    let audit = t.read_all(1);
+    assert_eq!(audit.len(), appended);
+    assert_eq!(audit[appended - 1], entry(appended - 1, 20));
+}
+
+#[test]
+fn full_circular_log() {
+    let sim = Sim::new();
+    let t = Test::new(&sim);
+
+    // A full circular log drops its oldest entries and stays in its region
+    let before = others(&t.volumes, 0);
+    let mut lost = false;
+    for i in 0..40 {
+        lost |= t.append(0, &entry(i, 24)).unwrap();
+    }
+    assert!(lost);
+    t.sync(0);
+    assert_eq!(others(&t.volumes, 0), before);
+    let start = t.manager.log(0).unwrap().log_start();
+    assert_eq!(t.manager.log(0).unwrap().seek(start), ReturnCode::SUCCESS);
+    assert_eq!(t.event(), Event::Seek(0, ReturnCode::SUCCESS));
+    let events = t.read_all(0);
+    assert!(!events.is_empty() && events.len() < 40);
+    let first = 40 - events.len();
+    for (i, event) in events.iter().enumerate() {
+        assert_eq!(*event, entry(first + i, 24));
+    }
+}
+
+#[test]
+fn synced_entries_survive_reboot() {
+    let sim = Sim::new();
+    let t = Test::new(&sim);
+    let reader = leak(DirectReader {
+        done: Cell::new(None),
+        buffer: TakeCell::new(leak_buf(PAGE)),
+    });
+    assert_eq!(t.append(2, b"trace 1"), Ok(false));
+
+    let rebooted = new_log(&sim, t.mux, t.volumes[2], true);
+    rebooted.set_read_client(reader);
+    match rebooted.read(reader.buffer.take().unwrap(), PAGE) {
+        Err((ReturnCode::FAIL, buffer)) => reader.buffer.replace(buffer.unwrap()),
+        _ => panic!("unsynced entries survived a reboot"),
+    };
+
+    t.sync(2);
+    let rebooted = new_log(&sim, t.mux, t.volumes[2], true);
+    rebooted.set_read_client(reader);
+    assert!(rebooted.read(reader.buffer.take().unwrap(), PAGE).is_ok());
+    t.pump();
+    assert_eq!(reader.done.take(), Some((ReturnCode::SUCCESS, 7)));
+    assert_eq!(
+        reader.buffer.map(|b| b[..7].to_vec()),
+        Some(b"trace 1".to_vec())
+    );
+}
+
+#[test]
+fn erase_one_log() {
+    let sim = Sim::new();
+    let t = Test::new(&sim);
+    assert_eq!(t.append(0, b"boot"), Ok(false));
+    assert_eq!(t.append(1, b"login"), Ok(false));
+    t.sync(0);
+    t.sync(1);
+
+    // Erasing a log only erases its own region
+    let before = others(&t.volumes, 1);
+    assert_eq!(t.manager.log(1).unwrap().erase(), ReturnCode::SUCCESS);
+    assert_eq!(t.event(), Event::Erase(1, ReturnCode::SUCCESS));
+    assert_eq!(t.read_all(1), Vec::<Vec<u8>>::new());
+    assert_eq!(others(&t.volumes, 1), before);
+    assert_eq!(t.read_all(0), vec![b"boot".to_vec()]);
+    assert_eq!(t.append(1, b"again"), Ok(false));
+    assert_eq!(t.read_all(1), vec![b"again".to_vec()]);
+}
@@ -0,0 +1,181 @@
+//! Secured 802.15.4 frames exchanged between loopback radios.
+
+mod common;
+
+use capsules::ieee802154::device::{self, MacDevice};
+use capsules::ieee802154::loopback::LoopbackRadio;
+use capsules::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
+use common::{leak, leak_buf, Node, Sim, PAN_ID};
+use kernel::hil::radio::{self, RadioConfig, RadioData};
+use kernel::hil::rng::Random;
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+const PAYLOAD: &[u8] = b"loopback radio secured payload";
+const DELAY_US: u32 = 500;
+
+#[derive(Default)]
+struct Recorder {
+    frames: RefCell<Vec<Vec<u8>>>,
+    secured: Cell<bool>,
+    send_done: Cell<Option<(bool, ReturnCode)>>,
+}
+
+impl device::RxClient for Recorder {
+    fn receive<'a>(&self, buf: &'a [u8], header: Header<'a>, data_offset: usize, data_len: usize) {
+        self.secured.set(header.security.is_some());
+        self.frames
+            .borrow_mut()
+            .push(buf[data_offset..data_offset + data_len].to_vec());
+    }
+}
+
+impl device::TxClient for Recorder {
+    fn send_done(&self, _buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.send_done.set(Some((acked, result)));
+    }
+}
+
+// Records raw PSDUs as seen on the medium.
+#[derive(Default)]
+struct Observer {
+    frames: RefCell<Vec<Vec<u8>>>,
+    radio: Cell<Option<&'static common::Radio>>,
+}
+
+impl radio::RxClient for Observer {
+    fn receive(&self, buf: &'static mut [u8], frame_len: usize, _crc: bool, _: ReturnCode) {
+        self.frames
+            .borrow_mut()
+            .push(buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len].to_vec());
+        self.radio.get().unwrap().set_receive_buffer(buf);
+    }
+}
+
+#[derive(Default)]
+struct TxRecorder {
+    send_done: Cell<Option<ReturnCode>>,
+}
+
+impl radio::TxClient for TxRecorder {
+    fn send_done(&self, _buf: &'static mut [u8], _acked: bool, result: ReturnCode) {
+        self.send_done.set(Some(result));
+    }
+}
+
+struct AlwaysZero;
+
+impl<'a> Random<'a> for AlwaysZero {
+    fn initialize(&'a self) {}
+    fn reseed(&self, _seed: u32) {}
+    fn random(&self) -> u32 {
+        0
+    }
+}
+
+fn send(sim: &Sim, from: &Node, to: &Node, sender: &Recorder) -> bool {
+    sender.send_done.set(None);
+    let mut frame = from
+        .mac_user
+        .prepare_data_frame(
+            leak_buf(radio::MAX_BUF_SIZE),
+            PAN_ID as PanID,
+            MacAddress::Short(to.address),
+            PAN_ID as PanID,
+            MacAddress::Short(from.address),
+            Some((SecurityLevel::EncMic64, KeyId::Index(1))),
+        )
+        .ok()
+        .unwrap();
+    assert_eq!(frame.append_payload(PAYLOAD), ReturnCode::SUCCESS);
+    let (rval, _) = from.mac_user.transmit(frame);
+    assert_eq!(rval, ReturnCode::SUCCESS);
+    let sent = sim.run(|| sender.send_done.get().is_some());
+    // Let the receiver finish decrypting.
+    sim.run(|| false);
+    sent
+}
+
+#[test]
+fn secured_frames_over_loopback() {
+    let sim = Sim::new();
+    sim.medium.set_delay_us(DELAY_US);
+    let node1 = Node::new(&sim, 0x0001, 0x0002);
+    let node2 = Node::new(&sim, 0x0002, 0x0001);
+
+    let tx = leak(Recorder::default());
+    let rx = leak(Recorder::default());
+    node1.mac_user.set_transmit_client(tx);
+    node2.mac_user.set_receive_client(rx);
+
+    // A third radio that only listens, to see what goes over the air.
+    let observer = leak(Observer::default());
+    let sniffer = leak(LoopbackRadio::new(sim.medium));
+    sim.medium.add_radio(sniffer);
+    observer.radio.set(Some(sniffer));
+    sniffer.set_receive_client(observer, leak_buf(radio::MAX_BUF_SIZE));
+    sniffer.start();
+    sim.run(|| false);
+
+    // Delivery: the payload is encrypted on air and decrypted by the peer.
+    let start = sim.now_us();
+    assert!(send(&sim, &node1, &node2, tx));
+    assert_eq!(tx.send_done.get(), Some((true, ReturnCode::SUCCESS)));
+    assert!(sim.now_us() - start >= DELAY_US);
+    assert_eq!(*rx.frames.borrow(), vec![PAYLOAD.to_vec()]);
+    assert!(rx.secured.get());
+    assert_eq!(observer.frames.borrow().len(), 1);
+    assert!(!observer.frames.borrow()[0]
+        .windows(PAYLOAD.len())
+        .any(|w| w == PAYLOAD));
+    assert_eq!(node2.radio.last_rssi(), common::DEFAULT_PATH_RSSI);
+
+    // Sensitivity: a deaf receiver neither receives nor acknowledges.
+    node2.radio.set_sensitivity(common::DEFAULT_PATH_RSSI + 1);
+    assert!(send(&sim, &node1, &node2, tx));
+    assert_eq!(tx.send_done.get(), Some((false, ReturnCode::SUCCESS)));
+    assert_eq!(rx.frames.borrow().len(), 1);
+    node2.radio.set_sensitivity(common::DEFAULT_PATH_RSSI);
+
+    // Loss: every delivery is dropped.
+    sim.medium.set_random(leak(AlwaysZero));
+    assert_eq!(sim.medium.set_loss(101), ReturnCode::EINVAL);
+    assert_eq!(sim.medium.set_loss(100), ReturnCode::SUCCESS);
+    assert!(send(&sim, &node1, &node2, tx));
+    assert_eq!(tx.send_done.get(), Some((false, ReturnCode::SUCCESS)));
+    assert_eq!(rx.frames.borrow().len(), 1);
+
+    // Recovery once the medium is lossless again.
+    assert_eq!(sim.medium.set_loss(0), ReturnCode::SUCCESS);
+    assert!(send(&sim, &node1, &node2, tx));
+    assert_eq!(tx.send_done.get(), Some((true, ReturnCode::SUCCESS)));
+    assert_eq!(rx.frames.borrow().len(), 2);
+}
+
+#[test]
+fn late_alarm_delivers_frame() {
+    let sim = Sim::new();
+    sim.medium.set_delay_us(DELAY_US);
+    let sender = leak(LoopbackRadio::new(sim.medium));
+    let listener = leak(LoopbackRadio::new(sim.medium));
+    sim.medium.add_radio(sender);
+    sim.medium.add_radio(listener);
+    let tx = leak(TxRecorder::default());
+    sender.set_transmit_client(tx);
+    let observer = leak(Observer::default());
+    observer.radio.set(Some(listener));
+    listener.set_receive_client(observer, leak_buf(radio::MAX_BUF_SIZE));
+    sender.start();
+    listener.start();
+    sim.run(|| false);
+
+    // The alarm fires several ticks after the frame was due.
+    let start = sim.now_us();
+    let (rval, _) = sender.transmit(leak_buf(radio::MAX_BUF_SIZE), 10);
+    assert_eq!(rval, ReturnCode::SUCCESS);
+    sim.alarm.advance(DELAY_US + 5);
+    assert!(sim.run(|| tx.send_done.get().is_some()));
+    assert_eq!(tx.send_done.get(), Some(ReturnCode::SUCCESS));
+    assert_eq!(observer.frames.borrow().len(), 1);
+    assert_eq!(sim.now_us() - start, DELAY_US + 5);
+}
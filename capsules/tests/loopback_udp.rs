@@ -0,0 +1,115 @@
+//! A fragmented UDP datagram sent between two nodes over loopback radios.
+
+mod common;
+
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::udp::udp_recv::UDPRecvClient;
+use capsules::net::udp::udp_send::{UDPSendClient, UDPSender};
+use common::{leak, leak_buf, link_local, Node, Sim, MAX_UDP_PAYLOAD};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+const PORT1: u16 = 16123;
+const PORT2: u16 = 16124;
+// Large enough to need several 802.15.4 frames.
+const PAYLOAD_LEN: usize = 400;
+
+#[derive(Default)]
+struct Endpoint {
+    sent: Cell<Option<ReturnCode>>,
+    received: RefCell<Vec<(IPAddr, u16, Vec<u8>)>>,
+}
+
+impl UDPSendClient for Endpoint {
+    fn send_done(&self, result: ReturnCode, _dgram: LeasableBuffer<'static, u8>) {
+        self.sent.set(Some(result));
+    }
+}
+
+impl UDPRecvClient for Endpoint {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        self.received
+            .borrow_mut()
+            .push((src_addr, src_port, payload.to_vec()));
+    }
+}
+
+fn send(sim: &Sim, from: &Node, to: &Node, port: u16, endpoint: &Endpoint, payload: &[u8]) {
+    let buf = leak_buf(payload.len());
+    buf.copy_from_slice(payload);
+    endpoint.sent.set(None);
+    assert!(from
+        .udp_sender
+        .send_to(
+            link_local(to.address),
+            port,
+            LeasableBuffer::new(buf),
+            from.net_cap
+        )
+        .is_ok());
+    assert!(sim.run(|| endpoint.sent.get().is_some()));
+    assert_eq!(endpoint.sent.get(), Some(ReturnCode::SUCCESS));
+    sim.run(|| false);
+}
+
+/// Two nodes with bound endpoints.
+fn nodes(sim: &Sim) -> (Node, Node, &'static Endpoint, &'static Endpoint) {
+    sim.medium.set_delay_us(200);
+    let node1 = Node::new(sim, 0x0001, 0x0002);
+    let node2 = Node::new(sim, 0x0002, 0x0001);
+
+    let endpoint1 = leak(Endpoint::default());
+    let endpoint2 = leak(Endpoint::default());
+    node1.udp_sender.set_client(endpoint1);
+    node1.udp_receiver.set_client(endpoint1);
+    node2.udp_sender.set_client(endpoint2);
+    node2.udp_receiver.set_client(endpoint2);
+    node1.bind(PORT1);
+    node2.bind(PORT2);
+    sim.run(|| false);
+    (node1, node2, endpoint1, endpoint2)
+}
+
+#[test]
+fn fragmented_udp_over_loopback() {
+    let sim = Sim::new();
+    let (node1, node2, endpoint1, endpoint2) = nodes(&sim);
+
+    let request: Vec<u8> = (0..PAYLOAD_LEN).map(|i| i as u8).collect();
+    send(&sim, &node1, &node2, PORT2, endpoint1, &request);
+    assert_eq!(
+        *endpoint2.received.borrow(),
+        vec![(link_local(node1.address), PORT1, request.clone())]
+    );
+
+    // And a short reply in the other direction.
+    send(&sim, &node2, &node1, PORT1, endpoint2, b"ack");
+    assert_eq!(
+        *endpoint1.received.borrow(),
+        vec![(link_local(node2.address), PORT2, b"ack".to_vec())]
+    );
+}
+
+#[test]
+fn full_size_datagram() {
+    let sim = Sim::new();
+    let (node1, node2, endpoint1, endpoint2) = nodes(&sim);
+
+    // The payload of a 1280 byte IPv6 datagram, which fills the whole
+    // reassembly bitmap.
+    let request: Vec<u8> = (0..MAX_UDP_PAYLOAD).map(|i| (i * 7) as u8).collect();
+    send(&sim, &node1, &node2, PORT2, endpoint1, &request);
+    assert_eq!(
+        *endpoint2.received.borrow(),
+        vec![(link_local(node1.address), PORT1, request)]
+    );
+    assert!(endpoint1.received.borrow().is_empty());
+}
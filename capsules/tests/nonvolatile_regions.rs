@@ -0,0 +1,336 @@
+//! Host tests for per-application nonvolatile regions.
+//!
+//! Regions are kept in a `RamDisk`, which is inspected directly to check
+//! that applications stay inside their regions and that the table survives
+//! reboots and torn writes.
+
+mod common;
+
+use capsules::nonvolatile_regions::{self, NonvolatileRegions, TABLE_SIZE};
+use common::ramdisk::RamDisk;
+use common::Device;
+use common::{leak, leak_buf, Sim};
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::TakeCell;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::ReturnCode;
+use std::cell::Cell;
+
+const START: usize = 0x1000;
+const LENGTH: usize = 2 * TABLE_SIZE + 4096;
+const QUOTA: usize = 1500;
+const DATA: usize = START + 2 * TABLE_SIZE;
+
+struct Cap;
+unsafe impl ProcessManagementCapability for Cap {}
+
+/// Records the result of the last operation.
+struct TestClient {
+    result: Cell<Option<Result<usize, ReturnCode>>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl nonvolatile_regions::Client for TestClient {
+    fn load_done(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result.map(|()| 0)));
+    }
+
+    fn allocate_done(&self, result: Result<usize, ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn free_done(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result.map(|()| 0)));
+    }
+
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.result.set(Some(Ok(length)));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.result.set(Some(Ok(length)));
+    }
+}
+
+impl nonvolatile_regions::AdminClient for TestClient {
+    fn reclaim_done(&self, result: Result<usize, ReturnCode>) {
+        self.result.set(Some(result));
+    }
+}
+
+struct Harness {
+    disk: &'static RamDisk,
+    regions: &'static NonvolatileRegions<'static>,
+    client: &'static TestClient,
+}
+
+impl Harness {
+    /// Boots a new instance on `disk` and loads the table.
+    fn boot(disk: &'static RamDisk) -> Harness {
+        let regions = leak(NonvolatileRegions::new(
+            disk,
+            START,
+            LENGTH,
+            QUOTA,
+            leak_buf(TABLE_SIZE),
+        ));
+        disk.set_client(regions);
+        let client = leak(TestClient {
+            result: Cell::new(None),
+            buffer: TakeCell::new(leak_buf(64)),
+        });
+        regions.set_client(client);
+        regions.set_admin_client(client);
+        let harness = Harness {
+            disk,
+            regions,
+            client,
+        };
+        assert_eq!(regions.load(), ReturnCode::SUCCESS);
+        assert_eq!(harness.finish(), Ok(0));
+        assert!(regions.is_loaded());
+        harness
+    }
+
+    /// Completes the pending operations and returns the result.
+    fn finish(&self) -> Result<usize, ReturnCode> {
+        self.client.result.set(None);
+        while self.disk.run() {}
+        self.client
+            .result
+            .take()
+            .expect("operation did not complete")
+    }
+
+    fn allocate(&self, owner: &str, size: usize) -> Result<usize, ReturnCode> {
+        match self.regions.allocate(owner, size) {
+            ReturnCode::SUCCESS => self.finish(),
+            e => Err(e),
+        }
+    }
+
+    fn free(&self, owner: &str) -> Result<usize, ReturnCode> {
+        match self.regions.free(owner) {
+            ReturnCode::SUCCESS => self.finish(),
+            e => Err(e),
+        }
+    }
+
+    fn write(&self, owner: &str, offset: usize, data: &[u8]) -> Result<usize, ReturnCode> {
+        let buffer = self.client.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        match self.regions.write(owner, buffer, offset, data.len()) {
+            Ok(()) => self.finish(),
+            Err((e, buffer)) => {
+                self.client.buffer.replace(buffer.unwrap());
+                Err(e)
+            }
+        }
+    }
+
+    fn read(&self, owner: &str, offset: usize, length: usize) -> Result<Vec<u8>, ReturnCode> {
+        let buffer = self.client.buffer.take().unwrap();
+        match self.regions.read(owner, buffer, offset, length) {
+            Ok(()) => {
+                self.finish()?;
+                Ok(self.client.buffer.map(|b| b[..length].to_vec()).unwrap())
+            }
+            Err((e, buffer)) => {
+                self.client.buffer.replace(buffer.unwrap());
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Boots on a new disk with two regions: "sensor" of 1000 bytes and
+/// "a-very-long-package-name" of 600.
+fn two_regions() -> Harness {
+    let disk = leak(RamDisk::byte_addressable());
+    // Leftovers of an earlier user of the storage.
+    disk.write_bytes(DATA, &[0xa5; 4096]);
+    let h = Harness::boot(disk);
+    assert_eq!(h.allocate("sensor", 1000), Ok(1000));
+    assert_eq!(h.allocate("a-very-long-package-name", 600), Ok(600));
+    h
+}
+
+/// The index of the region of `owner`.
+fn index_of(h: &Harness, owner: &[u8]) -> usize {
+    (0..nonvolatile_regions::MAX_REGIONS)
+        .find(|i| h.regions.region_at(*i).map_or(false, |r| r.name() == owner))
+        .unwrap()
+}
+
+#[test]
+fn requests_before_loading() {
+    let disk = leak(RamDisk::byte_addressable());
+    let regions = leak(NonvolatileRegions::new(
+        disk,
+        START,
+        LENGTH,
+        QUOTA,
+        leak_buf(TABLE_SIZE),
+    ));
+    assert_eq!(regions.allocate("early", 16), ReturnCode::EOFF);
+    assert!(!regions.is_loaded());
+}
+
+#[test]
+fn allocate() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    assert_eq!(h.regions.region_at(0), None);
+
+    assert_eq!(h.allocate("", 16), Err(ReturnCode::ENOSUPPORT));
+    assert_eq!(h.allocate("sensor", 0), Err(ReturnCode::ESIZE));
+    assert_eq!(h.allocate("sensor", 1000), Ok(1000));
+    assert_eq!(h.allocate("sensor", 10), Err(ReturnCode::EALREADY));
+    assert_eq!(h.allocate("a-very-long-package-name", 600), Ok(600));
+
+    let sensor = h.regions.region("sensor").unwrap();
+    let long = h.regions.region("a-very-long-package-name").unwrap();
+    assert_eq!(sensor.name(), b"sensor");
+    assert_eq!(long.name(), b"a-very-long-pack");
+    assert_eq!((sensor.offset, long.offset), (0, 1000));
+}
+
+#[test]
+fn quota() {
+    let h = Harness::boot(leak(RamDisk::byte_addressable()));
+    assert_eq!(h.allocate("sensor", QUOTA + 1), Err(ReturnCode::ESIZE));
+    assert_eq!(h.allocate("sensor", QUOTA), Ok(QUOTA));
+}
+
+#[test]
+fn new_regions_are_zeroed() {
+    let h = two_regions();
+    assert_eq!(h.read("sensor", 0, 64), Ok(vec![0; 64]));
+    let mut raw = vec![0; 1600];
+    h.disk.read_bytes(DATA, &mut raw);
+    assert!(raw.iter().all(|b| *b == 0));
+}
+
+#[test]
+fn applications_stay_in_their_regions() {
+    let h = two_regions();
+    assert_eq!(h.write("sensor", 990, &[1; 10]), Ok(10));
+    assert_eq!(h.write("sensor", 995, &[1; 10]), Err(ReturnCode::EINVAL));
+    assert_eq!(
+        h.write("sensor", usize::MAX, &[1; 2]),
+        Err(ReturnCode::EINVAL)
+    );
+    assert_eq!(h.write("other", 0, &[1; 2]), Err(ReturnCode::EINVAL));
+    assert_eq!(h.write("a-very-long-package-name", 0, &[2; 8]), Ok(8));
+    assert_eq!(
+        h.read("a-very-long-package-name", 0, 12),
+        Ok(vec![2, 2, 2, 2, 2, 2, 2, 2, 0, 0, 0, 0])
+    );
+    assert_eq!(
+        h.read("sensor", 988, 12),
+        Ok(vec![0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1])
+    );
+    assert_eq!(h.read("sensor", 995, 10), Err(ReturnCode::EINVAL));
+}
+
+#[test]
+fn space_runs_out() {
+    let h = two_regions();
+    // Space runs out before slots do.
+    assert_eq!(h.allocate("third", 1500), Ok(1500));
+    assert_eq!(h.allocate("fourth", 1500), Err(ReturnCode::ENOMEM));
+    assert_eq!(h.allocate("fourth", 996), Ok(996));
+}
+
+#[test]
+fn freed_regions_are_reused() {
+    let h = two_regions();
+    assert_eq!(h.write("sensor", 990, &[1; 10]), Ok(10));
+
+    // A freed region can be reused by another application, zeroed.
+    assert_eq!(h.free("other"), Err(ReturnCode::EINVAL));
+    assert_eq!(h.free("sensor"), Ok(0));
+    assert_eq!(h.regions.region("sensor"), None);
+    assert_eq!(h.allocate("fifth", 1000), Ok(1000));
+    assert_eq!(h.regions.region("fifth").unwrap().offset, 0);
+    assert_eq!(h.read("fifth", 988, 12), Ok(vec![0; 12]));
+}
+
+#[test]
+fn regions_survive_reboot() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    assert_eq!(h.allocate("sensor", 100), Ok(100));
+    assert_eq!(h.write("sensor", 10, b"kept"), Ok(4));
+    assert_eq!(h.allocate("monitor", 200), Ok(200));
+
+    let h = Harness::boot(disk);
+    assert_eq!(h.regions.region("sensor").unwrap().size, 100);
+    assert_eq!(h.regions.region("monitor").unwrap().offset, 100);
+    assert_eq!(h.read("sensor", 10, 4), Ok(b"kept".to_vec()));
+}
+
+#[test]
+fn torn_table_write() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    assert_eq!(h.allocate("sensor", 100), Ok(100));
+    assert_eq!(h.allocate("monitor", 200), Ok(200));
+
+    // A table copy torn by a power loss is ignored, and the previous copy
+    // is used. Table writes alternate between the copies, and the third one
+    // went to the second copy.
+    assert_eq!(h.free("monitor"), Ok(0));
+    disk.write_bytes(START + TABLE_SIZE + 100, &[0xff; 8]);
+    let h = Harness::boot(disk);
+    assert!(h.regions.region("monitor").is_some());
+    assert!(h.regions.region("sensor").is_some());
+
+    // The next table write goes over the torn copy.
+    assert_eq!(h.allocate("third", 50), Ok(50));
+    let h = Harness::boot(disk);
+    assert!(h.regions.region("third").is_some());
+    assert!(h.regions.region("monitor").is_some());
+}
+
+#[test]
+fn free_any_region() {
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    assert_eq!(h.allocate("sensor", 100), Ok(100));
+    assert_eq!(h.allocate("monitor", 200), Ok(200));
+
+    // Listing and freeing any region needs the capability.
+    let index = index_of(&h, b"monitor");
+    assert_eq!(h.regions.free_region(index, &Cap), ReturnCode::SUCCESS);
+    assert_eq!(h.finish(), Ok(1));
+    assert_eq!(h.regions.region("monitor"), None);
+    assert_eq!(h.regions.free_region(index, &Cap), ReturnCode::EINVAL);
+    let h = Harness::boot(disk);
+    assert_eq!(h.regions.region("monitor"), None);
+    assert!(h.regions.region("sensor").is_some());
+}
+
+#[test]
+fn reclaim_removed_applications() {
+    let sim = Sim::new();
+    let disk = leak(RamDisk::byte_addressable());
+    let h = Harness::boot(disk);
+    assert_eq!(h.allocate("sensor", 100), Ok(100));
+    assert_eq!(h.allocate("monitor", 200), Ok(200));
+
+    // No applications are installed, so every region is reclaimed.
+    assert_eq!(
+        h.regions.reclaim_removed(sim.kernel, &Cap),
+        ReturnCode::SUCCESS
+    );
+    assert_eq!(h.finish(), Ok(2));
+    assert_eq!(
+        h.regions.reclaim_removed(sim.kernel, &Cap),
+        ReturnCode::EALREADY
+    );
+    let h = Harness::boot(disk);
+    assert!((0..nonvolatile_regions::MAX_REGIONS).all(|i| h.regions.region_at(i).is_none()));
+}
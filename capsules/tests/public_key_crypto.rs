@@ -0,0 +1,402 @@
+//! Host tests for the software signature verification.
+//!
+//! The verifiers are driven through `hil::public_key_crypto` with deferred
+//! calls, and checked against the ECDSA P-256 examples of RFC 6979 and the
+//! Ed25519 test vectors of RFC 8032.
+
+mod common;
+
+use capsules::public_key_crypto::ecdsa_p256::EcdsaP256;
+use capsules::public_key_crypto::ed25519::Ed25519;
+use capsules::software_sha::BYTES_PER_CALL;
+use common::{leak, leak_buf, Sim};
+use kernel::hil::public_key_crypto::{ClientVerify, SignatureVerify};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+/// Records the result of the verification.
+struct TestClient {
+    result: Cell<Option<Result<bool, ReturnCode>>>,
+    buffers: RefCell<Option<(&'static mut [u8], &'static mut [u8])>>,
+}
+
+impl<'a> ClientVerify<'a> for TestClient {
+    fn verification_done(
+        &'a self,
+        result: Result<bool, ReturnCode>,
+        message: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) {
+        self.result.set(Some(result));
+        *self.buffers.borrow_mut() = Some((message, signature));
+    }
+}
+
+fn new_client() -> &'static TestClient {
+    leak(TestClient {
+        result: Cell::new(None),
+        buffers: RefCell::new(None),
+    })
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn buffer(contents: &[u8]) -> &'static mut [u8] {
+    let buffer = leak_buf(contents.len());
+    buffer.copy_from_slice(contents);
+    buffer
+}
+
+/// Verifies `signature` of `message` and returns the result and the number
+/// of deferred calls it took.
+fn verify<'a, V: SignatureVerify<'a>>(
+    sim: &Sim,
+    verifier: &'a V,
+    client: &TestClient,
+    message: &[u8],
+    signature: &[u8],
+) -> (Result<bool, ReturnCode>, usize) {
+    client.result.set(None);
+    assert!(verifier.verify(buffer(message), buffer(signature)).is_ok());
+    let calls = sim.run_all_deferred();
+    let (returned_message, returned_signature) = client.buffers.borrow_mut().take().unwrap();
+    assert_eq!(&returned_message[..], message);
+    assert_eq!(&returned_signature[..], signature);
+    (client.result.get().unwrap(), calls)
+}
+
+/// The public key of RFC 6979 A.2.5.
+const P256_KEY: &str = concat!(
+    "60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6",
+    "7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299"
+);
+
+/// SHA-256 of "sample" and "test", and their signatures with the key of
+/// RFC 6979 A.2.5.
+const P256_SAMPLE: (&str, &str) = (
+    "af2bdbe1aa9b6ec1e2ade1d694f41fc71a831d0268e9891562113d8a62add1bf",
+    concat!(
+        "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716",
+        "f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8"
+    ),
+);
+const P256_TEST: (&str, &str) = (
+    "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+    concat!(
+        "f1abb023518351cd71d881567b1ea663ed3efcf6c5132b354f28d3b0b7d38367",
+        "019f4113742a2b14bd25926b49c649155f267e60d3814b4c0cc84250e46f0083"
+    ),
+);
+
+/// An ECDSA P-256 verifier with the key of RFC 6979 A.2.5.
+fn new_ecdsa(sim: &Sim) -> (&'static EcdsaP256<'static>, &'static TestClient) {
+    let ecdsa = leak(EcdsaP256::new(sim.deferred_caller));
+    ecdsa.initialize_callback_handle(sim.deferred_caller.register(ecdsa).unwrap());
+    let client = new_client();
+    ecdsa.set_verify_client(client);
+    assert_eq!(ecdsa.set_public_key(&hex(P256_KEY)), Ok(()));
+    (ecdsa, client)
+}
+
+#[test]
+fn ecdsa_needs_callback_and_key() {
+    let sim = Sim::new();
+    let ecdsa = leak(EcdsaP256::new(sim.deferred_caller));
+    ecdsa.set_verify_client(new_client());
+    let (e, _, _) = ecdsa.verify(leak_buf(32), leak_buf(64)).unwrap_err();
+    assert_eq!(e, ReturnCode::EOFF);
+    assert_eq!(ecdsa.set_public_key(&hex(P256_KEY)), Ok(()));
+    let (e, _, _) = ecdsa.verify(leak_buf(32), leak_buf(64)).unwrap_err();
+    assert_eq!(e, ReturnCode::FAIL);
+}
+
+#[test]
+fn ecdsa_rfc6979_sample() {
+    let sim = Sim::new();
+    let (ecdsa, client) = new_ecdsa(&sim);
+    let (message, signature) = (hex(P256_SAMPLE.0), hex(P256_SAMPLE.1));
+    // One call for the scalars, the multiplication and the comparison.
+    assert_eq!(
+        verify(&sim, ecdsa, client, &message, &signature),
+        (Ok(true), 1 + 256 / 16 + 1)
+    );
+}
+
+#[test]
+fn ecdsa_rfc6979_test() {
+    let sim = Sim::new();
+    let (ecdsa, client) = new_ecdsa(&sim);
+    let (message, signature) = (hex(P256_TEST.0), hex(P256_TEST.1));
+    assert_eq!(
+        verify(&sim, ecdsa, client, &message, &signature).0,
+        Ok(true)
+    );
+}
+
+#[test]
+fn ecdsa_wrong_message() {
+    let sim = Sim::new();
+    let (ecdsa, client) = new_ecdsa(&sim);
+    assert_eq!(
+        verify(&sim, ecdsa, client, &hex(P256_TEST.0), &hex(P256_SAMPLE.1)).0,
+        Ok(false)
+    );
+}
+
+#[test]
+fn ecdsa_changed_signature() {
+    let sim = Sim::new();
+    let (ecdsa, client) = new_ecdsa(&sim);
+    let mut changed = hex(P256_SAMPLE.1);
+    changed[40] ^= 1;
+    assert_eq!(
+        verify(&sim, ecdsa, client, &hex(P256_SAMPLE.0), &changed).0,
+        Ok(false)
+    );
+}
+
+#[test]
+fn ecdsa_zero_r() {
+    let sim = Sim::new();
+    let (ecdsa, client) = new_ecdsa(&sim);
+    // r must be between 1 and n - 1, and out of range values are rejected
+    // without the multiplication.
+    let mut zero_r = hex(P256_SAMPLE.1);
+    zero_r[..32].copy_from_slice(&[0; 32]);
+    assert_eq!(
+        verify(&sim, ecdsa, client, &hex(P256_SAMPLE.0), &zero_r),
+        (Ok(false), 1)
+    );
+}
+
+#[test]
+fn ecdsa_s_out_of_range() {
+    let sim = Sim::new();
+    let (ecdsa, client) = new_ecdsa(&sim);
+    let mut large_s = hex(P256_SAMPLE.1);
+    large_s[32..].copy_from_slice(&[0xff; 32]);
+    assert_eq!(
+        verify(&sim, ecdsa, client, &hex(P256_SAMPLE.0), &large_s),
+        (Ok(false), 1)
+    );
+}
+
+#[test]
+fn ecdsa_signature_size() {
+    let sim = Sim::new();
+    let (ecdsa, _) = new_ecdsa(&sim);
+    let (e, _, _) = ecdsa.verify(leak_buf(32), leak_buf(63)).unwrap_err();
+    assert_eq!(e, ReturnCode::ESIZE);
+}
+
+#[test]
+fn ecdsa_one_verification_at_a_time() {
+    let sim = Sim::new();
+    let (ecdsa, client) = new_ecdsa(&sim);
+    assert!(ecdsa
+        .verify(buffer(&hex(P256_SAMPLE.0)), buffer(&hex(P256_SAMPLE.1)))
+        .is_ok());
+    let (e, _, _) = ecdsa.verify(leak_buf(32), leak_buf(64)).unwrap_err();
+    assert_eq!(e, ReturnCode::EBUSY);
+    assert_eq!(ecdsa.set_public_key(&hex(P256_KEY)), Err(ReturnCode::EBUSY));
+    sim.run_all_deferred();
+    assert_eq!(client.result.get(), Some(Ok(true)));
+}
+
+#[test]
+fn ecdsa_invalid_key() {
+    let sim = Sim::new();
+    let (ecdsa, _) = new_ecdsa(&sim);
+    // A point that isn't on the curve is rejected, and clears the key.
+    let mut invalid = hex(P256_KEY);
+    invalid[63] ^= 1;
+    assert_eq!(ecdsa.set_public_key(&invalid), Err(ReturnCode::EINVAL));
+    let (e, _, _) = ecdsa.verify(leak_buf(32), leak_buf(64)).unwrap_err();
+    assert_eq!(e, ReturnCode::EOFF);
+    assert_eq!(
+        ecdsa.set_public_key(&hex(P256_KEY)[..32]),
+        Err(ReturnCode::EINVAL)
+    );
+}
+
+/// The first three test vectors of RFC 8032 section 7.1.
+const ED25519_VECTORS: [(&str, &str, &str); 3] = [
+    (
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+        "",
+        concat!(
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155",
+            "5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+        ),
+    ),
+    (
+        "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+        "72",
+        concat!(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da",
+            "085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"
+        ),
+    ),
+    (
+        "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+        "af82",
+        concat!(
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac",
+            "18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a"
+        ),
+    ),
+];
+
+/// An Ed25519 verifier with the key of `ED25519_VECTORS[vector]`.
+fn new_ed25519(sim: &Sim, vector: usize) -> (&'static Ed25519<'static>, &'static TestClient) {
+    let ed25519 = leak(Ed25519::new(sim.deferred_caller));
+    ed25519.initialize_callback_handle(sim.deferred_caller.register(ed25519).unwrap());
+    let client = new_client();
+    ed25519.set_verify_client(client);
+    assert_eq!(
+        ed25519.set_public_key(&hex(ED25519_VECTORS[vector].0)),
+        Ok(())
+    );
+    (ed25519, client)
+}
+
+/// Checks that `ED25519_VECTORS[vector]` verifies, and doesn't once the
+/// message or the signature is changed.
+fn ed25519_vector(vector: usize) {
+    let sim = Sim::new();
+    let (ed25519, client) = new_ed25519(&sim, vector);
+    let (_, message, signature) = ED25519_VECTORS[vector];
+    let (message, signature) = (hex(message), hex(signature));
+    // One call for the hash, the multiplication and the comparison.
+    assert_eq!(
+        verify(&sim, ed25519, client, &message, &signature),
+        (Ok(true), 1 + 256 / 16 + 1)
+    );
+
+    let mut changed = message.clone();
+    changed.push(0);
+    assert_eq!(
+        verify(&sim, ed25519, client, &changed, &signature).0,
+        Ok(false)
+    );
+    let mut changed = signature.clone();
+    changed[0] ^= 1;
+    assert_eq!(
+        verify(&sim, ed25519, client, &message, &changed).0,
+        Ok(false)
+    );
+}
+
+#[test]
+fn ed25519_rfc8032_test_1() {
+    ed25519_vector(0);
+}
+
+#[test]
+fn ed25519_rfc8032_test_2() {
+    ed25519_vector(1);
+}
+
+#[test]
+fn ed25519_rfc8032_test_3() {
+    ed25519_vector(2);
+}
+
+#[test]
+fn ed25519_needs_key() {
+    let sim = Sim::new();
+    let ed25519 = leak(Ed25519::new(sim.deferred_caller));
+    ed25519.initialize_callback_handle(sim.deferred_caller.register(ed25519).unwrap());
+    ed25519.set_verify_client(new_client());
+    let (e, _, _) = ed25519.verify(leak_buf(0), leak_buf(64)).unwrap_err();
+    assert_eq!(e, ReturnCode::EOFF);
+}
+
+#[test]
+fn ed25519_other_key() {
+    let sim = Sim::new();
+    // A signature checked with another key doesn't verify.
+    let (ed25519, client) = new_ed25519(&sim, 1);
+    let (_, message, signature) = ED25519_VECTORS[2];
+    assert_eq!(
+        verify(&sim, ed25519, client, &hex(message), &hex(signature)).0,
+        Ok(false)
+    );
+}
+
+#[test]
+fn ed25519_unreduced_s() {
+    let sim = Sim::new();
+    let (ed25519, client) = new_ed25519(&sim, 2);
+    let (_, message, signature) = ED25519_VECTORS[2];
+
+    // S must be less than L, so adding L to it is rejected before the
+    // multiplication.
+    let mut unreduced = hex(signature);
+    let l = hex("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010");
+    let mut carry = 0;
+    for (s, l) in unreduced[32..].iter_mut().zip(l.iter()) {
+        let sum = *s as u16 + *l as u16 + carry;
+        *s = sum as u8;
+        carry = sum >> 8;
+    }
+    assert_eq!(
+        verify(&sim, ed25519, client, &hex(message), &unreduced),
+        (Ok(false), 1)
+    );
+}
+
+#[test]
+fn ed25519_long_message() {
+    let sim = Sim::new();
+    let (ed25519, client) = new_ed25519(&sim, 2);
+    // Long messages are hashed a part at a time.
+    let long = vec![0x5a; 3 * BYTES_PER_CALL + 1];
+    assert_eq!(
+        verify(&sim, ed25519, client, &long, &hex(ED25519_VECTORS[2].2)),
+        (Ok(false), 4 + 16 + 1)
+    );
+}
+
+#[test]
+fn ed25519_one_verification_at_a_time() {
+    let sim = Sim::new();
+    let (ed25519, client) = new_ed25519(&sim, 2);
+    let (_, message, signature) = ED25519_VECTORS[2];
+    assert!(ed25519
+        .verify(buffer(&hex(message)), buffer(&hex(signature)))
+        .is_ok());
+    let (e, _, _) = ed25519.verify(leak_buf(0), leak_buf(64)).unwrap_err();
+    assert_eq!(e, ReturnCode::EBUSY);
+    sim.run_all_deferred();
+    assert_eq!(client.result.get(), Some(Ok(true)));
+}
+
+#[test]
+fn ed25519_invalid_key() {
+    let sim = Sim::new();
+    let (ed25519, _) = new_ed25519(&sim, 0);
+    // A y coordinate without a matching x isn't a point on the curve.
+    let mut invalid = [0; 32];
+    invalid[0] = 2;
+    assert_eq!(ed25519.set_public_key(&invalid), Err(ReturnCode::EINVAL));
+    let (e, _, _) = ed25519.verify(leak_buf(0), leak_buf(64)).unwrap_err();
+    assert_eq!(e, ReturnCode::EOFF);
+    assert_eq!(
+        ed25519.set_public_key(&invalid[..31]),
+        Err(ReturnCode::EINVAL)
+    );
+}
+
+#[test]
+fn ed25519_signature_size() {
+    let sim = Sim::new();
+    let (ed25519, _) = new_ed25519(&sim, 0);
+    let (e, _, _) = ed25519.verify(leak_buf(0), leak_buf(32)).unwrap_err();
+    assert_eq!(e, ReturnCode::ESIZE);
+}
@@ -0,0 +1,162 @@
+//! Address autoconfiguration from Router Advertisements, and the lifetimes of
+//! the configured addresses.
+
+mod common;
+
+use capsules::net::ipv6::ip_utils::{verify_icmp_checksum, IPAddr, InterfaceAddresses};
+use capsules::net::ipv6::ipv6_recv::IP6Receiver;
+use capsules::net::ipv6::slaac::Slaac;
+use capsules::net::ipv6::IP6Header;
+use capsules::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
+use capsules::net::udp::udp_recv::UDPRecvClient;
+use capsules::net::udp::udp_send::{UDPSendClient, UDPSender};
+use capsules::virtual_alarm::VirtualMuxAlarm;
+use common::{leak, leak_buf, link_local, long_address, Node, Sim, VAlarm};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::Alarm;
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+const PORT: u16 = 16123;
+const PREFIX: [u8; 8] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1];
+const TWO_HOURS: u32 = 2 * 60 * 60;
+
+#[derive(Default)]
+struct Endpoint {
+    sent: Cell<Option<ReturnCode>>,
+    sources: RefCell<Vec<IPAddr>>,
+}
+
+impl UDPSendClient for Endpoint {
+    fn send_done(&self, result: ReturnCode, _dgram: LeasableBuffer<'static, u8>) {
+        self.sent.set(Some(result));
+    }
+}
+
+impl UDPRecvClient for Endpoint {
+    fn receive(&self, src_addr: IPAddr, _: IPAddr, _: u16, _: u16, _payload: &[u8]) {
+        self.sources.borrow_mut().push(src_addr);
+    }
+}
+
+/// A Router Advertisement from fe80::1 to all nodes with one Prefix
+/// Information option, as an IPv6 packet.
+fn router_advertisement(hop_limit: u8, flags: u8, valid: u32, preferred: u32) -> Vec<u8> {
+    let mut ra = vec![134, 0, 0, 0, 64, 0, 0x07, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+    ra.extend_from_slice(&[3, 4, 64, flags]);
+    ra.extend_from_slice(&valid.to_be_bytes());
+    ra.extend_from_slice(&preferred.to_be_bytes());
+    ra.extend_from_slice(&[0; 4]);
+    ra.extend_from_slice(&PREFIX);
+    ra.extend_from_slice(&[0; 8]);
+
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend_from_slice(&(ra.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[58, hop_limit]);
+    packet.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    packet.extend_from_slice(&[0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    let (_, header) = IP6Header::decode(&packet).done().unwrap();
+    let checksum = verify_icmp_checksum(&header, &ra);
+    ra[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&ra);
+    packet
+}
+
+fn advertise(node: &Node, packet: &[u8]) {
+    node.ip_receive
+        .receive(packet, packet.len(), ReturnCode::SUCCESS);
+}
+
+fn addresses(slaac: &Slaac<'static, VAlarm>) -> Vec<IPAddr> {
+    (0..)
+        .map(|i| slaac.address(i))
+        .take_while(|addr| addr.is_some())
+        .flatten()
+        .collect()
+}
+
+#[test]
+fn addresses_from_router_advertisements() {
+    let sim = Sim::new();
+    let node1 = Node::new(&sim, 0x0001, 0x0002);
+    let node2 = Node::new(&sim, 0x0002, 0x0001);
+
+    let alarm = leak(VirtualMuxAlarm::new(sim.mux_alarm));
+    let slaac = leak(Slaac::new(alarm, long_address(node2.address)));
+    alarm.set_alarm_client(slaac);
+    slaac.set_sender(node2.ip_send);
+    node2.ip_receive.set_client(slaac);
+    slaac.set_client(node2.udp_recv_mux);
+    node2.udp_driver.set_interface_addresses(slaac);
+
+    let endpoint1 = leak(Endpoint::default());
+    let endpoint2 = leak(Endpoint::default());
+    node1.udp_receiver.set_client(endpoint1);
+    node2.udp_sender.set_client(endpoint2);
+    node1.bind(PORT);
+    node2.bind(PORT);
+    sim.run(|| false);
+
+    // The link-local address comes from the extended address.
+    let link_local_addr = slaac.link_local_address();
+    assert!(link_local_addr.is_unicast_link_local());
+    assert_eq!(link_local_addr.0[8], long_address(node2.address)[0] ^ 0x02);
+    assert_eq!(&link_local_addr.0[9..], &long_address(node2.address)[1..]);
+    assert_eq!(addresses(slaac), vec![link_local_addr]);
+
+    // Advertisements that are not from a neighboring router, and prefixes
+    // without the autonomous flag, are ignored.
+    advertise(&node2, &router_advertisement(64, 0xc0, 20, 10));
+    advertise(&node2, &router_advertisement(255, 0x80, 20, 10));
+    assert_eq!(addresses(slaac).len(), 1);
+
+    // A global address from the advertised prefix, used as source address.
+    let start = sim.now_us();
+    advertise(&node2, &router_advertisement(255, 0xc0, 20, 10));
+    let mut global = link_local_addr;
+    global.0[..8].copy_from_slice(&PREFIX);
+    assert_eq!(addresses(slaac), vec![link_local_addr, global]);
+    assert_eq!(slaac.source_address(), global);
+
+    let buf = leak_buf(4);
+    assert!(node2
+        .udp_sender
+        .send_to(
+            link_local(node1.address),
+            PORT,
+            LeasableBuffer::new(buf),
+            node2.net_cap
+        )
+        .is_ok());
+    assert!(sim.run(|| endpoint2.sent.get().is_some()));
+    sim.run(|| !endpoint1.sources.borrow().is_empty());
+    assert_eq!(*endpoint1.sources.borrow(), vec![global]);
+
+    // Deprecated after the preferred lifetime, removed after the valid one.
+    assert!(sim.run(|| slaac.source_address() == link_local_addr));
+    assert!(sim.now_us() - start >= 10_000_000);
+    assert_eq!(addresses(slaac).len(), 2);
+    assert!(sim.run(|| addresses(slaac).len() == 1));
+    assert!(sim.now_us() - start >= 20_000_000);
+    assert!(!alarm.is_armed());
+
+    // A short valid lifetime cannot cut an address's lifetime below two
+    // hours.
+    advertise(
+        &node2,
+        &router_advertisement(255, 0xc0, 3 * TWO_HOURS, 3 * TWO_HOURS),
+    );
+    advertise(&node2, &router_advertisement(255, 0xc0, 60, 60));
+    let start = sim.now_us();
+    sim.run(|| sim.now_us().wrapping_sub(start) >= 120_000_000);
+    assert_eq!(addresses(slaac), vec![link_local_addr, global]);
+    assert_eq!(slaac.source_address(), link_local_addr);
+
+    // Infinite lifetimes need no alarm.
+    advertise(
+        &node2,
+        &router_advertisement(255, 0xc0, 0xffff_ffff, 0xffff_ffff),
+    );
+    assert!(!alarm.is_armed());
+    assert_eq!(slaac.source_address(), global);
+}
@@ -0,0 +1,301 @@
+//! Host tests for the software SHA-2 digests.
+//!
+//! The digests are driven through `hil::digest` with deferred calls, and
+//! checked against the NIST examples for SHA-256 and SHA-512 and the
+//! HMAC-SHA256 test cases of RFC 4231.
+
+mod common;
+
+use capsules::software_sha::{SoftwareSha256, SoftwareSha512, BYTES_PER_CALL};
+use common::{leak, leak_buf, Sim};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest::{self, Digest, DigestType, HMACSha256};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+/// Records the results of the digest.
+struct TestClient<T: 'static> {
+    data: RefCell<Option<&'static mut [u8]>>,
+    digest: RefCell<Option<&'static mut T>>,
+    add_data_done: Cell<usize>,
+}
+
+impl<'a, T: DigestType> digest::Client<'a, T> for TestClient<T> {
+    fn add_data_done(&'a self, result: Result<(), ReturnCode>, data: &'static mut [u8]) {
+        assert_eq!(result, Ok(()));
+        self.add_data_done.set(self.add_data_done.get() + 1);
+        *self.data.borrow_mut() = Some(data);
+    }
+
+    fn hash_done(&'a self, result: Result<(), ReturnCode>, digest: &'static mut T) {
+        assert_eq!(result, Ok(()));
+        *self.digest.borrow_mut() = Some(digest);
+    }
+}
+
+fn new_client<T>(digest: T) -> &'static TestClient<T> {
+    leak(TestClient {
+        data: RefCell::new(Some(leak_buf(2000))),
+        digest: RefCell::new(Some(leak(digest))),
+        add_data_done: Cell::new(0),
+    })
+}
+
+fn hex(digest: &str) -> Vec<u8> {
+    (0..digest.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digest[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Adds `data` to the digest, one buffer at a time.
+fn add_data<'a, T: DigestType, D: Digest<'a, T>>(
+    sim: &Sim,
+    sha: &D,
+    client: &TestClient<T>,
+    data: &[u8],
+) {
+    for chunk in data.chunks(2000) {
+        let buffer = client.data.borrow_mut().take().unwrap();
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let mut lease = LeasableBuffer::new(buffer);
+        lease.slice(..chunk.len());
+        assert_eq!(sha.add_data(lease), Ok(chunk.len()));
+        sim.run_all_deferred();
+    }
+}
+
+fn finish<'a, T: DigestType, D: Digest<'a, T>>(
+    sim: &Sim,
+    sha: &'a D,
+    client: &TestClient<T>,
+) -> Vec<u8> {
+    let digest = client.digest.borrow_mut().take().unwrap();
+    assert!(sha.run(digest).is_ok());
+    sim.run_all_deferred();
+    let result = client.digest.borrow().as_ref().unwrap().as_ref().to_vec();
+    result
+}
+
+type Client256 = TestClient<[u8; 32]>;
+type Client512 = TestClient<[u8; 64]>;
+
+fn new_sha256(sim: &Sim) -> (&'static SoftwareSha256<'static>, &'static Client256) {
+    let sha = leak(SoftwareSha256::new(sim.deferred_caller));
+    sha.initialize_callback_handle(sim.deferred_caller.register(sha).unwrap());
+    let client = new_client([0; 32]);
+    sha.set_client(client);
+    (sha, client)
+}
+
+fn new_sha512(sim: &Sim) -> (&'static SoftwareSha512<'static>, &'static Client512) {
+    let sha = leak(SoftwareSha512::new(sim.deferred_caller));
+    sha.initialize_callback_handle(sim.deferred_caller.register(sha).unwrap());
+    let client = new_client([0; 64]);
+    sha.set_client(client);
+    (sha, client)
+}
+
+/// The HMAC-SHA256 of `data` with `key`, which is padded with zeros to 32
+/// bytes. Keys shorter than the hash block are padded with zeros anyway, so
+/// this gives the same HMAC as the RFC 4231 keys shorter than 32 bytes.
+fn hmac(sim: &Sim, key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let (sha, client) = new_sha256(sim);
+    let mut padded = [0; 32];
+    padded[..key.len()].copy_from_slice(key);
+    assert_eq!(sha.set_mode_hmacsha256(&padded), Ok(()));
+    for part in data {
+        add_data(sim, sha, client, part);
+    }
+    finish(sim, sha, client)
+}
+
+#[test]
+fn sha256_one_block() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    add_data(&sim, sha, client, b"abc");
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+}
+
+#[test]
+fn sha256_two_blocks() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    add_data(
+        &sim,
+        sha,
+        client,
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+    );
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+    );
+}
+
+#[test]
+fn sha256_million_a() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    add_data(&sim, sha, client, &[b'a'; 1_000_000]);
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+    );
+}
+
+#[test]
+fn digest_starts_new_message() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    add_data(&sim, sha, client, b"xyz");
+    finish(&sim, sha, client);
+    add_data(&sim, sha, client, b"abc");
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+}
+
+#[test]
+fn long_buffers_are_hashed_in_parts() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    // A long message is hashed a few hundred bytes at a time, with the
+    // callback after the last part.
+    let buffer = client.data.borrow_mut().take().unwrap();
+    buffer.copy_from_slice(&[b'a'; 2000]);
+    assert_eq!(sha.add_data(LeasableBuffer::new(buffer)), Ok(2000));
+    assert_eq!(
+        sim.run_all_deferred(),
+        (2000 + BYTES_PER_CALL - 1) / BYTES_PER_CALL
+    );
+    assert_eq!(client.add_data_done.get(), 1);
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex("c4a700f85b7e9e5cdbdc51170409ee2ad48bebe2f2f0957a067937531a0a3c42")
+    );
+}
+
+#[test]
+fn one_buffer_at_a_time() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    let buffer = client.data.borrow_mut().take().unwrap();
+    assert_eq!(sha.add_data(LeasableBuffer::new(buffer)), Ok(2000));
+    let (e, buffer) = sha.add_data(LeasableBuffer::new(leak_buf(4))).unwrap_err();
+    assert_eq!((e, buffer.len()), (ReturnCode::EBUSY, 4));
+    assert_eq!(sha.set_mode_hmacsha256(&[0; 32]), Err(ReturnCode::EBUSY));
+    sim.run_all_deferred();
+    assert_eq!(client.add_data_done.get(), 1);
+}
+
+#[test]
+fn digest_while_adding_data() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    let buffer = client.data.borrow_mut().take().unwrap();
+    buffer.copy_from_slice(&[b'a'; 2000]);
+    assert_eq!(sha.add_data(LeasableBuffer::new(buffer)), Ok(2000));
+
+    // A digest requested while data is being added covers that data.
+    let digest = client.digest.borrow_mut().take().unwrap();
+    assert!(sha.run(digest).is_ok());
+    let (e, _) = sha.run(leak([0; 32])).unwrap_err();
+    assert_eq!(e, ReturnCode::EBUSY);
+    sim.run_all_deferred();
+    assert_eq!(client.add_data_done.get(), 1);
+    assert_eq!(
+        client.digest.borrow().as_ref().unwrap().to_vec(),
+        hex("c4a700f85b7e9e5cdbdc51170409ee2ad48bebe2f2f0957a067937531a0a3c42")
+    );
+}
+
+#[test]
+fn hmac_rfc4231_case_1() {
+    let sim = Sim::new();
+    assert_eq!(
+        hmac(&sim, &[0x0b; 20], &[b"Hi There"]),
+        hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7")
+    );
+}
+
+#[test]
+fn hmac_rfc4231_case_2() {
+    let sim = Sim::new();
+    assert_eq!(
+        hmac(&sim, b"Jefe", &[b"what do ya want ", b"for nothing?"]),
+        hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+    );
+}
+
+#[test]
+fn hmac_rfc4231_case_3() {
+    let sim = Sim::new();
+    assert_eq!(
+        hmac(&sim, &[0xaa; 20], &[&[0xdd; 50]]),
+        hex("773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe")
+    );
+}
+
+#[test]
+fn hmac_key_is_kept() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    let mut key = [0; 32];
+    key[..20].copy_from_slice(&[0xaa; 20]);
+    assert_eq!(sha.set_mode_hmacsha256(&key), Ok(()));
+    for _ in 0..2 {
+        add_data(&sim, sha, client, &[0xdd; 50]);
+        assert_eq!(
+            finish(&sim, sha, client),
+            hex("773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe")
+        );
+    }
+}
+
+#[test]
+fn clearing_the_key() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha256(&sim);
+    assert_eq!(sha.set_mode_hmacsha256(&[0x0b; 32]), Ok(()));
+    // Clearing the key goes back to plain hashes.
+    sha.clear_data();
+    add_data(&sim, sha, client, b"abc");
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+}
+
+#[test]
+fn sha512_one_block() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha512(&sim);
+    add_data(&sim, sha, client, b"abc");
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex(concat!(
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a",
+            "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        ))
+    );
+}
+
+#[test]
+fn sha512_million_a() {
+    let sim = Sim::new();
+    let (sha, client) = new_sha512(&sim);
+    add_data(&sim, sha, client, &[b'a'; 1_000_000]);
+    assert_eq!(
+        finish(&sim, sha, client),
+        hex(concat!(
+            "e718483d0ce769644e2e42c7bc15b4638e1f98b13b2044285632a803afa973eb",
+            "de0ff244877ea60a4cb0432ce577c31beb009c5c2c49aa2e4eadb217ad8cc09b"
+        ))
+    );
+}
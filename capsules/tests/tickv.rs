@@ -0,0 +1,381 @@
+//! Host tests for the TicKV capsule on a `MockFlash`, covering transactions
+//! and their recovery after a power loss.
+
+mod common;
+
+use capsules::block_storage::BlockPage;
+use capsules::tickv::{TicKVKeyType, TicKVStore};
+use common::{leak, leak_buf, Device, Sim};
+use kernel::common::cells::TakeCell;
+use kernel::hil::flash::HasClient;
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::ReturnCode;
+use std::cell::Cell;
+
+const REGION: usize = 512;
+const REGIONS: usize = 8;
+/// TicKV uses the flash pages after this one.
+const START_PAGE: usize = 2;
+
+type MockFlash = common::flash::MockFlash<REGION>;
+type Store = TicKVStore<'static, MockFlash>;
+
+/// Records the result of the last operation.
+struct TestClient {
+    result: Cell<Option<Result<(), ReturnCode>>>,
+    key: TakeCell<'static, TicKVKeyType>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl kv_system::Client<TicKVKeyType> for TestClient {
+    fn generate_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        _unhashed_key: &'static mut [u8],
+        key_buf: &'static mut TicKVKeyType,
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key_buf);
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut TicKVKeyType,
+        value: &'static mut [u8],
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+        self.buffer.replace(value);
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut TicKVKeyType,
+        ret_buf: &'static mut [u8],
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+        self.buffer.replace(ret_buf);
+    }
+
+    fn invalidate_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut TicKVKeyType,
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+    }
+
+    fn garbage_collect_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn next_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key_buf: &'static mut TicKVKeyType,
+        _cursor: usize,
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key_buf);
+    }
+
+    fn storage_stats_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        _stats: kv_system::StorageStats,
+    ) {
+        self.result.set(Some(result));
+    }
+
+    fn check_consistency_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        _found: usize,
+        _repaired: usize,
+    ) {
+        self.result.set(Some(result));
+    }
+
+    fn begin_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn stage_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut TicKVKeyType,
+        value: &'static mut [u8],
+    ) {
+        self.result.set(Some(result));
+        self.key.replace(key);
+        self.buffer.replace(value);
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn abort_transaction_complete(&self, result: Result<(), ReturnCode>) {
+        self.result.set(Some(result));
+    }
+}
+
+struct Harness<'a> {
+    sim: &'a Sim,
+    flash: &'static MockFlash,
+    store: &'static Store,
+    client: &'static TestClient,
+}
+
+impl<'a> Harness<'a> {
+    /// Creates and initialises a store on `flash`, as after a reboot.
+    fn boot(sim: &'a Sim, flash: &'static MockFlash) -> Harness<'a> {
+        let store = leak(TicKVStore::new(
+            flash,
+            leak([0; REGION]),
+            leak(BlockPage::default()),
+            START_PAGE,
+            REGIONS * REGION,
+            sim.deferred_caller,
+        ));
+        store.initialize_callback_handle(sim.deferred_caller.register(store).unwrap());
+        flash.set_client(store);
+        let client = leak(TestClient {
+            result: Cell::new(None),
+            key: TakeCell::new(leak([0; 8])),
+            buffer: TakeCell::new(leak_buf(16)),
+        });
+        store.set_client(client);
+
+        let h = Harness {
+            sim,
+            flash,
+            store,
+            client,
+        };
+        store.initalise();
+        h.run();
+        h
+    }
+
+    /// Completes flash operations and deferred calls until there are none.
+    fn run(&self) {
+        self.sim.pump(&[self.flash]);
+    }
+
+    fn complete(&self) -> Result<(), ReturnCode> {
+        self.run();
+        self.client
+            .result
+            .take()
+            .expect("operation did not complete")
+    }
+
+    fn append(&self, key: u8, value: u8) -> Result<(), ReturnCode> {
+        self.write(key, value, false)
+    }
+
+    fn stage(&self, key: u8, value: u8) -> Result<(), ReturnCode> {
+        self.write(key, value, true)
+    }
+
+    fn write(&self, key: u8, value: u8, staging: bool) -> Result<(), ReturnCode> {
+        let key_buf = self.client.key.take().unwrap();
+        let buffer = self.client.buffer.take().unwrap();
+        *key_buf = [key; 8];
+        buffer[..4].copy_from_slice(&[value; 4]);
+        let ret = if staging {
+            self.store.stage_key(key_buf, buffer, 4)
+        } else {
+            self.store.append_key(key_buf, buffer, 4)
+        };
+        if let Err((key_buf, buffer, e)) = ret {
+            self.client.key.replace(key_buf);
+            self.client.buffer.replace(buffer);
+            return Err(e);
+        }
+        self.complete()
+    }
+
+    /// The value stored for `key`, if there is one.
+    fn get(&self, key: u8) -> Option<u8> {
+        let key_buf = self.client.key.take().unwrap();
+        *key_buf = [key; 8];
+        let buffer = self.client.buffer.take().unwrap();
+        if let Err((key_buf, buffer, e)) = self.store.get_value(key_buf, buffer) {
+            self.client.key.replace(key_buf);
+            self.client.buffer.replace(buffer);
+            panic!("get_value failed: {:?}", e);
+        }
+        match self.complete() {
+            Ok(()) => self.client.buffer.map(|buffer| buffer[0]),
+            Err(ReturnCode::ENOSUPPORT) => None,
+            Err(e) => panic!("get_value failed: {:?}", e),
+        }
+    }
+
+    fn begin(&self) -> Result<(), ReturnCode> {
+        self.store.begin_transaction()?;
+        self.complete()
+    }
+
+    fn commit(&self) -> Result<(), ReturnCode> {
+        self.store.commit_transaction()?;
+        self.complete()
+    }
+
+    fn abort(&self) -> Result<(), ReturnCode> {
+        self.store.abort_transaction()?;
+        self.complete()
+    }
+}
+
+fn new_flash() -> &'static MockFlash {
+    leak(MockFlash::with_reserved(START_PAGE + REGIONS, START_PAGE))
+}
+
+#[test]
+fn append_and_get() {
+    let sim = Sim::new();
+    let h = Harness::boot(&sim, new_flash());
+    assert_eq!(h.get(1), None);
+    assert_eq!(h.append(1, 0x11), Ok(()));
+    assert_eq!(h.get(1), Some(0x11));
+    assert_eq!(h.append(1, 0x12), Err(ReturnCode::ENOSUPPORT));
+
+    let h = Harness::boot(&sim, h.flash);
+    assert_eq!(h.get(1), Some(0x11));
+}
+
+#[test]
+fn commit_replaces_values() {
+    let sim = Sim::new();
+    let h = Harness::boot(&sim, new_flash());
+    assert_eq!(h.append(1, 0x11), Ok(()));
+
+    assert_eq!(h.begin(), Ok(()));
+    assert_eq!(h.stage(1, 0x21), Ok(()));
+    assert_eq!(h.stage(2, 0x22), Ok(()));
+    assert_eq!(h.stage(2, 0x23), Err(ReturnCode::ENOSUPPORT));
+    assert_eq!((h.get(1), h.get(2)), (Some(0x11), None));
+    assert_eq!(h.commit(), Ok(()));
+    assert_eq!((h.get(1), h.get(2)), (Some(0x21), Some(0x22)));
+
+    let h = Harness::boot(&sim, h.flash);
+    assert_eq!((h.get(1), h.get(2)), (Some(0x21), Some(0x22)));
+}
+
+#[test]
+fn abort_discards_staged_values() {
+    let sim = Sim::new();
+    let h = Harness::boot(&sim, new_flash());
+    assert_eq!(h.append(1, 0x11), Ok(()));
+
+    assert_eq!(h.begin(), Ok(()));
+    assert_eq!(h.stage(1, 0x21), Ok(()));
+    assert_eq!(h.stage(2, 0x22), Ok(()));
+    assert_eq!(h.abort(), Ok(()));
+    assert_eq!((h.get(1), h.get(2)), (Some(0x11), None));
+
+    // The staged values are gone, so they can be staged again.
+    assert_eq!(h.begin(), Ok(()));
+    assert_eq!(h.stage(2, 0x32), Ok(()));
+    assert_eq!(h.commit(), Ok(()));
+    assert_eq!(h.get(2), Some(0x32));
+}
+
+#[test]
+fn transaction_errors() {
+    let sim = Sim::new();
+    let h = Harness::boot(&sim, new_flash());
+    assert_eq!(h.stage(1, 0x11), Err(ReturnCode::EINVAL));
+    assert_eq!(h.commit(), Err(ReturnCode::EINVAL));
+    assert_eq!(h.abort(), Err(ReturnCode::EINVAL));
+    assert_eq!(h.begin(), Ok(()));
+    assert_eq!(h.begin(), Err(ReturnCode::EALREADY));
+    assert_eq!(h.abort(), Ok(()));
+}
+
+/// Loses power during the `n`th flash operation of a transaction replacing
+/// keys 1 and 2, tearing the write in progress if `tear` is set. Returns the
+/// flash, or `None` if the transaction completed first.
+fn interrupted_transaction(sim: &Sim, n: usize, tear: bool) -> Option<&'static MockFlash> {
+    let h = Harness::boot(sim, new_flash());
+    assert_eq!(h.append(1, 0x11), Ok(()));
+    assert_eq!(h.append(2, 0x12), Ok(()));
+
+    // Run the transaction one flash operation at a time.
+    let mut remaining = n;
+    for step in 0..4 {
+        let ret = match step {
+            0 => h.store.begin_transaction(),
+            1 => stage_without_waiting(&h, 1, 0x21),
+            2 => stage_without_waiting(&h, 2, 0x22),
+            _ => h.store.commit_transaction(),
+        };
+        assert_eq!(ret, Ok(()));
+        loop {
+            if remaining == 0 {
+                h.flash.tear.set(tear);
+                h.flash.run();
+                h.flash.cut_power();
+                h.flash.tear.set(false);
+                return Some(h.flash);
+            }
+            remaining -= 1;
+            if !h.flash.run() {
+                break;
+            }
+        }
+        assert_eq!(h.client.result.take(), Some(Ok(())));
+    }
+    None
+}
+
+fn stage_without_waiting(h: &Harness, key: u8, value: u8) -> Result<(), ReturnCode> {
+    let key_buf = h.client.key.take().unwrap();
+    let buffer = h.client.buffer.take().unwrap();
+    *key_buf = [key; 8];
+    buffer[..4].copy_from_slice(&[value; 4]);
+    h.store.stage_key(key_buf, buffer, 4).map_err(|(_, _, e)| e)
+}
+
+/// Checks that power losses during a transaction leave either all or none of
+/// its values once the store is initialised again.
+fn power_loss(tear: bool) {
+    let mut n = 0;
+    let mut committed = false;
+    loop {
+        let sim = Sim::new();
+        let flash = match interrupted_transaction(&sim, n, tear) {
+            Some(flash) => flash,
+            None => break,
+        };
+        let h = Harness::boot(&sim, flash);
+        match (h.get(1), h.get(2)) {
+            (Some(0x11), Some(0x12)) => assert!(!committed, "commit lost after {} steps", n),
+            (Some(0x21), Some(0x22)) => committed = true,
+            values => panic!("partial transaction after {} steps: {:?}", n, values),
+        }
+
+        // Recovery closed the transaction.
+        assert_eq!(h.begin(), Ok(()));
+        assert_eq!(h.abort(), Ok(()));
+        n += 1;
+    }
+    assert!(committed);
+}
+
+#[test]
+fn power_loss_during_transaction() {
+    power_loss(false);
+}
+
+#[test]
+fn torn_write_during_transaction() {
+    power_loss(true);
+}
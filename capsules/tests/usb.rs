@@ -0,0 +1,1834 @@
+//! Enumerates the USB device classes over `SoftwareUsbController` and checks
+//! their descriptors, control requests and data endpoints from the host side.
+
+mod common;
+
+use capsules::block_storage::BlockPage;
+use capsules::net::ipv6::ipv6_link::{IP6Link, IP6LinkRxClient, IP6LinkTxClient};
+use capsules::usb::cdc::CdcAcm;
+use capsules::usb::cdc_ecm::{self, CdcEcm};
+use capsules::usb::composite::{CompositeDevice, UsbFunction};
+use capsules::usb::ctap::CtapHid;
+use capsules::usb::descriptors::{
+    DescriptorType, HIDCountryCode, HIDDescriptor, HIDSubordinateDescriptor, ReportDescriptor,
+};
+use capsules::usb::dfu::{self, Dfu, Target, TargetKind};
+use capsules::usb::hid::{self, BootProtocol, HidDevice};
+use capsules::usb::msc::{self, MassStorage};
+use capsules::usb::software_controller::{
+    Setup, SoftwareUsbController, TransferError, REQUEST_TYPE_CLASS_INTERFACE_IN,
+    REQUEST_TYPE_CLASS_INTERFACE_OUT,
+};
+use capsules::usb::usbc_client;
+use common::flash::MockFlash;
+use common::ramdisk::RamDisk;
+use common::{leak, leak_buf, Device, SimAlarm};
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::hil::flash::HasClient;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::hil::uart::{self, Receive, Transmit};
+use kernel::hil::usb::{Client, UsbController};
+use kernel::hil::usb_hid::{self, UsbHid};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+type Usb = SoftwareUsbController<'static>;
+
+const LANGUAGE: u16 = 0x0409;
+
+static STRINGS: &[&str; 3] = &["XYZ Corp.", "The Zorpinator", "Serial No. 5"];
+
+/// bmRequestType of a standard request to an interface, device to host.
+const REQUEST_TYPE_INTERFACE_IN: u8 = 0x81;
+/// bmRequestType of a standard request to an interface, host to device.
+const REQUEST_TYPE_INTERFACE_OUT: u8 = 0x01;
+
+/// An interface descriptor and the addresses of the endpoints following it.
+#[derive(Debug, PartialEq)]
+struct Interface {
+    number: u8,
+    alternate_setting: u8,
+    class: u8,
+    endpoints: Vec<u8>,
+}
+
+/// A configuration descriptor and the descriptors following it.
+struct Configuration {
+    bytes: Vec<u8>,
+    interfaces: Vec<Interface>,
+}
+
+impl Configuration {
+    /// Returns the first descriptor of the given type.
+    fn find(&self, descriptor_type: u8) -> Option<&[u8]> {
+        descriptors(&self.bytes).find(|d| d[1] == descriptor_type)
+    }
+}
+
+/// Iterates over the descriptors packed in `bytes`.
+fn descriptors(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = bytes;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let len = rest[0] as usize;
+        assert!(len >= 2 && len <= rest.len(), "bad descriptor length");
+        let (descriptor, tail) = rest.split_at(len);
+        rest = tail;
+        Some(descriptor)
+    })
+}
+
+/// Reads and checks the device descriptor.
+fn device_descriptor(usb: &Usb) -> [u8; 18] {
+    let mut descriptor = [0; 18];
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::Device, 0, 0, &mut descriptor),
+        Ok(18)
+    );
+    assert_eq!(descriptor[0], 18);
+    assert_eq!(descriptor[1], DescriptorType::Device as u8);
+    assert_eq!(descriptor[17], 1, "one configuration");
+    descriptor
+}
+
+/// Reads the configuration descriptor the way hosts do, first its header and
+/// then all of it, and checks that the lengths and counts in it add up.
+fn configuration(usb: &Usb) -> Configuration {
+    let mut header = [0; 9];
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::Configuration, 0, 0, &mut header),
+        Ok(9)
+    );
+    assert_eq!(header[1], DescriptorType::Configuration as u8);
+    let total_length = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+    let mut bytes = vec![0; total_length + 16];
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::Configuration, 0, 0, &mut bytes),
+        Ok(total_length),
+        "wTotalLength matches the descriptors sent"
+    );
+    bytes.truncate(total_length);
+    assert_eq!(bytes[..9], header);
+
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut expected_endpoints = Vec::new();
+    for descriptor in descriptors(&bytes).skip(1) {
+        if descriptor[1] == DescriptorType::Interface as u8 {
+            assert_eq!(descriptor.len(), 9);
+            interfaces.push(Interface {
+                number: descriptor[2],
+                alternate_setting: descriptor[3],
+                class: descriptor[5],
+                endpoints: Vec::new(),
+            });
+            expected_endpoints.push(descriptor[4] as usize);
+        } else if descriptor[1] == DescriptorType::Endpoint as u8 {
+            assert_eq!(descriptor.len(), 7);
+            interfaces
+                .last_mut()
+                .expect("endpoint before any interface")
+                .endpoints
+                .push(descriptor[2]);
+        }
+    }
+    for (interface, expected) in interfaces.iter().zip(expected_endpoints) {
+        assert_eq!(interface.endpoints.len(), expected, "bNumEndpoints");
+    }
+    let num_interfaces = interfaces
+        .iter()
+        .filter(|i| i.alternate_setting == 0)
+        .count();
+    assert_eq!(bytes[4] as usize, num_interfaces, "bNumInterfaces");
+
+    Configuration { bytes, interfaces }
+}
+
+/// Reads string descriptor `index` and decodes it.
+fn string(usb: &Usb, index: u8) -> Result<String, TransferError> {
+    let mut descriptor = [0; 255];
+    let len = usb.get_descriptor(DescriptorType::String, index, LANGUAGE, &mut descriptor)?;
+    assert_eq!(descriptor[0] as usize, len);
+    assert_eq!(descriptor[1], DescriptorType::String as u8);
+    let utf16: Vec<u16> = descriptor[2..len]
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    Ok(String::from_utf16(&utf16).unwrap())
+}
+
+/// Assigns the device an address and selects its configuration.
+fn enumerate(usb: &Usb, address: u16) {
+    // Hosts first read only the start of the device descriptor to learn the
+    // packet size of the control endpoint.
+    let mut start = [0; 8];
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::Device, 0, 0, &mut start),
+        Ok(8)
+    );
+    assert_eq!(usb.set_address(address), Ok(()));
+    assert_eq!(usb.address(), address);
+    device_descriptor(usb);
+    assert_eq!(usb.set_configuration(1), Ok(()));
+}
+
+fn software_controller() -> &'static Usb {
+    leak(SoftwareUsbController::new())
+}
+
+#[test]
+fn vendor_client_enumerates() {
+    let usb = software_controller();
+    let client = leak(usbc_client::Client::new(usb, 64));
+    usb.set_client(client);
+    client.enable();
+
+    let mut descriptor = [0; 18];
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::Device, 0, 0, &mut descriptor),
+        Err(TransferError::Detached)
+    );
+    client.attach();
+    assert!(usb.is_attached());
+
+    let mut start = [0; 8];
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::Device, 0, 0, &mut start),
+        Ok(8)
+    );
+    assert_eq!(start[7], 64, "bMaxPacketSize0");
+
+    // The new address only applies after the status stage.
+    assert_eq!(usb.address(), 0);
+    assert_eq!(usb.set_address(9), Ok(()));
+    assert_eq!(usb.address(), 9);
+
+    let device = device_descriptor(usb);
+    assert_eq!(u16::from_le_bytes([device[8], device[9]]), 0x6667);
+    assert_eq!(u16::from_le_bytes([device[10], device[11]]), 0xabcd);
+    assert_eq!(device[14..17], [1, 2, 3], "string indices");
+
+    let configuration = configuration(usb);
+    assert_eq!(
+        configuration.interfaces,
+        vec![Interface {
+            number: 0,
+            alternate_setting: 0,
+            class: 0xff,
+            endpoints: vec![0x81, 0x02],
+        }]
+    );
+
+    // String 0 lists the supported languages.
+    let mut languages = [0; 4];
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::String, 0, 0, &mut languages),
+        Ok(4)
+    );
+    assert_eq!(languages, [4, 3, 0x09, 0x04]);
+    for (i, expected) in STRINGS.iter().enumerate() {
+        assert_eq!(string(usb, i as u8 + 1).as_deref(), Ok(*expected));
+    }
+    assert_eq!(string(usb, 4), Err(TransferError::Stall));
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::String, 1, 0x0407, &mut descriptor),
+        Err(TransferError::Stall)
+    );
+    // A full-speed device has no device qualifier.
+    assert_eq!(
+        usb.get_descriptor(DescriptorType::DeviceQualifier, 0, 0, &mut descriptor),
+        Err(TransferError::Stall)
+    );
+    // Stalls do not disturb the following transfers.
+    assert_eq!(device_descriptor(usb), device);
+
+    assert_eq!(usb.set_configuration(1), Ok(()));
+}
+
+#[test]
+fn vendor_client_echoes_bulk_packets() {
+    let usb = software_controller();
+    let client = leak(usbc_client::Client::new(usb, 64));
+    usb.set_client(client);
+    client.enable();
+    client.attach();
+    enumerate(usb, 1);
+
+    let mut packet = [0; 8];
+    assert_eq!(usb.in_packet(1, &mut packet), Err(TransferError::Nak));
+    assert_eq!(usb.out_packet(0, b"ctrl"), Err(TransferError::Disabled));
+
+    assert_eq!(usb.out_packet(2, b"hello"), Ok(()));
+    assert!(usb.in_packet_ready(1));
+    assert_eq!(usb.out_packet(2, b"world"), Ok(()));
+    // The echo buffer only holds eight bytes, so the device NAKs until the
+    // first packet has gone out.
+    assert_eq!(usb.out_packet(2, b"again"), Err(TransferError::Nak));
+    assert_eq!(usb.out_packet(2, b"again"), Err(TransferError::Nak));
+
+    assert_eq!(usb.in_packet(1, &mut packet), Ok(5));
+    assert_eq!(&packet[..5], b"hello");
+    assert_eq!(usb.out_packet(2, b"again"), Ok(()));
+    assert_eq!(usb.in_packet(1, &mut packet), Ok(5));
+    assert_eq!(&packet[..5], b"world");
+    assert_eq!(usb.in_packet(1, &mut packet), Ok(5));
+    assert_eq!(&packet[..5], b"again");
+    assert_eq!(usb.in_packet(1, &mut packet), Err(TransferError::Nak));
+}
+
+struct UartRecorder {
+    transmitted: Cell<Option<(usize, ReturnCode)>>,
+    received: RefCell<Option<(Vec<u8>, ReturnCode)>>,
+}
+
+impl uart::TransmitClient for UartRecorder {
+    fn transmitted_buffer(&self, _tx_buffer: &'static mut [u8], tx_len: usize, rval: ReturnCode) {
+        self.transmitted.set(Some((tx_len, rval)));
+    }
+}
+
+impl uart::ReceiveClient for UartRecorder {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: ReturnCode,
+        _error: uart::Error,
+    ) {
+        *self.received.borrow_mut() = Some((rx_buffer[..rx_len].to_vec(), rval));
+    }
+}
+
+fn set_line_coding(usb: &Usb, baud_rate: u32) -> Result<(), TransferError> {
+    let mut line_coding = [0; 7];
+    line_coding[..4].copy_from_slice(&baud_rate.to_le_bytes());
+    line_coding[6] = 8; // Data bits
+    usb.control_write(
+        Setup {
+            request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+            request: 0x20,
+            value: 0,
+            index: 0,
+            length: line_coding.len() as u16,
+        },
+        &line_coding,
+    )
+}
+
+#[test]
+fn cdc_acm_connects_and_transfers() {
+    let usb = software_controller();
+    let alarm = leak(SimAlarm::new());
+    let deferred_caller = leak(DynamicDeferredCall::new(leak([
+        DynamicDeferredCallClientState::default(),
+    ])));
+    let bootloader_requested: &Cell<bool> = leak(Cell::new(false));
+    let enter_bootloader = leak(move || bootloader_requested.set(true));
+    let cdc = leak(CdcAcm::new(
+        usb,
+        64,
+        0x2341,
+        0x005d,
+        STRINGS,
+        alarm,
+        deferred_caller,
+        Some(enter_bootloader),
+    ));
+    let recorder = leak(UartRecorder {
+        transmitted: Cell::new(None),
+        received: RefCell::new(None),
+    });
+    cdc.set_transmit_client(recorder);
+    cdc.set_receive_client(recorder);
+    usb.set_client(cdc);
+    cdc.enable();
+    cdc.attach();
+
+    usb.reset();
+    enumerate(usb, 3);
+    let configuration = configuration(usb);
+    assert_eq!(
+        configuration.interfaces,
+        vec![
+            Interface {
+                number: 0,
+                alternate_setting: 0,
+                class: 0x02,
+                endpoints: vec![0x84],
+            },
+            Interface {
+                number: 1,
+                alternate_setting: 0,
+                class: 0x0a,
+                endpoints: vec![0x82, 0x03],
+            },
+        ]
+    );
+    assert!(
+        configuration.find(0x24).is_some(),
+        "CDC functional descriptors"
+    );
+
+    // Messages written during boot are held until a terminal connects.
+    let message: Vec<u8> = (0..80).collect();
+    let tx_buffer = leak_buf(message.len());
+    tx_buffer.copy_from_slice(&message);
+    let (result, _) = cdc.transmit_buffer(tx_buffer, message.len());
+    assert_eq!(result, ReturnCode::SUCCESS);
+    let mut packet = [0; 64];
+    assert_eq!(usb.in_packet(2, &mut packet), Err(TransferError::Nak));
+
+    assert_eq!(set_line_coding(usb, 115200), Ok(()));
+    assert_eq!(
+        usb.control_write(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+                request: 0x22, // SET_CONTROL_LINE_STATE
+                value: 0x03,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        ),
+        Ok(())
+    );
+    assert!(!bootloader_requested.get());
+
+    let mut received = Vec::new();
+    for expected in &[64, 16] {
+        assert_eq!(usb.in_packet(2, &mut packet), Ok(*expected));
+        received.extend_from_slice(&packet[..*expected]);
+    }
+    assert_eq!(received, message);
+    assert_eq!(
+        recorder.transmitted.get(),
+        Some((message.len(), ReturnCode::SUCCESS))
+    );
+    assert_eq!(usb.in_packet(2, &mut packet), Err(TransferError::Nak));
+
+    let (result, _) = cdc.receive_buffer(leak_buf(10), 10);
+    assert_eq!(result, ReturnCode::SUCCESS);
+    assert_eq!(usb.out_packet(3, b"abcdef"), Ok(()));
+    assert!(recorder.received.borrow().is_none());
+    assert_eq!(usb.out_packet(3, b"ghijkl"), Ok(()));
+    assert_eq!(
+        *recorder.received.borrow(),
+        Some((b"abcdefghij".to_vec(), ReturnCode::SUCCESS))
+    );
+
+    // Opening the port at 1200 baud asks the device to enter its bootloader.
+    assert_eq!(set_line_coding(usb, 1200), Ok(()));
+    assert!(bootloader_requested.get());
+}
+
+#[test]
+fn ctap_hid_report_descriptor() {
+    let usb = software_controller();
+    let ctap = leak(CtapHid::new(usb, 0x1915, 0x503a, STRINGS));
+    usb.set_client(ctap);
+    ctap.enable();
+    ctap.attach();
+    enumerate(usb, 4);
+
+    let configuration = configuration(usb);
+    assert_eq!(configuration.interfaces.len(), 1);
+    assert_eq!(configuration.interfaces[0].class, 0x03);
+    assert_eq!(configuration.interfaces[0].endpoints, vec![0x81, 0x01]);
+
+    let hid = configuration
+        .find(DescriptorType::HID as u8)
+        .expect("HID descriptor")
+        .to_vec();
+    assert_eq!(hid[6], DescriptorType::Report as u8);
+    let report_length = u16::from_le_bytes([hid[7], hid[8]]) as usize;
+
+    // The HID descriptor can also be requested on its own.
+    let mut descriptor = [0; 64];
+    let get_interface_descriptor = |descriptor_type: DescriptorType, data: &mut [u8]| {
+        usb.control_read(
+            Setup {
+                request_type: REQUEST_TYPE_INTERFACE_IN,
+                request: 6, // GET_DESCRIPTOR
+                value: (descriptor_type as u16) << 8,
+                index: 0,
+                length: data.len() as u16,
+            },
+            data,
+        )
+    };
+    assert_eq!(
+        get_interface_descriptor(DescriptorType::HID, &mut descriptor),
+        Ok(hid.len())
+    );
+    assert_eq!(descriptor[..hid.len()], hid[..]);
+
+    let mut report = [0; 128];
+    assert_eq!(
+        get_interface_descriptor(DescriptorType::Report, &mut report),
+        Ok(report_length)
+    );
+    // Usage page FIDO, in one application collection.
+    assert_eq!(report[..3], [0x06, 0xd0, 0xf1]);
+    assert_eq!(report[report_length - 1], 0xc0);
+}
+
+struct ReportRecorder {
+    transmitted: Cell<Option<ReturnCode>>,
+    received: RefCell<Vec<Vec<u8>>>,
+}
+
+impl<'a> usb_hid::Client<'a, [u8; 64]> for ReportRecorder {
+    fn packet_received(
+        &'a self,
+        result: ReturnCode,
+        buffer: &'static mut [u8; 64],
+        endpoint: usize,
+    ) {
+        assert_eq!(result, ReturnCode::SUCCESS);
+        assert_eq!(endpoint, 1);
+        self.received.borrow_mut().push(buffer.to_vec());
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        result: ReturnCode,
+        _buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        self.transmitted.set(Some(result));
+    }
+
+    fn can_receive(&'a self) -> bool {
+        true
+    }
+}
+
+/// Creates a HID device on `usb` with the given report descriptor, the way
+/// `UsbHidComponent` does, without enabling it.
+fn hid_device<U: UsbController<'static>>(
+    usb: &'static U,
+    report_descriptor: &'static [u8],
+    boot_protocol: BootProtocol,
+    input_report_len: usize,
+    output_report_len: usize,
+) -> (&'static HidDevice<'static, U>, &'static ReportRecorder) {
+    let sub_descriptors = leak([HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: report_descriptor.len() as u16,
+    }]);
+    let hid_descriptor = leak(HIDDescriptor {
+        hid_class: 0x0111,
+        country_code: HIDCountryCode::NotSupported,
+        sub_descriptors: sub_descriptors,
+    });
+    let hid = leak(HidDevice::new(
+        usb,
+        64,
+        0x1915,
+        0x503a,
+        STRINGS,
+        hid_descriptor,
+        leak(ReportDescriptor {
+            desc: report_descriptor,
+        }),
+        boot_protocol,
+        input_report_len,
+        output_report_len,
+    ));
+    let recorder = leak(ReportRecorder {
+        transmitted: Cell::new(None),
+        received: RefCell::new(Vec::new()),
+    });
+    hid.set_client(recorder);
+    usb.set_client(hid);
+    (hid, recorder)
+}
+
+/// Reads descriptor `descriptor_type` of interface `interface`.
+fn interface_descriptor(
+    usb: &Usb,
+    descriptor_type: DescriptorType,
+    interface: u16,
+    data: &mut [u8],
+) -> Result<usize, TransferError> {
+    usb.control_read(
+        Setup {
+            request_type: REQUEST_TYPE_INTERFACE_IN,
+            request: 6, // GET_DESCRIPTOR
+            value: (descriptor_type as u16) << 8,
+            index: interface,
+            length: data.len() as u16,
+        },
+        data,
+    )
+}
+
+/// Sends a HID class request that reads one byte.
+fn hid_get(usb: &Usb, request: u8, interface: u16) -> Result<u8, TransferError> {
+    let mut value = [0xff; 1];
+    assert_eq!(
+        usb.control_read(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_IN,
+                request: request,
+                value: 0,
+                index: interface,
+                length: 1,
+            },
+            &mut value,
+        )?,
+        1
+    );
+    Ok(value[0])
+}
+
+/// Sends a HID class request that carries no data.
+fn hid_set(usb: &Usb, request: u8, value: u16, interface: u16) -> Result<(), TransferError> {
+    usb.control_write(
+        Setup {
+            request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+            request: request,
+            value: value,
+            index: interface,
+            length: 0,
+        },
+        &[],
+    )
+}
+
+const HID_GET_REPORT: u8 = 0x01;
+const HID_GET_IDLE: u8 = 0x02;
+const HID_GET_PROTOCOL: u8 = 0x03;
+const HID_SET_REPORT: u8 = 0x09;
+const HID_SET_IDLE: u8 = 0x0a;
+const HID_SET_PROTOCOL: u8 = 0x0b;
+
+#[test]
+fn hid_keyboard_descriptors() {
+    let usb = software_controller();
+    let (hid, _) = hid_device(
+        usb,
+        hid::KEYBOARD_REPORT_DESCRIPTOR,
+        BootProtocol::Keyboard,
+        8,
+        1,
+    );
+    hid.enable();
+    hid.attach();
+    enumerate(usb, 6);
+
+    let configuration = configuration(usb);
+    assert_eq!(
+        configuration.interfaces,
+        vec![Interface {
+            number: 0,
+            alternate_setting: 0,
+            class: 0x03,
+            endpoints: vec![0x81, 0x01],
+        }]
+    );
+    let interface = configuration.find(DescriptorType::Interface as u8).unwrap();
+    assert_eq!(interface[6..8], [0x01, 0x01], "boot keyboard");
+    let endpoints: Vec<&[u8]> = descriptors(&configuration.bytes)
+        .filter(|d| d[1] == DescriptorType::Endpoint as u8)
+        .collect();
+    for (endpoint, max_packet_size) in endpoints.iter().zip(&[8, 1]) {
+        assert_eq!(endpoint[3], 0x03, "interrupt endpoint");
+        assert_eq!(
+            u16::from_le_bytes([endpoint[4], endpoint[5]]),
+            *max_packet_size
+        );
+    }
+
+    let hid = configuration
+        .find(DescriptorType::HID as u8)
+        .expect("HID descriptor")
+        .to_vec();
+    assert_eq!(hid[6], DescriptorType::Report as u8);
+    assert_eq!(
+        u16::from_le_bytes([hid[7], hid[8]]) as usize,
+        hid::KEYBOARD_REPORT_DESCRIPTOR.len()
+    );
+    let mut descriptor = [0; 64];
+    assert_eq!(
+        interface_descriptor(usb, DescriptorType::HID, 0, &mut descriptor),
+        Ok(hid.len())
+    );
+    assert_eq!(descriptor[..hid.len()], hid[..]);
+    let mut report = [0; 128];
+    assert_eq!(
+        interface_descriptor(usb, DescriptorType::Report, 0, &mut report),
+        Ok(hid::KEYBOARD_REPORT_DESCRIPTOR.len())
+    );
+    assert_eq!(
+        report[..hid::KEYBOARD_REPORT_DESCRIPTOR.len()],
+        hid::KEYBOARD_REPORT_DESCRIPTOR[..]
+    );
+}
+
+#[test]
+fn hid_mouse_has_no_out_endpoint() {
+    let usb = software_controller();
+    let (hid, _) = hid_device(usb, hid::MOUSE_REPORT_DESCRIPTOR, BootProtocol::Mouse, 4, 0);
+    hid.enable();
+    hid.attach();
+    enumerate(usb, 7);
+
+    let configuration = configuration(usb);
+    assert_eq!(
+        configuration.interfaces,
+        vec![Interface {
+            number: 0,
+            alternate_setting: 0,
+            class: 0x03,
+            endpoints: vec![0x81],
+        }]
+    );
+    let interface = configuration.find(DescriptorType::Interface as u8).unwrap();
+    assert_eq!(interface[6..8], [0x01, 0x02], "boot mouse");
+    assert_eq!(usb.out_packet(1, &[0]), Err(TransferError::Disabled));
+}
+
+#[test]
+fn hid_class_requests() {
+    let usb = software_controller();
+    let (hid, recorder) = hid_device(
+        usb,
+        hid::KEYBOARD_REPORT_DESCRIPTOR,
+        BootProtocol::Keyboard,
+        8,
+        1,
+    );
+    hid.enable();
+    hid.attach();
+    enumerate(usb, 8);
+
+    // Devices start with the report protocol and an infinite idle rate.
+    assert_eq!(hid_get(usb, HID_GET_PROTOCOL, 0), Ok(1));
+    assert_eq!(hid_get(usb, HID_GET_IDLE, 0), Ok(0));
+    assert_eq!(hid_set(usb, HID_SET_PROTOCOL, 0, 0), Ok(()));
+    assert_eq!(hid_get(usb, HID_GET_PROTOCOL, 0), Ok(0));
+    // The idle rate is in the upper byte, in units of 4 ms.
+    assert_eq!(hid_set(usb, HID_SET_IDLE, 125 << 8, 0), Ok(()));
+    assert_eq!(hid_get(usb, HID_GET_IDLE, 0), Ok(125));
+
+    let mut report = [0; 8];
+    assert_eq!(
+        usb.control_read(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_IN,
+                request: HID_GET_REPORT,
+                value: 0x0100,
+                index: 0,
+                length: 8,
+            },
+            &mut report,
+        ),
+        Err(TransferError::Stall)
+    );
+
+    // Output reports also arrive through SET_REPORT, and are dropped if the
+    // client has not given a buffer.
+    let set_report = |leds: u8| {
+        usb.control_write(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+                request: HID_SET_REPORT,
+                value: 0x0200, // Output report
+                index: 0,
+                length: 1,
+            },
+            &[leds],
+        )
+    };
+    assert_eq!(set_report(0x01), Ok(()));
+    assert!(recorder.received.borrow().is_empty());
+    assert!(hid.receive_buffer(leak([0; 64])).is_ok());
+    assert_eq!(set_report(0x02), Ok(()));
+    let mut expected = vec![0; 64];
+    expected[0] = 0x02;
+    assert_eq!(*recorder.received.borrow(), vec![expected]);
+
+    // A bus reset restores the defaults.
+    usb.reset();
+    enumerate(usb, 8);
+    assert_eq!(hid_get(usb, HID_GET_PROTOCOL, 0), Ok(1));
+    assert_eq!(hid_get(usb, HID_GET_IDLE, 0), Ok(0));
+}
+
+#[test]
+fn hid_reports_on_interrupt_endpoints() {
+    let usb = software_controller();
+    let (hid, recorder) = hid_device(
+        usb,
+        hid::KEYBOARD_REPORT_DESCRIPTOR,
+        BootProtocol::Keyboard,
+        8,
+        1,
+    );
+    hid.enable();
+    hid.attach();
+    enumerate(usb, 9);
+
+    // Only the first eight bytes of the buffer make up the input report.
+    let mut packet = [0; 64];
+    assert_eq!(usb.in_packet(1, &mut packet), Err(TransferError::Nak));
+    let report = leak([0xee; 64]);
+    report[..8].copy_from_slice(&[0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+    assert_eq!(hid.send_buffer(report).ok(), Some(8));
+    assert!(matches!(
+        hid.send_buffer(leak([0; 64])),
+        Err((ReturnCode::EBUSY, _))
+    ));
+    assert_eq!(usb.in_packet(1, &mut packet), Ok(8));
+    assert_eq!(packet[..8], [0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+    assert_eq!(recorder.transmitted.get(), Some(ReturnCode::SUCCESS));
+    assert_eq!(usb.in_packet(1, &mut packet), Err(TransferError::Nak));
+
+    // Without a receive buffer the device NAKs output reports, but keeps the
+    // first one and passes it on once it gets a buffer.
+    assert_eq!(usb.out_packet(1, &[0x03]), Err(TransferError::Nak));
+    assert_eq!(usb.out_packet(1, &[0x04]), Err(TransferError::Nak));
+    assert!(recorder.received.borrow().is_empty());
+    assert!(hid.receive_buffer(leak([0xee; 64])).is_ok());
+    let mut expected = vec![0; 64];
+    expected[0] = 0x03;
+    assert_eq!(*recorder.received.borrow(), vec![expected.clone()]);
+
+    // Bytes beyond the output report length are not passed on.
+    assert!(hid.receive_buffer(leak([0xee; 64])).is_ok());
+    assert_eq!(usb.out_packet(1, &[0x05, 0x06]), Ok(()));
+    expected[0] = 0x05;
+    assert_eq!(recorder.received.borrow()[1], expected);
+}
+
+struct LinkRecorder {
+    transmitted: Cell<Option<ReturnCode>>,
+    received: RefCell<Vec<Vec<u8>>>,
+}
+
+impl IP6LinkTxClient for LinkRecorder {
+    fn transmit_done(&self, _buf: &'static mut [u8], result: ReturnCode) {
+        self.transmitted.set(Some(result));
+    }
+}
+
+impl IP6LinkRxClient for LinkRecorder {
+    fn packet_received(&self, packet: &[u8]) {
+        self.received.borrow_mut().push(packet.to_vec());
+    }
+}
+
+/// An IPv6 packet carrying `payload_len` bytes of UDP to `dst`.
+fn ip6_packet(dst: [u8; 16], payload_len: usize) -> Vec<u8> {
+    let mut packet = vec![0; 40 + payload_len];
+    packet[0] = 0x60;
+    packet[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+    packet[6] = 17; // UDP
+    packet[7] = 64;
+    packet[8] = 0xfe;
+    packet[9] = 0x80;
+    packet[24..40].copy_from_slice(&dst);
+    for (i, byte) in packet[40..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    packet
+}
+
+fn set_data_interface(usb: &Usb, alternate_setting: u16) -> Result<(), TransferError> {
+    usb.control_write(
+        Setup {
+            request_type: REQUEST_TYPE_INTERFACE_OUT,
+            request: 0x0b, // SET_INTERFACE
+            value: alternate_setting,
+            index: 1,
+            length: 0,
+        },
+        &[],
+    )
+}
+
+fn data_interface(usb: &Usb) -> Result<u8, TransferError> {
+    alternate_setting(usb, 1)
+}
+
+/// Returns the alternate setting selected for interface `interface`.
+fn alternate_setting(usb: &Usb, interface: u16) -> Result<u8, TransferError> {
+    let mut alternate_setting = [0xff; 1];
+    usb.control_read(
+        Setup {
+            request_type: REQUEST_TYPE_INTERFACE_IN,
+            request: 0x0a, // GET_INTERFACE
+            value: 0,
+            index: interface,
+            length: 1,
+        },
+        &mut alternate_setting,
+    )?;
+    Ok(alternate_setting[0])
+}
+
+#[test]
+fn cdc_ecm_carries_ipv6_frames() {
+    const MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+    const HOST_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x57];
+
+    let usb = software_controller();
+    let strings = leak(["XYZ Corp.", "The Zorpinator", "Serial No. 5", ""]);
+    strings[3] = cdc_ecm::format_mac_address(&HOST_MAC, leak([0; 12]));
+    let ecm = leak(CdcEcm::new(
+        usb,
+        64,
+        0x1915,
+        0x503a,
+        strings,
+        MAC,
+        HOST_MAC,
+        leak([0; cdc_ecm::MAX_FRAME_LEN]),
+    ));
+    let recorder = leak(LinkRecorder {
+        transmitted: Cell::new(None),
+        received: RefCell::new(Vec::new()),
+    });
+    ecm.set_transmit_client(recorder);
+    ecm.set_receive_client(recorder);
+    usb.set_client(ecm);
+    ecm.enable();
+    ecm.attach();
+
+    usb.reset();
+    enumerate(usb, 5);
+    let configuration = configuration(usb);
+    assert_eq!(
+        configuration.interfaces,
+        vec![
+            Interface {
+                number: 0,
+                alternate_setting: 0,
+                class: 0x02,
+                endpoints: vec![0x81],
+            },
+            Interface {
+                number: 1,
+                alternate_setting: 0,
+                class: 0x0a,
+                endpoints: vec![],
+            },
+            Interface {
+                number: 1,
+                alternate_setting: 1,
+                class: 0x0a,
+                endpoints: vec![0x82, 0x03],
+            },
+        ]
+    );
+    // The Ethernet networking functional descriptor names the string
+    // holding the host's MAC address.
+    let ethernet = descriptors(&configuration.bytes)
+        .find(|d| d[1] == 0x24 && d[2] == 0x0f)
+        .expect("Ethernet networking descriptor")
+        .to_vec();
+    assert_eq!(string(usb, ethernet[3]).as_deref(), Ok("020000123457"));
+
+    let packet = ip6_packet([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], 60);
+    let tx_buffer = leak_buf(packet.len());
+    tx_buffer.copy_from_slice(&packet);
+    let tx_buffer = match ecm.transmit(tx_buffer, packet.len()) {
+        Err((ReturnCode::EOFF, buf)) => buf,
+        _ => panic!("transmit succeeded before the link was up"),
+    };
+
+    assert_eq!(
+        usb.control_write(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+                request: 0x43, // SET_ETHERNET_PACKET_FILTER
+                value: 0x000e,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        ),
+        Ok(())
+    );
+    assert_eq!(data_interface(usb), Ok(0));
+    assert_eq!(set_data_interface(usb, 1), Ok(()));
+    assert_eq!(data_interface(usb), Ok(1));
+    assert_eq!(set_data_interface(usb, 2), Err(TransferError::Stall));
+
+    // The device reports the connection and then its speed.
+    let mut notification = [0; 16];
+    assert_eq!(usb.in_packet(1, &mut notification), Ok(8));
+    assert_eq!(notification[..8], [0xa1, 0x00, 1, 0, 0, 0, 0, 0]);
+    assert_eq!(usb.in_packet(1, &mut notification), Ok(16));
+    assert_eq!(notification[1], 0x2a);
+    assert_eq!(
+        u32::from_le_bytes([
+            notification[8],
+            notification[9],
+            notification[10],
+            notification[11]
+        ]),
+        12_000_000
+    );
+    assert_eq!(usb.in_packet(1, &mut notification), Err(TransferError::Nak));
+
+    // Multicast packets go to the matching Ethernet group address.
+    assert!(ecm.transmit(tx_buffer, packet.len()).is_ok());
+    let mut frame = Vec::new();
+    let mut usb_packet = [0; 64];
+    loop {
+        let len = usb.in_packet(2, &mut usb_packet).unwrap();
+        frame.extend_from_slice(&usb_packet[..len]);
+        if len < usb_packet.len() {
+            break;
+        }
+    }
+    assert_eq!(frame[..6], [0x33, 0x33, 0, 0, 0, 1]);
+    assert_eq!(frame[6..12], MAC);
+    assert_eq!(frame[12..14], [0x86, 0xdd]);
+    assert_eq!(frame[14..], packet[..]);
+    assert_eq!(recorder.transmitted.get(), Some(ReturnCode::SUCCESS));
+
+    // Received frames are passed on without the Ethernet header and the
+    // padding the host may add.
+    let packet = ip6_packet([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2], 8);
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&MAC);
+    frame.extend_from_slice(&HOST_MAC);
+    frame.extend_from_slice(&[0x86, 0xdd]);
+    frame.extend_from_slice(&packet);
+    frame.extend_from_slice(&[0; 12]);
+    for chunk in frame.chunks(64) {
+        assert_eq!(usb.out_packet(3, chunk), Ok(()));
+    }
+    assert_eq!(*recorder.received.borrow(), vec![packet.clone()]);
+
+    // Frames for another adapter are dropped.
+    frame[5] ^= 0xff;
+    for chunk in frame.chunks(64) {
+        assert_eq!(usb.out_packet(3, chunk), Ok(()));
+    }
+    assert_eq!(recorder.received.borrow().len(), 1);
+
+    // Selecting the first alternate setting takes the link down.
+    assert_eq!(set_data_interface(usb, 0), Ok(()));
+    let tx_buffer = leak_buf(packet.len());
+    assert!(matches!(
+        ecm.transmit(tx_buffer, packet.len()),
+        Err((ReturnCode::EOFF, _))
+    ));
+}
+
+/// The drive starts this far into the disk.
+const MSC_START: usize = 4 * msc::BLOCK_SIZE;
+const MSC_BLOCKS: usize = 8;
+
+fn mass_storage(usb: &'static Usb, read_only: bool) -> &'static RamDisk {
+    let disk = leak(RamDisk::new());
+    let storage = leak(MassStorage::new(
+        usb,
+        64,
+        0x1915,
+        0x503a,
+        STRINGS,
+        disk,
+        MSC_START,
+        MSC_BLOCKS * msc::BLOCK_SIZE,
+        read_only,
+        leak([0; msc::BLOCK_SIZE]),
+    ));
+    disk.set_client(storage);
+    usb.set_client(storage);
+    storage.enable();
+    storage.attach();
+    disk
+}
+
+/// Calls `transfer` until the device stops NAKing, completing storage
+/// operations in between.
+fn until_ready<T>(disk: &RamDisk, mut transfer: impl FnMut() -> Result<T, TransferError>) -> T {
+    loop {
+        match transfer() {
+            Err(TransferError::Nak) => assert!(disk.run(), "device NAKs but the disk is idle"),
+            result => return result.unwrap(),
+        }
+    }
+}
+
+/// Sends one packet to the bulk OUT endpoint. The device takes it even when
+/// it NAKs to hold back the next one, so it is only resent if the endpoint
+/// was paused.
+fn bulk_out(usb: &Usb, disk: &RamDisk, packet: &[u8]) {
+    while usb.out_paused(2) {
+        assert!(disk.run(), "OUT endpoint paused but the disk is idle");
+    }
+    match usb.out_packet(2, packet) {
+        Ok(()) | Err(TransferError::Nak) => {}
+        Err(error) => panic!("OUT packet failed: {:?}", error),
+    }
+}
+
+/// The outcome of a command of the Bulk-Only Transport.
+#[derive(Debug, PartialEq)]
+struct CommandStatus {
+    data: Vec<u8>,
+    residue: u32,
+    status: u8,
+}
+
+/// Runs the SCSI command `command`, with `data_length` bytes of data sent by
+/// the device, or `data_out` sent by the host if it is not empty.
+fn scsi(
+    usb: &Usb,
+    disk: &RamDisk,
+    command: &[u8],
+    data_length: usize,
+    data_out: &[u8],
+) -> CommandStatus {
+    let tag = 0x7a67_0000 | command[0] as u32;
+    let data_in = data_out.is_empty() && data_length > 0;
+    let mut cbw = [0; 31];
+    cbw[0..4].copy_from_slice(b"USBC");
+    cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+    cbw[8..12].copy_from_slice(&(data_length.max(data_out.len()) as u32).to_le_bytes());
+    cbw[12] = if data_in { 0x80 } else { 0x00 };
+    cbw[14] = command.len() as u8;
+    cbw[15..15 + command.len()].copy_from_slice(command);
+    bulk_out(usb, disk, &cbw);
+
+    for packet in data_out.chunks(64) {
+        bulk_out(usb, disk, packet);
+    }
+    let mut data = Vec::new();
+    let mut packet = [0; 64];
+    if data_in {
+        // The data ends with a short packet, or once the host has all it
+        // asked for.
+        loop {
+            let len = until_ready(disk, || usb.in_packet(1, &mut packet));
+            data.extend_from_slice(&packet[..len]);
+            if len < packet.len() || data.len() >= data_length {
+                break;
+            }
+        }
+    }
+
+    assert_eq!(until_ready(disk, || usb.in_packet(1, &mut packet)), 13);
+    assert_eq!(packet[0..4], *b"USBS");
+    assert_eq!(packet[4..8], tag.to_le_bytes());
+    CommandStatus {
+        data,
+        residue: u32::from_le_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        status: packet[12],
+    }
+}
+
+/// A READ(10) or WRITE(10) command block.
+fn rw10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut command = [0; 10];
+    command[0] = opcode;
+    command[2..6].copy_from_slice(&lba.to_be_bytes());
+    command[7..9].copy_from_slice(&blocks.to_be_bytes());
+    command
+}
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1a;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+
+/// Returns the sense key and additional sense code of the last failure.
+fn sense(usb: &Usb, disk: &RamDisk) -> (u8, u8) {
+    let result = scsi(usb, disk, &[SCSI_REQUEST_SENSE, 0, 0, 0, 18, 0], 18, &[]);
+    assert_eq!(result.status, 0);
+    assert_eq!(result.data.len(), 18);
+    (result.data[2], result.data[12])
+}
+
+#[test]
+fn msc_enumerates() {
+    let usb = software_controller();
+    let disk = mass_storage(usb, false);
+    enumerate(usb, 10);
+
+    let configuration = configuration(usb);
+    assert_eq!(
+        configuration.interfaces,
+        vec![Interface {
+            number: 0,
+            alternate_setting: 0,
+            class: 0x08,
+            endpoints: vec![0x81, 0x02],
+        }]
+    );
+    let interface = configuration.find(DescriptorType::Interface as u8).unwrap();
+    assert_eq!(
+        interface[6..8],
+        [0x06, 0x50],
+        "SCSI over Bulk-Only Transport"
+    );
+
+    // GET_MAX_LUN: there is only logical unit 0.
+    let mut max_lun = [0xff; 1];
+    assert_eq!(
+        usb.control_read(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_IN,
+                request: 0xfe,
+                value: 0,
+                index: 0,
+                length: 1,
+            },
+            &mut max_lun,
+        ),
+        Ok(1)
+    );
+    assert_eq!(max_lun, [0]);
+
+    let inquiry = scsi(usb, disk, &[SCSI_INQUIRY, 0, 0, 0, 36, 0], 36, &[]);
+    assert_eq!(inquiry.status, 0);
+    assert_eq!(inquiry.data.len(), 36);
+    assert_eq!(inquiry.data[0], 0x00, "direct access block device");
+    assert_eq!(&inquiry.data[8..16], b"Tock    ");
+
+    let capacity = scsi(
+        usb,
+        disk,
+        &[SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        8,
+        &[],
+    );
+    assert_eq!(capacity.status, 0);
+    assert_eq!(
+        capacity.data,
+        [
+            &(MSC_BLOCKS as u32 - 1).to_be_bytes()[..],
+            &(msc::BLOCK_SIZE as u32).to_be_bytes()[..]
+        ]
+        .concat()
+    );
+
+    // The response is cut to the allocation length, and the host learns how
+    // much it did not get from the residue.
+    let inquiry = scsi(usb, disk, &[SCSI_INQUIRY, 0, 0, 0, 36, 0], 64, &[]);
+    assert_eq!(inquiry.data.len(), 36);
+    assert_eq!(inquiry.residue, 28);
+
+    let ready = scsi(usb, disk, &[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], 0, &[]);
+    assert_eq!(ready.status, 0);
+    let unknown = scsi(usb, disk, &[0xc0, 0, 0, 0, 0, 0], 0, &[]);
+    assert_eq!(unknown.status, 1);
+    assert_eq!(sense(usb, disk), (0x05, 0x20), "invalid command");
+    assert_eq!(sense(usb, disk), (0x00, 0x00), "sense is cleared");
+
+    // BULK_ONLY_RESET
+    assert_eq!(
+        usb.control_write(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+                request: 0xff,
+                value: 0,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        scsi(usb, disk, &[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], 0, &[]).status,
+        0
+    );
+}
+
+#[test]
+fn msc_reads_and_writes_blocks() {
+    let usb = software_controller();
+    let disk = mass_storage(usb, false);
+    enumerate(usb, 11);
+
+    let image: Vec<u8> = (0..2 * msc::BLOCK_SIZE).map(|i| (i * 7) as u8).collect();
+    let write = scsi(usb, disk, &rw10(SCSI_WRITE_10, 1, 2), 0, &image);
+    assert_eq!(
+        write,
+        CommandStatus {
+            data: vec![],
+            residue: 0,
+            status: 0,
+        }
+    );
+    let mut stored = vec![0; image.len()];
+    disk.read_bytes(MSC_START + msc::BLOCK_SIZE, &mut stored);
+    assert_eq!(stored, image);
+    assert_eq!(disk.writes.get(), 2);
+
+    let read = scsi(
+        usb,
+        disk,
+        &rw10(SCSI_READ_10, 0, 3),
+        3 * msc::BLOCK_SIZE,
+        &[],
+    );
+    assert_eq!(read.status, 0);
+    assert_eq!(read.residue, 0);
+    assert_eq!(read.data[..msc::BLOCK_SIZE], [0; msc::BLOCK_SIZE][..]);
+    assert_eq!(read.data[msc::BLOCK_SIZE..], image[..]);
+
+    // The last block can be read, the one after it can't.
+    let last = MSC_BLOCKS as u32 - 1;
+    let read = scsi(
+        usb,
+        disk,
+        &rw10(SCSI_READ_10, last, 1),
+        msc::BLOCK_SIZE,
+        &[],
+    );
+    assert_eq!(read.status, 0);
+    let read = scsi(
+        usb,
+        disk,
+        &rw10(SCSI_READ_10, last, 2),
+        2 * msc::BLOCK_SIZE,
+        &[],
+    );
+    assert_eq!(read.status, 1);
+    assert_eq!(read.residue, 2 * msc::BLOCK_SIZE as u32);
+    assert_eq!(sense(usb, disk), (0x05, 0x21), "LBA out of range");
+}
+
+#[test]
+fn msc_read_only_drive_rejects_writes() {
+    let usb = software_controller();
+    let disk = mass_storage(usb, true);
+    enumerate(usb, 12);
+
+    let mode = scsi(usb, disk, &[SCSI_MODE_SENSE_6, 0, 0x3f, 0, 4, 0], 4, &[]);
+    assert_eq!(mode.data[2] & 0x80, 0x80, "write protected");
+
+    // The device takes and discards the data the host sends anyway.
+    let block = [0x55; msc::BLOCK_SIZE];
+    let write = scsi(usb, disk, &rw10(SCSI_WRITE_10, 0, 1), 0, &block);
+    assert_eq!(write.status, 1);
+    assert_eq!(write.residue, 0);
+    assert_eq!(sense(usb, disk), (0x07, 0x27), "write protected");
+    assert_eq!(disk.writes.get(), 0);
+}
+
+#[test]
+fn composite_numbers_interfaces_and_endpoints() {
+    let usb = software_controller();
+    let composite = leak(CompositeDevice::new(
+        usb,
+        64,
+        0x1915,
+        0x503a,
+        STRINGS,
+        leak([0; 256]),
+    ));
+    usb.set_client(composite);
+
+    let cdc_function = leak(UsbFunction::new(composite));
+    cdc_function.setup();
+    let cdc = leak(CdcAcm::new(
+        cdc_function,
+        64,
+        0x2341,
+        0x005d,
+        leak(["ACM Corp.", "Serial port", "1"]),
+        leak(SimAlarm::new()),
+        leak(DynamicDeferredCall::new(leak([
+            DynamicDeferredCallClientState::default(),
+        ]))),
+        None,
+    ));
+    let uart_recorder = leak(UartRecorder {
+        transmitted: Cell::new(None),
+        received: RefCell::new(None),
+    });
+    cdc.set_transmit_client(uart_recorder);
+    cdc.set_receive_client(uart_recorder);
+    cdc_function.set_client(cdc);
+
+    let hid_function = leak(UsbFunction::new(composite));
+    hid_function.setup();
+    let (hid, hid_recorder) = hid_device(
+        hid_function,
+        hid::KEYBOARD_REPORT_DESCRIPTOR,
+        BootProtocol::Keyboard,
+        8,
+        1,
+    );
+
+    composite.enable();
+    composite.attach();
+    usb.reset();
+    enumerate(usb, 13);
+
+    // The device takes the strings of the composite device, and says that the
+    // functions are described by Interface Association Descriptors.
+    let device = device_descriptor(usb);
+    assert_eq!(device[4..7], [0xef, 0x02, 0x01]);
+    assert_eq!(u16::from_le_bytes([device[8], device[9]]), 0x1915);
+    assert_eq!(string(usb, 2).as_deref(), Ok(STRINGS[1]));
+
+    // Interfaces are numbered in the order the functions were added.
+    // Endpoints are assigned as the functions enable them, CDC-ACM's 2 and 3
+    // becoming 1 and 2 and HID's 1 becoming 3, and the interrupt endpoint 4
+    // of CDC-ACM, which it never enables, gets 4 when the configuration is
+    // built.
+    let configuration = configuration(usb);
+    let interfaces = &configuration.interfaces;
+    assert_eq!(
+        interfaces
+            .iter()
+            .map(|i| (i.number, i.class))
+            .collect::<Vec<_>>(),
+        vec![(0, 0x02), (1, 0x0a), (2, 0x03)]
+    );
+    assert_eq!(
+        interfaces
+            .iter()
+            .map(|i| i.endpoints.clone())
+            .collect::<Vec<_>>(),
+        vec![vec![0x84], vec![0x81, 0x02], vec![0x83, 0x03]]
+    );
+    let (cdc_in, cdc_out, hid_endpoint) = (1, 2, 3);
+
+    // Only CDC-ACM, with two interfaces, needs an association, and its union
+    // descriptor names the renumbered interfaces.
+    let associations: Vec<&[u8]> = descriptors(&configuration.bytes)
+        .filter(|d| d[1] == 0x0b)
+        .collect();
+    assert_eq!(
+        associations,
+        vec![&[8, 0x0b, 0, 2, 0x02, 0x02, 0x01, 0][..]]
+    );
+    let union = descriptors(&configuration.bytes)
+        .find(|d| d[1] == 0x24 && d[2] == 0x06)
+        .expect("CDC union descriptor");
+    assert_eq!(union[3..], [0, 1]);
+    assert_eq!(
+        descriptors(&configuration.bytes)
+            .filter(|d| d[1] == DescriptorType::Interface as u8)
+            .map(|d| d[8])
+            .collect::<Vec<_>>(),
+        vec![0, 0, 0],
+        "no interface strings"
+    );
+
+    // Requests to interface 2 reach the HID function as interface 0.
+    let mut report = [0; 128];
+    assert_eq!(
+        interface_descriptor(usb, DescriptorType::Report, 2, &mut report),
+        Ok(hid::KEYBOARD_REPORT_DESCRIPTOR.len())
+    );
+    assert_eq!(hid_get(usb, HID_GET_PROTOCOL, 2), Ok(1));
+    assert_eq!(hid_set(usb, HID_SET_PROTOCOL, 0, 2), Ok(()));
+    assert_eq!(hid_get(usb, HID_GET_PROTOCOL, 2), Ok(0));
+    assert_eq!(hid_get(usb, HID_GET_PROTOCOL, 3), Err(TransferError::Stall));
+
+    // Packets go through the endpoints of the controller the functions were
+    // given.
+    assert_eq!(set_line_coding(usb, 115200), Ok(()));
+    assert_eq!(
+        usb.control_write(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+                request: 0x22, // SET_CONTROL_LINE_STATE
+                value: 0x03,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        ),
+        Ok(())
+    );
+    let tx_buffer = leak_buf(5);
+    tx_buffer.copy_from_slice(b"hello");
+    assert_eq!(cdc.transmit_buffer(tx_buffer, 5).0, ReturnCode::SUCCESS);
+    let mut packet = [0; 64];
+    assert_eq!(usb.in_packet(cdc_in, &mut packet), Ok(5));
+    assert_eq!(&packet[..5], b"hello");
+    assert_eq!(
+        uart_recorder.transmitted.get(),
+        Some((5, ReturnCode::SUCCESS))
+    );
+
+    let (result, _) = cdc.receive_buffer(leak_buf(3), 3);
+    assert_eq!(result, ReturnCode::SUCCESS);
+    assert_eq!(usb.out_packet(cdc_out, b"abc"), Ok(()));
+    assert_eq!(
+        *uart_recorder.received.borrow(),
+        Some((b"abc".to_vec(), ReturnCode::SUCCESS))
+    );
+
+    let input = leak([0; 64]);
+    input[2] = 0x04;
+    assert_eq!(hid.send_buffer(input).ok(), Some(8));
+    assert_eq!(usb.in_packet(cdc_in, &mut packet), Err(TransferError::Nak));
+    assert_eq!(usb.in_packet(hid_endpoint, &mut packet), Ok(8));
+    assert_eq!(packet[..8], [0, 0, 0x04, 0, 0, 0, 0, 0]);
+    assert!(hid.receive_buffer(leak([0; 64])).is_ok());
+    assert_eq!(usb.out_packet(hid_endpoint, &[0x01]), Ok(()));
+    assert_eq!(hid_recorder.received.borrow()[0][0], 0x01);
+}
+
+const PAGE: usize = 512;
+
+static DFU_STRINGS: &[&str] = &[
+    "XYZ Corp.",
+    "The Zorpinator",
+    "Serial No. 5",
+    "kernel",
+    "app",
+];
+
+static DFU_TARGETS: &[Target] = &[
+    Target {
+        kind: TargetKind::Kernel,
+        start: 2 * PAGE,
+        len: 4 * PAGE,
+    },
+    Target {
+        kind: TargetKind::App,
+        start: 8 * PAGE,
+        len: 4 * PAGE,
+    },
+];
+
+// DFU class requests and states.
+const DFU_DETACH: u8 = 0x00;
+const DFU_DNLOAD: u8 = 0x01;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_CLRSTATUS: u8 = 0x04;
+const DFU_GETSTATE: u8 = 0x05;
+const DFU_ABORT: u8 = 0x06;
+
+const APP_IDLE: u8 = 0;
+const APP_DETACH: u8 = 1;
+const DFU_IDLE: u8 = 2;
+const DNLOAD_IDLE: u8 = 5;
+const DFU_ERROR: u8 = 10;
+
+struct DownloadRecorder {
+    completed: RefCell<Vec<(usize, usize)>>,
+}
+
+impl dfu::Client for DownloadRecorder {
+    fn download_complete(&self, target: usize, len: usize) {
+        self.completed.borrow_mut().push((target, len));
+    }
+}
+
+fn dfu_device(
+    usb: &'static Usb,
+    runtime: bool,
+) -> (&'static MockFlash<PAGE>, &'static DownloadRecorder) {
+    let flash = leak(MockFlash::new(16));
+    let dfu = leak(Dfu::new(
+        usb,
+        64,
+        0x1915,
+        0x521f,
+        DFU_STRINGS,
+        flash,
+        leak(BlockPage::default()),
+        DFU_TARGETS,
+        runtime,
+    ));
+    let recorder = leak(DownloadRecorder {
+        completed: RefCell::new(Vec::new()),
+    });
+    flash.set_client(dfu);
+    dfu.set_client(recorder);
+    usb.set_client(dfu);
+    dfu.enable();
+    dfu.attach();
+    (flash, recorder)
+}
+
+fn dfu_request(usb: &Usb, request: u8, value: u16, data: &[u8]) -> Result<(), TransferError> {
+    usb.control_write(
+        Setup {
+            request_type: REQUEST_TYPE_CLASS_INTERFACE_OUT,
+            request: request,
+            value: value,
+            index: 0,
+            length: data.len() as u16,
+        },
+        data,
+    )
+}
+
+fn dfu_state(usb: &Usb) -> Result<u8, TransferError> {
+    let mut state = [0xff; 1];
+    usb.control_read(
+        Setup {
+            request_type: REQUEST_TYPE_CLASS_INTERFACE_IN,
+            request: DFU_GETSTATE,
+            value: 0,
+            index: 0,
+            length: 1,
+        },
+        &mut state,
+    )?;
+    Ok(state[0])
+}
+
+/// Sends DFU_GETSTATUS and returns the status and state, completing any
+/// flash operation the device asks the host to wait for.
+fn dfu_status(usb: &Usb, flash: &MockFlash<PAGE>) -> (u8, u8) {
+    let mut status = [0; 6];
+    assert_eq!(
+        usb.control_read(
+            Setup {
+                request_type: REQUEST_TYPE_CLASS_INTERFACE_IN,
+                request: DFU_GETSTATUS,
+                value: 0,
+                index: 0,
+                length: 6,
+            },
+            &mut status,
+        ),
+        Ok(6)
+    );
+    let poll_timeout = u32::from_le_bytes([status[1], status[2], status[3], 0]);
+    if poll_timeout > 0 {
+        while flash.run() {}
+    }
+    (status[0], status[4])
+}
+
+/// Downloads `image` the way dfu-util does, polling the status after every
+/// block, and returns the final status and state.
+fn dfu_download(usb: &Usb, flash: &MockFlash<PAGE>, image: &[u8]) -> (u8, u8) {
+    for (block, data) in image.chunks(64).enumerate() {
+        if dfu_request(usb, DFU_DNLOAD, block as u16, data).is_err() {
+            return dfu_status(usb, flash);
+        }
+        loop {
+            match dfu_status(usb, flash) {
+                (0, DNLOAD_IDLE) => break,
+                (0, _) => {}
+                error => return error,
+            }
+        }
+    }
+    // A zero-length block ends the download.
+    let block = (image.len() + 63) / 64;
+    if dfu_request(usb, DFU_DNLOAD, block as u16, &[]).is_err() {
+        return dfu_status(usb, flash);
+    }
+    loop {
+        match dfu_status(usb, flash) {
+            (0, DFU_IDLE) => return (0, DFU_IDLE),
+            (0, _) => {}
+            error => return error,
+        }
+    }
+}
+
+/// A 16-byte TBF header with no TLVs, for an app of `total_len` bytes.
+fn tbf_header(total_len: u32) -> [u8; 16] {
+    let version_and_len = 2 | 16 << 16;
+    let flags = 1;
+    let checksum = version_and_len ^ total_len ^ flags;
+    let mut header = [0; 16];
+    header[0..4].copy_from_slice(&version_and_len.to_le_bytes());
+    header[4..8].copy_from_slice(&total_len.to_le_bytes());
+    header[8..12].copy_from_slice(&flags.to_le_bytes());
+    header[12..16].copy_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+fn set_target(usb: &Usb, target: u16) -> Result<(), TransferError> {
+    usb.control_write(
+        Setup {
+            request_type: REQUEST_TYPE_INTERFACE_OUT,
+            request: 0x0b, // SET_INTERFACE
+            value: target,
+            index: 0,
+            length: 0,
+        },
+        &[],
+    )
+}
+
+#[test]
+fn dfu_enumerates_targets() {
+    let usb = software_controller();
+    dfu_device(usb, false);
+    enumerate(usb, 14);
+
+    // Each target is an alternate setting of the one DFU interface, named by
+    // its interface string.
+    let configuration = configuration(usb);
+    assert_eq!(
+        configuration.interfaces,
+        vec![
+            Interface {
+                number: 0,
+                alternate_setting: 0,
+                class: 0xfe,
+                endpoints: vec![],
+            },
+            Interface {
+                number: 0,
+                alternate_setting: 1,
+                class: 0xfe,
+                endpoints: vec![],
+            },
+        ]
+    );
+    let interfaces: Vec<&[u8]> = descriptors(&configuration.bytes)
+        .filter(|d| d[1] == DescriptorType::Interface as u8)
+        .collect();
+    for (interface, name) in interfaces.iter().zip(&["kernel", "app"]) {
+        assert_eq!(interface[6..8], [0x01, 0x02], "DFU mode");
+        assert_eq!(string(usb, interface[8]).as_deref(), Ok(*name));
+    }
+    // Download capable and manifestation tolerant, detach timeout of 1 s,
+    // 64-byte blocks, DFU 1.1.
+    assert_eq!(
+        configuration.find(0x21),
+        Some(&[9, 0x21, 0x05, 0xe8, 0x03, 64, 0, 0x10, 0x01][..])
+    );
+
+    assert_eq!(dfu_state(usb), Ok(DFU_IDLE));
+    assert_eq!(alternate_setting(usb, 0), Ok(0));
+    assert_eq!(set_target(usb, 1), Ok(()));
+    assert_eq!(alternate_setting(usb, 0), Ok(1));
+    assert_eq!(set_target(usb, 2), Err(TransferError::Stall));
+    assert_eq!(alternate_setting(usb, 0), Ok(1));
+}
+
+#[test]
+fn dfu_downloads_kernel_image() {
+    let usb = software_controller();
+    let (flash, recorder) = dfu_device(usb, false);
+    enumerate(usb, 15);
+
+    let image: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(dfu_download(usb, flash, &image), (0, DFU_IDLE));
+    assert_eq!(*recorder.completed.borrow(), vec![(0, image.len())]);
+
+    // The last page is padded with 0xff, and nothing outside the target is
+    // touched.
+    let pages = flash.pages.borrow();
+    assert_eq!(pages[2][..], image[..PAGE]);
+    assert_eq!(pages[3][..image.len() - PAGE], image[PAGE..]);
+    assert!(pages[3][image.len() - PAGE..].iter().all(|b| *b == 0xff));
+    let erases = flash.erases.borrow();
+    assert_eq!(erases[..6], [0, 0, 1, 1, 0, 0]);
+}
+
+#[test]
+fn dfu_rejects_bad_downloads() {
+    let usb = software_controller();
+    let (flash, recorder) = dfu_device(usb, false);
+    enumerate(usb, 16);
+
+    // Blocks past the end of the target.
+    let image = vec![0; 4 * PAGE + 64];
+    assert_eq!(dfu_download(usb, flash, &image), (0x08, DFU_ERROR));
+    // Further requests fail until the host clears the status.
+    assert_eq!(
+        dfu_request(usb, DFU_DNLOAD, 0, &[0; 64]),
+        Err(TransferError::Stall)
+    );
+    assert_eq!(dfu_request(usb, DFU_CLRSTATUS, 0, &[]), Ok(()));
+    assert_eq!(dfu_state(usb), Ok(DFU_IDLE));
+
+    // An app slot only takes a valid TBF header.
+    assert_eq!(set_target(usb, 1), Ok(()));
+    let mut app = vec![0x5a; 600];
+    app[..16].copy_from_slice(&tbf_header(600));
+    app[9] ^= 0x01;
+    assert_eq!(dfu_download(usb, flash, &app), (0x02, DFU_ERROR));
+    assert_eq!(flash.erases.borrow()[8], 0);
+    assert_eq!(dfu_request(usb, DFU_CLRSTATUS, 0, &[]), Ok(()));
+
+    // Nor does it complete before the whole app arrived.
+    app[..16].copy_from_slice(&tbf_header(1024));
+    assert_eq!(dfu_download(usb, flash, &app), (0x09, DFU_ERROR));
+    assert_eq!(dfu_request(usb, DFU_CLRSTATUS, 0, &[]), Ok(()));
+
+    // Downloads can be aborted.
+    assert_eq!(dfu_request(usb, DFU_DNLOAD, 0, &app[..64]), Ok(()));
+    assert_eq!(dfu_status(usb, flash), (0, DNLOAD_IDLE));
+    assert_eq!(dfu_request(usb, DFU_ABORT, 0, &[]), Ok(()));
+    assert_eq!(dfu_state(usb), Ok(DFU_IDLE));
+
+    app[..16].copy_from_slice(&tbf_header(600));
+    assert_eq!(dfu_download(usb, flash, &app), (0, DFU_IDLE));
+    assert_eq!(*recorder.completed.borrow(), vec![(1, 600)]);
+    assert_eq!(flash.pages.borrow()[8][..PAGE], app[..PAGE]);
+}
+
+#[test]
+fn dfu_aborted_app_download_is_not_loadable() {
+    let usb = software_controller();
+    let (flash, recorder) = dfu_device(usb, false);
+    enumerate(usb, 18);
+    assert_eq!(set_target(usb, 1), Ok(()));
+
+    // The first page of the app is written, then the host gives up.
+    let mut app: Vec<u8> = (0..3 * PAGE).map(|i| (i % 251) as u8).collect();
+    app[..16].copy_from_slice(&tbf_header(3 * PAGE as u32));
+    for (block, data) in app[..PAGE + 64].chunks(64).enumerate() {
+        assert_eq!(dfu_request(usb, DFU_DNLOAD, block as u16, data), Ok(()));
+        while dfu_status(usb, flash) != (0, DNLOAD_IDLE) {}
+    }
+    assert_eq!(dfu_request(usb, DFU_ABORT, 0, &[]), Ok(()));
+    assert_eq!(dfu_state(usb), Ok(DFU_IDLE));
+
+    // The app is in flash, but its header does not verify.
+    {
+        let pages = flash.pages.borrow();
+        assert_eq!(pages[8][..12], app[..12]);
+        assert_ne!(pages[8][12..16], app[12..16]);
+        assert_eq!(pages[8][16..], app[16..PAGE]);
+    }
+    assert!(recorder.completed.borrow().is_empty());
+
+    // A complete download restores the header.
+    assert_eq!(dfu_download(usb, flash, &app), (0, DFU_IDLE));
+    assert_eq!(*recorder.completed.borrow(), vec![(1, app.len())]);
+    let pages = flash.pages.borrow();
+    assert_eq!(pages[8][..], app[..PAGE]);
+    assert_eq!(pages[10][..], app[2 * PAGE..]);
+}
+
+#[test]
+fn dfu_runtime_mode_detaches() {
+    let usb = software_controller();
+    dfu_device(usb, true);
+    enumerate(usb, 17);
+
+    let protocol = |usb: &Usb| {
+        configuration(usb)
+            .find(DescriptorType::Interface as u8)
+            .unwrap()[7]
+    };
+    assert_eq!(protocol(usb), 0x01, "runtime mode");
+    assert_eq!(dfu_state(usb), Ok(APP_IDLE));
+    // Downloads need DFU mode.
+    assert_eq!(
+        dfu_request(usb, DFU_DNLOAD, 0, &[0; 64]),
+        Err(TransferError::Stall)
+    );
+    assert_eq!(dfu_state(usb), Ok(APP_IDLE));
+
+    assert_eq!(dfu_request(usb, DFU_DETACH, 1000, &[]), Ok(()));
+    assert_eq!(dfu_state(usb), Ok(APP_DETACH));
+    usb.reset();
+    enumerate(usb, 17);
+    assert_eq!(protocol(usb), 0x02, "DFU mode");
+    assert_eq!(dfu_state(usb), Ok(DFU_IDLE));
+}
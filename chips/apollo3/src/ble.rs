@@ -414,7 +414,7 @@ impl<'a> Ble<'a> {
                         i = i + 4;
                     }
 
-                    client.receive_event(&mut PAYLOAD, 10, kernel::ReturnCode::SUCCESS);
+                    client.receive_event(&mut PAYLOAD, 10, None, kernel::ReturnCode::SUCCESS);
                 }
             });
         }
@@ -34,6 +34,7 @@
 //! * CRC - 3 bytes
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
@@ -529,12 +530,39 @@ register_bitfields! [u32,
 static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
+// Replies are built while the radio ramps up for transmission, so they need
+// a buffer of their own.
+static mut TX_PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
+    [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+
+/// Step of an exchange of `BleConnectionDriver`. Each step ends with the
+/// DISABLED event, and the shortcuts already started the next one by the
+/// time it is handled.
+#[derive(Copy, Clone, PartialEq)]
+enum Exchange {
+    Idle,
+    /// Transmitting an advertisement, then listening.
+    Advertising,
+    /// Listening, then replying.
+    Listening,
+    /// Transmitting the reply.
+    Replying,
+    /// Scanning, then replying.
+    Scanning,
+    /// Transmitting the reply to an advertisement, then listening.
+    ScanReplying,
+    /// Listening for the response to the reply.
+    AwaitingResponse,
+}
+
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
     buffer: TakeCell<'static, [u8]>,
+    connection_client: OptionalCell<&'a dyn ble_advertising::ConnectionClient>,
+    exchange: Cell<Exchange>,
 }
 
 impl<'a> Radio<'a> {
@@ -545,6 +573,8 @@ impl<'a> Radio<'a> {
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            connection_client: OptionalCell::empty(),
+            exchange: Cell::new(Exchange::Idle),
         }
     }
 
@@ -553,11 +583,16 @@ impl<'a> Radio<'a> {
     }
 
     fn tx(&self) {
+        self.registers.shorts.set(0);
         self.registers.event_ready.write(Event::READY::CLEAR);
         self.registers.task_txen.write(Task::ENABLE::SET);
     }
 
     fn rx(&self) {
+        self.registers
+            .shorts
+            .write(Shortcut::ADDRESS_RSSISTART::SET + Shortcut::DISABLED_RSSISTOP::SET);
+        self.registers.event_rssiend.write(Event::READY::CLEAR);
         self.registers.event_ready.write(Event::READY::CLEAR);
         self.registers.task_rxen.write(Task::ENABLE::SET);
     }
@@ -598,6 +633,11 @@ impl<'a> Radio<'a> {
     pub fn handle_interrupt(&self) {
         self.disable_all_interrupts();
 
+        if self.exchange.get() != Exchange::Idle {
+            self.handle_exchange_interrupt();
+            return;
+        }
+
         if self.registers.event_ready.is_set(Event::READY) {
             self.registers.event_ready.write(Event::READY::CLEAR);
             self.registers.event_end.write(Event::READY::CLEAR);
@@ -634,13 +674,14 @@ impl<'a> Radio<'a> {
                 | nrf5x::constants::RADIO_STATE_RXIDLE
                 | nrf5x::constants::RADIO_STATE_RXDISABLE
                 | nrf5x::constants::RADIO_STATE_RX => {
+                    let rssi = self.rssi();
                     self.radio_off();
                     unsafe {
                         self.rx_client.map(|client| {
                             // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
                             // And because the length field is directly read from the packet
                             // We need to add 2 to length to get the total length
-                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, rssi, result)
                         });
                     }
                 }
@@ -651,6 +692,135 @@ impl<'a> Radio<'a> {
         self.enable_interrupts();
     }
 
+    fn handle_exchange_interrupt(&self) {
+        if !self.registers.event_disabled.is_set(Event::READY) {
+            self.registers.intenset.write(Interrupt::DISABLED::SET);
+            return;
+        }
+        self.registers.event_disabled.write(Event::READY::CLEAR);
+
+        match self.exchange.get() {
+            Exchange::Advertising => {
+                // Now ramping up to listen for a request, and to reply to it
+                // once received.
+                self.prepare_listening(Shortcut::DISABLED_TXEN::SET.value);
+                self.exchange.set(Exchange::Listening);
+            }
+            Exchange::Listening | Exchange::Scanning => {
+                // Now ramping up to reply, which starts T_IFS after the end
+                // of the received packet.
+                let (len, result) = self.deliver_packet();
+                if len == 0 {
+                    self.end_exchange(result);
+                    return;
+                }
+                self.set_tx_dma_ptr();
+                if self.exchange.get() == Exchange::Scanning {
+                    self.registers.shorts.write(
+                        Shortcut::READY_START::SET
+                            + Shortcut::END_DISABLE::SET
+                            + Shortcut::DISABLED_RXEN::SET,
+                    );
+                    self.exchange.set(Exchange::ScanReplying);
+                } else {
+                    self.registers
+                        .shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                    self.exchange.set(Exchange::Replying);
+                }
+            }
+            Exchange::ScanReplying => {
+                // Now ramping up to listen for the response.
+                self.prepare_listening(0);
+                self.exchange.set(Exchange::AwaitingResponse);
+            }
+            Exchange::AwaitingResponse => {
+                let (_, result) = self.deliver_packet();
+                self.end_exchange(result);
+                return;
+            }
+            Exchange::Replying => {
+                self.end_exchange(ReturnCode::SUCCESS);
+                return;
+            }
+            Exchange::Idle => {}
+        }
+        self.registers.intenset.write(Interrupt::DISABLED::SET);
+    }
+
+    /// Points the radio at the receive buffer and sets the shortcuts for a
+    /// reception, followed by those in `next`.
+    fn prepare_listening(&self, next: u32) {
+        self.registers.event_address.write(Event::READY::CLEAR);
+        self.registers.event_rssiend.write(Event::READY::CLEAR);
+        self.set_dma_ptr();
+        let shorts = Shortcut::READY_START::SET
+            + Shortcut::END_DISABLE::SET
+            + Shortcut::ADDRESS_RSSISTART::SET
+            + Shortcut::DISABLED_RSSISTOP::SET;
+        self.registers.shorts.set(shorts.value | next);
+    }
+
+    /// Passes the received packet to the client. Returns the length of the
+    /// reply the client wrote to `TX_PAYLOAD`, and the result.
+    fn deliver_packet(&self) -> (usize, ReturnCode) {
+        let result = if self.registers.crcstatus.is_set(Event::READY) {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        };
+        let rssi = self.rssi();
+        let len = unsafe {
+            let len = cmp::min(PAYLOAD[1] as usize + 2, PAYLOAD.len());
+            self.connection_client.map_or(0, |client| {
+                client.packet_received(&PAYLOAD[..len], rssi, result, &mut TX_PAYLOAD)
+            })
+        };
+        (len, result)
+    }
+
+    /// Signal strength of the last packet received, in dBm.
+    fn rssi(&self) -> Option<i8> {
+        if self.registers.event_rssiend.is_set(Event::READY) {
+            self.registers.event_rssiend.write(Event::READY::CLEAR);
+            Some(-(self.registers.rssisample.read(RssiSample::RSSISAMPLE) as i8))
+        } else {
+            None
+        }
+    }
+
+    fn end_exchange(&self, result: ReturnCode) {
+        self.registers.shorts.set(0);
+        self.registers.task_disable.write(Task::ENABLE::SET);
+        self.radio_off();
+        self.exchange.set(Exchange::Idle);
+        self.connection_client
+            .map(|client| client.exchange_done(result));
+    }
+
+    fn set_tx_dma_ptr(&self) {
+        unsafe {
+            self.registers.packetptr.set(TX_PAYLOAD.as_ptr() as u32);
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
+    fn ble_set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
+        self.registers.crcinit.set(crc_init);
+    }
+
+    fn start_exchange(&self, exchange: Exchange) {
+        self.exchange.set(exchange);
+        self.registers
+            .tifs
+            .write(InterFrameSpacing::TIFS.val(ble_advertising::T_IFS_US));
+        self.registers.event_disabled.write(Event::READY::CLEAR);
+        self.registers.event_address.write(Event::READY::CLEAR);
+        self.registers.intenset.write(Interrupt::DISABLED::SET);
+    }
+
     pub fn enable_interrupts(&self) {
         self.registers.intenset.write(
             Interrupt::READY::SET
@@ -810,6 +980,75 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 }
 
+impl<'a> ble_advertising::BleConnectionDriver<'a> for Radio<'a> {
+    fn advertise(&self, channel: RadioChannel, pdu: &[u8]) -> ReturnCode {
+        if self.exchange.get() != Exchange::Idle {
+            return ReturnCode::EBUSY;
+        }
+        unsafe {
+            if pdu.len() > TX_PAYLOAD.len() {
+                return ReturnCode::ESIZE;
+            }
+            TX_PAYLOAD[..pdu.len()].copy_from_slice(pdu);
+        }
+        self.ble_initialize(channel);
+        self.set_tx_dma_ptr();
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_RXEN::SET,
+        );
+        self.start_exchange(Exchange::Advertising);
+        self.registers.task_txen.write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn listen(&self, channel: RadioChannel, access_address: u32, crc_init: u32) -> ReturnCode {
+        if self.exchange.get() != Exchange::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.ble_initialize(channel);
+        self.ble_set_access_address(access_address, crc_init);
+        self.prepare_listening(Shortcut::DISABLED_TXEN::SET.value);
+        self.start_exchange(Exchange::Listening);
+        self.registers.task_rxen.write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn scan(&self, channel: RadioChannel) -> ReturnCode {
+        if self.exchange.get() != Exchange::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.ble_initialize(channel);
+        self.prepare_listening(Shortcut::DISABLED_TXEN::SET.value);
+        self.start_exchange(Exchange::Scanning);
+        self.registers.task_rxen.write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        match self.exchange.get() {
+            Exchange::Idle => ReturnCode::EALREADY,
+            Exchange::Listening | Exchange::Scanning | Exchange::AwaitingResponse
+                if self.registers.event_address.is_set(Event::READY) =>
+            {
+                ReturnCode::EBUSY
+            }
+            Exchange::Replying | Exchange::ScanReplying => ReturnCode::EBUSY,
+            _ => {
+                self.disable_all_interrupts();
+                self.registers.shorts.set(0);
+                self.registers.task_disable.write(Task::ENABLE::SET);
+                self.radio_off();
+                self.exchange.set(Exchange::Idle);
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    fn set_connection_client(&self, client: &'a dyn ble_advertising::ConnectionClient) {
+        self.connection_client.set(client);
+    }
+}
+
 impl ble_advertising::BleConfig for Radio<'_> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
@@ -1016,6 +1016,9 @@ impl<'p> Radio<'p> {
 
 impl<'p> kernel::hil::radio::Radio for Radio<'p> {}
 
+// The radio doesn't filter received frames by address.
+impl<'p> kernel::hil::radio::PromiscuousRadio for Radio<'p> {}
+
 impl<'p> kernel::hil::radio::RadioConfig for Radio<'p> {
     fn initialize(
         &self,
@@ -151,6 +151,36 @@ impl AppId {
             (start, end)
         })
     }
+
+    /// Returns the full address of the start and the size of writeable flash
+    /// region `index` from the app's TBF header, or `None` if the app has no
+    /// such region or no longer exists.
+    pub fn get_writeable_flash_region(&self, index: usize) -> Option<(usize, usize)> {
+        self.kernel.process_map_or(None, *self, |process| {
+            if index >= process.number_writeable_flash_regions() {
+                return None;
+            }
+            let (offset, size) = process.get_writeable_flash_region(index);
+            if size == 0 {
+                None
+            } else {
+                Some((
+                    process.flash_start() as usize + offset as usize,
+                    size as usize,
+                ))
+            }
+        })
+    }
+
+    /// Returns the package name of the app from its TBF header, or an empty
+    /// string if the app no longer exists or has no package name.
+    ///
+    /// Unlike `id()`, the name stays the same across restarts and reboots,
+    /// so it can be used to associate persistent state with an app.
+    pub fn get_process_name(&self) -> &'static str {
+        self.kernel
+            .process_map_or("", *self, |process| process.get_process_name())
+    }
 }
 
 /// Type to uniquely identify a callback subscription across all drivers.
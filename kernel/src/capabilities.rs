@@ -90,3 +90,9 @@ pub unsafe trait CreatePortTableCapability {}
 /// of the networking stack. A capsule would never hold this capability although
 /// it may hold capabilities created via this capability.
 pub unsafe trait NetworkCapabilityCreationCapability {}
+
+/// The `KVStorePrivilegedCapability` allows the holder to access any key-value
+/// store namespace and to mark stored values as only modifiable by other
+/// holders of this capability. Capsules acting on behalf of applications
+/// should not hold this capability.
+pub unsafe trait KVStorePrivilegedCapability {}
@@ -214,8 +214,9 @@ impl DynamicDeferredCall {
     /// Call all registered and to-be-scheduled deferred calls
     ///
     /// It may be called without holding the `DynamicDeferredCall` reference through
-    /// `call_global_instance`.
-    pub(self) fn call(&self) {
+    /// `call_global_instance`. The kernel only calls the global instance, but code that owns
+    /// another instance, such as a host test, can run its calls with this.
+    pub fn call(&self) {
         self.call_while(|| true)
     }
 
@@ -60,8 +60,74 @@ pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> ReturnCode;
 }
 
+/// Radio operations that need an answer within the inter frame space.
+///
+/// Connectable advertising and connections require a device to reply to a
+/// received packet T_IFS (150 µs) after it ends, which is too short to go
+/// through the kernel's callback machinery. These operations are therefore
+/// exchanges: when a packet arrives, the radio asks its `ConnectionClient`
+/// for the reply synchronously and transmits it T_IFS later.
+///
+/// Listening never times out on its own. The client bounds the receive
+/// window with its own alarm and calls `stop` when it closes.
+pub trait BleConnectionDriver<'a> {
+    /// Transmits the advertising channel PDU `pdu` (header and payload) on
+    /// `channel` and then listens on the same channel for a request, such as
+    /// SCAN_REQ or CONNECT_IND, until a packet is received or `stop` is
+    /// called.
+    fn advertise(&self, channel: RadioChannel, pdu: &[u8]) -> ReturnCode;
+
+    /// Listens on `channel` for a packet with the given access address and
+    /// CRC initialization value until a packet is received or `stop` is
+    /// called.
+    fn listen(&self, channel: RadioChannel, access_address: u32, crc_init: u32) -> ReturnCode;
+
+    /// Listens on the advertising channel `channel` like `listen`. If the
+    /// client replies to a packet, the radio listens again after
+    /// transmitting the reply, and passes the response, such as a SCAN_RSP,
+    /// to `packet_received` as well. Its reply to the response is ignored.
+    fn scan(&self, channel: RadioChannel) -> ReturnCode;
+
+    /// Ends the current exchange if no packet is being received. Returns
+    /// SUCCESS if the exchange was ended, in which case `exchange_done` is
+    /// not called, and EBUSY if a packet is being received, in which case
+    /// the exchange completes normally.
+    fn stop(&self) -> ReturnCode;
+
+    fn set_connection_client(&self, client: &'a dyn ConnectionClient);
+}
+
+pub trait ConnectionClient {
+    /// Called, possibly from an interrupt handler, when a packet has been
+    /// received. `rx` holds the PDU header and payload, `rssi` the signal
+    /// strength in dBm if the radio measured it, and `result` is FAIL if the
+    /// CRC did not match. The client writes the reply PDU into `tx` and
+    /// returns its length, or returns 0 to not reply.
+    fn packet_received(
+        &self,
+        rx: &[u8],
+        rssi: Option<i8>,
+        result: ReturnCode,
+        tx: &mut [u8],
+    ) -> usize;
+
+    /// Called once the exchange is over: after the reply was transmitted, or
+    /// after a packet that needed no reply was received. When scanning, it is
+    /// called after the response to the reply was received instead.
+    fn exchange_done(&self, result: ReturnCode);
+}
+
+/// Access address of all advertising channel packets.
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8E89BED6;
+/// CRC initialization value of all advertising channel packets.
+pub const ADVERTISING_CRC_INIT: u32 = 0x555555;
+/// Inter frame space, in microseconds.
+pub const T_IFS_US: u32 = 150;
+
 pub trait RxClient {
-    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode);
+    /// `rssi` is the signal strength of the packet in dBm, if the radio
+    /// measured it.
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, rssi: Option<i8>, result: ReturnCode);
 }
 
 pub trait TxClient {
@@ -158,4 +224,53 @@ impl RadioChannel {
             RadioChannel::AdvertisingChannel39 => 39,
         }
     }
+
+    /// The channel with index `index`, as used by the channel selection
+    /// algorithm, or `None` if there is no such channel.
+    pub fn from_channel_index(index: u8) -> Option<RadioChannel> {
+        let channel = match index {
+            0 => RadioChannel::DataChannel0,
+            1 => RadioChannel::DataChannel1,
+            2 => RadioChannel::DataChannel2,
+            3 => RadioChannel::DataChannel3,
+            4 => RadioChannel::DataChannel4,
+            5 => RadioChannel::DataChannel5,
+            6 => RadioChannel::DataChannel6,
+            7 => RadioChannel::DataChannel7,
+            8 => RadioChannel::DataChannel8,
+            9 => RadioChannel::DataChannel9,
+            10 => RadioChannel::DataChannel10,
+            11 => RadioChannel::DataChannel11,
+            12 => RadioChannel::DataChannel12,
+            13 => RadioChannel::DataChannel13,
+            14 => RadioChannel::DataChannel14,
+            15 => RadioChannel::DataChannel15,
+            16 => RadioChannel::DataChannel16,
+            17 => RadioChannel::DataChannel17,
+            18 => RadioChannel::DataChannel18,
+            19 => RadioChannel::DataChannel19,
+            20 => RadioChannel::DataChannel20,
+            21 => RadioChannel::DataChannel21,
+            22 => RadioChannel::DataChannel22,
+            23 => RadioChannel::DataChannel23,
+            24 => RadioChannel::DataChannel24,
+            25 => RadioChannel::DataChannel25,
+            26 => RadioChannel::DataChannel26,
+            27 => RadioChannel::DataChannel27,
+            28 => RadioChannel::DataChannel28,
+            29 => RadioChannel::DataChannel29,
+            30 => RadioChannel::DataChannel30,
+            31 => RadioChannel::DataChannel31,
+            32 => RadioChannel::DataChannel32,
+            33 => RadioChannel::DataChannel33,
+            34 => RadioChannel::DataChannel34,
+            35 => RadioChannel::DataChannel35,
+            36 => RadioChannel::DataChannel36,
+            37 => RadioChannel::AdvertisingChannel37,
+            38 => RadioChannel::AdvertisingChannel38,
+            39 => RadioChannel::AdvertisingChannel39,
+            _ => return None,
+        };
+        Some(channel)
+    }
 }
@@ -0,0 +1,111 @@
+//! Interface for block devices such as SD cards and SPI flash chips.
+//!
+//! A block device is read and written in whole blocks. Devices that need
+//! erasing, such as flash, group blocks into larger erase units and can only
+//! write blocks that have been erased since they were last written. Devices
+//! that don't need erasing, such as SD cards and FRAM, can rewrite blocks
+//! directly. On all devices erased blocks read as `0xFF`.
+//!
+//! ```text
+//! +-----------+  +-----------+  +-----------+
+//! |    Log    |  |   TicKV   |  |    FAT    |
+//! +-----------+  +-----------+  +-----------+
+//!   hil::flash     hil::flash     hil::nonvolatile_storage
+//! +-----------------------------------------+
+//! |   capsules::block_storage adapters      |
+//! +-----------------------------------------+
+//!            hil::block_storage
+//! +-----------------------------------------+
+//! |  capsules::virtual_block_storage (mux)  |
+//! +-----------------------------------------+
+//!            hil::block_storage
+//! +-----------------------------------------+
+//! |    capsules::block_cache (optional)     |
+//! +-----------------------------------------+
+//!            hil::block_storage
+//! +-----------------------------------------+
+//! |   capsules::block_storage adapters      |
+//! +-----------------------------------------+
+//!   hil::flash     hil::nonvolatile_storage
+//! +-----------------------------------------+
+//! |  MX25R6435F, chip flash, SD card, FRAM  |
+//! +-----------------------------------------+
+//! ```
+
+use crate::returncode::ReturnCode;
+
+/// The layout of a block device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Geometry {
+    /// The size of a block in bytes. Reads and writes are whole blocks.
+    pub block_size: usize,
+    /// The number of blocks on the device
+    pub block_count: u32,
+    /// The number of blocks in an erase unit. Erases are whole erase units.
+    pub erase_blocks: u32,
+    /// Blocks must be erased before they can be written again
+    pub needs_erase: bool,
+}
+
+impl Geometry {
+    /// The size of the device in bytes.
+    pub fn size(&self) -> usize {
+        self.block_size * self.block_count as usize
+    }
+
+    /// Check that `count` blocks starting at `block` are on the device.
+    pub fn contains(&self, block: u32, count: u32) -> bool {
+        count > 0 && block < self.block_count && count <= self.block_count - block
+    }
+}
+
+/// Implement `Client` to receive callbacks from `BlockStorage`.
+pub trait Client {
+    /// Blocks were read into `buffer`.
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>);
+
+    /// Blocks were written from `buffer`.
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ReturnCode>);
+
+    /// Blocks were erased.
+    fn erase_complete(&self, result: Result<(), ReturnCode>);
+
+    /// Cached writes were written to the device.
+    fn flush_complete(&self, result: Result<(), ReturnCode>);
+}
+
+/// A device that is read and written in blocks.
+pub trait BlockStorage<'a> {
+    /// Set the client that is called when operations complete.
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// The layout of the device.
+    fn geometry(&self) -> Geometry;
+
+    /// Read `count` blocks starting at `block` into `buffer`, which must
+    /// hold at least `count` blocks.
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Write `count` blocks starting at `block` from `buffer`, which must
+    /// hold at least `count` blocks. Some devices only support writing one
+    /// block at a time and return `ENOSUPPORT` for longer writes.
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Erase `count` blocks starting at `block`. Both must be multiples of
+    /// `Geometry::erase_blocks`.
+    fn erase(&self, block: u32, count: u32) -> ReturnCode;
+
+    /// Write any cached writes to the device. Returns `EALREADY` if there
+    /// is nothing to write, in which case `flush_complete()` isn't called.
+    fn flush(&self) -> ReturnCode;
+}
@@ -8,6 +8,7 @@ use crate::returncode::ReturnCode;
 pub trait DigestType: Eq + Copy + Clone + Sized + AsRef<[u8]> + AsMut<[u8]> {}
 
 impl DigestType for [u8; 32] {}
+impl DigestType for [u8; 64] {}
 
 /// Implement this trait and use `set_client()` in order to receive callbacks.
 pub trait Client<'a, T: DigestType> {
@@ -0,0 +1,164 @@
+//! High level interface for Key-Value (KV) Stores
+//!
+//! This is level 3 of the KV store implementation described in
+//! `hil::kv_system`. Users of this HIL operate on unhashed keys, which are
+//! arbitrary byte strings, and values. Each operation is performed on behalf
+//! of a caller described by `StoragePermissions`, which selects the namespace
+//! the key lives in and whether the caller may bypass ownership checks.
+//!
+//! Keys in different namespaces never refer to the same value, so two
+//! applications can both use the key `"config"` without interfering with each
+//! other.
+//!
+//! ```text
+//! +-----------------------+
+//! |                       |
+//! |  Capsule using K-V    |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_store (this file)
+//!
+//! +-----------------------+
+//! |                       |
+//! |  K-V in Tock          |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_system
+//! ```
+
+use crate::capabilities;
+use crate::returncode::ReturnCode;
+
+/// Describes who is performing a KV store operation.
+#[derive(Clone, Copy)]
+pub struct StoragePermissions<'b> {
+    namespace: &'b [u8],
+    privileged: bool,
+}
+
+impl<'b> StoragePermissions<'b> {
+    /// Permissions for an application, or a capsule acting on behalf of one,
+    /// that can only access values stored in its own `namespace`.
+    pub fn for_app(namespace: &'b [u8]) -> StoragePermissions<'b> {
+        StoragePermissions {
+            namespace,
+            privileged: false,
+        }
+    }
+
+    /// Permissions for trusted kernel code. Privileged callers can modify
+    /// values in `namespace` regardless of who wrote them, and values they
+    /// write can only be modified or deleted by other privileged callers.
+    pub fn for_kernel(
+        namespace: &'b [u8],
+        _capability: &dyn capabilities::KVStorePrivilegedCapability,
+    ) -> StoragePermissions<'b> {
+        StoragePermissions {
+            namespace,
+            privileged: true,
+        }
+    }
+
+    /// The namespace keys are looked up in.
+    pub fn namespace(&self) -> &'b [u8] {
+        self.namespace
+    }
+
+    /// Whether this caller holds the `KVStorePrivilegedCapability`.
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+}
+
+/// Implement this trait and use `set_client()` in order to receive callbacks.
+pub trait StoreClient {
+    /// This callback is called when the get operation completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    /// `value`: The buffer passed to `get()`
+    /// `length`: The length of the stored value. On `ESIZE` this is larger
+    ///           than `value` and only the first `value.len()` bytes are valid.
+    fn get_complete(&self, result: Result<(), ReturnCode>, value: &'static mut [u8], length: usize);
+
+    /// This callback is called when the set operation completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    /// `value`: The buffer passed to `set()`
+    fn set_complete(&self, result: Result<(), ReturnCode>, value: &'static mut [u8]);
+
+    /// This callback is called when the delete operation completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    fn delete_complete(&self, result: Result<(), ReturnCode>);
+}
+
+pub trait KVStore<'a> {
+    /// Set the client
+    fn set_client(&self, client: &'a dyn StoreClient);
+
+    /// Retrieve the value stored for `key`.
+    ///
+    /// `permissions`: The caller performing the operation
+    /// `key`: The unhashed key. This is copied before the call returns.
+    /// `value`: A buffer to store the value to.
+    ///
+    /// On success nothing will be returned.
+    /// On error the value buffer and a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `EINVAL`: The key is empty
+    ///    `ESIZE`: The key or namespace is too long. Also returned in the
+    ///             callback if `value` is too small for the stored value.
+    ///    `ENOSUPPORT`: The key could not be found. Returned in the callback.
+    fn get(
+        &self,
+        permissions: StoragePermissions,
+        key: &[u8],
+        value: &'static mut [u8],
+    ) -> Result<(), (&'static mut [u8], ReturnCode)>;
+
+    /// Store the first `length` bytes of `value` for `key`, replacing any
+    /// existing value. If the new value can't be stored the existing value
+    /// is kept.
+    ///
+    /// `permissions`: The caller performing the operation
+    /// `key`: The unhashed key. This is copied before the call returns.
+    /// `value`: A buffer containing the data to be stored.
+    /// `length`: The number of bytes of `value` to store.
+    ///
+    /// On success nothing will be returned.
+    /// On error the value buffer and a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `EINVAL`: The key is empty or `length` is larger than `value`
+    ///    `ESIZE`: The key, namespace or value is too long
+    ///    `ERESERVE`: The existing value belongs to another caller or can
+    ///                only be modified by a privileged caller. Returned in
+    ///                the callback.
+    ///    `ENOMEM`: There is no space left. Returned in the callback.
+    fn set(
+        &self,
+        permissions: StoragePermissions,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut [u8], ReturnCode)>;
+
+    /// Delete the value stored for `key`.
+    ///
+    /// `permissions`: The caller performing the operation
+    /// `key`: The unhashed key. This is copied before the call returns.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `EINVAL`: The key is empty
+    ///    `ESIZE`: The key or namespace is too long
+    ///    `ERESERVE`: The value can only be deleted by a privileged caller.
+    ///                Returned in the callback.
+    ///    `ENOSUPPORT`: The key could not be found. Returned in the callback.
+    fn delete(&self, permissions: StoragePermissions, key: &[u8]) -> Result<(), ReturnCode>;
+}
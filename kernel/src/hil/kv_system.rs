@@ -30,7 +30,7 @@
 //! This level is also in charge of generating the key hash by calling into
 //! level 2.
 //!
-//! There is currently no implementation of this HIL in Tock.
+//! `capsules::kv_store` implements this HIL on top of any level 2 system.
 //!
 //! The expected setup inside Tock will look like this:
 //! +-----------------------+
@@ -39,7 +39,7 @@
 //! |                       |
 //! +-----------------------+
 //!
-//!    hil::kv_store (not written yet)
+//!    hil::kv_store
 //!
 //! +-----------------------+
 //! |                       |
@@ -47,7 +47,7 @@
 //! |                       |
 //! +-----------------------+
 //!
-//!    hil::kv_system (this PR)
+//!    hil::kv_system (this file)
 //!
 //! +-----------------------+
 //! |                       |
@@ -61,22 +61,37 @@ use crate::returncode::ReturnCode;
 
 /// The type of keys, this should define the output size of the digest
 /// operations.
-pub trait KeyType: Eq + Copy + Clone + Sized + AsRef<[u8]> + AsMut<[u8]> {}
+pub trait KeyType: 'static + Eq + Copy + Clone + Sized + AsRef<[u8]> + AsMut<[u8]> {}
 
 impl KeyType for [u8; 8] {}
 
+/// Space accounting for a KV system, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StorageStats {
+    /// The number of valid keys
+    pub keys: usize,
+    /// Space used by valid keys, including any headers and checksums
+    pub used: usize,
+    /// Space used by invalidated keys
+    pub invalidated: usize,
+    /// Space that can hold new keys
+    pub free: usize,
+    /// Space that `garbage_collect()` would currently free
+    pub reclaimable: usize,
+}
+
 /// Implement this trait and use `set_client()` in order to receive callbacks.
 pub trait Client<K: KeyType> {
-    /// This callback is called when the append_key operation completes
+    /// This callback is called when the generate_key operation completes
     ///
     /// `result`: Nothing on success, 'ReturnCode' on error
     /// `unhashed_key`: The unhashed_key buffer
-    /// `key_buf`: The key_buf buffer
+    /// `key_buf`: The key_buf buffer, containing the hashed key on success
     fn generate_key_complete(
         &self,
         result: Result<(), ReturnCode>,
-        unhashed_key: &'static [u8],
-        key_buf: &'static K,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut K,
     );
 
     /// This callback is called when the append_key operation completes
@@ -88,7 +103,7 @@ pub trait Client<K: KeyType> {
         &self,
         result: Result<(), ReturnCode>,
         key: &'static mut K,
-        value: &'static [u8],
+        value: &'static mut [u8],
     );
 
     /// This callback is called when the get_value operation completes
@@ -113,6 +128,67 @@ pub trait Client<K: KeyType> {
     ///
     /// `result`: Nothing on success, 'ReturnCode' on error
     fn garbage_collect_complete(&self, result: Result<(), ReturnCode>);
+
+    /// This callback is called when the next_key operation completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error. `ENOSUPPORT`
+    ///           indicates there are no more keys.
+    /// `key_buf`: The key_buf buffer, containing the key found on success
+    /// `cursor`: The cursor to pass to `next_key()` to continue listing keys
+    fn next_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key_buf: &'static mut K,
+        cursor: usize,
+    );
+
+    /// This callback is called when the storage_stats operation completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    /// `stats`: The space used by the KV system
+    fn storage_stats_complete(&self, result: Result<(), ReturnCode>, stats: StorageStats);
+
+    /// This callback is called when the check_consistency operation completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    /// `found`: The number of problems found
+    /// `repaired`: The number of problems that were repaired
+    fn check_consistency_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        found: usize,
+        repaired: usize,
+    );
+
+    /// This callback is called when the begin_transaction operation
+    /// completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    fn begin_transaction_complete(&self, result: Result<(), ReturnCode>);
+
+    /// This callback is called when the stage_key operation completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    /// `key`: The key buffer
+    /// `value`: The value buffer
+    fn stage_key_complete(
+        &self,
+        result: Result<(), ReturnCode>,
+        key: &'static mut K,
+        value: &'static mut [u8],
+    );
+
+    /// This callback is called when the commit_transaction operation
+    /// completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    fn commit_transaction_complete(&self, result: Result<(), ReturnCode>);
+
+    /// This callback is called when the abort_transaction operation
+    /// completes
+    ///
+    /// `result`: Nothing on success, 'ReturnCode' on error
+    fn abort_transaction_complete(&self, result: Result<(), ReturnCode>);
 }
 
 pub trait KVSystem<'a> {
@@ -140,6 +216,7 @@ pub trait KVSystem<'a> {
     /// `key`: A hashed key. This key will be used in future to retrieve
     ///        or remove the `value`.
     /// `value`: A buffer containing the data to be stored to flash.
+    /// `length`: The number of bytes from the start of `value` to store.
     ///
     /// On success nothing will be returned.
     /// On error the key, value and a `ReturnCode` will be returned.
@@ -153,8 +230,9 @@ pub trait KVSystem<'a> {
     fn append_key(
         &self,
         key: &'static mut Self::K,
-        value: &'static [u8],
-    ) -> Result<(), (&'static mut Self::K, &'static [u8], ReturnCode)>;
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Self::K, &'static mut [u8], ReturnCode)>;
 
     /// Retrieves the value from a specified key.
     ///
@@ -169,6 +247,7 @@ pub trait KVSystem<'a> {
     ///    `EINVAL`: An invalid parameter was passed
     ///    `ENODEVICE`: No KV store was setup
     ///    `ENOSUPPORT`: The key could not be found.
+    ///    `ESIZE`: `ret_buf` is too small to hold the stored value.
     fn get_value(
         &self,
         key: &'static mut Self::K,
@@ -205,4 +284,112 @@ pub trait KVSystem<'a> {
     ///    `EINVAL`: An invalid parameter was passed
     ///    `ENODEVICE`: No KV store was setup
     fn garbage_collect(&self) -> Result<usize, ReturnCode>;
+
+    /// Find the next valid key.
+    ///
+    /// `cursor`: Where to continue listing keys from. Use 0 to start from
+    ///           the first key, then the cursor passed to
+    ///           `next_key_complete()`.
+    /// `key_buf`: A buffer to store the key found.
+    ///
+    /// Keys are returned in no particular order. Keys added or removed while
+    /// listing keys may or may not be returned.
+    ///
+    /// On success nothing will be returned.
+    /// On error the key_buf and a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `ENODEVICE`: No KV store was setup
+    ///    `ENOSUPPORT`: There are no more keys
+    fn next_key(
+        &self,
+        cursor: usize,
+        key_buf: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, ReturnCode)>;
+
+    /// Measure how much space is used, free and reclaimable.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `ENODEVICE`: No KV store was setup
+    fn storage_stats(&self) -> Result<(), ReturnCode>;
+
+    /// Check the stored data for corruption.
+    ///
+    /// `repair`: Repair the problems that can be fixed without losing
+    ///           intact keys. Corrupt keys are invalidated.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `ENODEVICE`: No KV store was setup
+    fn check_consistency(&self, repair: bool) -> Result<(), ReturnCode>;
+
+    /// Start a transaction.
+    ///
+    /// Keys added with `stage_key()` only become visible once
+    /// `commit_transaction()` completes, and either all of them do or, if
+    /// power is lost first, none of them.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `EALREADY`: A transaction has already been started
+    ///    `ENODEVICE`: No KV store was setup
+    fn begin_transaction(&self) -> Result<(), ReturnCode>;
+
+    /// Stages the key/value pair in the current transaction.
+    ///
+    /// `key`: A hashed key. If the key already exists its value is replaced
+    ///        when the transaction commits.
+    /// `value`: A buffer containing the data to be stored to flash.
+    /// `length`: The number of bytes from the start of `value` to store.
+    ///
+    /// On success nothing will be returned.
+    /// On error the key, value and a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `EINVAL`: An invalid parameter was passed, or no transaction has
+    ///              been started
+    ///    `ENODEVICE`: No KV store was setup
+    ///    `ENOSUPPORT`: The key has already been staged.
+    ///    `ENOMEM`: The key could not be added due to no more space.
+    fn stage_key(
+        &self,
+        key: &'static mut Self::K,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (&'static mut Self::K, &'static mut [u8], ReturnCode)>;
+
+    /// Commit the current transaction, making all of the staged keys
+    /// visible.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `EINVAL`: No transaction has been started
+    ///    `ENODEVICE`: No KV store was setup
+    fn commit_transaction(&self) -> Result<(), ReturnCode>;
+
+    /// Abort the current transaction, discarding all of the staged keys.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ReturnCode` will be returned.
+    ///
+    /// The possible `ReturnCode`s are:
+    ///    `EBUSY`: An operation is already in progress
+    ///    `EINVAL`: No transaction has been started
+    ///    `ENODEVICE`: No KV store was setup
+    fn abort_transaction(&self) -> Result<(), ReturnCode>;
 }
@@ -54,7 +54,8 @@ pub trait LogWrite<'a> {
     /// Set the client for appending from a log. The client will be called when writing operations complete.
     fn set_append_client(&'a self, append_client: &'a dyn LogWriteClient);
 
-    /// Append an entry to the end of the log. May fail if the entry is too large.
+    /// Append an entry to the end of the log. May fail if the entry is too large. If the call
+    /// succeeds, `append_done` is called later, never from within `append`.
     fn append(
         &self,
         buffer: &'static mut [u8],
@@ -64,7 +65,8 @@ pub trait LogWrite<'a> {
     /// Sync log to storage, making all entries persistent (not including any entries that were
     /// previously overwritten). There is no guarantee that any changes to the log are persistent
     /// until it is synced. In the event of an error, not all pages may be synced, but the log will
-    /// remain in a valid state.
+    /// remain in a valid state. If the call succeeds, `sync_done` is always called, even if there
+    /// was nothing left to sync.
     fn sync(&self) -> ReturnCode;
 
     /// Erase the entire log. In the event of a failure, only some pages may be erased, but the log
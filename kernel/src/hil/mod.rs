@@ -3,6 +3,7 @@
 pub mod adc;
 pub mod analog_comparator;
 pub mod ble_advertising;
+pub mod block_storage;
 pub mod bus8080;
 pub mod crc;
 pub mod dac;
@@ -13,10 +14,12 @@ pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod kv_store;
 pub mod kv_system;
 pub mod led;
 pub mod log;
 pub mod nonvolatile_storage;
+pub mod public_key_crypto;
 pub mod pwm;
 pub mod radio;
 pub mod rng;
@@ -0,0 +1,53 @@
+//! Interface for public key cryptography.
+//!
+//! Signature schemes differ in what they sign. ECDSA signs a digest of the
+//! message, which the caller computes first, for example with
+//! `hil::digest`. Ed25519 signs the message itself and hashes it as part of
+//! the verification. Each implementation documents the formats of its
+//! public keys and signatures.
+
+use crate::returncode::ReturnCode;
+
+/// Implement this trait and use `set_verify_client()` in order to receive
+/// callbacks.
+pub trait ClientVerify<'a> {
+    /// This callback is called when a verification is finished.
+    ///
+    /// `result` is `Ok(true)` if `signature` is a valid signature of
+    /// `message` for the public key, `Ok(false)` if it isn't, or an error if
+    /// the verification could not be done.
+    /// On error or success `message` and `signature` will contain references
+    /// to the original buffers supplied to `verify()`.
+    fn verification_done(
+        &'a self,
+        result: Result<bool, ReturnCode>,
+        message: &'static mut [u8],
+        signature: &'static mut [u8],
+    );
+}
+
+/// Verifies signatures made with the private key that belongs to a public
+/// key.
+pub trait SignatureVerify<'a> {
+    /// Set the client instance which will receive the
+    /// `verification_done()` callback.
+    fn set_verify_client(&'a self, client: &'a dyn ClientVerify<'a>);
+
+    /// Set the public key used to verify signatures.
+    ///
+    /// Returns `EINVAL` if the key isn't valid, in which case the previous
+    /// key is no longer used either, or `EBUSY` if a verification is in
+    /// progress.
+    fn set_public_key(&self, public_key: &[u8]) -> Result<(), ReturnCode>;
+
+    /// Verify that `signature` is a valid signature of `message`.
+    ///
+    /// The result is delivered through the `verification_done()` callback.
+    /// On error the return value will contain a return code and the original
+    /// buffers. `EOFF` means there is no public key.
+    fn verify(
+        &'a self,
+        message: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])>;
+}
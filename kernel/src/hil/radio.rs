@@ -63,6 +63,11 @@ pub const MIN_PAYLOAD_OFFSET: usize = PSDU_OFFSET + MIN_MHR_SIZE;
 
 pub trait Radio: RadioConfig + RadioData {}
 
+/// A radio that passes every frame it receives to its receive client, without
+/// filtering on the destination address or PAN ID. Capsules that need to see
+/// all traffic on a channel, such as a sniffer, require this.
+pub trait PromiscuousRadio: Radio {}
+
 /// Configure the 802.15.4 radio.
 pub trait RadioConfig {
     /// buf must be at least MAX_BUF_SIZE in length, and
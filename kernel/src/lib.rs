@@ -109,7 +109,7 @@ mod process;
 mod returncode;
 mod sched;
 
-pub use crate::callback::{AppId, Callback};
+pub use crate::callback::{AppId, Callback, CallbackId};
 pub use crate::driver::Driver;
 pub use crate::grant::{DynamicGrant, Grant};
 pub use crate::mem::{AppSlice, Private, Shared};
@@ -100,11 +100,11 @@
 //! // when appending a key:
 //!
 //! // Add a key
-//! static VALUE: [u8; 32] = [0x23; 32];
-//! let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &VALUE) };
+//! static mut VALUE: [u8; 32] = [0x23; 32];
+//! let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut VALUE, 32) };
 //!
 //! match ret {
-//!     Err(ErrorCode::ReadNotReady(reg)) => {
+//!     Err((_, ErrorCode::ReadNotReady(reg))) => {
 //!         // There is no actual delay in the test, just continue now
 //!         tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
 //!         tickv
@@ -126,7 +126,7 @@
 use crate::error_codes::ErrorCode;
 use crate::flash_controller::FlashController;
 use crate::success_codes::SuccessCode;
-use crate::tickv::{State, TicKV};
+use crate::tickv::{RegionCheck, RegionUsage, State, TicKV};
 use core::cell::Cell;
 
 /// The return type from the continue operation
@@ -142,8 +142,15 @@ pub struct AsyncTicKV<'a, C: FlashController<S>, const S: usize> {
     /// The main TicKV struct
     pub tickv: TicKV<'a, C, S>,
     key: Cell<Option<u64>>,
-    value: Cell<Option<&'static [u8]>>,
+    value: Cell<Option<&'static mut [u8]>>,
+    value_length: Cell<usize>,
     buf: Cell<Option<&'static mut [u8]>>,
+    cursor: Cell<usize>,
+    region: Cell<usize>,
+    repair: Cell<bool>,
+    found_key: Cell<Option<(u64, usize)>>,
+    region_usage: Cell<Option<RegionUsage>>,
+    region_check: Cell<Option<RegionCheck>>,
 }
 
 impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
@@ -158,7 +165,14 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
             tickv: TicKV::<C, S>::new(controller, read_buffer, flash_size),
             key: Cell::new(None),
             value: Cell::new(None),
+            value_length: Cell::new(0),
             buf: Cell::new(None),
+            cursor: Cell::new(0),
+            region: Cell::new(0),
+            repair: Cell::new(false),
+            found_key: Cell::new(None),
+            region_usage: Cell::new(None),
+            region_check: Cell::new(None),
         }
     }
 
@@ -182,17 +196,40 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
     /// `hash`: A hashed key. This key will be used in future to retrieve
     ///         or remove the `value`.
     /// `value`: A buffer containing the data to be stored to flash.
+    /// `length`: The number of bytes from the start of `value` to store.
     ///
-    /// On success nothing will be returned.
+    /// On success a `SuccessCode` will be returned.
     /// On error a `ErrorCode` will be returned.
-    pub fn append_key(&self, hash: u64, value: &'static [u8]) -> Result<SuccessCode, ErrorCode> {
-        match self.tickv.append_key(hash, value) {
-            Ok(code) => Ok(code),
-            Err(e) => {
-                self.key.replace(Some(hash));
+    ///
+    /// The `value` buffer is kept until the operation finishes and can then
+    /// be retrieved with `get_stored_value_buffer()`. It is only returned
+    /// directly on a non async error.
+    pub fn append_key(
+        &self,
+        hash: u64,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<SuccessCode, (Option<&'static mut [u8]>, ErrorCode)> {
+        if length > value.len() {
+            return Err((Some(value), ErrorCode::BufferTooSmall(length)));
+        }
+
+        match self.tickv.append_key(hash, &value[..length]) {
+            Ok(code) => {
                 self.value.replace(Some(value));
-                Err(e)
+                Ok(code)
             }
+            Err(e) => match e {
+                ErrorCode::ReadNotReady(_)
+                | ErrorCode::EraseNotReady(_)
+                | ErrorCode::WriteNotReady(_) => {
+                    self.key.replace(Some(hash));
+                    self.value.replace(Some(value));
+                    self.value_length.set(length);
+                    Err((None, e))
+                }
+                _ => Err((Some(value), e)),
+            },
         }
     }
 
@@ -254,6 +291,125 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
         self.tickv.garbage_collect()
     }
 
+    /// Find the next valid key in flash storage.
+    ///
+    /// `cursor`: The position to continue from. Start with a cursor of zero
+    ///           and then use the cursor returned with the previous key.
+    ///
+    /// On success the hashed key and the cursor to continue from will be
+    /// returned. If the operation completes asynchronously these can be
+    /// retrieved with `get_stored_key()`.
+    /// On error a `ErrorCode` will be returned. `ErrorCode::KeyNotFound`
+    /// indicates there are no more keys.
+    pub fn next_key(&self, cursor: usize) -> Result<(u64, usize), ErrorCode> {
+        let mut cursor = cursor;
+        match self.tickv.next_key(&mut cursor) {
+            Ok(hash) => Ok((hash, cursor)),
+            Err(e) => {
+                self.cursor.set(cursor);
+                Err(e)
+            }
+        }
+    }
+
+    /// Measure the space used in `region`.
+    ///
+    /// On success the `RegionUsage` will be returned. If the operation
+    /// completes asynchronously it can be retrieved with
+    /// `get_stored_region_usage()`.
+    /// On error a `ErrorCode` will be returned.
+    pub fn region_usage(&self, region: usize) -> Result<RegionUsage, ErrorCode> {
+        self.region.set(region);
+        self.tickv.region_usage(region)
+    }
+
+    /// Check `region` for corruption, see `TicKV::check_region()`.
+    ///
+    /// On success a `RegionCheck` will be returned. If the operation
+    /// completes asynchronously it can be retrieved with
+    /// `get_stored_region_check()`.
+    /// On error a `ErrorCode` will be returned.
+    pub fn check_region(&self, region: usize, repair: bool) -> Result<RegionCheck, ErrorCode> {
+        self.region.set(region);
+        self.repair.set(repair);
+        self.tickv.check_region(region, repair)
+    }
+
+    /// Start a transaction, see `TicKV::begin_transaction()`.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn begin_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        self.tickv.begin_transaction()
+    }
+
+    /// Stage a key/value pair in the current transaction, see
+    /// `TicKV::stage_key()`.
+    ///
+    /// `hash`: A hashed key.
+    /// `value`: A buffer containing the data to be stored to flash.
+    /// `length`: The number of bytes from the start of `value` to store.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    ///
+    /// The `value` buffer is kept until the operation finishes and can then
+    /// be retrieved with `get_stored_value_buffer()`. It is only returned
+    /// directly on a non async error.
+    pub fn stage_key(
+        &self,
+        hash: u64,
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<SuccessCode, (Option<&'static mut [u8]>, ErrorCode)> {
+        if length > value.len() {
+            return Err((Some(value), ErrorCode::BufferTooSmall(length)));
+        }
+
+        match self.tickv.stage_key(hash, &value[..length]) {
+            Ok(code) => {
+                self.value.replace(Some(value));
+                Ok(code)
+            }
+            Err(e) => match e {
+                ErrorCode::ReadNotReady(_)
+                | ErrorCode::EraseNotReady(_)
+                | ErrorCode::WriteNotReady(_) => {
+                    self.key.replace(Some(hash));
+                    self.value.replace(Some(value));
+                    self.value_length.set(length);
+                    Err((None, e))
+                }
+                _ => Err((Some(value), e)),
+            },
+        }
+    }
+
+    /// Commit the current transaction, see `TicKV::commit_transaction()`.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn commit_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        self.tickv.commit_transaction()
+    }
+
+    /// Abort the current transaction, see `TicKV::abort_transaction()`.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn abort_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        self.tickv.abort_transaction()
+    }
+
+    /// Finish an interrupted transaction, see
+    /// `TicKV::recover_transaction()`.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn recover_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        self.tickv.recover_transaction()
+    }
+
     /// Copy data from `read_buffer` argument to the internal read_buffer.
     /// This should be used to copy the data that the implementation wanted
     /// to read when calling `read_region` after the async operation has
@@ -266,7 +422,7 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
 
     /// Get the `value` buffer that was passed in by previous
     /// commands.
-    pub fn get_stored_value_buffer(&self) -> Option<&'static [u8]> {
+    pub fn get_stored_value_buffer(&self) -> Option<&'static mut [u8]> {
         self.value.take()
     }
 
@@ -276,6 +432,21 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
         self.buf.take()
     }
 
+    /// Get the hashed key and cursor found by an async `next_key()`.
+    pub fn get_stored_key(&self) -> Option<(u64, usize)> {
+        self.found_key.take()
+    }
+
+    /// Get the `RegionUsage` measured by an async `region_usage()`.
+    pub fn get_stored_region_usage(&self) -> Option<RegionUsage> {
+        self.region_usage.take()
+    }
+
+    /// Get the `RegionCheck` from an async `check_region()`.
+    pub fn get_stored_region_check(&self) -> Option<RegionCheck> {
+        self.region_check.take()
+    }
+
     /// Continue the last operation after the async operation has completed.
     /// This should be called from a read/erase complete callback.
     /// NOTE: If called from a read callback, `set_read_buffer` should be
@@ -294,20 +465,54 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
     pub fn continue_operation(&self) -> ContinueReturn {
         let ret = match self.tickv.state.get() {
             State::Init(_) => self.tickv.initalise(self.key.get().unwrap()),
-            State::AppendKey(_) => self
-                .tickv
-                .append_key(self.key.get().unwrap(), self.value.get().unwrap()),
+            State::AppendKey(_) => {
+                let value = self.value.take().unwrap();
+                let ret = self
+                    .tickv
+                    .append_key(self.key.get().unwrap(), &value[..self.value_length.get()]);
+                self.value.replace(Some(value));
+                ret
+            }
             State::GetKey(_) => {
                 let buf = self.buf.take().unwrap();
                 let ret = self.tickv.get_key(self.key.get().unwrap(), buf);
                 self.buf.replace(Some(buf));
                 ret
             }
+            State::StageKey(_) => {
+                let value = self.value.take().unwrap();
+                let ret = self
+                    .tickv
+                    .stage_key(self.key.get().unwrap(), &value[..self.value_length.get()]);
+                self.value.replace(Some(value));
+                ret
+            }
+            State::Transaction(_) => self.tickv.continue_transaction(),
             State::InvalidateKey(_) => self.tickv.invalidate_key(self.key.get().unwrap()),
             State::GarbageCollect(_) => match self.tickv.garbage_collect() {
                 Ok(_) => Ok(SuccessCode::Complete),
                 Err(e) => Err(e),
             },
+            State::NextKey(_) => {
+                let mut cursor = self.cursor.get();
+                let ret = self.tickv.next_key(&mut cursor);
+                self.cursor.set(cursor);
+                ret.map(|hash| {
+                    self.found_key.set(Some((hash, cursor)));
+                    SuccessCode::Complete
+                })
+            }
+            State::RegionUsage(_) => self.tickv.region_usage(self.region.get()).map(|usage| {
+                self.region_usage.set(Some(usage));
+                SuccessCode::Complete
+            }),
+            State::CheckRegion(_) => self
+                .tickv
+                .check_region(self.region.get(), self.repair.get())
+                .map(|check| {
+                    self.region_check.set(Some(check));
+                    SuccessCode::Complete
+                }),
             _ => unreachable!(),
         };
 
@@ -319,7 +524,10 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
             Err(e) => match e {
                 ErrorCode::ReadNotReady(_) | ErrorCode::EraseNotReady(_) => (ret, None),
                 ErrorCode::WriteNotReady(_) => {
-                    self.tickv.state.set(State::None);
+                    // Transactions continue after the write completes
+                    if !matches!(self.tickv.state.get(), State::Transaction(_)) {
+                        self.tickv.state.set(State::None);
+                    }
                     (ret, None)
                 }
                 _ => {
@@ -337,6 +545,7 @@ mod store_flast_ctrl {
     use crate::async_ops::AsyncTicKV;
     use crate::error_codes::ErrorCode;
     use crate::flash_controller::FlashController;
+    use crate::success_codes::SuccessCode;
     use crate::tickv::{HASH_OFFSET, LEN_OFFSET, MAIN_KEY, VERSION, VERSION_OFFSET};
     use core::hash::{Hash, Hasher};
     use std::cell::Cell;
@@ -542,11 +751,12 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
+        static mut VALUE: [u8; 32] = [0x23; 32];
 
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        #[allow(unsafe_code)]
+        let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut VALUE, 32) };
         match ret {
-            Err(ErrorCode::ReadNotReady(reg)) => {
+            Err((_, ErrorCode::ReadNotReady(reg))) => {
                 // There is no actual delay in the test, just continue now
                 tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
                 tickv.continue_operation().0.unwrap();
@@ -555,9 +765,10 @@ mod store_flast_ctrl {
             _ => unreachable!(),
         }
 
-        let ret = tickv.append_key(get_hashed_key(b"TWO"), &VALUE);
+        #[allow(unsafe_code)]
+        let ret = unsafe { tickv.append_key(get_hashed_key(b"TWO"), &mut VALUE, 32) };
         match ret {
-            Err(ErrorCode::ReadNotReady(reg)) => {
+            Err((_, ErrorCode::ReadNotReady(reg))) => {
                 // There is no actual delay in the test, just continue now
                 tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
                 tickv.continue_operation().0.unwrap();
@@ -582,13 +793,14 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
+        static mut VALUE: [u8; 32] = [0x23; 32];
         static mut BUF: [u8; 32] = [0; 32];
 
         println!("Add key ONE");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        #[allow(unsafe_code)]
+        let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut VALUE, 32) };
         match ret {
-            Err(ErrorCode::ReadNotReady(reg)) => {
+            Err((_, ErrorCode::ReadNotReady(reg))) => {
                 // There is no actual delay in the test, just continue now
                 tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
                 tickv.continue_operation().0.unwrap();
@@ -617,9 +829,10 @@ mod store_flast_ctrl {
         }
 
         println!("Add key ONE again");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        #[allow(unsafe_code)]
+        let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut VALUE, 32) };
         match ret {
-            Err(ErrorCode::ReadNotReady(reg)) => {
+            Err((_, ErrorCode::ReadNotReady(reg))) => {
                 // There is no actual delay in the test, just continue now
                 tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
                 assert_eq!(
@@ -627,14 +840,15 @@ mod store_flast_ctrl {
                     Err(ErrorCode::KeyAlreadyExists)
                 );
             }
-            Err(ErrorCode::KeyAlreadyExists) => {}
+            Err((_, ErrorCode::KeyAlreadyExists)) => {}
             _ => unreachable!(),
         }
 
         println!("Add key TWO");
-        let ret = tickv.append_key(get_hashed_key(b"TWO"), &VALUE);
+        #[allow(unsafe_code)]
+        let ret = unsafe { tickv.append_key(get_hashed_key(b"TWO"), &mut VALUE, 32) };
         match ret {
-            Err(ErrorCode::ReadNotReady(reg)) => {
+            Err((_, ErrorCode::ReadNotReady(reg))) => {
                 // There is no actual delay in the test, just continue now
                 tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
                 tickv.continue_operation().0.unwrap();
@@ -707,13 +921,14 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
+        static mut VALUE: [u8; 32] = [0x23; 32];
         static mut BUF: [u8; 32] = [0; 32];
 
         println!("Add key ONE");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        #[allow(unsafe_code)]
+        let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut VALUE, 32) };
         match ret {
-            Err(ErrorCode::ReadNotReady(reg)) => {
+            Err((_, ErrorCode::ReadNotReady(reg))) => {
                 // There is no actual delay in the test, just continue now
                 tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
                 tickv.continue_operation().0.unwrap();
@@ -764,7 +979,7 @@ mod store_flast_ctrl {
             ret = r;
         }
 
-        static VALUE: [u8; 32] = [0x23; 32];
+        static mut VALUE: [u8; 32] = [0x23; 32];
         static mut BUF: [u8; 32] = [0; 32];
 
         println!("Garbage collect empty flash");
@@ -778,9 +993,10 @@ mod store_flast_ctrl {
         }
 
         println!("Add key ONE");
-        let ret = tickv.append_key(get_hashed_key(b"ONE"), &VALUE);
+        #[allow(unsafe_code)]
+        let ret = unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut VALUE, 32) };
         match ret {
-            Err(ErrorCode::ReadNotReady(reg)) => {
+            Err((_, ErrorCode::ReadNotReady(reg))) => {
                 // There is no actual delay in the test, just continue now
                 tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
                 tickv.continue_operation().0.unwrap();
@@ -860,6 +1076,142 @@ mod store_flast_ctrl {
         }
 
         println!("Add Key ONE");
-        tickv.append_key(get_hashed_key(b"ONE"), &VALUE).unwrap();
+        #[allow(unsafe_code)]
+        unsafe {
+            tickv
+                .append_key(get_hashed_key(b"ONE"), &mut VALUE, 32)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_next_key() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+
+        let tickv = AsyncTicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+
+        let mut ret = tickv.initalise(hash_function.finish());
+        while ret.is_err() {
+            // There is no actual delay in the test, just continue now
+            let (r, _buf) = tickv.continue_operation();
+            ret = r;
+        }
+
+        static mut VALUE: [u8; 32] = [0x23; 32];
+
+        for key in [b"ONE", b"TWO"].iter() {
+            #[allow(unsafe_code)]
+            let ret = unsafe { tickv.append_key(get_hashed_key(*key), &mut VALUE, 32) };
+            match ret {
+                Err((_, ErrorCode::ReadNotReady(reg))) => {
+                    // There is no actual delay in the test, just continue now
+                    tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
+                    tickv.continue_operation().0.unwrap();
+                }
+                Ok(_) => {}
+                _ => unreachable!(),
+            }
+        }
+
+        println!("List keys");
+        let mut keys = std::vec::Vec::new();
+        let mut cursor = 0;
+        loop {
+            let mut ret = tickv.next_key(cursor);
+            while let Err(ErrorCode::ReadNotReady(reg)) = ret {
+                // There is no actual delay in the test, just continue now
+                tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
+                ret = match tickv.continue_operation().0 {
+                    Ok(_) => Ok(tickv.get_stored_key().unwrap()),
+                    Err(e) => Err(e),
+                };
+            }
+
+            match ret {
+                Ok((hash, next)) => {
+                    keys.push(hash);
+                    cursor = next;
+                }
+                Err(ErrorCode::KeyNotFound) => break,
+                _ => unreachable!("ret: {:?}", ret),
+            }
+        }
+
+        keys.sort_unstable();
+        let mut expected = vec![get_hashed_key(b"ONE"), get_hashed_key(b"TWO")];
+        expected.sort_unstable();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+
+        let tickv = AsyncTicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+
+        // Finish an operation that is waiting on async reads
+        let finish = |mut ret: Result<SuccessCode, ErrorCode>| {
+            loop {
+                match ret {
+                    Err(ErrorCode::ReadNotReady(reg)) => {
+                        // There is no actual delay in the test, just continue now
+                        tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
+                    }
+                    Err(ErrorCode::EraseNotReady(_)) => {}
+                    _ => return ret,
+                }
+                ret = tickv.continue_operation().0;
+            }
+        };
+
+        finish(tickv.initalise(hash_function.finish())).unwrap();
+        // Don't check the transaction objects as keys
+        tickv.tickv.controller.run.set(3);
+
+        static mut VALUE: [u8; 32] = [0x23; 32];
+        static mut BUF: [u8; 32] = [0; 32];
+
+        finish(tickv.begin_transaction()).unwrap();
+
+        for key in [b"ONE", b"TWO"].iter() {
+            #[allow(unsafe_code)]
+            let ret = unsafe { tickv.stage_key(get_hashed_key(*key), &mut VALUE, 32) };
+            match ret {
+                Err((_, ErrorCode::ReadNotReady(reg))) => {
+                    finish(Err(ErrorCode::ReadNotReady(reg))).unwrap();
+                }
+                Ok(_) => {}
+                _ => unreachable!(),
+            }
+        }
+
+        println!("Get staged key ONE");
+        #[allow(unsafe_code)]
+        let ret = match unsafe { tickv.get_key(get_hashed_key(b"ONE"), &mut BUF) } {
+            Err((_, e)) => finish(Err(e)),
+            Ok(ret) => Ok(ret),
+        };
+        assert_eq!(ret, Err(ErrorCode::KeyNotFound));
+
+        println!("Commit");
+        finish(tickv.commit_transaction()).unwrap();
+        finish(tickv.recover_transaction()).unwrap();
+
+        for key in [b"ONE", b"TWO"].iter() {
+            #[allow(unsafe_code)]
+            let ret = match unsafe { tickv.get_key(get_hashed_key(*key), &mut BUF) } {
+                Err((_, e)) => finish(Err(e)),
+                Ok(ret) => Ok(ret),
+            };
+            ret.unwrap();
+            #[allow(unsafe_code)]
+            unsafe {
+                assert_eq!(BUF, VALUE);
+            }
+        }
     }
 }
@@ -40,6 +40,11 @@ pub enum ErrorCode {
     WriteNotReady(usize),
     /// Indicates that the flash erase operation is not yet ready.
     EraseNotReady(usize),
+    /// A transaction has not been started
+    NoTransaction,
+    /// A transaction has already been started. If it was started before a
+    /// power loss, `recover_transaction()` will finish it.
+    TransactionInProgress,
 }
 
 impl From<ErrorCode> for isize {
@@ -60,6 +65,8 @@ impl From<ErrorCode> for isize {
             ErrorCode::ReadNotReady(_) => -13,
             ErrorCode::WriteNotReady(_) => -14,
             ErrorCode::EraseNotReady(_) => -15,
+            ErrorCode::NoTransaction => -16,
+            ErrorCode::TransactionInProgress => -17,
         }
     }
 }
@@ -183,6 +183,20 @@
 //! and this will stall the application, this still seems like a good idea
 //! to avoid loosing data.
 //!
+//! # Transactions
+//!
+//! Several keys can be added so that either all of them become visible or
+//! none of them do. Call `begin_transaction()`, add the keys with
+//! `stage_key()` and then call `commit_transaction()`. Staged keys can't be
+//! read until the transaction commits, and `abort_transaction()` discards
+//! them.
+//!
+//! A transaction commits once its commit object is written to flash. After
+//! a power loss `initalise()` finishes publishing the staged keys of a
+//! committed transaction and discards the staged keys of any other
+//! transaction. If a transaction operation fails `recover_transaction()`
+//! does the same without restarting.
+//!
 //! # Security
 //!
 //! TicKV uses check sums to check data integrity. TicKV does not have any measures
@@ -224,6 +238,8 @@ pub use crate::flash_controller::FlashController;
 #[doc(inline)]
 pub use crate::tickv::TicKV;
 pub use crate::tickv::MAIN_KEY;
+pub use crate::tickv::{RegionCheck, RegionUsage};
+pub use crate::tickv::{TRANSACTION_BEGIN_KEY, TRANSACTION_COMMIT_KEY};
 
 // This is used to run the tests on a host
 #[cfg(test)]
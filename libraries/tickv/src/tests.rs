@@ -558,3 +558,583 @@ mod no_check_store_flast_ctrl {
         );
     }
 }
+
+/// Tests listing keys, measuring space and checking for corruption
+mod inspect_flash_ctrl {
+    use super::*;
+    use crate::tickv::{RegionCheck, RegionUsage};
+    use std::vec::Vec;
+
+    struct FlashCtrl {
+        buf: RefCell<[[u8; 256]; 4]>,
+    }
+
+    impl FlashCtrl {
+        fn new() -> Self {
+            Self {
+                buf: RefCell::new([[0xFF; 256]; 4]),
+            }
+        }
+    }
+
+    impl FlashController<256> for FlashCtrl {
+        fn read_region(
+            &self,
+            region_number: usize,
+            offset: usize,
+            buf: &mut [u8; 256],
+        ) -> Result<(), ErrorCode> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = self.buf.borrow()[region_number][offset + i]
+            }
+
+            Ok(())
+        }
+
+        fn write(&self, address: usize, buf: &[u8]) -> Result<(), ErrorCode> {
+            for (i, d) in buf.iter().enumerate() {
+                self.buf.borrow_mut()[address / 256][(address % 256) + i] = *d;
+            }
+
+            Ok(())
+        }
+
+        fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
+            for d in self.buf.borrow_mut()[region_number].iter_mut() {
+                *d = 0xFF;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn init(read_buf: &mut [u8; 256]) -> TicKV<FlashCtrl, 256> {
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        let hash = hash_function.finish();
+
+        let tickv = TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(), read_buf, 0x400);
+        tickv.initalise(hash).unwrap();
+        tickv
+    }
+
+    fn list_keys(tickv: &TicKV<FlashCtrl, 256>) -> Vec<u64> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            match tickv.next_key(&mut cursor) {
+                Ok(hash) => keys.push(hash),
+                Err(ErrorCode::KeyNotFound) => break,
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+        keys.sort_unstable();
+        keys
+    }
+
+    fn region_of(tickv: &TicKV<FlashCtrl, 256>, hash: u64) -> usize {
+        (0..tickv.num_regions())
+            .find(|region| {
+                let mut cursor = region * 256;
+                tickv.next_key(&mut cursor) == Ok(hash) && cursor / 256 == *region
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_next_key() {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = init(&mut read_buf);
+
+        let value: [u8; 32] = [0x23; 32];
+
+        println!("List keys on empty flash");
+        assert_eq!(list_keys(&tickv), vec![]);
+
+        println!("Add keys ONE, TWO and THREE");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        tickv.append_key(get_hashed_key(b"TWO"), &value).unwrap();
+        tickv.append_key(get_hashed_key(b"THREE"), &value).unwrap();
+
+        let mut expected = vec![
+            get_hashed_key(b"ONE"),
+            get_hashed_key(b"TWO"),
+            get_hashed_key(b"THREE"),
+        ];
+        expected.sort_unstable();
+        assert_eq!(list_keys(&tickv), expected);
+
+        println!("Delete Key TWO");
+        tickv.invalidate_key(get_hashed_key(b"TWO")).unwrap();
+        expected.retain(|hash| *hash != get_hashed_key(b"TWO"));
+        assert_eq!(list_keys(&tickv), expected);
+
+        println!("Iterate past the end");
+        let mut cursor = 0x400;
+        assert_eq!(tickv.next_key(&mut cursor), Err(ErrorCode::KeyNotFound));
+    }
+
+    #[test]
+    fn test_region_usage() {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = init(&mut read_buf);
+
+        let value: [u8; 32] = [0x23; 32];
+
+        println!("Add Key ONE");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+
+        let mut total = RegionUsage::default();
+        for region in 0..tickv.num_regions() {
+            let usage = tickv.region_usage(region).unwrap();
+            assert_eq!(usage.used + usage.invalidated + usage.free, 256);
+            total.keys += usage.keys;
+            total.used += usage.used;
+            total.free += usage.free;
+        }
+        // The main key and key ONE
+        assert_eq!(total.keys, 1);
+        assert_eq!(total.used, 15 + 47);
+        assert_eq!(total.free, 0x400 - 15 - 47);
+
+        println!("Delete Key ONE");
+        let region = region_of(&tickv, get_hashed_key(b"ONE"));
+        tickv.invalidate_key(get_hashed_key(b"ONE")).unwrap();
+
+        let usage = tickv.region_usage(region).unwrap();
+        assert_eq!(usage.keys, 0);
+        assert_eq!(usage.invalidated, 47);
+        assert_eq!(usage.reclaimable, 256);
+
+        println!("Garbage collect");
+        assert_eq!(tickv.garbage_collect(), Ok(256));
+        assert_eq!(
+            tickv.region_usage(region),
+            Ok(RegionUsage {
+                free: 256,
+                ..RegionUsage::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_corrupt_object() {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = init(&mut read_buf);
+
+        let value: [u8; 32] = [0x23; 32];
+        let mut buf: [u8; 32] = [0; 32];
+
+        println!("Add Key ONE");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        let region = region_of(&tickv, get_hashed_key(b"ONE"));
+
+        for region in 0..tickv.num_regions() {
+            assert_eq!(
+                tickv.check_region(region, false),
+                Ok(RegionCheck::default())
+            );
+        }
+
+        println!("Corrupt the value of Key ONE");
+        // Key ONE is the last object in its region
+        let offset = 256 - tickv.region_usage(region).unwrap().free - 47;
+        tickv.controller.buf.borrow_mut()[region][offset + 20] ^= 0x01;
+        assert_eq!(
+            tickv.get_key(get_hashed_key(b"ONE"), &mut buf),
+            Err(ErrorCode::InvalidCheckSum)
+        );
+        assert_eq!(
+            tickv.check_region(region, false),
+            Ok(RegionCheck {
+                corrupt_objects: 1,
+                damaged: false,
+                repaired: false,
+            })
+        );
+
+        println!("Repair the region");
+        assert_eq!(
+            tickv.check_region(region, true),
+            Ok(RegionCheck {
+                corrupt_objects: 1,
+                damaged: false,
+                repaired: true,
+            })
+        );
+        assert_eq!(tickv.check_region(region, true), Ok(RegionCheck::default()));
+        assert_eq!(
+            tickv.get_key(get_hashed_key(b"ONE"), &mut buf),
+            Err(ErrorCode::KeyNotFound)
+        );
+
+        println!("Add Key ONE again");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+        assert_eq!(buf, value);
+    }
+
+    #[test]
+    fn test_check_damaged_region() {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = init(&mut read_buf);
+
+        let value: [u8; 32] = [0x23; 32];
+
+        println!("Add Key ONE");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        let region = region_of(&tickv, get_hashed_key(b"ONE"));
+        let empty = (0..tickv.num_regions())
+            .find(|r| tickv.region_usage(*r).unwrap().free == 256)
+            .unwrap();
+
+        println!("Write part of a header to an empty region");
+        tickv.controller.buf.borrow_mut()[empty][5] = 0x12;
+        let damaged = RegionCheck {
+            corrupt_objects: 0,
+            damaged: true,
+            repaired: false,
+        };
+        assert_eq!(tickv.check_region(empty, false), Ok(damaged));
+
+        println!("Repair the empty region by erasing it");
+        assert_eq!(
+            tickv.check_region(empty, true),
+            Ok(RegionCheck {
+                repaired: true,
+                ..damaged
+            })
+        );
+        assert_eq!(tickv.check_region(empty, false), Ok(RegionCheck::default()));
+
+        println!("Damage the region holding Key ONE");
+        let end = 256 - tickv.region_usage(region).unwrap().free;
+        tickv.controller.buf.borrow_mut()[region][end + 3] = 0x00;
+        assert_eq!(tickv.check_region(region, false), Ok(damaged));
+
+        println!("Key ONE is kept, so the region can't be repaired");
+        assert_eq!(tickv.check_region(region, true), Ok(damaged));
+        assert_eq!(list_keys(&tickv), vec![get_hashed_key(b"ONE")]);
+    }
+}
+
+/// Tests transactions, losing power at every flash operation
+mod power_fail_flash_ctrl {
+    use super::*;
+    use crate::success_codes::SuccessCode;
+    use crate::tickv::RegionCheck;
+
+    type Flash = [[u8; 256]; 4];
+
+    /// A flash controller that loses power before the `fail_at` write or
+    /// erase. That operation and every one after it fails.
+    struct FlashCtrl {
+        buf: RefCell<Flash>,
+        ops: Cell<usize>,
+        fail_at: Cell<usize>,
+        /// The `fail_at` write is cut short instead, only its first half
+        /// reaches the flash
+        tear: bool,
+    }
+
+    impl FlashCtrl {
+        fn new(buf: Flash, fail_at: usize, tear: bool) -> Self {
+            Self {
+                buf: RefCell::new(buf),
+                ops: Cell::new(0),
+                fail_at: Cell::new(fail_at),
+                tear,
+            }
+        }
+
+        fn program(&self, address: usize, buf: &[u8]) {
+            for (i, d) in buf.iter().enumerate() {
+                // Writes can only clear bits
+                self.buf.borrow_mut()[address / 256][(address % 256) + i] &= *d;
+            }
+        }
+
+        fn power_lost(&self) -> bool {
+            let op = self.ops.get();
+            self.ops.set(op + 1);
+            op >= self.fail_at.get()
+        }
+    }
+
+    impl FlashController<256> for FlashCtrl {
+        fn read_region(
+            &self,
+            region_number: usize,
+            offset: usize,
+            buf: &mut [u8; 256],
+        ) -> Result<(), ErrorCode> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = self.buf.borrow()[region_number][offset + i]
+            }
+
+            Ok(())
+        }
+
+        fn write(&self, address: usize, buf: &[u8]) -> Result<(), ErrorCode> {
+            if self.power_lost() {
+                if self.tear && self.ops.get() == self.fail_at.get() + 1 {
+                    self.program(address, &buf[..buf.len() / 2]);
+                }
+                return Err(ErrorCode::WriteFail);
+            }
+
+            self.program(address, buf);
+            Ok(())
+        }
+
+        fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
+            if self.power_lost() {
+                return Err(ErrorCode::EraseFail);
+            }
+
+            for d in self.buf.borrow_mut()[region_number].iter_mut() {
+                *d = 0xFF;
+            }
+
+            Ok(())
+        }
+    }
+
+    const OLD: [u8; 16] = [0x11; 16];
+    const NEW: [u8; 16] = [0x22; 16];
+
+    fn main_key() -> u64 {
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        hash_function.finish()
+    }
+
+    /// Start TicKV on `flash`, which finishes any interrupted transaction.
+    fn start(
+        read_buf: &mut [u8; 256],
+        flash: Flash,
+        fail_at: usize,
+        tear: bool,
+    ) -> (TicKV<FlashCtrl, 256>, Result<SuccessCode, ErrorCode>) {
+        let tickv =
+            TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(flash, fail_at, tear), read_buf, 0x400);
+        let ret = tickv.initalise(main_key());
+        (tickv, ret)
+    }
+
+    /// Start TicKV on `flash` without losing power.
+    fn boot(read_buf: &mut [u8; 256], flash: Flash, fail_at: usize) -> TicKV<FlashCtrl, 256> {
+        let (tickv, ret) = start(read_buf, flash, fail_at, false);
+        ret.unwrap();
+        tickv
+    }
+
+    /// Add keys ONE and TWO with the old value, with some invalidated data
+    /// that has been garbage collected.
+    fn setup() -> Flash {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = boot(&mut read_buf, [[0xFF; 256]; 4], usize::MAX);
+
+        tickv.append_key(get_hashed_key(b"ONE"), &OLD).unwrap();
+        tickv.append_key(get_hashed_key(b"TWO"), &OLD).unwrap();
+        tickv.append_key(get_hashed_key(b"GONE"), &OLD).unwrap();
+        tickv.invalidate_key(get_hashed_key(b"GONE")).unwrap();
+        tickv.garbage_collect().unwrap();
+
+        let flash = *tickv.controller.buf.borrow();
+        flash
+    }
+
+    /// Replace keys ONE and TWO and add key THREE in a transaction.
+    ///
+    /// Returns whether the transaction was started and committed before
+    /// the power was lost.
+    fn transaction(tickv: &TicKV<FlashCtrl, 256>) -> Result<(), (bool, bool)> {
+        tickv.begin_transaction().map_err(|_| (false, false))?;
+        for key in [b"ONE" as &[u8], b"TWO", b"THREE"].iter() {
+            tickv
+                .stage_key(get_hashed_key(key), &NEW)
+                .map_err(|_| (true, false))?;
+        }
+        tickv.commit_transaction().map_err(|_| (true, true))?;
+        Ok(())
+    }
+
+    /// Check that either all of the transaction is visible or none of it.
+    ///
+    /// Returns true if the transaction is visible.
+    fn check(tickv: &TicKV<FlashCtrl, 256>) -> bool {
+        let mut buf: [u8; 16] = [0; 16];
+
+        tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+        let committed = buf == NEW;
+
+        tickv.get_key(get_hashed_key(b"TWO"), &mut buf).unwrap();
+        assert_eq!(buf, if committed { NEW } else { OLD });
+
+        if committed {
+            tickv.get_key(get_hashed_key(b"THREE"), &mut buf).unwrap();
+            assert_eq!(buf, NEW);
+        } else {
+            assert_eq!(
+                tickv.get_key(get_hashed_key(b"THREE"), &mut buf),
+                Err(ErrorCode::KeyNotFound)
+            );
+        }
+
+        for region in 0..tickv.num_regions() {
+            assert_eq!(
+                tickv.check_region(region, false),
+                Ok(RegionCheck::default())
+            );
+        }
+
+        committed
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = boot(&mut read_buf, setup(), usize::MAX);
+        let mut buf: [u8; 16] = [0; 16];
+
+        println!("Stage keys before beginning a transaction");
+        assert_eq!(
+            tickv.stage_key(get_hashed_key(b"ONE"), &NEW),
+            Err(ErrorCode::NoTransaction)
+        );
+        assert_eq!(tickv.commit_transaction(), Err(ErrorCode::NoTransaction));
+
+        println!("Begin a transaction");
+        assert_eq!(tickv.begin_transaction(), Ok(SuccessCode::Written));
+        assert_eq!(
+            tickv.begin_transaction(),
+            Err(ErrorCode::TransactionInProgress)
+        );
+
+        println!("Stage keys ONE, TWO and THREE");
+        tickv.stage_key(get_hashed_key(b"ONE"), &NEW).unwrap();
+        tickv.stage_key(get_hashed_key(b"TWO"), &NEW).unwrap();
+        tickv.stage_key(get_hashed_key(b"THREE"), &NEW).unwrap();
+        assert_eq!(
+            tickv.stage_key(get_hashed_key(b"THREE"), &NEW),
+            Err(ErrorCode::KeyAlreadyExists)
+        );
+
+        tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+        assert_eq!(buf, OLD);
+        assert_eq!(
+            tickv.get_key(get_hashed_key(b"THREE"), &mut buf),
+            Err(ErrorCode::KeyNotFound)
+        );
+
+        let mut cursor = 0;
+        let mut keys = 0;
+        while tickv.next_key(&mut cursor).is_ok() {
+            keys += 1;
+        }
+        assert_eq!(keys, 2);
+
+        println!("Commit");
+        tickv.commit_transaction().unwrap();
+        assert!(check(&tickv));
+
+        println!("Recover without a transaction");
+        assert_eq!(tickv.recover_transaction(), Ok(SuccessCode::Complete));
+        assert!(check(&tickv));
+    }
+
+    #[test]
+    fn test_abort_transaction() {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = boot(&mut read_buf, setup(), usize::MAX);
+
+        tickv.begin_transaction().unwrap();
+        tickv.stage_key(get_hashed_key(b"ONE"), &NEW).unwrap();
+        tickv.stage_key(get_hashed_key(b"TWO"), &NEW).unwrap();
+        tickv.stage_key(get_hashed_key(b"THREE"), &NEW).unwrap();
+
+        println!("Abort");
+        tickv.abort_transaction().unwrap();
+        assert!(!check(&tickv));
+        assert_eq!(tickv.abort_transaction(), Err(ErrorCode::NoTransaction));
+
+        println!("Commit a new transaction");
+        tickv.begin_transaction().unwrap();
+        tickv.stage_key(get_hashed_key(b"ONE"), &NEW).unwrap();
+        tickv.stage_key(get_hashed_key(b"TWO"), &NEW).unwrap();
+        tickv.stage_key(get_hashed_key(b"THREE"), &NEW).unwrap();
+        tickv.commit_transaction().unwrap();
+        assert!(check(&tickv));
+    }
+
+    /// Invalidate the objects left corrupt by torn writes, as
+    /// `check_consistency()` does.
+    fn repair(tickv: &TicKV<FlashCtrl, 256>) {
+        for region in 0..tickv.num_regions() {
+            while tickv.check_region(region, true).unwrap().repaired {}
+        }
+    }
+
+    /// Lose power at every flash operation of a transaction, and then at
+    /// every flash operation of the recovery.
+    fn power_fail(tear: bool) {
+        let flash = setup();
+
+        // Count the flash operations used by the transaction
+        let total = {
+            let mut read_buf: [u8; 256] = [0; 256];
+            let tickv = boot(&mut read_buf, flash, usize::MAX);
+            transaction(&tickv).unwrap();
+            tickv.controller.ops.get()
+        };
+
+        for fail_at in 0..=total {
+            println!("Lose power before operation {}", fail_at);
+            let mut read_buf: [u8; 256] = [0; 256];
+            let (tickv, _) = start(&mut read_buf, flash, fail_at, tear);
+            let ret = transaction(&tickv);
+            let crashed = *tickv.controller.buf.borrow();
+
+            // Check the result of the recovery on `recovered`, once it has
+            // booted without losing power.
+            let verify = |recovered: Flash| {
+                let mut read_buf: [u8; 256] = [0; 256];
+                let tickv = boot(&mut read_buf, recovered, usize::MAX);
+                if tear {
+                    repair(&tickv);
+                }
+                let committed = check(&tickv);
+                match ret {
+                    Ok(()) => assert!(committed),
+                    Err((_, false)) => assert!(!committed),
+                    Err((_, true)) => {}
+                }
+
+                println!("Start a new transaction after recovering");
+                tickv.begin_transaction().unwrap();
+                tickv.abort_transaction().unwrap();
+            };
+
+            // Lose power again at every point of the recovery
+            for recover_fail_at in 0.. {
+                let mut read_buf: [u8; 256] = [0; 256];
+                let (tickv, recovered) = start(&mut read_buf, crashed, recover_fail_at, tear);
+                verify(*tickv.controller.buf.borrow());
+                if recovered.is_ok() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_power_fail() {
+        power_fail(false);
+    }
+
+    #[test]
+    fn test_torn_write() {
+        power_fail(true);
+    }
+}
@@ -33,6 +33,20 @@ pub(crate) enum RubbishState {
     EraseRegion(usize),
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum CheckState {
+    ReadRegion(usize),
+    EraseRegion(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum TransactionState {
+    /// Between the steps of a transaction operation
+    Step,
+    /// Trying to read a region
+    ReadRegion(usize),
+}
+
 #[derive(Clone, Copy, PartialEq)]
 /// The current state machine when trying to complete a previous operation.
 /// This is used when returning from a complete async `FlashController` call.
@@ -49,6 +63,44 @@ pub(crate) enum State {
     InvalidateKey(KeyState),
     /// Running garbage collection
     GarbageCollect(RubbishState),
+    /// Finding the next key
+    NextKey(KeyState),
+    /// Measuring the space used in a region
+    RegionUsage(KeyState),
+    /// Checking a region for corruption
+    CheckRegion(CheckState),
+    /// Staging a key in a transaction
+    StageKey(KeyState),
+    /// Beginning, committing, aborting or recovering a transaction
+    Transaction(TransactionState),
+}
+
+/// The step a transaction operation is at. Every step can be repeated after a
+/// power loss, as it is decided by what is in flash.
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    /// No transaction operation is in progress
+    Idle,
+    /// Writing the begin object
+    Begin,
+    /// Looking for the begin object when recovering
+    FindBegin,
+    /// Looking for the commit object when recovering
+    FindCommit,
+    /// Writing the commit object
+    Commit,
+    /// Finding the next pending object. It is published if `true`, or
+    /// invalidated if `false`.
+    Scan(bool),
+    /// Invalidating the committed object with the same key as the pending
+    /// object at the address
+    ReplaceOld(u64, usize),
+    /// Clearing the pending flag of the object at the address
+    Publish(usize),
+    /// Invalidating the commit object
+    RemoveCommit,
+    /// Invalidating the begin object
+    RemoveBegin,
 }
 
 /// The struct storing all of the TicKV information.
@@ -58,6 +110,51 @@ pub struct TicKV<'a, C: FlashController<S>, const S: usize> {
     flash_size: usize,
     pub(crate) read_buffer: Cell<Option<&'a mut [u8; S]>>,
     pub(crate) state: Cell<State>,
+    /// The hashed main key, which is skipped when listing keys
+    main_key: Cell<u64>,
+    /// A transaction has been started and keys can be staged
+    transaction_open: Cell<bool>,
+    phase: Cell<Phase>,
+    /// The flash address to continue looking for pending objects from
+    pending_cursor: Cell<usize>,
+}
+
+/// The space used in a single region, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegionUsage {
+    /// The number of valid keys, not including the main key, the transaction
+    /// objects or keys staged in a transaction
+    pub keys: usize,
+    /// Space used by valid objects, including headers and check sums
+    pub used: usize,
+    /// Space used by invalidated objects
+    pub invalidated: usize,
+    /// Space after the last object that can hold new objects
+    pub free: usize,
+    /// Space that `garbage_collect()` would free. A region is only erased
+    /// once all of its objects have been invalidated.
+    pub reclaimable: usize,
+}
+
+/// The result of checking a region with `check_region()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegionCheck {
+    /// The number of valid objects whose check sum doesn't match
+    pub corrupt_objects: usize,
+    /// The objects in the region can't all be walked, or data was found
+    /// after the last object. New objects can't be added to the region.
+    pub damaged: bool,
+    /// A problem was repaired. The region should be checked again as only
+    /// one problem is repaired at a time.
+    pub repaired: bool,
+}
+
+/// An object found while walking a region
+struct Object {
+    length: usize,
+    valid: bool,
+    pending: bool,
+    hash: u64,
 }
 
 /// This is the current object header used for TicKV objects
@@ -71,13 +168,18 @@ struct ObjectHeader {
 }
 
 pub(crate) const FLAGS_VALID: u8 = 8;
+/// Set on objects staged in a transaction until the transaction commits
+pub(crate) const FLAGS_PENDING: u8 = 4;
+/// Set on objects written in a transaction. Their check sum is calculated
+/// with `FLAGS_PENDING` set, so it still matches once that is cleared.
+pub(crate) const FLAGS_TRANSACTION: u8 = 2;
 
 impl ObjectHeader {
-    fn new(hashed_key: u64, len: u16) -> Self {
+    fn new(hashed_key: u64, len: u16, flags: u8) -> Self {
         assert!(len < 0xFFF);
         Self {
             version: VERSION,
-            flags: FLAGS_VALID,
+            flags,
             len,
             hashed_key,
         }
@@ -95,6 +197,12 @@ pub(crate) const CHECK_SUM_LEN: usize = 4;
 /// `initalise()`.
 pub const MAIN_KEY: &[u8; 15] = b"tickv-super-key";
 
+/// The hashed key of the object written when a transaction begins.
+pub const TRANSACTION_BEGIN_KEY: u64 = 0x7469_636b_762d_7462;
+
+/// The hashed key of the object written when a transaction commits.
+pub const TRANSACTION_COMMIT_KEY: u64 = 0x7469_636b_762d_7463;
+
 /// This is the main TicKV struct.
 impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     /// Create a new struct
@@ -109,6 +217,10 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
             flash_size,
             read_buffer: Cell::new(Some(read_buffer)),
             state: Cell::new(State::None),
+            main_key: Cell::new(0),
+            transaction_open: Cell::new(false),
+            phase: Cell::new(Phase::Idle),
+            pending_cursor: Cell::new(0),
         }
     }
 
@@ -118,12 +230,14 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     /// `hashed_main_key`: The u64 hash of the const string `MAIN_KEY`.
     ///
     /// If the specified region has not already been setup for TicKV
-    /// the entire region will be erased.
+    /// the entire region will be erased. Otherwise any transaction
+    /// interrupted by a power loss is finished, see `recover_transaction()`.
     ///
     /// On success nothing will be returned.
     /// On error a `ErrorCode` will be returned.
     pub fn initalise(&self, hashed_main_key: u64) -> Result<SuccessCode, ErrorCode> {
         let mut buf: [u8; 0] = [0; 0];
+        self.main_key.set(hashed_main_key);
 
         let key_ret = match self.state.get() {
             State::None => self.get_key(hashed_main_key, &mut buf),
@@ -135,7 +249,10 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         };
 
         match key_ret {
-            Ok(ret) => Ok(ret),
+            Ok(_) => {
+                self.state.set(State::None);
+                self.recover_transaction()
+            }
             Err(e) => {
                 match e {
                     ErrorCode::ReadNotReady(reg) => {
@@ -243,6 +360,9 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
 
     /// Find a key in some loaded region data.
     ///
+    /// `pending`: Find a pending object staged in a transaction instead of a
+    ///            committed object.
+    ///
     /// On success return the offset in the region_data where the key is and the
     /// total length of the key.
     /// On failure return a bool indicating if the caller should keep looking in
@@ -251,6 +371,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         &self,
         hash: u64,
         region_data: &[u8],
+        pending: bool,
     ) -> Result<(usize, u16), (bool, ErrorCode)> {
         // Determine the total size of our payload
 
@@ -293,6 +414,12 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     continue;
                 }
 
+                // Skip pending entries unless we are looking for them
+                if (region_data[offset + LEN_OFFSET] & (FLAGS_PENDING << 4) != 0) != pending {
+                    offset += total_length as usize;
+                    continue;
+                }
+
                 // We have found a valid entry, see if it is ours.
                 if region_data[offset + HASH_OFFSET] != hash[7]
                     || region_data[offset + HASH_OFFSET + 1] != hash[6]
@@ -326,6 +453,28 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     /// On success nothing will be returned.
     /// On error a `ErrorCode` will be returned.
     pub fn append_key(&self, hash: u64, value: &[u8]) -> Result<SuccessCode, ErrorCode> {
+        match self.append_object(hash, value, FLAGS_VALID, |reg| {
+            State::AppendKey(KeyState::ReadRegion(reg))
+        }) {
+            Err(ErrorCode::WriteNotReady(_)) => Ok(SuccessCode::Queued),
+            ret => ret,
+        }
+    }
+
+    /// Appends an object with `flags` to flash storage.
+    ///
+    /// `read_state`: The state to continue from once a region has been read.
+    ///
+    /// On success `SuccessCode::Written` will be returned.
+    /// On error a `ErrorCode` will be returned, including
+    /// `ErrorCode::WriteNotReady` if the write was queued.
+    fn append_object(
+        &self,
+        hash: u64,
+        value: &[u8],
+        flags: u8,
+        read_state: fn(usize) -> State,
+    ) -> Result<SuccessCode, ErrorCode> {
         let region = self.get_region(hash);
         let crc = crc32::Crc::new();
         let mut check_sum = crc.digest();
@@ -339,13 +488,15 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         }
 
         // Create the header:
-        let header = ObjectHeader::new(hash, object_length as u16);
+        let header = ObjectHeader::new(hash, object_length as u16, flags);
 
         let mut region_offset: isize = 0;
 
         loop {
             let new_region = match self.state.get() {
-                State::None => region as isize + region_offset,
+                State::None | State::Transaction(TransactionState::Step) => {
+                    region as isize + region_offset
+                }
                 State::Init(state) => {
                     match state {
                         InitState::AppendKeyReadRegion(reg) => reg as isize,
@@ -355,15 +506,15 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                         }
                     }
                 }
-                State::AppendKey(key_state) => match key_state {
-                    KeyState::ReadRegion(reg) => reg as isize,
-                },
+                State::AppendKey(KeyState::ReadRegion(reg))
+                | State::StageKey(KeyState::ReadRegion(reg))
+                | State::Transaction(TransactionState::ReadRegion(reg)) => reg as isize,
                 State::GarbageCollect(RubbishState::ReadRegion(reg)) => reg as isize,
                 _ => unreachable!(),
             };
 
             let mut region_data = self.read_buffer.take().unwrap();
-            if self.state.get() != State::AppendKey(KeyState::ReadRegion(new_region as usize))
+            if self.state.get() != read_state(new_region as usize)
                 && self.state.get()
                     != State::Init(InitState::AppendKeyReadRegion(new_region as usize))
             {
@@ -375,14 +526,17 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     Err(e) => {
                         self.read_buffer.replace(Some(region_data));
                         if let ErrorCode::ReadNotReady(reg) = e {
-                            self.state.set(State::AppendKey(KeyState::ReadRegion(reg)));
+                            self.state.set(read_state(reg));
                         }
                         return Err(e);
                     }
                 };
             }
 
-            if self.find_key_offset(hash, region_data).is_ok() {
+            if self
+                .find_key_offset(hash, region_data, flags & FLAGS_PENDING != 0)
+                .is_ok()
+            {
                 // Check to make sure we don't already have this key
                 self.read_buffer.replace(Some(region_data));
                 return Err(ErrorCode::KeyAlreadyExists);
@@ -503,10 +657,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     &region_data[offset..(offset + package_length + CHECK_SUM_LEN)],
                 ) {
                     self.read_buffer.replace(Some(region_data));
-                    match e {
-                        ErrorCode::WriteNotReady(_) => return Ok(SuccessCode::Queued),
-                        _ => return Err(e),
-                    }
+                    return Err(e);
                 }
 
                 self.read_buffer.replace(Some(region_data));
@@ -531,8 +682,6 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         let mut region_offset: isize = 0;
 
         loop {
-            let crc = crc32::Crc::new();
-            let mut check_sum = crc.digest();
             let new_region = match self.state.get() {
                 State::None => region as isize + region_offset,
                 State::Init(state) => {
@@ -570,11 +719,8 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                 };
             }
 
-            match self.find_key_offset(hash, region_data) {
+            match self.find_key_offset(hash, region_data, false) {
                 Ok((offset, total_length)) => {
-                    // Add the header data to the check hash
-                    check_sum.update(&region_data[offset..(HEADER_LENGTH + offset)]);
-
                     // Make sure if will fit in the buffer
                     if buf.len() < (total_length as usize - HEADER_LENGTH - CHECK_SUM_LEN) {
                         self.read_buffer.replace(Some(region_data));
@@ -586,18 +732,10 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     // Copy in the value
                     for i in 0..(total_length as usize - HEADER_LENGTH - CHECK_SUM_LEN) {
                         buf[i] = region_data[offset + HEADER_LENGTH + i];
-                        check_sum.update(&[buf[i]])
                     }
 
                     // Check the hash
-                    let check_sum = check_sum.finalise();
-                    let check_sum = check_sum.to_ne_bytes();
-
-                    if check_sum[3] != region_data[offset + total_length as usize - 1]
-                        || check_sum[2] != region_data[offset + total_length as usize - 2]
-                        || check_sum[1] != region_data[offset + total_length as usize - 3]
-                        || check_sum[0] != region_data[offset + total_length as usize - 4]
-                    {
+                    if !Self::check_sum_matches(region_data, offset, total_length as usize) {
                         self.read_buffer.replace(Some(region_data));
                         return Err(ErrorCode::InvalidCheckSum);
                     }
@@ -668,7 +806,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                 };
             }
 
-            match self.find_key_offset(hash, region_data) {
+            match self.find_key_offset(hash, region_data, false) {
                 Ok((offset, _data_len)) => {
                     // We found a key, let's delete it
                     region_data[offset + LEN_OFFSET] &= !0x80;
@@ -817,4 +955,639 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
 
         Ok(flash_freed)
     }
+
+    /// The number of regions used by TicKV
+    pub fn num_regions(&self) -> usize {
+        self.flash_size / S
+    }
+
+    /// Read `region` into the read buffer, unless it was already provided
+    /// while continuing `state`.
+    fn load_region(&self, region: usize, state: State) -> Result<&'a mut [u8; S], ErrorCode> {
+        let region_data = self.read_buffer.take().unwrap();
+        if self.state.get() != state {
+            if let Err(e) = self.controller.read_region(region, 0, region_data) {
+                self.read_buffer.replace(Some(region_data));
+                if let ErrorCode::ReadNotReady(_) = e {
+                    self.state.set(state);
+                }
+                return Err(e);
+            }
+        }
+        Ok(region_data)
+    }
+
+    /// Whether `hash` is used by TicKV itself rather than for a stored value.
+    fn is_internal_key(&self, hash: u64) -> bool {
+        hash == self.main_key.get()
+            || hash == TRANSACTION_BEGIN_KEY
+            || hash == TRANSACTION_COMMIT_KEY
+    }
+
+    /// Parse the object header at `offset` in some loaded region data.
+    ///
+    /// Returns `None` if there are no more objects in the region.
+    fn read_object(region_data: &[u8; S], offset: usize) -> Result<Option<Object>, ErrorCode> {
+        if offset + HEADER_LENGTH >= S || region_data[offset + VERSION_OFFSET] == 0xFF {
+            return Ok(None);
+        }
+
+        if region_data[offset + VERSION_OFFSET] != VERSION {
+            return Err(ErrorCode::UnsupportedVersion);
+        }
+
+        let length = (((region_data[offset + LEN_OFFSET] as u16) & !0xF0) << 8
+            | region_data[offset + LEN_OFFSET + 1] as u16) as usize;
+        if length < HEADER_LENGTH + CHECK_SUM_LEN || offset + length > S {
+            return Err(ErrorCode::CorruptData);
+        }
+
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&region_data[offset + HASH_OFFSET..offset + HASH_OFFSET + 8]);
+
+        Ok(Some(Object {
+            length,
+            valid: region_data[offset + LEN_OFFSET] & 0x80 == 0x80,
+            pending: region_data[offset + LEN_OFFSET] & (FLAGS_PENDING << 4) != 0,
+            hash: u64::from_be_bytes(hash),
+        }))
+    }
+
+    /// Check the check sum of the valid object at `offset`.
+    fn check_sum_matches(region_data: &[u8; S], offset: usize, length: usize) -> bool {
+        let mut flags = region_data[offset + LEN_OFFSET];
+        if flags & (FLAGS_TRANSACTION << 4) != 0 {
+            // The check sum was calculated before the object was published
+            flags |= FLAGS_PENDING << 4;
+        }
+
+        let crc = crc32::Crc::new();
+        let mut check_sum = crc.digest();
+        check_sum.update(&region_data[offset..(offset + LEN_OFFSET)]);
+        check_sum.update(&[flags]);
+        check_sum
+            .update(&region_data[(offset + LEN_OFFSET + 1)..(offset + length - CHECK_SUM_LEN)]);
+        let check_sum = check_sum.finalise().to_ne_bytes();
+
+        region_data[(offset + length - CHECK_SUM_LEN)..(offset + length)] == check_sum
+    }
+
+    /// Find the next valid key in flash storage.
+    ///
+    /// `cursor`: The position to continue from. Start with a cursor of zero;
+    ///           on success it is moved past the returned key. If an async
+    ///           operation is pending the same cursor must be used when
+    ///           calling this again.
+    ///
+    /// On success the hashed key will be returned. The main key, the
+    /// transaction objects and keys staged in a transaction are skipped.
+    /// Once there are no more keys `ErrorCode::KeyNotFound` is returned.
+    ///
+    /// Regions that can't be walked are skipped, use `check_region()` to
+    /// find them.
+    pub fn next_key(&self, cursor: &mut usize) -> Result<u64, ErrorCode> {
+        loop {
+            let region = *cursor / S;
+            if region >= self.num_regions() {
+                return Err(ErrorCode::KeyNotFound);
+            }
+
+            let region_data =
+                self.load_region(region, State::NextKey(KeyState::ReadRegion(region)))?;
+
+            let mut offset = *cursor % S;
+            let found = loop {
+                match Self::read_object(region_data, offset) {
+                    Ok(Some(object)) => {
+                        offset += object.length;
+                        if object.valid && !object.pending && !self.is_internal_key(object.hash) {
+                            break Some(object.hash);
+                        }
+                    }
+                    Ok(None) | Err(_) => break None,
+                }
+            };
+
+            self.read_buffer.replace(Some(region_data));
+
+            match found {
+                Some(hash) => {
+                    *cursor = region * S + offset;
+                    return Ok(hash);
+                }
+                None => *cursor = (region + 1) * S,
+            }
+        }
+    }
+
+    /// Measure the space used in `region`.
+    ///
+    /// On success the `RegionUsage` will be returned.
+    /// On error a `ErrorCode` will be returned. `ErrorCode::CorruptData` and
+    /// `ErrorCode::UnsupportedVersion` indicate the region can't be walked.
+    pub fn region_usage(&self, region: usize) -> Result<RegionUsage, ErrorCode> {
+        let region_data =
+            self.load_region(region, State::RegionUsage(KeyState::ReadRegion(region)))?;
+
+        let mut usage = RegionUsage::default();
+        let mut valid_found = false;
+        let mut offset: usize = 0;
+
+        loop {
+            match Self::read_object(region_data, offset) {
+                Ok(Some(object)) => {
+                    if object.valid {
+                        valid_found = true;
+                        usage.used += object.length;
+                        if !object.pending && !self.is_internal_key(object.hash) {
+                            usage.keys += 1;
+                        }
+                    } else {
+                        usage.invalidated += object.length;
+                    }
+                    offset += object.length;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    self.read_buffer.replace(Some(region_data));
+                    return Err(e);
+                }
+            }
+        }
+
+        self.read_buffer.replace(Some(region_data));
+
+        usage.free = S - offset;
+        if !valid_found && usage.invalidated > 0 {
+            usage.reclaimable = S;
+        }
+
+        Ok(usage)
+    }
+
+    /// Check `region` for corruption.
+    ///
+    /// Every valid object has its check sum verified and the space after the
+    /// last object must not have been written to.
+    ///
+    /// `repair`: Fix the first problem found. A valid object with a bad check
+    ///           sum is invalidated, so it can be replaced and reclaimed. A
+    ///           damaged region is erased if it holds no intact valid
+    ///           objects.
+    ///
+    /// On success a `RegionCheck` describing the region will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn check_region(&self, region: usize, repair: bool) -> Result<RegionCheck, ErrorCode> {
+        if self.state.get() == State::CheckRegion(CheckState::EraseRegion(region)) {
+            // We were waiting to erase this region, it is now empty
+            return Ok(RegionCheck {
+                corrupt_objects: 0,
+                damaged: true,
+                repaired: true,
+            });
+        }
+
+        let region_data =
+            self.load_region(region, State::CheckRegion(CheckState::ReadRegion(region)))?;
+
+        let mut check = RegionCheck::default();
+        let mut first_corrupt = None;
+        let mut intact_found = false;
+        let mut offset: usize = 0;
+
+        loop {
+            match Self::read_object(region_data, offset) {
+                Ok(Some(object)) => {
+                    if object.valid {
+                        if Self::check_sum_matches(region_data, offset, object.length) {
+                            intact_found = true;
+                        } else {
+                            check.corrupt_objects += 1;
+                            first_corrupt.get_or_insert(offset);
+                        }
+                    }
+                    offset += object.length;
+                }
+                Ok(None) => {
+                    // Anything written after the last object, such as a
+                    // partially written header, stops new objects from
+                    // being added.
+                    check.damaged = region_data[offset..].iter().any(|b| *b != 0xFF);
+                    break;
+                }
+                Err(_) => {
+                    check.damaged = true;
+                    break;
+                }
+            }
+        }
+
+        if !repair {
+            self.read_buffer.replace(Some(region_data));
+            return Ok(check);
+        }
+
+        if let Some(offset) = first_corrupt {
+            // Invalidate the object, the same as `invalidate_key()`
+            region_data[offset + LEN_OFFSET] &= !0x80;
+
+            let ret = self.controller.write(
+                S * region + offset + LEN_OFFSET,
+                &region_data[offset + LEN_OFFSET..offset + LEN_OFFSET + 1],
+            );
+            self.read_buffer.replace(Some(region_data));
+            match ret {
+                Ok(()) | Err(ErrorCode::WriteNotReady(_)) => {
+                    check.repaired = true;
+                    return Ok(check);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.read_buffer.replace(Some(region_data));
+
+        if check.damaged && !intact_found {
+            if let Err(e) = self.controller.erase_region(region) {
+                if let ErrorCode::EraseNotReady(reg) = e {
+                    self.state
+                        .set(State::CheckRegion(CheckState::EraseRegion(reg)));
+                }
+                return Err(e);
+            }
+            check.repaired = true;
+        }
+
+        Ok(check)
+    }
+
+    /// Start a transaction.
+    ///
+    /// Keys added with `stage_key()` are not visible until
+    /// `commit_transaction()` completes. If power is lost before the
+    /// transaction commits none of the staged keys become visible, see
+    /// `recover_transaction()`.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned.
+    /// `ErrorCode::TransactionInProgress` indicates a transaction was already
+    /// started, possibly before a power loss.
+    pub fn begin_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        if self.phase.get() == Phase::Idle {
+            if self.transaction_open.get() {
+                return Err(ErrorCode::TransactionInProgress);
+            }
+            self.start_phase(Phase::Begin);
+        }
+        self.continue_transaction()
+    }
+
+    /// Stage a key/value pair in the current transaction.
+    ///
+    /// `hash`: A hashed key. If the key already exists the old value is
+    ///         replaced when the transaction commits.
+    /// `value`: A buffer containing the data to be stored to flash.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned. `ErrorCode::KeyAlreadyExists`
+    /// indicates the key has already been staged.
+    pub fn stage_key(&self, hash: u64, value: &[u8]) -> Result<SuccessCode, ErrorCode> {
+        if !self.transaction_open.get() {
+            return Err(ErrorCode::NoTransaction);
+        }
+
+        match self.append_object(
+            hash,
+            value,
+            FLAGS_VALID | FLAGS_PENDING | FLAGS_TRANSACTION,
+            |reg| State::StageKey(KeyState::ReadRegion(reg)),
+        ) {
+            Err(ErrorCode::WriteNotReady(_)) => Ok(SuccessCode::Queued),
+            ret => ret,
+        }
+    }
+
+    /// Commit the current transaction, making all of the staged keys
+    /// visible.
+    ///
+    /// This writes a commit object and then publishes the staged keys one at
+    /// a time. If an async write is started `ErrorCode::WriteNotReady` is
+    /// returned and this should be called again once it has completed.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn commit_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        if self.phase.get() == Phase::Idle {
+            if !self.transaction_open.get() {
+                return Err(ErrorCode::NoTransaction);
+            }
+            self.start_phase(Phase::Commit);
+        }
+        self.continue_transaction()
+    }
+
+    /// Abort the current transaction, discarding all of the staged keys.
+    ///
+    /// If an async write is started `ErrorCode::WriteNotReady` is returned
+    /// and this should be called again once it has completed.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn abort_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        if self.phase.get() == Phase::Idle {
+            if !self.transaction_open.get() {
+                return Err(ErrorCode::NoTransaction);
+            }
+            self.start_phase(Phase::Scan(false));
+        }
+        self.continue_transaction()
+    }
+
+    /// Finish a transaction interrupted by a power loss or an error.
+    ///
+    /// `initalise()` calls this, so it only needs to be called after a
+    /// transaction operation failed. If the transaction had committed the staged keys are published,
+    /// otherwise they are discarded. If there was no transaction nothing is
+    /// changed.
+    ///
+    /// If an async write is started `ErrorCode::WriteNotReady` is returned
+    /// and this should be called again once it has completed.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn recover_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        if self.phase.get() == Phase::Idle {
+            self.start_phase(Phase::FindBegin);
+        }
+        self.continue_transaction()
+    }
+
+    fn start_phase(&self, phase: Phase) {
+        // Scans continue from the last pending object
+        if matches!(phase, Phase::Scan(_))
+            && !matches!(self.phase.get(), Phase::Scan(_) | Phase::Publish(_))
+        {
+            self.pending_cursor.set(0);
+        }
+        self.phase.set(phase);
+        self.state.set(State::Transaction(TransactionState::Step));
+    }
+
+    /// Move on to `phase` after the write that returned `ret`.
+    ///
+    /// Returns `Some` if `continue_transaction()` should return.
+    fn written(
+        &self,
+        ret: Result<SuccessCode, ErrorCode>,
+        phase: Phase,
+    ) -> Option<Result<SuccessCode, ErrorCode>> {
+        match ret {
+            Ok(_) => {
+                self.start_phase(phase);
+                None
+            }
+            Err(ErrorCode::WriteNotReady(address)) => {
+                self.start_phase(phase);
+                Some(Err(ErrorCode::WriteNotReady(address)))
+            }
+            Err(e) => Some(self.transaction_error(e)),
+        }
+    }
+
+    fn transaction_error(&self, e: ErrorCode) -> Result<SuccessCode, ErrorCode> {
+        // On `ReadNotReady` the state was set when starting the read. Other
+        // errors stop the operation, it can be started again or finished by
+        // `recover_transaction()`.
+        if !matches!(e, ErrorCode::ReadNotReady(_)) {
+            self.phase.set(Phase::Idle);
+            self.state.set(State::None);
+        }
+        Err(e)
+    }
+
+    fn finish_transaction(&self, ret: SuccessCode) -> Result<SuccessCode, ErrorCode> {
+        self.phase.set(Phase::Idle);
+        self.state.set(State::None);
+        self.transaction_open.set(false);
+        Ok(ret)
+    }
+
+    /// Continue the current transaction operation.
+    pub(crate) fn continue_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        loop {
+            match self.phase.get() {
+                Phase::Idle => return Ok(SuccessCode::Complete),
+                Phase::Begin => {
+                    let ret = self.append_object(TRANSACTION_BEGIN_KEY, &[], FLAGS_VALID, |reg| {
+                        State::Transaction(TransactionState::ReadRegion(reg))
+                    });
+                    let ret = match ret {
+                        Ok(_) => Ok(SuccessCode::Written),
+                        Err(ErrorCode::WriteNotReady(_)) => Ok(SuccessCode::Queued),
+                        Err(ErrorCode::KeyAlreadyExists) => Err(ErrorCode::TransactionInProgress),
+                        Err(ErrorCode::ReadNotReady(reg)) => {
+                            return Err(ErrorCode::ReadNotReady(reg))
+                        }
+                        Err(e) => Err(e),
+                    };
+                    self.phase.set(Phase::Idle);
+                    self.state.set(State::None);
+                    self.transaction_open.set(ret.is_ok());
+                    return ret;
+                }
+                Phase::FindBegin => match self.find_object(TRANSACTION_BEGIN_KEY) {
+                    Ok(Some(_)) => self.start_phase(Phase::FindCommit),
+                    Ok(None) => return self.finish_transaction(SuccessCode::Complete),
+                    Err(e) => return self.transaction_error(e),
+                },
+                Phase::FindCommit => match self.find_object(TRANSACTION_COMMIT_KEY) {
+                    Ok(Some(_)) => self.start_phase(Phase::Scan(true)),
+                    Ok(None) => self.start_phase(Phase::Scan(false)),
+                    Err(e) => return self.transaction_error(e),
+                },
+                Phase::Commit => {
+                    let ret = self.append_object(TRANSACTION_COMMIT_KEY, &[], FLAGS_VALID, |reg| {
+                        State::Transaction(TransactionState::ReadRegion(reg))
+                    });
+                    // Once the commit object is written the transaction will
+                    // be completed, even after a power loss.
+                    let ret = match ret {
+                        Err(ErrorCode::KeyAlreadyExists) => Ok(SuccessCode::Complete),
+                        ret => ret,
+                    };
+                    if let Ok(_) | Err(ErrorCode::WriteNotReady(_)) = ret {
+                        self.transaction_open.set(false);
+                    }
+                    if let Some(ret) = self.written(ret, Phase::Scan(true)) {
+                        return ret;
+                    }
+                }
+                Phase::Scan(publish) => match self.next_pending() {
+                    Ok(Some((hash, address, flags))) => {
+                        if publish {
+                            self.start_phase(Phase::ReplaceOld(hash, address));
+                        } else {
+                            let ret = self.write_flags(address, flags & !(FLAGS_VALID << 4));
+                            if let Some(ret) = self.written(ret, Phase::Scan(false)) {
+                                return ret;
+                            }
+                        }
+                    }
+                    Ok(None) if publish => self.start_phase(Phase::RemoveCommit),
+                    Ok(None) => self.start_phase(Phase::RemoveBegin),
+                    Err(e) => return self.transaction_error(e),
+                },
+                Phase::ReplaceOld(hash, address) => match self.find_object(hash) {
+                    Ok(Some((old, flags))) => {
+                        let ret = self.write_flags(old, flags & !(FLAGS_VALID << 4));
+                        if let Some(ret) = self.written(ret, Phase::Publish(address)) {
+                            return ret;
+                        }
+                    }
+                    Ok(None) => self.start_phase(Phase::Publish(address)),
+                    Err(e) => return self.transaction_error(e),
+                },
+                Phase::Publish(address) => {
+                    // Read the region again so it is the last one read
+                    // before it is written to.
+                    let region = address / S;
+                    let flags = match self.load_region(
+                        region,
+                        State::Transaction(TransactionState::ReadRegion(region)),
+                    ) {
+                        Ok(region_data) => {
+                            let flags = region_data[address % S + LEN_OFFSET];
+                            self.read_buffer.replace(Some(region_data));
+                            flags
+                        }
+                        Err(e) => return self.transaction_error(e),
+                    };
+
+                    let ret = self.write_flags(address, flags & !(FLAGS_PENDING << 4));
+                    if let Some(ret) = self.written(ret, Phase::Scan(true)) {
+                        return ret;
+                    }
+                }
+                Phase::RemoveCommit | Phase::RemoveBegin => {
+                    let (hash, next) = if self.phase.get() == Phase::RemoveCommit {
+                        (TRANSACTION_COMMIT_KEY, Phase::RemoveBegin)
+                    } else {
+                        (TRANSACTION_BEGIN_KEY, Phase::Idle)
+                    };
+
+                    let ret = match self.find_object(hash) {
+                        Ok(Some((address, flags))) => {
+                            self.write_flags(address, flags & !(FLAGS_VALID << 4))
+                        }
+                        Ok(None) => Ok(SuccessCode::Complete),
+                        Err(e) => return self.transaction_error(e),
+                    };
+
+                    if next != Phase::Idle {
+                        if let Some(ret) = self.written(ret, next) {
+                            return ret;
+                        }
+                    } else {
+                        return match ret {
+                            Ok(ret) => self.finish_transaction(ret),
+                            Err(ErrorCode::WriteNotReady(_)) => {
+                                self.finish_transaction(SuccessCode::Queued)
+                            }
+                            Err(e) => self.transaction_error(e),
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find the next valid pending object, starting from `pending_cursor`.
+    ///
+    /// On success the hashed key, the flash address and the flags of the
+    /// object will be returned, or `None` if there are no more.
+    fn next_pending(&self) -> Result<Option<(u64, usize, u8)>, ErrorCode> {
+        loop {
+            let cursor = self.pending_cursor.get();
+            let region = cursor / S;
+            if region >= self.num_regions() {
+                return Ok(None);
+            }
+
+            let region_data = self.load_region(
+                region,
+                State::Transaction(TransactionState::ReadRegion(region)),
+            )?;
+
+            let mut offset = cursor % S;
+            let found = loop {
+                match Self::read_object(region_data, offset) {
+                    Ok(Some(object)) => {
+                        if object.valid && object.pending {
+                            break Some((object.hash, region_data[offset + LEN_OFFSET]));
+                        }
+                        offset += object.length;
+                    }
+                    Ok(None) | Err(_) => break None,
+                }
+            };
+
+            self.read_buffer.replace(Some(region_data));
+
+            match found {
+                Some((hash, flags)) => {
+                    self.pending_cursor.set(region * S + offset);
+                    return Ok(Some((hash, region * S + offset, flags)));
+                }
+                None => self.pending_cursor.set((region + 1) * S),
+            }
+        }
+    }
+
+    /// Find the committed object for `hash`, searching the same regions as
+    /// `get_key()`.
+    ///
+    /// On success the flash address and the flags of the object will be
+    /// returned, or `None` if it wasn't found.
+    fn find_object(&self, hash: u64) -> Result<Option<(usize, u8)>, ErrorCode> {
+        let region = self.get_region(hash);
+
+        let mut region_offset: isize = 0;
+
+        loop {
+            let new_region = match self.state.get() {
+                State::Transaction(TransactionState::ReadRegion(reg)) => reg as isize,
+                _ => region as isize + region_offset,
+            };
+
+            let region_data = self.load_region(
+                new_region as usize,
+                State::Transaction(TransactionState::ReadRegion(new_region as usize)),
+            )?;
+            // Any further regions need to be read
+            self.state.set(State::Transaction(TransactionState::Step));
+
+            let ret = self.find_key_offset(hash, region_data, false);
+            let flags = ret.map(|(offset, _)| region_data[offset + LEN_OFFSET]);
+            self.read_buffer.replace(Some(region_data));
+
+            match ret {
+                Ok((offset, _)) => {
+                    return Ok(Some((S * new_region as usize + offset, flags.unwrap())));
+                }
+                Err((true, _)) => match self.increment_region_offset(new_region) {
+                    Some(o) => region_offset = o,
+                    None => return Ok(None),
+                },
+                Err((false, ErrorCode::KeyNotFound)) => return Ok(None),
+                Err((false, e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Write the flags of the object at `address`. Flags can only be
+    /// cleared.
+    fn write_flags(&self, address: usize, flags: u8) -> Result<SuccessCode, ErrorCode> {
+        self.controller.write(address + LEN_OFFSET, &[flags])?;
+        Ok(SuccessCode::Written)
+    }
 }
@@ -0,0 +1,131 @@
+//! Converts the stream produced by the Tock 802.15.4 sniffer capsule into a
+//! live pcap feed for Wireshark.
+//!
+//! See `capsules/src/ieee802154/sniffer.rs` for the record format.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: [u8; 2] = [0xC3, 0x15];
+const HEADER_SIZE: usize = 14;
+const MAX_MTU: usize = 127;
+const FLAG_CRC_VALID: u8 = 0x01;
+
+/// LINKTYPE_IEEE802_15_4_NOFCS
+const LINKTYPE: u32 = 230;
+
+struct Record {
+    crc_valid: bool,
+    dropped: u16,
+    timestamp_us: u64,
+    psdu: Vec<u8>,
+}
+
+/// Reads the next record, resynchronizing on the magic bytes if the stream
+/// is corrupted. Returns `None` at end of input.
+fn read_record<R: Read>(input: &mut R) -> io::Result<Option<Record>> {
+    loop {
+        let mut byte = [0u8; 1];
+        let mut prev = None;
+        loop {
+            if input.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if prev == Some(MAGIC[0]) && byte[0] == MAGIC[1] {
+                break;
+            }
+            prev = Some(byte[0]);
+        }
+
+        let mut header = [0u8; HEADER_SIZE - 2];
+        input.read_exact(&mut header)?;
+        let len = header[0] as usize;
+        if len > MAX_MTU {
+            eprintln!("Skipping record with invalid length {}", len);
+            continue;
+        }
+        let mut psdu = vec![0u8; len];
+        input.read_exact(&mut psdu)?;
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&header[4..12]);
+        return Ok(Some(Record {
+            crc_valid: header[1] & FLAG_CRC_VALID != 0,
+            dropped: u16::from_le_bytes([header[2], header[3]]),
+            timestamp_us: u64::from_le_bytes(timestamp),
+            psdu: psdu,
+        }));
+    }
+}
+
+fn write_global_header<W: Write>(output: &mut W) -> io::Result<()> {
+    output.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    output.write_all(&2u16.to_le_bytes())?;
+    output.write_all(&4u16.to_le_bytes())?;
+    output.write_all(&0i32.to_le_bytes())?;
+    output.write_all(&0u32.to_le_bytes())?;
+    output.write_all(&(MAX_MTU as u32).to_le_bytes())?;
+    output.write_all(&LINKTYPE.to_le_bytes())?;
+    output.flush()
+}
+
+fn write_packet<W: Write>(output: &mut W, timestamp_us: u64, data: &[u8]) -> io::Result<()> {
+    output.write_all(&((timestamp_us / 1_000_000) as u32).to_le_bytes())?;
+    output.write_all(&((timestamp_us % 1_000_000) as u32).to_le_bytes())?;
+    output.write_all(&(data.len() as u32).to_le_bytes())?;
+    output.write_all(&(data.len() as u32).to_le_bytes())?;
+    output.write_all(data)?;
+    output.flush()
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: ieee802154-sniffer [--bad-fcs] <device|file|->");
+    eprintln!("Writes a pcap stream to standard output.");
+    std::process::exit(1);
+}
+
+fn main() -> io::Result<()> {
+    let mut keep_bad_fcs = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--bad-fcs" => keep_bad_fcs = true,
+            "-h" | "--help" => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let mut input: Box<dyn Read> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(File::open(&path)?)
+    };
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    write_global_header(&mut output)?;
+
+    // The device timestamps are relative to when the sniffer started; anchor
+    // them to the host clock when the first frame arrives.
+    let mut offset_us = None;
+    while let Some(record) = read_record(&mut input)? {
+        if record.dropped > 0 {
+            eprintln!("Device dropped {} frame(s)", record.dropped);
+        }
+        if !record.crc_valid && !keep_bad_fcs {
+            continue;
+        }
+        let offset = *offset_us.get_or_insert_with(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or(0);
+            now.saturating_sub(record.timestamp_us)
+        });
+        write_packet(&mut output, offset + record.timestamp_us, &record.psdu)?;
+    }
+    Ok(())
+}